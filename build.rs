@@ -14,6 +14,9 @@
  * limitations under the License.
  */
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
 // This build script is used to generate the rust source files that
 // we need for XDS GRPC communication.
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -29,12 +32,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "proto/data-plane-api/envoy/type/tracing/v3/custom_tag.proto",
         "proto/udpa/xds/core/v3/resource_name.proto",
         "proto/quilkin/extensions/filters/debug/v1alpha1/debug.proto",
+        "proto/quilkin/extensions/filters/external_authorization/v1alpha1/external_authorization.proto",
         "proto/quilkin/extensions/filters/capture_bytes/v1alpha1/capture_bytes.proto",
+        "proto/quilkin/extensions/filters/client_address/v1alpha1/client_address.proto",
         "proto/quilkin/extensions/filters/compress/v1alpha1/compress.proto",
         "proto/quilkin/extensions/filters/concatenate_bytes/v1alpha1/concatenate_bytes.proto",
         "proto/quilkin/extensions/filters/load_balancer/v1alpha1/load_balancer.proto",
         "proto/quilkin/extensions/filters/local_rate_limit/v1alpha1/local_rate_limit.proto",
         "proto/quilkin/extensions/filters/token_router/v1alpha1/token_router.proto",
+        "proto/quilkin/extensions/filters/token_router/v1alpha1/token_provider.proto",
     ]
     .iter()
     .map(|name| std::env::current_dir().unwrap().join(name))
@@ -70,6 +76,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .collect::<Vec<_>>(),
         )?;
 
+    // Plugins must be built with the same compiler, see `filters::plugin`.
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc_version = std::process::Command::new(rustc)
+        .arg("--version")
+        .output()?
+        .stdout;
+    println!(
+        "cargo:rustc-env=QUILKIN_RUSTC_VERSION={}",
+        String::from_utf8(rustc_version)?.trim()
+    );
+
+    // And with the same features, some of which change the layout of the
+    // types passed to plugins.
+    let mut features: Vec<_> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_owned))
+        .collect();
+    features.sort();
+    let mut hasher = DefaultHasher::new();
+    features.hash(&mut hasher);
+    println!(
+        "cargo:rustc-env=QUILKIN_FEATURES_HASH={:016x}",
+        hasher.finish()
+    );
+
     // This tells cargo to re-run this build script only when the proto files
     // we're interested in change or the any of the proto directories were updated.
     for path in vec![proto_files, include_dirs].concat() {
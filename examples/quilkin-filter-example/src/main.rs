@@ -34,13 +34,15 @@ struct Greet(String);
 
 impl Filter for Greet {
     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
-        ctx.contents
-            .splice(0..0, format!("{} ", self.0).into_bytes());
+        ctx.contents = [format!("{} ", self.0).as_bytes(), &ctx.contents[..]]
+            .concat()
+            .into();
         Some(ctx.into())
     }
     fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
-        ctx.contents
-            .splice(0..0, format!("{} ", self.0).into_bytes());
+        ctx.contents = [format!("{} ", self.0).as_bytes(), &ctx.contents[..]]
+            .concat()
+            .into();
         Some(ctx.into())
     }
 }
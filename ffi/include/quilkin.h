@@ -0,0 +1,66 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/*
+ * Embeds a Quilkin proxy in-process. Functions that fail return NULL or a
+ * non-zero value, and set their error argument, unless it is NULL, to why
+ * they did, which is then freed with quilkin_error_free.
+ */
+
+#ifndef QUILKIN_H
+#define QUILKIN_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct quilkin_proxy quilkin_proxy;
+
+/*
+ * Creates a proxy from the text of a YAML or JSON configuration, without
+ * running it. Returns NULL if the configuration is invalid.
+ */
+quilkin_proxy *quilkin_proxy_new(const char *config, char **error);
+
+/*
+ * Starts the proxy on threads of its own and returns immediately. Returns
+ * non-zero if it was already run.
+ */
+int quilkin_proxy_run(quilkin_proxy *proxy, char **error);
+
+/*
+ * Stops the proxy, blocking until it has stopped. Returns non-zero if the
+ * proxy failed while it ran.
+ */
+int quilkin_proxy_shutdown(quilkin_proxy *proxy, char **error);
+
+/*
+ * Frees the proxy, shutting it down first if it is running. Does nothing if
+ * proxy is NULL.
+ */
+void quilkin_proxy_free(quilkin_proxy *proxy);
+
+/*
+ * Frees an error set by one of the other functions. Does nothing if error is
+ * NULL.
+ */
+void quilkin_error_free(char *error);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* QUILKIN_H */
@@ -22,11 +22,17 @@ use std::net::SocketAddr;
 pub(crate) mod cluster_manager;
 mod metrics;
 
+/// An upstream endpoint that packets are sent to, as passed to filters.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Endpoint {
     pub address: SocketAddr,
+    /// The tokens that route packets to the endpoint, see the
+    /// `TokenRouter` filter.
     pub tokens: HashSet<Vec<u8>>,
     pub metadata: Option<Value>,
+    /// Whether the source of the endpoint reports it as healthy. Unhealthy
+    /// endpoints are still sent packets, but don't make the proxy ready.
+    pub healthy: bool,
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
@@ -54,6 +60,7 @@ impl Endpoint {
             address,
             tokens,
             metadata,
+            healthy: true,
         }
     }
 
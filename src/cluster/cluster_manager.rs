@@ -19,14 +19,17 @@ use std::sync::Arc;
 // We use a parking_lot since it's significantly faster under low contention
 // and we will need to acquire a read lock with every packet that is processed
 // to be able to capture the current endpoint state and pass it to Filters.
+use opentelemetry::trace::Span;
+use opentelemetry::KeyValue;
 use parking_lot::RwLock;
 use slog::{debug, o, warn, Logger};
 
 use prometheus::{Registry, Result as MetricsResult};
 use tokio::sync::{mpsc, watch};
 
-use crate::cluster::Endpoint;
 use crate::config::{Endpoints, UpstreamEndpoints};
+use crate::proxy::events::{Event, EventHistory};
+use crate::proxy::tracing::Tracer;
 use crate::xds::ads_client::ClusterUpdate;
 
 use super::metrics::Metrics;
@@ -57,6 +60,13 @@ impl ClusterManager {
         self.endpoints = endpoints;
     }
 
+    /// Replaces the endpoints of a fixed ClusterManager, e.g when the
+    /// configuration file they came from changes.
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.metrics.set_endpoints(Some(&endpoints));
+        self.update(Some(endpoints));
+    }
+
     /// Returns all endpoints known at the time of invocation.
     /// Returns `None` if there are no endpoints.
     pub fn get_all_endpoints(&self) -> Option<UpstreamEndpoints> {
@@ -70,12 +80,7 @@ impl ClusterManager {
     ) -> MetricsResult<SharedClusterManager> {
         let cm = Self::new(metrics_registry, Some(endpoints))?;
         // Set the endpoints count metrics.
-        cm.metrics.active_endpoints.set(
-            cm.endpoints
-                .as_ref()
-                .map(|ep| ep.as_ref().len())
-                .unwrap_or_default() as i64,
-        );
+        cm.metrics.set_endpoints(cm.endpoints.as_ref());
         Ok(Arc::new(RwLock::new(cm)))
     }
 
@@ -90,6 +95,8 @@ impl ClusterManager {
     pub fn dynamic(
         base_logger: Logger,
         metrics_registry: &Registry,
+        events: EventHistory,
+        tracer: Tracer,
         cluster_update: ClusterUpdate,
         cluster_updates_rx: mpsc::Receiver<ClusterUpdate>,
         shutdown_rx: watch::Receiver<()>,
@@ -110,6 +117,8 @@ impl ClusterManager {
         Self::spawn_updater(
             log.clone(),
             metrics,
+            events,
+            tracer,
             cluster_manager.clone(),
             cluster_updates_rx,
             shutdown_rx,
@@ -120,11 +129,7 @@ impl ClusterManager {
 
     fn update_cluster_update_metrics(metrics: &Metrics, update: &ClusterUpdate) {
         metrics.active_clusters.set(update.len() as i64);
-        metrics.active_endpoints.set(
-            Self::create_endpoints_from_update(update)
-                .map(|ep| ep.as_ref().len() as i64)
-                .unwrap_or_default(),
-        )
+        metrics.set_endpoints(Self::create_endpoints_from_update(update).as_ref())
     }
 
     fn create_endpoints_from_update(update: &ClusterUpdate) -> Option<Endpoints> {
@@ -137,12 +142,7 @@ impl ClusterManager {
                 let cluster_endpoints = cluster
                     .localities
                     .iter()
-                    .map(|(_, endpoints)| {
-                        endpoints
-                            .endpoints
-                            .iter()
-                            .map(|ep| Endpoint::from_address(ep.address))
-                    })
+                    .map(|(_, endpoints)| endpoints.endpoints.iter().cloned())
                     .flatten();
                 endpoints.extend(cluster_endpoints);
 
@@ -160,6 +160,8 @@ impl ClusterManager {
     fn spawn_updater(
         log: Logger,
         metrics: Metrics,
+        events: EventHistory,
+        tracer: Tracer,
         cluster_manager: Arc<RwLock<ClusterManager>>,
         mut cluster_updates_rx: mpsc::Receiver<ClusterUpdate>,
         mut shutdown_rx: watch::Receiver<()>,
@@ -170,10 +172,23 @@ impl ClusterManager {
                     update = cluster_updates_rx.recv() => {
                         match update {
                             Some(update) => {
+                                let span = tracer.span("cluster.update");
+                                span.set_attribute(KeyValue::new("quilkin.clusters", update.len() as i64));
+                                let clusters_before = metrics.active_clusters.get();
+                                let endpoints_before = metrics.active_endpoints.get();
                                 Self::update_cluster_update_metrics(&metrics, &update);
+                                let clusters = update.len();
                                 let update = Self::create_endpoints_from_update(&update);
                                 debug!(log, "Received a cluster update.");
+                                let endpoints = update.as_ref().map_or(0, |endpoints| endpoints.as_ref().len());
+                                span.set_attribute(KeyValue::new("quilkin.endpoints", endpoints as i64));
                                 cluster_manager.write().update(update);
+                                span.end();
+                                events.record(&log, Event::new("cluster.update", "Applied a cluster update")
+                                    .with("clusters_before", clusters_before)
+                                    .with("clusters_after", clusters)
+                                    .with("endpoints_before", endpoints_before)
+                                    .with("endpoints_after", endpoints));
                             }
                             None => {
                                 warn!(log, "Exiting cluster update receive loop because the sender dropped the channel.");
@@ -196,6 +211,8 @@ mod tests {
     use super::ClusterManager;
     use crate::cluster::{Cluster, Endpoint, LocalityEndpoints};
     use crate::config::Endpoints;
+    use crate::proxy::events::EventHistory;
+    use crate::proxy::tracing::Tracer;
     use crate::test_utils::logger;
     use prometheus::Registry;
     use tokio::sync::{mpsc, watch};
@@ -213,6 +230,7 @@ mod tests {
         .unwrap();
         let metrics = &cm.read().metrics;
         assert_eq!(2, metrics.active_endpoints.get());
+        assert_eq!(2, metrics.healthy_endpoints.get());
         assert_eq!(0, metrics.active_clusters.get());
     }
 
@@ -223,6 +241,8 @@ mod tests {
         let cm = ClusterManager::dynamic(
             logger(),
             &Registry::default(),
+            EventHistory::default(),
+            Tracer::default(),
             vec![(
                 "cluster-1".into(),
                 Cluster {
@@ -14,6 +14,7 @@
  *  limitations under the License.
  */
 
+use crate::config::Endpoints;
 use crate::metrics::{opts, CollectorExt};
 use prometheus::core::{AtomicI64, GenericGauge};
 use prometheus::Result as MetricsResult;
@@ -23,6 +24,7 @@ use prometheus::{IntGauge, Registry};
 pub(super) struct Metrics {
     pub active_clusters: GenericGauge<AtomicI64>,
     pub active_endpoints: GenericGauge<AtomicI64>,
+    pub healthy_endpoints: GenericGauge<AtomicI64>,
 }
 
 impl Metrics {
@@ -41,6 +43,21 @@ impl Metrics {
                 "Number of currently active endpoints.",
             ))?
             .register_if_not_exists(registry)?,
+            healthy_endpoints: IntGauge::with_opts(opts(
+                "healthy_endpoints",
+                subsystem,
+                "Number of currently active endpoints that are healthy.",
+            ))?
+            .register_if_not_exists(registry)?,
         })
     }
+
+    /// Sets the number of active and healthy endpoints to those of
+    /// `endpoints`.
+    pub fn set_endpoints(&self, endpoints: Option<&Endpoints>) {
+        let endpoints = endpoints.map_or(&[][..], |endpoints| endpoints.as_ref());
+        self.active_endpoints.set(endpoints.len() as i64);
+        self.healthy_endpoints
+            .set(endpoints.iter().filter(|endpoint| endpoint.healthy).count() as i64);
+    }
 }
@@ -14,19 +14,31 @@
  * limitations under the License.
  */
 
+use std::convert::TryFrom;
+use std::fs::File;
 use std::io;
 use std::marker::PhantomData;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use base64_serde::base64_serde_type;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use uuid::Uuid;
 
 mod builder;
 mod endpoints;
+mod env;
 mod error;
+mod include;
 mod metadata;
+mod migrate;
+mod preset;
+mod secret;
+mod units;
 
+pub use crate::cluster::Endpoint;
 pub use crate::config::endpoints::{
     EmptyListError, Endpoints, RetainedItems, UpstreamEndpoints, UpstreamEndpointsIter,
 };
@@ -34,6 +46,7 @@ pub(crate) use crate::config::error::ValueInvalidArgs;
 pub use builder::Builder;
 pub use error::ValidationError;
 pub(crate) use metadata::{extract_endpoint_tokens, parse_endpoint_metadata_from_yaml};
+pub(crate) use preset::names as preset_names;
 
 base64_serde_type!(Base64Standard, base64::STANDARD);
 
@@ -47,13 +60,51 @@ pub enum Version {
     V1Alpha1,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Proxy {
     #[serde(default = "default_proxy_id")]
     pub id: String,
     #[serde(default = "default_proxy_port")]
     pub port: u16,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional_ports: Vec<AdditionalPorts>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<Tcp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quic: Option<Quic>,
+    /// How long a session can go without receiving a packet from its client
+    /// before it expires.
+    #[serde(with = "humantime_serde", default = "default_session_timeout")]
+    pub session_timeout: Duration,
+    #[serde(default)]
+    pub session_limits: SessionLimits,
+    #[serde(default)]
+    pub upstream: Upstream,
+    /// Use UDP generic segmentation and receive offload (Linux only).
+    #[serde(default)]
+    pub segmentation_offload: bool,
+    /// Accept packets from proxies with [`Upstream::multiplex`] enabled,
+    /// creating a session for every session id they send.
+    #[serde(default)]
+    pub demultiplex: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hot_restart: Option<HotRestart>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_persistence: Option<SessionPersistence>,
+    #[serde(default)]
+    pub overload: Overload,
+    #[serde(default)]
+    pub runtime: Runtime,
+    /// Logs a random sample of the packets the proxy receives, along with
+    /// where they were sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug_sampling: Option<DebugSampling>,
+    /// Reads the zone and ID of the cloud instance the proxy runs on at
+    /// startup, which identify it to management servers and label its
+    /// metrics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloud_metadata: Option<CloudMetadata>,
 }
 
 fn default_proxy_id() -> String {
@@ -64,249 +115,2601 @@ fn default_proxy_port() -> u16 {
     7000
 }
 
+/// default value for [`Proxy::session_timeout`]
+fn default_session_timeout() -> Duration {
+    Duration::from_secs(crate::proxy::SESSION_TIMEOUT_SECONDS)
+}
+
 impl Default for Proxy {
     fn default() -> Self {
         Proxy {
             id: default_proxy_id(),
             port: default_proxy_port(),
+            additional_ports: vec![],
+            tcp: None,
+            quic: None,
+            session_timeout: default_session_timeout(),
+            session_limits: SessionLimits::default(),
+            upstream: Upstream::default(),
+            segmentation_offload: false,
+            demultiplex: false,
+            hot_restart: None,
+            session_persistence: None,
+            overload: Overload::default(),
+            runtime: Runtime::default(),
+            debug_sampling: None,
+            cloud_metadata: None,
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+impl Proxy {
+    /// Returns every port the proxy listens on for UDP traffic.
+    pub fn listening_ports(&self) -> Vec<u16> {
+        std::iter::once(self.port)
+            .chain(
+                self.additional_ports
+                    .iter()
+                    .flat_map(|additional| additional.ports.ports()),
+            )
+            .collect()
+    }
+
+    /// Returns the number of workers processing received packets for each
+    /// listening port.
+    pub fn num_workers(&self) -> usize {
+        self.runtime.worker_threads.unwrap_or_else(num_cpus::get)
+    }
+}
+
+/// Ports the proxy listens on besides `port`, written as a [`PortRange`] in
+/// configuration, or along with the filters and endpoints that packets
+/// received on them go through instead of those of the `source`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(from = "AdditionalPortsValue", into = "AdditionalPortsValue")]
+pub struct AdditionalPorts {
+    pub ports: PortRange,
+    pub filters: Option<Vec<Filter>>,
+    pub endpoints: Option<Vec<EndPoint>>,
+}
+
+impl From<PortRange> for AdditionalPorts {
+    fn from(ports: PortRange) -> Self {
+        AdditionalPorts {
+            ports,
+            filters: None,
+            endpoints: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum AdditionalPortsValue {
+    Ports(PortRange),
+    Listener(Listener),
+}
+
+#[derive(Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
-pub struct Admin {
-    pub address: SocketAddr,
+struct Listener {
+    ports: PortRange,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filters: Option<Vec<Filter>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    endpoints: Option<Vec<EndPoint>>,
 }
 
-impl Default for Admin {
-    fn default() -> Self {
-        Admin {
-            address: "[::]:9091".parse().unwrap(),
+impl From<AdditionalPortsValue> for AdditionalPorts {
+    fn from(value: AdditionalPortsValue) -> Self {
+        match value {
+            AdditionalPortsValue::Ports(ports) => ports.into(),
+            AdditionalPortsValue::Listener(Listener {
+                ports,
+                filters,
+                endpoints,
+            }) => AdditionalPorts {
+                ports,
+                filters,
+                endpoints,
+            },
+        }
+    }
+}
+
+impl From<AdditionalPorts> for AdditionalPortsValue {
+    fn from(additional: AdditionalPorts) -> Self {
+        match additional {
+            AdditionalPorts {
+                ports,
+                filters: None,
+                endpoints: None,
+            } => AdditionalPortsValue::Ports(ports),
+            AdditionalPorts {
+                ports,
+                filters,
+                endpoints,
+            } => AdditionalPortsValue::Listener(Listener {
+                ports,
+                filters,
+                endpoints,
+            }),
+        }
+    }
+}
+
+/// A single port or an inclusive range of ports, written as either `7001`
+/// or `"7100-7199"` in configuration.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "PortRangeValue")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    /// Returns the ports in the range.
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeValue {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortRangeValue> for PortRange {
+    type Error = String;
+
+    fn try_from(value: PortRangeValue) -> Result<Self, Self::Error> {
+        let range = match value {
+            PortRangeValue::Port(port) => {
+                return Ok(PortRange {
+                    start: port,
+                    end: port,
+                })
+            }
+            PortRangeValue::Range(range) => range,
+        };
+
+        let invalid = || format!("invalid port range `{}`, expected e.g `7100-7199`", range);
+        let mut parts = range.splitn(2, '-');
+        let start = parts.next().and_then(|start| start.trim().parse().ok());
+        let end = parts.next().and_then(|end| end.trim().parse().ok());
+        match (start, end) {
+            (Some(start), Some(end)) if start <= end => Ok(PortRange { start, end }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.start == self.end {
+            serializer.serialize_u16(self.start)
+        } else {
+            serializer.serialize_str(&format!("{}-{}", self.start, self.end))
+        }
+    }
+}
+
+/// The rate packets are sampled at, written as `"1/N"` in configuration to
+/// sample one in every `N` packets.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct DebugSampling {
+    pub one_in: u32,
+}
+
+impl TryFrom<String> for DebugSampling {
+    type Error = String;
+
+    fn try_from(rate: String) -> Result<Self, Self::Error> {
+        let one_in = rate
+            .trim()
+            .strip_prefix("1/")
+            .and_then(|one_in| one_in.trim().parse().ok());
+        match one_in {
+            Some(one_in) if one_in > 0 => Ok(DebugSampling { one_in }),
+            _ => Err(format!(
+                "invalid sample rate `{}`, expected e.g `1/1000`",
+                rate
+            )),
         }
     }
 }
 
+impl Serialize for DebugSampling {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("1/{}", self.one_in))
+    }
+}
+
+/// How a TCP byte stream is split into the units passed through the filter chain.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Framing {
+    /// Each read from the socket is treated as a single packet.
+    #[serde(rename = "CHUNK")]
+    Chunk,
+    /// Each packet is preceded by its length as a big-endian `u16`.
+    #[serde(rename = "LENGTH_PREFIXED")]
+    LengthPrefixed,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing::Chunk
+    }
+}
+
+/// Configuration for proxying TCP traffic alongside UDP.
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct ManagementServer {
-    pub address: String,
+pub struct Tcp {
+    /// The listening port for TCP connections.
+    pub port: u16,
+    #[serde(default)]
+    pub framing: Framing,
+    /// The size of the buffer that a connection reads into with
+    /// [`Framing::Chunk`], which limits the size of the frames filters see.
+    #[serde(with = "units::bytes", default = "default_tcp_buffer_size")]
+    pub buffer_size: usize,
+    /// How long a connection is kept open without a frame from either side,
+    /// including while waiting for its first frame.
+    #[serde(with = "humantime_serde", default = "default_tcp_idle_timeout")]
+    pub idle_timeout: Duration,
+    /// The maximum number of connections proxied at once. Connections accepted
+    /// beyond it are closed right away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub enum Source {
-    #[serde(rename = "static")]
-    Static {
-        #[serde(default)]
-        filters: Vec<Filter>,
+/// default value for [`Tcp::buffer_size`]
+fn default_tcp_buffer_size() -> usize {
+    64 * 1024
+}
 
-        endpoints: Vec<EndPoint>,
-    },
-    #[serde(rename = "dynamic")]
-    Dynamic {
-        management_servers: Vec<ManagementServer>,
-    },
+/// default value for [`Tcp::idle_timeout`]
+fn default_tcp_idle_timeout() -> Duration {
+    Duration::from_secs(60)
 }
 
-/// Config is the configuration of a proxy
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// Configuration for terminating QUIC connections and proxying their
+/// DATAGRAM frames to upstream endpoints.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct Config {
-    pub version: Version,
+pub struct Quic {
+    /// The listening port for QUIC connections.
+    pub port: u16,
+    /// Path to the PEM encoded certificate chain presented to clients.
+    pub certificate: PathBuf,
+    /// Path to the PEM encoded private key for the certificate.
+    pub private_key: PathBuf,
+}
 
+/// How received packets are queued before being processed by the filter
+/// chain, and what happens to packets received while the queues are full.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Overload {
     #[serde(default)]
-    pub proxy: Proxy,
+    pub policy: OverloadPolicy,
+    /// The maximum number of packets queued for each worker. Defaults to the
+    /// number of workers, see [`Proxy::num_workers`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_size: Option<usize>,
+}
 
+/// The threads the proxy runs on.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Runtime {
     #[serde(default)]
-    pub admin: Admin,
+    pub flavor: RuntimeFlavor,
+    /// The number of threads processing packets. Defaults to the number of
+    /// CPUs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_threads: Option<usize>,
+}
 
-    #[serde(flatten)]
-    pub source: Source,
+/// How work is spread across threads.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum RuntimeFlavor {
+    /// A single multi-threaded runtime, whose threads steal work from each
+    /// other.
+    #[serde(rename = "MULTI_THREAD")]
+    MultiThread,
+    /// A single-threaded runtime per worker, each processing its share of
+    /// received packets along with the sessions it created.
+    #[serde(rename = "PER_CORE")]
+    PerCore,
+}
 
-    // Limit struct creation to the builder. We use an Optional<Phantom>
-    // so that we can create instances though deserialization.
-    #[serde(skip_serializing)]
-    pub(super) phantom: Option<PhantomData<()>>,
+impl Default for RuntimeFlavor {
+    fn default() -> Self {
+        RuntimeFlavor::MultiThread
+    }
 }
 
-impl Source {
-    /// Returns the list of filters if the config is a static config and None otherwise.
-    /// This is a convenience function and should only be used for doc tests and tests.
-    pub fn get_static_filters(&self) -> Option<&[Filter]> {
-        match self {
-            Source::Static {
-                filters,
-                endpoints: _,
-            } => Some(filters),
-            Source::Dynamic {
-                management_servers: _,
-            } => None,
-        }
+/// What happens to a packet received while the queue it belongs in is full.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum OverloadPolicy {
+    /// Stop receiving packets until there is room in the queue, leaving it to
+    /// the operating system to drop packets once its socket buffer is full.
+    #[serde(rename = "BLOCK")]
+    Block,
+    /// Drop the received packet.
+    #[serde(rename = "DROP_NEWEST")]
+    DropNewest,
+    /// Drop the packet that has been queued the longest to make room.
+    #[serde(rename = "DROP_OLDEST")]
+    DropOldest,
+    /// Queue packets separately for each client and process the queues in
+    /// turn, dropping packets of the client with the most packets queued.
+    #[serde(rename = "FAIR")]
+    Fair,
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        OverloadPolicy::Block
     }
 }
 
-/// Filter is the configuration for a single filter
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// Caps on the number of sessions the proxy keeps track of at once. Packets
+/// that would create a session beyond these limits are dropped.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct Filter {
-    pub name: String,
-    pub config: Option<serde_yaml::Value>,
+pub struct SessionLimits {
+    /// The maximum number of concurrent sessions across all clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+    /// The maximum number of concurrent sessions for a single downstream address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sessions_per_client: Option<usize>,
 }
 
-/// A singular endpoint, to pass on UDP packets to.
-#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+/// Configuration of the sockets used to send packets to upstream endpoints.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
-pub struct EndPoint {
-    pub address: SocketAddr,
-    pub metadata: Option<serde_yaml::Value>,
+pub struct Upstream {
+    /// The local IP address that upstream sockets are bound to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_address: Option<IpAddr>,
+    /// The network interface that upstream sockets are bound to, using
+    /// `SO_BINDTODEVICE`. Only supported on Linux.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_device: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive: Option<Keepalive>,
+    /// Send the packets of every session through a single socket per
+    /// listening port, prefixed with a session id header, rather than a
+    /// socket per session. The endpoint must be a proxy with
+    /// [`Proxy::demultiplex`] enabled.
+    #[serde(default)]
+    pub multiplex: bool,
 }
 
-impl EndPoint {
-    pub fn new(address: SocketAddr) -> Self {
-        EndPoint::with_metadata(address, None)
-    }
+/// Packets sent to an upstream endpoint while a session is idle, so that NATs
+/// and firewalls between the proxy and the endpoint keep the session's
+/// mapping open.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Keepalive {
+    /// How long a session can go without sending a packet upstream before a
+    /// keepalive packet is sent.
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// The contents of each keepalive packet.
+    #[serde(with = "Base64Standard", default)]
+    pub payload: Vec<u8>,
+}
 
-    pub fn with_metadata(address: SocketAddr, metadata: Option<serde_yaml::Value>) -> Self {
-        EndPoint { address, metadata }
-    }
+/// Configuration for handing the proxy's listening sockets over to a newly
+/// started proxy process, so the binary can be upgraded without downtime.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HotRestart {
+    /// Path of the Unix domain socket a new process connects to in order to
+    /// take over the listening sockets.
+    pub socket: PathBuf,
+    /// How long the old process keeps serving existing sessions after handing
+    /// over its sockets, before it exits.
+    #[serde(with = "humantime_serde", default = "default_drain_timeout")]
+    pub drain_timeout: Duration,
 }
 
-impl Config {
-    /// from_reader returns a config from a given Reader
-    pub fn from_reader<R: io::Read>(input: R) -> Result<Config, serde_yaml::Error> {
-        serde_yaml::from_reader(input)
-    }
+/// default value for [`HotRestart::drain_timeout`]
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(60)
 }
 
-#[cfg(test)]
-mod tests {
-    use serde_yaml::Value;
+/// Configuration for periodically saving the session table, so that it can
+/// be restored when the proxy starts back up.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SessionPersistence {
+    /// Path of the file the session table is saved to and restored from.
+    pub path: PathBuf,
+    /// How often the session table is saved.
+    #[serde(with = "humantime_serde", default = "default_snapshot_interval")]
+    pub interval: Duration,
+}
 
-    use crate::config::{Builder, Config, EndPoint, ManagementServer, Source};
-    use std::collections::HashMap;
+/// default value for [`SessionPersistence::interval`]
+fn default_snapshot_interval() -> Duration {
+    Duration::from_secs(5)
+}
 
-    fn parse_config(yaml: &str) -> Config {
-        Config::from_reader(yaml.as_bytes()).unwrap()
-    }
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Admin {
+    pub address: SocketAddr,
+    /// The credentials that requests for anything other than health checks
+    /// and metrics must provide. Without them, such requests are only
+    /// served to clients on a loopback address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub basic_auth: Option<BasicAuth>,
+    #[serde(default)]
+    pub metrics: MetricsEndpoint,
+    /// The number of control plane events kept for the admin API, where `0`
+    /// keeps none.
+    #[serde(default)]
+    pub event_history: usize,
+    /// Whether to serve CPU and heap profiles, which requires quilkin to be
+    /// built with the `profiling` feature.
+    #[serde(default)]
+    pub profiling: bool,
+    /// The fraction of endpoints, from `0` to `1`, that must be healthy for
+    /// the proxy to be ready. At least one healthy endpoint is required
+    /// regardless.
+    #[serde(default)]
+    pub min_healthy_endpoints: f64,
+}
 
-    fn assert_static_endpoints(source: &Source, expected_endpoints: Vec<EndPoint>) {
-        match source {
-            Source::Static {
-                filters: _,
-                endpoints,
-            } => {
-                assert_eq!(&expected_endpoints, endpoints,);
-            }
-            _ => unreachable!("expected static config source"),
+impl Default for Admin {
+    fn default() -> Self {
+        Admin {
+            address: "[::]:9091".parse().unwrap(),
+            basic_auth: None,
+            metrics: MetricsEndpoint::default(),
+            event_history: 0,
+            profiling: false,
+            min_healthy_endpoints: 0.0,
         }
     }
+}
 
-    fn assert_management_servers(source: &Source, expected: Vec<ManagementServer>) {
-        match source {
-            Source::Dynamic { management_servers } => {
-                assert_eq!(&expected, management_servers,);
-            }
-            _ => unreachable!("expected dynamic config source"),
+/// Configuration of the admin endpoint serving Prometheus metrics, and of
+/// the metrics it serves.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsEndpoint {
+    /// The path that metrics are served on.
+    #[serde(default = "default_metrics_path")]
+    pub path: String,
+    /// The credentials that requests for metrics must provide, if any.
+    pub basic_auth: Option<BasicAuth>,
+    /// Whether every upstream endpoint gets series of its own in the
+    /// per-endpoint metrics, labelled with its address.
+    #[serde(default = "default_metrics_per_endpoint")]
+    pub per_endpoint: bool,
+    /// The number of endpoints that still get series of their own when
+    /// `per_endpoint` is turned off. The others are counted together.
+    #[serde(default = "default_metrics_sampled_endpoints")]
+    pub sampled_endpoints: usize,
+}
+
+impl MetricsEndpoint {
+    /// Returns the number of endpoints that may get series of their own at
+    /// a time, if limited.
+    pub fn endpoint_limit(&self) -> Option<usize> {
+        if self.per_endpoint {
+            None
+        } else {
+            Some(self.sampled_endpoints)
         }
     }
+}
 
-    #[test]
-    fn deserialise_client() {
-        let config = Builder::empty()
-            .with_port(7000)
-            .with_static(
-                vec![],
-                vec![EndPoint::new("127.0.0.1:25999".parse().unwrap())],
-            )
-            .build();
-        let _ = serde_yaml::to_string(&config).unwrap();
+impl Default for MetricsEndpoint {
+    fn default() -> Self {
+        Self {
+            path: default_metrics_path(),
+            basic_auth: None,
+            per_endpoint: default_metrics_per_endpoint(),
+            sampled_endpoints: default_metrics_sampled_endpoints(),
+        }
     }
+}
 
-    #[test]
-    fn deserialise_server() {
-        let config = Builder::empty()
-            .with_port(7000)
-            .with_static(
-                vec![],
-                vec![
-                    EndPoint::new("127.0.0.1:26000".parse().unwrap()),
-                    EndPoint::new("127.0.0.1:26001".parse().unwrap()),
-                ],
-            )
-            .build();
-        let _ = serde_yaml::to_string(&config).unwrap();
-    }
+/// default value for [`MetricsEndpoint::path`]
+fn default_metrics_path() -> String {
+    "/metrics".into()
+}
 
-    #[test]
-    fn parse_default_values() {
-        let yaml = "
-version: v1alpha1
-static:
-  endpoints:
-    - address: 127.0.0.1:25999
-  ";
-        let config = parse_config(yaml);
+/// default value for [`MetricsEndpoint::per_endpoint`]
+fn default_metrics_per_endpoint() -> bool {
+    true
+}
 
-        assert_eq!(config.proxy.port, 7000);
-        assert_eq!(config.proxy.id.len(), 36);
-    }
+/// default value for [`MetricsEndpoint::sampled_endpoints`]
+fn default_metrics_sampled_endpoints() -> usize {
+    10
+}
 
-    #[test]
-    fn parse_filter_config() {
-        let yaml = "
-version: v1alpha1
-proxy:
-  id: client-proxy
-  port: 7000 # the port to receive traffic to locally
-static:
-  filters: # new filters section
-    - name: quilkin.core.v1.rate-limiter
-      config:
-        map: of arbitrary key value pairs
-        could:
+/// Credentials for HTTP basic authentication.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Configuration of the logs that the proxy writes.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Logging {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// A syslog server that logs are also sent to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub syslog: Option<Syslog>,
+}
+
+/// Configuration of sending logs to a syslog server as RFC 5424 messages.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Syslog {
+    /// The address of the server, as `host:port` or the path of a Unix
+    /// socket.
+    pub address: String,
+    #[serde(default)]
+    pub transport: SyslogTransport,
+    #[serde(default)]
+    pub facility: SyslogFacility,
+    /// The `APP-NAME` of messages.
+    #[serde(default = "default_syslog_app_name")]
+    pub app_name: String,
+}
+
+fn default_syslog_app_name() -> String {
+    "quilkin".into()
+}
+
+/// How messages are sent to a syslog server.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum SyslogTransport {
+    /// A datagram per message.
+    #[serde(rename = "udp")]
+    Udp,
+    /// A stream of messages, each prefixed with its length as per RFC 6587.
+    #[serde(rename = "tcp")]
+    Tcp,
+    /// A datagram per message on a Unix socket, e.g `/dev/log`.
+    #[serde(rename = "unix")]
+    Unix,
+}
+
+impl Default for SyslogTransport {
+    fn default() -> Self {
+        SyslogTransport::Udp
+    }
+}
+
+/// The syslog facility that messages are sent with.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+impl Default for SyslogFacility {
+    fn default() -> Self {
+        SyslogFacility::User
+    }
+}
+
+/// Where the identity of the cloud instance the proxy runs on is read from.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CloudMetadata {
+    /// The cloud whose instance metadata service is queried.
+    #[serde(default)]
+    pub provider: CloudProvider,
+    /// How long to wait for each request to the metadata service.
+    #[serde(with = "humantime_serde", default = "default_cloud_metadata_timeout")]
+    pub timeout: Duration,
+    /// The URL of the metadata service, which defaults to that of the
+    /// provider.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+/// default value for [`CloudMetadata::timeout`]
+fn default_cloud_metadata_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// A cloud with an instance metadata service.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum CloudProvider {
+    /// Whichever of the other providers has a metadata service the proxy can
+    /// reach, if any.
+    #[serde(rename = "auto")]
+    Auto,
+    /// Google Compute Engine.
+    #[serde(rename = "gce")]
+    Gce,
+    /// Amazon EC2.
+    #[serde(rename = "ec2")]
+    Ec2,
+}
+
+impl Default for CloudProvider {
+    fn default() -> Self {
+        CloudProvider::Auto
+    }
+}
+
+/// The format that logs are written to stdout in.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum LogFormat {
+    /// A JSON object per line.
+    #[serde(rename = "json")]
+    Json,
+    /// Human-readable lines.
+    #[serde(rename = "plain")]
+    Plain,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Plain
+    }
+}
+
+/// Configuration of exporting spans for sampled packets and control plane
+/// events to an OpenTelemetry collector.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Tracing {
+    /// The address of the collector's OTLP gRPC endpoint.
+    pub endpoint: String,
+    /// The name of the service that spans are reported for.
+    #[serde(default = "default_tracing_service_name")]
+    pub service_name: String,
+    /// The fraction of packets, between 0 and 1, to record spans for.
+    #[serde(default)]
+    pub packet_sample_rate: f64,
+}
+
+/// default value for [`Tracing::service_name`]
+fn default_tracing_service_name() -> String {
+    "quilkin".into()
+}
+
+/// Configuration of pushing metrics to a Prometheus Pushgateway, for proxies
+/// that may exit before they are ever scraped.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Pushgateway {
+    /// The URL of the Pushgateway.
+    pub url: String,
+    /// The job that metrics are pushed for.
+    #[serde(default = "default_pushgateway_job")]
+    pub job: String,
+    /// How often metrics are pushed while the proxy runs. They are also
+    /// pushed once more when it shuts down.
+    #[serde(with = "humantime_serde", default = "default_pushgateway_interval")]
+    pub interval: Duration,
+}
+
+/// default value for [`Pushgateway::job`]
+fn default_pushgateway_job() -> String {
+    "quilkin".into()
+}
+
+/// default value for [`Pushgateway::interval`]
+fn default_pushgateway_interval() -> Duration {
+    Duration::from_secs(15)
+}
+
+/// Configuration of posting session events to a webhook, for systems that
+/// want to know about the connections of clients, such as analytics or
+/// anti-cheat.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    /// The URL that batches of events are posted to.
+    pub url: String,
+    /// The most events posted in a single request.
+    #[serde(default = "default_webhook_batch_size")]
+    pub batch_size: usize,
+    /// How long events wait to be batched with others before they are
+    /// posted.
+    #[serde(with = "humantime_serde", default = "default_webhook_flush_interval")]
+    pub flush_interval: Duration,
+    /// How many times a batch is posted again if posting it fails, before it
+    /// is dropped.
+    #[serde(default = "default_webhook_max_retries")]
+    pub max_retries: u32,
+}
+
+/// default value for [`Webhook::batch_size`]
+fn default_webhook_batch_size() -> usize {
+    100
+}
+
+/// default value for [`Webhook::flush_interval`]
+fn default_webhook_flush_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// default value for [`Webhook::max_retries`]
+fn default_webhook_max_retries() -> u32 {
+    3
+}
+
+/// Configuration of the integration with the Agones SDK server of the game
+/// server the proxy runs next to.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Agones {
+    /// The URL of the SDK server's HTTP API.
+    #[serde(default = "default_agones_sdk_address")]
+    pub sdk_address: String,
+    /// How often the game server is reported as healthy.
+    #[serde(with = "humantime_serde", default = "default_agones_health_interval")]
+    pub health_interval: Duration,
+    /// The annotation of the `GameServer` that holds the tokens of the
+    /// endpoints, as a comma separated list of base64 encoded tokens.
+    #[serde(default = "default_agones_tokens_annotation")]
+    pub tokens_annotation: String,
+}
+
+/// default value for [`Agones::sdk_address`], which uses the port the SDK
+/// server is given to sidecars if set.
+fn default_agones_sdk_address() -> String {
+    let port = std::env::var("AGONES_SDK_HTTP_PORT").unwrap_or_else(|_| "9358".into());
+    format!("http://localhost:{}", port)
+}
+
+/// default value for [`Agones::health_interval`]
+fn default_agones_health_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// default value for [`Agones::tokens_annotation`]
+fn default_agones_tokens_annotation() -> String {
+    "quilkin.dev/tokens".into()
+}
+
+/// A shared library that filter factories are loaded from when the proxy
+/// starts, see [`plugin`][crate::filters::plugin].
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Plugin {
+    /// The path of the library. A path without a slash is searched for in
+    /// the directories the system loads libraries from.
+    pub path: PathBuf,
+}
+
+/// Where the endpoints of a `kubernetes` source are discovered.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KubernetesDiscovery {
+    /// The name of the Service whose EndpointSlices are watched.
+    pub service: String,
+    /// The namespace of the Service, which defaults to the namespace of the
+    /// proxy's pod, or that of the kubeconfig context.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// The name of the port of the Service that packets are sent to. Defaults
+    /// to its first port.
+    #[serde(default)]
+    pub port: Option<String>,
+    /// The kubeconfig file used to connect to the API server. Defaults to the
+    /// service account of the proxy's pod when running in a cluster, or the
+    /// default kubeconfig otherwise.
+    #[serde(default)]
+    pub kubeconfig: Option<PathBuf>,
+}
+
+/// Where the endpoints of a `gamelift` source are discovered.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GameLiftDiscovery {
+    /// The name or ARN of the FleetIQ game server group whose game servers
+    /// packets are sent to.
+    pub game_server_group: String,
+    /// The AWS region of the game server group. Defaults to the `AWS_REGION`
+    /// or `AWS_DEFAULT_REGION` environment variables.
+    #[serde(default)]
+    pub region: Option<String>,
+    /// How often the game servers of the group are listed.
+    #[serde(with = "humantime_serde", default = "default_gamelift_interval")]
+    pub interval: Duration,
+    /// The URL of the GameLift API, which defaults to the endpoint of the
+    /// region.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+/// default value for [`GameLiftDiscovery::interval`]
+fn default_gamelift_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Where the endpoints of a `consul` source are discovered.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConsulDiscovery {
+    /// The name of the Consul service whose instances packets are sent to.
+    pub service: String,
+    /// The URL of the HTTP API of a Consul agent.
+    #[serde(default = "default_consul_address")]
+    pub address: String,
+    /// The datacenter of the service. Defaults to that of the agent.
+    #[serde(default)]
+    pub datacenter: Option<String>,
+    /// A tag that instances must have to be discovered.
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Whether only the instances whose health checks are all passing are
+    /// discovered.
+    #[serde(default = "default_consul_passing_only")]
+    pub passing_only: bool,
+    /// How long the agent holds a query open until the instances change.
+    #[serde(with = "humantime_serde", default = "default_consul_wait")]
+    pub wait: Duration,
+}
+
+/// default value for [`ConsulDiscovery::address`], which uses the agent
+/// address of the Consul CLI if set.
+fn default_consul_address() -> String {
+    match std::env::var("CONSUL_HTTP_ADDR") {
+        Ok(address) if address.contains("://") => address,
+        Ok(address) if !address.is_empty() => format!("http://{}", address),
+        _ => "http://127.0.0.1:8500".into(),
+    }
+}
+
+/// default value for [`ConsulDiscovery::passing_only`]
+fn default_consul_passing_only() -> bool {
+    true
+}
+
+/// default value for [`ConsulDiscovery::wait`]
+fn default_consul_wait() -> Duration {
+    Duration::from_secs(300)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ManagementServer {
+    pub address: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Source {
+    #[serde(rename = "static")]
+    Static {
+        #[serde(default)]
+        filters: Vec<Filter>,
+
+        endpoints: Vec<EndPoint>,
+    },
+    #[serde(rename = "dynamic")]
+    Dynamic {
+        management_servers: Vec<ManagementServer>,
+    },
+    #[serde(rename = "kubernetes")]
+    Kubernetes {
+        #[serde(default)]
+        filters: Vec<Filter>,
+
+        discovery: KubernetesDiscovery,
+    },
+    #[serde(rename = "gamelift")]
+    GameLift {
+        #[serde(default)]
+        filters: Vec<Filter>,
+
+        discovery: GameLiftDiscovery,
+    },
+    #[serde(rename = "consul")]
+    Consul {
+        #[serde(default)]
+        filters: Vec<Filter>,
+
+        discovery: ConsulDiscovery,
+    },
+}
+
+/// Config is the configuration of a proxy
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, try_from = "ConfigFile")]
+pub struct Config {
+    pub version: Version,
+
+    #[serde(default)]
+    pub proxy: Proxy,
+
+    #[serde(default)]
+    pub admin: Admin,
+
+    #[serde(default)]
+    pub logging: Logging,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracing: Option<Tracing>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pushgateway: Option<Pushgateway>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<Webhook>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agones: Option<Agones>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plugins: Vec<Plugin>,
+
+    #[serde(flatten)]
+    pub source: Source,
+
+    // Limit struct creation to the builder. We use an Optional<Phantom>
+    // so that we can create instances though deserialization.
+    #[serde(skip_serializing)]
+    pub(super) phantom: Option<PhantomData<()>>,
+
+    #[serde(skip)]
+    pub(super) deprecations: Vec<String>,
+
+    /// The secrets that references in filter configurations were replaced with.
+    #[serde(skip)]
+    pub(super) secrets: Vec<String>,
+}
+
+/// The layout of a configuration file, which [`Config`] is deserialized
+/// through rather than flattening [`Source`] into it, since the latter loses
+/// the location of any errors within the source.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    version: Version,
+    #[serde(default)]
+    proxy: Proxy,
+    #[serde(default)]
+    admin: Admin,
+    #[serde(default)]
+    logging: Logging,
+    tracing: Option<Tracing>,
+    pushgateway: Option<Pushgateway>,
+    webhook: Option<Webhook>,
+    agones: Option<Agones>,
+    #[serde(default)]
+    plugins: Vec<Plugin>,
+    #[serde(rename = "static")]
+    static_source: Option<StaticSource>,
+    dynamic: Option<DynamicSource>,
+    kubernetes: Option<KubernetesSource>,
+    gamelift: Option<GameLiftSource>,
+    consul: Option<ConsulSource>,
+}
+
+#[derive(Deserialize)]
+struct StaticSource {
+    #[serde(default)]
+    filters: Vec<Filter>,
+    endpoints: Vec<EndPoint>,
+}
+
+#[derive(Deserialize)]
+struct DynamicSource {
+    management_servers: Vec<ManagementServer>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KubernetesSource {
+    #[serde(default)]
+    filters: Vec<Filter>,
+    service: String,
+    #[serde(default)]
+    namespace: Option<String>,
+    #[serde(default)]
+    port: Option<String>,
+    #[serde(default)]
+    kubeconfig: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GameLiftSource {
+    #[serde(default)]
+    filters: Vec<Filter>,
+    game_server_group: String,
+    #[serde(default)]
+    region: Option<String>,
+    #[serde(with = "humantime_serde", default = "default_gamelift_interval")]
+    interval: Duration,
+    #[serde(default)]
+    endpoint: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConsulSource {
+    #[serde(default)]
+    filters: Vec<Filter>,
+    service: String,
+    #[serde(default = "default_consul_address")]
+    address: String,
+    #[serde(default)]
+    datacenter: Option<String>,
+    #[serde(default)]
+    tag: Option<String>,
+    #[serde(default = "default_consul_passing_only")]
+    passing_only: bool,
+    #[serde(with = "humantime_serde", default = "default_consul_wait")]
+    wait: Duration,
+}
+
+impl TryFrom<ConfigFile> for Config {
+    type Error = &'static str;
+
+    fn try_from(file: ConfigFile) -> Result<Self, Self::Error> {
+        let source = match (
+            file.static_source,
+            file.dynamic,
+            file.kubernetes,
+            file.gamelift,
+            file.consul,
+        ) {
+            (Some(StaticSource { filters, endpoints }), None, None, None, None) => {
+                Source::Static { filters, endpoints }
+            }
+            (None, Some(DynamicSource { management_servers }), None, None, None) => {
+                Source::Dynamic { management_servers }
+            }
+            (None, None, Some(source), None, None) => Source::Kubernetes {
+                filters: source.filters,
+                discovery: KubernetesDiscovery {
+                    service: source.service,
+                    namespace: source.namespace,
+                    port: source.port,
+                    kubeconfig: source.kubeconfig,
+                },
+            },
+            (None, None, None, Some(source), None) => Source::GameLift {
+                filters: source.filters,
+                discovery: GameLiftDiscovery {
+                    game_server_group: source.game_server_group,
+                    region: source.region,
+                    interval: source.interval,
+                    endpoint: source.endpoint,
+                },
+            },
+            (None, None, None, None, Some(source)) => Source::Consul {
+                filters: source.filters,
+                discovery: ConsulDiscovery {
+                    service: source.service,
+                    address: source.address,
+                    datacenter: source.datacenter,
+                    tag: source.tag,
+                    passing_only: source.passing_only,
+                    wait: source.wait,
+                },
+            },
+            (None, None, None, None, None) => {
+                return Err(
+                    "one of `static`, `dynamic`, `kubernetes`, `gamelift` or `consul` must be set",
+                )
+            }
+            _ => return Err(
+                "only one of `static`, `dynamic`, `kubernetes`, `gamelift` or `consul` can be set",
+            ),
+        };
+        Ok(Self {
+            version: file.version,
+            proxy: file.proxy,
+            admin: file.admin,
+            logging: file.logging,
+            tracing: file.tracing,
+            pushgateway: file.pushgateway,
+            webhook: file.webhook,
+            agones: file.agones,
+            plugins: file.plugins,
+            source,
+            phantom: None,
+            deprecations: vec![],
+            secrets: vec![],
+        })
+    }
+}
+
+impl Source {
+    /// Returns the list of filters if they are set in the config and None if
+    /// they come from a management server.
+    /// This is a convenience function and should only be used for doc tests and tests.
+    pub fn get_static_filters(&self) -> Option<&[Filter]> {
+        match self {
+            Source::Static {
+                filters,
+                endpoints: _,
+            } => Some(filters),
+            Source::Dynamic {
+                management_servers: _,
+            } => None,
+            Source::Kubernetes { filters, .. }
+            | Source::GameLift { filters, .. }
+            | Source::Consul { filters, .. } => Some(filters),
+        }
+    }
+}
+
+/// Filter is the configuration for a single filter
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    pub name: String,
+    pub config: Option<serde_yaml::Value>,
+}
+
+/// A singular endpoint, to pass on UDP packets to.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EndPoint {
+    pub address: SocketAddr,
+    pub metadata: Option<serde_yaml::Value>,
+}
+
+impl EndPoint {
+    pub fn new(address: SocketAddr) -> Self {
+        EndPoint::with_metadata(address, None)
+    }
+
+    pub fn with_metadata(address: SocketAddr, metadata: Option<serde_yaml::Value>) -> Self {
+        EndPoint { address, metadata }
+    }
+}
+
+impl Config {
+    /// Returns a config with the default proxy and admin settings that sends
+    /// packets to `endpoints` without any filters.
+    pub fn from_endpoints(endpoints: Vec<EndPoint>) -> Self {
+        Config {
+            version: Version::V1Alpha1,
+            proxy: Proxy::default(),
+            admin: Admin::default(),
+            logging: Logging::default(),
+            tracing: None,
+            pushgateway: None,
+            webhook: None,
+            agones: None,
+            plugins: vec![],
+            source: Source::Static {
+                filters: vec![],
+                endpoints,
+            },
+            phantom: None,
+            deprecations: vec![],
+            secrets: vec![],
+        }
+    }
+
+    /// Returns a config like [`Config::from_endpoints`], but with a round robin
+    /// `LoadBalancer` filter so that each packet is sent to one of `endpoints`
+    /// rather than to all of them.
+    pub fn load_balanced(endpoints: Vec<EndPoint>) -> Self {
+        let mut config = Self::from_endpoints(endpoints);
+        if let Source::Static { filters, .. } = &mut config.source {
+            filters.push(Filter {
+                name: "quilkin.extensions.filters.load_balancer.v1alpha1.LoadBalancer".into(),
+                config: Some(serde_yaml::from_str("policy: ROUND_ROBIN").unwrap()),
+            });
+        }
+        config
+    }
+
+    /// Returns the config of the built-in preset named `name`, sending packets
+    /// to `endpoints`.
+    pub fn from_preset(name: &str, endpoints: Vec<EndPoint>) -> Result<Self, serde_yaml::Error> {
+        let endpoints = serde_yaml::to_value(endpoints)?;
+        serde_yaml::from_value(
+            preset::with_endpoints(name, endpoints).map_err(serde::de::Error::custom)?,
+        )
+    }
+
+    /// from_reader returns a config from a given Reader, after substituting
+    /// any `${VAR}` or `${VAR:-default}` with the environment variable `VAR`.
+    /// The config is parsed as JSON if it starts with `{`, and as YAML
+    /// otherwise. Files it includes are looked up relative to the current
+    /// directory, and `file://` and `env://` references to secrets in the
+    /// configuration of filters are replaced with the secret.
+    pub fn from_reader<R: io::Read>(input: R) -> Result<Config, serde_yaml::Error> {
+        Self::from_reader_in(input, Path::new("."), None)
+    }
+
+    /// Returns the config in the file at `path`, like [`Config::from_reader`]
+    /// but looking up the files it includes relative to its own directory.
+    pub fn from_file(path: &Path) -> Result<Config, serde_yaml::Error> {
+        let file = File::open(path).map_err(serde::de::Error::custom)?;
+        Self::from_reader_in(file, path.parent().unwrap_or(path), None)
+    }
+
+    /// Returns the config from `input`, looking up the files it includes
+    /// relative to `dir` and merging it over the preset named `preset` unless
+    /// it names one itself.
+    pub(crate) fn from_reader_in<R: io::Read>(
+        mut input: R,
+        dir: &Path,
+        preset: Option<&str>,
+    ) -> Result<Config, serde_yaml::Error> {
+        let mut contents = String::new();
+        input
+            .read_to_string(&mut contents)
+            .map_err(serde::de::Error::custom)?;
+        let contents = env::substitute(&contents, |name| std::env::var(name).ok())
+            .map_err(serde::de::Error::custom)?;
+        let mut value = parse_value(&contents).map_err(serde::de::Error::custom)?;
+        let included = include::resolve(&mut value, dir).map_err(serde::de::Error::custom)?;
+        let preset = preset::resolve(&mut value, preset).map_err(serde::de::Error::custom)?;
+        let deprecations = migrate::migrate(&mut value);
+        let secrets = secret::resolve(&mut value, |name| std::env::var(name).ok())
+            .map_err(serde::de::Error::custom)?;
+
+        // Parsing the rewritten value loses the location of any errors, so
+        // only do so if anything was rewritten.
+        let mut config: Config =
+            if included || preset || !secrets.is_empty() || !deprecations.is_empty() {
+                serde_yaml::from_value(value)?
+            } else if contents.trim_start().starts_with('{') {
+                serde_json::from_str(&contents).map_err(serde::de::Error::custom)?
+            } else {
+                serde_yaml::from_str(&contents)?
+            };
+        config.deprecations = deprecations;
+        config.secrets = secrets;
+        Ok(config)
+    }
+
+    /// Returns a description of every deprecated part of the format that the
+    /// config was written in, which was upgraded when it was loaded.
+    pub fn deprecations(&self) -> &[String] {
+        &self.deprecations
+    }
+
+    /// Returns the secrets that references in filter configurations were
+    /// replaced with when the config was loaded.
+    pub(crate) fn secrets(&self) -> &[String] {
+        &self.secrets
+    }
+}
+
+/// Parses a config as JSON if it starts with `{`, and as YAML otherwise.
+fn parse_value(contents: &str) -> Result<serde_yaml::Value, String> {
+    if contents.trim_start().starts_with('{') {
+        serde_json::from_str(contents).map_err(|err| err.to_string())
+    } else {
+        serde_yaml::from_str(contents).map_err(|err| err.to_string())
+    }
+}
+
+/// The sources whose configuration has a list of filters.
+const FILTER_SOURCES: &[&str] = &["static", "kubernetes", "gamelift", "consul"];
+
+/// Returns every list of filters in an unparsed config: that of its source,
+/// and those of its additional ports.
+fn filter_lists(config: &mut serde_yaml::Value) -> Vec<&mut serde_yaml::Value> {
+    let mut lists = vec![];
+    let entries = config
+        .as_mapping_mut()
+        .into_iter()
+        .flat_map(|config| config.iter_mut());
+    for (key, value) in entries {
+        match key.as_str() {
+            Some(source) if FILTER_SOURCES.contains(&source) => {
+                lists.extend(value.get_mut("filters"))
+            }
+            Some("proxy") => {
+                let additional_ports = value
+                    .get_mut("additional_ports")
+                    .and_then(serde_yaml::Value::as_sequence_mut);
+                for ports in additional_ports.into_iter().flatten() {
+                    lists.extend(ports.get_mut("filters"));
+                }
+            }
+            _ => {}
+        }
+    }
+    lists
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use crate::config::{
+        AdditionalPorts, Agones, BasicAuth, Builder, CloudMetadata, CloudProvider, Config,
+        ConsulDiscovery, DebugSampling, EndPoint, Filter, Framing, GameLiftDiscovery, HotRestart,
+        Keepalive, KubernetesDiscovery, LogFormat, ManagementServer, MetricsEndpoint, Overload,
+        OverloadPolicy, Plugin, PortRange, Pushgateway, Quic, Runtime, RuntimeFlavor,
+        SessionLimits, SessionPersistence, Source, Syslog, SyslogFacility, SyslogTransport, Tcp,
+        Tracing, Upstream, Webhook,
+    };
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn parse_config(yaml: &str) -> Config {
+        Config::from_reader(yaml.as_bytes()).unwrap()
+    }
+
+    fn assert_static_endpoints(source: &Source, expected_endpoints: Vec<EndPoint>) {
+        match source {
+            Source::Static {
+                filters: _,
+                endpoints,
+            } => {
+                assert_eq!(&expected_endpoints, endpoints,);
+            }
+            _ => unreachable!("expected static config source"),
+        }
+    }
+
+    fn assert_management_servers(source: &Source, expected: Vec<ManagementServer>) {
+        match source {
+            Source::Dynamic { management_servers } => {
+                assert_eq!(&expected, management_servers,);
+            }
+            _ => unreachable!("expected dynamic config source"),
+        }
+    }
+
+    #[test]
+    fn deserialise_client() {
+        let config = Builder::empty()
+            .with_port(7000)
+            .with_static(
+                vec![],
+                vec![EndPoint::new("127.0.0.1:25999".parse().unwrap())],
+            )
+            .build();
+        let _ = serde_yaml::to_string(&config).unwrap();
+    }
+
+    #[test]
+    fn deserialise_server() {
+        let config = Builder::empty()
+            .with_port(7000)
+            .with_static(
+                vec![],
+                vec![
+                    EndPoint::new("127.0.0.1:26000".parse().unwrap()),
+                    EndPoint::new("127.0.0.1:26001".parse().unwrap()),
+                ],
+            )
+            .build();
+        let _ = serde_yaml::to_string(&config).unwrap();
+    }
+
+    #[test]
+    fn load_balanced() {
+        let endpoints = vec![
+            EndPoint::new("127.0.0.1:26000".parse().unwrap()),
+            EndPoint::new("127.0.0.1:26001".parse().unwrap()),
+        ];
+        let config = Config::load_balanced(endpoints.clone());
+
+        assert_eq!(config.proxy.port, 7000);
+        assert_static_endpoints(&config.source, endpoints);
+        assert_eq!(
+            config.source.get_static_filters(),
+            Some(
+                &[Filter {
+                    name: "quilkin.extensions.filters.load_balancer.v1alpha1.LoadBalancer".into(),
+                    config: Some(serde_yaml::from_str("policy: ROUND_ROBIN").unwrap()),
+                }][..]
+            )
+        );
+    }
+
+    #[test]
+    fn parse_default_values() {
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(config.proxy.port, 7000);
+        assert_eq!(config.proxy.id.len(), 36);
+    }
+
+    #[test]
+    fn parse_env_substitution() {
+        std::env::set_var("QUILKIN_TEST_PARSE_ENV_ID", "client-proxy");
+        let yaml = "
+version: v1alpha1
+proxy:
+  id: ${QUILKIN_TEST_PARSE_ENV_ID}
+  port: ${QUILKIN_TEST_PARSE_ENV_PORT:-7001}
+static:
+  endpoints:
+    - address: 127.0.0.1:${QUILKIN_TEST_PARSE_ENV_PORT:-26000}
+        ";
+        let config = parse_config(yaml);
+        assert_eq!("client-proxy", config.proxy.id);
+        assert_eq!(7001, config.proxy.port);
+        assert_static_endpoints(
+            &config.source,
+            vec![EndPoint::new("127.0.0.1:26000".parse().unwrap())],
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  port: ${QUILKIN_TEST_PARSE_ENV_PORT}
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+        ";
+        assert!(Config::from_reader(yaml.as_bytes())
+            .unwrap_err()
+            .to_string()
+            .contains("QUILKIN_TEST_PARSE_ENV_PORT"));
+    }
+
+    #[test]
+    fn parse_source_required() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  port: 7000
+";
+        assert!(Config::from_reader(yaml.as_bytes())
+            .unwrap_err()
+            .to_string()
+            .contains(
+                "one of `static`, `dynamic`, `kubernetes`, `gamelift` or `consul` must be set"
+            ));
+
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:7001
+dynamic:
+  management_servers:
+    - address: 127.0.0.1:25999
+";
+        assert!(Config::from_reader(yaml.as_bytes())
+            .unwrap_err()
+            .to_string()
+            .contains(
+                "only one of `static`, `dynamic`, `kubernetes`, `gamelift` or `consul` can be set"
+            ));
+
+        // Errors within the source point at where they are in the file.
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:7001
+    - adress: 127.0.0.1:7002
+";
+        let error = Config::from_reader(yaml.as_bytes()).unwrap_err();
+        assert!(error.to_string().starts_with("static.endpoints[1]: "));
+        assert_eq!(6, error.location().unwrap().line());
+    }
+
+    #[test]
+    fn parse_json() {
+        let json = r#"
+{
+	"version": "v1alpha1",
+	"proxy": {"id": "client-proxy", "port": 7000},
+	"static": {
+		"filters": [
+			{"name": "quilkin.core.v1.rate-limiter", "config": {"map": {"key": 1}}}
+		],
+		"endpoints": [
+			{"address": "127.0.0.1:26000", "metadata": {"quilkin.dev": {"tokens": ["MXg3aWp5Ng=="]}}}
+		]
+	}
+}"#;
+        let yaml = "
+version: v1alpha1
+proxy:
+  id: client-proxy
+  port: 7000
+static:
+  filters:
+    - name: quilkin.core.v1.rate-limiter
+      config:
+        map:
+          key: 1
+  endpoints:
+    - address: 127.0.0.1:26000
+      metadata:
+        quilkin.dev:
+          tokens:
+            - MXg3aWp5Ng==
+";
+        let config = parse_config(json);
+        assert_eq!(config.proxy, parse_config(yaml).proxy);
+        assert_eq!(config.source, parse_config(yaml).source);
+
+        let error =
+            Config::from_reader(r#"{"version": "v1alpha1", "static": }"#.as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("line 1 column 35"));
+    }
+
+    #[test]
+    fn parse_include() {
+        let dir = std::env::temp_dir().join(format!("quilkin-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("filters.yaml"),
+            "
+static:
+  filters:
+    - name: quilkin.core.v1.rate-limiter
+",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("quilkin.yaml"),
+            "
+version: v1alpha1
+include: filters.yaml
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        )
+        .unwrap();
+
+        let config = Config::from_file(&dir.join("quilkin.yaml")).unwrap();
+        assert_eq!(
+            Source::Static {
+                filters: vec![Filter {
+                    name: "quilkin.core.v1.rate-limiter".into(),
+                    config: None,
+                }],
+                endpoints: vec![EndPoint::new("127.0.0.1:26000".parse().unwrap())],
+            },
+            config.source
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_secret() {
+        std::env::set_var("QUILKIN_TEST_PARSE_SECRET", "MXg3aWp5Ng==");
+        let yaml = "
+version: v1alpha1
+static:
+  filters:
+    - name: quilkin.extensions.filters.concatenate_bytes.v1alpha1.ConcatenateBytes
+      config:
+        on_read: APPEND
+        bytes: env://QUILKIN_TEST_PARSE_SECRET
+  endpoints:
+    - address: 127.0.0.1:26000
+";
+        let config = parse_config(yaml);
+        assert_eq!(
+            Some(&Value::from("MXg3aWp5Ng==")),
+            config.source.get_static_filters().unwrap()[0]
+                .config
+                .as_ref()
+                .and_then(|config| config.get("bytes"))
+        );
+
+        let yaml = yaml.replace(
+            "QUILKIN_TEST_PARSE_SECRET",
+            "QUILKIN_TEST_PARSE_SECRET_UNSET",
+        );
+        assert!(Config::from_reader(yaml.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parse_deprecated() {
+        let yaml = "
+version: v1alpha1
+static:
+  filters:
+    - name: quilkin.extensions.filters.debug_filter.v1alpha1.Debug
+  endpoints:
+    - address: 127.0.0.1:26000
+";
+        let config = parse_config(yaml);
+        assert_eq!(1, config.deprecations().len());
+        assert_eq!(
+            "quilkin.extensions.filters.debug.v1alpha1.Debug",
+            config.source.get_static_filters().unwrap()[0].name
+        );
+
+        let config = parse_config(&yaml.replace("debug_filter", "debug"));
+        assert!(config.deprecations().is_empty());
+    }
+
+    #[test]
+    fn parse_preset() {
+        let config = parse_config(
+            "
+preset: relay
+proxy:
+  overload:
+    policy: DROP_NEWEST
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(Some(8), config.proxy.session_limits.max_sessions_per_client);
+        assert_eq!(OverloadPolicy::DropNewest, config.proxy.overload.policy);
+        assert_eq!(RuntimeFlavor::PerCore, config.proxy.runtime.flavor);
+        assert_eq!(2, config.source.get_static_filters().unwrap().len());
+
+        let config = Config::from_preset(
+            "client",
+            vec![EndPoint::new("127.0.0.1:26000".parse().unwrap())],
+        )
+        .unwrap();
+        assert_eq!(Some(16), config.proxy.session_limits.max_sessions);
+        assert_static_endpoints(
+            &config.source,
+            vec![EndPoint::new("127.0.0.1:26000".parse().unwrap())],
+        );
+    }
+
+    #[test]
+    fn parse_filter_config() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  id: client-proxy
+  port: 7000 # the port to receive traffic to locally
+static:
+  filters: # new filters section
+    - name: quilkin.core.v1.rate-limiter
+      config:
+        map: of arbitrary key value pairs
+        could:
           - also
           - be
           - 27
           - true
   endpoints:
-    - address: 127.0.0.1:7001
-        ";
+    - address: 127.0.0.1:7001
+        ";
+        let config = parse_config(yaml);
+
+        let filter = config.source.get_static_filters().unwrap().get(0).unwrap();
+        assert_eq!("quilkin.core.v1.rate-limiter", filter.name);
+        let config = filter.config.as_ref().unwrap();
+        let filter_config = config.as_mapping().unwrap();
+
+        let key = Value::from("map");
+        assert_eq!(
+            "of arbitrary key value pairs",
+            filter_config.get(&key).unwrap().as_str().unwrap()
+        );
+
+        let key = Value::from("could");
+        let could = filter_config.get(&key).unwrap().as_sequence().unwrap();
+        assert_eq!("also", could.get(0).unwrap().as_str().unwrap());
+        assert_eq!("be", could.get(1).unwrap().as_str().unwrap());
+        assert_eq!(27, could.get(2).unwrap().as_i64().unwrap());
+        assert!(could.get(3).unwrap().as_bool().unwrap());
+    }
+
+    #[test]
+    fn parse_proxy() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  id: server-proxy
+  port: 7000
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(config.proxy.port, 7000);
+        assert_eq!(config.proxy.id.as_str(), "server-proxy");
+    }
+
+    #[test]
+    fn parse_admin() {
+        let config = parse_config(
+            "
+version: v1alpha1
+admin:
+  address: 127.0.0.1:9092
+  basic_auth:
+    username: admin
+    password: hunter3
+  metrics:
+    path: /stats/prometheus
+    basic_auth:
+      username: prometheus
+      password: hunter2
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(
+            MetricsEndpoint {
+                path: "/stats/prometheus".into(),
+                basic_auth: Some(BasicAuth {
+                    username: "prometheus".into(),
+                    password: "hunter2".into(),
+                }),
+                ..MetricsEndpoint::default()
+            },
+            config.admin.metrics
+        );
+        assert_eq!(
+            Some(BasicAuth {
+                username: "admin".into(),
+                password: "hunter3".into(),
+            }),
+            config.admin.basic_auth
+        );
+
+        let config = parse_config(
+            "
+version: v1alpha1
+admin:
+  address: 127.0.0.1:9092
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(MetricsEndpoint::default(), config.admin.metrics);
+        assert_eq!(None, config.admin.basic_auth);
+    }
+
+    #[test]
+    fn parse_tracing() {
+        let config = parse_config(
+            "
+version: v1alpha1
+tracing:
+  endpoint: http://collector:4317
+  packet_sample_rate: 0.01
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(
+            Some(Tracing {
+                endpoint: "http://collector:4317".into(),
+                service_name: "quilkin".into(),
+                packet_sample_rate: 0.01,
+            }),
+            config.tracing
+        );
+
+        let config = parse_config(
+            "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(None, config.tracing);
+    }
+
+    #[test]
+    fn parse_pushgateway() {
+        let config = parse_config(
+            "
+version: v1alpha1
+pushgateway:
+  url: http://pushgateway:9091
+  interval: 5s
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(
+            Some(Pushgateway {
+                url: "http://pushgateway:9091".into(),
+                job: "quilkin".into(),
+                interval: Duration::from_secs(5),
+            }),
+            config.pushgateway
+        );
+    }
+
+    #[test]
+    fn parse_webhook() {
+        let config = parse_config(
+            "
+version: v1alpha1
+webhook:
+  url: http://analytics:8080/quilkin
+  batch_size: 500
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(
+            Some(Webhook {
+                url: "http://analytics:8080/quilkin".into(),
+                batch_size: 500,
+                flush_interval: Duration::from_secs(1),
+                max_retries: 3,
+            }),
+            config.webhook
+        );
+    }
+
+    #[test]
+    fn parse_plugins() {
+        let config = parse_config(
+            "
+version: v1alpha1
+plugins:
+  - path: /usr/lib/quilkin/libgreet.so
+  - path: libauth.so
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(
+            vec![
+                Plugin {
+                    path: "/usr/lib/quilkin/libgreet.so".into()
+                },
+                Plugin {
+                    path: "libauth.so".into()
+                },
+            ],
+            config.plugins
+        );
+    }
+
+    #[test]
+    fn parse_agones() {
+        let config = parse_config(
+            "
+version: v1alpha1
+agones:
+  sdk_address: http://localhost:9400
+  health_interval: 2s
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(
+            Some(Agones {
+                sdk_address: "http://localhost:9400".into(),
+                health_interval: Duration::from_secs(2),
+                tokens_annotation: "quilkin.dev/tokens".into(),
+            }),
+            config.agones
+        );
+    }
+
+    #[test]
+    fn parse_kubernetes() {
+        let config = parse_config(
+            "
+version: v1alpha1
+kubernetes:
+  service: game-servers
+  port: game
+  filters:
+    - name: quilkin.extensions.filters.debug.v1alpha1.Debug
+",
+        );
+        match config.source {
+            Source::Kubernetes { filters, discovery } => {
+                assert_eq!(1, filters.len());
+                assert_eq!(
+                    KubernetesDiscovery {
+                        service: "game-servers".into(),
+                        namespace: None,
+                        port: Some("game".into()),
+                        kubeconfig: None,
+                    },
+                    discovery
+                );
+            }
+            _ => unreachable!("expected a kubernetes source"),
+        }
+    }
+
+    #[test]
+    fn parse_gamelift() {
+        let config = parse_config(
+            "
+version: v1alpha1
+gamelift:
+  game_server_group: game-servers
+  region: eu-west-1
+",
+        );
+        match config.source {
+            Source::GameLift { filters, discovery } => {
+                assert!(filters.is_empty());
+                assert_eq!(
+                    GameLiftDiscovery {
+                        game_server_group: "game-servers".into(),
+                        region: Some("eu-west-1".into()),
+                        interval: Duration::from_secs(10),
+                        endpoint: None,
+                    },
+                    discovery
+                );
+            }
+            _ => unreachable!("expected a gamelift source"),
+        }
+    }
+
+    #[test]
+    fn parse_consul() {
+        let config = parse_config(
+            "
+version: v1alpha1
+consul:
+  service: game-servers
+  address: http://consul:8500
+  tag: eu
+  wait: 1m
+",
+        );
+        match config.source {
+            Source::Consul { filters, discovery } => {
+                assert!(filters.is_empty());
+                assert_eq!(
+                    ConsulDiscovery {
+                        service: "game-servers".into(),
+                        address: "http://consul:8500".into(),
+                        datacenter: None,
+                        tag: Some("eu".into()),
+                        passing_only: true,
+                        wait: Duration::from_secs(60),
+                    },
+                    discovery
+                );
+            }
+            _ => unreachable!("expected a consul source"),
+        }
+    }
+
+    #[test]
+    fn parse_logging() {
+        let config = parse_config(
+            "
+version: v1alpha1
+logging:
+  format: json
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(LogFormat::Json, config.logging.format);
+        assert_eq!(None, config.logging.syslog);
+
+        let config = parse_config(
+            "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(LogFormat::Plain, config.logging.format);
+
+        let config = parse_config(
+            "
+version: v1alpha1
+logging:
+  syslog:
+    address: /dev/log
+    transport: unix
+    facility: local3
+static:
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        assert_eq!(
+            Some(Syslog {
+                address: "/dev/log".into(),
+                transport: SyslogTransport::Unix,
+                facility: SyslogFacility::Local3,
+                app_name: "quilkin".into(),
+            }),
+            config.logging.syslog
+        );
+    }
+
+    #[test]
+    fn parse_proxy_tcp() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  port: 7000
+  tcp:
+    port: 7001
+    framing: LENGTH_PREFIXED
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.tcp,
+            Some(Tcp {
+                port: 7001,
+                framing: Framing::LengthPrefixed,
+                buffer_size: 64 * 1024,
+                idle_timeout: Duration::from_secs(60),
+                max_connections: None,
+            })
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  tcp:
+    port: 7001
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(config.proxy.tcp.unwrap().framing, Framing::Chunk);
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  tcp:
+    port: 7001
+    buffer_size: 16KiB
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(config.proxy.tcp.unwrap().buffer_size, 16 * 1024);
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  tcp:
+    port: 7001
+    idle_timeout: 5m
+    max_connections: 1000
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let tcp = parse_config(yaml).proxy.tcp.unwrap();
+        assert_eq!(tcp.idle_timeout, Duration::from_secs(300));
+        assert_eq!(tcp.max_connections, Some(1000));
+    }
+
+    #[test]
+    fn parse_proxy_quic() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  quic:
+    port: 7002
+    certificate: /etc/quilkin/cert.pem
+    private_key: /etc/quilkin/key.pem
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.quic,
+            Some(Quic {
+                port: 7002,
+                certificate: "/etc/quilkin/cert.pem".into(),
+                private_key: "/etc/quilkin/key.pem".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_proxy_session_timeout() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  session_timeout: 5m
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(config.proxy.session_timeout, Duration::from_secs(300));
+
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(config.proxy.session_timeout, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn parse_proxy_session_limits() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  session_limits:
+    max_sessions: 10000
+    max_sessions_per_client: 4
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
         let config = parse_config(yaml);
 
-        let filter = config.source.get_static_filters().unwrap().get(0).unwrap();
-        assert_eq!("quilkin.core.v1.rate-limiter", filter.name);
-        let config = filter.config.as_ref().unwrap();
-        let filter_config = config.as_mapping().unwrap();
+        assert_eq!(
+            config.proxy.session_limits,
+            SessionLimits {
+                max_sessions: Some(10000),
+                max_sessions_per_client: Some(4),
+            }
+        );
+
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(config.proxy.session_limits, SessionLimits::default());
+    }
+
+    #[test]
+    fn parse_proxy_upstream() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  upstream:
+    local_address: 10.0.0.2
+    bind_device: eth1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
 
-        let key = Value::from("map");
         assert_eq!(
-            "of arbitrary key value pairs",
-            filter_config.get(&key).unwrap().as_str().unwrap()
+            config.proxy.upstream,
+            Upstream {
+                local_address: Some("10.0.0.2".parse().unwrap()),
+                bind_device: Some("eth1".into()),
+                keepalive: None,
+                multiplex: false,
+            }
         );
+    }
 
-        let key = Value::from("could");
-        let could = filter_config.get(&key).unwrap().as_sequence().unwrap();
-        assert_eq!("also", could.get(0).unwrap().as_str().unwrap());
-        assert_eq!("be", could.get(1).unwrap().as_str().unwrap());
-        assert_eq!(27, could.get(2).unwrap().as_i64().unwrap());
-        assert!(could.get(3).unwrap().as_bool().unwrap());
+    #[test]
+    fn parse_proxy_upstream_keepalive() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  upstream:
+    keepalive:
+      interval: 15s
+      payload: cGluZw==
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.proxy.upstream.keepalive,
+            Some(Keepalive {
+                interval: Duration::from_secs(15),
+                payload: b"ping".to_vec(),
+            })
+        );
     }
 
     #[test]
-    fn parse_proxy() {
+    fn parse_proxy_multiplexing() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  demultiplex: true
+  upstream:
+    multiplex: true
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert!(config.proxy.demultiplex);
+        assert!(config.proxy.upstream.multiplex);
+    }
+
+    #[test]
+    fn parse_proxy_segmentation_offload() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  segmentation_offload: true
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert!(config.proxy.segmentation_offload);
+
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert!(!config.proxy.segmentation_offload);
+    }
+
+    #[test]
+    fn parse_proxy_hot_restart() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  hot_restart:
+    socket: /run/quilkin/hot-restart.sock
+    drain_timeout: 30s
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.hot_restart,
+            Some(HotRestart {
+                socket: "/run/quilkin/hot-restart.sock".into(),
+                drain_timeout: Duration::from_secs(30),
+            })
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  hot_restart:
+    socket: /run/quilkin/hot-restart.sock
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.hot_restart.unwrap().drain_timeout,
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn parse_proxy_session_persistence() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  session_persistence:
+    path: /dev/shm/quilkin-sessions.json
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.session_persistence,
+            Some(SessionPersistence {
+                path: "/dev/shm/quilkin-sessions.json".into(),
+                interval: Duration::from_secs(5),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_proxy_overload() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  overload:
+    policy: FAIR
+    queue_size: 256
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.overload,
+            Overload {
+                policy: OverloadPolicy::Fair,
+                queue_size: Some(256),
+            }
+        );
+
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(config.proxy.overload.policy, OverloadPolicy::Block);
+    }
+
+    #[test]
+    fn parse_proxy_runtime() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  runtime:
+    flavor: PER_CORE
+    worker_threads: 4
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.runtime,
+            Runtime {
+                flavor: RuntimeFlavor::PerCore,
+                worker_threads: Some(4),
+            }
+        );
+        assert_eq!(config.proxy.num_workers(), 4);
+
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(config.proxy.runtime.flavor, RuntimeFlavor::MultiThread);
+        assert_eq!(config.proxy.num_workers(), num_cpus::get());
+    }
+
+    #[test]
+    fn parse_proxy_additional_ports() {
         let yaml = "
 version: v1alpha1
 proxy:
-  id: server-proxy
   port: 7000
+  additional_ports:
+    - 7001
+    - 7100-7102
+    - ports: 7200
+      filters:
+        - name: quilkin.extensions.filters.debug.v1alpha1.Debug
+      endpoints:
+        - address: 127.0.0.1:26000
 static:
   endpoints:
     - address: 127.0.0.1:25999
   ";
         let config = parse_config(yaml);
 
-        assert_eq!(config.proxy.port, 7000);
-        assert_eq!(config.proxy.id.as_str(), "server-proxy");
+        assert_eq!(
+            config.proxy.additional_ports,
+            vec![
+                PortRange {
+                    start: 7001,
+                    end: 7001
+                }
+                .into(),
+                PortRange {
+                    start: 7100,
+                    end: 7102
+                }
+                .into(),
+                AdditionalPorts {
+                    ports: PortRange {
+                        start: 7200,
+                        end: 7200
+                    },
+                    filters: Some(vec![Filter {
+                        name: "quilkin.extensions.filters.debug.v1alpha1.Debug".into(),
+                        config: None,
+                    }]),
+                    endpoints: Some(vec![EndPoint::new("127.0.0.1:26000".parse().unwrap())]),
+                },
+            ]
+        );
+        assert_eq!(
+            config.proxy.listening_ports(),
+            vec![7000, 7001, 7100, 7101, 7102, 7200]
+        );
+        assert_eq!(
+            serde_yaml::to_value(&config.proxy.additional_ports[..2]).unwrap(),
+            serde_yaml::from_str::<serde_yaml::Value>("[7001, 7100-7102]").unwrap()
+        );
+        let value = serde_yaml::to_value(&config.proxy.additional_ports).unwrap();
+        assert_eq!(
+            config.proxy.additional_ports,
+            serde_yaml::from_value::<Vec<AdditionalPorts>>(value).unwrap()
+        );
+
+        for range in &["7102-7100", "7100-", "abc"] {
+            let yaml = format!(
+                "
+version: v1alpha1
+proxy:
+  additional_ports:
+    - {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ",
+                range
+            );
+            assert!(Config::from_reader(yaml.as_bytes()).is_err(), "{}", range);
+        }
+    }
+
+    #[test]
+    fn parse_proxy_cloud_metadata() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  cloud_metadata:
+    provider: ec2
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.cloud_metadata,
+            Some(CloudMetadata {
+                provider: CloudProvider::Ec2,
+                timeout: Duration::from_secs(1),
+                endpoint: None,
+            })
+        );
+
+        let yaml = "
+version: v1alpha1
+proxy:
+  cloud_metadata: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.cloud_metadata.unwrap().provider,
+            CloudProvider::Auto
+        );
+    }
+
+    #[test]
+    fn parse_proxy_debug_sampling() {
+        let yaml = "
+version: v1alpha1
+proxy:
+  debug_sampling: 1/1000
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(
+            config.proxy.debug_sampling,
+            Some(DebugSampling { one_in: 1000 })
+        );
+        assert_eq!(
+            serde_yaml::to_value(config.proxy.debug_sampling).unwrap(),
+            serde_yaml::Value::from("1/1000")
+        );
+
+        for rate in &["1/0", "2/1000", "1000", "0.001"] {
+            let yaml = format!(
+                "
+version: v1alpha1
+proxy:
+  debug_sampling: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ",
+                rate
+            );
+            assert!(Config::from_reader(yaml.as_bytes()).is_err(), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn parse_admin_metrics() {
+        let yaml = "
+version: v1alpha1
+admin:
+  address: 127.0.0.1:9091
+  metrics:
+    per_endpoint: false
+    sampled_endpoints: 5
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+
+        assert_eq!(
+            config.admin.metrics,
+            MetricsEndpoint {
+                per_endpoint: false,
+                sampled_endpoints: 5,
+                ..MetricsEndpoint::default()
+            }
+        );
+        assert_eq!(config.admin.metrics.endpoint_limit(), Some(5));
+
+        let yaml = "
+version: v1alpha1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+  ";
+        let config = parse_config(yaml);
+        assert_eq!(config.admin.metrics, MetricsEndpoint::default());
+        assert_eq!(config.admin.metrics.endpoint_limit(), None);
     }
 
     #[test]
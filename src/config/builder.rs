@@ -14,15 +14,42 @@
  *  limitations under the License.
  */
 
+use std::time::Duration;
+
 use super::{Config, Filter};
-use crate::config::{Admin, EndPoint, Proxy, Source, Version};
+use crate::config::{
+    AdditionalPorts, Admin, Agones, CloudMetadata, ConsulDiscovery, DebugSampling, EndPoint,
+    GameLiftDiscovery, HotRestart, KubernetesDiscovery, Logging, Overload, Plugin, Proxy,
+    Pushgateway, Quic, Runtime, SessionLimits, SessionPersistence, Source, Tcp, Tracing, Upstream,
+    Version, Webhook,
+};
 
 /// Builder for a [`Config`]
 #[derive(Debug)]
 pub struct Builder {
     pub port: u16,
+    pub additional_ports: Vec<AdditionalPorts>,
+    pub tcp: Option<Tcp>,
+    pub quic: Option<Quic>,
+    pub session_timeout: Duration,
+    pub session_limits: SessionLimits,
+    pub upstream: Upstream,
+    pub segmentation_offload: bool,
+    pub demultiplex: bool,
+    pub hot_restart: Option<HotRestart>,
+    pub session_persistence: Option<SessionPersistence>,
+    pub overload: Overload,
+    pub runtime: Runtime,
+    pub debug_sampling: Option<DebugSampling>,
+    pub cloud_metadata: Option<CloudMetadata>,
     pub source: Source,
     pub admin: Admin,
+    pub logging: Logging,
+    pub tracing: Option<Tracing>,
+    pub pushgateway: Option<Pushgateway>,
+    pub webhook: Option<Webhook>,
+    pub agones: Option<Agones>,
+    pub plugins: Vec<Plugin>,
 }
 
 impl Builder {
@@ -30,7 +57,27 @@ impl Builder {
     pub fn empty() -> Self {
         Builder {
             port: 0,
+            additional_ports: vec![],
+            tcp: None,
+            quic: None,
+            session_timeout: super::default_session_timeout(),
+            session_limits: SessionLimits::default(),
+            upstream: Upstream::default(),
+            segmentation_offload: false,
+            demultiplex: false,
+            hot_restart: None,
+            session_persistence: None,
+            overload: Overload::default(),
+            runtime: Runtime::default(),
+            debug_sampling: None,
+            cloud_metadata: None,
             admin: Admin::default(),
+            logging: Logging::default(),
+            tracing: None,
+            pushgateway: None,
+            webhook: None,
+            agones: None,
+            plugins: vec![],
             source: Source::Static {
                 filters: vec![],
                 endpoints: vec![],
@@ -42,25 +89,187 @@ impl Builder {
         Builder { port, ..self }
     }
 
+    pub fn with_additional_ports(self, additional_ports: Vec<AdditionalPorts>) -> Self {
+        Builder {
+            additional_ports,
+            ..self
+        }
+    }
+
+    pub fn with_tcp(self, tcp: Tcp) -> Self {
+        Builder {
+            tcp: Some(tcp),
+            ..self
+        }
+    }
+
+    pub fn with_quic(self, quic: Quic) -> Self {
+        Builder {
+            quic: Some(quic),
+            ..self
+        }
+    }
+
+    pub fn with_session_timeout(self, session_timeout: Duration) -> Self {
+        Builder {
+            session_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_session_limits(self, session_limits: SessionLimits) -> Self {
+        Builder {
+            session_limits,
+            ..self
+        }
+    }
+
+    pub fn with_upstream(self, upstream: Upstream) -> Self {
+        Builder { upstream, ..self }
+    }
+
+    pub fn with_segmentation_offload(self, segmentation_offload: bool) -> Self {
+        Builder {
+            segmentation_offload,
+            ..self
+        }
+    }
+
+    pub fn with_demultiplex(self, demultiplex: bool) -> Self {
+        Builder {
+            demultiplex,
+            ..self
+        }
+    }
+
+    pub fn with_hot_restart(self, hot_restart: HotRestart) -> Self {
+        Builder {
+            hot_restart: Some(hot_restart),
+            ..self
+        }
+    }
+
+    pub fn with_session_persistence(self, session_persistence: SessionPersistence) -> Self {
+        Builder {
+            session_persistence: Some(session_persistence),
+            ..self
+        }
+    }
+
+    pub fn with_overload(self, overload: Overload) -> Self {
+        Builder { overload, ..self }
+    }
+
+    pub fn with_runtime(self, runtime: Runtime) -> Self {
+        Builder { runtime, ..self }
+    }
+
+    pub fn with_debug_sampling(self, debug_sampling: DebugSampling) -> Self {
+        Builder {
+            debug_sampling: Some(debug_sampling),
+            ..self
+        }
+    }
+
+    pub fn with_cloud_metadata(self, cloud_metadata: CloudMetadata) -> Self {
+        Builder {
+            cloud_metadata: Some(cloud_metadata),
+            ..self
+        }
+    }
+
     pub fn with_static(self, filters: Vec<Filter>, endpoints: Vec<EndPoint>) -> Self {
         let source = Source::Static { filters, endpoints };
         Builder { source, ..self }
     }
 
+    pub fn with_kubernetes(self, filters: Vec<Filter>, discovery: KubernetesDiscovery) -> Self {
+        let source = Source::Kubernetes { filters, discovery };
+        Builder { source, ..self }
+    }
+
+    pub fn with_gamelift(self, filters: Vec<Filter>, discovery: GameLiftDiscovery) -> Self {
+        let source = Source::GameLift { filters, discovery };
+        Builder { source, ..self }
+    }
+
+    pub fn with_consul(self, filters: Vec<Filter>, discovery: ConsulDiscovery) -> Self {
+        let source = Source::Consul { filters, discovery };
+        Builder { source, ..self }
+    }
+
     pub fn with_admin(self, admin: Admin) -> Self {
         Self { admin, ..self }
     }
 
+    pub fn with_logging(self, logging: Logging) -> Self {
+        Self { logging, ..self }
+    }
+
+    pub fn with_tracing(self, tracing: Tracing) -> Self {
+        Self {
+            tracing: Some(tracing),
+            ..self
+        }
+    }
+
+    pub fn with_pushgateway(self, pushgateway: Pushgateway) -> Self {
+        Self {
+            pushgateway: Some(pushgateway),
+            ..self
+        }
+    }
+
+    pub fn with_webhook(self, webhook: Webhook) -> Self {
+        Self {
+            webhook: Some(webhook),
+            ..self
+        }
+    }
+
+    pub fn with_agones(self, agones: Agones) -> Self {
+        Self {
+            agones: Some(agones),
+            ..self
+        }
+    }
+
+    pub fn with_plugins(self, plugins: Vec<Plugin>) -> Self {
+        Self { plugins, ..self }
+    }
+
     pub fn build(self) -> Config {
         Config {
             version: Version::V1Alpha1,
             proxy: Proxy {
                 id: "test".into(),
                 port: self.port,
+                additional_ports: self.additional_ports,
+                tcp: self.tcp,
+                quic: self.quic,
+                session_timeout: self.session_timeout,
+                session_limits: self.session_limits,
+                upstream: self.upstream,
+                segmentation_offload: self.segmentation_offload,
+                demultiplex: self.demultiplex,
+                hot_restart: self.hot_restart,
+                session_persistence: self.session_persistence,
+                overload: self.overload,
+                runtime: self.runtime,
+                debug_sampling: self.debug_sampling,
+                cloud_metadata: self.cloud_metadata,
             },
             admin: self.admin,
+            logging: self.logging,
+            tracing: self.tracing,
+            pushgateway: self.pushgateway,
+            webhook: self.webhook,
+            agones: self.agones,
+            plugins: self.plugins,
             source: self.source,
             phantom: None,
+            deprecations: vec![],
+            secrets: vec![],
         }
     }
 }
@@ -0,0 +1,142 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Substitution of environment variables in configuration files.
+
+/// Replaces every `${VAR}` in `input` with the value that `lookup` returns
+/// for `VAR`, and every `${VAR:-default}` with `default` if `VAR` is unset or
+/// empty. `$$` is replaced with a single `$`, any other `$` is left as is.
+pub(super) fn substitute<F>(input: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            output.push('$');
+            rest = after;
+            continue;
+        }
+        let expr = match rest.strip_prefix('{') {
+            Some(expr) => expr,
+            None => {
+                output.push('$');
+                continue;
+            }
+        };
+        let end = expr
+            .find('}')
+            .ok_or_else(|| "missing closing `}` after `${`".to_string())?;
+        let (name, default) = match expr[..end].find(":-") {
+            Some(separator) => (&expr[..separator], Some(&expr[separator + 2..end])),
+            None => (&expr[..end], None),
+        };
+        if !is_valid_name(name) {
+            return Err(format!("invalid environment variable name `{}`", name));
+        }
+
+        match (lookup(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => output.push_str(default),
+            (Some(value), _) => output.push_str(&value),
+            (None, Some(default)) => output.push_str(default),
+            (None, None) => {
+                return Err(format!(
+                    "environment variable `{}` is not set and has no default",
+                    name
+                ))
+            }
+        }
+        rest = &expr[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::substitute;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "PORT" => Some("7001".into()),
+            "EMPTY" => Some("".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn substitute_variables() {
+        assert_eq!(
+            "port: 7001",
+            substitute("port: ${PORT}", lookup).unwrap().as_str()
+        );
+        assert_eq!(
+            "port: 7001",
+            substitute("port: ${PORT:-7000}", lookup).unwrap().as_str()
+        );
+        assert_eq!(
+            "port: 7000",
+            substitute("port: ${UNSET:-7000}", lookup).unwrap().as_str()
+        );
+        assert_eq!(
+            "port: 7000",
+            substitute("port: ${EMPTY:-7000}", lookup).unwrap().as_str()
+        );
+        assert_eq!("id: ", substitute("id: ${EMPTY}", lookup).unwrap().as_str());
+        assert_eq!(
+            "id: a-7001-7001",
+            substitute("id: a-${PORT}-${PORT}", lookup)
+                .unwrap()
+                .as_str()
+        );
+        assert_eq!(
+            "address: ${PORT} $PORT $ ",
+            substitute("address: $${PORT} $PORT $ ", lookup)
+                .unwrap()
+                .as_str()
+        );
+        assert_eq!(
+            "address: 127.0.0.1:7001",
+            substitute("address: ${HOST:-127.0.0.1}:${PORT:-}", lookup)
+                .unwrap()
+                .as_str()
+        );
+    }
+
+    #[test]
+    fn substitute_invalid() {
+        for input in &[
+            "port: ${UNSET}",
+            "port: ${PORT",
+            "port: ${}",
+            "port: ${:-7000}",
+            "port: ${1PORT}",
+            "port: ${PO RT}",
+        ] {
+            assert!(substitute(input, lookup).is_err(), "{}", input);
+        }
+    }
+}
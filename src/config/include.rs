@@ -0,0 +1,236 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Merging of configuration files that include other files.
+
+use std::path::{Path, PathBuf};
+
+use serde_yaml::Value;
+
+/// The key listing the files a configuration file includes.
+const INCLUDE: &str = "include";
+
+/// Merges the files that `config` includes into it, resolving relative paths
+/// against `dir`. Returns whether it included any.
+pub(super) fn resolve(config: &mut Value, dir: &Path) -> Result<bool, String> {
+    if config.get(INCLUDE).is_none() {
+        return Ok(false);
+    }
+    *config = merge_includes(std::mem::take(config), dir, &mut vec![])?;
+    Ok(true)
+}
+
+/// Merges the files that `value` includes into it, where `parents` are the
+/// files that led to `value` being included.
+fn merge_includes(
+    mut value: Value,
+    dir: &Path,
+    parents: &mut Vec<PathBuf>,
+) -> Result<Value, String> {
+    let includes = match &mut value {
+        Value::Mapping(mapping) => mapping.remove(&Value::from(INCLUDE)),
+        _ => None,
+    };
+    let includes = match includes {
+        None => return Ok(value),
+        Some(Value::String(path)) => vec![path],
+        Some(Value::Sequence(paths)) => paths
+            .into_iter()
+            .map(|path| match path {
+                Value::String(path) => Ok(path),
+                _ => Err(format!("`{}` must only contain file names", INCLUDE)),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(format!(
+                "`{}` must be a file name or a list of file names",
+                INCLUDE
+            ))
+        }
+    };
+
+    let mut merged = Value::Null;
+    for include in includes {
+        let path = dir.join(&include);
+        let path = path
+            .canonicalize()
+            .map_err(|err| format!("failed to include `{}`: {}", path.display(), err))?;
+        if parents.contains(&path) {
+            return Err(format!("`{}` includes itself", path.display()));
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| format!("failed to include `{}`: {}", path.display(), err))?;
+        let contents = super::env::substitute(&contents, |name| std::env::var(name).ok())
+            .and_then(|contents| super::parse_value(&contents))
+            .map_err(|err| format!("failed to include `{}`: {}", path.display(), err))?;
+
+        let dir = path.parent().unwrap_or(&path).to_owned();
+        parents.push(path);
+        let included = merge_includes(contents, &dir, parents)?;
+        parents.pop();
+        merged = merge(merged, included);
+    }
+    Ok(merge(merged, value))
+}
+
+/// Returns `base` with the values in `overlay` merged into it. Mappings are
+/// merged key by key, while any other value in `overlay` replaces the one in
+/// `base`.
+pub(super) fn merge(base: Value, overlay: Value) -> Value {
+    match (base, overlay) {
+        (Value::Mapping(mut base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => *existing = merge(std::mem::take(existing), value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+            Value::Mapping(base)
+        }
+        (_, overlay) => overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_yaml::Value;
+
+    use super::{merge, resolve};
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    fn resolve_yaml(s: &str, dir: &Path) -> Result<Option<Value>, String> {
+        let mut config = yaml(s);
+        resolve(&mut config, dir).map(|included| Some(config).filter(|_| included))
+    }
+
+    #[test]
+    fn merge_values() {
+        let base = yaml(
+            "
+proxy:
+  port: 7000
+  id: base
+static:
+  filters:
+    - name: a
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        let overlay = yaml(
+            "
+proxy:
+  port: 7001
+static:
+  endpoints:
+    - address: 127.0.0.1:26001
+",
+        );
+        assert_eq!(
+            yaml(
+                "
+proxy:
+  port: 7001
+  id: base
+static:
+  filters:
+    - name: a
+  endpoints:
+    - address: 127.0.0.1:26001
+"
+            ),
+            merge(base, overlay)
+        );
+    }
+
+    #[test]
+    fn resolve_includes() {
+        let dir = std::env::temp_dir().join(format!("quilkin-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("region")).unwrap();
+        std::fs::write(
+            dir.join("filters.yaml"),
+            "
+static:
+  filters:
+    - name: a
+  endpoints: []
+",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("region/endpoints.json"),
+            r#"{"static": {"endpoints": [{"address": "127.0.0.1:26000"}]}}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("region/proxy.yaml"),
+            "
+include: endpoints.json
+proxy:
+  port: 7001
+",
+        )
+        .unwrap();
+
+        assert_eq!(None, resolve_yaml("version: v1alpha1", &dir).unwrap());
+        assert_eq!(
+            Some(yaml(
+                "
+static:
+  filters:
+    - name: a
+  endpoints:
+    - address: 127.0.0.1:26000
+proxy:
+  port: 7002
+version: v1alpha1
+"
+            )),
+            resolve_yaml(
+                "
+version: v1alpha1
+include:
+  - filters.yaml
+  - region/proxy.yaml
+proxy:
+  port: 7002
+",
+                &dir
+            )
+            .unwrap()
+        );
+
+        assert!(resolve_yaml("include: missing.yaml", &dir).is_err());
+        assert!(resolve_yaml("include: {a: b}", &dir).is_err());
+        assert!(resolve_yaml("include: [1]", &dir).is_err());
+
+        std::fs::write(dir.join("cycle.yaml"), "include: region/cycle.yaml").unwrap();
+        std::fs::write(dir.join("region/cycle.yaml"), "include: ../cycle.yaml").unwrap();
+        assert!(resolve_yaml("include: cycle.yaml", &dir)
+            .unwrap_err()
+            .contains("includes itself"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Upgrading of configs written for an older format to the current one,
+//! through the upgrade of each version of the format since the one the
+//! config declares.
+
+use serde_yaml::{Mapping, Value};
+
+const CAPTURE_BYTES: &str = "quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes";
+const DEBUG: &str = "quilkin.extensions.filters.debug.v1alpha1.Debug";
+
+/// Filters that were renamed, as their old and new name.
+const RENAMED_FILTERS: &[(&str, &str)] = &[
+    (
+        "quilkin.extensions.filters.debug_filter.v1alpha1.DebugFilter",
+        DEBUG,
+    ),
+    (
+        "quilkin.extensions.filters.debug_filter.v1alpha1.Debug",
+        DEBUG,
+    ),
+];
+
+/// Filter configuration fields that were renamed, as the filter's name and
+/// the field's old and new name.
+const RENAMED_FILTER_FIELDS: &[(&str, &str, &str)] =
+    &[(CAPTURE_BYTES, "contextKey", "metadataKey")];
+
+/// Rewrites the parts of a config that are deprecated in a version of the
+/// format, and moves it to the next version if there is one, adding a
+/// description of each one to the deprecations.
+type Upgrade = fn(&mut Value, &mut Vec<String>);
+
+/// Every version of the config format, oldest first, along with the upgrade
+/// of a config written for it.
+const VERSIONS: &[(&str, Upgrade)] = &[("v1alpha1", upgrade_v1alpha1)];
+
+/// Upgrades `config` from the version it declares to the current one,
+/// returning a description of each deprecated part that was rewritten for
+/// warning about it. Configs without a known version are left for parsing to
+/// reject.
+pub(super) fn migrate(config: &mut Value) -> Vec<String> {
+    let mut deprecations = vec![];
+    let version = config.get("version").and_then(Value::as_str);
+    let oldest = match VERSIONS.iter().position(|(name, _)| Some(*name) == version) {
+        Some(oldest) => oldest,
+        None => return deprecations,
+    };
+    for (index, (_, upgrade)) in VERSIONS.iter().enumerate().skip(oldest) {
+        upgrade(config, &mut deprecations);
+        if let (Some((next, _)), Value::Mapping(config)) = (VERSIONS.get(index + 1), &mut *config) {
+            config.insert(Value::from("version"), Value::from(*next));
+        }
+    }
+    deprecations
+}
+
+/// Renames the filters and filter configuration fields that were renamed
+/// while `v1alpha1` was current, in every list of filters.
+fn upgrade_v1alpha1(config: &mut Value, deprecations: &mut Vec<String>) {
+    for filters in super::filter_lists(config) {
+        for filter in filters.as_sequence_mut().into_iter().flatten() {
+            if let Value::Mapping(filter) = filter {
+                migrate_filter(filter, deprecations);
+            }
+        }
+    }
+}
+
+fn migrate_filter(filter: &mut Mapping, deprecations: &mut Vec<String>) {
+    let name = match filter.get_mut(&Value::from("name")) {
+        Some(Value::String(name)) => name,
+        _ => return,
+    };
+    if let Some((old, new)) = RENAMED_FILTERS.iter().find(|(old, _)| name == old) {
+        deprecations.push(format!("filter `{}` was renamed to `{}`", old, new));
+        *name = new.to_string();
+    }
+    let name = name.clone();
+
+    let config = match filter.get_mut(&Value::from("config")) {
+        Some(Value::Mapping(config)) => config,
+        _ => return,
+    };
+    for (_, old, new) in RENAMED_FILTER_FIELDS
+        .iter()
+        .filter(|(filter, _, _)| *filter == name)
+    {
+        if let Some(value) = config.remove(&Value::from(*old)) {
+            deprecations.push(format!(
+                "field `{}` of filter `{}` was renamed to `{}`",
+                old, name, new
+            ));
+            // The current name wins if both are set.
+            if !config.contains_key(&Value::from(*new)) {
+                config.insert(Value::from(*new), value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::migrate;
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn migrate_filters() {
+        let mut config = yaml(
+            "
+version: v1alpha1
+static:
+  filters:
+    - name: quilkin.extensions.filters.debug_filter.v1alpha1.DebugFilter
+    - name: quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes
+      config:
+        size: 3
+        contextKey: key
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+        );
+        let deprecations = migrate(&mut config);
+        assert_eq!(2, deprecations.len());
+        assert_eq!(
+            yaml(
+                "
+version: v1alpha1
+static:
+  filters:
+    - name: quilkin.extensions.filters.debug.v1alpha1.Debug
+    - name: quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes
+      config:
+        size: 3
+        metadataKey: key
+  endpoints:
+    - address: 127.0.0.1:26000
+"
+            ),
+            config
+        );
+
+        // A current config is left alone.
+        let current = config.clone();
+        assert!(migrate(&mut config).is_empty());
+        assert_eq!(current, config);
+        assert!(migrate(&mut yaml("version: v1alpha1\nstatic: []")).is_empty());
+
+        // Configs of an unknown version are left for parsing to reject.
+        let mut config = yaml(
+            "{version: v0, static: {filters: [{name: quilkin.extensions.filters.debug_filter.v1alpha1.DebugFilter}]}}",
+        );
+        let unknown = config.clone();
+        assert!(migrate(&mut config).is_empty());
+        assert_eq!(unknown, config);
+    }
+
+    #[test]
+    fn migrate_every_filter_list() {
+        for source in &["kubernetes", "gamelift", "consul"] {
+            let mut config = yaml(&format!(
+                "{{version: v1alpha1, {}: {{filters: [{{name: quilkin.extensions.filters.debug_filter.v1alpha1.Debug}}]}}}}",
+                source
+            ));
+            assert_eq!(1, migrate(&mut config).len(), "{}", source);
+            assert_eq!(
+                yaml(&format!(
+                    "{{version: v1alpha1, {}: {{filters: [{{name: quilkin.extensions.filters.debug.v1alpha1.Debug}}]}}}}",
+                    source
+                )),
+                config
+            );
+        }
+
+        let mut config = yaml(
+            "{version: v1alpha1, proxy: {additional_ports: [7001, {ports: 7002, filters: [{name: quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes, config: {size: 3, contextKey: key}}]}]}}",
+        );
+        assert_eq!(1, migrate(&mut config).len());
+        assert_eq!(
+            yaml("{version: v1alpha1, proxy: {additional_ports: [7001, {ports: 7002, filters: [{name: quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes, config: {size: 3, metadataKey: key}}]}]}}"),
+            config
+        );
+    }
+}
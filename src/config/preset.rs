@@ -0,0 +1,185 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Built-in configurations for common roles, which configs are merged over.
+
+use serde_yaml::Value;
+
+/// The key naming the preset a config is based on.
+const PRESET: &str = "preset";
+
+/// The name of every preset, along with its configuration.
+const PRESETS: &[(&str, &str)] = &[
+    (
+        "server-sidecar",
+        "
+version: v1alpha1
+proxy:
+  session_limits:
+    max_sessions_per_client: 4
+  overload:
+    policy: DROP_OLDEST
+static:
+  filters: []
+",
+    ),
+    (
+        "client",
+        "
+version: v1alpha1
+proxy:
+  session_limits:
+    max_sessions: 16
+  upstream:
+    keepalive:
+      interval: 10s
+static:
+  filters: []
+",
+    ),
+    (
+        "relay",
+        "
+version: v1alpha1
+proxy:
+  session_limits:
+    max_sessions_per_client: 8
+  overload:
+    policy: FAIR
+  runtime:
+    flavor: PER_CORE
+static:
+  filters:
+    - name: quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes
+      config:
+        strategy: SUFFIX
+        size: 7
+        remove: true
+    - name: quilkin.extensions.filters.token_router.v1alpha1.TokenRouter
+",
+    ),
+];
+
+/// Returns the names of the presets.
+pub(crate) fn names() -> Vec<&'static str> {
+    PRESETS.iter().map(|(name, _)| *name).collect()
+}
+
+/// Returns the configuration of the preset named `name`.
+fn get(name: &str) -> Result<Value, String> {
+    let (_, preset) = PRESETS
+        .iter()
+        .find(|(preset, _)| *preset == name)
+        .ok_or_else(|| {
+            format!(
+                "unknown preset `{}`, expected one of: {}",
+                name,
+                names().join(", ")
+            )
+        })?;
+    Ok(serde_yaml::from_str(preset).expect("presets should be valid YAML"))
+}
+
+/// Merges `config` over the preset it names, or over `default` if it does not
+/// name one. Returns whether it was merged over any.
+pub(super) fn resolve(config: &mut Value, default: Option<&str>) -> Result<bool, String> {
+    let name = match config {
+        Value::Mapping(mapping) => mapping.remove(&Value::from(PRESET)),
+        _ => None,
+    };
+    let name = match name {
+        Some(Value::String(name)) => name,
+        Some(_) => return Err(format!("`{}` must be the name of a preset", PRESET)),
+        None => match default {
+            Some(name) => name.into(),
+            None => return Ok(false),
+        },
+    };
+    *config = super::include::merge(get(&name)?, std::mem::take(config));
+    Ok(true)
+}
+
+/// Returns the preset named `name`, sending packets to `endpoints`.
+pub(super) fn with_endpoints(name: &str, endpoints: Value) -> Result<Value, String> {
+    let mut overlay = serde_yaml::Mapping::new();
+    let mut source = serde_yaml::Mapping::new();
+    source.insert("endpoints".into(), endpoints);
+    overlay.insert("static".into(), source.into());
+    Ok(super::include::merge(get(name)?, overlay.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::{names, resolve};
+    use crate::config::Config;
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn presets_are_valid() {
+        for name in names() {
+            let mut config = yaml("static: {endpoints: [{address: 127.0.0.1:26000}]}");
+            assert!(resolve(&mut config, Some(name)).unwrap());
+            let config: Config = serde_yaml::from_value(config).unwrap();
+            crate::proxy::Builder::from(std::sync::Arc::new(config))
+                .validate()
+                .unwrap_or_else(|err| panic!("preset `{}` is invalid: {}", name, err));
+        }
+    }
+
+    #[test]
+    fn resolve_preset() {
+        let mut config = yaml("version: v1alpha1");
+        assert!(!resolve(&mut config, None).unwrap());
+        assert_eq!(yaml("version: v1alpha1"), config);
+
+        // The preset named by the config takes precedence over the default.
+        let mut config = yaml(
+            "
+preset: client
+proxy:
+  session_limits:
+    max_sessions: 32
+",
+        );
+        assert!(resolve(&mut config, Some("relay")).unwrap());
+        assert_eq!(
+            yaml(
+                "
+version: v1alpha1
+proxy:
+  session_limits:
+    max_sessions: 32
+  upstream:
+    keepalive:
+      interval: 10s
+static:
+  filters: []
+"
+            ),
+            config
+        );
+
+        assert!(resolve(&mut yaml("preset: unknown"), None)
+            .unwrap_err()
+            .contains("unknown preset `unknown`"));
+        assert!(resolve(&mut yaml("preset: [client]"), None).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Resolution of references to secrets in filter configurations.
+
+use serde_yaml::Value;
+
+/// The prefix of a reference to a file containing a secret.
+const FILE: &str = "file://";
+/// The prefix of a reference to an environment variable containing a secret.
+const ENV: &str = "env://";
+
+/// Replaces every `file://PATH` and `env://VAR` string in the configuration
+/// of the filters of the source in `config`, the filters of its additional
+/// ports, and the passwords of the admin server and its metrics endpoint,
+/// with the contents of the file at `PATH` or the value of the environment
+/// variable `VAR`, with `lookup` returning the value of an environment
+/// variable. Returns the secrets it replaced them with, so that they can be
+/// kept out of anything the proxy reports.
+pub(super) fn resolve<F>(config: &mut Value, lookup: F) -> Result<Vec<String>, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut secrets = vec![];
+    for filters in super::filter_lists(config) {
+        resolve_filters(filters, &lookup, &mut secrets)?;
+    }
+
+    if let Some(admin) = config.get_mut("admin") {
+        let password = admin
+            .get_mut("basic_auth")
+            .and_then(|basic_auth| basic_auth.get_mut("password"));
+        if let Some(password) = password {
+            resolve_value(password, &lookup, &mut secrets)
+                .map_err(|err| format!("admin.basic_auth.password: {}", err))?;
+        }
+        let password = admin
+            .get_mut("metrics")
+            .and_then(|metrics| metrics.get_mut("basic_auth"))
+            .and_then(|basic_auth| basic_auth.get_mut("password"));
+        if let Some(password) = password {
+            resolve_value(password, &lookup, &mut secrets)
+                .map_err(|err| format!("admin.metrics.basic_auth.password: {}", err))?;
+        }
+    }
+    Ok(secrets)
+}
+
+fn resolve_filters<F>(
+    filters: &mut Value,
+    lookup: &F,
+    secrets: &mut Vec<String>,
+) -> Result<(), String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    for filter in filters.as_sequence_mut().into_iter().flatten() {
+        let name = filter
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned();
+        if let Some(config) = filter.get_mut("config") {
+            resolve_value(config, lookup, secrets)
+                .map_err(|err| format!("filter `{}`: {}", name, err))?;
+        }
+    }
+    Ok(())
+}
+
+fn resolve_value<F>(value: &mut Value, lookup: &F, secrets: &mut Vec<String>) -> Result<(), String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match value {
+        Value::String(reference) => {
+            if let Some(secret) = secret(reference, lookup)? {
+                *reference = secret.clone();
+                secrets.push(secret);
+            }
+        }
+        Value::Sequence(values) => {
+            for value in values {
+                resolve_value(value, lookup, secrets)?;
+            }
+        }
+        Value::Mapping(values) => {
+            for (_, value) in values.iter_mut() {
+                resolve_value(value, lookup, secrets)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Returns the secret that `reference` refers to, or `None` if it is not a
+/// reference.
+fn secret<F>(reference: &str, lookup: &F) -> Result<Option<String>, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if let Some(path) = reference.strip_prefix(FILE) {
+        let secret = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read secret `{}`: {}", reference, err))?;
+        // Files written by editors and `echo` usually end with a newline that
+        // is not part of the secret.
+        let secret = secret
+            .strip_suffix('\n')
+            .map(|secret| secret.strip_suffix('\r').unwrap_or(secret))
+            .unwrap_or(&secret);
+        Ok(Some(secret.to_owned()))
+    } else if let Some(name) = reference.strip_prefix(ENV) {
+        lookup(name)
+            .map(Some)
+            .ok_or_else(|| format!("environment variable of secret `{}` is not set", reference))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::resolve;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HMAC_KEY" => Some("a2V5".into()),
+            _ => None,
+        }
+    }
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn resolve_secrets() {
+        let path = std::env::temp_dir().join(format!("quilkin-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "dG9rZW4=\n").unwrap();
+
+        let mut config = yaml(&format!(
+            "
+version: v1alpha1
+static:
+  filters:
+    - name: a
+      config:
+        key: env://HMAC_KEY
+        tokens:
+          - file://{}
+          - plain
+  endpoints:
+    - address: 127.0.0.1:26000
+",
+            path.display()
+        ));
+        assert_eq!(
+            vec!["a2V5".to_owned(), "dG9rZW4=".to_owned()],
+            resolve(&mut config, lookup).unwrap()
+        );
+        assert_eq!(
+            yaml(
+                "
+version: v1alpha1
+static:
+  filters:
+    - name: a
+      config:
+        key: a2V5
+        tokens:
+          - dG9rZW4=
+          - plain
+  endpoints:
+    - address: 127.0.0.1:26000
+"
+            ),
+            config
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let mut config = yaml(
+            "admin: {basic_auth: {username: admin, password: env://HMAC_KEY}, metrics: {basic_auth: {username: prometheus, password: env://HMAC_KEY}}}",
+        );
+        assert_eq!(
+            vec!["a2V5".to_owned(), "a2V5".to_owned()],
+            resolve(&mut config, lookup).unwrap()
+        );
+        assert_eq!(
+            yaml("admin: {basic_auth: {username: admin, password: a2V5}, metrics: {basic_auth: {username: prometheus, password: a2V5}}}"),
+            config
+        );
+
+        let mut config = yaml(
+            "proxy: {additional_ports: [7001, {ports: 7002, filters: [{name: a, config: {key: env://HMAC_KEY}}]}]}",
+        );
+        assert_eq!(
+            vec!["a2V5".to_owned()],
+            resolve(&mut config, lookup).unwrap()
+        );
+        assert_eq!(
+            yaml("proxy: {additional_ports: [7001, {ports: 7002, filters: [{name: a, config: {key: a2V5}}]}]}"),
+            config
+        );
+
+        let mut config = yaml(
+            "kubernetes: {service: game, filters: [{name: a, config: {key: env://HMAC_KEY}}]}",
+        );
+        assert_eq!(
+            vec!["a2V5".to_owned()],
+            resolve(&mut config, lookup).unwrap()
+        );
+        assert_eq!(
+            yaml("kubernetes: {service: game, filters: [{name: a, config: {key: a2V5}}]}"),
+            config
+        );
+
+        let mut config = yaml(
+            "gamelift: {game_server_group: game, filters: [{name: a, config: {key: env://HMAC_KEY}}]}",
+        );
+        assert_eq!(
+            vec!["a2V5".to_owned()],
+            resolve(&mut config, lookup).unwrap()
+        );
+        assert_eq!(
+            yaml("gamelift: {game_server_group: game, filters: [{name: a, config: {key: a2V5}}]}"),
+            config
+        );
+
+        let mut config =
+            yaml("consul: {service: game, filters: [{name: a, config: {key: env://HMAC_KEY}}]}");
+        assert_eq!(
+            vec!["a2V5".to_owned()],
+            resolve(&mut config, lookup).unwrap()
+        );
+        assert_eq!(
+            yaml("consul: {service: game, filters: [{name: a, config: {key: a2V5}}]}"),
+            config
+        );
+
+        // Secrets are only resolved within the configuration of filters and
+        // the passwords of the admin server.
+        let mut config = yaml("proxy: {id: env://HMAC_KEY}\nstatic: {filters: [{name: a}]}");
+        let unresolved = config.clone();
+        assert!(resolve(&mut config, lookup).unwrap().is_empty());
+        assert_eq!(unresolved, config);
+
+        assert!(resolve(
+            &mut yaml("static: {filters: [{name: a, config: {key: env://UNSET}}]}"),
+            lookup
+        )
+        .unwrap_err()
+        .contains("filter `a`"));
+        assert!(resolve(
+            &mut yaml("static: {filters: [{name: a, config: {key: file:///missing/secret}}]}"),
+            lookup
+        )
+        .is_err());
+    }
+}
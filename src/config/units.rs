@@ -0,0 +1,143 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Parsing of sizes written with human-friendly units.
+
+/// Units that sizes can be written in, along with their number of bytes.
+const UNITS: &[(&str, usize)] = &[
+    ("B", 1),
+    ("KB", 1000),
+    ("KiB", 1 << 10),
+    ("MB", 1000 * 1000),
+    ("MiB", 1 << 20),
+    ("GB", 1000 * 1000 * 1000),
+    ("GiB", 1 << 30),
+];
+
+/// Parses a size such as `512`, `1500B`, `64KiB` or `4MB` into its number of
+/// bytes.
+fn parse_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| size.len());
+    let (number, unit) = size.split_at(digits);
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("invalid size `{}`, expected e.g `64KiB`", size))?;
+    let unit = unit.trim_start();
+    let multiplier = if unit.is_empty() {
+        1
+    } else {
+        UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, multiplier)| *multiplier)
+            .ok_or_else(|| {
+                format!(
+                    "invalid size unit `{}`, expected one of: {}",
+                    unit,
+                    UNITS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size `{}` is too large", size))
+}
+
+/// Serde helpers for a size in bytes, which is deserialized from either a
+/// number of bytes or a string with a unit such as `64KiB`, and serialized as
+/// a number of bytes.
+pub(crate) mod bytes {
+    use std::fmt;
+
+    use serde::de::{self, Deserializer, Visitor};
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(size: &usize, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(*size as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+        struct SizeVisitor;
+
+        impl<'de> Visitor<'de> for SizeVisitor {
+            type Value = usize;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number of bytes or a size such as `64KiB`")
+            }
+
+            fn visit_u64<E: de::Error>(self, size: u64) -> Result<usize, E> {
+                Ok(size as usize)
+            }
+
+            fn visit_i64<E: de::Error>(self, size: i64) -> Result<usize, E> {
+                if size < 0 {
+                    return Err(E::custom("size cannot be negative"));
+                }
+                Ok(size as usize)
+            }
+
+            fn visit_str<E: de::Error>(self, size: &str) -> Result<usize, E> {
+                super::parse_size(size).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(SizeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::parse_size;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(Ok(512), parse_size("512"));
+        assert_eq!(Ok(1500), parse_size("1500B"));
+        assert_eq!(Ok(64 * 1024), parse_size("64KiB"));
+        assert_eq!(Ok(64 * 1000), parse_size("64 KB"));
+        assert_eq!(Ok(4 * 1024 * 1024), parse_size("4MiB"));
+        assert_eq!(Ok(2 * 1000 * 1000 * 1000), parse_size("2GB"));
+
+        for size in &["", "KiB", "64kib", "64 KiB extra", "1.5MiB", "-1"] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn deserialize_sizes() {
+        #[derive(Deserialize)]
+        struct Sizes {
+            #[serde(with = "super::bytes")]
+            size: usize,
+        }
+
+        let size = |yaml: &str| serde_yaml::from_str::<Sizes>(yaml).map(|sizes| sizes.size);
+        assert_eq!(1024, size("size: 1024").unwrap());
+        assert_eq!(1024, size("size: 1KiB").unwrap());
+        assert!(size("size: -1").is_err());
+        assert!(size("size: 1 parsec").is_err());
+    }
+}
@@ -0,0 +1,274 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A C interface for embedding a proxy in an application written in another
+//! language, e.g a game server engine written in C++, which the `quilkin-ffi`
+//! crate builds as a shared and a static library along with its
+//! `quilkin.h` header.
+//!
+//! A proxy is created from the text of a configuration, run in the background
+//! on threads of its own, and shut down:
+//!
+//! ```c
+//! char *error = NULL;
+//! quilkin_proxy *proxy = quilkin_proxy_new(config, &error);
+//! if (proxy == NULL || quilkin_proxy_run(proxy, &error) != 0) {
+//!     fprintf(stderr, "%s\n", error);
+//!     quilkin_error_free(error);
+//! }
+//! /* ... */
+//! quilkin_proxy_shutdown(proxy, NULL);
+//! quilkin_proxy_free(proxy);
+//! ```
+//!
+//! Functions that fail return `NULL` or a non-zero value, and set their
+//! `error` argument, unless it is `NULL`, to why they did, which is then
+//! freed with [`quilkin_error_free`].
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+use tokio::runtime::Runtime;
+
+use crate::config::Config;
+use crate::proxy::{Builder, Handle, Server};
+
+type Error = Box<dyn std::error::Error>;
+
+/// A proxy created by [`quilkin_proxy_new`], along with the runtime it runs
+/// on.
+pub struct Proxy {
+    runtime: Runtime,
+    state: State,
+}
+
+enum State {
+    Created(Server),
+    Running(Handle),
+    Stopped,
+}
+
+impl Proxy {
+    fn new(config: &str) -> Result<Self, Error> {
+        let config = Config::from_reader(config.as_bytes())?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        // Some filters start background tasks when they are created.
+        let server = {
+            let _guard = runtime.enter();
+            Builder::from(config).validate()?.build()
+        };
+        Ok(Self {
+            runtime,
+            state: State::Created(server),
+        })
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        match std::mem::replace(&mut self.state, State::Stopped) {
+            State::Created(server) => {
+                let _guard = self.runtime.enter();
+                self.state = State::Running(server.spawn());
+                Ok(())
+            }
+            state => {
+                self.state = state;
+                Err("the proxy has already been run".into())
+            }
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        match std::mem::replace(&mut self.state, State::Stopped) {
+            State::Running(handle) => {
+                handle.shutdown();
+                self.runtime.block_on(handle.await_termination())?;
+                Ok(())
+            }
+            State::Created(_) | State::Stopped => Ok(()),
+        }
+    }
+}
+
+/// Calls `f`, setting `error` to the error it fails or panics with unless
+/// `error` is `NULL`.
+///
+/// # Safety
+/// `error` must be `NULL` or valid for writes.
+unsafe fn report<T>(error: *mut *mut c_char, f: impl FnOnce() -> Result<T, Error>) -> Option<T> {
+    let message = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => return Some(value),
+        Ok(Err(err)) => err.to_string(),
+        Err(_) => "the proxy panicked".into(),
+    };
+    if let Some(error) = error.as_mut() {
+        *error = CString::new(message.replace('\0', ""))
+            .unwrap_or_default()
+            .into_raw();
+    }
+    None
+}
+
+/// Creates a proxy from the YAML or JSON `config`, a nul-terminated string,
+/// without running it. Returns `NULL` if the configuration is invalid.
+///
+/// # Safety
+/// `config` must be a nul-terminated string, and `error` `NULL` or valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn quilkin_proxy_new(
+    config: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut Proxy {
+    report(error, || {
+        if config.is_null() {
+            return Err("the configuration is NULL".into());
+        }
+        Proxy::new(CStr::from_ptr(config).to_str()?)
+    })
+    .map_or(ptr::null_mut(), |proxy| Box::into_raw(Box::new(proxy)))
+}
+
+/// Starts the proxy on threads of its own and returns immediately. Returns
+/// non-zero if it was already run.
+///
+/// # Safety
+/// `proxy` must have been returned by [`quilkin_proxy_new`] and not freed,
+/// and `error` be `NULL` or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn quilkin_proxy_run(proxy: *mut Proxy, error: *mut *mut c_char) -> c_int {
+    report(error, || match proxy.as_mut() {
+        Some(proxy) => proxy.run(),
+        None => Err("the proxy is NULL".into()),
+    })
+    .map_or(-1, |()| 0)
+}
+
+/// Stops the proxy, blocking until it has stopped. Returns non-zero if the
+/// proxy failed while it ran.
+///
+/// # Safety
+/// `proxy` must have been returned by [`quilkin_proxy_new`] and not freed,
+/// and `error` be `NULL` or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn quilkin_proxy_shutdown(
+    proxy: *mut Proxy,
+    error: *mut *mut c_char,
+) -> c_int {
+    report(error, || match proxy.as_mut() {
+        Some(proxy) => proxy.shutdown(),
+        None => Err("the proxy is NULL".into()),
+    })
+    .map_or(-1, |()| 0)
+}
+
+/// Frees the proxy, shutting it down first if it is running. Does nothing if
+/// `proxy` is `NULL`.
+///
+/// # Safety
+/// `proxy` must have been returned by [`quilkin_proxy_new`] and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn quilkin_proxy_free(proxy: *mut Proxy) {
+    if !proxy.is_null() {
+        let mut proxy = Box::from_raw(proxy);
+        report(ptr::null_mut(), || proxy.shutdown());
+    }
+}
+
+/// Frees an error set by one of the other functions. Does nothing if `error`
+/// is `NULL`.
+///
+/// # Safety
+/// `error` must have been set by one of the other functions and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn quilkin_error_free(error: *mut c_char) {
+    if !error.is_null() {
+        drop(CString::from_raw(error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::net::UdpSocket;
+    use std::os::raw::c_char;
+    use std::ptr;
+    use std::time::Duration;
+
+    use super::{
+        quilkin_error_free, quilkin_proxy_free, quilkin_proxy_new, quilkin_proxy_run,
+        quilkin_proxy_shutdown,
+    };
+
+    /// Returns and frees the message of `error`.
+    fn message(error: *mut c_char) -> String {
+        assert!(!error.is_null());
+        let message = unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { quilkin_error_free(error) };
+        message
+    }
+
+    #[test]
+    fn create_run_and_shutdown() {
+        let mut error = ptr::null_mut();
+        let invalid = CString::new("version: v1alpha1").unwrap();
+        assert!(unsafe { quilkin_proxy_new(invalid.as_ptr(), &mut error) }.is_null());
+        assert!(message(error).contains("must be set"));
+
+        let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+        echo.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let config = CString::new(format!(
+            "
+version: v1alpha1
+proxy:
+  port: 12372
+admin:
+  address: 127.0.0.1:0
+static:
+  endpoints:
+    - address: {}
+",
+            echo.local_addr().unwrap()
+        ))
+        .unwrap();
+
+        let mut error = ptr::null_mut();
+        let proxy = unsafe { quilkin_proxy_new(config.as_ptr(), &mut error) };
+        assert!(!proxy.is_null());
+        assert!(error.is_null());
+        assert_eq!(0, unsafe { quilkin_proxy_run(proxy, ptr::null_mut()) });
+        assert_ne!(0, unsafe { quilkin_proxy_run(proxy, &mut error) });
+        assert_eq!("the proxy has already been run", message(error));
+
+        // Packets may be sent before the proxy has bound its socket.
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buffer = [0; 16];
+        let received = (0..50).any(|_| {
+            client.send_to(b"hello", "127.0.0.1:12372").unwrap();
+            echo.recv_from(&mut buffer).is_ok()
+        });
+        assert!(received);
+        assert_eq!(b"hello", &buffer[..5]);
+
+        assert_eq!(0, unsafe { quilkin_proxy_shutdown(proxy, ptr::null_mut()) });
+        unsafe { quilkin_proxy_free(proxy) };
+    }
+}
@@ -17,6 +17,7 @@
 //! Filters for processing packets.
 
 mod config;
+mod drop_reason;
 mod error;
 mod factory;
 mod read;
@@ -28,18 +29,21 @@ pub(crate) mod chain;
 pub(crate) mod manager;
 
 pub mod extensions;
+pub mod plugin;
 
 /// Prelude containing all types and traits required to implement [`Filter`] and
 /// [`FilterFactory`].
 pub mod prelude {
     pub use super::{
-        ConvertProtoConfigError, CreateFilterArgs, Error, Filter, FilterFactory, ReadContext,
-        ReadResponse, WriteContext, WriteResponse,
+        ConvertProtoConfigError, CreateFilterArgs, DropReason, Error, Filter, FilterFactory,
+        ReadContext, ReadResponse, WriteContext, WriteResponse,
     };
 }
 
 pub use self::{
+    chain::{Error as FilterChainError, FilterChain},
     config::ConfigType,
+    drop_reason::DropReason,
     error::{ConvertProtoConfigError, Error},
     factory::{CreateFilterArgs, DynFilterFactory, FilterFactory},
     read::{ReadContext, ReadResponse},
@@ -48,8 +52,6 @@ pub use self::{
     write::{WriteContext, WriteResponse},
 };
 
-pub(crate) use self::chain::FilterChain;
-
 /// Filter is a trait for routing and manipulating packets.
 pub trait Filter: Send + Sync {
     /// Read is invoked when the proxy receives data from a downstream connection on the
@@ -57,7 +59,8 @@ pub trait Filter: Send + Sync {
     /// This function should return a [`ReadResponse`] containing the array of
     /// endpoints that the packet should be sent to and the packet that should be
     /// sent (which may be manipulated) as well.
-    /// If the packet should be rejected, return None.
+    /// If the packet should be rejected, return None, preferably through
+    /// [`DropReason::drop_packet`] so that the drop is counted with a reason.
     /// By default, passes the context through unchanged
     fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
         Some(ctx.into())
@@ -67,7 +70,8 @@ pub trait Filter: Send + Sync {
     /// via the listening port after receiving it via one of the upstream Endpoints.
     /// This function should return an [`WriteResponse`] containing the packet to
     /// be sent (which may be manipulated).
-    /// If the packet should be rejected, return None.
+    /// If the packet should be rejected, return None, preferably through
+    /// [`DropReason::drop_packet`] so that the drop is counted with a reason.
     /// By default, passes the context through unchanged
     fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
         Some(ctx.into())
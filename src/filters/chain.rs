@@ -14,30 +14,79 @@
  * limitations under the License.
  */
 
-use prometheus::{Error as PrometheusError, Histogram, HistogramOpts, Registry};
+use prometheus::{Error as PrometheusError, Histogram, HistogramOpts, IntCounterVec, Registry};
 
 use crate::config::{Filter as FilterConfig, ValidationError};
 use crate::filters::{prelude::*, FilterRegistry};
-use crate::metrics::CollectorExt;
+use crate::metrics::{opts, CollectorExt};
 
 const FILTER_LABEL: &str = "filter";
 
+/// What secrets in filter configurations are reported as.
+const REDACTED: &str = "<redacted>";
+
 /// A chain of [`Filter`]s to be executed in order.
 ///
 /// Executes each filter, passing the [`ReadContext`] and [`WriteContext`]
 /// between each filter's execution, returning the result of data that has gone
 /// through all of the filters in the chain. If any of the filters in the chain
 /// return `None`, then the chain is broken, and `None` is returned.
+///
+/// A chain can be used on its own, to run packets that a service handles
+/// itself through Quilkin's filters:
+///
+/// ```rust
+/// use quilkin::config::{Endpoint, Endpoints, Filter as FilterConfig};
+/// use quilkin::filters::{Filter, FilterChain, FilterRegistry, FilterSet, ReadContext, WriteContext};
+///
+/// let log = quilkin::proxy::logger();
+/// let chain = FilterChain::try_create(
+///     vec![FilterConfig {
+///         name: "quilkin.extensions.filters.concatenate_bytes.v1alpha1.ConcatenateBytes".into(),
+///         config: serde_yaml::from_str("{on_read: APPEND, bytes: YWJj}").unwrap(),
+///     }],
+///     &FilterRegistry::new(FilterSet::default(&log)),
+///     &prometheus::Registry::default(),
+/// )
+/// .unwrap();
+///
+/// let endpoint = Endpoint::from_address("127.0.0.1:7777".parse().unwrap());
+/// let response = chain
+///     .read(ReadContext::new(
+///         Endpoints::new(vec![endpoint.clone()]).unwrap().into(),
+///         "127.0.0.1:7000".parse().unwrap(),
+///         b"hello".to_vec(),
+///     ))
+///     .unwrap();
+/// assert_eq!(b"helloabc", &*response.contents);
+///
+/// let response = chain
+///     .write(WriteContext::new(
+///         &endpoint,
+///         endpoint.address,
+///         "127.0.0.1:7000".parse().unwrap(),
+///         b"world".to_vec(),
+///     ))
+///     .unwrap();
+/// assert_eq!(b"world", &*response.contents);
+/// ```
 pub struct FilterChain {
     filters: Vec<(String, Box<dyn Filter>)>,
+    /// The configuration each filter was created from, if known, as reported
+    /// by the admin server.
+    configs: Vec<Option<serde_json::Value>>,
     filter_read_duration_seconds: Vec<Histogram>,
     filter_write_duration_seconds: Vec<Histogram>,
+    packets_dropped_total: IntCounterVec,
 }
 
+/// The reason a [`FilterChain`] could not be created.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    /// The metrics of the chain could not be registered.
     #[error("{}", .0)]
     Prometheus(PrometheusError),
+    /// A filter could not be created from its configuration.
     #[error("failed to create filter {}: {}", filter_name, error)]
     Filter {
         filter_name: String,
@@ -52,6 +101,8 @@ impl From<PrometheusError> for Error {
 }
 
 impl FilterChain {
+    /// Creates a chain of `filters`, named by the factories they were
+    /// created by, whose metrics are registered in `registry`.
     pub fn new(
         filters: Vec<(String, Box<dyn Filter>)>,
         registry: &Registry,
@@ -83,6 +134,17 @@ impl FilterChain {
                     .and_then(|histogram| histogram.register_if_not_exists(&registry))
                 })
                 .collect::<Result<_, prometheus::Error>>()?,
+            packets_dropped_total: IntCounterVec::new(
+                opts(
+                    "packets_dropped_total",
+                    "filter",
+                    "Total number of packets dropped by a filter, by the direction of the packet \
+                     and the reason it was dropped for.",
+                ),
+                &[FILTER_LABEL, "direction", "reason"],
+            )?
+            .register_if_not_exists(registry)?,
+            configs: vec![None; filters.len()],
             filters,
         })
     }
@@ -95,12 +157,20 @@ impl FilterChain {
         metrics_registry: &Registry,
     ) -> Result<Self, Error> {
         let mut filters = Vec::new();
+        let mut configs = Vec::new();
 
         for filter_config in filter_configs {
+            // Configs with keys that are not strings cannot be written as
+            // JSON, they are reported as unknown instead.
+            configs.push(
+                filter_config
+                    .config
+                    .as_ref()
+                    .and_then(|config| serde_json::to_value(config).ok()),
+            );
             match filter_registry.get(
                 &filter_config.name,
-                CreateFilterArgs::fixed(metrics_registry.clone(), filter_config.config.as_ref())
-                    .with_metrics_registry(metrics_registry.clone()),
+                CreateFilterArgs::fixed(metrics_registry.clone(), filter_config.config.as_ref()),
             ) {
                 Ok(filter) => filters.push((filter_config.name, filter)),
                 Err(err) => {
@@ -112,36 +182,89 @@ impl FilterChain {
             }
         }
 
-        FilterChain::new(filters, &metrics_registry)
+        Ok(FilterChain::new(filters, &metrics_registry)?.with_configs(configs))
+    }
+
+    /// Sets the configuration each filter was created from, in order.
+    pub(crate) fn with_configs(mut self, configs: Vec<Option<serde_json::Value>>) -> Self {
+        self.configs = configs;
+        self
+    }
+
+    /// Replaces every string in the filter configurations that is one of
+    /// `secrets`, so that they are not reported.
+    pub(crate) fn redact(&mut self, secrets: &[String]) {
+        fn redact(value: &mut serde_json::Value, secrets: &[String]) {
+            match value {
+                serde_json::Value::String(value) if secrets.contains(value) => {
+                    *value = REDACTED.into();
+                }
+                serde_json::Value::Array(values) => {
+                    values.iter_mut().for_each(|value| redact(value, secrets))
+                }
+                serde_json::Value::Object(values) => {
+                    values.values_mut().for_each(|value| redact(value, secrets))
+                }
+                _ => {}
+            }
+        }
+
+        for config in self.configs.iter_mut().flatten() {
+            redact(config, secrets);
+        }
+    }
+
+    /// Returns the name of each filter in the chain along with the
+    /// configuration it was created from, if known.
+    pub(crate) fn configs(&self) -> impl Iterator<Item = (&str, Option<&serde_json::Value>)> {
+        self.filters
+            .iter()
+            .zip(&self.configs)
+            .map(|((name, _), config)| (name.as_str(), config.as_ref()))
+    }
+
+    /// Counts a packet dropped by the filter named `name`, with the reason
+    /// the filter gave for it, and drops it for the same reason.
+    fn dropped<T>(&self, name: &str, direction: &str) -> Option<T> {
+        let reason = DropReason::take().unwrap_or(DropReason::UNSPECIFIED);
+        self.packets_dropped_total
+            .with_label_values(&[name, direction, reason.as_str()])
+            .inc();
+        reason.drop_packet()
     }
 }
 
 impl Filter for FilterChain {
     fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+        // Clear a reason no chain has taken, so it is not counted again.
+        DropReason::take();
         self.filters
             .iter()
             .zip(self.filter_read_duration_seconds.iter())
-            .try_fold(ctx, |ctx, ((_, filter), histogram)| {
-                Some(ReadContext::with_response(
-                    ctx.from,
-                    histogram.observe_closure_duration(|| filter.read(ctx))?,
-                ))
+            .try_fold(ctx, |ctx, ((name, filter), histogram)| {
+                let from = ctx.from;
+                match histogram.observe_closure_duration(|| filter.read(ctx)) {
+                    Some(response) => Some(ReadContext::with_response(from, response)),
+                    None => self.dropped(name, "read"),
+                }
             })
             .map(ReadResponse::from)
     }
 
     fn write(&self, ctx: WriteContext) -> Option<WriteResponse> {
+        DropReason::take();
         self.filters
             .iter()
             .rev()
             .zip(self.filter_write_duration_seconds.iter().rev())
-            .try_fold(ctx, |ctx, ((_, filter), histogram)| {
-                Some(WriteContext::with_response(
-                    ctx.endpoint,
-                    ctx.from,
-                    ctx.to,
-                    histogram.observe_closure_duration(|| filter.write(ctx))?,
-                ))
+            .try_fold(ctx, |ctx, ((name, filter), histogram)| {
+                let (endpoint, from, to) = (ctx.endpoint, ctx.from, ctx.to);
+                match histogram.observe_closure_duration(|| filter.write(ctx)) {
+                    Some(response) => {
+                        Some(WriteContext::with_response(endpoint, from, to, response))
+                    }
+                    None => self.dropped(name, "write"),
+                }
             })
             .map(WriteResponse::from)
     }
@@ -184,6 +307,37 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn redact_configs() {
+        let log = logger();
+        let registry = FilterRegistry::new(FilterSet::default(&log));
+        let filter_configs = vec![
+            config::Filter {
+                name: DebugFactory::new(&log).name().into(),
+                config: Some(serde_yaml::from_str("id: hunter2").unwrap()),
+            },
+            config::Filter {
+                name: DebugFactory::new(&log).name().into(),
+                config: None,
+            },
+        ];
+        let mut chain =
+            FilterChain::try_create(filter_configs, &registry, &Registry::default()).unwrap();
+        chain.redact(&["hunter2".into()]);
+
+        let configs: Vec<_> = chain.configs().collect();
+        assert_eq!(
+            vec![
+                (
+                    "quilkin.extensions.filters.debug.v1alpha1.Debug",
+                    Some(&serde_json::json!({ "id": "<redacted>" }))
+                ),
+                ("quilkin.extensions.filters.debug.v1alpha1.Debug", None),
+            ],
+            configs
+        );
+    }
+
     fn endpoints() -> Vec<Endpoint> {
         vec![
             Endpoint::from_address("127.0.0.1:80".parse().unwrap()),
@@ -216,7 +370,7 @@ mod tests {
         );
         assert_eq!(
             "hello:odr:127.0.0.1:70",
-            from_utf8(response.contents.as_slice()).unwrap()
+            from_utf8(response.contents.as_ref()).unwrap()
         );
         assert_eq!(
             "receive",
@@ -242,7 +396,7 @@ mod tests {
         );
         assert_eq!(
             "hello:our:127.0.0.1:80:127.0.0.1:70",
-            from_utf8(response.contents.as_slice()).unwrap()
+            from_utf8(response.contents.as_ref()).unwrap()
         );
     }
 
@@ -275,7 +429,7 @@ mod tests {
         );
         assert_eq!(
             "hello:odr:127.0.0.1:70:odr:127.0.0.1:70",
-            from_utf8(response.contents.as_slice()).unwrap()
+            from_utf8(response.contents.as_ref()).unwrap()
         );
         assert_eq!(
             "receive:receive",
@@ -294,7 +448,7 @@ mod tests {
             .unwrap();
         assert_eq!(
             "hello:our:127.0.0.1:80:127.0.0.1:70:our:127.0.0.1:80:127.0.0.1:70",
-            from_utf8(response.contents.as_slice()).unwrap()
+            from_utf8(response.contents.as_ref()).unwrap()
         );
         assert_eq!(
             "receive:receive",
@@ -303,4 +457,69 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn chain_counts_dropped_packets() {
+        struct Drop;
+        impl Filter for Drop {
+            fn read(&self, _: ReadContext) -> Option<ReadResponse> {
+                None
+            }
+            fn write(&self, _: WriteContext) -> Option<WriteResponse> {
+                DropReason::new("chain_test_dropped").drop_packet()
+            }
+        }
+
+        let new_chain = |registry| {
+            FilterChain::new(
+                vec![
+                    ("TestFilter".into(), Box::new(TestFilter {})),
+                    ("Drop".into(), Box::new(Drop)),
+                ],
+                registry,
+            )
+            .unwrap()
+        };
+        let registry = prometheus::Registry::default();
+        let chain = new_chain(&registry);
+        let other_chain = new_chain(&prometheus::Registry::default());
+        let dropped = |chain: &FilterChain, direction, reason| {
+            chain
+                .packets_dropped_total
+                .with_label_values(&["Drop", direction, reason])
+                .get()
+        };
+
+        let endpoints_fixture = endpoints();
+        assert!(chain
+            .read(ReadContext::new(
+                upstream_endpoints(endpoints_fixture.clone()),
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .is_none());
+        assert_eq!(1, dropped(&chain, "read", "unspecified"));
+
+        assert!(chain
+            .write(WriteContext::new(
+                &endpoints_fixture[0],
+                endpoints_fixture[0].address,
+                "127.0.0.1:70".parse().unwrap(),
+                b"hello".to_vec(),
+            ))
+            .is_none());
+        assert_eq!(1, dropped(&chain, "write", "chain_test_dropped"));
+        assert_eq!(0, dropped(&other_chain, "write", "chain_test_dropped"));
+
+        // The drops are exported by the registry the chain was created with.
+        let exported = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "quilkin_filter_packets_dropped_total")
+            .unwrap();
+        assert!(exported.get_metric().iter().any(|metric| metric
+            .get_label()
+            .iter()
+            .any(|label| label.get_value() == "chain_test_dropped")));
+    }
 }
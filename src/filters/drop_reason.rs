@@ -0,0 +1,84 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::Cell;
+use std::fmt;
+
+#[cfg(doc)]
+use crate::filters::Filter;
+
+thread_local! {
+    /// The reason the packet a filter on this thread last dropped was dropped
+    /// for. Filters run synchronously, so the filter chain that ran the filter
+    /// takes the reason on the same thread, then sets it again for its caller.
+    static DROPPED: Cell<Option<DropReason>> = Cell::new(None);
+}
+
+/// Why a [`Filter`] dropped a packet, such as `decompress_failed` or
+/// `rate_limited`.
+///
+/// A filter drops a packet for a reason by returning the result of
+/// [`DropReason::drop_packet`], which the filter chain counts the drop by.
+/// Reasons are fixed, short `snake_case` strings rather than a description of
+/// a single packet, which should be logged instead.
+///
+/// ```rust
+/// # use quilkin::filters::{DropReason, ReadContext, ReadResponse};
+/// const EMPTY_PACKET: DropReason = DropReason::new("empty_packet");
+///
+/// fn read(ctx: ReadContext) -> Option<ReadResponse> {
+///     if ctx.contents.is_empty() {
+///         return EMPTY_PACKET.drop_packet();
+///     }
+///     Some(ctx.into())
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DropReason(&'static str);
+
+impl DropReason {
+    /// The reason drops are counted with when a filter did not give one.
+    pub(crate) const UNSPECIFIED: DropReason = DropReason::new("unspecified");
+
+    /// Returns a reason named `reason`.
+    pub const fn new(reason: &'static str) -> Self {
+        Self(reason)
+    }
+
+    /// Returns the name of the reason.
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+    /// Drops the packet being processed for this reason, returning `None` for
+    /// a filter to return from [`Filter::read`] or [`Filter::write`].
+    pub fn drop_packet<T>(self) -> Option<T> {
+        DROPPED.with(|dropped| dropped.set(Some(self)));
+        None
+    }
+
+    /// Takes the reason the last packet dropped on this thread was dropped
+    /// for, if the filter that dropped it gave one.
+    pub(crate) fn take() -> Option<Self> {
+        DROPPED.with(Cell::take)
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
@@ -17,17 +17,21 @@
 //! Useful filters for common operations.
 
 pub use capture_bytes::CaptureBytesFactory;
+pub use client_address::ClientAddressFactory;
 pub use compress::CompressFactory;
 pub use concatenate_bytes::ConcatBytesFactory;
 pub use debug::DebugFactory;
+pub use external_authorization::ExternalAuthorizationFactory;
 pub use load_balancer::LoadBalancerFilterFactory;
 pub use local_rate_limit::RateLimitFilterFactory;
-pub use token_router::TokenRouterFactory;
+pub use token_router::{TokenProvider, TokenRoute, TokenRouterFactory};
 
 mod capture_bytes;
+mod client_address;
 mod compress;
 mod concatenate_bytes;
 mod debug;
+mod external_authorization;
 mod load_balancer;
 mod local_rate_limit;
 mod token_router;
@@ -17,6 +17,7 @@
 use std::convert::TryFrom;
 use std::sync::Arc;
 
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use slog::{o, warn, Logger};
 
@@ -111,6 +112,36 @@ impl CaptureBytesFactory {
     }
 }
 
+/// The schema of the filter's configuration.
+const CONFIG_SCHEMA: &str = "\
+properties:
+  strategy:
+    type: string
+    description: |
+      The selected strategy for capturing the series of bytes from the incoming packet.
+       - SUFFIX: Retrieve bytes from the end of the packet.
+       - PREFIX: Retrieve bytes from the beginnning of the packet.
+    default: SUFFIX
+    enum: [PREFIX, SUFFIX]
+  metadataKey:
+    type: string
+    default: quilkin.dev/captured_bytes
+    description: |
+      The key under which the captured bytes are stored in the Filter invocation values.
+  size:
+    type: integer
+    description: |
+      The number of bytes in the packet to capture using the applied strategy.
+    example: 3
+  remove:
+    type: boolean
+    default: false
+    description: |
+      Whether or not to remove the captured bytes from the packet before passing it along to the next filter in the
+      chain.
+required: [size]
+";
+
 impl FilterFactory for CaptureBytesFactory {
     fn name(&self) -> &'static str {
         CaptureBytes::FILTER_NAME
@@ -124,6 +155,10 @@ impl FilterFactory for CaptureBytesFactory {
             Metrics::new(&args.metrics_registry)?,
         )))
     }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(CONFIG_SCHEMA)
+    }
 }
 
 #[crate::filter("quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes")]
@@ -145,7 +180,8 @@ impl CaptureBytes {
         };
 
         CaptureBytes {
-            log: base.new(o!("source" => "extensions::CaptureBytes")),
+            log: base
+                .new(o!("source" => "extensions::CaptureBytes", "filter" => Self::FILTER_NAME)),
             capture,
             metrics,
             metadata_key: Arc::new(config.metadata_key),
@@ -155,6 +191,9 @@ impl CaptureBytes {
     }
 }
 
+/// Packets shorter than the number of bytes to capture.
+const PACKET_TOO_SMALL: DropReason = DropReason::new("packet_too_small");
+
 impl Filter for CaptureBytes {
     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
         // if the capture size is bigger than the packet size, then we drop the packet,
@@ -168,7 +207,7 @@ impl Filter for CaptureBytes {
                 );
             }
             self.metrics.packets_dropped_total.inc();
-            return None;
+            return PACKET_TOO_SMALL.drop_packet();
         }
         let token = self
             .capture
@@ -184,34 +223,30 @@ impl Filter for CaptureBytes {
 /// Trait to implement different strategies for capturing packet data
 trait Capture {
     /// Capture the packet data from the contents. If remove is true, contents will be altered to
-    /// not have the retrieved set of bytes.
+    /// not have the retrieved set of bytes, without copying the remaining bytes.
     /// Returns the captured bytes.
-    fn capture(&self, contents: &mut Vec<u8>, size: usize, remove: bool) -> Vec<u8>;
+    fn capture(&self, contents: &mut Bytes, size: usize, remove: bool) -> Vec<u8>;
 }
 
 struct Suffix;
 impl Capture for Suffix {
-    fn capture(&self, contents: &mut Vec<u8>, size: usize, remove: bool) -> Vec<u8> {
+    fn capture(&self, contents: &mut Bytes, size: usize, remove: bool) -> Vec<u8> {
         if remove {
-            return contents.split_off(contents.len() - size);
+            return contents.split_off(contents.len() - size).to_vec();
         }
 
-        contents
-            .iter()
-            .skip(contents.len() - size)
-            .cloned()
-            .collect::<Vec<u8>>()
+        contents[contents.len() - size..].to_vec()
     }
 }
 
 struct Prefix;
 impl Capture for Prefix {
-    fn capture(&self, contents: &mut Vec<u8>, size: usize, remove: bool) -> Vec<u8> {
+    fn capture(&self, contents: &mut Bytes, size: usize, remove: bool) -> Vec<u8> {
         if remove {
-            return contents.drain(..size).collect();
+            return contents.split_to(size).to_vec();
         }
 
-        contents.iter().cloned().take(size).collect()
+        contents[..size].to_vec()
     }
 }
 
@@ -220,15 +255,16 @@ mod tests {
     use std::convert::TryFrom;
     use std::sync::Arc;
 
+    use bytes::Bytes;
     use prometheus::Registry;
     use serde_yaml::{Mapping, Value};
 
     use crate::config::Endpoints;
-    use crate::test_utils::{assert_write_no_change, logger};
+    use crate::test_utils::{assert_write_no_change, drop_reason, logger};
 
     use super::{
         default_metadata_key, default_remove, Capture, CaptureBytes, CaptureBytesFactory, Config,
-        Metrics, Prefix, Strategy, Suffix,
+        Metrics, Prefix, Strategy, Suffix, PACKET_TOO_SMALL,
     };
 
     use super::proto::quilkin::extensions::filters::capture_bytes::v1alpha1::{
@@ -389,7 +425,7 @@ mod tests {
             "abc".to_string().into_bytes(),
         ));
 
-        assert!(response.is_none());
+        assert_eq!(Some(PACKET_TOO_SMALL), drop_reason(response));
         let count = filter.metrics.packets_dropped_total.get();
         assert_eq!(1, count);
     }
@@ -409,7 +445,7 @@ mod tests {
     #[test]
     fn end_capture() {
         let end = Suffix {};
-        let mut contents = b"helloabc".to_vec();
+        let mut contents = Bytes::from_static(b"helloabc");
         let result = end.capture(&mut contents, 3, false);
         assert_eq!(b"abc".to_vec(), result);
         assert_eq!(b"helloabc".to_vec(), contents);
@@ -422,7 +458,7 @@ mod tests {
     #[test]
     fn beginning_capture() {
         let beg = Prefix {};
-        let mut contents = b"abchello".to_vec();
+        let mut contents = Bytes::from_static(b"abchello");
 
         let result = beg.capture(&mut contents, 3, false);
         assert_eq!(b"abc".to_vec(), result);
@@ -0,0 +1,299 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::filters::prelude::*;
+
+crate::include_proto!("quilkin.extensions.filters.client_address.v1alpha1");
+use self::quilkin::extensions::filters::client_address::v1alpha1::ClientAddress as ProtoConfig;
+
+/// Identifies a packet that starts with a client address header.
+const MAGIC: [u8; 2] = *b"QK";
+/// The version of the header format.
+const VERSION: u8 = 1;
+const FAMILY_IPV4: u8 = 4;
+const FAMILY_IPV6: u8 = 6;
+/// The length of the magic, version and address family fields.
+const PREAMBLE_LEN: usize = 4;
+
+/// Config represents a [`ClientAddress`] filter configuration.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Whether to remove a header from the start of packets sent back by
+    /// upstream endpoints.
+    #[serde(default = "default_strip_responses")]
+    strip_responses: bool,
+}
+
+/// default value for [`Config::strip_responses`].
+fn default_strip_responses() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            strip_responses: default_strip_responses(),
+        }
+    }
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            strip_responses: p.strip_responses.unwrap_or_else(default_strip_responses),
+        })
+    }
+}
+
+/// The `ClientAddress` filter prepends a header carrying the address of the
+/// client to every packet sent to an upstream endpoint, so game servers can
+/// see who they are talking to through the proxy.
+#[crate::filter("quilkin.extensions.filters.client_address.v1alpha1.ClientAddress")]
+struct ClientAddress {
+    strip_responses: bool,
+}
+
+impl ClientAddress {
+    fn new(config: Config) -> Self {
+        Self {
+            strip_responses: config.strip_responses,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ClientAddressFactory;
+
+/// The schema of the filter's configuration.
+const CONFIG_SCHEMA: &str = "\
+properties:
+  strip_responses:
+    type: boolean
+    description: |
+      Whether to remove a header from the start of packets sent back by upstream endpoints.
+    default: true
+";
+
+impl FilterFactory for ClientAddressFactory {
+    fn name(&self) -> &'static str {
+        ClientAddress::FILTER_NAME
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config = args
+            .config
+            .map(|config| config.deserialize::<Config, ProtoConfig>(self.name()))
+            .transpose()?
+            .unwrap_or_default();
+        Ok(Box::new(ClientAddress::new(config)))
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(CONFIG_SCHEMA)
+    }
+}
+
+impl Filter for ClientAddress {
+    fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        ctx.contents = prepend_header(ctx.from, &ctx.contents);
+        Some(ctx.into())
+    }
+
+    fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+        if self.strip_responses {
+            ctx.contents = strip_header(&ctx.contents);
+        }
+        Some(ctx.into())
+    }
+}
+
+/// Returns `contents` preceded by a header for `addr`. The header consists of
+/// the magic bytes `QK`, the version, the address family (4 or 6), the IP
+/// address and the port, with all numbers in network byte order.
+fn prepend_header(addr: SocketAddr, contents: &[u8]) -> Bytes {
+    let mut packet = BytesMut::with_capacity(PREAMBLE_LEN + 16 + 2 + contents.len());
+    packet.extend_from_slice(&MAGIC);
+    packet.extend_from_slice(&[VERSION]);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            packet.extend_from_slice(&[FAMILY_IPV4]);
+            packet.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            packet.extend_from_slice(&[FAMILY_IPV6]);
+            packet.extend_from_slice(&ip.octets());
+        }
+    }
+    packet.extend_from_slice(&addr.port().to_be_bytes());
+    packet.extend_from_slice(contents);
+    packet.freeze()
+}
+
+/// Returns `contents` without its header, or unchanged if it does not start
+/// with a header.
+fn strip_header(contents: &Bytes) -> Bytes {
+    if contents.len() < PREAMBLE_LEN || contents[..2] != MAGIC || contents[2] != VERSION {
+        return contents.clone();
+    }
+    let header_len = match contents[3] {
+        FAMILY_IPV4 => PREAMBLE_LEN + 4 + 2,
+        FAMILY_IPV6 => PREAMBLE_LEN + 16 + 2,
+        _ => return contents.clone(),
+    };
+    if contents.len() < header_len {
+        return contents.clone();
+    }
+    contents.slice(header_len..)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use prometheus::Registry;
+    use serde_yaml::{Mapping, Value};
+
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::{CreateFilterArgs, Filter, FilterFactory, ReadContext, WriteContext};
+    use crate::test_utils::assert_write_no_change;
+
+    use super::{prepend_header, strip_header, ClientAddress, ClientAddressFactory, Config};
+
+    #[test]
+    fn prepend_ipv4_header() {
+        let packet = prepend_header("192.0.2.1:8080".parse().unwrap(), b"hello");
+        assert_eq!(
+            &[b'Q', b'K', 1, 4, 192, 0, 2, 1, 0x1f, 0x90][..],
+            &packet[..10]
+        );
+        assert_eq!(b"hello", &packet[10..]);
+    }
+
+    #[test]
+    fn prepend_ipv6_header() {
+        let packet = prepend_header("[2001:db8::1]:8080".parse().unwrap(), b"hello");
+        assert_eq!(
+            &[b'Q', b'K', 1, 6, 0x20, 0x01, 0x0d, 0xb8][..],
+            &packet[..8]
+        );
+        assert_eq!(&[0, 1, 0x1f, 0x90][..], &packet[18..22]);
+        assert_eq!(b"hello", &packet[22..]);
+    }
+
+    #[test]
+    fn strip() {
+        for addr in &["192.0.2.1:8080", "[2001:db8::1]:8080"] {
+            let packet = prepend_header(addr.parse().unwrap(), b"hello");
+            assert_eq!(Bytes::from_static(b"hello"), strip_header(&packet));
+        }
+
+        // Packets without a complete header are left alone.
+        for contents in &[&b"hello"[..], b"QK", b"QK\x01\x04\x7f", b"QK\x02\x04hello"] {
+            let contents = Bytes::copy_from_slice(contents);
+            assert_eq!(contents, strip_header(&contents));
+        }
+    }
+
+    #[test]
+    fn read_prepends_header() {
+        let filter = ClientAddress::new(Config::default());
+        let from = "127.0.0.1:90".parse().unwrap();
+        let response = filter
+            .read(ReadContext::new(
+                Endpoints::new(vec![Endpoint::from_address(
+                    "127.0.0.1:80".parse().unwrap(),
+                )])
+                .unwrap()
+                .into(),
+                from,
+                b"hello".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(prepend_header(from, b"hello"), response.contents);
+    }
+
+    #[test]
+    fn write_strips_header() {
+        let filter = ClientAddress::new(Config::default());
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        let to = "127.0.0.1:90".parse().unwrap();
+        let response = filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                to,
+                prepend_header(to, b"hello"),
+            ))
+            .unwrap();
+        assert_eq!(Bytes::from_static(b"hello"), response.contents);
+
+        assert_write_no_change(&filter);
+    }
+
+    #[test]
+    fn write_keeps_header() {
+        let filter = ClientAddress::new(Config {
+            strip_responses: false,
+        });
+        let endpoint = Endpoint::from_address("127.0.0.1:80".parse().unwrap());
+        let to = "127.0.0.1:90".parse().unwrap();
+        let contents = prepend_header(to, b"hello");
+        let response = filter
+            .write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                to,
+                contents.clone(),
+            ))
+            .unwrap();
+        assert_eq!(contents, response.contents);
+    }
+
+    #[test]
+    fn factory() {
+        let factory = ClientAddressFactory::default();
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), None))
+            .is_ok());
+
+        let mut map = Mapping::new();
+        map.insert(Value::from("strip_responses"), Value::from(false));
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(
+                Registry::default(),
+                Some(&Value::Mapping(map)),
+            ))
+            .is_ok());
+
+        let mut map = Mapping::new();
+        map.insert(Value::from("strip"), Value::from(false));
+        assert!(factory
+            .create_filter(CreateFilterArgs::fixed(
+                Registry::default(),
+                Some(&Value::Mapping(map)),
+            ))
+            .is_err());
+    }
+}
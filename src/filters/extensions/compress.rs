@@ -17,6 +17,7 @@
 use std::convert::TryFrom;
 use std::io;
 
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use slog::{o, warn, Logger};
 use snap::read::FrameDecoder;
@@ -140,6 +141,29 @@ impl CompressFactory {
     }
 }
 
+/// The schema of the filter's configuration.
+const CONFIG_SCHEMA: &str = "\
+properties:
+  on_read:
+    type: string
+    description: |
+      Whether to compress, decompress or do nothing when reading packets from the local listening port
+    enum: [DO_NOTHING, COMPRESS, DECOMPRESS]
+    default: DO_NOTHING
+  on_write:
+    type: string
+    description: |
+      Whether to compress, decompress or do nothing when writing packets to the local listening port
+    enum: [DO_NOTHING, COMPRESS, DECOMPRESS]
+    default: DO_NOTHING
+  mode:
+    type: string
+    description: |
+      The compression implementation to use on the incoming and outgoing packets.
+    enum: [SNAPPY]
+    default: SNAPPY
+";
+
 impl FilterFactory for CompressFactory {
     fn name(&self) -> &'static str {
         Compress::FILTER_NAME
@@ -153,6 +177,10 @@ impl FilterFactory for CompressFactory {
             Metrics::new(&args.metrics_registry)?,
         )))
     }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(CONFIG_SCHEMA)
+    }
 }
 
 /// Filter for compressing and decompressing packet data
@@ -172,7 +200,7 @@ impl Compress {
             Mode::Snappy => Box::new(Snappy {}),
         };
         Compress {
-            log: base.new(o!("source" => "extensions::Compress")),
+            log: base.new(o!("source" => "extensions::Compress", "filter" => Self::FILTER_NAME)),
             metrics,
             compression_mode: config.mode,
             on_read: config.on_read,
@@ -189,7 +217,7 @@ impl Compress {
                             "count" => self.metrics.packets_dropped_compress.get());
         }
         self.metrics.packets_dropped_compress.inc();
-        None
+        COMPRESS_FAILED.drop_packet()
     }
 
     /// Track a failed attempt at decompression
@@ -200,10 +228,16 @@ impl Compress {
                             "count" => self.metrics.packets_dropped_decompress.get());
         }
         self.metrics.packets_dropped_decompress.inc();
-        None
+        DECOMPRESS_FAILED.drop_packet()
     }
 }
 
+/// Packets that could not be compressed.
+const COMPRESS_FAILED: DropReason = DropReason::new("compress_failed");
+/// Packets that could not be decompressed, such as ones that were never
+/// compressed.
+const DECOMPRESS_FAILED: DropReason = DropReason::new("decompress_failed");
+
 impl Filter for Compress {
     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
         let original_size = ctx.contents.len();
@@ -276,26 +310,30 @@ type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
 /// Conversion takes place on a mutable Vec, to ensure the most performant compression or
 /// decompression operation can occur.
 trait Compressor {
-    /// Compress the contents of the Vec - overwriting the original content.
-    fn encode(&self, contents: &mut Vec<u8>) -> Result<()>;
-    /// Decompress the contents of the Vec - overwriting the original content.
-    fn decode(&self, contents: &mut Vec<u8>) -> Result<()>;
+    /// Compress the contents - overwriting the original content.
+    fn encode(&self, contents: &mut Bytes) -> Result<()>;
+    /// Decompress the contents - overwriting the original content.
+    fn decode(&self, contents: &mut Bytes) -> Result<()>;
 }
 
 struct Snappy {}
 
 impl Compressor for Snappy {
-    fn encode(&self, contents: &mut Vec<u8>) -> Result<()> {
-        let input = std::mem::take(contents);
-        let mut wtr = FrameEncoder::new(contents);
-        io::copy(&mut input.as_slice(), &mut wtr)?;
+    fn encode(&self, contents: &mut Bytes) -> Result<()> {
+        let mut output = Vec::new();
+        {
+            let mut wtr = FrameEncoder::new(&mut output);
+            io::copy(&mut contents.as_ref(), &mut wtr)?;
+        }
+        *contents = output.into();
         Ok(())
     }
 
-    fn decode(&self, contents: &mut Vec<u8>) -> Result<()> {
-        let input = std::mem::take(contents);
-        let mut rdr = FrameDecoder::new(input.as_slice());
-        io::copy(&mut rdr, contents)?;
+    fn decode(&self, contents: &mut Bytes) -> Result<()> {
+        let mut output = Vec::new();
+        let mut rdr = FrameDecoder::new(contents.as_ref());
+        io::copy(&mut rdr, &mut output)?;
+        *contents = output.into();
         Ok(())
     }
 }
@@ -304,7 +342,9 @@ impl Compressor for Snappy {
 mod tests {
     use std::convert::TryFrom;
 
+    use bytes::Bytes;
     use prometheus::Registry;
+    use proptest::prelude::*;
     use serde_yaml::{Mapping, Value};
 
     use crate::cluster::Endpoint;
@@ -313,13 +353,16 @@ mod tests {
         extensions::compress::Compressor, CreateFilterArgs, Filter, FilterFactory, ReadContext,
         WriteContext,
     };
-    use crate::test_utils::logger;
+    use crate::test_utils::prop::{check_read_read, check_read_write, packet};
+    use crate::test_utils::{drop_reason, logger};
 
     use super::quilkin::extensions::filters::compress::v1alpha1::{
         compress::{Action as ProtoAction, ActionValue, Mode as ProtoMode, ModeValue},
         Compress as ProtoConfig,
     };
-    use super::{Action, Compress, CompressFactory, Config, Metrics, Mode, Snappy};
+    use super::{
+        Action, Compress, CompressFactory, Config, Metrics, Mode, Snappy, DECOMPRESS_FAILED,
+    };
 
     #[test]
     fn convert_proto_config() {
@@ -507,7 +550,7 @@ mod tests {
             ))
             .expect("should decompress");
 
-        assert_eq!(expected, write_response.contents);
+        assert_eq!(expected, write_response.contents.to_vec());
 
         assert_eq!(0, compress.metrics.packets_dropped_decompress.get());
         assert_eq!(0, compress.metrics.packets_dropped_compress.get());
@@ -571,7 +614,7 @@ mod tests {
             b"hello".to_vec(),
         ));
 
-        assert!(write_response.is_none());
+        assert_eq!(Some(DECOMPRESS_FAILED), drop_reason(write_response));
         assert_eq!(1, compression.metrics.packets_dropped_decompress.get());
         assert_eq!(0, compression.metrics.packets_dropped_compress.get());
 
@@ -596,7 +639,7 @@ mod tests {
             b"hello".to_vec(),
         ));
 
-        assert!(read_response.is_none());
+        assert_eq!(Some(DECOMPRESS_FAILED), drop_reason(read_response));
         assert_eq!(1, compression.metrics.packets_dropped_decompress.get());
         assert_eq!(0, compression.metrics.packets_dropped_compress.get());
         assert_eq!(0, compression.metrics.compressed_bytes_total.get());
@@ -641,7 +684,7 @@ mod tests {
     #[test]
     fn snappy() {
         let expected = contents_fixture();
-        let mut contents = expected.clone();
+        let mut contents = Bytes::from(expected.clone());
         let snappy = Snappy {};
 
         let ok = snappy.encode(&mut contents);
@@ -669,6 +712,30 @@ mod tests {
         );
     }
 
+    fn compress(on_read: Action, on_write: Action) -> Compress {
+        Compress::new(
+            &logger(),
+            Config {
+                mode: Default::default(),
+                on_read,
+                on_write,
+            },
+            Metrics::new(&Registry::default()).unwrap(),
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(packet in packet()) {
+            check_read_write(&compress(Action::Compress, Action::Decompress), &packet)?;
+            check_read_read(
+                &compress(Action::Compress, Action::DoNothing),
+                &compress(Action::Decompress, Action::DoNothing),
+                &packet,
+            )?;
+        }
+    }
+
     /// At small data packets, compression will add data, so let's give a bigger data packet!
     fn contents_fixture() -> Vec<u8> {
         String::from("hello my name is mark and I like to do things")
@@ -694,7 +761,7 @@ mod tests {
             ))
             .expect("should compress");
 
-        assert_ne!(expected, write_response.contents);
+        assert_ne!(expected, write_response.contents.to_vec());
         assert!(
             expected.len() > write_response.contents.len(),
             "Original: {}. Compressed: {}",
@@ -717,6 +784,6 @@ mod tests {
             .expect("should decompress");
 
         assert_eq!(expected, read_response.contents);
-        (expected, write_response.contents)
+        (expected, write_response.contents.to_vec())
     }
 }
@@ -17,6 +17,7 @@
 use std::convert::TryFrom;
 
 use base64_serde::base64_serde_type;
+use bytes::{Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 
 use crate::filters::prelude::*;
@@ -29,7 +30,7 @@ use self::quilkin::extensions::filters::concatenate_bytes::v1alpha1::{
 
 base64_serde_type!(Base64Standard, base64::STANDARD);
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 enum Strategy {
     #[serde(rename = "APPEND")]
     Append,
@@ -116,6 +117,29 @@ impl Default for ConcatBytesFactory {
     }
 }
 
+/// The schema of the filter's configuration.
+const CONFIG_SCHEMA: &str = "\
+properties:
+  on_read:
+    type: string
+    description: |
+      Either append or prepend the `bytes` data to each packet filtered on read of the listening port.
+    default: DO_NOTHING
+    enum: [DO_NOTHING, APPEND, PREPEND]
+  on_write:
+    type: string
+    description: |
+      Either append or prepend the `bytes` data to each packet filtered on write of the listening port.
+    default: DO_NOTHING
+    enum: [DO_NOTHING, APPEND, PREPEND]
+  bytes:
+    type: string
+    description: |
+      Base64 encoded string of the byte array to add to each packet as it is filtered.
+    example: MXg3aWp5Ng==
+required: [bytes]
+";
+
 impl FilterFactory for ConcatBytesFactory {
     fn name(&self) -> &'static str {
         ConcatenateBytes::FILTER_NAME
@@ -127,6 +151,10 @@ impl FilterFactory for ConcatBytesFactory {
                 .deserialize::<Config, ProtoConfig>(self.name())?,
         )))
     }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(CONFIG_SCHEMA)
+    }
 }
 
 impl ConcatenateBytes {
@@ -141,44 +169,50 @@ impl ConcatenateBytes {
 
 impl Filter for ConcatenateBytes {
     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
-        match self.on_read {
-            Strategy::Append => {
-                ctx.contents.extend(self.bytes.iter());
-            }
-            Strategy::Prepend => {
-                ctx.contents.splice(..0, self.bytes.iter().cloned());
-            }
-            Strategy::DoNothing => {}
-        }
-
+        ctx.contents = concatenate(self.on_read, &self.bytes, &ctx.contents);
         Some(ctx.into())
     }
 
     fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
-        match self.on_write {
-            Strategy::Append => {
-                ctx.contents.extend(self.bytes.iter());
-            }
-            Strategy::Prepend => {
-                ctx.contents.splice(..0, self.bytes.iter().cloned());
-            }
-            Strategy::DoNothing => {}
-        }
-
+        ctx.contents = concatenate(self.on_write, &self.bytes, &ctx.contents);
         Some(ctx.into())
     }
 }
 
+/// Returns `contents` with `bytes` added according to `strategy`.
+fn concatenate(strategy: Strategy, bytes: &[u8], contents: &Bytes) -> Bytes {
+    let mut concatenated = BytesMut::with_capacity(contents.len() + bytes.len());
+    match strategy {
+        Strategy::Append => {
+            concatenated.extend_from_slice(contents);
+            concatenated.extend_from_slice(bytes);
+        }
+        Strategy::Prepend => {
+            concatenated.extend_from_slice(bytes);
+            concatenated.extend_from_slice(contents);
+        }
+        Strategy::DoNothing => return contents.clone(),
+    }
+    concatenated.freeze()
+}
+
 #[cfg(test)]
 mod tests {
     use std::convert::TryFrom;
 
+    use proptest::prelude::*;
     use serde_yaml::{Mapping, Value};
 
     use crate::cluster::Endpoint;
     use crate::config::Endpoints;
-    use crate::filters::{CreateFilterArgs, Filter, FilterFactory, ReadContext, WriteContext};
-    use crate::test_utils::{assert_filter_read_no_change, assert_write_no_change};
+    use crate::filters::{
+        extensions::CaptureBytesFactory, CreateFilterArgs, Filter, FilterFactory, ReadContext,
+        WriteContext,
+    };
+    use crate::test_utils::prop::{
+        check_no_change, check_read_read, packet, packet_with_metadata, token,
+    };
+    use crate::test_utils::{assert_filter_read_no_change, assert_write_no_change, logger};
 
     use super::quilkin::extensions::filters::concatenate_bytes::v1alpha1::{
         concatenate_bytes::{Strategy as ProtoStrategy, StrategyValue},
@@ -404,6 +438,45 @@ mod tests {
         assert_write_no_change(&filter);
     }
 
+    proptest! {
+        /// Bytes concatenated by one proxy are taken off again by another
+        /// capturing them.
+        #[test]
+        fn captured(packet in packet(), bytes in token()) {
+            let capture = |strategy| {
+                CaptureBytesFactory::new(&logger())
+                    .create_filter(CreateFilterArgs::fixed(
+                        Registry::default(),
+                        Some(&serde_yaml::from_str(&format!(
+                            "{{strategy: {}, size: {}, remove: true}}",
+                            strategy,
+                            bytes.len()
+                        )).unwrap()),
+                    ))
+                    .unwrap()
+            };
+            let concat = |on_read| {
+                ConcatenateBytes::new(Config {
+                    on_read,
+                    on_write: Default::default(),
+                    bytes: bytes.clone(),
+                })
+            };
+            check_read_read(&concat(Strategy::Append), capture("SUFFIX").as_ref(), &packet)?;
+            check_read_read(&concat(Strategy::Prepend), capture("PREFIX").as_ref(), &packet)?;
+        }
+
+        #[test]
+        fn do_nothing(packet in packet_with_metadata()) {
+            let filter = ConcatenateBytes::new(Config {
+                on_read: Default::default(),
+                on_write: Default::default(),
+                bytes: vec![],
+            });
+            check_no_change(&filter, &packet)?;
+        }
+    }
+
     fn assert_create_read_filter(on_read: Strategy, expected: &str) {
         let contents = b"hello".to_vec();
         let config = Config {
@@ -36,8 +36,10 @@ impl Debug {
     /// Filter.
     fn new(base: &Logger, id: Option<String>) -> Self {
         let log = match id {
-            None => base.new(o!("source" => "extensions::Debug")),
-            Some(id) => base.new(o!("source" => "extensions::Debug", "id" => id)),
+            None => base.new(o!("source" => "extensions::Debug", "filter" => Self::FILTER_NAME)),
+            Some(id) => base.new(
+                o!("source" => "extensions::Debug", "filter" => Self::FILTER_NAME, "id" => id),
+            ),
         };
 
         Debug { log }
@@ -69,6 +71,16 @@ impl DebugFactory {
     }
 }
 
+/// The schema of the filter's configuration.
+const CONFIG_SCHEMA: &str = "\
+properties:
+  id:
+    type: string
+    description: |
+      An identifier that will be included with each log message.
+    example: debug
+";
+
 impl FilterFactory for DebugFactory {
     fn name(&self) -> &'static str {
         Debug::FILTER_NAME
@@ -84,11 +96,15 @@ impl FilterFactory for DebugFactory {
             config.and_then(|cfg| cfg.id),
         )))
     }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(CONFIG_SCHEMA)
+    }
 }
 
 impl Filter for Debug {
     fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
-        info!(self.log, "Read filter event"; "from" => ctx.from, "contents" => packet_to_string(ctx.contents.clone()));
+        info!(self.log, "Read filter event"; "from" => ctx.from, "contents" => packet_to_string(&ctx.contents));
         Some(ctx.into())
     }
 
@@ -96,16 +112,16 @@ impl Filter for Debug {
         info!(self.log, "Write filter event"; "endpoint" => ctx.endpoint.address,
         "from" => ctx.from,
         "to" => ctx.to,
-        "contents" => packet_to_string(ctx.contents.clone()));
+        "contents" => packet_to_string(&ctx.contents));
         Some(ctx.into())
     }
 }
 
 /// packet_to_string takes the content, and attempts to convert it to a string.
 /// Returns a string of "error decoding packet" on failure.
-fn packet_to_string(contents: Vec<u8>) -> String {
-    match String::from_utf8(contents) {
-        Ok(str) => str,
+fn packet_to_string(contents: &[u8]) -> String {
+    match std::str::from_utf8(contents) {
+        Ok(str) => str.into(),
         Err(_) => String::from("error decoding packet"),
     }
 }
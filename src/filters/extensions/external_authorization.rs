@@ -0,0 +1,542 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod metrics;
+
+crate::include_proto!("quilkin.extensions.filters.external_authorization.v1alpha1");
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use slog::{debug, o, warn, Logger};
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tonic::transport::{Channel, Endpoint};
+
+use crate::filters::{extensions::CAPTURED_BYTES, prelude::*};
+
+use self::metrics::Metrics;
+use self::quilkin::extensions::filters::external_authorization::v1alpha1::{
+    authorization_client::AuthorizationClient, CheckRequest, ExternalAuthorization as ProtoConfig,
+};
+
+/// The most clients that can wait to be checked. Packets of clients that
+/// haven't been checked are dropped without checking them once it is reached.
+const CHECK_QUEUE_SIZE: usize = 1024;
+/// How long to wait before checking a client again after failing to.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Config represents an [`ExternalAuthorization`] filter configuration.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// The URL of the authorization service.
+    address: String,
+    /// The key under which the token is stored in the filter dynamic
+    /// metadata.
+    #[serde(rename = "metadataKey", default = "default_metadata_key")]
+    metadata_key: String,
+    /// How long to wait for the authorization service to answer.
+    #[serde(with = "humantime_serde", default = "default_timeout")]
+    timeout: Duration,
+    /// How long a client is allowed for before it is checked again.
+    #[serde(with = "humantime_serde", default = "default_allowed_ttl")]
+    allowed_ttl: Duration,
+    /// How long a client is denied for before it is checked again.
+    #[serde(with = "humantime_serde", default = "default_denied_ttl")]
+    denied_ttl: Duration,
+}
+
+/// default value for [`Config::metadata_key`].
+fn default_metadata_key() -> String {
+    CAPTURED_BYTES.into()
+}
+
+/// default value for [`Config::timeout`].
+fn default_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// default value for [`Config::allowed_ttl`].
+fn default_allowed_ttl() -> Duration {
+    Duration::from_secs(300)
+}
+
+/// default value for [`Config::denied_ttl`].
+fn default_denied_ttl() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        let duration = |value: Option<prost_types::Duration>, field: &str, default: Duration| {
+            value
+                .map(|value| {
+                    value.try_into().map_err(|err| {
+                        ConvertProtoConfigError::new(
+                            format!("invalid duration: {:?}", err),
+                            Some(field.into()),
+                        )
+                    })
+                })
+                .transpose()
+                .map(|value| value.unwrap_or(default))
+        };
+
+        Ok(Self {
+            address: p.address,
+            metadata_key: p.metadata_key.unwrap_or_else(default_metadata_key),
+            timeout: duration(p.timeout, "timeout", default_timeout())?,
+            allowed_ttl: duration(p.allowed_ttl, "allowed_ttl", default_allowed_ttl())?,
+            denied_ttl: duration(p.denied_ttl, "denied_ttl", default_denied_ttl())?,
+        })
+    }
+}
+
+/// The clients and tokens that are checked, and whether they were allowed.
+type Decisions = Arc<RwLock<HashMap<(SocketAddr, Vec<u8>), Decision>>>;
+
+struct Decision {
+    /// Whether the client was allowed, or `None` until it has been checked.
+    allowed: Option<bool>,
+    /// When the client must be checked again.
+    expires: Instant,
+    /// Whether the client is being checked.
+    checking: bool,
+}
+
+/// The `ExternalAuthorization` filter asks an authorization service whether
+/// a client may send packets with the token captured from them, and drops
+/// the packets of clients that may not.
+#[crate::filter("quilkin.extensions.filters.external_authorization.v1alpha1.ExternalAuthorization")]
+struct ExternalAuthorization {
+    metadata_key: String,
+    decisions: Decisions,
+    checks_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    metrics: Arc<Metrics>,
+}
+
+/// Creates instances of [`ExternalAuthorization`].
+pub struct ExternalAuthorizationFactory {
+    log: Logger,
+}
+
+impl ExternalAuthorizationFactory {
+    pub fn new(base: &Logger) -> Self {
+        Self { log: base.clone() }
+    }
+}
+
+/// The schema of the filter's configuration.
+const CONFIG_SCHEMA: &str = "\
+properties:
+  address:
+    type: string
+    description: |
+      The URL of the authorization service.
+    example: http://127.0.0.1:50051
+  metadataKey:
+    type: string
+    default: quilkin.dev/captured_bytes
+    description: |
+      The key under which the token is stored in the Filter dynamic metadata.
+  timeout:
+    type: string
+    default: 1s
+    description: |
+      How long to wait for the authorization service to answer.
+  allowed_ttl:
+    type: string
+    default: 5m
+    description: |
+      How long a client is allowed for before it is checked again.
+  denied_ttl:
+    type: string
+    default: 10s
+    description: |
+      How long a client is denied for before it is checked again.
+required: [address]
+";
+
+impl FilterFactory for ExternalAuthorizationFactory {
+    fn name(&self) -> &'static str {
+        ExternalAuthorization::FILTER_NAME
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let config: Config = self
+            .require_config(args.config)?
+            .deserialize::<Config, ProtoConfig>(self.name())?;
+
+        for (field, value) in vec![
+            ("timeout", config.timeout),
+            ("allowed_ttl", config.allowed_ttl),
+            ("denied_ttl", config.denied_ttl),
+        ] {
+            if value == Duration::from_secs(0) {
+                return Err(Error::FieldInvalid {
+                    field: field.into(),
+                    reason: "value must be greater than 0".into(),
+                });
+            }
+        }
+        let channel = Endpoint::from_shared(config.address.clone())
+            .map_err(|err| err.to_string())
+            .and_then(|endpoint| {
+                endpoint
+                    .timeout(config.timeout)
+                    .connect_lazy()
+                    .map_err(|err| err.to_string())
+            })
+            .map_err(|reason| Error::FieldInvalid {
+                field: "address".into(),
+                reason,
+            })?;
+
+        Ok(Box::new(ExternalAuthorization::new(
+            &self.log,
+            config,
+            channel,
+            Metrics::new(&args.metrics_registry)?,
+        )))
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(CONFIG_SCHEMA)
+    }
+}
+
+impl ExternalAuthorization {
+    /// Creates the filter, which checks clients in the background until it is
+    /// dropped.
+    fn new(base: &Logger, config: Config, channel: Channel, metrics: Metrics) -> Self {
+        let log = base.new(
+            o!("source" => "extensions::ExternalAuthorization", "filter" => Self::FILTER_NAME),
+        );
+        let decisions = Decisions::default();
+        let metrics = Arc::new(metrics);
+        let (checks_tx, checks_rx) = mpsc::channel(CHECK_QUEUE_SIZE);
+
+        tokio::spawn(check(
+            log,
+            AuthorizationClient::new(channel),
+            config.allowed_ttl,
+            config.denied_ttl,
+            decisions.clone(),
+            metrics.clone(),
+            checks_rx,
+        ));
+
+        Self {
+            metadata_key: config.metadata_key,
+            decisions,
+            checks_tx,
+            metrics,
+        }
+    }
+
+    /// Returns whether the client at `from` may send packets with `token`,
+    /// starting to check it if it isn't known or its decision expired.
+    fn allowed(&self, from: SocketAddr, token: &[u8]) -> Option<bool> {
+        let now = Instant::now();
+        let key = (from, token.to_vec());
+        if let Some(decision) = self.decisions.read().get(&key) {
+            if decision.checking || decision.expires > now {
+                return decision.allowed;
+            }
+        }
+
+        let mut decisions = self.decisions.write();
+        let decision = decisions.entry(key.clone()).or_insert(Decision {
+            allowed: None,
+            expires: now,
+            checking: false,
+        });
+        // Clients whose decision expired keep it while they are checked
+        // again, so their sessions aren't interrupted.
+        if !decision.checking && decision.expires <= now && self.checks_tx.try_send(key).is_ok() {
+            decision.checking = true;
+        }
+        decision.allowed
+    }
+}
+
+/// Packets without a token in their metadata.
+const TOKEN_MISSING: DropReason = DropReason::new("token_missing");
+/// Packets whose token in their metadata is not a byte array.
+const INVALID_TOKEN: DropReason = DropReason::new("invalid_token");
+/// Packets of clients that haven't been checked yet.
+const AUTHORIZATION_PENDING: DropReason = DropReason::new("authorization_pending");
+/// Packets of clients that the authorization service denied.
+const UNAUTHORIZED: DropReason = DropReason::new("unauthorized");
+
+impl Filter for ExternalAuthorization {
+    fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
+        let token = match ctx.metadata.get(&self.metadata_key) {
+            Some(value) => match value.downcast_ref::<Vec<u8>>() {
+                Some(token) => token,
+                None => {
+                    self.metrics.packets_dropped_invalid_token.inc();
+                    return INVALID_TOKEN.drop_packet();
+                }
+            },
+            None => {
+                self.metrics.packets_dropped_no_token_found.inc();
+                return TOKEN_MISSING.drop_packet();
+            }
+        };
+
+        match self.allowed(ctx.from, token) {
+            Some(true) => Some(ctx.into()),
+            Some(false) => {
+                self.metrics.packets_dropped_unauthorized.inc();
+                UNAUTHORIZED.drop_packet()
+            }
+            None => {
+                self.metrics.packets_dropped_authorization_pending.inc();
+                AUTHORIZATION_PENDING.drop_packet()
+            }
+        }
+    }
+}
+
+/// Checks the clients received on `checks_rx` with the authorization service,
+/// and forgets the clients that stopped sending packets, until the filter is
+/// dropped.
+async fn check(
+    log: Logger,
+    client: AuthorizationClient<Channel>,
+    allowed_ttl: Duration,
+    denied_ttl: Duration,
+    decisions: Decisions,
+    metrics: Arc<Metrics>,
+    mut checks_rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+) {
+    let mut prune_interval = time::interval_at(Instant::now() + allowed_ttl, allowed_ttl);
+    loop {
+        let (from, token) = tokio::select! {
+            client = checks_rx.recv() => match client {
+                Some(client) => client,
+                None => return,
+            },
+            _ = prune_interval.tick() => {
+                // Clients are checked again by their next packet once their
+                // decision expired, so those that didn't send one since then
+                // are gone.
+                let now = Instant::now();
+                decisions
+                    .write()
+                    .retain(|_, decision| decision.checking || decision.expires + allowed_ttl > now);
+                continue;
+            }
+        };
+
+        let mut client = client.clone();
+        let log = log.clone();
+        let decisions = decisions.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let request = CheckRequest {
+                token: token.clone(),
+                source_address: from.to_string(),
+            };
+            let result = client.check(request).await;
+
+            let mut decisions = decisions.write();
+            let decision = match decisions.get_mut(&(from, token)) {
+                Some(decision) => decision,
+                None => return,
+            };
+            decision.checking = false;
+            match result {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    if response.allowed {
+                        metrics.checks_allowed.inc();
+                        decision.expires = Instant::now() + allowed_ttl;
+                    } else {
+                        debug!(log, "Client was denied by the authorization service"; "client" => %from, "reason" => response.reason);
+                        metrics.checks_denied.inc();
+                        decision.expires = Instant::now() + denied_ttl;
+                    }
+                    decision.allowed = Some(response.allowed);
+                }
+                Err(status) => {
+                    warn!(log, "Failed to check a client with the authorization service"; "client" => %from, "error" => %status);
+                    metrics.checks_failed.inc();
+                    // The client keeps its previous decision, if any, until
+                    // it is checked again.
+                    decision.expires = Instant::now() + RETRY_DELAY;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use prometheus::Registry;
+    use tonic::{transport::Server, Request, Response, Status};
+
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::filters::{
+        extensions::CAPTURED_BYTES, CreateFilterArgs, Filter, FilterFactory, ReadContext,
+    };
+    use crate::test_utils::{drop_reason, logger};
+
+    use super::quilkin::extensions::filters::external_authorization::v1alpha1::{
+        authorization_server::{Authorization, AuthorizationServer},
+        CheckRequest, CheckResponse,
+    };
+    use super::{
+        Config, ExternalAuthorizationFactory, ProtoConfig, AUTHORIZATION_PENDING, TOKEN_MISSING,
+        UNAUTHORIZED,
+    };
+
+    /// Allows the clients whose token is `allowed`, and counts how often it
+    /// was asked to.
+    #[derive(Default)]
+    struct MockAuthorization {
+        checks: Arc<AtomicUsize>,
+    }
+
+    #[tonic::async_trait]
+    impl Authorization for MockAuthorization {
+        async fn check(
+            &self,
+            request: Request<CheckRequest>,
+        ) -> Result<Response<CheckResponse>, Status> {
+            self.checks.fetch_add(1, Ordering::SeqCst);
+            let request = request.into_inner();
+            assert_eq!("127.0.0.1:100", request.source_address);
+            let allowed = request.token == b"allowed";
+            Ok(Response::new(CheckResponse {
+                allowed,
+                reason: if allowed { "" } else { "unknown token" }.into(),
+            }))
+        }
+    }
+
+    fn read_context(token: Option<&[u8]>) -> ReadContext {
+        let mut ctx = ReadContext::new(
+            Endpoints::new(vec![Endpoint::from_address(
+                "127.0.0.1:90".parse().unwrap(),
+            )])
+            .unwrap()
+            .into(),
+            "127.0.0.1:100".parse().unwrap(),
+            b"hello".to_vec(),
+        );
+        if let Some(token) = token {
+            ctx.metadata
+                .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(token.to_vec()));
+        }
+        ctx
+    }
+
+    fn create_filter(config: &str) -> Result<Box<dyn Filter>, crate::filters::Error> {
+        ExternalAuthorizationFactory::new(&logger()).create_filter(CreateFilterArgs::fixed(
+            Registry::default(),
+            Some(&serde_yaml::from_str(config).unwrap()),
+        ))
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config::try_from(ProtoConfig {
+            address: "http://auth:50051".into(),
+            metadata_key: None,
+            timeout: Some(Duration::from_millis(500).into()),
+            allowed_ttl: None,
+            denied_ttl: None,
+        })
+        .unwrap();
+        assert_eq!(
+            Config {
+                address: "http://auth:50051".into(),
+                metadata_key: CAPTURED_BYTES.into(),
+                timeout: Duration::from_millis(500),
+                allowed_ttl: Duration::from_secs(300),
+                denied_ttl: Duration::from_secs(10),
+            },
+            config
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        assert!(create_filter("address: http://auth:50051\ntimeout: 0s").is_err());
+        assert!(create_filter("address: not a url").is_err());
+        assert!(create_filter("timeout: 1s").is_err());
+    }
+
+    #[tokio::test]
+    async fn check_clients() {
+        let address: SocketAddr = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let authorization = MockAuthorization::default();
+        let checks = authorization.checks.clone();
+        tokio::spawn(
+            Server::builder()
+                .add_service(AuthorizationServer::new(authorization))
+                .serve(address),
+        );
+
+        let filter = create_filter(&format!("address: http://{}", address)).unwrap();
+        assert_eq!(
+            Some(TOKEN_MISSING),
+            drop_reason(filter.read(read_context(None)))
+        );
+
+        // Packets are dropped until the client has been checked, which only
+        // happens once.
+        let read = |token: &'static [u8]| drop_reason(filter.read(read_context(Some(token))));
+        assert_eq!(Some(AUTHORIZATION_PENDING), read(b"allowed"));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while read(b"allowed").is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(1, checks.load(Ordering::SeqCst));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while read(b"denied") != Some(UNAUTHORIZED) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(2, checks.load(Ordering::SeqCst));
+    }
+}
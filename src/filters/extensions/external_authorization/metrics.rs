@@ -0,0 +1,68 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::core::{AtomicU64, GenericCounter};
+use prometheus::{IntCounterVec, Registry, Result as MetricsResult};
+
+use crate::metrics::{filter_opts, CollectorExt};
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped_no_token_found: GenericCounter<AtomicU64>,
+    pub(super) packets_dropped_invalid_token: GenericCounter<AtomicU64>,
+    pub(super) packets_dropped_authorization_pending: GenericCounter<AtomicU64>,
+    pub(super) packets_dropped_unauthorized: GenericCounter<AtomicU64>,
+    pub(super) checks_allowed: GenericCounter<AtomicU64>,
+    pub(super) checks_denied: GenericCounter<AtomicU64>,
+    pub(super) checks_failed: GenericCounter<AtomicU64>,
+}
+
+impl Metrics {
+    pub(super) fn new(registry: &Registry) -> MetricsResult<Self> {
+        let packets_dropped = IntCounterVec::new(
+            filter_opts(
+                "packets_dropped",
+                "ExternalAuthorization",
+                "Total number of packets dropped. labels: reason.",
+            ),
+            &["reason"],
+        )?
+        .register(registry)?;
+        let checks = IntCounterVec::new(
+            filter_opts(
+                "checks_total",
+                "ExternalAuthorization",
+                "Total number of clients checked with the authorization service. labels: result.",
+            ),
+            &["result"],
+        )?
+        .register(registry)?;
+
+        Ok(Metrics {
+            packets_dropped_no_token_found: packets_dropped
+                .get_metric_with_label_values(&["NoTokenFound"])?,
+            packets_dropped_invalid_token: packets_dropped
+                .get_metric_with_label_values(&["InvalidToken"])?,
+            packets_dropped_authorization_pending: packets_dropped
+                .get_metric_with_label_values(&["AuthorizationPending"])?,
+            packets_dropped_unauthorized: packets_dropped
+                .get_metric_with_label_values(&["Unauthorized"])?,
+            checks_allowed: checks.get_metric_with_label_values(&["allowed"])?,
+            checks_denied: checks.get_metric_with_label_values(&["denied"])?,
+            checks_failed: checks.get_metric_with_label_values(&["error"])?,
+        })
+    }
+}
@@ -17,9 +17,9 @@
 use std::convert::TryFrom;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
+use crate::utils::random;
 use crate::{config::UpstreamEndpoints, filters::prelude::*, map_proto_enum};
 
 crate::include_proto!("quilkin.extensions.filters.load_balancer.v1alpha1");
@@ -108,7 +108,7 @@ pub struct RandomEndpointChooser;
 impl EndpointChooser for RandomEndpointChooser {
     fn choose_endpoints(&self, endpoints: &mut UpstreamEndpoints) {
         // Note: Unwrap is safe here because the index is guaranteed to be in range.
-        let idx = (&mut thread_rng()).gen_range(0..endpoints.size());
+        let idx = random::index(endpoints.size());
         endpoints.keep(idx)
             .expect("BUG: unwrap should have been safe because index into endpoints list should be in range");
     }
@@ -124,6 +124,19 @@ struct LoadBalancerFilter {
     endpoint_chooser: Box<dyn EndpointChooser>,
 }
 
+/// The schema of the filter's configuration.
+const CONFIG_SCHEMA: &str = "\
+properties:
+  policy:
+    type: string
+    description: |
+      The load balancing policy with which to distribute packets among endpoints.
+       - ROUND_ROBIN: Send packets by selecting endpoints in turn.
+       - RANDOM: Send packets by randomly selecting endpoints.
+    enum: [ROUND_ROBIN, RANDOM]
+    default: ROUND_ROBIN
+";
+
 impl FilterFactory for LoadBalancerFilterFactory {
     fn name(&self) -> &'static str {
         LoadBalancerFilter::FILTER_NAME
@@ -141,6 +154,10 @@ impl FilterFactory for LoadBalancerFilterFactory {
 
         Ok(Box::new(LoadBalancerFilter { endpoint_chooser }))
     }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(CONFIG_SCHEMA)
+    }
 }
 
 impl Filter for LoadBalancerFilter {
@@ -90,6 +90,25 @@ struct RateLimitFilter {
     shutdown_tx: Option<Sender<()>>,
 }
 
+/// The schema of the filter's configuration.
+const CONFIG_SCHEMA: &str = "\
+properties:
+  max_packets:
+    type: integer
+    description: |
+      The maximum number of packets allowed to be forwarded over the given duration.
+    minimum: 0
+    example: 1000
+  period:
+    type: string
+    description: |
+      A human readable duration overwhich `max_packets` applies.
+      Examples: `1s` 1 second, `500ms` 500 milliseconds.
+      The minimum allowed value is 100ms.
+    default: 1s
+required: [max_packets]
+";
+
 impl FilterFactory for RateLimitFilterFactory {
     fn name(&self) -> &'static str {
         RateLimitFilter::FILTER_NAME
@@ -112,6 +131,10 @@ impl FilterFactory for RateLimitFilterFactory {
             )))
         }
     }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(CONFIG_SCHEMA)
+    }
 }
 
 impl RateLimitFilter {
@@ -193,11 +216,15 @@ impl Drop for RateLimitFilter {
     }
 }
 
+/// Packets received after the maximum number of packets for the current
+/// period.
+const RATE_LIMITED: DropReason = DropReason::new("rate_limited");
+
 impl Filter for RateLimitFilter {
     fn read(&self, ctx: ReadContext) -> Option<ReadResponse> {
         self.acquire_token().map(|()| ctx.into()).or_else(|| {
             self.metrics.packets_dropped_total.inc();
-            None
+            RATE_LIMITED.drop_packet()
         })
     }
 }
@@ -210,14 +237,14 @@ mod tests {
     use prometheus::Registry;
     use tokio::time;
 
-    use super::ProtoConfig;
+    use super::{ProtoConfig, RATE_LIMITED};
     use crate::cluster::Endpoint;
     use crate::config::Endpoints;
     use crate::filters::{
         extensions::local_rate_limit::{metrics::Metrics, Config, RateLimitFilter},
         Filter, ReadContext,
     };
-    use crate::test_utils::assert_write_no_change;
+    use crate::test_utils::{assert_write_no_change, drop_reason};
 
     fn rate_limiter(config: Config) -> RateLimitFilter {
         RateLimitFilter::new(config, Metrics::new(&Registry::default()).unwrap())
@@ -342,17 +369,20 @@ mod tests {
         assert_write_no_change(&r);
 
         // Check that we're rate limited.
-        assert!(r
-            .read(ReadContext::new(
-                Endpoints::new(vec![Endpoint::from_address(
+        assert_eq!(
+            Some(RATE_LIMITED),
+            drop_reason(
+                r.read(ReadContext::new(
+                    Endpoints::new(vec![Endpoint::from_address(
+                        "127.0.0.1:8080".parse().unwrap(),
+                    )])
+                    .unwrap()
+                    .into(),
                     "127.0.0.1:8080".parse().unwrap(),
-                )])
-                .unwrap()
-                .into(),
-                "127.0.0.1:8080".parse().unwrap(),
-                vec![9],
-            ))
-            .is_none(),);
+                    vec![9],
+                ))
+            )
+        );
     }
 
     #[tokio::test]
@@ -14,25 +14,41 @@
  *  limitations under the License.
  */
 
+mod file;
+mod grpc;
 mod metrics;
+mod provider;
+#[cfg(feature = "redis")]
+mod routes;
 
 crate::include_proto!("quilkin.extensions.filters.token_router.v1alpha1");
 
-use std::convert::TryFrom;
+use std::convert::{TryFrom, TryInto};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use slog::{error, o, Logger};
+use tonic::transport::Endpoint;
 
 use crate::{
-    config::{RetainedItems, LOG_SAMPLING_RATE},
+    config::{RetainedItems, UpstreamEndpoints, LOG_SAMPLING_RATE},
     filters::{
         extensions::{token_router::metrics::Metrics, CAPTURED_BYTES},
         prelude::*,
     },
 };
 
-use self::quilkin::extensions::filters::token_router::v1alpha1::TokenRouter as ProtoConfig;
+use self::file::FileRoutes;
+use self::grpc::GrpcRoutes;
+pub use self::provider::{TokenProvider, TokenRoute};
+use self::quilkin::extensions::filters::token_router::v1alpha1::{
+    token_router::{File as ProtoFileConfig, Grpc as ProtoGrpcConfig, Redis as ProtoRedisConfig},
+    TokenRouter as ProtoConfig,
+};
+#[cfg(feature = "redis")]
+use self::routes::RedisRoutes;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(default)]
@@ -40,6 +56,55 @@ struct Config {
     /// the key to use when retrieving the token from the Filter's dynamic metadata
     #[serde(rename = "metadataKey", default = "default_metadata_key")]
     metadata_key: String,
+    /// looks up the endpoint of each token in Redis, instead of matching it
+    /// against the tokens of the endpoints
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redis: Option<RedisConfig>,
+    /// reads the endpoint of each token from a file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<FileConfig>,
+    /// watches the endpoint of each token from a `TokenProvider` gRPC service
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grpc: Option<GrpcConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct RedisConfig {
+    /// the URL of the Redis server, e.g `redis://127.0.0.1:6379`
+    address: String,
+    /// the prefix of the key that holds the address of a token's endpoint,
+    /// followed by the token
+    #[serde(rename = "keyPrefix", default = "default_key_prefix")]
+    key_prefix: String,
+    /// the channel on which tokens whose endpoint changed are published
+    #[serde(default = "default_channel")]
+    channel: String,
+    /// how long the endpoint of a token is cached for
+    #[serde(
+        rename = "cacheTtl",
+        with = "humantime_serde",
+        default = "default_cache_ttl"
+    )]
+    cache_ttl: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    /// the YAML or JSON file mapping base64 encoded tokens to the addresses
+    /// of their endpoints
+    path: PathBuf,
+    /// how often the file is checked for modifications
+    #[serde(with = "humantime_serde", default = "default_file_interval")]
+    interval: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct GrpcConfig {
+    /// the URL of the `TokenProvider` service, e.g `http://127.0.0.1:50051`
+    address: String,
 }
 
 /// Default value for [`Config::metadata_key`]
@@ -47,10 +112,33 @@ fn default_metadata_key() -> String {
     CAPTURED_BYTES.into()
 }
 
+/// Default value for [`RedisConfig::key_prefix`]
+fn default_key_prefix() -> String {
+    "quilkin:token:".into()
+}
+
+/// Default value for [`RedisConfig::channel`]
+fn default_channel() -> String {
+    "quilkin:tokens".into()
+}
+
+/// Default value for [`RedisConfig::cache_ttl`]
+fn default_cache_ttl() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Default value for [`FileConfig::interval`]
+fn default_file_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             metadata_key: default_metadata_key(),
+            redis: None,
+            file: None,
+            grpc: None,
         }
     }
 }
@@ -61,6 +149,61 @@ impl TryFrom<ProtoConfig> for Config {
     fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
         Ok(Self {
             metadata_key: p.metadata_key.unwrap_or_else(default_metadata_key),
+            redis: p.redis.map(RedisConfig::try_from).transpose()?,
+            file: p.file.map(FileConfig::try_from).transpose()?,
+            grpc: p.grpc.map(GrpcConfig::from),
+        })
+    }
+}
+
+impl TryFrom<ProtoRedisConfig> for RedisConfig {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoRedisConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            address: p.address,
+            key_prefix: p.key_prefix.unwrap_or_else(default_key_prefix),
+            channel: p.channel.unwrap_or_else(default_channel),
+            cache_ttl: p
+                .cache_ttl
+                .map(|cache_ttl| {
+                    cache_ttl.try_into().map_err(|err| {
+                        ConvertProtoConfigError::new(
+                            format!("invalid duration: {:?}", err),
+                            Some("redis.cache_ttl".into()),
+                        )
+                    })
+                })
+                .transpose()?
+                .unwrap_or_else(default_cache_ttl),
+        })
+    }
+}
+
+impl From<ProtoGrpcConfig> for GrpcConfig {
+    fn from(p: ProtoGrpcConfig) -> Self {
+        Self { address: p.address }
+    }
+}
+
+impl TryFrom<ProtoFileConfig> for FileConfig {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoFileConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            path: p.path.into(),
+            interval: p
+                .interval
+                .map(|interval| {
+                    interval.try_into().map_err(|err| {
+                        ConvertProtoConfigError::new(
+                            format!("invalid duration: {:?}", err),
+                            Some("file.interval".into()),
+                        )
+                    })
+                })
+                .transpose()?
+                .unwrap_or_else(default_file_interval),
         })
     }
 }
@@ -72,19 +215,100 @@ struct TokenRouter {
     log: Logger,
     metadata_key: Arc<String>,
     metrics: Metrics,
+    provider: Option<Arc<dyn TokenProvider>>,
 }
 
 /// Factory for the TokenRouter filter
 pub struct TokenRouterFactory {
     log: Logger,
+    provider: Option<Arc<dyn TokenProvider>>,
 }
 
 impl TokenRouterFactory {
     pub fn new(base: &Logger) -> Self {
-        TokenRouterFactory { log: base.clone() }
+        TokenRouterFactory {
+            log: base.clone(),
+            provider: None,
+        }
+    }
+
+    /// Returns a factory whose filters route tokens to the endpoints that
+    /// `provider` assigns them to, unless their configuration sets a provider
+    /// of its own.
+    pub fn with_provider(base: &Logger, provider: Arc<dyn TokenProvider>) -> Self {
+        TokenRouterFactory {
+            log: base.clone(),
+            provider: Some(provider),
+        }
     }
 }
 
+/// The schema of the filter's configuration.
+const CONFIG_SCHEMA: &str = "\
+properties:
+  metadataKey:
+    type: string
+    default: quilkin.dev/captured_bytes
+    description: |
+      The key under which the token is stored in the Filter dynamic metadata.
+  redis:
+    type: object
+    default: null
+    description: |
+      Looks up the endpoint of each token in Redis, instead of matching it against the tokens of the endpoints.
+      Requires Quilkin to be built with the `redis` feature.
+    properties:
+      address:
+        type: string
+        description: |
+          The URL of the Redis server, e.g `redis://127.0.0.1:6379`.
+      keyPrefix:
+        type: string
+        default: \"quilkin:token:\"
+        description: |
+          The prefix of the key that holds the address of a token's endpoint, followed by the token.
+      channel:
+        type: string
+        default: \"quilkin:tokens\"
+        description: |
+          The channel on which tokens whose endpoint changed are published.
+      cacheTtl:
+        type: string
+        default: 30s
+        description: |
+          How long the endpoint of a token is cached for.
+    required: [address]
+  file:
+    type: object
+    default: null
+    description: |
+      Reads the endpoint of each token from a file, instead of matching it against the tokens of the endpoints.
+    properties:
+      path:
+        type: string
+        example: /etc/quilkin/tokens.yaml
+        description: |
+          The YAML or JSON file mapping base64 encoded tokens to the addresses of their endpoints.
+      interval:
+        type: string
+        default: 5s
+        description: |
+          How often the file is checked for modifications.
+    required: [path]
+  grpc:
+    type: object
+    default: null
+    description: |
+      Watches the endpoint of each token from a `TokenProvider` gRPC service, instead of matching it against the tokens of the endpoints.
+    properties:
+      address:
+        type: string
+        example: http://127.0.0.1:50051
+        description: |
+          The URL of the `TokenProvider` service.
+    required: [address]
+";
+
 impl FilterFactory for TokenRouterFactory {
     fn name(&self) -> &'static str {
         TokenRouter::FILTER_NAME
@@ -97,24 +321,148 @@ impl FilterFactory for TokenRouterFactory {
             .transpose()?
             .unwrap_or_default();
 
-        Ok(Box::new(TokenRouter::new(
+        let providers = [
+            config.redis.is_some(),
+            config.file.is_some(),
+            config.grpc.is_some(),
+        ];
+        if providers.iter().filter(|set| **set).count() > 1 {
+            return Err(Error::FieldInvalid {
+                field: "redis".into(),
+                reason: "only one of `redis`, `file` or `grpc` can be set".into(),
+            });
+        }
+        if let Some(redis) = &config.redis {
+            if redis.cache_ttl == Duration::from_secs(0) {
+                return Err(Error::FieldInvalid {
+                    field: "redis.cacheTtl".into(),
+                    reason: "value must be greater than 0".into(),
+                });
+            }
+        }
+        if let Some(file) = &config.file {
+            if file.interval == Duration::from_secs(0) {
+                return Err(Error::FieldInvalid {
+                    field: "file.interval".into(),
+                    reason: "value must be greater than 0".into(),
+                });
+            }
+        }
+
+        TokenRouter::new(
             &self.log,
             config,
+            self.provider.clone(),
             Metrics::new(&args.metrics_registry)?,
-        )))
+        )
+        .map(|filter| Box::new(filter) as Box<dyn Filter>)
+    }
+
+    fn config_schema(&self) -> Option<&'static str> {
+        Some(CONFIG_SCHEMA)
     }
 }
 
 impl TokenRouter {
-    fn new(base: &Logger, config: Config, metrics: Metrics) -> Self {
-        Self {
-            log: base.new(o!("source" => "extensions::TokenRouter")),
+    /// Creates the filter, with the provider set by `config` or otherwise
+    /// `provider`, if any.
+    fn new(
+        base: &Logger,
+        config: Config,
+        provider: Option<Arc<dyn TokenProvider>>,
+        metrics: Metrics,
+    ) -> Result<Self, Error> {
+        let log =
+            base.new(o!("source" => "extensions::TokenRouter", "filter" => Self::FILTER_NAME));
+
+        let provider = if let Some(redis) = &config.redis {
+            Some(redis_routes(&log, redis)?)
+        } else if let Some(file) = &config.file {
+            let routes = FileRoutes::new(&log, file).map_err(|reason| Error::FieldInvalid {
+                field: "file.path".into(),
+                reason,
+            })?;
+            Some(Arc::new(routes) as Arc<dyn TokenProvider>)
+        } else if let Some(grpc) = &config.grpc {
+            let channel = Endpoint::from_shared(grpc.address.clone())
+                .map_err(|err| err.to_string())
+                .and_then(|endpoint| endpoint.connect_lazy().map_err(|err| err.to_string()))
+                .map_err(|reason| Error::FieldInvalid {
+                    field: "grpc.address".into(),
+                    reason,
+                })?;
+            Some(Arc::new(GrpcRoutes::new(&log, channel)) as Arc<dyn TokenProvider>)
+        } else {
+            provider
+        };
+
+        Ok(Self {
+            log,
             metadata_key: Arc::new(config.metadata_key),
             metrics,
+            provider,
+        })
+    }
+
+    /// Keeps the endpoints that packets with `token` are sent to.
+    fn route(&self, token: &[u8], endpoints: &mut UpstreamEndpoints) -> Result<(), DropReason> {
+        if let Some(provider) = &self.provider {
+            let address = match provider.route(token) {
+                TokenRoute::Endpoint(address) => address,
+                TokenRoute::Unassigned => {
+                    self.metrics.packets_dropped_no_endpoint_match.inc();
+                    return Err(NO_ENDPOINT_MATCH);
+                }
+                TokenRoute::Pending => {
+                    self.metrics.packets_dropped_token_lookup_pending.inc();
+                    return Err(TOKEN_LOOKUP_PENDING);
+                }
+            };
+            return match endpoints.retain(|e| e.address == address) {
+                RetainedItems::None => {
+                    self.metrics.packets_dropped_no_endpoint_match.inc();
+                    Err(NO_ENDPOINT_MATCH)
+                }
+                _ => Ok(()),
+            };
+        }
+
+        match endpoints.retain(|e| e.tokens.contains(token)) {
+            RetainedItems::None => {
+                self.metrics.packets_dropped_no_endpoint_match.inc();
+                Err(NO_ENDPOINT_MATCH)
+            }
+            _ => Ok(()),
         }
     }
 }
 
+#[cfg(feature = "redis")]
+fn redis_routes(log: &Logger, config: &RedisConfig) -> Result<Arc<dyn TokenProvider>, Error> {
+    let routes = RedisRoutes::new(log, config).map_err(|err| Error::FieldInvalid {
+        field: "redis.address".into(),
+        reason: err.to_string(),
+    })?;
+    Ok(Arc::new(routes))
+}
+
+#[cfg(not(feature = "redis"))]
+fn redis_routes(_: &Logger, _: &RedisConfig) -> Result<Arc<dyn TokenProvider>, Error> {
+    Err(Error::FieldInvalid {
+        field: "redis".into(),
+        reason: "quilkin must be built with the `redis` feature".into(),
+    })
+}
+
+/// Packets without a token in their metadata.
+const TOKEN_MISSING: DropReason = DropReason::new("token_missing");
+/// Packets with a token that no endpoint has.
+const NO_ENDPOINT_MATCH: DropReason = DropReason::new("no_endpoint_match");
+/// Packets whose token in their metadata is not a byte array.
+const INVALID_TOKEN: DropReason = DropReason::new("invalid_token");
+/// Packets with a token whose endpoint isn't known yet by the provider.
+const TOKEN_LOOKUP_PENDING: DropReason = DropReason::new("token_lookup_pending");
+
 impl Filter for TokenRouter {
     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
         match ctx.metadata.get(self.metadata_key.as_ref()) {
@@ -128,16 +476,15 @@ impl Filter for TokenRouter {
                     );
                 }
                 self.metrics.packets_dropped_no_token_found.inc();
-                None
+                TOKEN_MISSING.drop_packet()
             }
             Some(value) => match value.downcast_ref::<Vec<u8>>() {
-                Some(token) => match ctx.endpoints.retain(|e| e.tokens.contains(token)) {
-                    RetainedItems::None => {
-                        self.metrics.packets_dropped_no_endpoint_match.inc();
-                        None
+                Some(token) => {
+                    if let Err(reason) = self.route(token, &mut ctx.endpoints) {
+                        return reason.drop_packet();
                     }
-                    _ => Some(ctx.into()),
-                },
+                    Some(ctx.into())
+                }
                 None => {
                     if self.metrics.packets_dropped_invalid_token.get() % LOG_SAMPLING_RATE == 0 {
                         error!(
@@ -148,7 +495,7 @@ impl Filter for TokenRouter {
                         );
                     }
                     self.metrics.packets_dropped_invalid_token.inc();
-                    None
+                    INVALID_TOKEN.drop_packet()
                 }
             },
         }
@@ -164,15 +511,18 @@ mod tests {
     use std::convert::TryFrom;
     use std::ops::Deref;
     use std::sync::Arc;
+    use std::time::Duration;
 
     use prometheus::Registry;
     use serde_yaml::{Mapping, Value};
 
     use crate::config::Endpoints;
-    use crate::test_utils::{assert_write_no_change, logger};
+    use crate::test_utils::{assert_write_no_change, drop_reason, logger};
 
     use super::{
-        default_metadata_key, Config, Metrics, ProtoConfig, TokenRouter, TokenRouterFactory,
+        default_metadata_key, Config, FileConfig, Metrics, ProtoConfig, ProtoFileConfig,
+        ProtoRedisConfig, RedisConfig, TokenProvider, TokenRoute, TokenRouter, TokenRouterFactory,
+        INVALID_TOKEN, NO_ENDPOINT_MATCH, TOKEN_LOOKUP_PENDING, TOKEN_MISSING,
     };
     use crate::cluster::Endpoint;
     use crate::filters::{
@@ -185,8 +535,10 @@ mod tests {
         TokenRouter::new(
             &logger(),
             config,
+            None,
             Metrics::new(&Registry::default()).unwrap(),
         )
+        .unwrap()
     }
 
     #[test]
@@ -196,16 +548,56 @@ mod tests {
                 "should succeed when all valid values are provided",
                 ProtoConfig {
                     metadata_key: Some("foobar".into()),
+                    redis: Some(ProtoRedisConfig {
+                        address: "redis://127.0.0.1:6379".into(),
+                        key_prefix: Some("tokens/".into()),
+                        channel: Some("assignments".into()),
+                        cache_ttl: Some(Duration::from_secs(5).into()),
+                    }),
+                    file: None,
+                    grpc: None,
                 },
                 Some(Config {
                     metadata_key: "foobar".into(),
+                    redis: Some(RedisConfig {
+                        address: "redis://127.0.0.1:6379".into(),
+                        key_prefix: "tokens/".into(),
+                        channel: "assignments".into(),
+                        cache_ttl: Duration::from_secs(5),
+                    }),
+                    file: None,
+                    grpc: None,
                 }),
             ),
             (
                 "should use correct default values",
-                ProtoConfig { metadata_key: None },
+                ProtoConfig {
+                    metadata_key: None,
+                    redis: Some(ProtoRedisConfig {
+                        address: "redis://127.0.0.1:6379".into(),
+                        key_prefix: None,
+                        channel: None,
+                        cache_ttl: None,
+                    }),
+                    file: Some(ProtoFileConfig {
+                        path: "tokens.yaml".into(),
+                        interval: None,
+                    }),
+                    grpc: None,
+                },
                 Some(Config {
                     metadata_key: default_metadata_key(),
+                    redis: Some(RedisConfig {
+                        address: "redis://127.0.0.1:6379".into(),
+                        key_prefix: "quilkin:token:".into(),
+                        channel: "quilkin:tokens".into(),
+                        cache_ttl: Duration::from_secs(30),
+                    }),
+                    file: Some(FileConfig {
+                        path: "tokens.yaml".into(),
+                        interval: Duration::from_secs(5),
+                    }),
+                    grpc: None,
                 }),
             ),
         ];
@@ -274,11 +666,114 @@ mod tests {
         assert_read(filter.deref(), ctx);
     }
 
+    #[tokio::test]
+    async fn factory_redis() {
+        let factory = TokenRouterFactory::new(&logger());
+        let create_filter = |config: &str| {
+            factory.create_filter(CreateFilterArgs::fixed(
+                Registry::default(),
+                Some(&serde_yaml::from_str(config).unwrap()),
+            ))
+        };
+
+        assert!(create_filter("redis: {address: redis://127.0.0.1:6379, cacheTtl: 0s}").is_err());
+
+        let result = create_filter("redis: {address: redis://127.0.0.1:0}");
+        #[cfg(not(feature = "redis"))]
+        assert!(result.is_err());
+        #[cfg(feature = "redis")]
+        {
+            assert!(create_filter("redis: {address: not-redis}").is_err());
+
+            // Packets are dropped until the endpoint of their token is found.
+            let mut ctx = new_ctx();
+            ctx.metadata
+                .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(b"123".to_vec()));
+            assert_eq!(
+                Some(TOKEN_LOOKUP_PENDING),
+                drop_reason(result.unwrap().read(ctx))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn factory_file() {
+        let path = std::env::temp_dir().join(format!("quilkin-{}.yaml", uuid::Uuid::new_v4()));
+        // `456` is assigned to the endpoint whose own token is `123`.
+        std::fs::write(&path, "NDU2: 127.0.0.1:80").unwrap();
+
+        let factory = TokenRouterFactory::new(&logger());
+        let create_filter = |config: String| {
+            factory.create_filter(CreateFilterArgs::fixed(
+                Registry::default(),
+                Some(&serde_yaml::from_str(&config).unwrap()),
+            ))
+        };
+        assert!(create_filter(format!("file: {{path: {:?}, interval: 0s}}", path)).is_err());
+        assert!(create_filter(format!(
+            "file: {{path: {:?}}}
+grpc: {{address: http://127.0.0.1:50051}}",
+            path
+        ))
+        .is_err());
+
+        let filter = create_filter(format!("file: {{path: {:?}}}", path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(create_filter(format!("file: {{path: {:?}}}", path)).is_err());
+
+        let mut ctx = new_ctx();
+        ctx.metadata
+            .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(b"456".to_vec()));
+        let result = filter.read(ctx).unwrap();
+        assert_eq!(
+            vec!["127.0.0.1:80".parse::<std::net::SocketAddr>().unwrap()],
+            result
+                .endpoints
+                .iter()
+                .map(|endpoint| endpoint.address)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn factory_with_provider() {
+        struct Provider;
+
+        impl TokenProvider for Provider {
+            fn route(&self, token: &[u8]) -> TokenRoute {
+                match token {
+                    b"pending" => TokenRoute::Pending,
+                    b"assigned" => TokenRoute::Endpoint("127.0.0.1:90".parse().unwrap()),
+                    _ => TokenRoute::Unassigned,
+                }
+            }
+        }
+
+        let factory = TokenRouterFactory::with_provider(&logger(), Arc::new(Provider));
+        let filter = factory
+            .create_filter(CreateFilterArgs::fixed(Registry::default(), None))
+            .unwrap();
+        let read = |token: &[u8]| {
+            let mut ctx = new_ctx();
+            ctx.metadata
+                .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(token.to_vec()));
+            filter.read(ctx)
+        };
+
+        assert_eq!(1, read(b"assigned").unwrap().endpoints.size());
+        assert_eq!(Some(TOKEN_LOOKUP_PENDING), drop_reason(read(b"pending")));
+        // The tokens of the endpoints are ignored.
+        assert_eq!(Some(NO_ENDPOINT_MATCH), drop_reason(read(b"123")));
+    }
+
     #[test]
     fn downstream_receive() {
         // valid key
         let config = Config {
             metadata_key: CAPTURED_BYTES.into(),
+            redis: None,
+            file: None,
+            grpc: None,
         };
         let filter = router(config);
 
@@ -292,13 +787,12 @@ mod tests {
         ctx.metadata
             .insert(Arc::new(CAPTURED_BYTES.into()), Box::new(b"567".to_vec()));
 
-        let option = filter.read(ctx);
-        assert!(option.is_none());
+        assert_eq!(Some(NO_ENDPOINT_MATCH), drop_reason(filter.read(ctx)));
         assert_eq!(1, filter.metrics.packets_dropped_no_endpoint_match.get());
 
         // no key
         let ctx = new_ctx();
-        assert!(filter.read(ctx).is_none());
+        assert_eq!(Some(TOKEN_MISSING), drop_reason(filter.read(ctx)));
         assert_eq!(1, filter.metrics.packets_dropped_no_token_found.get());
 
         // wrong type key
@@ -307,7 +801,7 @@ mod tests {
             Arc::new(CAPTURED_BYTES.into()),
             Box::new(String::from("wrong")),
         );
-        assert!(filter.read(ctx).is_none());
+        assert_eq!(Some(INVALID_TOKEN), drop_reason(filter.read(ctx)));
         assert_eq!(1, filter.metrics.packets_dropped_invalid_token.get());
     }
 
@@ -315,6 +809,9 @@ mod tests {
     fn write() {
         let config = Config {
             metadata_key: CAPTURED_BYTES.into(),
+            redis: None,
+            file: None,
+            grpc: None,
         };
         let filter = router(config);
         assert_write_no_change(&filter);
@@ -0,0 +1,183 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The endpoints that routing tokens are assigned to in a file, which maps
+//! base64 encoded tokens to the addresses of their endpoints. The file is
+//! read again whenever it is modified.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::RwLock;
+use slog::{debug, warn, Logger};
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+use super::{FileConfig, TokenProvider, TokenRoute};
+
+type Assignments = Arc<RwLock<HashMap<Vec<u8>, SocketAddr>>>;
+
+/// Routes tokens to the endpoints they are assigned to in a file. The
+/// background task reading the file stops once it is dropped.
+pub(super) struct FileRoutes {
+    assignments: Assignments,
+    _shutdown_tx: watch::Sender<()>,
+}
+
+impl FileRoutes {
+    /// Reads the file, which is then read again in the background whenever it
+    /// is modified. Must be called from within a Tokio runtime.
+    pub(super) fn new(log: &Logger, config: &FileConfig) -> Result<Self, String> {
+        let modified = modified(&config.path);
+        let assignments = Assignments::new(RwLock::new(read(&config.path)?));
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+
+        tokio::spawn(reload(
+            log.clone(),
+            config.path.clone(),
+            config.interval,
+            modified,
+            assignments.clone(),
+            shutdown_rx,
+        ));
+
+        Ok(Self {
+            assignments,
+            _shutdown_tx: shutdown_tx,
+        })
+    }
+}
+
+impl TokenProvider for FileRoutes {
+    fn route(&self, token: &[u8]) -> TokenRoute {
+        self.assignments
+            .read()
+            .get(token)
+            .copied()
+            .map_or(TokenRoute::Unassigned, TokenRoute::Endpoint)
+    }
+}
+
+/// Reads the file whenever its modification time changes, keeping the
+/// previous assignments if it can't be read.
+async fn reload(
+    log: Logger,
+    path: PathBuf,
+    interval: Duration,
+    mut last_modified: Option<SystemTime>,
+    assignments: Assignments,
+    mut shutdown_rx: watch::Receiver<()>,
+) {
+    let mut interval = time::interval_at(Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown_rx.changed() => return,
+        }
+        let modified = modified(&path);
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+        match read(&path) {
+            Ok(new_assignments) => {
+                debug!(log, "Read the routing tokens"; "path" => %path.display(), "tokens" => new_assignments.len());
+                *assignments.write() = new_assignments;
+            }
+            Err(err) => {
+                warn!(log, "Failed to read the routing tokens, keeping the previous ones"; "path" => %path.display(), "error" => %err)
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reads a YAML or JSON mapping of base64 encoded tokens to addresses.
+fn read(path: &Path) -> Result<HashMap<Vec<u8>, SocketAddr>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read `{}`: {}", path.display(), err))?;
+    let assignments: Option<HashMap<String, SocketAddr>> = serde_yaml::from_str(&contents)
+        .map_err(|err| format!("failed to parse `{}`: {}", path.display(), err))?;
+    assignments
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(token, address)| {
+            base64::decode(&token)
+                .map(|token| (token, address))
+                .map_err(|err| format!("`{}` is not a base64 encoded token: {}", token, err))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::FileRoutes;
+    use crate::filters::extensions::token_router::{FileConfig, TokenProvider, TokenRoute};
+    use crate::test_utils::logger;
+
+    #[tokio::test]
+    async fn read_and_reload_tokens() {
+        let path = std::env::temp_dir().join(format!("quilkin-{}.yaml", uuid::Uuid::new_v4()));
+        // `abc` and `xyz`.
+        std::fs::write(&path, "YWJj: 127.0.0.1:80\neHl6: 127.0.0.1:90\n").unwrap();
+
+        let config = FileConfig {
+            path: path.clone(),
+            interval: Duration::from_millis(10),
+        };
+        let routes = FileRoutes::new(&logger(), &config).unwrap();
+        assert_eq!(
+            TokenRoute::Endpoint("127.0.0.1:80".parse().unwrap()),
+            routes.route(b"abc")
+        );
+        assert_eq!(TokenRoute::Unassigned, routes.route(b"123"));
+
+        // Make sure the modification time changes on file systems that only
+        // keep it to the second.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        std::fs::write(&path, "{\"YWJj\": \"127.0.0.1:90\"}").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while routes.route(b"abc") != TokenRoute::Endpoint("127.0.0.1:90".parse().unwrap()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(TokenRoute::Unassigned, routes.route(b"xyz"));
+
+        // Invalid files are ignored.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        std::fs::write(&path, "not base64: 127.0.0.1:80").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            TokenRoute::Endpoint("127.0.0.1:90".parse().unwrap()),
+            routes.route(b"abc")
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(FileRoutes::new(&logger(), &config).is_err());
+    }
+}
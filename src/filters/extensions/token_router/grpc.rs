@@ -0,0 +1,242 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The endpoints that routing tokens are assigned to by a `TokenProvider`
+//! gRPC service, whose stream of assignments is watched for as long as the
+//! filter exists.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use slog::{debug, warn, Logger};
+use tokio::sync::watch;
+use tokio::time;
+use tonic::transport::Channel;
+
+use super::quilkin::extensions::filters::token_router::v1alpha1::{
+    token_provider_client::TokenProviderClient, Assignments, WatchAssignmentsRequest,
+};
+use super::{TokenProvider, TokenRoute};
+
+/// How long to wait before watching the assignments again after the stream
+/// failed or ended.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The assignments received so far, or `None` until the first ones are.
+type State = Arc<RwLock<Option<HashMap<Vec<u8>, SocketAddr>>>>;
+
+/// Routes tokens to the endpoints a `TokenProvider` service assigns them to.
+/// The background task watching the assignments stops once it is dropped.
+pub(super) struct GrpcRoutes {
+    state: State,
+    _shutdown_tx: watch::Sender<()>,
+}
+
+impl GrpcRoutes {
+    /// Creates the routes, which connect to the service through `channel` in
+    /// the background. Must be called from within a Tokio runtime.
+    pub(super) fn new(log: &Logger, channel: Channel) -> Self {
+        let state = State::default();
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::spawn(watch_assignments(
+            log.clone(),
+            TokenProviderClient::new(channel),
+            state.clone(),
+            shutdown_rx,
+        ));
+        Self {
+            state,
+            _shutdown_tx: shutdown_tx,
+        }
+    }
+}
+
+impl TokenProvider for GrpcRoutes {
+    /// Returns the route of `token`, which is pending until the first
+    /// assignments are received.
+    fn route(&self, token: &[u8]) -> TokenRoute {
+        match &*self.state.read() {
+            Some(assignments) => assignments
+                .get(token)
+                .copied()
+                .map_or(TokenRoute::Unassigned, TokenRoute::Endpoint),
+            None => TokenRoute::Pending,
+        }
+    }
+}
+
+/// Applies the assignments streamed by the service, watching them again
+/// whenever the stream fails or ends. The previous assignments are kept in
+/// the meantime.
+async fn watch_assignments(
+    log: Logger,
+    mut client: TokenProviderClient<Channel>,
+    state: State,
+    mut shutdown_rx: watch::Receiver<()>,
+) {
+    loop {
+        let watch = async {
+            let mut stream = client
+                .watch_assignments(WatchAssignmentsRequest {})
+                .await?
+                .into_inner();
+            debug!(log, "Watching the assignments of routing tokens");
+            while let Some(assignments) = stream.message().await? {
+                apply(&log, &state, assignments);
+            }
+            Ok::<_, tonic::Status>(())
+        };
+
+        tokio::select! {
+            result = watch => match result {
+                Ok(()) => warn!(log, "The stream of routing token assignments ended"),
+                Err(err) => warn!(log, "Failed to watch the assignments of routing tokens"; "error" => %err),
+            },
+            _ = shutdown_rx.changed() => return,
+        }
+        tokio::select! {
+            _ = time::sleep(RECONNECT_DELAY) => {}
+            _ = shutdown_rx.changed() => return,
+        }
+    }
+}
+
+fn apply(log: &Logger, state: &State, assignments: Assignments) {
+    let mut state = state.write();
+    let current = state.get_or_insert_with(HashMap::new);
+    if assignments.replace {
+        current.clear();
+    }
+    for assignment in assignments.assignments {
+        if assignment.endpoint.is_empty() {
+            current.remove(&assignment.token);
+            continue;
+        }
+        match assignment.endpoint.parse() {
+            Ok(address) => {
+                current.insert(assignment.token, address);
+            }
+            Err(_) => {
+                warn!(log, "Ignoring a routing token assigned to an invalid address"; "address" => &assignment.endpoint)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tokio_stream::Stream;
+    use tonic::transport::{Endpoint, Server};
+    use tonic::{Request, Response, Status};
+
+    use super::GrpcRoutes;
+    use crate::filters::extensions::token_router::quilkin::extensions::filters::token_router::v1alpha1::{
+        token_provider_server::{TokenProvider as TokenProviderService, TokenProviderServer},
+        Assignment, Assignments, WatchAssignmentsRequest,
+    };
+    use crate::filters::extensions::token_router::{TokenProvider, TokenRoute};
+    use crate::test_utils::logger;
+
+    /// Streams the assignments sent to it to the first watch.
+    struct MockProvider(parking_lot::Mutex<Option<mpsc::Receiver<Result<Assignments, Status>>>>);
+
+    #[tonic::async_trait]
+    impl TokenProviderService for MockProvider {
+        type WatchAssignmentsStream =
+            Pin<Box<dyn Stream<Item = Result<Assignments, Status>> + Send + Sync + 'static>>;
+
+        async fn watch_assignments(
+            &self,
+            _: Request<WatchAssignmentsRequest>,
+        ) -> Result<Response<Self::WatchAssignmentsStream>, Status> {
+            let assignments_rx = self.0.lock().take().expect("watched more than once");
+            Ok(Response::new(Box::pin(ReceiverStream::new(assignments_rx))))
+        }
+    }
+
+    fn assignment(token: &[u8], endpoint: &str) -> Assignment {
+        Assignment {
+            token: token.to_vec(),
+            endpoint: endpoint.into(),
+        }
+    }
+
+    async fn wait_for(routes: &GrpcRoutes, token: &[u8], expected: TokenRoute) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while routes.route(token) != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {:?}", expected));
+    }
+
+    #[tokio::test]
+    async fn watch_assignments() {
+        let (assignments_tx, assignments_rx) = mpsc::channel(10);
+        let address: SocketAddr = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        tokio::spawn(
+            Server::builder()
+                .add_service(TokenProviderServer::new(MockProvider(
+                    parking_lot::Mutex::new(Some(assignments_rx)),
+                )))
+                .serve(address),
+        );
+        let channel = Endpoint::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect_lazy()
+            .unwrap();
+        let routes = GrpcRoutes::new(&logger(), channel);
+        assert_eq!(TokenRoute::Pending, routes.route(b"abc"));
+
+        let endpoint = |address: &str| TokenRoute::Endpoint(address.parse::<SocketAddr>().unwrap());
+        assignments_tx
+            .send(Ok(Assignments {
+                replace: true,
+                assignments: vec![
+                    assignment(b"abc", "127.0.0.1:80"),
+                    assignment(b"xyz", "127.0.0.1:90"),
+                ],
+            }))
+            .await
+            .unwrap();
+        wait_for(&routes, b"abc", endpoint("127.0.0.1:80")).await;
+        assert_eq!(endpoint("127.0.0.1:90"), routes.route(b"xyz"));
+        assert_eq!(TokenRoute::Unassigned, routes.route(b"123"));
+
+        assignments_tx
+            .send(Ok(Assignments {
+                replace: false,
+                assignments: vec![assignment(b"abc", "127.0.0.1:90"), assignment(b"xyz", "")],
+            }))
+            .await
+            .unwrap();
+        wait_for(&routes, b"xyz", TokenRoute::Unassigned).await;
+        assert_eq!(endpoint("127.0.0.1:90"), routes.route(b"abc"));
+    }
+}
@@ -23,6 +23,7 @@ pub(super) struct Metrics {
     pub(super) packets_dropped_no_token_found: GenericCounter<AtomicU64>,
     pub(super) packets_dropped_invalid_token: GenericCounter<AtomicU64>,
     pub(super) packets_dropped_no_endpoint_match: GenericCounter<AtomicU64>,
+    pub(super) packets_dropped_token_lookup_pending: GenericCounter<AtomicU64>,
 }
 
 impl Metrics {
@@ -45,6 +46,8 @@ impl Metrics {
                 .get_metric_with_label_values(vec!["InvalidToken"].as_slice())?,
             packets_dropped_no_endpoint_match: metric
                 .get_metric_with_label_values(vec!["NoEndpointMatch"].as_slice())?,
+            packets_dropped_token_lookup_pending: metric
+                .get_metric_with_label_values(vec!["TokenLookupPending"].as_slice())?,
         })
     }
 }
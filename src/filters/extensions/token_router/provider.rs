@@ -0,0 +1,63 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::SocketAddr;
+
+/// Where the packets with a routing token are sent to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenRoute {
+    /// The endpoint of the token isn't known yet, e.g because it is being
+    /// looked up. Packets are dropped until it is.
+    Pending,
+    /// The token isn't assigned to any endpoint.
+    Unassigned,
+    /// The token is assigned to the endpoint with this address.
+    Endpoint(SocketAddr),
+}
+
+/// Supplies the endpoints that routing tokens are assigned to, which the
+/// [`TokenRouter`][super::TokenRouterFactory] filter sends packets to instead
+/// of matching tokens against the tokens of the endpoints.
+///
+/// Providers are responsible for keeping their assignments up to date, e.g
+/// from a background task. [`TokenProvider::route`] is called for every
+/// packet, so it must return without waiting on anything.
+///
+/// ```
+/// # use std::collections::HashMap;
+/// # use std::net::SocketAddr;
+/// # use std::sync::Arc;
+/// use quilkin::filters::{extensions::{TokenProvider, TokenRoute, TokenRouterFactory}, FilterSet};
+///
+/// struct Matchmaker(HashMap<Vec<u8>, SocketAddr>);
+///
+/// impl TokenProvider for Matchmaker {
+///     fn route(&self, token: &[u8]) -> TokenRoute {
+///         self.0.get(token).copied().map_or(TokenRoute::Unassigned, TokenRoute::Endpoint)
+///     }
+/// }
+///
+/// # let log = quilkin::test_utils::logger();
+/// let provider = Matchmaker(HashMap::new());
+/// let filters = FilterSet::default_with(
+///     &log,
+///     Some(Box::from(TokenRouterFactory::with_provider(&log, Arc::new(provider))) as _),
+/// );
+/// ```
+pub trait TokenProvider: Send + Sync {
+    /// Returns where the packets with `token` are sent to.
+    fn route(&self, token: &[u8]) -> TokenRoute;
+}
@@ -0,0 +1,430 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The endpoints that routing tokens are assigned to in Redis.
+//!
+//! Each token is looked up once, the first time a packet carries it, and the
+//! result is cached until it expires or the token is published on the
+//! invalidation channel. Packets are never held back by a lookup, they are
+//! dropped until it completes instead.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use redis::aio::MultiplexedConnection;
+use redis::{Client, RedisError};
+use slog::{debug, warn, Logger};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Instant};
+use tokio_stream::StreamExt;
+
+use super::{RedisConfig, TokenProvider, TokenRoute};
+
+/// The most tokens that can wait to be looked up. Packets with a token that
+/// isn't cached are dropped without looking it up once it is reached.
+const LOOKUP_QUEUE_SIZE: usize = 1024;
+/// The most tokens looked up with a single request.
+const LOOKUP_BATCH_SIZE: usize = 128;
+/// How long to wait before connecting to Redis again after failing to.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+enum Entry {
+    Pending,
+    Resolved {
+        address: Option<SocketAddr>,
+        expires: Instant,
+    },
+}
+
+impl Entry {
+    /// Returns the route of the entry, unless it expired.
+    fn route(&self, now: Instant) -> Option<TokenRoute> {
+        match self {
+            Entry::Pending => Some(TokenRoute::Pending),
+            Entry::Resolved { address, expires } if *expires > now => {
+                Some(address.map_or(TokenRoute::Unassigned, TokenRoute::Endpoint))
+            }
+            Entry::Resolved { .. } => None,
+        }
+    }
+}
+
+type Cache = Arc<RwLock<HashMap<Vec<u8>, Entry>>>;
+
+/// Looks up and caches the endpoints of routing tokens. The background tasks
+/// doing so stop once it is dropped.
+pub(super) struct RedisRoutes {
+    cache: Cache,
+    lookups_tx: mpsc::Sender<Vec<u8>>,
+    _shutdown_tx: watch::Sender<()>,
+}
+
+impl RedisRoutes {
+    /// Creates the routes, which connect to Redis in the background. Must be
+    /// called from within a Tokio runtime.
+    pub(super) fn new(log: &Logger, config: &RedisConfig) -> Result<Self, RedisError> {
+        let client = Client::open(config.address.as_str())?;
+        let cache = Cache::default();
+        let (lookups_tx, lookups_rx) = mpsc::channel(LOOKUP_QUEUE_SIZE);
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+
+        tokio::spawn(lookup(
+            log.clone(),
+            client.clone(),
+            config.key_prefix.clone(),
+            config.cache_ttl,
+            cache.clone(),
+            lookups_rx,
+            shutdown_rx.clone(),
+        ));
+        tokio::spawn(invalidate(
+            log.clone(),
+            client,
+            config.channel.clone(),
+            cache.clone(),
+            shutdown_rx,
+        ));
+
+        Ok(Self {
+            cache,
+            lookups_tx,
+            _shutdown_tx: shutdown_tx,
+        })
+    }
+}
+
+impl TokenProvider for RedisRoutes {
+    /// Returns the route of `token`, starting to look it up if it isn't
+    /// cached.
+    fn route(&self, token: &[u8]) -> TokenRoute {
+        let now = Instant::now();
+        if let Some(route) = self.cache.read().get(token).and_then(|e| e.route(now)) {
+            return route;
+        }
+
+        let mut cache = self.cache.write();
+        // Another packet may have started looking up the token in the meantime.
+        if let Some(route) = cache.get(token).and_then(|e| e.route(now)) {
+            return route;
+        }
+        if self.lookups_tx.try_send(token.to_vec()).is_ok() {
+            cache.insert(token.to_vec(), Entry::Pending);
+        }
+        TokenRoute::Pending
+    }
+}
+
+/// Looks up the tokens received on `lookups_rx`, and removes expired entries
+/// from the cache.
+async fn lookup(
+    log: Logger,
+    client: Client,
+    key_prefix: String,
+    cache_ttl: Duration,
+    cache: Cache,
+    mut lookups_rx: mpsc::Receiver<Vec<u8>>,
+    mut shutdown_rx: watch::Receiver<()>,
+) {
+    let mut connection: Option<MultiplexedConnection> = None;
+    let mut prune_interval = time::interval_at(Instant::now() + cache_ttl, cache_ttl);
+    loop {
+        let token = tokio::select! {
+            token = lookups_rx.recv() => match token {
+                Some(token) => token,
+                None => return,
+            },
+            _ = prune_interval.tick() => {
+                let now = Instant::now();
+                cache.write().retain(|_, entry| entry.route(now).is_some());
+                continue;
+            }
+            _ = shutdown_rx.changed() => return,
+        };
+
+        // Look up every token that is waiting at once.
+        let mut tokens = vec![token];
+        while tokens.len() < LOOKUP_BATCH_SIZE {
+            match lookups_rx.try_recv() {
+                Ok(token) => tokens.push(token),
+                Err(_) => break,
+            }
+        }
+
+        let result = match connection.as_mut() {
+            Some(connection) => Ok(connection),
+            None => client
+                .get_multiplexed_tokio_connection()
+                .await
+                .map(|new_connection| connection.get_or_insert(new_connection)),
+        };
+        let result = match result {
+            Ok(connection) => {
+                let keys: Vec<Vec<u8>> = tokens
+                    .iter()
+                    .map(|token| [key_prefix.as_bytes(), token].concat())
+                    .collect();
+                redis::cmd("MGET")
+                    .arg(keys)
+                    .query_async::<_, Vec<Option<String>>>(connection)
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+
+        let values = match result {
+            Ok(values) => values,
+            Err(err) => {
+                warn!(log, "Failed to look up routing tokens in Redis"; "tokens" => tokens.len(), "error" => %err);
+                // The tokens are looked up again by the next packet that
+                // carries them.
+                {
+                    let mut cache = cache.write();
+                    for token in &tokens {
+                        cache.remove(token);
+                    }
+                }
+                connection = None;
+                tokio::select! {
+                    _ = time::sleep(RECONNECT_DELAY) => {}
+                    _ = shutdown_rx.changed() => return,
+                }
+                continue;
+            }
+        };
+
+        let expires = Instant::now() + cache_ttl;
+        let mut cache = cache.write();
+        for (token, value) in tokens.into_iter().zip(values) {
+            let address = value.and_then(|value| match value.parse() {
+                Ok(address) => Some(address),
+                Err(_) => {
+                    warn!(log, "Ignoring a routing token assigned to an invalid address"; "address" => value);
+                    None
+                }
+            });
+            // The token was invalidated while it was being looked up, so the
+            // value may already be out of date.
+            if let Some(entry @ Entry::Pending) = cache.get_mut(&token) {
+                *entry = Entry::Resolved { address, expires };
+            }
+        }
+    }
+}
+
+/// Removes the tokens published on `channel` from the cache.
+async fn invalidate(
+    log: Logger,
+    client: Client,
+    channel: String,
+    cache: Cache,
+    mut shutdown_rx: watch::Receiver<()>,
+) {
+    loop {
+        let subscription = async {
+            let mut pubsub = client.get_async_connection().await?.into_pubsub();
+            pubsub.subscribe(&channel).await?;
+            // Tokens may have been assigned to other endpoints while not
+            // subscribed.
+            cache.write().clear();
+            debug!(log, "Subscribed to routing token invalidations"; "channel" => &channel);
+
+            let mut messages = pubsub.on_message();
+            while let Some(message) = messages.next().await {
+                cache.write().remove(message.get_payload_bytes());
+            }
+            Ok::<_, RedisError>(())
+        };
+
+        tokio::select! {
+            result = subscription => match result {
+                Ok(()) => warn!(log, "Lost the subscription to routing token invalidations"; "channel" => &channel),
+                Err(err) => warn!(log, "Failed to subscribe to routing token invalidations"; "channel" => &channel, "error" => %err),
+            },
+            _ = shutdown_rx.changed() => return,
+        }
+        tokio::select! {
+            _ = time::sleep(RECONNECT_DELAY) => {}
+            _ = shutdown_rx.changed() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast;
+
+    use super::RedisRoutes;
+    use crate::filters::extensions::token_router::{RedisConfig, TokenProvider, TokenRoute};
+    use crate::test_utils::logger;
+
+    /// Just enough of a Redis server to look up keys with `MGET` and
+    /// `SUBSCRIBE` to a channel.
+    struct MockRedis {
+        values: Mutex<HashMap<Vec<u8>, String>>,
+        messages: broadcast::Sender<Vec<u8>>,
+    }
+
+    impl MockRedis {
+        async fn serve(self: Arc<Self>, stream: TcpStream) {
+            let mut stream = BufReader::new(stream);
+            loop {
+                let command = match read_command(&mut stream).await {
+                    Some(command) => command,
+                    None => return,
+                };
+                let reply = match command[0].to_ascii_uppercase().as_slice() {
+                    b"MGET" => {
+                        let values = self.values.lock();
+                        let mut reply = format!("*{}\r\n", command.len() - 1).into_bytes();
+                        for key in &command[1..] {
+                            reply.extend(match values.get(key) {
+                                Some(value) => bulk(value.as_bytes()),
+                                None => b"$-1\r\n".to_vec(),
+                            });
+                        }
+                        reply
+                    }
+                    b"SUBSCRIBE" => {
+                        let mut messages = self.messages.subscribe();
+                        let channel = command[1].clone();
+                        let mut reply = b"*3\r\n".to_vec();
+                        reply.extend(bulk(b"subscribe"));
+                        reply.extend(bulk(&channel));
+                        reply.extend(b":1\r\n");
+                        stream.write_all(&reply).await.unwrap();
+                        while let Ok(payload) = messages.recv().await {
+                            let mut message = b"*3\r\n".to_vec();
+                            message.extend(bulk(b"message"));
+                            message.extend(bulk(&channel));
+                            message.extend(bulk(&payload));
+                            stream.write_all(&message).await.unwrap();
+                        }
+                        return;
+                    }
+                    other => panic!("unexpected command {:?}", String::from_utf8_lossy(other)),
+                };
+                stream.write_all(&reply).await.unwrap();
+            }
+        }
+    }
+
+    fn bulk(value: &[u8]) -> Vec<u8> {
+        let mut bulk = format!("${}\r\n", value.len()).into_bytes();
+        bulk.extend(value);
+        bulk.extend(b"\r\n");
+        bulk
+    }
+
+    async fn read_command(stream: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+        let mut command = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            stream.read_line(&mut line).await.ok()?;
+            let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            stream.read_exact(&mut arg).await.ok()?;
+            arg.truncate(len);
+            command.push(arg);
+        }
+        Some(command)
+    }
+
+    async fn wait_for(routes: &RedisRoutes, token: &[u8], expected: TokenRoute) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while routes.route(token) != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {:?}", expected));
+    }
+
+    #[tokio::test]
+    async fn look_up_and_invalidate_tokens() {
+        let redis = Arc::new(MockRedis {
+            values: Mutex::new(
+                vec![(b"quilkin:token:abc".to_vec(), "127.0.0.1:80".into())]
+                    .into_iter()
+                    .collect(),
+            ),
+            messages: broadcast::channel(10).0,
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = redis.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(server.clone().serve(stream));
+            }
+        });
+
+        let routes = RedisRoutes::new(
+            &logger(),
+            &RedisConfig {
+                address: format!("redis://{}", address),
+                key_prefix: "quilkin:token:".into(),
+                channel: "quilkin:tokens".into(),
+                cache_ttl: Duration::from_secs(60),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(TokenRoute::Pending, routes.route(b"abc"));
+        wait_for(
+            &routes,
+            b"abc",
+            TokenRoute::Endpoint("127.0.0.1:80".parse().unwrap()),
+        )
+        .await;
+        wait_for(&routes, b"xyz", TokenRoute::Unassigned).await;
+
+        // Wait for the subscription before publishing to it.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while redis.messages.receiver_count() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        redis
+            .values
+            .lock()
+            .insert(b"quilkin:token:abc".to_vec(), "127.0.0.1:90".into());
+        redis.messages.send(b"abc".to_vec()).unwrap();
+        wait_for(
+            &routes,
+            b"abc",
+            TokenRoute::Endpoint("127.0.0.1:90".parse().unwrap()),
+        )
+        .await;
+    }
+}
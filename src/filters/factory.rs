@@ -31,12 +31,22 @@ pub trait FilterFactory: Sync + Send {
     ///     <version>: The filter's version.
     ///     <item-name>: The name of the rust item (e.g enum, struct) implementing the filter.
     /// For example the `v1alpha1` version of the debug filter has the name:
-    ///     `quilkin.extensions.filters.debug_filter.v1alpha1.Debug`
+    ///     `quilkin.extensions.filters.debug.v1alpha1.Debug`
     fn name(&self) -> &'static str;
 
     /// Returns a filter based on the provided arguments.
     fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error>;
 
+    /// Returns the schema of the filter's configuration, if it has one, as a
+    /// YAML document in the JSON Schema style of the filter documentation.
+    ///
+    /// `quilkin generate-config` writes an example configuration from the
+    /// `properties` it lists, using the `description`, `example`, `default`
+    /// and `enum` of each, and the schema's `required` list.
+    fn config_schema(&self) -> Option<&'static str> {
+        None
+    }
+
     /// Returns the [`ConfigType`] from the provided Option, otherwise it returns
     /// Error::MissingConfig if the Option is None.
     fn require_config<'a, 'b>(
@@ -79,13 +89,4 @@ impl CreateFilterArgs<'_> {
             metrics_registry,
         }
     }
-
-    /// Consumes `self` and returns a new instance of [`Self`] using
-    /// `metrics_registry` for metrics.
-    pub(crate) fn with_metrics_registry(self, metrics_registry: Registry) -> Self {
-        CreateFilterArgs {
-            metrics_registry,
-            ..self
-        }
-    }
 }
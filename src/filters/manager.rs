@@ -15,6 +15,7 @@
  */
 
 use crate::filters::{FilterChain, FilterRegistry};
+use crate::proxy::events::{Event, EventHistory};
 
 use std::sync::Arc;
 
@@ -63,6 +64,12 @@ impl FilterManager {
         self.filter_chain.clone()
     }
 
+    /// Replaces the filter chain of a fixed FilterManager, e.g when the
+    /// configuration file it came from changes.
+    pub fn set_filter_chain(&mut self, filter_chain: Arc<FilterChain>) {
+        self.update(filter_chain);
+    }
+
     /// Returns a new instance backed only by the provided filter chain.
     pub fn fixed(filter_chain: Arc<FilterChain>) -> SharedFilterManager {
         Arc::new(RwLock::new(FilterManager { filter_chain }))
@@ -72,6 +79,7 @@ impl FilterManager {
     /// Updates from the provided stream will be reflected in the current filter chain.
     pub fn dynamic(
         base_logger: Logger,
+        events: EventHistory,
         filter_chain_update: Arc<FilterChain>,
         filter_chain_updates_rx: mpsc::Receiver<Arc<FilterChain>>,
         shutdown_rx: watch::Receiver<()>,
@@ -87,6 +95,7 @@ impl FilterManager {
         // and update the FilterManager's filter chain in turn.
         Self::spawn_updater(
             log,
+            events,
             filter_manager.clone(),
             filter_chain_updates_rx,
             shutdown_rx,
@@ -99,6 +108,7 @@ impl FilterManager {
     /// updates the filter manager's current filter in turn.
     fn spawn_updater(
         log: Logger,
+        events: EventHistory,
         filter_manager: SharedFilterManager,
         mut filter_chain_updates_rx: mpsc::Receiver<Arc<FilterChain>>,
         mut shutdown_rx: watch::Receiver<()>,
@@ -110,7 +120,9 @@ impl FilterManager {
                         match update {
                             Some(filter_chain) => {
                                 debug!(log, "Received a filter chain update.");
+                                let event = filter_chain_event(&filter_manager.read().filter_chain, &filter_chain);
                                 filter_manager.write().update(filter_chain);
+                                events.record(&log, event);
                             }
                             None => {
                                 warn!(log, "Exiting filter chain update receive loop because the sender dropped the channel.");
@@ -132,10 +144,26 @@ impl FilterManager {
     }
 }
 
+/// Returns the event for replacing the filter chain `before` with `after`.
+pub(crate) fn filter_chain_event(before: &FilterChain, after: &FilterChain) -> Event {
+    let names = |filter_chain: &FilterChain| {
+        filter_chain
+            .configs()
+            .map(|(name, _)| name.to_owned())
+            .collect::<Vec<_>>()
+    };
+    let (before, after) = (names(before), names(after));
+    Event::new("filter_chain.update", "Replaced the filter chain")
+        .with("filters_before", before.len())
+        .with("filters_after", after.len())
+        .with("filter_names", after.join(","))
+}
+
 #[cfg(test)]
 mod tests {
     use super::FilterManager;
     use crate::filters::{Filter, FilterChain, ReadContext, ReadResponse};
+    use crate::proxy::events::EventHistory;
     use crate::test_utils::logger;
 
     use std::sync::Arc;
@@ -157,6 +185,7 @@ mod tests {
 
         FilterManager::spawn_updater(
             logger(),
+            EventHistory::default(),
             filter_manager.clone(),
             filter_chain_updates_rx,
             shutdown_rx,
@@ -229,6 +258,7 @@ mod tests {
 
         FilterManager::spawn_updater(
             logger(),
+            EventHistory::default(),
             filter_manager.clone(),
             filter_chain_updates_rx,
             shutdown_rx,
@@ -0,0 +1,303 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Loading of filter factories from shared libraries, so that filters can be
+//! distributed as binaries and used by a stock build of Quilkin.
+//!
+//! A plugin is a `cdylib` crate that registers its factories in a function
+//! exported with [`export_plugin!`][crate::export_plugin]:
+//!
+//! ```rust,no_run
+//! use quilkin::filters::{extensions::DebugFactory, plugin::Registrar};
+//!
+//! fn register(registrar: &mut Registrar) {
+//!     let factory = DebugFactory::new(registrar.log());
+//!     registrar.register(Box::new(factory));
+//! }
+//!
+//! quilkin::export_plugin!(register);
+//! ```
+//!
+//! There is no stable ABI between a plugin and Quilkin: filters are passed
+//! between them as Rust trait objects, whose layout is only guaranteed to
+//! match when both are built with the same version of Quilkin and of the
+//! compiler, and with the same Cargo features of Quilkin, so a plugin built
+//! any other way is rejected when it is loaded. Even then, a plugin links
+//! its own copy of Quilkin and of every crate they share, so that:
+//!
+//! - Statics are not shared. The filters of a plugin see their own copies
+//!   rather than those of the proxy, and should only rely on what their
+//!   factory is passed, such as the metrics registry and the logger of the
+//!   [`Registrar`]. Nor are thread-locals, so the packets a plugin's filters
+//!   drop are counted without a reason.
+//! - The proxy's Tokio runtime is not visible to the plugin, whose copy of
+//!   Tokio has its own thread-locals, so filters can't spawn tasks, create
+//!   timers or do any other I/O through Tokio.
+//! - Memory allocated on one side may be freed on the other, which is only
+//!   sound when both use the system allocator. Plugins are rejected by a
+//!   build with the `profiling` feature, whose allocator is jemalloc.
+//!
+//! The plugin in `tests/plugin` is built and loaded by its tests.
+
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use slog::{info, o, Logger};
+
+use crate::config::Plugin;
+use crate::filters::DynFilterFactory;
+
+/// The symbol of the function returning the [`ABI_VERSION`] a plugin was
+/// built with.
+const ABI_VERSION_SYMBOL: &str = "quilkin_plugin_abi_version";
+/// The symbol of the function registering the filter factories of a plugin.
+const REGISTER_SYMBOL: &str = "quilkin_plugin_register";
+
+/// Identifies the versions of Quilkin and of the compiler a plugin was built
+/// with, along with a hash of the enabled features of Quilkin, as a
+/// nul-terminated string.
+#[doc(hidden)]
+pub const ABI_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " ",
+    env!("QUILKIN_RUSTC_VERSION"),
+    " features ",
+    env!("QUILKIN_FEATURES_HASH"),
+    "\0"
+);
+
+/// Collects the filter factories of a plugin while it is loaded.
+pub struct Registrar {
+    log: Logger,
+    factories: Vec<DynFilterFactory>,
+}
+
+impl Registrar {
+    /// The logger that the plugin's filters can log to.
+    pub fn log(&self) -> &Logger {
+        &self.log
+    }
+
+    /// Makes the filters created by `factory` available to the proxy. A
+    /// factory with the same name as a built-in one replaces it.
+    pub fn register(&mut self, factory: DynFilterFactory) {
+        self.factories.push(factory);
+    }
+}
+
+/// Exports `$register`, a `fn(&mut Registrar)`, as the entry point of a
+/// plugin. See the [`plugin`][crate::filters::plugin] module.
+#[macro_export]
+macro_rules! export_plugin {
+    ($register:path) => {
+        #[doc(hidden)]
+        #[no_mangle]
+        pub extern "C" fn quilkin_plugin_abi_version() -> *const std::os::raw::c_char {
+            $crate::filters::plugin::ABI_VERSION.as_ptr() as *const std::os::raw::c_char
+        }
+
+        #[doc(hidden)]
+        #[no_mangle]
+        pub unsafe extern "C" fn quilkin_plugin_register(
+            registrar: *mut $crate::filters::plugin::Registrar,
+        ) {
+            $register(&mut *registrar)
+        }
+    };
+}
+
+/// The reason a plugin could not be loaded.
+#[derive(Debug)]
+pub struct Error {
+    path: PathBuf,
+    reason: String,
+}
+
+impl Error {
+    fn new(path: &Path, reason: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to load the plugin `{}`: {}",
+            self.path.display(),
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Loads `plugins` in order and returns the filter factories they register.
+///
+/// The libraries stay loaded until the process exits, as the factories and
+/// the filters they create run code from them.
+pub fn load(base: &Logger, plugins: &[Plugin]) -> Result<Vec<DynFilterFactory>, Error> {
+    let mut registrar = Registrar {
+        log: base.clone(),
+        factories: vec![],
+    };
+    let log = base.new(o!("source" => "filters::plugin"));
+    for plugin in plugins {
+        let registered = registrar.factories.len();
+        library::register(&plugin.path, &mut registrar)?;
+        let filters: Vec<_> = registrar.factories[registered..]
+            .iter()
+            .map(|factory| factory.name())
+            .collect();
+        info!(log, "Loaded plugin"; "path" => %plugin.path.display(), "filters" => filters.join(", "));
+    }
+    Ok(registrar.factories)
+}
+
+#[cfg(unix)]
+mod library {
+    use std::ffi::{CStr, CString};
+    use std::os::raw::{c_char, c_void};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use super::{Error, Registrar, ABI_VERSION, ABI_VERSION_SYMBOL, REGISTER_SYMBOL};
+
+    /// Opens the library at `path` and calls its registration function, if
+    /// it was built with the same [`ABI_VERSION`]. The library is never
+    /// closed.
+    pub(super) fn register(path: &Path, registrar: &mut Registrar) -> Result<(), Error> {
+        if cfg!(feature = "profiling") {
+            return Err(Error::new(
+                path,
+                "plugins can't be loaded when Quilkin is built with the `profiling` feature, as they don't share its allocator",
+            ));
+        }
+        let name = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| Error::new(path, "the path contains a nul byte"))?;
+        // Safety: the name is nul-terminated, and loading the library runs
+        // its initializers, which the configuration trusts.
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(Error::new(path, last_error()));
+        }
+
+        let abi_version = symbol(handle, ABI_VERSION_SYMBOL)
+            .ok_or_else(|| Error::new(path, "it is not a Quilkin plugin"))?;
+        // Safety: the symbol is the function exported by `export_plugin!`,
+        // which returns a pointer to a nul-terminated string.
+        let abi_version = unsafe {
+            let abi_version: extern "C" fn() -> *const c_char = std::mem::transmute(abi_version);
+            CStr::from_ptr(abi_version())
+        };
+        let expected = &ABI_VERSION[..ABI_VERSION.len() - 1];
+        if abi_version.to_bytes() != expected.as_bytes() {
+            return Err(Error::new(
+                path,
+                format!(
+                    "it was built for `{}` rather than `{}`",
+                    abi_version.to_string_lossy(),
+                    expected
+                ),
+            ));
+        }
+
+        let register = symbol(handle, REGISTER_SYMBOL)
+            .ok_or_else(|| Error::new(path, "it has no registration function"))?;
+        // Safety: the symbol is the function exported by `export_plugin!`,
+        // and the plugin was built with the same layout of `Registrar`.
+        unsafe {
+            let register: unsafe extern "C" fn(*mut Registrar) = std::mem::transmute(register);
+            register(registrar);
+        }
+        Ok(())
+    }
+
+    fn symbol(handle: *mut c_void, name: &str) -> Option<*mut c_void> {
+        let name = CString::new(name).unwrap();
+        // Safety: the handle is of an open library and the name is
+        // nul-terminated.
+        let symbol = unsafe { libc::dlsym(handle, name.as_ptr()) };
+        if symbol.is_null() {
+            None
+        } else {
+            Some(symbol)
+        }
+    }
+
+    fn last_error() -> String {
+        // Safety: the message, if any, is valid until the next call to
+        // `dlerror`, and is copied before then.
+        unsafe {
+            let message = libc::dlerror();
+            if message.is_null() {
+                "unknown error".into()
+            } else {
+                CStr::from_ptr(message).to_string_lossy().into_owned()
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod library {
+    use std::path::Path;
+
+    use super::{Error, Registrar};
+
+    pub(super) fn register(path: &Path, _: &mut Registrar) -> Result<(), Error> {
+        Err(Error::new(path, "plugins are only supported on Unix"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load;
+    use crate::config::Plugin;
+    use crate::test_utils::logger;
+
+    #[test]
+    fn load_plugins() {
+        assert!(load(&logger(), &[]).unwrap().is_empty());
+
+        let missing = Plugin {
+            path: std::env::temp_dir().join(format!("quilkin-{}.so", uuid::Uuid::new_v4())),
+        };
+        let err = load(&logger(), std::slice::from_ref(&missing))
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with(&format!(
+            "failed to load the plugin `{}`",
+            missing.path.display()
+        )));
+
+        // A library that doesn't export the entry points.
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        {
+            let libc = Plugin {
+                path: "libc.so.6".into(),
+            };
+            let err = load(&logger(), &[libc]).err().unwrap();
+            assert_eq!(
+                "failed to load the plugin `libc.so.6`: it is not a Quilkin plugin",
+                err.to_string()
+            );
+        }
+    }
+}
@@ -16,6 +16,8 @@
 
 use std::{any::Any, collections::HashMap, net::SocketAddr, sync::Arc};
 
+use bytes::Bytes;
+
 use crate::config::UpstreamEndpoints;
 #[cfg(doc)]
 use crate::filters::Filter;
@@ -30,19 +32,20 @@ pub struct ReadContext {
     pub endpoints: UpstreamEndpoints,
     /// The source of the received packet.
     pub from: SocketAddr,
-    /// Contents of the received packet.
-    pub contents: Vec<u8>,
+    /// Contents of the received packet. Cloning or slicing the contents
+    /// does not copy them.
+    pub contents: Bytes,
     /// Arbitrary values that can be passed from one filter to another.
     pub metadata: DynamicMetadata,
 }
 
 impl ReadContext {
     /// Creates a new [`ReadContext`].
-    pub fn new(endpoints: UpstreamEndpoints, from: SocketAddr, contents: Vec<u8>) -> Self {
+    pub fn new(endpoints: UpstreamEndpoints, from: SocketAddr, contents: impl Into<Bytes>) -> Self {
         Self {
             endpoints,
             from,
-            contents,
+            contents: contents.into(),
             metadata: HashMap::new(),
         }
     }
@@ -83,7 +86,7 @@ pub struct ReadResponse {
     /// The upstream endpoints that the packet should be forwarded to.
     pub endpoints: UpstreamEndpoints,
     /// Contents of the packet to be forwarded.
-    pub contents: Vec<u8>,
+    pub contents: Bytes,
     /// Arbitrary values that can be passed from one filter to another
     pub metadata: DynamicMetadata,
 }
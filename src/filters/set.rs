@@ -42,6 +42,8 @@ impl FilterSet {
     /// - [`CaptureBytes`][extensions::CaptureBytesFactory]
     /// - [`TokenRouter`][extensions::TokenRouterFactory]
     /// - [`Compress`][extensions::CompressFactory]
+    /// - [`ClientAddress`][extensions::ClientAddressFactory]
+    /// - [`ExternalAuthorization`][extensions::ExternalAuthorizationFactory]
     pub fn default(base: &Logger) -> Self {
         Self::default_with(base, Option::into_iter(None))
     }
@@ -64,6 +66,8 @@ impl FilterSet {
                 Box::from(extensions::CaptureBytesFactory::new(base)),
                 Box::from(extensions::TokenRouterFactory::new(base)),
                 Box::from(extensions::CompressFactory::new(base)),
+                Box::from(extensions::ClientAddressFactory::default()),
+                Box::from(extensions::ExternalAuthorizationFactory::new(base)),
             ])
             .chain(filters),
         )
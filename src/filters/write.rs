@@ -16,6 +16,8 @@
 
 use std::{any::Any, collections::HashMap, net::SocketAddr};
 
+use bytes::Bytes;
+
 use crate::cluster::Endpoint;
 
 #[cfg(doc)]
@@ -30,8 +32,9 @@ pub struct WriteContext<'a> {
     pub from: SocketAddr,
     /// The destination of the received packet.
     pub to: SocketAddr,
-    /// Contents of the received packet.
-    pub contents: Vec<u8>,
+    /// Contents of the received packet. Cloning or slicing the contents
+    /// does not copy them.
+    pub contents: Bytes,
     /// Arbitrary values that can be passed from one filter to another
     pub metadata: HashMap<String, Box<dyn Any + Send>>,
 }
@@ -49,7 +52,7 @@ pub struct WriteContext<'a> {
 #[non_exhaustive]
 pub struct WriteResponse {
     /// Contents of the packet to be sent back to the original sender.
-    pub contents: Vec<u8>,
+    pub contents: Bytes,
     /// Arbitrary values that can be passed from one filter to another.
     pub metadata: HashMap<String, Box<dyn Any + Send>>,
 }
@@ -60,13 +63,13 @@ impl WriteContext<'_> {
         endpoint: &Endpoint,
         from: SocketAddr,
         to: SocketAddr,
-        contents: Vec<u8>,
+        contents: impl Into<Bytes>,
     ) -> WriteContext {
         WriteContext {
             endpoint,
             from,
             to,
-            contents,
+            contents: contents.into(),
             metadata: HashMap::new(),
         }
     }
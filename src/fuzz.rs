@@ -0,0 +1,175 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Entry points for the fuzz targets in `fuzz/`, which pass them arbitrary
+//! input. Invalid input must be rejected with an error or a dropped packet,
+//! so any panic is a bug.
+
+use std::collections::HashSet;
+
+use once_cell::sync::Lazy;
+use prometheus::Registry;
+use slog::{o, Discard, Logger};
+
+use crate::config::{Config, Endpoint, Endpoints, Filter as FilterConfig};
+use crate::filters::{Filter, FilterChain, FilterRegistry, FilterSet, ReadContext, WriteContext};
+use crate::proxy::Builder;
+
+/// The tokens of the endpoints that [`token_router`] routes to.
+const TOKENS: [&[u8]; 2] = [b"abc", b"xyz"];
+
+fn log() -> Logger {
+    Logger::root(Discard, o!())
+}
+
+/// Returns a chain of the filters configured by `filters`, as
+/// `(name, config)`.
+fn chain(filters: &[(&str, &str)]) -> FilterChain {
+    let log = log();
+    FilterChain::try_create(
+        filters
+            .iter()
+            .map(|(name, config)| FilterConfig {
+                name: (*name).into(),
+                config: Some(serde_yaml::from_str(config).unwrap()),
+            })
+            .collect(),
+        &FilterRegistry::new(FilterSet::default(&log)),
+        &Registry::default(),
+    )
+    .unwrap()
+}
+
+/// Token routing chains, capturing tokens from the start and from the end of
+/// packets.
+static TOKEN_ROUTERS: Lazy<[FilterChain; 2]> = Lazy::new(|| {
+    let router = |strategy| {
+        chain(&[
+            (
+                "quilkin.extensions.filters.capture_bytes.v1alpha1.CaptureBytes",
+                strategy,
+            ),
+            (
+                "quilkin.extensions.filters.token_router.v1alpha1.TokenRouter",
+                "{}",
+            ),
+        ])
+    };
+    [
+        router("{strategy: PREFIX, size: 3, remove: true}"),
+        router("{strategy: SUFFIX, size: 3, remove: false}"),
+    ]
+});
+
+/// A chain decompressing packets in both directions.
+static DECOMPRESS: Lazy<FilterChain> = Lazy::new(|| {
+    chain(&[(
+        "quilkin.extensions.filters.compress.v1alpha1.Compress",
+        "{mode: SNAPPY, on_read: DECOMPRESS, on_write: DECOMPRESS}",
+    )])
+});
+
+fn endpoints() -> Vec<Endpoint> {
+    TOKENS
+        .iter()
+        .enumerate()
+        .map(|(i, token)| {
+            Endpoint::new(
+                ([127, 0, 0, 1], 8000 + i as u16).into(),
+                vec![token.to_vec()].into_iter().collect::<HashSet<_>>(),
+                None,
+            )
+        })
+        .collect()
+}
+
+fn read(chain: &FilterChain, data: &[u8]) {
+    let endpoints = Endpoints::new(endpoints()).unwrap().into();
+    let _ = chain.read(ReadContext::new(
+        endpoints,
+        ([127, 0, 0, 1], 7000).into(),
+        data.to_vec(),
+    ));
+}
+
+/// Parses `data` as a configuration and, if it parses, validates it and
+/// creates its filters like a proxy would before running.
+pub fn config(data: &[u8]) {
+    let config = match Config::from_reader(data) {
+        Ok(config) => config,
+        Err(_) => return,
+    };
+    // Some filters start background tasks when they are created, which are
+    // dropped along with the runtime.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let _guard = runtime.enter();
+    Builder::from(config)
+        .with_log(log())
+        .with_metrics_registry(Registry::default())
+        .validate()
+        .ok();
+}
+
+/// Passes `data` as a packet through `CaptureBytes` and `TokenRouter`, with
+/// the token at its start and at its end.
+pub fn token_router(data: &[u8]) {
+    for chain in TOKEN_ROUTERS.iter() {
+        read(chain, data);
+    }
+}
+
+/// Passes `data` as a packet through the `Compress` filter, decompressing it
+/// as read from a client and as written by an endpoint.
+pub fn decompress(data: &[u8]) {
+    read(&DECOMPRESS, data);
+    let endpoint = Endpoint::from_address(([127, 0, 0, 1], 8000).into());
+    let _ = DECOMPRESS.write(WriteContext::new(
+        &endpoint,
+        endpoint.address,
+        ([127, 0, 0, 1], 7000).into(),
+        data.to_vec(),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config, decompress, token_router};
+
+    /// Inputs that each target must survive, valid or not.
+    const INPUTS: &[&[u8]] = &[
+        b"",
+        b"\0",
+        b"\xff\xfe\xfd",
+        b"helloabc",
+        b"abc",
+        b"{",
+        b"version: v1alpha1\nstatic:\n  endpoints:\n    - address: 127.0.0.1:7001\n",
+        b"version: v1alpha1\nstatic:\n  filters:\n    - name: quilkin.extensions.filters.compress.v1alpha1.Compress\n      config: {mode: 1}\n",
+        b"\xff\x06\x00\x00sNaPpY\x01\x09\x00\x00\x00\x00\x00\x00hello",
+    ];
+
+    #[test]
+    fn targets() {
+        for input in INPUTS {
+            config(input);
+            token_router(input);
+            decompress(input);
+        }
+    }
+}
@@ -16,14 +16,21 @@
 
 mod cluster;
 pub mod config;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod filters;
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub mod fuzz;
 pub(crate) mod metrics;
 pub mod proxy;
 pub mod runner;
+#[cfg(any(test, feature = "test-utils"))]
 pub mod test_utils;
 pub(crate) mod utils;
 pub(crate) mod xds;
 
+pub use proxy::Builder;
 pub use quilkin_macros::{filter, include_proto};
 
 /// Run tests in our external documentation. This is only available in
@@ -45,6 +52,9 @@ mod external_doc_tests {
             #[doc = include_str!("../docs/extensions/filters/capture_bytes.md")]
             #[doc = include_str!("../docs/extensions/filters/token_router.md")]
             #[doc = include_str!("../docs/extensions/filters/compress.md")]
+            #[doc = include_str!("../docs/extensions/filters/client_address.md")]
+            #[doc = include_str!("../docs/extensions/filters/external_authorization.md")]
+            #[doc = include_str!("../docs/using.md")]
             mod tests {}
         };
     }
@@ -14,9 +14,19 @@
  * limitations under the License.
  */
 
-use quilkin::runner::run;
+use quilkin::runner::start;
 
-#[tokio::main]
-async fn main() -> Result<(), quilkin::runner::Error> {
-    run(vec![]).await
+// Heap profiles are dumped by jemalloc, which records allocations once the
+// admin server activates profiling.
+#[cfg(feature = "profiling")]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "profiling")]
+#[allow(non_upper_case_globals)]
+#[export_name = "_rjem_malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:false,lg_prof_sample:19\0";
+
+fn main() -> Result<(), quilkin::runner::Error> {
+    start(vec![])
 }
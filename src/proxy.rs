@@ -15,14 +15,40 @@
  */
 
 pub(crate) use admin::Admin;
-pub use builder::{logger, Builder, PendingValidation, Validated};
+pub use builder::{logger, Builder, Error as BuildError, PendingValidation, Validated};
 pub(crate) use health::Health;
+pub use logging::{config_logger, LogLevels};
 pub(crate) use metrics::Metrics;
-pub use server::Server;
+#[cfg(feature = "sim")]
+pub use server::sim;
+pub use server::{Error as RunError, Handle, Server, Stats};
+pub(crate) use sessions::SESSION_TIMEOUT_SECONDS;
 
 mod admin;
+#[cfg(feature = "agones")]
+mod agones;
 mod builder;
+mod capture;
+pub(crate) mod cloud_metadata;
+mod config_dump;
+mod consul;
+mod debug_sampling;
+pub(crate) mod events;
+mod gamelift;
 mod health;
+#[cfg(feature = "kubernetes")]
+mod kubernetes;
+mod logging;
 mod metrics;
+#[cfg(feature = "profiling")]
+mod profiling;
+mod pushgateway;
+#[cfg(feature = "quic")]
+mod quic;
 mod server;
+mod session_dump;
 mod sessions;
+mod tcp;
+mod top_talkers;
+pub(crate) mod tracing;
+mod webhook;
@@ -0,0 +1,419 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Integration with the Agones SDK server of the game server the proxy runs
+//! next to, through the SDK server's HTTP API. The game server is reported as
+//! healthy while the proxy runs, and the tokens of the endpoints follow an
+//! annotation of its `GameServer`.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use serde_json::Value;
+use slog::{debug, info, o, warn, Logger};
+use tokio::sync::watch;
+use tokio::time;
+
+use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::cluster::Endpoint;
+use crate::config::{Agones, Endpoints};
+use crate::proxy::events::{Event, EventHistory};
+
+/// How long to wait for the SDK server to accept a health report.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before watching the `GameServer` again after the
+/// connection to the SDK server was lost, which doubles on every failure.
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Starts reporting the game server as healthy and watching its `GameServer`
+/// for changes until shutdown.
+pub(crate) fn spawn(
+    base: &Logger,
+    config: &Agones,
+    cluster_manager: SharedClusterManager,
+    events: EventHistory,
+    shutdown_rx: watch::Receiver<()>,
+) -> Result<(), Error> {
+    let log = base.new(o!("source" => "proxy::Agones"));
+    let client = Client::builder().build()?;
+    let sdk_address = Url::parse(&config.sdk_address)?;
+
+    let health = Health {
+        log: log.clone(),
+        client: client.clone(),
+        url: sdk_address.join("health")?,
+        interval: config.health_interval,
+    };
+    tokio::spawn(health.run(shutdown_rx.clone()));
+
+    let watcher = Watcher {
+        log,
+        client,
+        game_server_url: sdk_address.join("gameserver")?,
+        watch_url: sdk_address.join("watch/gameserver")?,
+        tokens_annotation: config.tokens_annotation.clone(),
+        cluster_manager,
+        events,
+        state: None,
+        tokens: None,
+    };
+    tokio::spawn(watcher.run(shutdown_rx));
+    Ok(())
+}
+
+/// Reports the game server as healthy on an interval.
+struct Health {
+    log: Logger,
+    client: Client,
+    url: Url,
+    interval: Duration,
+}
+
+impl Health {
+    async fn run(self, mut shutdown_rx: watch::Receiver<()>) {
+        let mut interval = time::interval(self.interval);
+        let mut failing = false;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_rx.changed() => return,
+            }
+            match self.report().await {
+                Ok(()) if failing => {
+                    info!(self.log, "Reporting health to the Agones SDK server again");
+                    failing = false;
+                }
+                Ok(()) => {}
+                // Only warn once, rather than on every interval while the SDK
+                // server is unavailable.
+                Err(err) if !failing => {
+                    warn!(self.log, "Failed to report health to the Agones SDK server"; "url" => %self.url, "error" => %err);
+                    failing = true;
+                }
+                Err(err) => {
+                    debug!(self.log, "Failed to report health to the Agones SDK server"; "url" => %self.url, "error" => %err)
+                }
+            }
+        }
+    }
+
+    async fn report(&self) -> Result<(), Error> {
+        self.client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body("{}")
+            .timeout(HEALTH_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Applies the state and annotations of the `GameServer` whenever it changes.
+struct Watcher {
+    log: Logger,
+    client: Client,
+    game_server_url: Url,
+    watch_url: Url,
+    tokens_annotation: String,
+    cluster_manager: SharedClusterManager,
+    events: EventHistory,
+    /// The last state of the `GameServer`, such as `Ready` or `Allocated`.
+    state: Option<String>,
+    /// The tokens last applied to the endpoints.
+    tokens: Option<HashSet<Vec<u8>>>,
+}
+
+impl Watcher {
+    async fn run(mut self, mut shutdown_rx: watch::Receiver<()>) {
+        let mut retry_delay = MIN_RETRY_DELAY;
+        loop {
+            let result = tokio::select! {
+                result = self.watch(&mut retry_delay) => result,
+                _ = shutdown_rx.changed() => return,
+            };
+            if let Err(err) = result {
+                warn!(self.log, "Lost the connection to the Agones SDK server, reconnecting"; "url" => %self.watch_url, "error" => %err, "delay" => ?retry_delay);
+            }
+            tokio::select! {
+                _ = time::sleep(retry_delay) => {}
+                _ = shutdown_rx.changed() => return,
+            }
+            retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Applies the current `GameServer`, and then every change to it until
+    /// the connection to the SDK server is lost.
+    async fn watch(&mut self, retry_delay: &mut Duration) -> Result<(), Error> {
+        let game_server = self
+            .client
+            .get(self.game_server_url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        self.apply(&serde_json::from_slice(&game_server)?);
+
+        let mut response = self
+            .client
+            .get(self.watch_url.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        debug!(self.log, "Watching the GameServer"; "url" => %self.watch_url);
+        *retry_delay = MIN_RETRY_DELAY;
+
+        // Every change is streamed as a JSON object of its own line.
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let update: Value = serde_json::from_slice(&line)?;
+                if let Some(error) = update.get("error") {
+                    return Err(format!("the watch failed: {}", error).into());
+                }
+                self.apply(update.get("result").unwrap_or(&update));
+            }
+        }
+        Err("the watch ended".into())
+    }
+
+    /// Applies the state and tokens of `game_server` if they changed.
+    fn apply(&mut self, game_server: &Value) {
+        let state = game_server["status"]["state"].as_str().unwrap_or_default();
+        if self.state.as_deref() != Some(state) {
+            self.events.record(
+                &self.log,
+                Event::new("agones.state", "The GameServer changed state")
+                    .with("state", state)
+                    .with(
+                        "state_before",
+                        self.state.clone().map_or(Value::Null, Value::from),
+                    ),
+            );
+            self.state = Some(state.to_owned());
+        }
+
+        let annotation = match game_server["object_meta"]["annotations"]
+            .get(&self.tokens_annotation)
+            .and_then(Value::as_str)
+        {
+            Some(annotation) => annotation,
+            // The endpoints keep the tokens they have until the annotation
+            // is set.
+            None => return,
+        };
+        let tokens = match parse_tokens(annotation) {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                warn!(self.log, "Ignoring the tokens of the GameServer: the annotation is not a list of base64 encoded tokens"; "annotation" => &self.tokens_annotation, "error" => %err);
+                return;
+            }
+        };
+        if self.tokens.as_ref() == Some(&tokens) {
+            return;
+        }
+
+        {
+            let mut cluster_manager = self.cluster_manager.write();
+            let endpoints: Vec<Endpoint> = cluster_manager
+                .get_all_endpoints()
+                .into_iter()
+                .flat_map(|endpoints| {
+                    endpoints
+                        .iter()
+                        .map(|endpoint| Endpoint {
+                            tokens: tokens.clone(),
+                            ..endpoint.clone()
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            match Endpoints::new(endpoints) {
+                Ok(endpoints) => cluster_manager.set_endpoints(endpoints),
+                Err(_) => return,
+            }
+        }
+        self.events.record(
+            &self.log,
+            Event::new(
+                "agones.tokens",
+                "Updated the tokens of the endpoints from the GameServer",
+            )
+            .with("tokens", tokens.len()),
+        );
+        self.tokens = Some(tokens);
+    }
+}
+
+/// Parses a comma separated list of base64 encoded tokens.
+fn parse_tokens(annotation: &str) -> Result<HashSet<Vec<u8>>, base64::DecodeError> {
+    annotation
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(base64::decode)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response};
+    use prometheus::Registry;
+    use serde_json::json;
+    use tokio::sync::{mpsc, watch};
+
+    use super::{parse_tokens, spawn};
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::{Agones, Endpoints};
+    use crate::proxy::events::EventHistory;
+    use crate::test_utils::logger;
+
+    fn game_server(state: &str, tokens: &str) -> String {
+        json!({
+            "object_meta": {
+                "name": "game-server",
+                "annotations": {"quilkin.dev/tokens": tokens},
+            },
+            "status": {"state": state},
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn follow_game_server() {
+        let (health_tx, mut health_rx) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let health_tx = health_tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let health_tx = health_tx.clone();
+                    async move {
+                        let body = match (request.method(), request.uri().path()) {
+                            (&Method::POST, "/health") => {
+                                health_tx.send(()).ok();
+                                Body::from("{}")
+                            }
+                            (&Method::GET, "/gameserver") => game_server("Ready", "YWJj").into(),
+                            (&Method::GET, "/watch/gameserver") => {
+                                // Stream a single update, and then keep the
+                                // watch open.
+                                let (mut sender, body) = Body::channel();
+                                tokio::spawn(async move {
+                                    let update = format!(
+                                        "{{\"result\":{}}}\n",
+                                        game_server("Allocated", "ZGVm,Z2hp")
+                                    );
+                                    sender.send_data(update.into()).await.ok();
+                                    tokio::time::sleep(Duration::from_secs(60)).await;
+                                    drop(sender);
+                                });
+                                body
+                            }
+                            _ => unreachable!("unexpected request {}", request.uri()),
+                        };
+                        Ok::<_, Infallible>(Response::new(body))
+                    }
+                }))
+            }
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let cluster_manager = ClusterManager::fixed(
+            &Registry::default(),
+            Endpoints::new(vec![Endpoint::from_address(
+                "127.0.0.1:7654".parse().unwrap(),
+            )])
+            .unwrap(),
+        )
+        .unwrap();
+        let config = Agones {
+            sdk_address: format!("http://{}", address),
+            health_interval: Duration::from_millis(100),
+            tokens_annotation: "quilkin.dev/tokens".into(),
+        };
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        spawn(
+            &logger(),
+            &config,
+            cluster_manager.clone(),
+            EventHistory::default(),
+            shutdown_rx,
+        )
+        .unwrap();
+
+        let expected: HashSet<Vec<u8>> =
+            vec![b"def".to_vec(), b"ghi".to_vec()].into_iter().collect();
+        let tokens = || {
+            cluster_manager
+                .read()
+                .get_all_endpoints()
+                .unwrap()
+                .iter()
+                .next()
+                .unwrap()
+                .tokens
+                .clone()
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tokens() != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the tokens of the GameServer should be applied");
+
+        tokio::time::timeout(Duration::from_secs(5), health_rx.recv())
+            .await
+            .expect("health should be reported")
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_annotation_tokens() {
+        let tokens = parse_tokens("YWJj, ZGVm,").unwrap();
+        assert_eq!(
+            vec![b"abc".to_vec(), b"def".to_vec()]
+                .into_iter()
+                .collect::<HashSet<_>>(),
+            tokens
+        );
+        assert!(parse_tokens("").unwrap().is_empty());
+        assert!(parse_tokens("not base64!").is_err());
+    }
+}
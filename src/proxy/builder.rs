@@ -14,21 +14,30 @@
  *  limitations under the License.
  */
 
-use std::{collections::HashSet, convert::TryInto, marker::PhantomData, sync::Arc};
+use std::{collections::HashSet, convert::TryInto, marker::PhantomData, path::PathBuf, sync::Arc};
 
 use prometheus::Registry;
-use slog::{o, Drain, Logger};
+use slog::{o, Logger};
 use tonic::transport::Endpoint as TonicEndpoint;
 
 use crate::cluster::Endpoint;
+#[cfg(feature = "agones")]
+use crate::config::Agones;
+#[cfg(feature = "kubernetes")]
+use crate::config::KubernetesDiscovery;
 use crate::config::{
-    parse_endpoint_metadata_from_yaml, Config, Endpoints, ManagementServer, Proxy, Source,
-    ValidationError, ValueInvalidArgs,
+    parse_endpoint_metadata_from_yaml, Config, ConsulDiscovery, EndPoint, Endpoints,
+    GameLiftDiscovery, LogFormat, ManagementServer, PortRange, Proxy, Pushgateway, Source,
+    SyslogTransport, Tracing, ValidationError, ValueInvalidArgs, Webhook,
 };
 use crate::filters::{chain::Error as FilterChainError, FilterChain, FilterRegistry, FilterSet};
+use crate::proxy::logging::{stdout_logger, LogLevels};
+#[cfg(feature = "quic")]
+use crate::proxy::quic::metrics::Metrics as QuicMetrics;
 use crate::proxy::server::metrics::Metrics as ProxyMetrics;
 use crate::proxy::sessions::metrics::Metrics as SessionMetrics;
-use crate::proxy::{Admin as ProxyAdmin, Health, Metrics, Server};
+use crate::proxy::tcp::metrics::Metrics as TcpMetrics;
+use crate::proxy::{admin, Admin as ProxyAdmin, Health, Metrics, Server};
 
 pub(super) enum ValidatedSource {
     Static {
@@ -38,11 +47,39 @@ pub(super) enum ValidatedSource {
     Dynamic {
         management_servers: Vec<ManagementServer>,
     },
+    #[cfg(feature = "kubernetes")]
+    Kubernetes {
+        filter_chain: Arc<FilterChain>,
+        discovery: KubernetesDiscovery,
+    },
+    GameLift {
+        filter_chain: Arc<FilterChain>,
+        discovery: GameLiftDiscovery,
+        region: String,
+    },
+    Consul {
+        filter_chain: Arc<FilterChain>,
+        discovery: ConsulDiscovery,
+    },
+}
+
+/// The filter chain and endpoints that packets received on some of the
+/// additional ports go through instead of those of the source.
+pub(super) struct PortOverride {
+    pub ports: PortRange,
+    pub filter_chain: Option<Arc<FilterChain>>,
+    pub endpoints: Option<Endpoints>,
 }
 
 pub(super) struct ValidatedConfig {
     pub proxy: Proxy,
     pub source: ValidatedSource,
+    pub port_overrides: Vec<PortOverride>,
+    pub tracing: Option<Tracing>,
+    pub pushgateway: Option<Pushgateway>,
+    pub webhook: Option<Webhook>,
+    #[cfg(feature = "agones")]
+    pub agones: Option<Agones>,
     // Limit struct creation to the builder.
     pub phantom: PhantomData<()>,
 }
@@ -50,8 +87,10 @@ pub(super) struct ValidatedConfig {
 /// Represents an error that occurred while validating and building a server.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    /// A setting of the config is invalid.
     #[error("invalid config: {}", .0)]
     InvalidConfig(ValidationError),
+    /// A filter of the config could not be created.
     #[error("failed to create filter chain: {}", .0)]
     CreateFilterChain(FilterChainError),
 }
@@ -77,7 +116,7 @@ trait ValidationStatus {
 }
 
 /// Marks a ServerBuild as having validated successfully.
-pub struct Validated(ValidatedConfig);
+pub struct Validated(ValidatedConfig, FilterRegistry);
 impl ValidationStatus for Validated {
     type Output = ValidatedConfig;
 }
@@ -88,89 +127,392 @@ impl ValidationStatus for PendingValidation {
     type Output = ();
 }
 
-/// Represents the components needed to create a Server.
+/// Builds a proxy [`Server`] from a [`Config`], for running Quilkin as part of
+/// another binary rather than through the `quilkin` command.
+///
+/// Unless set otherwise, the proxy writes logs like the `quilkin` command
+/// does, can use the filters that come with Quilkin, registers its metrics in
+/// a registry of its own and serves the admin interface of the config.
+///
+/// ```no_run
+/// use quilkin::{config::{Builder as ConfigBuilder, EndPoint}, filters::FilterSet, Builder};
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let log = quilkin::proxy::logger();
+/// let config = ConfigBuilder::empty()
+///     .with_port(7000)
+///     .with_static(vec![], vec![EndPoint::new("127.0.0.1:7777".parse()?)])
+///     .build();
+///
+/// let server = Builder::from(config)
+///     .with_log(log.clone())
+///     .with_filter_set(FilterSet::default(&log))
+///     .with_metrics_registry(prometheus::Registry::default())
+///     .validate()?
+///     .build();
+///
+/// let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+/// tokio::spawn(async move {
+///     tokio::signal::ctrl_c().await.ok();
+///     shutdown_tx.send(()).ok();
+/// });
+/// server.run(shutdown_rx).await?;
+/// # Ok(())
+/// # }
+/// ```
 pub struct Builder<V> {
     log: Logger,
+    /// The levels that `log` writes records at, which the admin server
+    /// changes.
+    log_levels: Arc<LogLevels>,
     config: Arc<Config>,
-    filter_registry: FilterRegistry,
-    admin: Option<ProxyAdmin>,
+    /// The filters that the config can use, or `None` for the default ones,
+    /// until they are moved into [`Validated`].
+    filter_registry: Option<FilterRegistry>,
+    admin: bool,
     metrics: Arc<Metrics>,
+    config_path: Option<PathBuf>,
     validation_status: V,
 }
 
 impl From<Arc<Config>> for Builder<PendingValidation> {
     fn from(config: Arc<Config>) -> Self {
-        let log = logger();
-        let metrics = Arc::new(Metrics::new(&log, Registry::default()));
-        let health = Health::new(&log);
-        let admin = ProxyAdmin::new(&log, config.admin.address, metrics.clone(), health);
+        let log_levels = Arc::<LogLevels>::default();
+        let log = stdout_logger(LogFormat::Plain, log_levels.clone());
         Builder {
+            log_levels,
             config,
-            filter_registry: FilterRegistry::new(FilterSet::default(&log)),
-            admin: Some(admin),
-            metrics,
+            filter_registry: None,
+            admin: true,
+            metrics: Arc::new(Metrics::new(&log, Registry::default())),
             log,
+            config_path: None,
             validation_status: PendingValidation,
         }
     }
 }
 
+impl From<Config> for Builder<PendingValidation> {
+    fn from(config: Config) -> Self {
+        Self::from(Arc::new(config))
+    }
+}
+
 impl ValidatedConfig {
-    fn validate(
+    pub(super) fn validate(
         config: Arc<Config>,
         filter_registry: &FilterRegistry,
         metrics: &Metrics,
     ) -> Result<Self, Error> {
-        let validated_source = match &config.source {
-            Source::Static {
-                filters,
-                endpoints: config_endpoints,
-            } => {
-                if config_endpoints
-                    .iter()
-                    .map(|ep| ep.address)
-                    .collect::<HashSet<_>>()
-                    .len()
-                    != config_endpoints.len()
-                {
-                    return Err(
-                        ValidationError::NotUnique("static.endpoints.address".to_string()).into(),
-                    );
-                }
+        if cfg!(not(feature = "profiling")) && config.admin.profiling {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "admin.profiling".into(),
+                clarification: Some("quilkin must be built with the `profiling` feature".into()),
+                examples: None,
+            })
+            .into());
+        }
 
-                let mut endpoints = Vec::with_capacity(config_endpoints.len());
-                for ep in config_endpoints {
-                    endpoints.push(Endpoint::from_config(ep).map_err(|err| {
-                        ValidationError::ValueInvalid(ValueInvalidArgs {
-                            field: "static.endpoints".to_string(),
-                            clarification: Some(format!("invalid endpoint config: {}", err)),
-                            examples: None,
-                        })
-                    })?);
-                }
-                let endpoints = Endpoints::new(endpoints).map_err(|_empty_list_error| {
-                    ValidationError::EmptyList("static.endpoints".into())
-                })?;
-
-                for ep in config_endpoints {
-                    if let Some(ref metadata) = ep.metadata {
-                        if let Err(err) = parse_endpoint_metadata_from_yaml(metadata.clone()) {
-                            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
-                                field: "static.endpoints.metadata".into(),
-                                clarification: Some(err),
-                                examples: None,
-                            })
-                            .into());
-                        }
-                    }
+        if cfg!(not(feature = "quic")) && config.proxy.quic.is_some() {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.quic".into(),
+                clarification: Some("quilkin must be built with the `quic` feature".into()),
+                examples: None,
+            })
+            .into());
+        }
+
+        if cfg!(not(target_os = "linux")) && config.proxy.upstream.bind_device.is_some() {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.upstream.bind_device".into(),
+                clarification: Some(
+                    "binding to a network interface is only supported on Linux".into(),
+                ),
+                examples: None,
+            })
+            .into());
+        }
+
+        if let Some(keepalive) = &config.proxy.upstream.keepalive {
+            if keepalive.interval.as_nanos() == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.upstream.keepalive.interval".into(),
+                    clarification: Some("the interval must be greater than zero".into()),
+                    examples: Some(vec!["15s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(syslog) = &config.logging.syslog {
+            if syslog.address.is_empty() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "logging.syslog.address".into(),
+                    clarification: Some("the address of the syslog server must be set".into()),
+                    examples: Some(vec!["127.0.0.1:514".into(), "/dev/log".into()]),
+                })
+                .into());
+            }
+            if cfg!(not(unix)) && syslog.transport == SyslogTransport::Unix {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "logging.syslog.transport".into(),
+                    clarification: Some("Unix sockets are only supported on Unix".into()),
+                    examples: Some(vec!["udp".into(), "tcp".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(cloud_metadata) = &config.proxy.cloud_metadata {
+            if cloud_metadata.timeout.as_nanos() == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.cloud_metadata.timeout".into(),
+                    clarification: Some("the timeout must be greater than zero".into()),
+                    examples: Some(vec!["1s".into()]),
+                })
+                .into());
+            }
+            if let Some(endpoint) = &cloud_metadata.endpoint {
+                if reqwest::Url::parse(endpoint).is_err() {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "proxy.cloud_metadata.endpoint".into(),
+                        clarification: Some("the provided value must be a valid URL".into()),
+                        examples: Some(vec!["http://169.254.169.254".into()]),
+                    })
+                    .into());
                 }
+            }
+        }
 
-                ValidatedSource::Static {
-                    filter_chain: Arc::new(FilterChain::try_create(
+        if config.proxy.runtime.worker_threads == Some(0) {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.runtime.worker_threads".into(),
+                clarification: Some("at least one worker thread is required".into()),
+                examples: None,
+            })
+            .into());
+        }
+
+        if !(0.0..=1.0).contains(&config.admin.min_healthy_endpoints) {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "admin.min_healthy_endpoints".into(),
+                clarification: Some("the fraction must be between 0 and 1".into()),
+                examples: Some(vec!["0.5".into()]),
+            })
+            .into());
+        }
+
+        let metrics_path = &config.admin.metrics.path;
+        if !metrics_path.starts_with('/') || admin::PATHS.contains(&metrics_path.as_str()) {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "admin.metrics.path".into(),
+                clarification: Some(format!(
+                    "the path must start with `/` and not be one of: {}",
+                    admin::PATHS.join(", ")
+                )),
+                examples: Some(vec!["/metrics".into()]),
+            })
+            .into());
+        }
+
+        if let Some(tracing) = &config.tracing {
+            if !(0.0..=1.0).contains(&tracing.packet_sample_rate) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "tracing.packet_sample_rate".into(),
+                    clarification: Some("the rate must be between 0 and 1".into()),
+                    examples: Some(vec!["0.01".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(pushgateway) = &config.pushgateway {
+            if reqwest::Url::parse(&pushgateway.url).is_err() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "pushgateway.url".into(),
+                    clarification: Some("the provided value must be a valid URL".into()),
+                    examples: Some(vec!["http://pushgateway:9091".into()]),
+                })
+                .into());
+            }
+            if pushgateway.interval.as_nanos() == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "pushgateway.interval".into(),
+                    clarification: Some("the interval must be greater than zero".into()),
+                    examples: Some(vec!["15s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(webhook) = &config.webhook {
+            if reqwest::Url::parse(&webhook.url).is_err() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "webhook.url".into(),
+                    clarification: Some("the provided value must be a valid URL".into()),
+                    examples: Some(vec!["http://analytics:8080/quilkin".into()]),
+                })
+                .into());
+            }
+            if webhook.batch_size == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "webhook.batch_size".into(),
+                    clarification: Some("the batch size must be greater than zero".into()),
+                    examples: Some(vec!["100".into()]),
+                })
+                .into());
+            }
+            if webhook.flush_interval.as_nanos() == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "webhook.flush_interval".into(),
+                    clarification: Some("the interval must be greater than zero".into()),
+                    examples: Some(vec!["1s".into()]),
+                })
+                .into());
+            }
+        }
+
+        if let Some(agones) = &config.agones {
+            if reqwest::Url::parse(&agones.sdk_address).is_err() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "agones.sdk_address".into(),
+                    clarification: Some("the provided value must be a valid URL".into()),
+                    examples: Some(vec!["http://localhost:9358".into()]),
+                })
+                .into());
+            }
+            if agones.health_interval.as_nanos() == 0 {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "agones.health_interval".into(),
+                    clarification: Some("the interval must be greater than zero".into()),
+                    examples: Some(vec!["5s".into()]),
+                })
+                .into());
+            }
+            if cfg!(not(feature = "agones")) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "agones".into(),
+                    clarification: Some("quilkin must be built with the `agones` feature".into()),
+                    examples: None,
+                })
+                .into());
+            }
+            if !matches!(config.source, Source::Static { .. }) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "agones".into(),
+                    clarification: Some(
+                        "endpoints can only be updated from Agones with a `static` configuration"
+                            .into(),
+                    ),
+                    examples: None,
+                })
+                .into());
+            }
+        }
+
+        if config.proxy.session_timeout.as_nanos() == 0 {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.session_timeout".into(),
+                clarification: Some("the timeout must be greater than zero".into()),
+                examples: Some(vec!["60s".into()]),
+            })
+            .into());
+        }
+
+        if matches!(&config.proxy.tcp, Some(tcp) if tcp.buffer_size == 0) {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.tcp.buffer_size".into(),
+                clarification: Some("the buffer size must be greater than zero".into()),
+                examples: Some(vec!["64KiB".into()]),
+            })
+            .into());
+        }
+
+        if config.proxy.overload.queue_size == Some(0) {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.overload.queue_size".into(),
+                clarification: Some("the queue size must be greater than zero".into()),
+                examples: None,
+            })
+            .into());
+        }
+
+        if cfg!(not(target_os = "linux")) && config.proxy.segmentation_offload {
+            return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: "proxy.segmentation_offload".into(),
+                clarification: Some("UDP segmentation offload is only supported on Linux".into()),
+                examples: None,
+            })
+            .into());
+        }
+
+        if config.proxy.hot_restart.is_some() {
+            if cfg!(not(unix)) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.hot_restart".into(),
+                    clarification: Some("hot restart is only supported on Unix".into()),
+                    examples: None,
+                })
+                .into());
+            }
+            if config.proxy.quic.is_some() {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: "proxy.hot_restart".into(),
+                    clarification: Some(
+                        "hot restart cannot hand over the QUIC listener, remove `proxy.quic`"
+                            .into(),
+                    ),
+                    examples: None,
+                })
+                .into());
+            }
+        }
+
+        let ports = config.proxy.listening_ports();
+        if ports.iter().collect::<HashSet<_>>().len() != ports.len() {
+            return Err(ValidationError::NotUnique("proxy.additional_ports".to_string()).into());
+        }
+
+        let mut port_overrides = Vec::new();
+        for (index, additional) in config.proxy.additional_ports.iter().enumerate() {
+            if additional.filters.is_none() && additional.endpoints.is_none() {
+                continue;
+            }
+            let filter_chain = match &additional.filters {
+                Some(filters) => {
+                    let mut filter_chain = FilterChain::try_create(
                         filters.clone(),
                         filter_registry,
                         &metrics.registry,
-                    )?),
+                    )?;
+                    filter_chain.redact(config.secrets());
+                    Some(Arc::new(filter_chain))
+                }
+                None => None,
+            };
+            let endpoints = match &additional.endpoints {
+                Some(endpoints) => Some(validate_endpoints(
+                    &format!("proxy.additional_ports[{}].endpoints", index),
+                    endpoints,
+                )?),
+                None => None,
+            };
+            port_overrides.push(PortOverride {
+                ports: additional.ports,
+                filter_chain,
+                endpoints,
+            });
+        }
+
+        let validated_source = match &config.source {
+            Source::Static { filters, endpoints } => {
+                let endpoints = validate_endpoints("static.endpoints", endpoints)?;
+                let mut filter_chain =
+                    FilterChain::try_create(filters.clone(), filter_registry, &metrics.registry)?;
+                filter_chain.redact(config.secrets());
+                ValidatedSource::Static {
+                    filter_chain: Arc::new(filter_chain),
                     endpoints,
                 }
             }
@@ -215,24 +557,232 @@ impl ValidatedConfig {
                     management_servers: management_servers.clone(),
                 }
             }
+            Source::Kubernetes { filters, discovery } => {
+                if discovery.service.is_empty() {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "kubernetes.service".into(),
+                        clarification: Some("the name of a Service must be set".into()),
+                        examples: Some(vec!["game-servers".into()]),
+                    })
+                    .into());
+                }
+                if cfg!(not(feature = "kubernetes")) {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "kubernetes".into(),
+                        clarification: Some(
+                            "quilkin must be built with the `kubernetes` feature".into(),
+                        ),
+                        examples: None,
+                    })
+                    .into());
+                }
+
+                let mut filter_chain =
+                    FilterChain::try_create(filters.clone(), filter_registry, &metrics.registry)?;
+                filter_chain.redact(config.secrets());
+                #[cfg(feature = "kubernetes")]
+                {
+                    ValidatedSource::Kubernetes {
+                        filter_chain: Arc::new(filter_chain),
+                        discovery: discovery.clone(),
+                    }
+                }
+                #[cfg(not(feature = "kubernetes"))]
+                unreachable!("the `kubernetes` feature was checked to be enabled")
+            }
+            Source::GameLift { filters, discovery } => {
+                if discovery.game_server_group.is_empty() {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "gamelift.game_server_group".into(),
+                        clarification: Some(
+                            "the name or ARN of a game server group must be set".into(),
+                        ),
+                        examples: Some(vec!["game-servers".into()]),
+                    })
+                    .into());
+                }
+                if discovery.interval.as_nanos() == 0 {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "gamelift.interval".into(),
+                        clarification: Some("the interval must be greater than zero".into()),
+                        examples: Some(vec!["10s".into()]),
+                    })
+                    .into());
+                }
+                if let Some(endpoint) = &discovery.endpoint {
+                    if reqwest::Url::parse(endpoint).is_err() {
+                        return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                            field: "gamelift.endpoint".into(),
+                            clarification: Some("the provided value must be a valid URL".into()),
+                            examples: Some(vec!["https://gamelift.us-west-2.amazonaws.com".into()]),
+                        })
+                        .into());
+                    }
+                }
+                let region = discovery
+                    .region
+                    .clone()
+                    .or_else(|| std::env::var("AWS_REGION").ok())
+                    .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+                    .filter(|region| !region.is_empty())
+                    .ok_or_else(|| {
+                        ValidationError::ValueInvalid(ValueInvalidArgs {
+                            field: "gamelift.region".into(),
+                            clarification: Some(
+                                "the region must be set here or through the `AWS_REGION` environment variable"
+                                    .into(),
+                            ),
+                            examples: Some(vec!["us-west-2".into()]),
+                        })
+                    })?;
+
+                let mut filter_chain =
+                    FilterChain::try_create(filters.clone(), filter_registry, &metrics.registry)?;
+                filter_chain.redact(config.secrets());
+                ValidatedSource::GameLift {
+                    filter_chain: Arc::new(filter_chain),
+                    discovery: discovery.clone(),
+                    region,
+                }
+            }
+            Source::Consul { filters, discovery } => {
+                if discovery.service.is_empty() {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "consul.service".into(),
+                        clarification: Some("the name of a service must be set".into()),
+                        examples: Some(vec!["game-servers".into()]),
+                    })
+                    .into());
+                }
+                if reqwest::Url::parse(&discovery.address).is_err() {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "consul.address".into(),
+                        clarification: Some("the provided value must be a valid URL".into()),
+                        examples: Some(vec!["http://127.0.0.1:8500".into()]),
+                    })
+                    .into());
+                }
+                if discovery.wait.as_nanos() == 0 {
+                    return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                        field: "consul.wait".into(),
+                        clarification: Some("the wait must be greater than zero".into()),
+                        examples: Some(vec!["5m".into()]),
+                    })
+                    .into());
+                }
+
+                let mut filter_chain =
+                    FilterChain::try_create(filters.clone(), filter_registry, &metrics.registry)?;
+                filter_chain.redact(config.secrets());
+                ValidatedSource::Consul {
+                    filter_chain: Arc::new(filter_chain),
+                    discovery: discovery.clone(),
+                }
+            }
         };
 
         Ok(ValidatedConfig {
             proxy: config.proxy.clone(),
             source: validated_source,
+            port_overrides,
+            tracing: config.tracing.clone(),
+            pushgateway: config.pushgateway.clone(),
+            webhook: config.webhook.clone(),
+            #[cfg(feature = "agones")]
+            agones: config.agones.clone(),
             phantom: Default::default(),
         })
     }
 }
 
+/// Validates the endpoints of `field`, which must be unique and not empty.
+fn validate_endpoints(field: &str, config_endpoints: &[EndPoint]) -> Result<Endpoints, Error> {
+    if config_endpoints
+        .iter()
+        .map(|ep| ep.address)
+        .collect::<HashSet<_>>()
+        .len()
+        != config_endpoints.len()
+    {
+        return Err(ValidationError::NotUnique(format!("{}.address", field)).into());
+    }
+
+    let mut endpoints = Vec::with_capacity(config_endpoints.len());
+    for (index, ep) in config_endpoints.iter().enumerate() {
+        endpoints.push(Endpoint::from_config(ep).map_err(|err| {
+            ValidationError::ValueInvalid(ValueInvalidArgs {
+                field: format!("{}[{}]", field, index),
+                clarification: Some(format!("invalid endpoint config: {}", err)),
+                examples: None,
+            })
+        })?);
+    }
+    let endpoints = Endpoints::new(endpoints)
+        .map_err(|_empty_list_error| ValidationError::EmptyList(field.into()))?;
+
+    for (index, ep) in config_endpoints.iter().enumerate() {
+        if let Some(ref metadata) = ep.metadata {
+            if let Err(err) = parse_endpoint_metadata_from_yaml(metadata.clone()) {
+                return Err(ValidationError::ValueInvalid(ValueInvalidArgs {
+                    field: format!("{}[{}].metadata", field, index),
+                    clarification: Some(err),
+                    examples: None,
+                })
+                .into());
+            }
+        }
+    }
+
+    Ok(endpoints)
+}
+
 impl Builder<PendingValidation> {
+    /// Sets the logger that the proxy and its filters write logs to.
     pub fn with_log(self, log: Logger) -> Self {
-        Self { log, ..self }
+        Self {
+            metrics: Arc::new(Metrics::new(&log, self.metrics.registry.clone())),
+            log,
+            ..self
+        }
     }
 
+    /// Sets the levels that the logger writes records at, which the admin
+    /// server changes, e.g those of a logger created by
+    /// [`config_logger`][crate::proxy::config_logger].
+    pub fn with_log_levels(self, log_levels: Arc<LogLevels>) -> Self {
+        Self { log_levels, ..self }
+    }
+
+    /// Sets the filters that the config can use.
     pub fn with_filter_registry(self, filter_registry: FilterRegistry) -> Self {
         Self {
-            filter_registry,
+            filter_registry: Some(filter_registry),
+            ..self
+        }
+    }
+
+    /// Sets the filters that the config can use, like
+    /// [`Builder::with_filter_registry`].
+    pub fn with_filter_set(self, filters: FilterSet) -> Self {
+        self.with_filter_registry(FilterRegistry::new(filters))
+    }
+
+    /// Sets the registry that the metrics of the proxy and its filters are
+    /// registered in, e.g to serve them along with the metrics of the binary
+    /// Quilkin runs in. The registry must not already have metrics with the
+    /// same names.
+    pub fn with_metrics_registry(self, registry: Registry) -> Self {
+        Self {
+            metrics: Arc::new(Metrics::new(&self.log, registry)),
+            ..self
+        }
+    }
+
+    /// Sets the file the config was loaded from. Changes to its static
+    /// endpoints and filters are then applied while the proxy is running.
+    pub fn with_config_path(self, config_path: impl Into<PathBuf>) -> Self {
+        Self {
+            config_path: Some(config_path.into()),
             ..self
         }
     }
@@ -240,51 +790,78 @@ impl Builder<PendingValidation> {
     /// Disable the admin interface
     pub fn disable_admin(self) -> Self {
         Self {
-            admin: None,
+            admin: false,
             ..self
         }
     }
 
-    // Validates the builder's config and filter configurations.
+    /// Validates the config and creates its filters, which must succeed
+    /// before a server can be built.
     pub fn validate(self) -> Result<Builder<Validated>, Error> {
+        let log = &self.log;
+        let filter_registry = self
+            .filter_registry
+            .unwrap_or_else(|| FilterRegistry::new(FilterSet::default(log)));
         let validated_config =
-            ValidatedConfig::validate(self.config.clone(), &self.filter_registry, &self.metrics)?;
+            ValidatedConfig::validate(self.config.clone(), &filter_registry, &self.metrics)?;
 
         Ok(Builder {
             log: self.log,
+            log_levels: self.log_levels,
             config: self.config,
             admin: self.admin,
             metrics: self.metrics,
-            filter_registry: self.filter_registry,
-            validation_status: Validated(validated_config),
+            filter_registry: None,
+            config_path: self.config_path,
+            validation_status: Validated(validated_config, filter_registry),
         })
     }
 }
 
 impl Builder<Validated> {
+    /// Returns the server, which starts proxying once it is run.
     pub fn build(self) -> Server {
+        let admin = if self.admin {
+            Some(ProxyAdmin::new(
+                &self.log,
+                &self.config.admin,
+                self.metrics.clone(),
+                Health::new(&self.log),
+                self.log_levels,
+            ))
+        } else {
+            None
+        };
+        let mut session_metrics = SessionMetrics::with_endpoint_limit(
+            &self.metrics.registry,
+            self.config.admin.metrics.endpoint_limit(),
+        )
+        .expect("session metrics should be setup properly");
+        session_metrics.capture = self.metrics.capture.clone();
         Server {
             log: self.log.new(o!("source" => "server::Server")),
             config: Arc::new(self.validation_status.0),
+            config_file: self.config_path.zip(Some(self.config)),
             proxy_metrics: ProxyMetrics::new(&self.metrics.registry)
                 .expect("proxy metrics should be setup properly"),
-            session_metrics: SessionMetrics::new(&self.metrics.registry)
-                .expect("session metrics should be setup properly"),
-            admin: self.admin,
+            session_metrics,
+            tcp_metrics: TcpMetrics::new(&self.metrics.registry)
+                .expect("tcp metrics should be setup properly"),
+            #[cfg(feature = "quic")]
+            quic_metrics: QuicMetrics::new(&self.metrics.registry)
+                .expect("quic metrics should be setup properly"),
+            admin,
             metrics: self.metrics,
-            filter_registry: self.filter_registry,
+            filter_registry: self.validation_status.1,
+            cluster_manager: Default::default(),
         }
     }
 }
 
+/// Returns a logger writing plain text records to stdout at the default
+/// levels.
 pub fn logger() -> Logger {
-    let drain = slog_json::Json::new(std::io::stdout())
-        .set_pretty(false)
-        .add_default_keys()
-        .build()
-        .fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    slog::Logger::root(drain, o!())
+    stdout_logger(LogFormat::Plain, Arc::default())
 }
 
 #[cfg(test)]
@@ -292,8 +869,12 @@ mod tests {
     use std::convert::TryFrom;
     use std::sync::Arc;
 
+    use prometheus::Registry;
+
     use crate::config::{Config, ValidationError};
+    use crate::filters::{DynFilterFactory, FilterSet};
     use crate::proxy::builder::Validated;
+    use crate::test_utils::{logger, TestFilterFactory};
 
     use super::{Builder, Error};
 
@@ -361,6 +942,433 @@ dynamic:
         );
     }
 
+    #[test]
+    fn validate_admin() {
+        let yaml = "
+version: v1alpha1
+admin:
+  address: 127.0.0.1:9091
+  metrics:
+    path: /stats/prometheus
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        for path in &["metrics", "/live"] {
+            let yaml = format!(
+                "
+version: v1alpha1
+admin:
+  address: 127.0.0.1:9091
+  metrics:
+    path: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+                path
+            );
+            match Builder::try_from(Arc::new(parse_config(&yaml)))
+                .unwrap()
+                .validate()
+            {
+                Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                    assert_eq!("admin.metrics.path", args.field)
+                }
+                _ => unreachable!("expected an invalid metrics path: {}", path),
+            }
+        }
+
+        let yaml = "
+version: v1alpha1
+admin:
+  address: 127.0.0.1:9091
+  min_healthy_endpoints: 1.5
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .validate()
+        {
+            Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                assert_eq!("admin.min_healthy_endpoints", args.field)
+            }
+            _ => unreachable!("expected an invalid fraction of healthy endpoints"),
+        }
+    }
+
+    #[test]
+    fn validate_tracing() {
+        let yaml = "
+version: v1alpha1
+tracing:
+  endpoint: http://127.0.0.1:4317
+  packet_sample_rate: 1
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        let yaml = "
+version: v1alpha1
+tracing:
+  endpoint: http://127.0.0.1:4317
+  packet_sample_rate: 1.5
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .validate()
+        {
+            Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                assert_eq!("tracing.packet_sample_rate", args.field)
+            }
+            _ => unreachable!("expected an invalid packet sample rate"),
+        }
+    }
+
+    #[test]
+    fn validate_kubernetes() {
+        let yaml = "
+version: v1alpha1
+kubernetes:
+  service: game-servers
+";
+        let result = Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .validate();
+        #[cfg(feature = "kubernetes")]
+        assert!(result.is_ok());
+        #[cfg(not(feature = "kubernetes"))]
+        match result {
+            Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                assert_eq!("kubernetes", args.field)
+            }
+            _ => unreachable!("expected the `kubernetes` feature to be required"),
+        }
+
+        let yaml = "
+version: v1alpha1
+kubernetes:
+  service: ''
+";
+        match Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .validate()
+        {
+            Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                assert_eq!("kubernetes.service", args.field)
+            }
+            _ => unreachable!("expected an invalid service"),
+        }
+    }
+
+    #[test]
+    fn validate_gamelift() {
+        let yaml = "
+version: v1alpha1
+gamelift:
+  game_server_group: game-servers
+  region: us-west-2
+";
+        assert!(Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .validate()
+            .is_ok());
+
+        for (gamelift, field) in &[
+            (
+                "{game_server_group: '', region: us-west-2}",
+                "gamelift.game_server_group",
+            ),
+            (
+                "{game_server_group: a, region: us-west-2, interval: 0s}",
+                "gamelift.interval",
+            ),
+            (
+                "{game_server_group: a, region: us-west-2, endpoint: gamelift}",
+                "gamelift.endpoint",
+            ),
+        ] {
+            let yaml = format!(
+                "
+version: v1alpha1
+gamelift: {}
+",
+                gamelift
+            );
+            match Builder::try_from(Arc::new(parse_config(&yaml)))
+                .unwrap()
+                .validate()
+            {
+                Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                    assert_eq!(*field, args.field)
+                }
+                _ => unreachable!("expected an invalid {}", field),
+            }
+        }
+    }
+
+    #[test]
+    fn validate_consul() {
+        let yaml = "
+version: v1alpha1
+consul:
+  service: game-servers
+";
+        assert!(Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .validate()
+            .is_ok());
+
+        for (consul, field) in &[
+            ("{service: ''}", "consul.service"),
+            ("{service: a, address: consul}", "consul.address"),
+            ("{service: a, wait: 0s}", "consul.wait"),
+        ] {
+            let yaml = format!(
+                "
+version: v1alpha1
+consul: {}
+",
+                consul
+            );
+            match Builder::try_from(Arc::new(parse_config(&yaml)))
+                .unwrap()
+                .validate()
+            {
+                Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                    assert_eq!(*field, args.field)
+                }
+                _ => unreachable!("expected an invalid {}", field),
+            }
+        }
+    }
+
+    #[test]
+    fn build_with_filter_set_and_metrics_registry() {
+        let yaml = "
+version: v1alpha1
+static:
+  filters:
+    - name: TestFilter
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        // Only the default filters can be used unless set otherwise.
+        assert!(Builder::from(parse_config(yaml)).validate().is_err());
+
+        let log = logger();
+        let registry = Registry::default();
+        let _server = Builder::from(parse_config(yaml))
+            .with_log(log.clone())
+            .with_filter_set(FilterSet::default_with(
+                &log,
+                Some(DynFilterFactory::from(Box::from(TestFilterFactory {}))),
+            ))
+            .with_metrics_registry(registry.clone())
+            .disable_admin()
+            .validate()
+            .unwrap()
+            .build();
+        assert!(registry
+            .gather()
+            .iter()
+            .any(|family| family.get_name() == "quilkin_session_active"));
+    }
+
+    #[test]
+    fn validate_syslog() {
+        let yaml = "
+version: v1alpha1
+logging:
+  syslog:
+    address: ''
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        match Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .validate()
+        {
+            Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                assert_eq!("logging.syslog.address", args.field)
+            }
+            _ => unreachable!("expected an invalid syslog address"),
+        }
+    }
+
+    #[test]
+    fn validate_cloud_metadata() {
+        for (cloud_metadata, field) in &[
+            ("{timeout: 0s}", "proxy.cloud_metadata.timeout"),
+            ("{endpoint: metadata}", "proxy.cloud_metadata.endpoint"),
+        ] {
+            let yaml = format!(
+                "
+version: v1alpha1
+proxy:
+  cloud_metadata: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+                cloud_metadata
+            );
+            match Builder::try_from(Arc::new(parse_config(&yaml)))
+                .unwrap()
+                .validate()
+            {
+                Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                    assert_eq!(*field, args.field)
+                }
+                _ => unreachable!("expected an invalid {}", field),
+            }
+        }
+    }
+
+    #[test]
+    fn validate_agones() {
+        let yaml = "
+version: v1alpha1
+agones: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let result = Builder::try_from(Arc::new(parse_config(yaml)))
+            .unwrap()
+            .validate();
+        #[cfg(feature = "agones")]
+        assert!(result.is_ok());
+        #[cfg(not(feature = "agones"))]
+        match result {
+            Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                assert_eq!("agones", args.field)
+            }
+            _ => unreachable!("expected the `agones` feature to be required"),
+        }
+
+        for (agones, field) in &[
+            ("{sdk_address: localhost}", "agones.sdk_address"),
+            ("{health_interval: 0s}", "agones.health_interval"),
+        ] {
+            let yaml = format!(
+                "
+version: v1alpha1
+agones: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+                agones
+            );
+            match Builder::try_from(Arc::new(parse_config(&yaml)))
+                .unwrap()
+                .validate()
+            {
+                Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                    assert_eq!(*field, args.field)
+                }
+                _ => unreachable!("expected an invalid {}", field),
+            }
+        }
+    }
+
+    #[test]
+    fn validate_pushgateway() {
+        let yaml = "
+version: v1alpha1
+pushgateway:
+  url: http://127.0.0.1:9091
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        for (pushgateway, field) in &[
+            ("{url: pushgateway}", "pushgateway.url"),
+            (
+                "{url: 'http://127.0.0.1:9091', interval: 0s}",
+                "pushgateway.interval",
+            ),
+        ] {
+            let yaml = format!(
+                "
+version: v1alpha1
+pushgateway: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+                pushgateway
+            );
+            match Builder::try_from(Arc::new(parse_config(&yaml)))
+                .unwrap()
+                .validate()
+            {
+                Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                    assert_eq!(*field, args.field)
+                }
+                _ => unreachable!("expected an invalid {}", field),
+            }
+        }
+    }
+
+    #[test]
+    fn validate_webhook() {
+        let yaml = "
+version: v1alpha1
+webhook:
+  url: http://127.0.0.1:8080
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+";
+        let _ = validate_unwrap_ok(yaml);
+
+        for (webhook, field) in &[
+            ("{url: analytics}", "webhook.url"),
+            (
+                "{url: 'http://127.0.0.1:8080', batch_size: 0}",
+                "webhook.batch_size",
+            ),
+            (
+                "{url: 'http://127.0.0.1:8080', flush_interval: 0s}",
+                "webhook.flush_interval",
+            ),
+        ] {
+            let yaml = format!(
+                "
+version: v1alpha1
+webhook: {}
+static:
+  endpoints:
+    - address: 127.0.0.1:25999
+",
+                webhook
+            );
+            match Builder::try_from(Arc::new(parse_config(&yaml)))
+                .unwrap()
+                .validate()
+            {
+                Err(Error::InvalidConfig(ValidationError::ValueInvalid(args))) => {
+                    assert_eq!(*field, args.field)
+                }
+                _ => unreachable!("expected an invalid {}", field),
+            }
+        }
+    }
+
     #[test]
     fn validate() {
         // client - valid
@@ -407,6 +1415,11 @@ static:
         quilkin.dev:
           tokens: abc
 ";
-        let _ = validate_unwrap_err(yaml);
+        match validate_unwrap_err(yaml) {
+            ValidationError::ValueInvalid(args) => {
+                assert_eq!(args.field, "static.endpoints[0]".to_string());
+            }
+            err => unreachable!("expected invalid value error: got {}", err),
+        }
     }
 }
@@ -0,0 +1,597 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! On-demand capture of the packets passing through the proxy in the pcap
+//! format, so live traffic can be inspected with tools such as Wireshark.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::header::CONTENT_TYPE;
+use hyper::{body, Body, Response, StatusCode};
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+
+/// The number of packets captured if a capture does not limit it.
+const DEFAULT_PACKETS: u64 = 1000;
+/// How long packets are captured for if a capture does not limit it.
+const DEFAULT_DURATION: Duration = Duration::from_secs(60);
+/// The number of captured packets that can wait to be written before any
+/// more are dropped from the capture.
+const QUEUE_SIZE: usize = 1024;
+/// The maximum number of bytes of a packet written to a capture.
+const SNAPLEN: u32 = 262_144;
+/// The pcap link type of packets starting with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+
+/// Captures the packets passing through a proxy, one capture at a time.
+#[derive(Default)]
+pub(crate) struct Capture {
+    /// Whether a capture is running, which is checked for every packet
+    /// before taking the lock.
+    active: AtomicBool,
+    recording: Mutex<Option<Recording>>,
+    next_id: AtomicU64,
+}
+
+struct Recording {
+    id: u64,
+    filter: Filter,
+    /// The number of packets left to capture.
+    remaining: u64,
+    records: mpsc::Sender<Bytes>,
+}
+
+/// Which packets to capture.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Filter {
+    /// The client whose packets are captured.
+    pub source: Option<Address>,
+    /// The endpoint whose packets are captured.
+    pub endpoint: Option<Address>,
+}
+
+impl Filter {
+    fn matches(&self, client: SocketAddr, endpoint: Option<SocketAddr>) -> bool {
+        let source_matches = match &self.source {
+            Some(source) => source.matches(client),
+            None => true,
+        };
+        let endpoint_matches = match (&self.endpoint, endpoint) {
+            (Some(filter), Some(endpoint)) => filter.matches(endpoint),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        source_matches && endpoint_matches
+    }
+}
+
+/// An address to capture the packets of, or to find sessions by, on any port
+/// if none is given.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Address {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+impl Address {
+    pub fn matches(&self, address: SocketAddr) -> bool {
+        match self {
+            Address::Ip(ip) => canonical(*ip) == canonical(address.ip()),
+            Address::Socket(socket) => {
+                canonical(socket.ip()) == canonical(address.ip()) && socket.port() == address.port()
+            }
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map(Address::Socket)
+            .or_else(|_| s.parse().map(Address::Ip))
+            .map_err(|_| format!("invalid address `{}`", s))
+    }
+}
+
+/// The limits of a capture, which stops once it reaches either of them.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Limits {
+    pub packets: u64,
+    pub duration: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            packets: DEFAULT_PACKETS,
+            duration: DEFAULT_DURATION,
+        }
+    }
+}
+
+/// Where a capture is written to.
+enum Output {
+    File(BufWriter<File>),
+    Stream(body::Sender),
+}
+
+impl Capture {
+    /// Starts capturing packets that match `filter` until `limits` are
+    /// reached, returning the pcap records of the captured packets, or
+    /// `None` if a capture is already running.
+    fn start(&self, filter: Filter, limits: &Limits) -> Option<(u64, mpsc::Receiver<Bytes>)> {
+        let mut recording = self.recording.lock();
+        if recording.is_some() {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Relaxed);
+        let (records, receiver) = mpsc::channel(QUEUE_SIZE);
+        *recording = Some(Recording {
+            id,
+            filter,
+            remaining: limits.packets,
+            records,
+        });
+        self.active.store(true, Relaxed);
+        Some((id, receiver))
+    }
+
+    /// Stops the capture with `id`, if it is still running.
+    fn stop(&self, id: u64) {
+        let mut recording = self.recording.lock();
+        if matches!(&*recording, Some(recording) if recording.id == id) {
+            self.active.store(false, Relaxed);
+            *recording = None;
+        }
+    }
+
+    /// Adds a packet sent from `from` to `to` to the running capture, if
+    /// any, where `client` is the address of the client the packet is sent
+    /// from or to, and `endpoint` the address of the endpoint, if known.
+    pub(crate) fn record(
+        &self,
+        from: SocketAddr,
+        to: SocketAddr,
+        client: SocketAddr,
+        endpoint: Option<SocketAddr>,
+        contents: &[u8],
+    ) {
+        if !self.active.load(Relaxed) {
+            return;
+        }
+        let mut recording = self.recording.lock();
+        let current = match &mut *recording {
+            Some(current) if current.filter.matches(client, endpoint) => current,
+            _ => return,
+        };
+        // A packet that can't be written in time is left out of the capture
+        // rather than holding up the proxy.
+        current
+            .records
+            .try_send(pcap_record(SystemTime::now(), from, to, contents))
+            .ok();
+        current.remaining -= 1;
+        if current.remaining == 0 {
+            self.active.store(false, Relaxed);
+            *recording = None;
+        }
+    }
+
+    /// Writes the records of the capture with `id` to `file` until it stops,
+    /// stopping it if `file` can't be written to. Blocks the calling thread,
+    /// so that a single thread writes the whole capture.
+    fn write_file(&self, id: u64, mut records: mpsc::Receiver<Bytes>, mut file: BufWriter<File>) {
+        let written = file.write_all(&pcap_header()).and_then(|()| {
+            while let Some(record) = records.blocking_recv() {
+                file.write_all(&record)?;
+            }
+            file.flush()
+        });
+        if written.is_err() {
+            self.stop(id);
+        }
+    }
+
+    /// Streams the records of the capture with `id` to `sender` until it
+    /// stops, stopping it if the stream is closed.
+    async fn write_stream(
+        &self,
+        id: u64,
+        mut records: mpsc::Receiver<Bytes>,
+        mut sender: body::Sender,
+    ) {
+        if sender.send_data(pcap_header()).await.is_err() {
+            self.stop(id);
+            return;
+        }
+        while let Some(record) = records.recv().await {
+            if sender.send_data(record).await.is_err() {
+                self.stop(id);
+                return;
+            }
+        }
+    }
+}
+
+/// Starts a capture as requested by the `packets`, `duration`, `source`,
+/// `endpoint` and `file` parameters of `query`, returning a response that
+/// streams the capture, or confirms that it is written to `file`.
+pub(super) async fn start(capture: &Arc<Capture>, query: Option<&str>) -> Response<Body> {
+    let (filter, limits, file) = match parse_query(query) {
+        Ok(parsed) => parsed,
+        Err(message) => return error_response(StatusCode::BAD_REQUEST, message),
+    };
+
+    // Claim the capture before creating the file, so that a request that
+    // conflicts with a running capture leaves the file alone.
+    let (id, records) = match capture.start(filter, &limits) {
+        Some(started) => started,
+        None => return error_response(StatusCode::CONFLICT, "a capture is already running".into()),
+    };
+
+    let (output, response) = match file {
+        Some(path) => {
+            let created = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || File::create(path))
+                    .await
+                    .map_err(|err| err.to_string())
+                    .and_then(|result| result.map_err(|err| err.to_string()))
+            };
+            match created {
+                Ok(file) => (
+                    Output::File(BufWriter::new(file)),
+                    Response::new(format!("capturing packets to {}\n", path).into()),
+                ),
+                Err(err) => {
+                    capture.stop(id);
+                    return error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("failed to create `{}`: {}", path, err),
+                    );
+                }
+            }
+        }
+        None => {
+            let (sender, body) = Body::channel();
+            let mut response = Response::new(body);
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                "application/vnd.tcpdump.pcap".parse().unwrap(),
+            );
+            (Output::Stream(sender), response)
+        }
+    };
+
+    // Stopping the capture closes the channel of its records, which ends the
+    // writer once the remaining ones are written.
+    let deadline = Instant::now() + limits.duration;
+    tokio::spawn({
+        let capture = capture.clone();
+        async move {
+            time::sleep_until(deadline).await;
+            capture.stop(id);
+        }
+    });
+    let capture = capture.clone();
+    match output {
+        Output::File(file) => {
+            tokio::task::spawn_blocking(move || capture.write_file(id, records, file));
+        }
+        Output::Stream(sender) => {
+            tokio::spawn(async move { capture.write_stream(id, records, sender).await });
+        }
+    }
+    response
+}
+
+fn parse_query(query: Option<&str>) -> Result<(Filter, Limits, Option<String>), String> {
+    let mut filter = Filter::default();
+    let mut limits = Limits::default();
+    let mut file = None;
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match &*key {
+            "packets" => {
+                limits.packets = match value.parse() {
+                    Ok(packets) if packets > 0 => packets,
+                    _ => return Err(format!("invalid number of packets `{}`", value)),
+                }
+            }
+            "duration" => {
+                limits.duration = humantime::parse_duration(&value)
+                    .map_err(|err| format!("invalid duration `{}`: {}", value, err))?
+            }
+            "source" => filter.source = Some(value.parse()?),
+            "endpoint" => filter.endpoint = Some(value.parse()?),
+            "file" => file = Some(value.into_owned()),
+            _ => return Err(format!("unknown parameter `{}`", key)),
+        }
+    }
+    Ok((filter, limits, file))
+}
+
+fn error_response(status: StatusCode, message: String) -> Response<Body> {
+    let mut response = Response::new(message.into());
+    *response.status_mut() = status;
+    response
+}
+
+/// Returns the header of a pcap file holding raw IP packets.
+fn pcap_header() -> Bytes {
+    let mut header = BytesMut::with_capacity(24);
+    header.put_u32_le(0xa1b2_c3d4);
+    header.put_u16_le(2);
+    header.put_u16_le(4);
+    // The timezone offset and accuracy of timestamps, which are always zero.
+    header.put_u32_le(0);
+    header.put_u32_le(0);
+    header.put_u32_le(SNAPLEN);
+    header.put_u32_le(LINKTYPE_RAW);
+    header.freeze()
+}
+
+/// Returns the pcap record of a UDP packet with `contents` sent from `from` to
+/// `to` at `timestamp`, which has an IPv4 header if both addresses are IPv4
+/// addresses and an IPv6 header otherwise.
+fn pcap_record(timestamp: SystemTime, from: SocketAddr, to: SocketAddr, contents: &[u8]) -> Bytes {
+    let udp_length = 8 + contents.len();
+    let mut packet = BytesMut::with_capacity(48 + udp_length);
+    match (canonical(from.ip()), canonical(to.ip())) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let mut header = [0; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&length(20 + udp_length).to_be_bytes());
+            header[8] = 64;
+            header[9] = 17;
+            header[12..16].copy_from_slice(&source.octets());
+            header[16..20].copy_from_slice(&destination.octets());
+            let checksum = checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.put_slice(&header);
+        }
+        (source, destination) => {
+            packet.put_u32(6 << 28);
+            packet.put_u16(length(udp_length));
+            packet.put_u8(17);
+            packet.put_u8(64);
+            packet.put_slice(&ipv6(source).octets());
+            packet.put_slice(&ipv6(destination).octets());
+        }
+    }
+    packet.put_u16(from.port());
+    packet.put_u16(to.port());
+    packet.put_u16(length(udp_length));
+    // Zero marks the UDP checksum as not computed.
+    packet.put_u16(0);
+    packet.put_slice(contents);
+
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let captured = packet.len().min(SNAPLEN as usize);
+    let mut record = BytesMut::with_capacity(16 + captured);
+    record.put_u32_le(since_epoch.as_secs() as u32);
+    record.put_u32_le(since_epoch.subsec_micros());
+    record.put_u32_le(captured as u32);
+    record.put_u32_le(packet.len() as u32);
+    record.put_slice(&packet[..captured]);
+    record.freeze()
+}
+
+fn length(length: usize) -> u16 {
+    u16::try_from(length).unwrap_or(u16::MAX)
+}
+
+/// Returns the internet checksum of an IPv4 header.
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns `ip`, as an IPv4 address if it is an IPv4-mapped IPv6 address.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use hyper::StatusCode;
+
+    use super::{checksum, parse_query, pcap_record, start, Address, Capture, Filter, Limits};
+
+    #[test]
+    fn parse_capture_query() {
+        let (filter, limits, file) = parse_query(Some(
+            "packets=10&duration=5s&source=127.0.0.1&endpoint=%5B::1%5D:26000&file=/tmp/a.pcap",
+        ))
+        .unwrap();
+        assert_eq!(
+            Filter {
+                source: Some(Address::Ip("127.0.0.1".parse().unwrap())),
+                endpoint: Some(Address::Socket("[::1]:26000".parse().unwrap())),
+            },
+            filter
+        );
+        assert_eq!(
+            Limits {
+                packets: 10,
+                duration: Duration::from_secs(5),
+            },
+            limits
+        );
+        assert_eq!(Some("/tmp/a.pcap".into()), file);
+
+        let (filter, limits, file) = parse_query(None).unwrap();
+        assert_eq!(Filter::default(), filter);
+        assert_eq!(Limits::default(), limits);
+        assert_eq!(None, file);
+
+        assert!(parse_query(Some("packets=0")).is_err());
+        assert!(parse_query(Some("duration=soon")).is_err());
+        assert!(parse_query(Some("source=localhost")).is_err());
+        assert!(parse_query(Some("count=1")).is_err());
+    }
+
+    #[test]
+    fn filter_packets() {
+        let client = "127.0.0.1:20000".parse().unwrap();
+        let endpoint = "127.0.0.1:26000".parse().unwrap();
+        let mapped_client = "[::ffff:127.0.0.1]:20000".parse().unwrap();
+
+        assert!(Filter::default().matches(client, None));
+
+        let source = Filter {
+            source: Some("127.0.0.1".parse().unwrap()),
+            endpoint: None,
+        };
+        assert!(source.matches(client, Some(endpoint)));
+        assert!(source.matches(mapped_client, None));
+        assert!(!source.matches("127.0.0.2:20000".parse().unwrap(), None));
+
+        let endpoint_filter = Filter {
+            source: None,
+            endpoint: Some("127.0.0.1:26000".parse().unwrap()),
+        };
+        assert!(endpoint_filter.matches(client, Some(endpoint)));
+        assert!(!endpoint_filter.matches(client, Some("127.0.0.1:26001".parse().unwrap())));
+        // Packets between the client and the proxy aren't sent to any endpoint.
+        assert!(!endpoint_filter.matches(client, None));
+    }
+
+    #[tokio::test]
+    async fn capture_packets() {
+        let capture = Capture::default();
+        let client = "127.0.0.1:20000".parse().unwrap();
+        let other = "127.0.0.2:20000".parse().unwrap();
+        let proxy = "0.0.0.0:7000".parse().unwrap();
+
+        let filter = Filter {
+            source: Some("127.0.0.1".parse().unwrap()),
+            endpoint: None,
+        };
+        let limits = Limits {
+            packets: 2,
+            duration: Duration::from_secs(60),
+        };
+        let (_, mut records) = capture.start(filter, &limits).unwrap();
+        assert!(capture.start(Filter::default(), &limits).is_none());
+
+        capture.record(other, proxy, other, None, b"other");
+        capture.record(client, proxy, client, None, b"first");
+        capture.record(proxy, client, client, None, b"second");
+        capture.record(client, proxy, client, None, b"third");
+
+        assert!(records.recv().await.unwrap().ends_with(b"first"));
+        assert!(records.recv().await.unwrap().ends_with(b"second"));
+        assert!(records.recv().await.is_none());
+
+        // Once stopped, another capture can be started.
+        let (id, _records) = capture.start(Filter::default(), &limits).unwrap();
+        capture.stop(id);
+        assert!(capture.start(Filter::default(), &limits).is_some());
+    }
+
+    #[tokio::test]
+    async fn conflicting_capture_leaves_file_alone() {
+        let capture = Arc::new(Capture::default());
+        let path = std::env::temp_dir().join(format!("quilkin-{}.pcap", uuid::Uuid::new_v4()));
+        std::fs::write(&path, b"earlier capture").unwrap();
+
+        let (_, _records) = capture
+            .start(Filter::default(), &Limits::default())
+            .unwrap();
+        let query = format!("file={}", path.display());
+        let response = start(&capture, Some(&query)).await;
+        assert_eq!(StatusCode::CONFLICT, response.status());
+        assert_eq!(b"earlier capture", &std::fs::read(&path).unwrap()[..]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pcap_record_ipv4() {
+        let timestamp = UNIX_EPOCH + Duration::from_micros(1_500_000);
+        let record = pcap_record(
+            timestamp,
+            "[::ffff:127.0.0.1]:20000".parse().unwrap(),
+            "127.0.0.2:7000".parse().unwrap(),
+            b"hello",
+        );
+
+        let header = [1, 0, 0, 0, 0x20, 0xa1, 0x07, 0, 33, 0, 0, 0, 33, 0, 0, 0];
+        assert_eq!(&header[..], &record[..16]);
+        let ip = &record[16..36];
+        assert_eq!(&[0x45, 0, 0, 33][..], &ip[..4]);
+        assert_eq!(&[127, 0, 0, 1, 127, 0, 0, 2][..], &ip[12..]);
+        assert_eq!(0, checksum(ip));
+        assert_eq!(&[0x4e, 0x20, 0x1b, 0x58, 0, 13, 0, 0][..], &record[36..44]);
+        assert_eq!(b"hello", &record[44..]);
+    }
+
+    #[test]
+    fn pcap_record_ipv6() {
+        let record = pcap_record(
+            UNIX_EPOCH,
+            "127.0.0.1:20000".parse().unwrap(),
+            "[::1]:7000".parse().unwrap(),
+            b"hello",
+        );
+
+        assert_eq!(16 + 40 + 8 + 5, record.len());
+        let ip = &record[16..56];
+        assert_eq!(&[0x60, 0, 0, 0, 0, 13, 17, 64][..], &ip[..8]);
+        assert_eq!(ipv6("::ffff:127.0.0.1"), &ip[8..24]);
+        assert_eq!(ipv6("::1"), &ip[24..40]);
+        assert_eq!(b"hello", &record[64..]);
+    }
+
+    fn ipv6(address: &str) -> [u8; 16] {
+        address.parse::<Ipv6Addr>().unwrap().octets()
+    }
+}
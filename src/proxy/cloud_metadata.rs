@@ -0,0 +1,274 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Identification of the cloud instance the proxy runs on, from the instance
+//! metadata service of Google Compute Engine or Amazon EC2.
+
+use reqwest::{Client, Method};
+use slog::{debug, o, warn, Logger};
+
+use crate::config::{CloudMetadata, CloudProvider};
+
+const GCE_ENDPOINT: &str = "http://metadata.google.internal";
+pub(crate) const EC2_ENDPOINT: &str = "http://169.254.169.254";
+/// How long an EC2 session token is valid for, in seconds. It is only used
+/// for the requests made at startup.
+const EC2_TOKEN_TTL: &str = "60";
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// The cloud instance the proxy runs on.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Instance {
+    pub provider: &'static str,
+    pub region: String,
+    pub zone: String,
+    pub id: String,
+}
+
+impl Instance {
+    /// The labels added to every metric of the proxy.
+    pub fn labels(&self) -> Vec<(String, String)> {
+        vec![
+            ("cloud_provider".into(), self.provider.into()),
+            ("cloud_region".into(), self.region.clone()),
+            ("cloud_zone".into(), self.zone.clone()),
+            ("instance_id".into(), self.id.clone()),
+        ]
+    }
+}
+
+/// Returns the instance the proxy runs on. If the provider is
+/// [`CloudProvider::Auto`], `None` is returned when neither metadata service
+/// can be reached, rather than an error.
+pub(crate) async fn discover(
+    base: &Logger,
+    config: &CloudMetadata,
+) -> Result<Option<Instance>, Error> {
+    let log = base.new(o!("source" => "proxy::CloudMetadata"));
+    let client = Client::builder().timeout(config.timeout).build()?;
+    let endpoint = |default: &str| {
+        config
+            .endpoint
+            .as_deref()
+            .unwrap_or(default)
+            .trim_end_matches('/')
+            .to_owned()
+    };
+
+    match config.provider {
+        CloudProvider::Gce => gce(&client, &endpoint(GCE_ENDPOINT)).await.map(Some),
+        CloudProvider::Ec2 => ec2(&client, &endpoint(EC2_ENDPOINT)).await.map(Some),
+        CloudProvider::Auto => {
+            match gce(&client, &endpoint(GCE_ENDPOINT)).await {
+                Ok(instance) => return Ok(Some(instance)),
+                Err(err) => debug!(log, "Not running on Google Compute Engine"; "error" => %err),
+            }
+            match ec2(&client, &endpoint(EC2_ENDPOINT)).await {
+                Ok(instance) => return Ok(Some(instance)),
+                Err(err) => debug!(log, "Not running on Amazon EC2"; "error" => %err),
+            }
+            warn!(
+                log,
+                "No instance metadata service could be reached, the proxy won't be identified by its cloud instance"
+            );
+            Ok(None)
+        }
+    }
+}
+
+async fn gce(client: &Client, endpoint: &str) -> Result<Instance, Error> {
+    let get = |path: &str| {
+        let request = client
+            .get(format!("{}/computeMetadata/v1/instance/{}", endpoint, path))
+            .header("Metadata-Flavor", "Google");
+        text(request)
+    };
+    // The zone is returned as `projects/<project number>/zones/<zone>`.
+    let zone = get("zone")
+        .await?
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+    let region = match zone.rfind('-') {
+        Some(index) => zone[..index].to_owned(),
+        None => return Err(format!("`{}` is not a zone", zone).into()),
+    };
+    Ok(Instance {
+        provider: "gce",
+        region,
+        zone,
+        id: get("id").await?,
+    })
+}
+
+async fn ec2(client: &Client, endpoint: &str) -> Result<Instance, Error> {
+    // Instances that still allow IMDSv1 are queried without a session token
+    // if one can't be created.
+    let token = text(
+        client
+            .request(Method::PUT, format!("{}/latest/api/token", endpoint))
+            .header("X-aws-ec2-metadata-token-ttl-seconds", EC2_TOKEN_TTL),
+    )
+    .await
+    .ok();
+    let get = |path: &str| {
+        let mut request = client.get(format!("{}/latest/meta-data/{}", endpoint, path));
+        if let Some(token) = &token {
+            request = request.header("X-aws-ec2-metadata-token", token);
+        }
+        text(request)
+    };
+    Ok(Instance {
+        provider: "ec2",
+        region: get("placement/region").await?,
+        zone: get("placement/availability-zone").await?,
+        id: get("instance-id").await?,
+    })
+}
+
+async fn text(request: reqwest::RequestBuilder) -> Result<String, Error> {
+    let body = request.send().await?.error_for_status()?.text().await?;
+    Ok(body.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, StatusCode};
+
+    use super::{discover, Instance};
+    use crate::config::{CloudMetadata, CloudProvider};
+    use crate::test_utils::logger;
+
+    /// Serves the metadata of an EC2 instance that requires a session token.
+    async fn ec2(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let authorized = request
+            .headers()
+            .get("X-aws-ec2-metadata-token")
+            .and_then(|token| token.to_str().ok())
+            == Some("token");
+        let body = match (request.method(), request.uri().path()) {
+            (&Method::PUT, "/latest/api/token") => "token",
+            (_, _) if !authorized => "",
+            (&Method::GET, "/latest/meta-data/placement/region") => "us-west-2",
+            (&Method::GET, "/latest/meta-data/placement/availability-zone") => "us-west-2a",
+            (&Method::GET, "/latest/meta-data/instance-id") => "i-0123456789abcdef0",
+            _ => "",
+        };
+        let mut response = Response::new(Body::from(body));
+        if body.is_empty() {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        }
+        Ok(response)
+    }
+
+    /// Serves the metadata of a GCE instance.
+    async fn gce(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let flavor = request.headers().get("Metadata-Flavor");
+        let body = match request.uri().path() {
+            _ if flavor.and_then(|flavor| flavor.to_str().ok()) != Some("Google") => "",
+            "/computeMetadata/v1/instance/zone" => "projects/123/zones/europe-west1-b",
+            "/computeMetadata/v1/instance/id" => "4520031799277581759",
+            _ => "",
+        };
+        let mut response = Response::new(Body::from(body));
+        if body.is_empty() {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        }
+        Ok(response)
+    }
+
+    fn config(provider: CloudProvider, address: SocketAddr) -> CloudMetadata {
+        CloudMetadata {
+            provider,
+            timeout: Duration::from_secs(1),
+            endpoint: Some(format!("http://{}/", address)),
+        }
+    }
+
+    #[tokio::test]
+    async fn discover_instance() {
+        let ec2_server = hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(ec2)) }),
+        );
+        let ec2_address = ec2_server.local_addr();
+        tokio::spawn(ec2_server);
+        let gce_server = hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(gce)) }),
+        );
+        let gce_address = gce_server.local_addr();
+        tokio::spawn(gce_server);
+
+        let ec2_instance = Instance {
+            provider: "ec2",
+            region: "us-west-2".into(),
+            zone: "us-west-2a".into(),
+            id: "i-0123456789abcdef0".into(),
+        };
+        let gce_instance = Instance {
+            provider: "gce",
+            region: "europe-west1".into(),
+            zone: "europe-west1-b".into(),
+            id: "4520031799277581759".into(),
+        };
+        let log = logger();
+        assert_eq!(
+            Some(ec2_instance.clone()),
+            discover(&log, &config(CloudProvider::Ec2, ec2_address))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Some(ec2_instance),
+            discover(&log, &config(CloudProvider::Auto, ec2_address))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Some(gce_instance.clone()),
+            discover(&log, &config(CloudProvider::Gce, gce_address))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Some(gce_instance),
+            discover(&log, &config(CloudProvider::Auto, gce_address))
+                .await
+                .unwrap()
+        );
+
+        // Only an explicit provider that can't be reached is an error.
+        assert!(discover(&log, &config(CloudProvider::Gce, ec2_address))
+            .await
+            .is_err());
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        assert_eq!(
+            None,
+            discover(&log, &config(CloudProvider::Auto, unreachable))
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reporting of the configuration that a running proxy uses.
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response};
+use serde_json::{json, Value};
+
+use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::config::Proxy;
+use crate::filters::manager::SharedFilterManager;
+use crate::xds::ads_client::ClientState;
+
+/// The parts of a running proxy that make up its effective configuration.
+pub(crate) struct ConfigDump {
+    pub proxy: Proxy,
+    pub cluster_manager: SharedClusterManager,
+    pub filter_manager: SharedFilterManager,
+    /// The state of the xDS client, if the proxy is configured by a
+    /// management server.
+    pub xds: Option<ClientState>,
+}
+
+impl ConfigDump {
+    /// Returns the current configuration as JSON.
+    pub fn to_json(&self) -> Value {
+        let filter_chain = self.filter_manager.read().get_filter_chain();
+        let filters: Vec<_> = filter_chain
+            .configs()
+            .map(|(name, config)| json!({ "name": name, "config": config }))
+            .collect();
+
+        let endpoints: Vec<_> = self
+            .cluster_manager
+            .read()
+            .get_all_endpoints()
+            .map(|endpoints| {
+                endpoints
+                    .iter()
+                    .map(|endpoint| {
+                        let mut tokens: Vec<_> =
+                            endpoint.tokens.iter().map(base64::encode).collect();
+                        tokens.sort();
+                        json!({
+                            "address": endpoint.address,
+                            "tokens": tokens,
+                            "metadata": endpoint.metadata,
+                            "healthy": endpoint.healthy,
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let xds = self.xds.as_ref().map(|xds| {
+            json!({
+                "connected": xds.connected.get() == 1,
+                "versions": *xds.versions.read(),
+            })
+        });
+
+        json!({
+            "proxy": serde_json::to_value(&self.proxy).unwrap_or(Value::Null),
+            "filters": filters,
+            "endpoints": endpoints,
+            "xds": xds,
+        })
+    }
+
+    /// Returns a HTTP 200 response with the current configuration as JSON.
+    pub fn response(&self) -> Response<Body> {
+        let body = serde_json::to_string_pretty(&self.to_json()).unwrap_or_default();
+        let mut response = Response::new(body.into());
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use prometheus::core::GenericGauge;
+    use prometheus::Registry;
+    use serde_json::json;
+
+    use super::ConfigDump;
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, Proxy};
+    use crate::filters::{manager::FilterManager, FilterChain};
+    use crate::test_utils::TestFilter;
+    use crate::xds::ads_client::ClientState;
+
+    #[test]
+    fn dump_config() {
+        let registry = Registry::default();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::new(
+                "127.0.0.1:26000".parse().unwrap(),
+                vec![b"abc".to_vec()].into_iter().collect::<HashSet<_>>(),
+                Some(json!({ "region": "eu" })),
+            )])
+            .unwrap(),
+        )
+        .unwrap();
+        let filter_chain = FilterChain::new(
+            vec![("TestFilter".into(), Box::new(TestFilter {}))],
+            &registry,
+        )
+        .unwrap()
+        .with_configs(vec![Some(json!({ "key": "value" }))]);
+        let xds = ClientState {
+            connected: GenericGauge::new("connected_state", "connected").unwrap(),
+            versions: Default::default(),
+        };
+        xds.connected.set(1);
+        xds.versions.write().insert(
+            "type.googleapis.com/envoy.config.cluster.v3.Cluster".into(),
+            "3".into(),
+        );
+
+        let dump = ConfigDump {
+            proxy: Proxy::default(),
+            cluster_manager,
+            filter_manager: FilterManager::fixed(Arc::new(filter_chain)),
+            xds: Some(xds),
+        }
+        .to_json();
+
+        assert_eq!(
+            json!([{ "name": "TestFilter", "config": { "key": "value" } }]),
+            dump["filters"]
+        );
+        assert_eq!(
+            json!([{
+                "address": "127.0.0.1:26000",
+                "tokens": ["YWJj"],
+                "metadata": { "region": "eu" },
+                "healthy": true,
+            }]),
+            dump["endpoints"]
+        );
+        assert_eq!(
+            json!({
+                "connected": true,
+                "versions": { "type.googleapis.com/envoy.config.cluster.v3.Cluster": "3" },
+            }),
+            dump["xds"]
+        );
+        assert_eq!(json!(7000), dump["proxy"]["port"]);
+    }
+}
@@ -0,0 +1,386 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Discovery of endpoints from the instances of a Consul service, which are
+//! watched through blocking queries of the health API of a Consul agent and
+//! fed into a [`ClusterManager`] as cluster updates.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use prometheus::Registry;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::json;
+use slog::{debug, o, warn, Logger};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+
+use crate::cluster::cluster_manager::{ClusterManager, SharedClusterManager};
+use crate::cluster::{Cluster, Endpoint, LocalityEndpoints};
+use crate::config::ConsulDiscovery;
+use crate::proxy::events::EventHistory;
+use crate::proxy::tracing::Tracer;
+use crate::xds::ads_client::{ClusterUpdate, UPDATES_CHANNEL_BUFFER_SIZE};
+
+/// How much longer than the wait of a blocking query to wait for a response,
+/// in addition to the jitter of up to a sixteenth of the wait that the agent
+/// adds.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before querying again after a query failed.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// The key of the service metadata that holds the tokens of an instance, as a
+/// comma separated list of base64 encoded tokens.
+const TOKENS_META_KEY: &str = "quilkin-tokens";
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Returns a [`ClusterManager`] with the instances of the Consul service of
+/// `discovery` as its endpoints, which are kept up to date until shutdown.
+pub(crate) async fn cluster_manager(
+    base: &Logger,
+    discovery: &ConsulDiscovery,
+    metrics_registry: &Registry,
+    events: &EventHistory,
+    tracer: &Tracer,
+    shutdown_rx: watch::Receiver<()>,
+) -> Result<SharedClusterManager, Error> {
+    let log = base.new(o!("source" => "proxy::Consul"));
+    let watcher = Watcher::new(log, discovery, std::env::var("CONSUL_HTTP_TOKEN").ok())?;
+    // Fail to start rather than wait for a service that can't be read, e.g
+    // because the agent can't be reached.
+    let (update, index) = watcher.query(0).await?;
+
+    let (updates_tx, updates_rx) = mpsc::channel(UPDATES_CHANNEL_BUFFER_SIZE);
+    let cluster_manager = ClusterManager::dynamic(
+        base.clone(),
+        metrics_registry,
+        events.clone(),
+        tracer.clone(),
+        update.clone(),
+        updates_rx,
+        shutdown_rx.clone(),
+    )?;
+    tokio::spawn(watcher.run(update, index, updates_tx, shutdown_rx));
+    Ok(cluster_manager)
+}
+
+/// An instance of a service, as returned by `/v1/health/service/:service`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    node: Node,
+    service: Service,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    node: String,
+    address: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    meta: Option<HashMap<String, String>>,
+}
+
+/// Watches the instances of a service.
+struct Watcher {
+    log: Logger,
+    client: Client,
+    url: Url,
+    token: Option<String>,
+    service: String,
+    wait: Duration,
+}
+
+impl Watcher {
+    fn new(log: Logger, discovery: &ConsulDiscovery, token: Option<String>) -> Result<Self, Error> {
+        let mut url = Url::parse(&discovery.address)?;
+        url.path_segments_mut()
+            .map_err(|()| format!("`{}` can't be a base URL", discovery.address))?
+            .pop_if_empty()
+            .extend(&["v1", "health", "service", &discovery.service]);
+        {
+            let mut query = url.query_pairs_mut();
+            if discovery.passing_only {
+                query.append_pair("passing", "true");
+            }
+            if let Some(datacenter) = &discovery.datacenter {
+                query.append_pair("dc", datacenter);
+            }
+            if let Some(tag) = &discovery.tag {
+                query.append_pair("tag", tag);
+            }
+        }
+        Ok(Self {
+            log,
+            client: Client::builder().build()?,
+            url,
+            token: token.filter(|token| !token.is_empty()),
+            service: discovery.service.clone(),
+            wait: discovery.wait,
+        })
+    }
+
+    async fn run(
+        self,
+        mut last_update: ClusterUpdate,
+        mut index: u64,
+        updates_tx: mpsc::Sender<ClusterUpdate>,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) {
+        loop {
+            let result = tokio::select! {
+                result = self.query(index) => result,
+                _ = shutdown_rx.changed() => return,
+            };
+            // The previous endpoints are kept if the service can't be read.
+            let (update, next_index) = match result {
+                Ok(result) => result,
+                Err(err) => {
+                    warn!(self.log, "Failed to query the instances of the service"; "service" => &self.service, "error" => %err);
+                    tokio::select! {
+                        _ = time::sleep(RETRY_DELAY) => {}
+                        _ = shutdown_rx.changed() => return,
+                    }
+                    continue;
+                }
+            };
+            // The index can go backwards, e.g when the agent's servers lose
+            // their state, after which the next query doesn't block. It's
+            // kept above zero so that queries never stop blocking.
+            index = if next_index < index {
+                1
+            } else {
+                next_index.max(1)
+            };
+            // A blocking query can return without the instances changing,
+            // e.g when its wait is over.
+            if update != last_update {
+                if updates_tx.send(update.clone()).await.is_err() {
+                    return;
+                }
+                last_update = update;
+            }
+        }
+    }
+
+    /// Returns a cluster update with the instances of the service as the
+    /// endpoints of a cluster named after it, along with the index to block
+    /// the next query on. A query with a non-zero `index` waits until the
+    /// instances change after it, or until the wait is over.
+    async fn query(&self, index: u64) -> Result<(ClusterUpdate, u64), Error> {
+        let mut url = self.url.clone();
+        let mut timeout = TIMEOUT;
+        if index > 0 {
+            url.query_pairs_mut()
+                .append_pair("index", &index.to_string())
+                .append_pair("wait", &format!("{}s", self.wait.as_secs().max(1)));
+            timeout += self.wait + self.wait / 16;
+        }
+        let mut request = self.client.get(url).timeout(timeout);
+        if let Some(token) = &self.token {
+            request = request.header("X-Consul-Token", token);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let next_index = response
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|index| index.to_str().ok())
+            .and_then(|index| index.parse().ok())
+            .unwrap_or(0);
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            return Err(format!("{}: {}", status, String::from_utf8_lossy(&body)).into());
+        }
+        let entries: Vec<ServiceEntry> = serde_json::from_slice(&body)?;
+
+        let mut endpoints = Vec::new();
+        for entry in &entries {
+            match endpoint(entry) {
+                Some(endpoint) => endpoints.push(endpoint),
+                None => {
+                    debug!(self.log, "Skipping an instance without an IP address"; "service_id" => &entry.service.id, "node" => &entry.node.node)
+                }
+            }
+        }
+        debug!(self.log, "Queried the instances of the service"; "service" => &self.service, "endpoints" => endpoints.len(), "index" => next_index);
+
+        // Keep the same order between queries, so that unchanged instances
+        // are recognised as such.
+        endpoints.sort_by_key(|endpoint| endpoint.address);
+        let mut localities = HashMap::new();
+        localities.insert(None, LocalityEndpoints { endpoints });
+        let mut update = ClusterUpdate::new();
+        update.insert(self.service.clone(), Cluster { localities });
+        Ok((update, next_index))
+    }
+}
+
+/// Returns an endpoint for the instance of `entry` if it has an IP address,
+/// with its identifiers as metadata.
+fn endpoint(entry: &ServiceEntry) -> Option<Endpoint> {
+    // Instances without an address of their own are reached through the
+    // address of their node.
+    let address = if entry.service.address.is_empty() {
+        &entry.node.address
+    } else {
+        &entry.service.address
+    };
+    let address = SocketAddr::new(address.parse::<IpAddr>().ok()?, entry.service.port);
+    let tokens = entry
+        .service
+        .meta
+        .as_ref()
+        .and_then(|meta| meta.get(TOKENS_META_KEY))
+        .map(|tokens| parse_tokens(tokens))
+        .unwrap_or_default();
+    let metadata = json!({
+        "consul": {
+            "service_id": entry.service.id,
+            "node": entry.node.node,
+            "tags": entry.service.tags,
+            "meta": entry.service.meta,
+        }
+    });
+    Some(Endpoint::new(address, tokens, Some(metadata)))
+}
+
+/// Parses a comma separated list of base64 encoded tokens, skipping those
+/// that aren't valid base64.
+fn parse_tokens(tokens: &str) -> HashSet<Vec<u8>> {
+    tokens
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .filter_map(|token| base64::decode(token).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use reqwest::Url;
+    use serde_json::json;
+
+    use super::Watcher;
+    use crate::config::ConsulDiscovery;
+    use crate::test_utils::logger;
+
+    #[tokio::test]
+    async fn query_instances() {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                assert_eq!("/v1/health/service/game-servers", request.uri().path());
+                assert_eq!("secret", request.headers()["x-consul-token"]);
+                let url = Url::parse(&format!("http://consul{}", request.uri())).unwrap();
+                let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+                assert_eq!("true", query["passing"]);
+                assert_eq!("eu", query["tag"]);
+
+                let instance = |id: &str,
+                                node_address: &str,
+                                address: &str,
+                                meta: serde_json::Value| {
+                    json!({
+                        "Node": {"Node": format!("node-{}", id), "Address": node_address},
+                        "Service": {"ID": id, "Address": address, "Port": 7777, "Tags": ["eu"], "Meta": meta},
+                        "Checks": [],
+                    })
+                };
+                let body = match query.get("index").map(String::as_str) {
+                    None => json!([
+                        instance("b", "10.0.0.2", "", json!({"quilkin-tokens": "YWJj, eHl6"})),
+                        instance("a", "10.0.1.1", "10.0.0.1", json!(null)),
+                        instance("hostname", "10.0.0.3", "game-server.internal", json!(null)),
+                    ]),
+                    Some(index) => {
+                        assert_eq!("5", index);
+                        assert_eq!("60s", query["wait"]);
+                        json!([])
+                    }
+                };
+                let response = Response::builder()
+                    .header("X-Consul-Index", "7")
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let discovery = ConsulDiscovery {
+            service: "game-servers".into(),
+            address: format!("http://{}", address),
+            datacenter: None,
+            tag: Some("eu".into()),
+            passing_only: true,
+            wait: Duration::from_secs(60),
+        };
+        let watcher = Watcher::new(logger(), &discovery, Some("secret".into())).unwrap();
+
+        let (update, index) = watcher.query(0).await.unwrap();
+        assert_eq!(7, index);
+        let endpoints = &update["game-servers"].localities[&None].endpoints;
+        assert_eq!(
+            vec![
+                "10.0.0.1:7777".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:7777".parse().unwrap()
+            ],
+            endpoints
+                .iter()
+                .map(|endpoint| endpoint.address)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(&json!({"service_id": "a", "node": "node-a", "tags": ["eu"], "meta": null})),
+            endpoints[0].metadata.as_ref().unwrap().get("consul")
+        );
+        assert!(endpoints[0].tokens.is_empty());
+        assert_eq!(vec![b"abc".to_vec(), b"xyz".to_vec()], {
+            let mut tokens = endpoints[1].tokens.iter().cloned().collect::<Vec<_>>();
+            tokens.sort();
+            tokens
+        });
+
+        let (update, _) = watcher.query(5).await.unwrap();
+        assert!(update["game-servers"].localities[&None]
+            .endpoints
+            .is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Logging of a random sample of packets along with what the proxy did with
+//! them, for a low volume view of its routing decisions.
+
+use std::net::SocketAddr;
+
+use rand::Rng;
+use slog::{info, Logger};
+
+use crate::config::DebugSampling;
+
+/// Samples packets at the rate of a single proxy, so that proxies embedded in
+/// the same process each keep their own.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Sampler {
+    /// One in how many packets are logged, where `0` logs none.
+    one_in: u32,
+}
+
+impl Sampler {
+    /// Returns a sampler of packets at `rate`, which samples none if it is
+    /// `None`.
+    pub fn new(rate: Option<DebugSampling>) -> Self {
+        Self {
+            one_in: rate.map(|rate| rate.one_in).unwrap_or_default(),
+        }
+    }
+
+    /// Samples a packet of `size` bytes received from `from`, which is
+    /// processed by the filter chain's `read` or `write` as `direction` says.
+    pub fn start(&self, direction: &'static str, from: SocketAddr, size: usize) -> PacketSample {
+        if !sampled(self.one_in) {
+            return PacketSample(None);
+        }
+        PacketSample(Some(Packet {
+            direction,
+            from,
+            size,
+        }))
+    }
+}
+
+/// A packet passing through the proxy, which is only logged if sampled.
+pub(crate) struct PacketSample(Option<Packet>);
+
+struct Packet {
+    direction: &'static str,
+    from: SocketAddr,
+    size: usize,
+}
+
+impl PacketSample {
+    /// Logs that the packet was sent to `to`.
+    pub fn sent_to<'a>(self, log: &Logger, to: impl IntoIterator<Item = &'a SocketAddr>) {
+        if let Some(packet) = self.0 {
+            let to: Vec<String> = to.into_iter().map(ToString::to_string).collect();
+            info!(log, "Sampled packet";
+                "direction" => packet.direction,
+                "from" => packet.from,
+                "size" => packet.size,
+                "outcome" => "forwarded",
+                "to" => to.join(","));
+        }
+    }
+
+    /// Logs that the packet was dropped for `reason`.
+    pub fn dropped(self, log: &Logger, reason: &str) {
+        if let Some(packet) = self.0 {
+            info!(log, "Sampled packet";
+                "direction" => packet.direction,
+                "from" => packet.from,
+                "size" => packet.size,
+                "outcome" => "dropped",
+                "reason" => reason);
+        }
+    }
+}
+
+/// Returns whether to sample a packet that is sampled one in `one_in` times.
+fn sampled(one_in: u32) -> bool {
+    one_in > 0 && rand::thread_rng().gen_ratio(1, one_in)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::config::DebugSampling;
+
+    use super::{sampled, Sampler};
+
+    #[test]
+    fn sample_one_in() {
+        assert!((0..1000).all(|_| !sampled(0)));
+        assert!((0..1000).all(|_| sampled(1)));
+
+        let count = (0..10_000).filter(|_| sampled(10)).count();
+        assert!((700..1300).contains(&count), "sampled {} packets", count);
+    }
+
+    #[test]
+    fn sampler_rate() {
+        let from = SocketAddr::from(([127, 0, 0, 1], 7000));
+        let none = Sampler::new(None);
+        let all = Sampler::new(Some(DebugSampling { one_in: 1 }));
+        assert!((0..100).all(|_| none.start("read", from, 1).0.is_none()));
+        assert!((0..100).all(|_| all.start("read", from, 1).0.is_some()));
+    }
+}
@@ -0,0 +1,203 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Structured events for changes to what the proxy is running with, such as
+//! cluster updates, filter chain reloads and connections to management
+//! servers, which are logged and kept in a history of each proxy served by
+//! the admin API.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+use slog::{info, Logger, Record, Serializer, KV};
+
+/// A change to what the proxy is running with.
+pub(crate) struct Event {
+    kind: &'static str,
+    message: &'static str,
+    details: Vec<(&'static str, Value)>,
+}
+
+impl Event {
+    /// Returns an event of `kind`, such as `cluster.update`, which is logged
+    /// with `message`.
+    pub fn new(kind: &'static str, message: &'static str) -> Self {
+        Self {
+            kind,
+            message,
+            details: Vec::new(),
+        }
+    }
+
+    /// Adds a detail of the event, such as the number of endpoints before
+    /// and after a cluster update.
+    pub fn with(mut self, key: &'static str, value: impl Into<Value>) -> Self {
+        self.details.push((key, value.into()));
+        self
+    }
+}
+
+impl KV for Event {
+    fn serialize(&self, _: &Record, serializer: &mut dyn Serializer) -> slog::Result {
+        serializer.emit_str("event", self.kind)?;
+        for (key, value) in &self.details {
+            match value {
+                Value::String(value) => serializer.emit_str(key, value)?,
+                Value::Bool(value) => serializer.emit_bool(key, *value)?,
+                Value::Number(number) if number.is_u64() => {
+                    serializer.emit_u64(key, number.as_u64().unwrap_or_default())?
+                }
+                Value::Number(number) if number.is_i64() => {
+                    serializer.emit_i64(key, number.as_i64().unwrap_or_default())?
+                }
+                value => serializer.emit_arguments(key, &format_args!("{}", value))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The most recent events of a proxy, up to a configured number of them.
+/// Clones share the same history.
+#[derive(Clone, Default)]
+pub(crate) struct EventHistory {
+    inner: Arc<Mutex<History>>,
+}
+
+#[derive(Default)]
+struct History {
+    /// The maximum number of events to keep, where `0` keeps none.
+    capacity: usize,
+    events: VecDeque<Value>,
+}
+
+impl EventHistory {
+    /// Logs `event` to `log` and adds it to the history.
+    pub fn record(&self, log: &Logger, event: Event) {
+        info!(log, "{}", event.message; &event);
+        self.push(SystemTime::now(), event);
+    }
+
+    /// Sets the number of events to keep, dropping the oldest ones if there
+    /// are more than that.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock();
+        inner.capacity = capacity;
+        while inner.events.len() > capacity {
+            inner.events.pop_front();
+        }
+    }
+
+    fn push(&self, time: SystemTime, event: Event) {
+        let mut inner = self.inner.lock();
+        if inner.capacity == 0 {
+            return;
+        }
+        if inner.events.len() == inner.capacity {
+            inner.events.pop_front();
+        }
+
+        let mut entry = Map::new();
+        entry.insert(
+            "time".into(),
+            humantime::format_rfc3339_millis(time).to_string().into(),
+        );
+        entry.insert("event".into(), event.kind.into());
+        entry.insert("message".into(), event.message.into());
+        for (key, value) in event.details {
+            entry.insert(key.into(), value);
+        }
+        inner.events.push_back(entry.into());
+    }
+
+    /// Returns a HTTP 200 response with the events in the history as JSON,
+    /// from oldest to newest, or a HTTP 404 response if it is disabled.
+    pub(super) fn response(&self) -> Response<Body> {
+        let inner = self.inner.lock();
+        if inner.capacity == 0 {
+            let mut response = Response::new("the event history is disabled".into());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+
+        let body = Value::Array(inner.events.iter().cloned().collect());
+        let mut response = Response::new(body.to_string().into());
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use hyper::StatusCode;
+    use serde_json::{json, Value};
+
+    use super::{Event, EventHistory};
+
+    #[tokio::test]
+    async fn event_history() {
+        let history = EventHistory::default();
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        history.push(time, Event::new("cluster.update", "Updated clusters"));
+        assert_eq!(StatusCode::NOT_FOUND, history.response().status());
+
+        history.set_capacity(2);
+        for endpoints in 1..=3 {
+            history.push(
+                time,
+                Event::new("cluster.update", "Updated clusters")
+                    .with("endpoints_before", endpoints - 1)
+                    .with("endpoints_after", endpoints),
+            );
+        }
+
+        let response = history.response();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let events: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json!([
+                {
+                    "time": "1970-01-01T00:01:00.000Z",
+                    "event": "cluster.update",
+                    "message": "Updated clusters",
+                    "endpoints_before": 1,
+                    "endpoints_after": 2,
+                },
+                {
+                    "time": "1970-01-01T00:01:00.000Z",
+                    "event": "cluster.update",
+                    "message": "Updated clusters",
+                    "endpoints_before": 2,
+                    "endpoints_after": 3,
+                },
+            ]),
+            events
+        );
+
+        history.set_capacity(1);
+        assert_eq!(1, history.inner.lock().events.len());
+    }
+}
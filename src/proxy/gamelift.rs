@@ -0,0 +1,675 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Discovery of endpoints from the game servers of a GameLift FleetIQ game
+//! server group, which are listed through the GameLift API on an interval
+//! and fed into a [`ClusterManager`] as cluster updates.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use prometheus::Registry;
+use reqwest::header::AUTHORIZATION;
+use reqwest::{Client, Url};
+use ring::{digest, hmac};
+use serde::Deserialize;
+use serde_json::json;
+use slog::{debug, o, warn, Logger};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time;
+
+use crate::cluster::cluster_manager::{ClusterManager, SharedClusterManager};
+use crate::cluster::{Cluster, Endpoint, LocalityEndpoints};
+use crate::config::GameLiftDiscovery;
+use crate::proxy::cloud_metadata::EC2_ENDPOINT;
+use crate::proxy::events::EventHistory;
+use crate::proxy::tracing::Tracer;
+use crate::xds::ads_client::{ClusterUpdate, UPDATES_CHANNEL_BUFFER_SIZE};
+
+/// How long to wait for a response from the GameLift API.
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SERVICE: &str = "gamelift";
+const CONTENT_TYPE_JSON: &str = "application/x-amz-json-1.1";
+/// How long before they expire the credentials of an instance role are
+/// refreshed.
+const REFRESH_BEFORE_EXPIRY: Duration = Duration::from_secs(5 * 60);
+/// How long an EC2 session token is valid for, in seconds.
+const EC2_TOKEN_TTL: &str = "21600";
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Returns a [`ClusterManager`] with the claimable game servers of the game
+/// server group of `discovery` as its endpoints, which are kept up to date
+/// until shutdown.
+pub(crate) async fn cluster_manager(
+    base: &Logger,
+    discovery: &GameLiftDiscovery,
+    region: &str,
+    metrics_registry: &Registry,
+    events: &EventHistory,
+    tracer: &Tracer,
+    shutdown_rx: watch::Receiver<()>,
+) -> Result<SharedClusterManager, Error> {
+    let log = base.new(o!("source" => "proxy::GameLift"));
+    let credentials = CredentialsProvider::new(EC2_ENDPOINT)?;
+    let poller = Poller::new(log, discovery, region, credentials)?;
+    // Fail to start rather than wait for a game server group that can't be
+    // read, e.g because the credentials aren't allowed to.
+    let update = poller.poll().await?;
+
+    let (updates_tx, updates_rx) = mpsc::channel(UPDATES_CHANNEL_BUFFER_SIZE);
+    let cluster_manager = ClusterManager::dynamic(
+        base.clone(),
+        metrics_registry,
+        events.clone(),
+        tracer.clone(),
+        update.clone(),
+        updates_rx,
+        shutdown_rx.clone(),
+    )?;
+    tokio::spawn(poller.run(update, updates_tx, shutdown_rx));
+    Ok(cluster_manager)
+}
+
+/// The AWS credentials requests are signed with.
+#[derive(Clone)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+/// Where the credentials requests are signed with come from.
+enum CredentialsProvider {
+    /// The credentials in the standard AWS environment variables, which are
+    /// used as they are.
+    Env(Credentials),
+    /// The temporary credentials of the role of the EC2 instance the proxy
+    /// runs on, which are fetched from the instance metadata service at
+    /// `endpoint` and refreshed before they expire.
+    Instance {
+        client: Client,
+        endpoint: String,
+        cached: Mutex<Option<(Credentials, SystemTime)>>,
+    },
+}
+
+/// The credentials of an instance role, as returned by the instance metadata
+/// service.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InstanceCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: String,
+}
+
+impl CredentialsProvider {
+    /// Uses the credentials in the `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment variables
+    /// if they are set, or those of the instance role from the metadata
+    /// service at `endpoint` otherwise.
+    fn new(endpoint: &str) -> Result<Self, Error> {
+        let var = |name| {
+            std::env::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(Self::Env(Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: var("AWS_SESSION_TOKEN"),
+            })),
+            (None, None) => Ok(Self::Instance {
+                client: Client::builder().timeout(TIMEOUT).build()?,
+                endpoint: endpoint.trim_end_matches('/').to_owned(),
+                cached: Mutex::new(None),
+            }),
+            _ => Err(
+                "both or neither of `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` must be set"
+                    .into(),
+            ),
+        }
+    }
+
+    /// Returns the credentials to sign a request with.
+    async fn credentials(&self) -> Result<Credentials, Error> {
+        let (client, endpoint, cached) = match self {
+            Self::Env(credentials) => return Ok(credentials.clone()),
+            Self::Instance {
+                client,
+                endpoint,
+                cached,
+            } => (client, endpoint, cached),
+        };
+        let mut cached = cached.lock().await;
+        if let Some((credentials, expiration)) = &*cached {
+            if SystemTime::now() + REFRESH_BEFORE_EXPIRY < *expiration {
+                return Ok(credentials.clone());
+            }
+        }
+        let (credentials, expiration) = instance_credentials(client, endpoint).await?;
+        *cached = Some((credentials.clone(), expiration));
+        Ok(credentials)
+    }
+}
+
+/// Returns the credentials of the role of the EC2 instance from its metadata
+/// service at `endpoint`, along with when they expire.
+async fn instance_credentials(
+    client: &Client,
+    endpoint: &str,
+) -> Result<(Credentials, SystemTime), Error> {
+    // Instances that still allow IMDSv1 are queried without a session token
+    // if one can't be created.
+    let token = client
+        .put(format!("{}/latest/api/token", endpoint))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", EC2_TOKEN_TTL)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    let token = match token {
+        Ok(response) => response.text().await.ok(),
+        Err(_) => None,
+    };
+    let get = |path: String| {
+        let mut request = client.get(format!(
+            "{}/latest/meta-data/iam/security-credentials/{}",
+            endpoint, path
+        ));
+        if let Some(token) = &token {
+            request = request.header("X-aws-ec2-metadata-token", token);
+        }
+        async move {
+            let body = request.send().await?.error_for_status()?.text().await?;
+            Ok::<_, Error>(body)
+        }
+    };
+
+    // The role is listed on the first line.
+    let roles = get(String::new()).await?;
+    let role = match roles.lines().next().map(str::trim) {
+        Some(role) if !role.is_empty() => role.to_owned(),
+        _ => return Err("the instance has no role to take credentials from".into()),
+    };
+    let credentials: InstanceCredentials = serde_json::from_str(&get(role).await?)?;
+    let expiration = humantime::parse_rfc3339(&credentials.expiration)?;
+    Ok((
+        Credentials {
+            access_key_id: credentials.access_key_id,
+            secret_access_key: credentials.secret_access_key,
+            session_token: Some(credentials.token),
+        },
+        expiration,
+    ))
+}
+
+/// A game server of a game server group, as returned by `ListGameServers`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GameServer {
+    game_server_id: String,
+    #[serde(default)]
+    instance_id: Option<String>,
+    #[serde(default)]
+    connection_info: Option<String>,
+    #[serde(default)]
+    game_server_data: Option<String>,
+    #[serde(default)]
+    claim_status: Option<String>,
+    #[serde(default)]
+    utilization_status: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListGameServersResponse {
+    #[serde(default)]
+    game_servers: Vec<GameServer>,
+    #[serde(default)]
+    next_token: Option<String>,
+}
+
+/// Lists the game servers of a game server group.
+struct Poller {
+    log: Logger,
+    client: Client,
+    url: Url,
+    region: String,
+    credentials: CredentialsProvider,
+    game_server_group: String,
+    interval: Duration,
+}
+
+impl Poller {
+    fn new(
+        log: Logger,
+        discovery: &GameLiftDiscovery,
+        region: &str,
+        credentials: CredentialsProvider,
+    ) -> Result<Self, Error> {
+        let url = match &discovery.endpoint {
+            Some(endpoint) => Url::parse(endpoint)?,
+            None => Url::parse(&format!("https://gamelift.{}.amazonaws.com/", region))?,
+        };
+        Ok(Self {
+            log,
+            client: Client::builder().timeout(TIMEOUT).build()?,
+            url,
+            region: region.into(),
+            credentials,
+            game_server_group: discovery.game_server_group.clone(),
+            interval: discovery.interval,
+        })
+    }
+
+    async fn run(
+        self,
+        mut last_update: ClusterUpdate,
+        updates_tx: mpsc::Sender<ClusterUpdate>,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) {
+        let mut interval = time::interval_at(time::Instant::now() + self.interval, self.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_rx.changed() => return,
+            }
+            // The previous endpoints are kept if the game servers can't be
+            // listed.
+            let update = match self.poll().await {
+                Ok(update) => update,
+                Err(err) => {
+                    warn!(self.log, "Failed to list the game servers of the game server group"; "game_server_group" => &self.game_server_group, "error" => %err);
+                    continue;
+                }
+            };
+            if update != last_update {
+                if updates_tx.send(update.clone()).await.is_err() {
+                    return;
+                }
+                last_update = update;
+            }
+        }
+    }
+
+    /// Returns a cluster update with the claimable game servers of the game
+    /// server group as the endpoints of a cluster named after it.
+    async fn poll(&self) -> Result<ClusterUpdate, Error> {
+        let mut endpoints = Vec::new();
+        let mut next_token = None;
+        loop {
+            let mut request = json!({ "GameServerGroupName": self.game_server_group });
+            if let Some(next_token) = next_token {
+                request["NextToken"] = next_token;
+            }
+            let response: ListGameServersResponse =
+                serde_json::from_slice(&self.call("ListGameServers", request.to_string()).await?)?;
+            for game_server in &response.game_servers {
+                match endpoint(game_server) {
+                    Some(endpoint) => endpoints.push(endpoint),
+                    None => {
+                        debug!(self.log, "Skipping a game server that can't be claimed"; "game_server_id" => &game_server.game_server_id)
+                    }
+                }
+            }
+            next_token = match response.next_token {
+                Some(next_token) => Some(next_token.into()),
+                None => break,
+            };
+        }
+        debug!(self.log, "Listed the game servers of the game server group"; "game_server_group" => &self.game_server_group, "endpoints" => endpoints.len());
+
+        // Keep the same order between polls, so that unchanged game servers
+        // are recognised as such.
+        endpoints.sort_by_key(|endpoint| endpoint.address);
+        let mut localities = HashMap::new();
+        localities.insert(None, LocalityEndpoints { endpoints });
+        let mut update = ClusterUpdate::new();
+        update.insert(self.game_server_group.clone(), Cluster { localities });
+        Ok(update)
+    }
+
+    /// Calls `action` of the GameLift API with a JSON `body`, returning the
+    /// body of the response.
+    async fn call(&self, action: &str, body: String) -> Result<Vec<u8>, Error> {
+        let host = match (self.url.host_str(), self.url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(format!("`{}` has no host", self.url).into()),
+        };
+        // Fetched for every request, so that expiring credentials are
+        // replaced in time.
+        let credentials = self.credentials.credentials().await?;
+        let amz_date = amz_date(SystemTime::now());
+        let mut headers = vec![
+            ("content-type", CONTENT_TYPE_JSON.to_owned()),
+            ("host", host),
+            ("x-amz-date", amz_date.clone()),
+            ("x-amz-target", format!("GameLift.{}", action)),
+        ];
+        if let Some(session_token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", session_token.clone()));
+        }
+        headers.sort();
+        let authorization = sign(
+            &credentials,
+            &self.region,
+            SERVICE,
+            &amz_date,
+            &headers,
+            body.as_bytes(),
+        );
+
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(AUTHORIZATION, authorization)
+            .body(body);
+        // The host header is set from the URL.
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.header(*name, value);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            return Err(format!("{}: {}", status, String::from_utf8_lossy(&body)).into());
+        }
+        Ok(body.to_vec())
+    }
+}
+
+/// Returns an endpoint for `game_server` if it can be claimed, with its
+/// identifiers as metadata.
+fn endpoint(game_server: &GameServer) -> Option<Endpoint> {
+    let claimable = game_server.utilization_status.as_deref() == Some("AVAILABLE")
+        && game_server.claim_status.as_deref() != Some("CLAIMED");
+    if !claimable {
+        return None;
+    }
+    // Game servers are expected to register their connection info as an IP
+    // address and port.
+    let address: SocketAddr = game_server.connection_info.as_ref()?.parse().ok()?;
+    let metadata = json!({
+        "gamelift": {
+            "game_server_id": game_server.game_server_id,
+            "instance_id": game_server.instance_id,
+            "game_server_data": game_server.game_server_data,
+        }
+    });
+    Some(Endpoint::new(address, Default::default(), Some(metadata)))
+}
+
+/// Returns the time in the format of the `x-amz-date` header.
+fn amz_date(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time)
+        .to_string()
+        .replace(&['-', ':'][..], "")
+}
+
+/// Returns the `Authorization` header of a `POST /` request with `headers`,
+/// which are sorted by name, signed with AWS Signature Version 4.
+fn sign(
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    amz_date: &str,
+    headers: &[(&str, String)],
+    payload: &[u8],
+) -> String {
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        signed_headers,
+        hex(digest::digest(&digest::SHA256, payload).as_ref())
+    );
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+
+    let hmac_sign = |key: &[u8], data: &str| {
+        hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+    };
+    let key = format!("AWS4{}", credentials.secret_access_key);
+    let key = hmac_sign(key.as_bytes(), date);
+    let key = hmac_sign(key.as_ref(), region);
+    let key = hmac_sign(key.as_ref(), service);
+    let key = hmac_sign(key.as_ref(), "aws4_request");
+    let signature = hmac_sign(key.as_ref(), &string_to_sign);
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex(signature.as_ref())
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use serde_json::{json, Value};
+
+    use super::{amz_date, sign, Credentials, CredentialsProvider, Poller};
+    use crate::config::GameLiftDiscovery;
+    use crate::test_utils::logger;
+
+    fn credentials() -> Credentials {
+        Credentials {
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            session_token: None,
+        }
+    }
+
+    #[test]
+    fn sign_request() {
+        // The `post-vanilla` case of the AWS Signature Version 4 test suite.
+        let amz_date = amz_date(UNIX_EPOCH + Duration::from_secs(1_440_938_160));
+        assert_eq!("20150830T123600Z", amz_date);
+        let headers = vec![
+            ("host", "example.amazonaws.com".to_owned()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        assert_eq!(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b",
+            sign(
+                &credentials(),
+                "us-east-1",
+                "service",
+                &amz_date,
+                &headers,
+                b""
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn list_claimable_game_servers() {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                assert_eq!(
+                    "GameLift.ListGameServers",
+                    request.headers()["x-amz-target"]
+                );
+                let authorization = request.headers()["authorization"].to_str().unwrap();
+                assert!(
+                    authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"),
+                    "{}",
+                    authorization
+                );
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let body: Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!("game-servers", body["GameServerGroupName"]);
+
+                let game_server = |id: &str, address: &str, claim: Value, utilization: &str| {
+                    json!({
+                        "GameServerId": id,
+                        "InstanceId": "i-1234",
+                        "ConnectionInfo": address,
+                        "ClaimStatus": claim,
+                        "UtilizationStatus": utilization,
+                    })
+                };
+                let response = if body.get("NextToken").is_none() {
+                    json!({
+                        "GameServers": [
+                            game_server("b", "10.0.0.2:7777", Value::Null, "AVAILABLE"),
+                            game_server("claimed", "10.0.0.3:7777", "CLAIMED".into(), "AVAILABLE"),
+                            game_server("utilized", "10.0.0.4:7777", Value::Null, "UTILIZED"),
+                            game_server("no-port", "10.0.0.5", Value::Null, "AVAILABLE"),
+                        ],
+                        "NextToken": "page-2",
+                    })
+                } else {
+                    assert_eq!("page-2", body["NextToken"]);
+                    json!({
+                        "GameServers": [game_server("a", "10.0.0.1:7777", Value::Null, "AVAILABLE")],
+                    })
+                };
+                Ok::<_, Infallible>(Response::new(Body::from(response.to_string())))
+            }))
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let discovery = GameLiftDiscovery {
+            game_server_group: "game-servers".into(),
+            region: None,
+            interval: Duration::from_secs(10),
+            endpoint: Some(format!("http://{}/", address)),
+        };
+        let credentials = CredentialsProvider::Env(credentials());
+        let poller = Poller::new(logger(), &discovery, "us-west-2", credentials).unwrap();
+        let update = poller.poll().await.unwrap();
+
+        let endpoints = &update["game-servers"].localities[&None].endpoints;
+        assert_eq!(
+            vec![
+                "10.0.0.1:7777".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:7777".parse().unwrap()
+            ],
+            endpoints
+                .iter()
+                .map(|endpoint| endpoint.address)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(
+                &json!({"game_server_id": "a", "instance_id": "i-1234", "game_server_data": null})
+            ),
+            endpoints[0].metadata.as_ref().unwrap().get("gamelift")
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_instance_credentials() {
+        // Serves credentials that expire within minutes the first time, and
+        // in an hour afterwards.
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let make_service = {
+            let fetches = fetches.clone();
+            make_service_fn(move |_| {
+                let fetches = fetches.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let fetches = fetches.clone();
+                        async move {
+                            let body = match request.uri().path() {
+                                "/latest/api/token" => "token".to_owned(),
+                                "/latest/meta-data/iam/security-credentials/" => {
+                                    assert_eq!(
+                                        "token",
+                                        request.headers()["x-aws-ec2-metadata-token"]
+                                    );
+                                    "proxy-role\n".to_owned()
+                                }
+                                "/latest/meta-data/iam/security-credentials/proxy-role" => {
+                                    let fetch = fetches.fetch_add(1, Ordering::SeqCst) + 1;
+                                    let expires_in = if fetch == 1 { 60 } else { 60 * 60 };
+                                    let expiration = humantime::format_rfc3339_seconds(
+                                        SystemTime::now() + Duration::from_secs(expires_in),
+                                    );
+                                    json!({
+                                        "AccessKeyId": format!("key-{}", fetch),
+                                        "SecretAccessKey": "secret",
+                                        "Token": "session",
+                                        "Expiration": expiration.to_string(),
+                                    })
+                                    .to_string()
+                                }
+                                path => panic!("unexpected request for {}", path),
+                            };
+                            Ok::<_, Infallible>(Response::new(Body::from(body)))
+                        }
+                    }))
+                }
+            })
+        };
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let provider = CredentialsProvider::Instance {
+            client: reqwest::Client::new(),
+            endpoint: format!("http://{}", address),
+            cached: Default::default(),
+        };
+        let credentials = provider.credentials().await.unwrap();
+        assert_eq!("key-1", credentials.access_key_id);
+        assert_eq!(Some("session".to_owned()), credentials.session_token);
+        // Credentials about to expire are refreshed, others are reused.
+        assert_eq!("key-2", provider.credentials().await.unwrap().access_key_id);
+        assert_eq!("key-2", provider.credentials().await.unwrap().access_key_id);
+        assert_eq!(2, fetches.load(Ordering::SeqCst));
+    }
+}
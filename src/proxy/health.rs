@@ -17,14 +17,54 @@
 use std::sync::atomic::AtomicBool;
 
 use hyper::{Body, Response, StatusCode};
+use parking_lot::RwLock;
+use prometheus::core::{AtomicU64, GenericGauge};
 use slog::{error, o, Logger};
 use std::panic;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
 
+use crate::cluster::cluster_manager::SharedClusterManager;
+
 pub struct Health {
     log: Logger,
     healthy: Arc<AtomicBool>,
+    /// What the proxy needs in order to receive traffic, set once it has
+    /// started.
+    readiness: RwLock<Option<Readiness>>,
+}
+
+/// What the proxy needs in order to receive traffic.
+struct Readiness {
+    cluster_manager: SharedClusterManager,
+    /// The connection state with the xDS management server, if the proxy gets
+    /// its configuration from one.
+    xds_connected: Option<GenericGauge<AtomicU64>>,
+    /// The fraction of endpoints that must be healthy.
+    min_healthy_endpoints: f64,
+}
+
+impl Readiness {
+    /// Returns why the proxy cannot receive traffic, if it cannot.
+    fn check(&self) -> Result<(), &'static str> {
+        if let Some(connected) = &self.xds_connected {
+            if connected.get() == 0 {
+                return Err("not connected to an xDS management server");
+            }
+        }
+        let endpoints = match self.cluster_manager.read().get_all_endpoints() {
+            Some(endpoints) => endpoints,
+            None => return Err("no endpoints available"),
+        };
+        let healthy = endpoints.iter().filter(|endpoint| endpoint.healthy).count();
+        if healthy == 0 {
+            return Err("no healthy endpoints available");
+        }
+        if (healthy as f64) < self.min_healthy_endpoints * endpoints.size() as f64 {
+            return Err("too few healthy endpoints available");
+        }
+        Ok(())
+    }
 }
 
 impl Health {
@@ -32,6 +72,7 @@ impl Health {
         let health = Self {
             log: base.new(o!("source" => "proxy::Health")),
             healthy: Arc::new(AtomicBool::new(true)),
+            readiness: RwLock::new(None),
         };
 
         let log = health.log.clone();
@@ -56,14 +97,61 @@ impl Health {
         *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
         response
     }
+
+    /// Marks the proxy as started, after which it is ready whenever at least
+    /// one of its endpoints, and `min_healthy_endpoints` of them, are healthy
+    /// and, if `xds_connected` is set, it is connected to an xDS management
+    /// server.
+    pub fn set_started(
+        &self,
+        cluster_manager: SharedClusterManager,
+        xds_connected: Option<GenericGauge<AtomicU64>>,
+        min_healthy_endpoints: f64,
+    ) {
+        *self.readiness.write() = Some(Readiness {
+            cluster_manager,
+            xds_connected,
+            min_healthy_endpoints,
+        });
+    }
+
+    /// returns a HTTP 200 response if the proxy is healthy and ready to
+    /// receive traffic, otherwise a HTTP 503 response with the reason.
+    pub fn check_ready(&self) -> Response<Body> {
+        let result = if !self.healthy.load(Relaxed) {
+            Err("unhealthy")
+        } else {
+            match &*self.readiness.read() {
+                Some(readiness) => readiness.check(),
+                None => Err("starting"),
+            }
+        };
+
+        match result {
+            Ok(()) => Response::new("ok".into()),
+            Err(reason) => {
+                let mut response = Response::new(reason.into());
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                response
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::proxy::health::Health;
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::Endpoints;
+    use crate::proxy::events::EventHistory;
+    use crate::proxy::health::{Health, Readiness};
+    use crate::proxy::tracing::Tracer;
     use crate::test_utils::logger;
     use hyper::StatusCode;
+    use prometheus::core::GenericGauge;
+    use prometheus::Registry;
     use std::panic;
+    use tokio::sync::{mpsc, watch};
 
     #[test]
     fn panic_hook() {
@@ -80,4 +168,94 @@ mod tests {
         let response = health.check_healthy();
         assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
+
+    #[tokio::test]
+    async fn readiness() {
+        let registry = Registry::default();
+        let cluster_manager = ClusterManager::fixed(
+            &registry,
+            Endpoints::new(vec![Endpoint::from_address(
+                "127.0.0.1:26000".parse().unwrap(),
+            )])
+            .unwrap(),
+        )
+        .unwrap();
+        let readiness = Readiness {
+            cluster_manager,
+            xds_connected: None,
+            min_healthy_endpoints: 0.0,
+        };
+        assert_eq!(Ok(()), readiness.check());
+
+        let xds_connected = GenericGauge::new("connected_state", "connected").unwrap();
+        let readiness = Readiness {
+            cluster_manager: readiness.cluster_manager,
+            xds_connected: Some(xds_connected.clone()),
+            min_healthy_endpoints: 0.0,
+        };
+        assert!(readiness.check().is_err());
+        xds_connected.set(1);
+        assert_eq!(Ok(()), readiness.check());
+
+        // A management server may not have any endpoints for the proxy.
+        let (_cluster_updates_tx, cluster_updates_rx) = mpsc::channel(1);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let readiness = Readiness {
+            cluster_manager: ClusterManager::dynamic(
+                logger(),
+                &registry,
+                EventHistory::default(),
+                Tracer::default(),
+                Default::default(),
+                cluster_updates_rx,
+                shutdown_rx,
+            )
+            .unwrap(),
+            xds_connected: Some(xds_connected),
+            min_healthy_endpoints: 0.0,
+        };
+        assert_eq!(Err("no endpoints available"), readiness.check());
+    }
+
+    #[test]
+    fn readiness_healthy_endpoints() {
+        let endpoint = |port: u16, healthy: bool| {
+            let mut endpoint = Endpoint::from_address(([127, 0, 0, 1], port).into());
+            endpoint.healthy = healthy;
+            endpoint
+        };
+        let readiness = |healthy: &[bool], min_healthy_endpoints: f64| {
+            let endpoints = healthy
+                .iter()
+                .enumerate()
+                .map(|(port, healthy)| endpoint(26000 + port as u16, *healthy))
+                .collect();
+            Readiness {
+                cluster_manager: ClusterManager::fixed(
+                    &Registry::default(),
+                    Endpoints::new(endpoints).unwrap(),
+                )
+                .unwrap(),
+                xds_connected: None,
+                min_healthy_endpoints,
+            }
+            .check()
+        };
+
+        assert_eq!(Ok(()), readiness(&[true, false, false, false], 0.0));
+        assert_eq!(
+            Err("no healthy endpoints available"),
+            readiness(&[false, false], 0.0)
+        );
+        assert_eq!(Ok(()), readiness(&[true, true, false, false], 0.5));
+        assert_eq!(
+            Err("too few healthy endpoints available"),
+            readiness(&[true, false, false, false], 0.5)
+        );
+        assert_eq!(Ok(()), readiness(&[true, true], 1.0));
+        assert_eq!(
+            Err("too few healthy endpoints available"),
+            readiness(&[true, true, true, false], 1.0)
+        );
+    }
 }
@@ -0,0 +1,480 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Discovery of endpoints from the EndpointSlices of a Kubernetes Service,
+//! which are watched through the API server and fed into a [`ClusterManager`]
+//! as cluster updates, in the same way as those received over xDS.
+
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use k8s_openapi::api::discovery::v1beta1::EndpointSlice;
+use k8s_openapi::{ListOptional, WatchOptional};
+use kube::api::{ObjectList, WatchEvent};
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::{Client, Config};
+use prometheus::Registry;
+use slog::{debug, o, warn, Logger};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use tokio_stream::StreamExt;
+
+use crate::cluster::cluster_manager::{ClusterManager, SharedClusterManager};
+use crate::cluster::{Cluster, Endpoint, LocalityEndpoints};
+use crate::config::KubernetesDiscovery;
+use crate::proxy::events::EventHistory;
+use crate::proxy::tracing::Tracer;
+use crate::xds::ads_client::{ClusterUpdate, UPDATES_CHANNEL_BUFFER_SIZE};
+
+/// The label that Kubernetes sets on EndpointSlices with the name of their
+/// Service.
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+/// How long the API server is asked to keep a watch open for, which is
+/// within the timeout of the client's requests.
+const WATCH_TIMEOUT_SECONDS: i64 = 290;
+/// How long to wait before listing the EndpointSlices again after the watch
+/// failed, which doubles on every failure.
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Returns a [`ClusterManager`] with the endpoints of the Service of
+/// `discovery`, which are kept up to date until shutdown.
+pub(crate) async fn cluster_manager(
+    base: &Logger,
+    discovery: &KubernetesDiscovery,
+    metrics_registry: &Registry,
+    events: &EventHistory,
+    tracer: &Tracer,
+    shutdown_rx: watch::Receiver<()>,
+) -> Result<SharedClusterManager, Error> {
+    let log = base.new(o!("source" => "proxy::Kubernetes"));
+    let config = match &discovery.kubeconfig {
+        Some(path) => {
+            Config::from_custom_kubeconfig(
+                Kubeconfig::read_from(path)?,
+                &KubeConfigOptions::default(),
+            )
+            .await?
+        }
+        None => Config::infer().await?,
+    };
+    let namespace = discovery
+        .namespace
+        .clone()
+        .unwrap_or_else(|| config.default_ns.clone());
+
+    let mut watcher = Watcher {
+        log: log.clone(),
+        client: Client::try_from(config)?,
+        namespace: namespace.clone(),
+        label_selector: format!("{}={}", SERVICE_NAME_LABEL, discovery.service),
+        service: discovery.service.clone(),
+        port: discovery.port.clone(),
+        slices: HashMap::new(),
+        resource_version: String::new(),
+    };
+    // Fail to start rather than wait for a Service that can't be read, e.g
+    // because the proxy isn't allowed to.
+    let update = watcher.list().await?;
+    debug!(log, "Listed the EndpointSlices of the Service"; "service" => &discovery.service, "namespace" => &namespace, "endpoints" => update_endpoints(&update));
+
+    let (updates_tx, updates_rx) = mpsc::channel(UPDATES_CHANNEL_BUFFER_SIZE);
+    let cluster_manager = ClusterManager::dynamic(
+        base.clone(),
+        metrics_registry,
+        events.clone(),
+        tracer.clone(),
+        update,
+        updates_rx,
+        shutdown_rx.clone(),
+    )?;
+    tokio::spawn(watcher.run(updates_tx, shutdown_rx));
+    Ok(cluster_manager)
+}
+
+/// Keeps track of the addresses of every EndpointSlice of a Service.
+///
+/// Requests are built with `k8s_openapi` rather than through `kube::Api`,
+/// which gets the path of EndpointSlices wrong.
+struct Watcher {
+    log: Logger,
+    client: Client,
+    namespace: String,
+    label_selector: String,
+    service: String,
+    port: Option<String>,
+    /// The addresses of each EndpointSlice, by its name.
+    slices: HashMap<String, BTreeSet<SocketAddr>>,
+    /// The version of the EndpointSlices that the next watch starts from.
+    resource_version: String,
+}
+
+impl Watcher {
+    async fn run(
+        mut self,
+        updates_tx: mpsc::Sender<ClusterUpdate>,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) {
+        let mut retry_delay = MIN_RETRY_DELAY;
+        let mut listed = true;
+        loop {
+            let result = tokio::select! {
+                result = self.sync(listed, &mut retry_delay, &updates_tx) => result,
+                _ = shutdown_rx.changed() => return,
+            };
+            listed = result.is_ok();
+            // The API server ends watches after a while, in which case the
+            // next one carries on from where it left off.
+            if let Err(err) = result {
+                warn!(self.log, "Failed to watch the EndpointSlices of the Service, listing them again"; "service" => &self.service, "error" => %err, "delay" => ?retry_delay);
+                tokio::select! {
+                    _ = time::sleep(retry_delay) => {}
+                    _ = shutdown_rx.changed() => return,
+                }
+                retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+
+    /// Lists the EndpointSlices again unless they are `listed` already, and
+    /// then watches them for changes.
+    async fn sync(
+        &mut self,
+        listed: bool,
+        retry_delay: &mut Duration,
+        updates_tx: &mpsc::Sender<ClusterUpdate>,
+    ) -> Result<(), Error> {
+        if !listed {
+            let update = self.list().await?;
+            *retry_delay = MIN_RETRY_DELAY;
+            updates_tx
+                .send(update)
+                .await
+                .map_err(|_| "the cluster manager stopped")?;
+        }
+
+        let (request, _) = EndpointSlice::watch_namespaced_endpoint_slice(
+            &self.namespace,
+            WatchOptional {
+                label_selector: Some(&self.label_selector),
+                resource_version: Some(&self.resource_version),
+                allow_watch_bookmarks: Some(true),
+                timeout_seconds: Some(WATCH_TIMEOUT_SECONDS),
+                ..WatchOptional::default()
+            },
+        )?;
+        let mut events = Box::pin(self.client.request_events::<EndpointSlice>(request).await?);
+        while let Some(event) = events.next().await {
+            let changed = match event? {
+                WatchEvent::Added(slice) | WatchEvent::Modified(slice) => {
+                    self.set_resource_version(&slice);
+                    let addresses = slice_addresses(&slice, self.port.as_deref());
+                    let name = slice.metadata.name.unwrap_or_default();
+                    self.slices.insert(name, addresses.clone()) != Some(addresses)
+                }
+                WatchEvent::Deleted(slice) => {
+                    self.set_resource_version(&slice);
+                    let name = slice.metadata.name.unwrap_or_default();
+                    self.slices.remove(&name).is_some()
+                }
+                WatchEvent::Bookmark(bookmark) => {
+                    self.resource_version = bookmark.metadata.resource_version;
+                    false
+                }
+                // Such as the version being too old to watch from, which
+                // requires listing the EndpointSlices again.
+                WatchEvent::Error(err) => return Err(err.into()),
+            };
+            if changed {
+                let update = self.update();
+                debug!(self.log, "The EndpointSlices of the Service changed"; "service" => &self.service, "endpoints" => update_endpoints(&update));
+                updates_tx
+                    .send(update)
+                    .await
+                    .map_err(|_| "the cluster manager stopped")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the addresses of every EndpointSlice with the current ones.
+    async fn list(&mut self) -> Result<ClusterUpdate, Error> {
+        let (request, _) = EndpointSlice::list_namespaced_endpoint_slice(
+            &self.namespace,
+            ListOptional {
+                label_selector: Some(&self.label_selector),
+                ..ListOptional::default()
+            },
+        )?;
+        let slices: ObjectList<EndpointSlice> = self.client.request(request).await?;
+        self.resource_version = slices.metadata.resource_version.unwrap_or_default();
+        self.slices = slices
+            .items
+            .iter()
+            .map(|slice| {
+                (
+                    slice.metadata.name.clone().unwrap_or_default(),
+                    slice_addresses(slice, self.port.as_deref()),
+                )
+            })
+            .collect();
+        Ok(self.update())
+    }
+
+    fn set_resource_version(&mut self, slice: &EndpointSlice) {
+        if let Some(version) = &slice.metadata.resource_version {
+            self.resource_version = version.clone();
+        }
+    }
+
+    /// Returns a cluster update with the addresses of every EndpointSlice as
+    /// the endpoints of a cluster named after the Service.
+    fn update(&self) -> ClusterUpdate {
+        let addresses: BTreeSet<SocketAddr> = self.slices.values().flatten().copied().collect();
+        let mut localities = HashMap::new();
+        localities.insert(
+            None,
+            LocalityEndpoints {
+                endpoints: addresses.into_iter().map(Endpoint::from_address).collect(),
+            },
+        );
+        let mut update = ClusterUpdate::new();
+        update.insert(self.service.clone(), Cluster { localities });
+        update
+    }
+}
+
+/// Returns the addresses of the ready endpoints of `slice`, with the number
+/// of the port named `port`, or its first port.
+fn slice_addresses(slice: &EndpointSlice, port: Option<&str>) -> BTreeSet<SocketAddr> {
+    let port = slice
+        .ports
+        .iter()
+        .flatten()
+        .find(|slice_port| match port {
+            Some(name) => slice_port.name.as_deref() == Some(name),
+            None => true,
+        })
+        .and_then(|slice_port| slice_port.port)
+        .and_then(|port| u16::try_from(port).ok());
+    let port = match port {
+        Some(port) => port,
+        None => return BTreeSet::new(),
+    };
+
+    slice
+        .endpoints
+        .iter()
+        // Endpoints whose readiness is unknown should be treated as ready.
+        .filter(|endpoint| {
+            endpoint
+                .conditions
+                .as_ref()
+                .and_then(|conditions| conditions.ready)
+                .unwrap_or(true)
+        })
+        .flat_map(|endpoint| endpoint.addresses.iter())
+        // `FQDN` EndpointSlices have hostnames rather than IP addresses.
+        .filter_map(|address| address.parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect()
+}
+
+fn update_endpoints(update: &ClusterUpdate) -> usize {
+    update
+        .values()
+        .flat_map(|cluster| cluster.localities.values())
+        .map(|locality| locality.endpoints.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use k8s_openapi::api::discovery::v1beta1::EndpointSlice;
+    use prometheus::Registry;
+    use serde_json::{json, Value};
+    use tokio::sync::watch;
+
+    use super::{cluster_manager, slice_addresses};
+    use crate::config::KubernetesDiscovery;
+    use crate::proxy::events::EventHistory;
+    use crate::proxy::tracing::Tracer;
+    use crate::test_utils::logger;
+
+    fn slice(name: &str, addresses: &[&str], ready: bool) -> Value {
+        json!({
+            "apiVersion": "discovery.k8s.io/v1beta1",
+            "kind": "EndpointSlice",
+            "metadata": {"name": name, "resourceVersion": "2"},
+            "addressType": "IPv4",
+            "endpoints": [{"addresses": addresses, "conditions": {"ready": ready}}],
+            "ports": [{"name": "metrics", "port": 9091}, {"name": "game", "port": 7777}],
+        })
+    }
+
+    #[test]
+    fn ready_slice_addresses() {
+        let mut slice: EndpointSlice =
+            serde_json::from_value(slice("a", &["10.0.0.1", "10.0.0.2"], true)).unwrap();
+        let expected: BTreeSet<SocketAddr> = vec![
+            "10.0.0.1:7777".parse().unwrap(),
+            "10.0.0.2:7777".parse().unwrap(),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(expected, slice_addresses(&slice, Some("game")));
+        assert_eq!(
+            9091,
+            slice_addresses(&slice, None).iter().next().unwrap().port()
+        );
+        assert!(slice_addresses(&slice, Some("voice")).is_empty());
+
+        slice.endpoints[0].conditions.as_mut().unwrap().ready = Some(false);
+        assert!(slice_addresses(&slice, Some("game")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn watch_endpoint_slices() {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                assert_eq!(
+                    "/apis/discovery.k8s.io/v1beta1/namespaces/games/endpointslices",
+                    request.uri().path()
+                );
+                let query = request.uri().query().unwrap_or_default();
+                assert!(
+                    query.contains("labelSelector=kubernetes.io%2Fservice-name%3Dgame-servers"),
+                    "{}",
+                    query
+                );
+                let body = if query.contains("watch=true") {
+                    // Stream a change, and then keep the watch open.
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        let event = json!({
+                            "type": "MODIFIED",
+                            "object": slice("a", &["10.0.0.1", "10.0.0.2"], true),
+                        });
+                        sender.send_data(format!("{}\n", event).into()).await.ok();
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        drop(sender);
+                    });
+                    body
+                } else {
+                    json!({
+                        "apiVersion": "discovery.k8s.io/v1beta1",
+                        "kind": "EndpointSliceList",
+                        "metadata": {"resourceVersion": "1"},
+                        "items": [slice("a", &["10.0.0.1"], true)],
+                    })
+                    .to_string()
+                    .into()
+                };
+                Ok::<_, Infallible>(Response::new(body))
+            }))
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let dir = std::env::temp_dir().join(format!("quilkin-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let kubeconfig = dir.join("kubeconfig");
+        std::fs::write(
+            &kubeconfig,
+            format!(
+                "
+apiVersion: v1
+kind: Config
+clusters:
+  - name: test
+    cluster:
+      server: http://{}
+users:
+  - name: test
+    user:
+      token: test
+contexts:
+  - name: test
+    context:
+      cluster: test
+      user: test
+current-context: test
+",
+                address
+            ),
+        )
+        .unwrap();
+
+        let discovery = KubernetesDiscovery {
+            service: "game-servers".into(),
+            namespace: Some("games".into()),
+            port: Some("game".into()),
+            kubeconfig: Some(kubeconfig),
+        };
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let cluster_manager = cluster_manager(
+            &logger(),
+            &discovery,
+            &Registry::default(),
+            &EventHistory::default(),
+            &Tracer::default(),
+            shutdown_rx,
+        )
+        .await
+        .unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        let addresses = || -> Vec<SocketAddr> {
+            cluster_manager
+                .read()
+                .get_all_endpoints()
+                .map(|endpoints| endpoints.iter().map(|ep| ep.address).collect())
+                .unwrap_or_default()
+        };
+        assert_eq!(
+            vec!["10.0.0.1:7777".parse::<SocketAddr>().unwrap()],
+            addresses()
+        );
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while addresses().len() != 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the change to the EndpointSlice should be applied");
+        assert_eq!(
+            vec![
+                "10.0.0.1:7777".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:7777".parse().unwrap()
+            ],
+            addresses()
+        );
+    }
+}
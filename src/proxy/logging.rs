@@ -0,0 +1,592 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Control of the level and format that a proxy's log records are written
+//! in while it runs.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::panic::RefUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Response, StatusCode};
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use slog::{
+    o, BorrowedKV, Drain, Key, Level, Logger, OwnedKVList, Record, RecordStatic, Serializer, KV,
+};
+use slog_term::{FullFormat, PlainDecorator};
+
+use crate::config::{LogFormat, Logging as LoggingConfig};
+
+use self::syslog::Syslog;
+
+mod syslog;
+
+/// The level that records are written at unless changed, with debug builds
+/// writing debug records as they always have.
+const DEFAULT_LEVEL: Level = if cfg!(debug_assertions) {
+    Level::Debug
+} else {
+    Level::Info
+};
+
+/// Returns a logger writing records to stdout in the format of `config`,
+/// and sending them to its syslog server if it has one, at `levels`, which
+/// can be changed at any time. Fails if the syslog server can't be reached.
+///
+/// Levels of records that no target writes can be checked cheaply with
+/// [`Drain::is_enabled`] on the logger or any logger created from it.
+pub fn config_logger(config: &LoggingConfig, levels: Arc<LogLevels>) -> io::Result<Logger> {
+    let syslog = config
+        .syslog
+        .as_ref()
+        .map(|syslog| {
+            Syslog::connect(syslog).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "failed to connect to the syslog server `{}`: {}",
+                        syslog.address, err
+                    ),
+                )
+            })
+        })
+        .transpose()?;
+    Ok(root_logger(
+        Formatted::stdout(config.format, syslog),
+        levels,
+    ))
+}
+
+/// Returns a logger writing records to stdout in `format` at `levels`.
+pub(crate) fn stdout_logger(format: LogFormat, levels: Arc<LogLevels>) -> Logger {
+    root_logger(Formatted::stdout(format, None), levels)
+}
+
+fn root_logger(drain: Formatted, levels: Arc<LogLevels>) -> Logger {
+    let drain = slog_async::Async::new(drain.fuse()).build().fuse();
+    let drain = LevelFilter::new(drain, levels).fuse();
+    Logger::root(drain, o!())
+}
+
+/// The level that log records are written at, globally and for individual
+/// targets.
+///
+/// A target is either the `source` of a logger, such as
+/// `extensions::TokenRouter`, or the path of a Rust module, such as
+/// `quilkin::xds`, and also applies to the sources and modules nested within
+/// it. The most specific target that matches a record decides its level.
+pub struct LogLevels {
+    levels: RwLock<Levels>,
+    /// The most verbose level of any target, as returned by
+    /// [`Level::as_usize`], so most records can be dropped without locking.
+    max_level: AtomicUsize,
+}
+
+// Loggers must be usable after a panic, which cannot leave the levels in an
+// inconsistent state as every update is done while holding the lock.
+impl RefUnwindSafe for LogLevels {}
+
+impl Default for LogLevels {
+    /// Levels writing records at info, or debug in debug builds, for every
+    /// target.
+    fn default() -> Self {
+        Self::new(DEFAULT_LEVEL)
+    }
+}
+
+struct Levels {
+    level: Level,
+    targets: BTreeMap<String, Level>,
+}
+
+impl LogLevels {
+    pub(super) fn new(level: Level) -> Self {
+        Self {
+            levels: RwLock::new(Levels {
+                level,
+                targets: BTreeMap::new(),
+            }),
+            max_level: AtomicUsize::new(level.as_usize()),
+        }
+    }
+
+    /// Returns the level of records that no target overrides.
+    pub fn level(&self) -> Level {
+        self.levels.read().level
+    }
+
+    /// Returns the level of each target that overrides it.
+    pub fn target_levels(&self) -> BTreeMap<String, Level> {
+        self.levels.read().targets.clone()
+    }
+
+    /// Sets the level of records that no target overrides.
+    pub fn set_level(&self, level: Level) {
+        self.update(|levels| levels.level = level);
+    }
+
+    /// Sets the level of records from `target`.
+    pub fn set_target_level(&self, target: impl Into<String>, level: Level) {
+        let target = target.into();
+        self.update(|levels| {
+            levels.targets.insert(target, level);
+        });
+    }
+
+    /// Removes the level of `target`, so that its records are written at the
+    /// level of the target that contains it, or the global level. Returns
+    /// whether `target` had a level.
+    pub fn clear_target_level(&self, target: &str) -> bool {
+        let mut removed = false;
+        self.update(|levels| removed = levels.targets.remove(target).is_some());
+        removed
+    }
+
+    /// Returns whether any target writes records at `level`, without locking,
+    /// so that records on the path of every packet can be skipped before
+    /// they are built.
+    pub fn is_level_enabled(&self, level: Level) -> bool {
+        level.as_usize() <= self.max_level.load(Relaxed)
+    }
+
+    fn update(&self, update: impl FnOnce(&mut Levels)) {
+        let mut levels = self.levels.write();
+        update(&mut levels);
+        let max_level = levels
+            .targets
+            .values()
+            .chain(Some(&levels.level))
+            .map(Level::as_usize)
+            .max()
+            .unwrap_or_default();
+        self.max_level.store(max_level, Relaxed);
+    }
+
+    /// Returns whether a record at `level` from `module`, logged through a
+    /// logger with `source`, is written.
+    fn is_enabled(&self, level: Level, module: &str, source: Option<&str>) -> bool {
+        let levels = self.levels.read();
+        let target_level = levels
+            .targets
+            .iter()
+            .filter(|(target, _)| {
+                contains(target, module)
+                    || matches!(source, Some(source) if contains(target, source))
+            })
+            .max_by_key(|(target, _)| target.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(levels.level);
+        level.as_usize() <= target_level.as_usize()
+    }
+}
+
+/// Returns a HTTP 200 response with the levels as JSON.
+pub(super) fn response(levels: &LogLevels) -> Response<Body> {
+    let levels = levels.levels.read();
+    let targets: BTreeMap<_, _> = levels
+        .targets
+        .iter()
+        .map(|(target, level)| (target, level_name(*level)))
+        .collect();
+    let body = serde_json::json!({
+        "level": level_name(levels.level),
+        "targets": targets,
+    });
+    let mut response = Response::new(body.to_string().into());
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+/// Changes the levels as requested by the `level` and `target` parameters of
+/// `query`, either setting the level of a target or of all records with
+/// `POST`, or removing the level of a target with `DELETE`.
+pub(super) fn update(levels: &LogLevels, method: &Method, query: Option<&str>) -> Response<Body> {
+    let mut level = None;
+    let mut target = None;
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match &*key {
+            "level" => level = Some(value),
+            "target" => target = Some(value),
+            _ => {}
+        }
+    }
+
+    let result = match (method, target, level) {
+        (&Method::DELETE, Some(target), None) => {
+            if levels.clear_target_level(&target) {
+                Ok(())
+            } else {
+                Err(format!("target `{}` has no level", target))
+            }
+        }
+        (&Method::POST, target, Some(level)) => match level.parse() {
+            Ok(level) => {
+                match target {
+                    Some(target) => levels.set_target_level(target, level),
+                    None => levels.set_level(level),
+                }
+                Ok(())
+            }
+            Err(()) => Err(format!(
+                "invalid level `{}`, expected one of: critical, error, warning, info, debug, trace",
+                level
+            )),
+        },
+        (&Method::DELETE, _, _) => Err("expected a `target` parameter only".into()),
+        _ => Err("expected a `level` parameter and optionally a `target`".into()),
+    };
+
+    match result {
+        Ok(()) => response(levels),
+        Err(message) => {
+            let mut response = Response::new(message.into());
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            response
+        }
+    }
+}
+
+fn level_name(level: Level) -> String {
+    level.as_str().to_lowercase()
+}
+
+/// Returns whether `target` is `path` or one of its parents, with `::`
+/// separating the components of both.
+fn contains(target: &str, path: &str) -> bool {
+    match path.strip_prefix(target) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+/// A [`Drain`] that only passes on the records that [`LogLevels`] allows.
+struct LevelFilter<D> {
+    drain: D,
+    levels: Arc<LogLevels>,
+}
+
+impl<D> LevelFilter<D> {
+    fn new(drain: D, levels: Arc<LogLevels>) -> Self {
+        Self { drain, levels }
+    }
+}
+
+impl<D: Drain> Drain for LevelFilter<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if !self.levels.is_level_enabled(record.level()) {
+            return Ok(());
+        }
+        let mut source = Source(None);
+        values.serialize(record, &mut source).ok();
+        if self
+            .levels
+            .is_enabled(record.level(), record.module(), source.0.as_deref())
+        {
+            self.drain.log(record, values).map(|_| ())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn is_enabled(&self, level: Level) -> bool {
+        self.levels.is_level_enabled(level)
+    }
+}
+
+/// Captures the `source` of a logger from its values.
+struct Source(Option<String>);
+
+impl Serializer for Source {
+    fn emit_arguments(&mut self, key: Key, value: &fmt::Arguments) -> slog::Result {
+        // Values are serialized from the most recently added, which is the
+        // most specific source.
+        if key == "source" && self.0.is_none() {
+            self.0 = Some(value.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A [`Drain`] that writes records to stdout in its format, and sends them
+/// to its syslog server if it has one.
+struct Formatted {
+    stdout: Stdout,
+    syslog: Option<Mutex<Syslog>>,
+    /// The record last formatted for syslog, which is written without a
+    /// newline.
+    syslog_json: Json<Buffer>,
+    syslog_buffer: Buffer,
+}
+
+enum Stdout {
+    Json(Json<io::Stdout>),
+    Plain(FullFormat<PlainDecorator<io::Stdout>>),
+}
+
+impl Formatted {
+    fn stdout(format: LogFormat, syslog: Option<Syslog>) -> Self {
+        let syslog_buffer = Buffer::default();
+        Self {
+            stdout: match format {
+                LogFormat::Json => Stdout::Json(Json::new(io::stdout(), true)),
+                LogFormat::Plain => {
+                    Stdout::Plain(FullFormat::new(PlainDecorator::new(io::stdout())).build())
+                }
+            },
+            syslog: syslog.map(Mutex::new),
+            syslog_json: Json::new(syslog_buffer.clone(), false),
+            syslog_buffer,
+        }
+    }
+}
+
+impl Drain for Formatted {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        if let Some(syslog) = &self.syslog {
+            let mut syslog = syslog.lock();
+            self.syslog_json.log(record, values)?;
+            let message = std::mem::take(&mut *self.syslog_buffer.0.lock());
+            syslog.send(record.level(), &message);
+        }
+        match &self.stdout {
+            Stdout::Json(json) => json.log(record, values),
+            Stdout::Plain(plain) => plain.log(record, values),
+        }
+    }
+}
+
+/// A writer to memory that can be read from while a drain writes to it.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The keys that [`Json`] writes first, from the record itself.
+const RECORD_KEYS: [&str; 3] = ["ts", "level", "msg"];
+
+/// A [`Drain`] that writes each record as a JSON object, starting with the
+/// `ts`, `level` and `msg` of the record.
+///
+/// Unlike loggers usually do, a key only appears once in an object, with the
+/// value of the record or, failing that, the logger that was created last, so
+/// that e.g. the `source` of a filter is not lost to that of the logger it was
+/// created from.
+struct Json<W: Write>(slog_json::Json<W>);
+
+impl<W: Write> Json<W> {
+    fn new(writer: W, newlines: bool) -> Self {
+        Self(
+            slog_json::Json::new(writer)
+                .set_newlines(newlines)
+                // Values are serialized from the last, so that `ts` is first.
+                .add_key_value(slog::o!(
+                    "msg" => slog::PushFnValue(|record: &Record, serializer| {
+                        serializer.emit(record.msg())
+                    }),
+                    "level" => slog::FnValue(|record: &Record| record.level().as_short_str()),
+                    "ts" => slog::FnValue(|_: &Record| {
+                        humantime::format_rfc3339_nanos(SystemTime::now()).to_string()
+                    }),
+                ))
+                .build(),
+        )
+    }
+}
+
+impl<W: Write> Drain for Json<W> {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        // The values of both the record and its logger are passed on as
+        // those of the record, so that they are deduplicated together.
+        let values = Deduplicated { record, values };
+        let record_static = RecordStatic {
+            location: record.location(),
+            tag: record.tag(),
+            level: record.level(),
+        };
+        let deduplicated = Record::new(&record_static, record.msg(), BorrowedKV(&values));
+        self.0.log(&deduplicated, &NO_VALUES)
+    }
+}
+
+static NO_VALUES: Lazy<OwnedKVList> = Lazy::new(|| slog::o!().into());
+
+/// The values of a record followed by those of its logger, of which only the
+/// first with each key is serialized.
+struct Deduplicated<'a> {
+    record: &'a Record<'a>,
+    values: &'a OwnedKVList,
+}
+
+impl KV for Deduplicated<'_> {
+    fn serialize(&self, record: &Record, serializer: &mut dyn Serializer) -> slog::Result {
+        let mut serializer = Unique {
+            seen: RECORD_KEYS.iter().copied().collect(),
+            serializer,
+        };
+        self.record.kv().serialize(record, &mut serializer)?;
+        self.values.serialize(record, &mut serializer)
+    }
+}
+
+/// A [`Serializer`] that passes on the first value with each key, and
+/// ignores the rest.
+struct Unique<'a> {
+    seen: HashSet<Key>,
+    serializer: &'a mut dyn Serializer,
+}
+
+macro_rules! emit_unique {
+    ($($emit:ident($value:ty)),* $(,)?) => {
+        $(
+            fn $emit(&mut self, key: Key, value: $value) -> slog::Result {
+                if self.seen.insert(key) {
+                    self.serializer.$emit(key, value)?;
+                }
+                Ok(())
+            }
+        )*
+    };
+}
+
+impl Serializer for Unique<'_> {
+    emit_unique!(
+        emit_arguments(&fmt::Arguments),
+        emit_str(&str),
+        emit_bool(bool),
+        emit_char(char),
+        emit_u8(u8),
+        emit_i8(i8),
+        emit_u16(u16),
+        emit_i16(i16),
+        emit_u32(u32),
+        emit_i32(i32),
+        emit_u64(u64),
+        emit_i64(i64),
+        emit_usize(usize),
+        emit_isize(isize),
+        emit_f32(f32),
+        emit_f64(f64),
+    );
+
+    fn emit_unit(&mut self, key: Key) -> slog::Result {
+        if self.seen.insert(key) {
+            self.serializer.emit_unit(key)?;
+        }
+        Ok(())
+    }
+
+    fn emit_none(&mut self, key: Key) -> slog::Result {
+        if self.seen.insert(key) {
+            self.serializer.emit_none(key)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use slog::{info, o, Drain, Level, Logger};
+
+    use super::{Buffer, Json, LogLevels};
+
+    #[test]
+    fn target_levels() {
+        let levels = LogLevels::new(Level::Info);
+        assert!(levels.is_enabled(Level::Info, "quilkin::proxy", None));
+        assert!(!levels.is_enabled(Level::Debug, "quilkin::proxy", None));
+
+        levels.set_target_level("extensions::TokenRouter", Level::Debug);
+        levels.set_target_level("quilkin::xds", Level::Warning);
+        levels.set_target_level("quilkin::xds::cluster", Level::Trace);
+        assert_eq!(
+            Level::Trace.as_usize(),
+            levels.max_level.load(super::Relaxed)
+        );
+
+        let token_router = Some("extensions::TokenRouter");
+        let module = "quilkin::filters::extensions::token_router";
+        assert!(levels.is_enabled(Level::Debug, module, token_router));
+        assert!(!levels.is_enabled(Level::Trace, module, token_router));
+        assert!(!levels.is_enabled(Level::Debug, module, Some("extensions::TokenRouterV2")));
+        assert!(!levels.is_enabled(Level::Info, "quilkin::xds::ads_client", None));
+        assert!(levels.is_enabled(Level::Trace, "quilkin::xds::cluster", None));
+
+        assert!(levels.clear_target_level("quilkin::xds::cluster"));
+        assert!(!levels.clear_target_level("quilkin::xds::cluster"));
+        assert!(!levels.is_enabled(Level::Trace, "quilkin::xds::cluster", None));
+        assert_eq!(
+            Level::Debug.as_usize(),
+            levels.max_level.load(super::Relaxed)
+        );
+
+        levels.set_level(Level::Error);
+        assert!(!levels.is_enabled(Level::Warning, "quilkin::proxy", None));
+        assert_eq!(Level::Error, levels.level());
+        assert_eq!(2, levels.target_levels().len());
+    }
+
+    #[test]
+    fn json_format() {
+        let buffer = Buffer::default();
+        let drain = Mutex::new(Json::new(buffer.clone(), true)).fuse();
+        let log = Logger::root(drain, o!("source" => "run"))
+            .new(o!("source" => "extensions::Debug", "filter" => "Debug"));
+        info!(log, "Read filter event"; "from" => "127.0.0.1:7000", "port" => 7000u16, "filter" => "Other");
+        info!(log, "Second");
+
+        let output = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(2, lines.len());
+
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!("INFO", record["level"]);
+        assert_eq!("Read filter event", record["msg"]);
+        assert_eq!("extensions::Debug", record["source"]);
+        assert_eq!("Other", record["filter"]);
+        assert_eq!("127.0.0.1:7000", record["from"]);
+        assert_eq!(7000, record["port"]);
+        assert!(record["ts"].is_string());
+        assert_eq!(1, lines[0].matches("\"source\"").count());
+        assert!(lines[0].starts_with("{\"ts\":"));
+    }
+}
@@ -0,0 +1,276 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Sending of log records to a syslog server as RFC 5424 messages, whose
+//! `MSG` is the record as a JSON object.
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, SystemTime};
+
+use slog::Level;
+
+use crate::config::{Syslog as SyslogConfig, SyslogTransport};
+
+/// How long to wait for the server to accept a connection or a message, so
+/// that an unresponsive server can't hold up logging for long.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A connection to a syslog server.
+pub(super) struct Syslog {
+    /// The priority of messages before their severity is added, which is
+    /// their facility times eight.
+    priority_base: u8,
+    /// The `HOSTNAME APP-NAME PROCID` of every message.
+    origin: String,
+    transport: Transport,
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    /// The stream is reconnected to the first message after it failed.
+    Tcp {
+        address: SocketAddr,
+        stream: Option<TcpStream>,
+    },
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl Syslog {
+    /// Connects to the server, failing if it can't be reached over TCP or
+    /// its address can't be resolved.
+    pub(super) fn connect(config: &SyslogConfig) -> io::Result<Self> {
+        let transport = match config.transport {
+            SyslogTransport::Udp => {
+                let address = resolve(&config.address)?;
+                let local: SocketAddr = if address.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(address)?;
+                Transport::Udp(socket)
+            }
+            SyslogTransport::Tcp => {
+                let address = resolve(&config.address)?;
+                Transport::Tcp {
+                    address,
+                    stream: Some(connect_tcp(address)?),
+                }
+            }
+            #[cfg(unix)]
+            SyslogTransport::Unix => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&config.address)?;
+                Transport::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            SyslogTransport::Unix => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Unix sockets are only supported on Unix",
+                ))
+            }
+        };
+
+        Ok(Self {
+            priority_base: config.facility as u8 * 8,
+            origin: format!(
+                "{} {} {}",
+                hostname(),
+                header_field(&config.app_name, 48),
+                std::process::id()
+            ),
+            transport,
+        })
+    }
+
+    /// Sends `msg` at `level`. Messages that can't be sent are dropped, as
+    /// there is nowhere to report the failure to.
+    pub(super) fn send(&mut self, level: Level, msg: &[u8]) {
+        let message = self.message(level, msg);
+        match &mut self.transport {
+            Transport::Udp(socket) => {
+                socket.send(&message).ok();
+            }
+            Transport::Tcp { address, stream } => {
+                let mut frame = format!("{} ", message.len()).into_bytes();
+                frame.extend_from_slice(&message);
+                if stream.is_none() {
+                    *stream = connect_tcp(*address).ok();
+                }
+                if let Some(connected) = stream {
+                    if connected.write_all(&frame).is_err() {
+                        *stream = None;
+                    }
+                }
+            }
+            #[cfg(unix)]
+            Transport::Unix(socket) => {
+                socket.send(&message).ok();
+            }
+        }
+    }
+
+    fn message(&self, level: Level, msg: &[u8]) -> Vec<u8> {
+        let mut message = format!(
+            "<{}>1 {} {} - - ",
+            self.priority_base + severity(level),
+            humantime::format_rfc3339_micros(SystemTime::now()),
+            self.origin
+        )
+        .into_bytes();
+        message.extend_from_slice(msg);
+        message
+    }
+}
+
+fn resolve(address: &str) -> io::Result<SocketAddr> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("`{}` did not resolve to any address", address),
+        )
+    })
+}
+
+fn connect_tcp(address: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+/// The RFC 5424 severity of records at `level`.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Critical => 2,
+        Level::Error => 3,
+        Level::Warning => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Returns `value` as a header field of at most `max_len` printable ASCII
+/// characters, or `-` if it has none.
+fn header_field(value: &str, max_len: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if field.is_empty() {
+        "-".into()
+    } else {
+        field
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // Safety: the buffer is valid for writes of its whole length.
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return "-".into();
+    }
+    let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    header_field(&String::from_utf8_lossy(&buffer[..len]), 255)
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    "-".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, UdpSocket};
+    use std::time::Duration;
+
+    use slog::Level;
+
+    use super::Syslog;
+    use crate::config::{Syslog as SyslogConfig, SyslogFacility, SyslogTransport};
+
+    fn config(address: String, transport: SyslogTransport) -> SyslogConfig {
+        SyslogConfig {
+            address,
+            transport,
+            facility: SyslogFacility::Local0,
+            app_name: "quilkin proxy".into(),
+        }
+    }
+
+    /// Checks the header of an RFC 5424 message and returns its `MSG`.
+    fn msg(message: &str, priority: &str) -> String {
+        let parts: Vec<&str> = message.splitn(8, ' ').collect();
+        assert_eq!(8, parts.len(), "{}", message);
+        assert_eq!(format!("<{}>1", priority), parts[0]);
+        assert!(humantime::parse_rfc3339(parts[1]).is_ok(), "{}", parts[1]);
+        assert_eq!("quilkinproxy", parts[3]);
+        assert_eq!(std::process::id().to_string(), parts[4]);
+        assert_eq!(["-", "-"], parts[5..7]);
+        parts[7].into()
+    }
+
+    #[test]
+    fn send_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let mut syslog = Syslog::connect(&config(address, SyslogTransport::Udp)).unwrap();
+
+        syslog.send(Level::Warning, br#"{"msg":"hello"}"#);
+        let mut buffer = [0; 1024];
+        let len = server.recv(&mut buffer).unwrap();
+        let message = String::from_utf8(buffer[..len].to_vec()).unwrap();
+        // local0 (16) * 8 + warning (4)
+        assert_eq!(r#"{"msg":"hello"}"#, msg(&message, "132"));
+    }
+
+    #[test]
+    fn send_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut syslog = Syslog::connect(&config(address, SyslogTransport::Tcp)).unwrap();
+        syslog.send(Level::Info, b"first");
+        syslog.send(Level::Critical, b"second");
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        for (priority, expected) in &[("134", "first"), ("130", "second")] {
+            let mut len = vec![];
+            reader.read_until(b' ', &mut len).unwrap();
+            let len: usize = std::str::from_utf8(&len[..len.len() - 1])
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut message = vec![0; len];
+            reader.read_exact(&mut message).unwrap();
+            let message = String::from_utf8(message).unwrap();
+            assert_eq!(*expected, msg(&message, priority));
+        }
+    }
+}
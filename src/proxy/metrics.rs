@@ -14,15 +14,35 @@
  *  limitations under the License.
  */
 
+use std::sync::Arc;
+
 use hyper::{Body, Response, StatusCode};
+use parking_lot::RwLock;
+use prometheus::proto::{LabelPair, MetricFamily};
 use prometheus::{Encoder, Registry, TextEncoder};
 use slog::{o, warn, Logger};
 
+use crate::proxy::capture::Capture;
+use crate::proxy::events::EventHistory;
+use crate::proxy::top_talkers::TopTalkers;
+
 /// Metrics contains metrics configuration for the server.
 #[derive(Clone)]
 pub struct Metrics {
     log: Logger,
     pub(crate) registry: Registry,
+    /// The traffic received from each client, for finding the ones that
+    /// send the most.
+    pub(crate) top_talkers: TopTalkers,
+    /// The capture of the packets passing through the proxy, which is
+    /// started through the admin server.
+    pub(crate) capture: Arc<Capture>,
+    /// The changes to what the proxy is running with, which are served by
+    /// the admin server.
+    pub(crate) events: EventHistory,
+    /// The labels added to every metric, which identify the instance the
+    /// proxy runs on.
+    instance_labels: Arc<RwLock<Vec<(String, String)>>>,
 }
 
 impl Metrics {
@@ -30,7 +50,45 @@ impl Metrics {
         Metrics {
             log: base.new(o!("source" => "proxy::Metrics")),
             registry,
+            top_talkers: TopTalkers::default(),
+            capture: Arc::default(),
+            events: EventHistory::default(),
+            instance_labels: Arc::default(),
+        }
+    }
+
+    /// Adds `labels` to every metric, unless it already has a label with the
+    /// same name.
+    pub(crate) fn set_instance_labels(&self, labels: Vec<(String, String)>) {
+        *self.instance_labels.write() = labels;
+    }
+
+    /// Returns the current value of every metric.
+    pub(crate) fn gather(&self) -> Vec<MetricFamily> {
+        let mut families = self.registry.gather();
+        let instance_labels = self.instance_labels.read();
+        if instance_labels.is_empty() {
+            return families;
         }
+
+        for family in &mut families {
+            for metric in family.mut_metric().iter_mut() {
+                let mut labels = metric.take_label();
+                for (name, value) in instance_labels.iter() {
+                    if labels.iter().any(|label| label.get_name() == name) {
+                        continue;
+                    }
+                    let mut label = LabelPair::default();
+                    label.set_name(name.clone());
+                    label.set_value(value.clone());
+                    labels.push(label);
+                }
+                // The encoders expect labels to be sorted by name.
+                labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                metric.set_label(labels);
+            }
+        }
+        families
     }
 
     pub fn collect_metrics(&self) -> Response<Body> {
@@ -38,7 +96,7 @@ impl Metrics {
         let mut buffer = vec![];
         let encoder = TextEncoder::new();
         let body = encoder
-            .encode(&self.registry.gather(), &mut buffer)
+            .encode(&self.gather(), &mut buffer)
             .map_err(|err| warn!(self.log, "Failed to encode metrics"; "error" => %err))
             .and_then(|_| {
                 String::from_utf8(buffer).map(Body::from).map_err(
@@ -62,7 +120,7 @@ impl Metrics {
 #[cfg(test)]
 mod tests {
     use hyper::StatusCode;
-    use prometheus::Registry;
+    use prometheus::{IntCounterVec, Opts, Registry};
 
     use crate::proxy::Metrics;
     use crate::test_utils::logger;
@@ -74,4 +132,33 @@ mod tests {
         let response = metrics.collect_metrics();
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn collect_metrics_with_instance_labels() {
+        let registry = Registry::default();
+        let counter = IntCounterVec::new(
+            Opts::new("packets_total", "packets"),
+            &["cloud_zone", "kind"],
+        )
+        .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["local", "read"]).inc();
+
+        let metrics = Metrics::new(&logger(), registry);
+        metrics.set_instance_labels(vec![
+            ("cloud_zone".into(), "us-west-2a".into()),
+            ("instance_id".into(), "i-0123".into()),
+        ]);
+        let body = hyper::body::to_bytes(metrics.collect_metrics().into_body())
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            body.contains(
+                r#"packets_total{cloud_zone="local",instance_id="i-0123",kind="read"} 1"#
+            ),
+            "{}",
+            body
+        );
+    }
 }
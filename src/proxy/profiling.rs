@@ -0,0 +1,230 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! CPU and heap profiles of the running proxy, in formats that `pprof` reads.
+//!
+//! CPU profiles are sampled with `pprof-rs`. Heap profiles are dumped by
+//! jemalloc, which must be the global allocator and have been started with
+//! profiling enabled, as the `quilkin` binary does when built with the
+//! `profiling` feature.
+
+use std::ffi::CString;
+use std::fs;
+use std::time::Duration;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use pprof::protos::Message;
+use pprof::ProfilerGuard;
+
+/// How long CPU profiles are recorded for if a request does not set it.
+const DEFAULT_DURATION: Duration = Duration::from_secs(30);
+/// The longest a CPU profile can be recorded for.
+const MAX_DURATION: Duration = Duration::from_secs(300);
+/// How often stacks are sampled while recording a CPU profile, per second.
+const FREQUENCY: i32 = 99;
+
+/// The format a CPU profile is returned in.
+#[derive(Debug, PartialEq)]
+enum Format {
+    /// The `profile.proto` format of `pprof`.
+    Protobuf,
+    /// An SVG flamegraph.
+    Flamegraph,
+}
+
+/// Starts or stops recording heap profiles. Allocations are only recorded
+/// while active, and heap profiles only contain those.
+pub(crate) fn set_heap_profiling(active: bool) -> Result<(), String> {
+    // SAFETY: `prof.active` is a boolean.
+    unsafe { tikv_jemalloc_ctl::raw::write(b"prof.active\0", active) }
+        .map_err(|err| format!("failed to activate heap profiling: {}", err))
+}
+
+/// Records a CPU profile for the duration set by `query`, returning it in the
+/// requested format.
+pub(super) async fn cpu_profile(query: Option<&str>) -> Response<Body> {
+    let (duration, format) = match parse_query(query) {
+        Ok(parsed) => parsed,
+        Err(message) => return error_response(StatusCode::BAD_REQUEST, message),
+    };
+
+    // Only one CPU profile can be recorded at a time.
+    let guard = match ProfilerGuard::new(FREQUENCY) {
+        Ok(guard) => guard,
+        Err(pprof::Error::Running) => {
+            return error_response(
+                StatusCode::CONFLICT,
+                "a CPU profile is already being recorded".into(),
+            )
+        }
+        Err(err) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to start the CPU profiler: {}", err),
+            )
+        }
+    };
+    tokio::time::sleep(duration).await;
+
+    let result = guard
+        .report()
+        .build()
+        .map_err(|err| err.to_string())
+        .and_then(|report| {
+            let mut body = Vec::new();
+            match format {
+                Format::Protobuf => report
+                    .pprof()
+                    .map_err(|err| err.to_string())?
+                    .encode(&mut body)
+                    .map_err(|err| err.to_string())?,
+                Format::Flamegraph => report
+                    .flamegraph(&mut body)
+                    .map_err(|err| err.to_string())?,
+            }
+            Ok(body)
+        });
+    drop(guard);
+
+    match result {
+        Ok(body) => {
+            let content_type = match format {
+                Format::Protobuf => "application/octet-stream",
+                Format::Flamegraph => "image/svg+xml",
+            };
+            let mut response = Response::new(body.into());
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, content_type.parse().unwrap());
+            response
+        }
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to build the CPU profile: {}", err),
+        ),
+    }
+}
+
+/// Dumps the allocations recorded since heap profiling was activated.
+pub(super) async fn heap_profile() -> Response<Body> {
+    let result = tokio::task::spawn_blocking(|| {
+        let path = std::env::temp_dir().join(format!("quilkin-{}.heap", uuid::Uuid::new_v4()));
+        let c_path =
+            CString::new(path.to_string_lossy().into_owned()).map_err(|err| err.to_string())?;
+        // SAFETY: `prof.dump` takes the path of the file to write the
+        // profile to as a C string, which outlives the call.
+        unsafe { tikv_jemalloc_ctl::raw::write(b"prof.dump\0", c_path.as_ptr()) }
+            .map_err(|err| err.to_string())?;
+        let profile = fs::read(&path).map_err(|err| err.to_string());
+        fs::remove_file(&path).ok();
+        profile
+    })
+    .await
+    .map_err(|err| err.to_string())
+    .and_then(|result| result);
+
+    match result {
+        Ok(profile) => {
+            let mut response = Response::new(profile.into());
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/octet-stream".parse().unwrap());
+            response
+        }
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to dump the heap profile: {}", err),
+        ),
+    }
+}
+
+fn parse_query(query: Option<&str>) -> Result<(Duration, Format), String> {
+    let mut duration = DEFAULT_DURATION;
+    let mut format = Format::Protobuf;
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match &*key {
+            "seconds" => {
+                duration = match value.parse() {
+                    Ok(seconds) if seconds > 0 && seconds <= MAX_DURATION.as_secs() => {
+                        Duration::from_secs(seconds)
+                    }
+                    _ => {
+                        return Err(format!(
+                            "invalid number of seconds `{}`, expected a number between 1 and {}",
+                            value,
+                            MAX_DURATION.as_secs()
+                        ))
+                    }
+                }
+            }
+            "format" => {
+                format = match &*value {
+                    "pprof" => Format::Protobuf,
+                    "flamegraph" => Format::Flamegraph,
+                    _ => {
+                        return Err(format!(
+                            "invalid format `{}`, expected one of: pprof, flamegraph",
+                            value
+                        ))
+                    }
+                }
+            }
+            _ => return Err(format!("unknown parameter `{}`", key)),
+        }
+    }
+    Ok((duration, format))
+}
+
+fn error_response(status: StatusCode, message: String) -> Response<Body> {
+    let mut response = Response::new(message.into());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::StatusCode;
+
+    use super::{cpu_profile, parse_query, Format};
+
+    #[test]
+    fn parse_profile_query() {
+        assert_eq!(
+            (Duration::from_secs(5), Format::Flamegraph),
+            parse_query(Some("seconds=5&format=flamegraph")).unwrap()
+        );
+        assert_eq!(
+            (Duration::from_secs(30), Format::Protobuf),
+            parse_query(None).unwrap()
+        );
+        assert!(parse_query(Some("seconds=0")).is_err());
+        assert!(parse_query(Some("seconds=3600")).is_err());
+        assert!(parse_query(Some("format=svg")).is_err());
+    }
+
+    #[tokio::test]
+    async fn record_cpu_profile() {
+        let (first, second) = tokio::join!(cpu_profile(Some("seconds=1")), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            cpu_profile(Some("seconds=1")).await
+        });
+        assert_eq!(StatusCode::OK, first.status());
+        assert_eq!(StatusCode::CONFLICT, second.status());
+    }
+}
@@ -0,0 +1,173 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Pushing of metrics to a Prometheus Pushgateway, for proxies that may exit
+//! before they are ever scraped.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use prometheus::{Encoder, TextEncoder};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use slog::{debug, o, warn, Logger};
+use tokio::sync::watch;
+use tokio::time;
+
+use crate::config::Pushgateway;
+use crate::proxy::Metrics;
+
+/// How long to wait for the Pushgateway to accept metrics.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Pushes the metrics of the proxy to a Pushgateway, grouped by the job and
+/// the ID of the proxy as its instance.
+#[derive(Clone)]
+pub(crate) struct Pusher {
+    log: Logger,
+    client: Client,
+    url: Url,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl Pusher {
+    pub fn new(
+        base: &Logger,
+        config: &Pushgateway,
+        proxy_id: &str,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Error> {
+        let mut url = Url::parse(&config.url)?;
+        url.path_segments_mut()
+            .map_err(|()| format!("`{}` can't be used as a base URL", config.url))?
+            .pop_if_empty()
+            .extend(&["metrics", "job", &config.job, "instance", proxy_id]);
+        Ok(Self {
+            log: base.new(o!("source" => "proxy::Pushgateway")),
+            client: Client::builder().timeout(TIMEOUT).build()?,
+            url,
+            interval: config.interval,
+            metrics,
+        })
+    }
+
+    /// Spawns a task that pushes metrics on the configured interval until
+    /// shutdown.
+    pub fn spawn(&self, mut shutdown_rx: watch::Receiver<()>) {
+        let pusher = self.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                time::interval_at(time::Instant::now() + pusher.interval, pusher.interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => pusher.push().await,
+                    _ = shutdown_rx.changed() => return,
+                }
+            }
+        });
+    }
+
+    /// Pushes the current value of every metric, replacing the ones pushed
+    /// before.
+    pub async fn push(&self) {
+        match self.try_push().await {
+            Ok(()) => debug!(self.log, "Pushed metrics"; "url" => %self.url),
+            Err(err) => {
+                warn!(self.log, "Failed to push metrics"; "url" => %self.url, "error" => %err)
+            }
+        }
+    }
+
+    async fn try_push(&self) -> Result<(), Error> {
+        let encoder = TextEncoder::new();
+        let mut body = vec![];
+        encoder.encode(&self.metrics.gather(), &mut body)?;
+        self.client
+            .put(self.url.clone())
+            .header(CONTENT_TYPE, encoder.format_type())
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use prometheus::{IntCounter, Registry};
+    use tokio::sync::mpsc;
+
+    use super::Pusher;
+    use crate::config::Pushgateway;
+    use crate::proxy::Metrics;
+    use crate::test_utils::logger;
+
+    #[tokio::test]
+    async fn push_metrics() {
+        let (pushed_tx, mut pushed_rx) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let pushed_tx = pushed_tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let pushed_tx = pushed_tx.clone();
+                    async move {
+                        let method = request.method().clone();
+                        let path = request.uri().path().to_owned();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        pushed_tx
+                            .send((method, path, String::from_utf8(body.to_vec()).unwrap()))
+                            .unwrap();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let registry = Registry::default();
+        let counter = IntCounter::new("packets_total", "packets").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+
+        let config = Pushgateway {
+            url: format!("http://{}/", address),
+            job: "quilkin".into(),
+            interval: Duration::from_secs(15),
+        };
+        let metrics = Arc::new(Metrics::new(&logger(), registry));
+        let pusher = Pusher::new(&logger(), &config, "proxy/1", metrics).unwrap();
+        pusher.push().await;
+
+        let (method, path, body) = pushed_rx.recv().await.unwrap();
+        assert_eq!(hyper::Method::PUT, method);
+        assert_eq!("/metrics/job/quilkin/instance/proxy%2F1", path);
+        assert!(body.contains("packets_total 1"), "{}", body);
+    }
+}
@@ -0,0 +1,453 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use quinn::{Connecting, Connection, Endpoint as QuicEndpoint, NewConnection, ServerConfig};
+use slog::{debug, o, trace, Drain, Level, Logger};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::StreamExt;
+
+use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::cluster::Endpoint;
+use crate::config::{Quic, Upstream};
+use crate::filters::{manager::SharedFilterManager, Filter, ReadContext, WriteContext};
+use crate::proxy::sessions::bind_upstream_socket;
+
+use metrics::Metrics;
+
+pub(crate) mod metrics;
+
+/// The largest UDP payload we expect to receive from an upstream endpoint.
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// Contains the arguments needed to accept QUIC connections and proxy their datagrams.
+pub(crate) struct QuicProxyArgs {
+    pub log: Logger,
+    pub port: u16,
+    pub server_config: ServerConfig,
+    pub upstream: Upstream,
+    pub cluster_manager: SharedClusterManager,
+    pub filter_manager: SharedFilterManager,
+    pub metrics: Metrics,
+    pub shutdown_rx: watch::Receiver<()>,
+}
+
+/// Contains the state shared by every proxied QUIC connection.
+#[derive(Clone)]
+struct ConnectionContext {
+    log: Logger,
+    upstream: Upstream,
+    cluster_manager: SharedClusterManager,
+    filter_manager: SharedFilterManager,
+    metrics: Metrics,
+    shutdown_rx: watch::Receiver<()>,
+}
+
+/// Creates the TLS configuration used to terminate QUIC connections from
+/// the certificate chain and private key referenced by `config`.
+pub(crate) fn server_config(config: &Quic) -> Result<ServerConfig, String> {
+    let certificates = read_pem(&config.certificate, rustls_pemfile::certs)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect::<Vec<_>>();
+    if certificates.is_empty() {
+        return Err(format!(
+            "no certificates found in {}",
+            config.certificate.display()
+        ));
+    }
+
+    let private_key = read_pem(&config.private_key, rustls_pemfile::pkcs8_private_keys)?
+        .into_iter()
+        .chain(read_pem(
+            &config.private_key,
+            rustls_pemfile::rsa_private_keys,
+        )?)
+        .next()
+        .map(rustls::PrivateKey)
+        .ok_or_else(|| format!("no private key found in {}", config.private_key.display()))?;
+
+    ServerConfig::with_single_cert(certificates, private_key)
+        .map_err(|err| format!("invalid certificate or private key: {}", err))
+}
+
+fn read_pem(
+    path: &Path,
+    parse: fn(&mut dyn io::BufRead) -> io::Result<Vec<Vec<u8>>>,
+) -> Result<Vec<Vec<u8>>, String> {
+    File::open(path)
+        .and_then(|file| parse(&mut BufReader::new(file)))
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))
+}
+
+/// Binds a QUIC endpoint on all interfaces at the configured port and spawns
+/// a background task that proxies the DATAGRAM frames of each connection
+/// to upstream endpoints.
+///
+/// Each datagram received from a client is passed through the filter chain's
+/// `read` and sent to the resulting endpoints over UDP, from a socket
+/// dedicated to that connection. Packets an endpoint sends back to that
+/// socket are passed through the filter chain's `write` and returned to the
+/// client as a datagram.
+pub(crate) fn spawn_listener(args: QuicProxyArgs) -> io::Result<()> {
+    let QuicProxyArgs {
+        log,
+        port,
+        server_config,
+        upstream,
+        cluster_manager,
+        filter_manager,
+        metrics,
+        mut shutdown_rx,
+    } = args;
+    let log = log.new(o!("source" => "proxy::Quic"));
+    let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
+    let (endpoint, mut incoming) = QuicEndpoint::server(server_config, addr.into())?;
+    let ctx = ConnectionContext {
+        log: log.clone(),
+        upstream,
+        cluster_manager,
+        filter_manager,
+        metrics,
+        shutdown_rx: shutdown_rx.clone(),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                connecting = incoming.next() => {
+                    match connecting {
+                        Some(connecting) => {
+                            let ctx = ctx.clone();
+                            tokio::spawn(async move {
+                                handle_connection(connecting, ctx).await;
+                            });
+                        }
+                        None => return,
+                    }
+                }
+                _ = shutdown_rx.changed() => {
+                    debug!(log, "Exiting QUIC accept loop: received shutdown signal.");
+                    endpoint.close(0u32.into(), b"shutdown");
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// The UDP sockets used by a single QUIC connection to talk to upstream
+/// endpoints. Each endpoint gets its own connected socket so that packets
+/// sent back can be attributed to the endpoint they came from.
+struct UpstreamSockets {
+    log: Logger,
+    upstream: Upstream,
+    sockets: HashMap<SocketAddr, Arc<UdpSocket>>,
+    received_tx: mpsc::Sender<(Endpoint, Bytes)>,
+    // The receive task of every socket exits once this is dropped.
+    closed_tx: watch::Sender<()>,
+}
+
+impl UpstreamSockets {
+    fn new(log: Logger, upstream: Upstream, received_tx: mpsc::Sender<(Endpoint, Bytes)>) -> Self {
+        Self {
+            log,
+            upstream,
+            sockets: HashMap::new(),
+            received_tx,
+            closed_tx: watch::channel(()).0,
+        }
+    }
+
+    /// Returns the socket connected to `endpoint`, creating it and spawning
+    /// a task that forwards the packets it receives if it does not exist yet.
+    async fn get_or_create(&mut self, endpoint: &Endpoint) -> io::Result<Arc<UdpSocket>> {
+        if let Some(socket) = self.sockets.get(&endpoint.address) {
+            return Ok(socket.clone());
+        }
+
+        let socket = Arc::new(bind_upstream_socket(&self.upstream)?);
+        socket.connect(endpoint.address).await?;
+        self.sockets.insert(endpoint.address, socket.clone());
+
+        let log = self.log.clone();
+        let recv_socket = socket.clone();
+        let received_tx = self.received_tx.clone();
+        let mut closed_rx = self.closed_tx.subscribe();
+        let endpoint = endpoint.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                tokio::select! {
+                    received = recv_socket.recv(&mut buf) => {
+                        match received {
+                            Ok(size) => {
+                                if received_tx.send((endpoint.clone(), Bytes::copy_from_slice(&buf[..size]))).await.is_err() {
+                                    return;
+                                }
+                            }
+                            Err(err) => {
+                                debug!(log, "Error receiving packet from upstream";
+                                    "endpoint" => endpoint.address, "error" => %err);
+                            }
+                        }
+                    }
+                    _ = closed_rx.changed() => return,
+                }
+            }
+        });
+
+        Ok(socket)
+    }
+}
+
+/// Proxies the datagrams of a single QUIC connection until it is closed.
+async fn handle_connection(connecting: Connecting, ctx: ConnectionContext) {
+    let NewConnection {
+        connection,
+        mut datagrams,
+        ..
+    } = match connecting.await {
+        Ok(connection) => connection,
+        Err(err) => {
+            ctx.metrics.handshake_errors_total.inc();
+            debug!(ctx.log, "QUIC handshake failed"; "error" => %err);
+            return;
+        }
+    };
+
+    let from = connection.remote_address();
+    let log = ctx.log.new(o!("from" => from));
+    ctx.metrics.connections_total.inc();
+    ctx.metrics.active_connections.inc();
+
+    let (received_tx, mut received_rx) = mpsc::channel(1024);
+    let mut sockets = UpstreamSockets::new(log.clone(), ctx.upstream.clone(), received_tx);
+    let mut shutdown_rx = ctx.shutdown_rx.clone();
+    loop {
+        tokio::select! {
+            datagram = datagrams.next() => {
+                match datagram {
+                    Some(Ok(contents)) => {
+                        process_downstream_datagram(&ctx, from, &mut sockets, contents).await;
+                    }
+                    Some(Err(err)) => {
+                        debug!(log, "QUIC connection closed"; "reason" => %err);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            Some((endpoint, contents)) = received_rx.recv() => {
+                process_upstream_packet(&ctx, from, &connection, &endpoint, contents);
+            }
+            _ = shutdown_rx.changed() => {
+                connection.close(0u32.into(), b"shutdown");
+                break;
+            }
+        }
+    }
+
+    ctx.metrics.active_connections.dec();
+}
+
+async fn process_downstream_datagram(
+    ctx: &ConnectionContext,
+    from: SocketAddr,
+    sockets: &mut UpstreamSockets,
+    contents: Bytes,
+) {
+    if ctx.log.is_enabled(Level::Trace) {
+        trace!(ctx.log, "Received QUIC datagram"; "from" => from, "length" => contents.len());
+    }
+
+    let endpoints = match ctx.cluster_manager.read().get_all_endpoints() {
+        Some(endpoints) => endpoints,
+        None => {
+            ctx.metrics.datagrams_dropped_read.inc();
+            return;
+        }
+    };
+
+    let filter_chain = ctx.filter_manager.read().get_filter_chain();
+    let response = match filter_chain.read(ReadContext::new(endpoints, from, contents)) {
+        Some(response) => response,
+        None => {
+            ctx.metrics.datagrams_dropped_read.inc();
+            return;
+        }
+    };
+
+    for endpoint in response.endpoints.iter() {
+        let result = match sockets.get_or_create(endpoint).await {
+            Ok(socket) => socket.send(&response.contents).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(size) => ctx.metrics.tx_bytes_total.inc_by(size as u64),
+            Err(err) => {
+                debug!(ctx.log, "Error sending packet upstream";
+                    "endpoint" => endpoint.address, "error" => %err);
+            }
+        }
+    }
+}
+
+fn process_upstream_packet(
+    ctx: &ConnectionContext,
+    to: SocketAddr,
+    connection: &Connection,
+    endpoint: &Endpoint,
+    contents: Bytes,
+) {
+    ctx.metrics.rx_bytes_total.inc_by(contents.len() as u64);
+
+    let filter_chain = ctx.filter_manager.read().get_filter_chain();
+    match filter_chain.write(WriteContext::new(endpoint, endpoint.address, to, contents)) {
+        Some(response) => {
+            if let Err(err) = connection.send_datagram(response.contents) {
+                ctx.metrics.datagrams_dropped_write.inc();
+                debug!(ctx.log, "Error sending QUIC datagram"; "to" => to, "error" => %err);
+            }
+        }
+        None => ctx.metrics.datagrams_dropped_write.inc(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket as StdUdpSocket};
+    use std::path::Path;
+
+    use prometheus::Registry;
+    use tokio::sync::watch;
+    use tokio::time::{timeout, Duration};
+    use tokio_stream::StreamExt;
+
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, Quic, Upstream};
+    use crate::filters::manager::FilterManager;
+    use crate::test_utils::{logger, new_test_chain, TestHelper};
+
+    use super::{read_pem, server_config, spawn_listener, Metrics, QuicProxyArgs};
+
+    /// Returns the config of a self-signed certificate for `localhost`, valid
+    /// until 2126, and the certificate itself for clients to trust. It was
+    /// generated with:
+    ///
+    /// ```sh
+    /// openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out key.pem
+    /// openssl req -x509 -new -key key.pem -out cert.pem -days 36500 -subj /CN=localhost \
+    ///     -addext subjectAltName=DNS:localhost -addext basicConstraints=critical,CA:FALSE
+    /// ```
+    fn certificate() -> (Quic, rustls::Certificate) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/quic");
+        let config = Quic {
+            port: 0,
+            certificate: dir.join("cert.pem"),
+            private_key: dir.join("key.pem"),
+        };
+        let certificate = read_pem(&config.certificate, rustls_pemfile::certs)
+            .unwrap()
+            .remove(0);
+        (config, rustls::Certificate(certificate))
+    }
+
+    #[test]
+    fn server_config_missing_files() {
+        let config = Quic {
+            port: 0,
+            certificate: "/does/not/exist/cert.pem".into(),
+            private_key: "/does/not/exist/key.pem".into(),
+        };
+        assert!(server_config(&config)
+            .unwrap_err()
+            .contains("/does/not/exist/cert.pem"));
+    }
+
+    #[tokio::test]
+    async fn proxy_datagrams() {
+        let mut t = TestHelper::default();
+        let echo_addr = t.run_echo_server().await;
+        let registry = Registry::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+
+        let (config, certificate) = certificate();
+        // Find a free port for the proxy to listen on.
+        let port = StdUdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        spawn_listener(QuicProxyArgs {
+            log: logger(),
+            port,
+            server_config: server_config(&config).unwrap(),
+            upstream: Upstream::default(),
+            cluster_manager: ClusterManager::fixed(
+                &registry,
+                Endpoints::new(vec![Endpoint::from_address(echo_addr)]).unwrap(),
+            )
+            .unwrap(),
+            filter_manager: FilterManager::fixed(new_test_chain(&registry)),
+            metrics: Metrics::new(&registry).unwrap(),
+            shutdown_rx,
+        })
+        .unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&certificate).unwrap();
+        let mut client = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::with_root_certificates(roots));
+        let quinn::NewConnection {
+            connection,
+            mut datagrams,
+            ..
+        } = client
+            .connect(SocketAddr::from((Ipv4Addr::LOCALHOST, port)), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let client_addr =
+            SocketAddr::from((Ipv4Addr::LOCALHOST, client.local_addr().unwrap().port()));
+
+        connection.send_datagram("hello".into()).unwrap();
+        let response = timeout(Duration::from_secs(5), datagrams.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            format!(
+                "hello:odr:{}:our:{}:{}",
+                client_addr, echo_addr, client_addr
+            ),
+            String::from_utf8(response.to_vec()).unwrap()
+        );
+    }
+}
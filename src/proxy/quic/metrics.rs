@@ -0,0 +1,82 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::metrics::{opts, CollectorExt};
+use prometheus::core::{AtomicI64, AtomicU64, GenericCounter, GenericGauge};
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Registry, Result as MetricsResult};
+
+#[derive(Clone)]
+pub struct Metrics {
+    pub active_connections: GenericGauge<AtomicI64>,
+    pub connections_total: GenericCounter<AtomicU64>,
+    pub handshake_errors_total: GenericCounter<AtomicU64>,
+    pub rx_bytes_total: GenericCounter<AtomicU64>,
+    pub tx_bytes_total: GenericCounter<AtomicU64>,
+    pub datagrams_dropped_read: GenericCounter<AtomicU64>,
+    pub datagrams_dropped_write: GenericCounter<AtomicU64>,
+}
+
+impl Metrics {
+    pub fn new(registry: &Registry) -> MetricsResult<Self> {
+        let subsystem = "quic";
+        let datagrams_dropped_total = IntCounterVec::new(
+            opts(
+                "datagrams_dropped_total",
+                subsystem,
+                "Total number of datagrams that were dropped instead of being proxied",
+            ),
+            &["direction"],
+        )?
+        .register_if_not_exists(registry)?;
+
+        Ok(Self {
+            active_connections: IntGauge::with_opts(opts(
+                "active_connections",
+                subsystem,
+                "Number of QUIC connections currently being proxied",
+            ))?
+            .register_if_not_exists(registry)?,
+            connections_total: IntCounter::with_opts(opts(
+                "connections_total",
+                subsystem,
+                "Total number of proxied QUIC connections",
+            ))?
+            .register_if_not_exists(registry)?,
+            handshake_errors_total: IntCounter::with_opts(opts(
+                "handshake_errors_total",
+                subsystem,
+                "Total number of QUIC connections that failed to complete their handshake",
+            ))?
+            .register_if_not_exists(registry)?,
+            rx_bytes_total: IntCounter::with_opts(opts(
+                "rx_bytes_total",
+                subsystem,
+                "Total number of bytes received from upstream endpoints",
+            ))?
+            .register_if_not_exists(registry)?,
+            tx_bytes_total: IntCounter::with_opts(opts(
+                "tx_bytes_total",
+                subsystem,
+                "Total number of bytes sent to upstream endpoints",
+            ))?
+            .register_if_not_exists(registry)?,
+            datagrams_dropped_read: datagrams_dropped_total
+                .get_metric_with_label_values(&["read"])?,
+            datagrams_dropped_write: datagrams_dropped_total
+                .get_metric_with_label_values(&["write"])?,
+        })
+    }
+}
@@ -15,34 +15,75 @@
  */
 
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
 use std::sync::Arc;
 
-use slog::{debug, error, info, trace, warn, Logger};
-use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, watch};
+use bytes::Bytes;
+use prometheus::Registry;
+use slog::{debug, error, info, trace, warn, Drain, Level, Logger};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::runtime;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task::JoinHandle;
-use tokio::time::Duration;
+use tokio::time::{self, Duration, Instant};
 
 use metrics::Metrics as ProxyMetrics;
 use resource_manager::{DynamicResourceManagers, StaticResourceManagers};
 
-use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::cluster::cluster_manager::{ClusterManager, SharedClusterManager};
 use crate::cluster::Endpoint;
-use crate::filters::{manager::SharedFilterManager, Filter, FilterRegistry, ReadContext};
+use crate::config::{
+    Config, PortRange, RuntimeFlavor, SessionLimits, SessionPersistence, Upstream,
+    UpstreamEndpoints, LOG_SAMPLING_RATE,
+};
+use crate::filters::{
+    manager::{FilterManager, SharedFilterManager},
+    DropReason, Filter, FilterRegistry, ReadContext,
+};
+#[cfg(feature = "agones")]
+use crate::proxy::agones;
 use crate::proxy::builder::{ValidatedConfig, ValidatedSource};
-use crate::proxy::server::error::Error;
+use crate::proxy::cloud_metadata::{self, Instance};
+use crate::proxy::config_dump::ConfigDump;
+use crate::proxy::consul;
+use crate::proxy::debug_sampling::Sampler;
+use crate::proxy::gamelift;
+#[cfg(feature = "kubernetes")]
+use crate::proxy::kubernetes;
+use crate::proxy::pushgateway::Pusher;
+#[cfg(feature = "quic")]
+use crate::proxy::quic::{self, metrics::Metrics as QuicMetrics, QuicProxyArgs};
+pub use crate::proxy::server::error::Error;
+use crate::proxy::server::handle::StartedClusterManager;
+pub use crate::proxy::server::handle::{Handle, Stats};
 use crate::proxy::sessions::metrics::Metrics as SessionMetrics;
-use crate::proxy::sessions::session_manager::SessionManager;
-use crate::proxy::sessions::{Packet, Session, SESSION_TIMEOUT_SECONDS};
+use crate::proxy::sessions::multiplex::{self, Multiplexer};
+use crate::proxy::sessions::persistence::{self, RestoredRoutes, SessionEntry};
+use crate::proxy::sessions::session_manager::{SessionCounts, SessionLimit, SessionManager};
+use crate::proxy::sessions::{Packet, Session};
+use crate::proxy::tcp::{self, metrics::Metrics as TcpMetrics, TcpProxyArgs};
+use crate::proxy::tracing;
+use crate::proxy::webhook::Notifier;
 use crate::proxy::Admin;
-use crate::utils::debug;
+use crate::utils::clock;
+use crate::xds::ads_client::{self, ClientState};
 
 use super::metrics::Metrics;
 
-pub mod error;
+mod error;
+mod handle;
+#[cfg(unix)]
+mod hot_restart;
 pub(super) mod metrics;
+mod offload;
+mod queue;
+mod reload;
 mod resource_manager;
+#[cfg(feature = "sim")]
+pub mod sim;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -51,12 +92,20 @@ pub struct Server {
     // We use pub(super) to limit instantiation only to the Builder.
     pub(super) log: Logger,
     pub(super) config: Arc<ValidatedConfig>,
+    /// The file the config was loaded from, if any, along with its contents
+    /// before validation.
+    pub(super) config_file: Option<(PathBuf, Arc<Config>)>,
     // Admin may be turned off, primarily for testing.
     pub(super) admin: Option<Admin>,
     pub(super) metrics: Arc<Metrics>,
     pub(super) proxy_metrics: ProxyMetrics,
     pub(super) session_metrics: SessionMetrics,
+    pub(super) tcp_metrics: TcpMetrics,
+    #[cfg(feature = "quic")]
+    pub(super) quic_metrics: QuicMetrics,
     pub(super) filter_registry: FilterRegistry,
+    /// Set once the server has started, for the stats of its [`Handle`].
+    pub(super) cluster_manager: StartedClusterManager,
 }
 
 /// Represents arguments to the `Server::run_recv_from` method.
@@ -68,6 +117,12 @@ struct RunRecvFromArgs {
     session_ttl: Duration,
     send_packets: mpsc::Sender<Packet>,
     shutdown_rx: watch::Receiver<()>,
+    drain_rx: watch::Receiver<()>,
+    restored_routes: Option<RestoredRoutes>,
+    multiplexer: Option<Multiplexer>,
+    /// The runtimes that the workers run on with the `PER_CORE` runtime
+    /// flavor, shared by every listening port.
+    worker_runtimes: Option<Arc<[runtime::Handle]>>,
 }
 
 /// Represents the required arguments to run a worker task that
@@ -76,7 +131,7 @@ struct DownstreamReceiveWorkerConfig {
     /// ID of the worker.
     worker_id: usize,
     /// Channel from which the worker picks up the downstream packets.
-    packet_rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+    packet_rx: queue::PacketReceiver,
     /// Configuration required to process a received downstream packet.
     receive_config: ProcessDownstreamReceiveConfig,
     /// The worker task exits when a value is received from this shutdown channel.
@@ -93,54 +148,524 @@ struct ProcessDownstreamReceiveConfig {
     filter_manager: SharedFilterManager,
     session_manager: SessionManager,
     session_ttl: Duration,
+    session_limits: SessionLimits,
+    upstream: Upstream,
     send_packets: mpsc::Sender<Packet>,
+    restored_routes: Option<RestoredRoutes>,
+    /// Whether to accept packets of multiplexed sessions.
+    demultiplex: bool,
+    /// The socket shared by all sessions, if sessions are multiplexed.
+    multiplexer: Option<Multiplexer>,
+    /// Samples the packets of the proxy for logging.
+    sampler: Sampler,
 }
 
 impl Server {
+    /// Runs the server in the background until it is shut down through the
+    /// returned handle. Must be called from within a Tokio runtime.
+    pub fn spawn(self) -> Handle {
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let proxy_metrics = self.proxy_metrics.clone();
+        let session_metrics = self.session_metrics.clone();
+        let cluster_manager = self.cluster_manager.clone();
+        Handle::new(
+            shutdown_tx,
+            tokio::spawn(self.run(shutdown_rx)),
+            proxy_metrics,
+            session_metrics,
+            cluster_manager,
+        )
+    }
+
     /// start the async processing of incoming UDP packets. Will block until an
     /// event is sent through the stop Receiver.
-    pub async fn run(self, mut shutdown_rx: watch::Receiver<()>) -> Result<()> {
+    pub async fn run(mut self, mut shutdown_rx: watch::Receiver<()>) -> Result<()> {
         self.log_config();
 
+        let exporter = match &self.config.tracing {
+            Some(config) => {
+                let (tracer, exporter) =
+                    tracing::install(config, &self.config.proxy.id).map_err(|err| {
+                        Error::Initialize(format!("failed to start exporting traces: {}", err))
+                    })?;
+                self.session_metrics.tracer = tracer;
+                Some(exporter)
+            }
+            None => None,
+        };
+
+        let instance = match &self.config.proxy.cloud_metadata {
+            Some(config) => cloud_metadata::discover(&self.log, config)
+                .await
+                .map_err(|err| {
+                    Error::Initialize(format!("failed to read the instance metadata: {}", err))
+                })?,
+            None => None,
+        };
+        if let Some(instance) = &instance {
+            info!(self.log, "Running on cloud instance"; "provider" => instance.provider, "region" => &instance.region, "zone" => &instance.zone, "instance_id" => &instance.id);
+            self.metrics.set_instance_labels(instance.labels());
+        }
+
         if let Some(admin) = &self.admin {
             admin.run(shutdown_rx.clone());
         }
 
-        let socket = Arc::new(Server::bind(self.config.proxy.port).await?);
-        let session_manager = SessionManager::new(self.log.clone(), shutdown_rx.clone());
-        let (send_packets, receive_packets) = mpsc::channel::<Packet>(1024);
+        let pusher = match &self.config.pushgateway {
+            Some(config) => {
+                let pusher = Pusher::new(
+                    &self.log,
+                    config,
+                    &self.config.proxy.id,
+                    self.metrics.clone(),
+                )
+                .map_err(|err| {
+                    Error::Initialize(format!("failed to start pushing metrics: {}", err))
+                })?;
+                pusher.spawn(shutdown_rx.clone());
+                Some(pusher)
+            }
+            None => None,
+        };
 
-        let session_ttl = Duration::from_secs(SESSION_TIMEOUT_SECONDS);
+        if let Some(config) = &self.config.webhook {
+            self.session_metrics.webhook = Some(
+                Notifier::spawn(
+                    &self.log,
+                    config,
+                    &self.config.proxy.id,
+                    &self.metrics.registry,
+                    shutdown_rx.clone(),
+                )
+                .map_err(|err| {
+                    Error::Initialize(format!("failed to start posting session events: {}", err))
+                })?,
+            );
+        }
 
-        let (cluster_manager, filter_manager) =
-            self.create_resource_managers(shutdown_rx.clone()).await?;
-        self.run_receive_packet(socket.clone(), receive_packets);
-        let recv_loop = self.run_recv_from(RunRecvFromArgs {
-            cluster_manager,
-            filter_manager,
-            socket,
-            session_manager,
-            session_ttl,
-            send_packets,
-            shutdown_rx: shutdown_rx.clone(),
+        // Take over the listening sockets of a running proxy, if there is one.
+        #[cfg(unix)]
+        let mut inherited = match &self.config.proxy.hot_restart {
+            Some(config) => hot_restart::take_over(&config.socket)
+                .await
+                .map_err(|err| {
+                    Error::Initialize(format!("failed to take over sockets: {}", err))
+                })?,
+            None => None,
+        };
+        #[cfg(unix)]
+        if inherited.is_some() {
+            info!(self.log, "Took over listening sockets from running process");
+        }
+
+        let mut sockets = Vec::new();
+        for port in self.config.proxy.listening_ports() {
+            #[cfg(unix)]
+            let socket = match inherited.as_mut().and_then(|i| i.take_udp(port)) {
+                Some(socket) => UdpSocket::from_std(socket).map_err(Error::Bind)?,
+                None => Server::bind(port).await?,
+            };
+            #[cfg(not(unix))]
+            let socket = Server::bind(port).await?;
+            if self.config.proxy.segmentation_offload {
+                offload::enable_gro(&socket).map_err(|err| {
+                    Error::Initialize(format!("failed to enable UDP receive offload: {}", err))
+                })?;
+            }
+            sockets.push(Arc::new(socket));
+        }
+
+        let session_ttl = self.config.proxy.session_timeout;
+
+        // Signals every listener to stop accepting new traffic once another
+        // process has taken over.
+        let (drain_tx, drain_rx) = watch::channel(());
+        #[cfg(unix)]
+        let mut handover_fds: Vec<_> = sockets.iter().map(|socket| socket.as_raw_fd()).collect();
+
+        let (cluster_manager, filter_manager, xds_state) = self
+            .create_resource_managers(instance.as_ref(), shutdown_rx.clone())
+            .await?;
+        *self.cluster_manager.write() = Some(cluster_manager.clone());
+        if let (Some((path, config)), ValidatedSource::Static { .. }) =
+            (&self.config_file, &self.config.source)
+        {
+            let result = reload::spawn(reload::ReloadArgs {
+                log: self.log.clone(),
+                path: path.clone(),
+                config: config.clone(),
+                filter_registry: self.filter_registry.clone(),
+                metrics: self.metrics.clone(),
+                cluster_manager: cluster_manager.clone(),
+                filter_manager: filter_manager.clone(),
+                shutdown_rx: shutdown_rx.clone(),
+            });
+            if let Err(err) = result {
+                warn!(self.log, "Failed to watch the configuration file, changes will require a restart"; "path" => %path.display(), "error" => %err);
+            }
+        }
+        #[cfg(feature = "agones")]
+        if let Some(config) = &self.config.agones {
+            agones::spawn(
+                &self.log,
+                config,
+                cluster_manager.clone(),
+                self.metrics.events.clone(),
+                shutdown_rx.clone(),
+            )
+            .map_err(|err| {
+                Error::Initialize(format!(
+                    "failed to connect to the Agones SDK server: {}",
+                    err
+                ))
+            })?;
+        }
+        if let Some(tcp) = &self.config.proxy.tcp {
+            #[cfg(unix)]
+            let listener = match inherited.as_mut().and_then(|i| i.take_tcp(tcp.port)) {
+                Some(listener) => TcpListener::from_std(listener).map_err(Error::Bind)?,
+                None => Server::bind_tcp(tcp.port).await?,
+            };
+            #[cfg(not(unix))]
+            let listener = Server::bind_tcp(tcp.port).await?;
+            #[cfg(unix)]
+            handover_fds.push(listener.as_raw_fd());
+
+            tcp::spawn_listener(TcpProxyArgs {
+                log: self.log.clone(),
+                listener,
+                framing: tcp.framing,
+                buffer_size: tcp.buffer_size,
+                idle_timeout: tcp.idle_timeout,
+                max_connections: tcp.max_connections,
+                upstream: self.config.proxy.upstream.clone(),
+                cluster_manager: cluster_manager.clone(),
+                filter_manager: filter_manager.clone(),
+                metrics: self.tcp_metrics.clone(),
+                shutdown_rx: shutdown_rx.clone(),
+                drain_rx: drain_rx.clone(),
+            });
+        }
+        #[cfg(feature = "quic")]
+        if let Some(config) = &self.config.proxy.quic {
+            quic::spawn_listener(QuicProxyArgs {
+                log: self.log.clone(),
+                port: config.port,
+                server_config: quic::server_config(config).map_err(Error::Initialize)?,
+                upstream: self.config.proxy.upstream.clone(),
+                cluster_manager: cluster_manager.clone(),
+                filter_manager: filter_manager.clone(),
+                metrics: self.quic_metrics.clone(),
+                shutdown_rx: shutdown_rx.clone(),
+            })
+            .map_err(Error::Bind)?;
+        }
+
+        let persisted_sessions = self.config.proxy.session_persistence.as_ref().map(|config| {
+            persistence::load(&config.path).unwrap_or_else(|err| {
+                warn!(self.log, "Failed to load session snapshot"; "path" => %config.path.display(), "error" => %err);
+                vec![]
+            })
         });
 
-        tokio::select! {
-            join_result = recv_loop => {
+        // With the `PER_CORE` runtime flavor the workers of every listening
+        // port share the same threads, rather than each port starting its own.
+        let worker_runtimes = match self.config.proxy.runtime.flavor {
+            RuntimeFlavor::MultiThread => None,
+            RuntimeFlavor::PerCore => Some(Self::spawn_worker_runtimes(
+                self.config.proxy.num_workers(),
+                &shutdown_rx,
+            )?),
+        };
+
+        // Each listening port gets its own sessions so that packets from
+        // upstream endpoints are sent back from the port the client sent to.
+        let (recv_loop_tx, mut recv_loop_rx) = mpsc::channel(1);
+        let mut session_managers = Vec::new();
+        // Session limits apply to the sessions of every port together.
+        let session_counts = Arc::new(SessionCounts::default());
+        let port_managers =
+            self.create_port_resource_managers(&cluster_manager, &filter_manager)?;
+        for socket in sockets {
+            let port = socket.local_addr().map_err(Error::Bind)?.port();
+            let (cluster_manager, filter_manager) = port_managers
+                .iter()
+                .find(|(ports, _, _)| ports.ports().contains(&port))
+                .map(|(_, cluster_manager, filter_manager)| {
+                    (cluster_manager.clone(), filter_manager.clone())
+                })
+                .unwrap_or_else(|| (cluster_manager.clone(), filter_manager.clone()));
+            let session_manager = SessionManager::new(
+                self.log.clone(),
+                session_counts.clone(),
+                shutdown_rx.clone(),
+            );
+            session_managers.push((port, session_manager.clone()));
+            let (send_packets, receive_packets) = mpsc::channel::<Packet>(1024);
+
+            self.run_receive_packet(socket.clone(), receive_packets);
+            let multiplexer = if self.config.proxy.upstream.multiplex {
+                let multiplexer = Multiplexer::bind(
+                    &self.log,
+                    self.session_metrics.clone(),
+                    &self.config.proxy.upstream,
+                    shutdown_rx.clone(),
+                )
+                .map_err(Error::Bind)?;
+                Some(multiplexer)
+            } else {
+                None
+            };
+            let mut args = RunRecvFromArgs {
+                cluster_manager: cluster_manager.clone(),
+                filter_manager: filter_manager.clone(),
+                socket,
+                session_manager,
+                session_ttl,
+                send_packets,
+                shutdown_rx: shutdown_rx.clone(),
+                drain_rx: drain_rx.clone(),
+                restored_routes: None,
+                multiplexer,
+                worker_runtimes: worker_runtimes.clone(),
+            };
+            if let Some(entries) = &persisted_sessions {
+                let entries = entries.iter().filter(|entry| entry.port == port);
+                args.restored_routes = Some(self.restore_sessions(entries, &args).await);
+            }
+            let recv_loop = self.run_recv_from(args);
+
+            let recv_loop_tx = recv_loop_tx.clone();
+            tokio::spawn(async move {
+                recv_loop_tx.send(recv_loop.await).await.ok();
+            });
+        }
+
+        // Resolves once a newly started process has taken over our sockets.
+        let (handed_over_tx, handed_over_rx) = oneshot::channel::<()>();
+        #[cfg(unix)]
+        if let Some(config) = &self.config.proxy.hot_restart {
+            let listener = hot_restart::listen(&config.socket).map_err(|err| {
+                Error::Initialize(format!("failed to listen for hot restarts: {}", err))
+            })?;
+            if let Some(inherited) = inherited {
+                inherited.complete().await.map_err(|err| {
+                    Error::Initialize(format!("failed to complete taking over sockets: {}", err))
+                })?;
+            }
+
+            let log = self.log.clone();
+            tokio::spawn(async move {
+                match hot_restart::wait_for_take_over(&log, listener, handover_fds).await {
+                    Ok(()) => {
+                        handed_over_tx.send(()).ok();
+                    }
+                    Err(err) => error!(log, "Stopped listening for hot restarts"; "error" => %err),
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        drop(handed_over_tx);
+
+        if let Some(config) = &self.config.proxy.session_persistence {
+            Self::spawn_session_snapshots(
+                self.log.clone(),
+                config.clone(),
+                session_managers.clone(),
+                drain_rx,
+                shutdown_rx.clone(),
+            );
+        }
+
+        if let Some(admin) = &self.admin {
+            admin.set_started(
+                ConfigDump {
+                    proxy: self.config.proxy.clone(),
+                    cluster_manager: cluster_manager.clone(),
+                    filter_manager: filter_manager.clone(),
+                    xds: xds_state,
+                },
+                session_managers.clone(),
+            );
+        }
+
+        let result = tokio::select! {
+            Some(join_result) = recv_loop_rx.recv() => {
                 join_result
                     .map_err(|join_err| Error::RecvLoop(format!("{}", join_err)))
                     .and_then(|inner| inner.map_err(Error::RecvLoop))
             }
+            Ok(()) = handed_over_rx => {
+                info!(self.log, "Listening sockets were taken over, draining");
+                drain_tx.send(()).ok();
+                self.drain(&session_managers, shutdown_rx).await;
+                Ok(())
+            }
             _ = shutdown_rx.changed() => {
+                if let Some(config) = &self.config.proxy.session_persistence {
+                    Self::save_sessions(&self.log, &config.path, &session_managers).await;
+                }
                 Ok(())
             }
+        };
+
+        if let Some(pusher) = pusher {
+            pusher.push().await;
+        }
+        if let Some(exporter) = exporter {
+            exporter.shutdown().await;
+        }
+        result
+    }
+
+    /// Recreates the sessions from a snapshot that have not expired yet and
+    /// whose endpoint is still part of the cluster.
+    async fn restore_sessions(
+        &self,
+        entries: impl Iterator<Item = &SessionEntry>,
+        args: &RunRecvFromArgs,
+    ) -> RestoredRoutes {
+        let routes = RestoredRoutes::default();
+        let endpoints = match args.cluster_manager.read().get_all_endpoints() {
+            Some(endpoints) => endpoints,
+            None => return routes,
+        };
+        let now = clock::unix_now()
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+
+        let mut sessions = args.session_manager.get_sessions_mut().await;
+        for entry in entries.filter(|entry| entry.expiration > now) {
+            let endpoint = match endpoints.iter().find(|ep| ep.address == entry.endpoint) {
+                Some(endpoint) => endpoint.clone(),
+                None => continue,
+            };
+            match Session::new(
+                &self.log,
+                self.session_metrics.clone(),
+                args.filter_manager.clone(),
+                entry.downstream,
+                None,
+                endpoint,
+                args.send_packets.clone(),
+                args.session_ttl,
+                &self.config.proxy.upstream,
+                args.multiplexer.as_ref(),
+                Sampler::new(self.config.proxy.debug_sampling),
+            )
+            .await
+            {
+                Ok(session) => {
+                    session.set_expiration(entry.expiration);
+                    routes.insert(entry.downstream, entry.endpoint);
+                    sessions.insert(session.key(), session);
+                }
+                Err(err) => {
+                    warn!(self.log, "Failed to restore session"; "from" => entry.downstream, "endpoint" => entry.endpoint, "error" => %err)
+                }
+            }
+        }
+        info!(self.log, "Restored sessions"; "count" => sessions.len());
+        routes
+    }
+
+    /// Spawns a background task that saves the session table every
+    /// `config.interval`, until the proxy starts draining or shuts down.
+    fn spawn_session_snapshots(
+        log: Logger,
+        config: SessionPersistence,
+        session_managers: Vec<(u16, SessionManager)>,
+        mut drain_rx: watch::Receiver<()>,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) {
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = time::sleep(config.interval) => {
+                        Self::save_sessions(&log, &config.path, &session_managers).await;
+                    }
+                    Ok(()) = drain_rx.changed() => return,
+                    _ = shutdown_rx.changed() => return,
+                }
+            }
+        });
+    }
+
+    async fn save_sessions(log: &Logger, path: &Path, session_managers: &[(u16, SessionManager)]) {
+        let mut sessions = vec![];
+        for (port, session_manager) in session_managers {
+            sessions.extend(persistence::snapshot(*port, session_manager).await);
+        }
+        let path = path.to_owned();
+        match tokio::task::spawn_blocking(move || persistence::save(&path, sessions)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!(log, "Failed to save session snapshot"; "error" => %err),
+            Err(err) => warn!(log, "Failed to save session snapshot"; "error" => %err),
+        }
+    }
+
+    /// Waits for existing sessions and TCP connections to finish, up to the
+    /// configured drain timeout or until shutdown.
+    async fn drain(
+        &self,
+        session_managers: &[(u16, SessionManager)],
+        mut shutdown_rx: watch::Receiver<()>,
+    ) {
+        let drain_timeout = match &self.config.proxy.hot_restart {
+            Some(config) => config.drain_timeout,
+            None => return,
+        };
+        let deadline = Instant::now() + drain_timeout;
+        loop {
+            let mut active = self.tcp_metrics.active_connections.get() as usize;
+            for (_, session_manager) in session_managers {
+                active += session_manager.get_sessions().await.len();
+            }
+            if active == 0 || Instant::now() >= deadline {
+                return;
+            }
+            debug!(self.log, "Draining"; "active" => active);
+
+            tokio::select! {
+                _ = time::sleep(Duration::from_secs(1)) => {}
+                _ = shutdown_rx.changed() => return,
+            }
         }
     }
 
+    /// Returns the cluster and filter managers of the ports that override
+    /// the endpoints or filter chain of the source, which are fixed.
+    fn create_port_resource_managers(
+        &self,
+        cluster_manager: &SharedClusterManager,
+        filter_manager: &SharedFilterManager,
+    ) -> Result<Vec<(PortRange, SharedClusterManager, SharedFilterManager)>> {
+        let mut managers = Vec::new();
+        for port_override in &self.config.port_overrides {
+            let cluster_manager = match &port_override.endpoints {
+                // The cluster metrics only cover the endpoints of the source.
+                Some(endpoints) => ClusterManager::fixed(&Registry::default(), endpoints.clone())
+                    .map_err(|err| Error::Initialize(format!("{}", err)))?,
+                None => cluster_manager.clone(),
+            };
+            let filter_manager = match &port_override.filter_chain {
+                Some(filter_chain) => FilterManager::fixed(filter_chain.clone()),
+                None => filter_manager.clone(),
+            };
+            managers.push((port_override.ports, cluster_manager, filter_manager));
+        }
+        Ok(managers)
+    }
+
     async fn create_resource_managers(
         &self,
+        instance: Option<&Instance>,
         shutdown_rx: watch::Receiver<()>,
-    ) -> Result<(SharedClusterManager, SharedFilterManager)> {
+    ) -> Result<(
+        SharedClusterManager,
+        SharedFilterManager,
+        Option<ClientState>,
+    )> {
         match &self.config.source {
             ValidatedSource::Static {
                 filter_chain,
@@ -152,13 +677,15 @@ impl Server {
                     filter_chain.clone(),
                 )
                 .map_err(|err| Error::Initialize(format!("{}", err)))?;
-                Ok((manager.cluster_manager, manager.filter_manager))
+                Ok((manager.cluster_manager, manager.filter_manager, None))
             }
             ValidatedSource::Dynamic { management_servers } => {
                 let manager = DynamicResourceManagers::new(
                     self.log.clone(),
-                    self.config.proxy.id.clone(),
+                    ads_client::node(self.config.proxy.id.clone(), instance),
                     self.metrics.registry.clone(),
+                    self.metrics.events.clone(),
+                    self.session_metrics.tracer.clone(),
                     self.filter_registry.clone(),
                     management_servers.to_vec(),
                     shutdown_rx,
@@ -181,7 +708,89 @@ impl Server {
                     }
                 });
 
-                Ok((manager.cluster_manager, manager.filter_manager))
+                Ok((
+                    manager.cluster_manager,
+                    manager.filter_manager,
+                    Some(manager.xds_state),
+                ))
+            }
+            #[cfg(feature = "kubernetes")]
+            ValidatedSource::Kubernetes {
+                filter_chain,
+                discovery,
+            } => {
+                let cluster_manager = kubernetes::cluster_manager(
+                    &self.log,
+                    discovery,
+                    &self.metrics.registry,
+                    &self.metrics.events,
+                    &self.session_metrics.tracer,
+                    shutdown_rx,
+                )
+                .await
+                .map_err(|err| {
+                    Error::Initialize(format!(
+                        "failed to discover the endpoints of the Service: {}",
+                        err
+                    ))
+                })?;
+                Ok((
+                    cluster_manager,
+                    FilterManager::fixed(filter_chain.clone()),
+                    None,
+                ))
+            }
+            ValidatedSource::GameLift {
+                filter_chain,
+                discovery,
+                region,
+            } => {
+                let cluster_manager = gamelift::cluster_manager(
+                    &self.log,
+                    discovery,
+                    region,
+                    &self.metrics.registry,
+                    &self.metrics.events,
+                    &self.session_metrics.tracer,
+                    shutdown_rx,
+                )
+                .await
+                .map_err(|err| {
+                    Error::Initialize(format!(
+                        "failed to discover the game servers of the game server group: {}",
+                        err
+                    ))
+                })?;
+                Ok((
+                    cluster_manager,
+                    FilterManager::fixed(filter_chain.clone()),
+                    None,
+                ))
+            }
+            ValidatedSource::Consul {
+                filter_chain,
+                discovery,
+            } => {
+                let cluster_manager = consul::cluster_manager(
+                    &self.log,
+                    discovery,
+                    &self.metrics.registry,
+                    &self.metrics.events,
+                    &self.session_metrics.tracer,
+                    shutdown_rx,
+                )
+                .await
+                .map_err(|err| {
+                    Error::Initialize(format!(
+                        "failed to discover the instances of the Consul service: {}",
+                        err
+                    ))
+                })?;
+                Ok((
+                    cluster_manager,
+                    FilterManager::fixed(filter_chain.clone()),
+                    None,
+                ))
             }
         }
     }
@@ -199,14 +808,20 @@ impl Server {
 
         // The number of worker tasks to spawn. Each task gets a dedicated queue to
         // consume packets off.
-        let num_workers = num_cpus::get();
+        let num_workers = self.config.proxy.num_workers();
+        let overload = &self.config.proxy.overload;
+        let queue_size = overload.queue_size.unwrap_or(num_workers);
 
         // Contains channel Senders for each worker task.
         let mut packet_txs = vec![];
         // Contains config for each worker task.
         let mut worker_configs = vec![];
         for worker_id in 0..num_workers {
-            let (packet_tx, packet_rx) = mpsc::channel(num_workers);
+            let (packet_tx, packet_rx) = queue::channel(
+                queue_size,
+                overload.policy,
+                proxy_metrics.packets_dropped_overloaded.clone(),
+            );
             packet_txs.push(packet_tx);
             worker_configs.push(DownstreamReceiveWorkerConfig {
                 worker_id,
@@ -220,18 +835,33 @@ impl Server {
                     filter_manager: args.filter_manager.clone(),
                     session_manager: session_manager.clone(),
                     session_ttl: args.session_ttl,
+                    session_limits: self.config.proxy.session_limits.clone(),
+                    upstream: self.config.proxy.upstream.clone(),
                     send_packets: args.send_packets.clone(),
+                    restored_routes: args.restored_routes.clone(),
+                    demultiplex: self.config.proxy.demultiplex,
+                    multiplexer: args.multiplexer.clone(),
+                    sampler: Sampler::new(self.config.proxy.debug_sampling),
                 },
             })
         }
 
         // Start the worker tasks that pick up received packets from their queue
         // and processes them.
-        Self::spawn_downstream_receive_workers(log.clone(), worker_configs);
+        Self::spawn_downstream_receive_workers(
+            log.clone(),
+            worker_configs,
+            args.worker_runtimes.as_deref(),
+        );
 
         // Start the background task to receive downstream packets from the socket
         // and place them onto the worker tasks' queue for processing.
         let socket = args.socket;
+        let local_addr = local_addr(&socket);
+        let top_talkers = self.metrics.top_talkers.shard();
+        let capture = self.metrics.capture.clone();
+        let segmentation_offload = self.config.proxy.segmentation_offload;
+        let mut drain_rx = args.drain_rx;
         tokio::spawn(async move {
             // Index to round-robin over workers to process packets.
             let mut next_worker = 0;
@@ -241,22 +871,36 @@ impl Server {
             // packet, which is the maximum value of 16 a bit integer.
             let mut buf = [0; 1 << 16];
             loop {
-                match socket.recv_from(&mut buf).await {
-                    Ok((size, recv_addr)) => {
-                        let packet_tx = &mut packet_txs[next_worker % num_workers];
-                        next_worker += 1;
-
-                        if packet_tx
-                            .send((recv_addr, (&buf[..size]).to_vec()))
-                            .await
-                            .is_err()
-                        {
-                            // We cannot recover from this error since
-                            // it implies that the receiver has been dropped.
-                            let reason =
-                                "Failed to send received packet over channel to worker".into();
-                            error!(log, "{}", reason);
-                            return Err(reason);
+                let received = tokio::select! {
+                    received = offload::recv_from(&socket, &mut buf, segmentation_offload) => received,
+                    Ok(()) = drain_rx.changed() => {
+                        debug!(log, "Exiting receive loop: draining sessions.");
+                        return Ok(());
+                    }
+                };
+                match received {
+                    Ok((size, recv_addr, segment_size)) => {
+                        let received_at = Instant::now();
+                        // With receive offload enabled, a single read may
+                        // contain several packets from the same client.
+                        for contents in offload::segments(&buf[..size], segment_size) {
+                            capture.record(recv_addr, local_addr, recv_addr, None, contents);
+                            top_talkers.record(recv_addr, contents.len());
+                            let packet_tx = &packet_txs[next_worker % num_workers];
+                            next_worker += 1;
+
+                            if packet_tx
+                                .send((recv_addr, Bytes::copy_from_slice(contents), received_at))
+                                .await
+                                .is_err()
+                            {
+                                // We cannot recover from this error since
+                                // it implies that the receiver has been dropped.
+                                let reason =
+                                    "Failed to send received packet over channel to worker".into();
+                                error!(log, "{}", reason);
+                                return Err(reason);
+                            }
                         }
                     }
                     err => {
@@ -269,65 +913,135 @@ impl Server {
         })
     }
 
+    /// Starts a dedicated thread running a single-threaded runtime for each
+    /// of `num_workers` workers, returning handles to spawn tasks on them.
+    fn spawn_worker_runtimes(
+        num_workers: usize,
+        shutdown_rx: &watch::Receiver<()>,
+    ) -> Result<Arc<[runtime::Handle]>> {
+        let mut handles = Vec::with_capacity(num_workers);
+        for worker_id in 0..num_workers {
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|err| {
+                    Error::Initialize(format!(
+                        "failed to create the runtime of worker-{}: {}",
+                        worker_id, err
+                    ))
+                })?;
+            handles.push(runtime.handle().clone());
+            let mut shutdown_rx = shutdown_rx.clone();
+            // Tasks only run while the thread blocks on the runtime, and the
+            // sessions created by workers run on it too, so keep it around
+            // until shutdown even if the workers stop receiving packets while
+            // draining.
+            std::thread::Builder::new()
+                .name(format!("quilkin-worker-{}", worker_id))
+                .spawn(move || {
+                    runtime.block_on(async move {
+                        shutdown_rx.changed().await.ok();
+                    });
+                })
+                .map_err(|err| {
+                    Error::Initialize(format!(
+                        "failed to spawn the thread of worker-{}: {}",
+                        worker_id, err
+                    ))
+                })?;
+        }
+        Ok(handles.into())
+    }
+
     // For each worker config provided, spawn a background task that sits in a
     // loop, receiving packets from a queue and processing them through
-    // the filter chain.
+    // the filter chain. With the `PER_CORE` runtime flavor each task runs on
+    // the worker runtime of the same ID.
     fn spawn_downstream_receive_workers(
         log: Logger,
         worker_configs: Vec<DownstreamReceiveWorkerConfig>,
+        worker_runtimes: Option<&[runtime::Handle]>,
     ) {
-        for DownstreamReceiveWorkerConfig {
+        for config in worker_configs {
+            let worker_id = config.worker_id;
+            let worker = Self::run_downstream_receive_worker(log.clone(), config);
+            match worker_runtimes {
+                Some(runtimes) => {
+                    runtimes[worker_id % runtimes.len()].spawn(worker);
+                }
+                None => {
+                    tokio::spawn(worker);
+                }
+            }
+        }
+    }
+
+    async fn run_downstream_receive_worker(log: Logger, config: DownstreamReceiveWorkerConfig) {
+        let DownstreamReceiveWorkerConfig {
             worker_id,
             mut packet_rx,
             mut shutdown_rx,
             receive_config,
-        } in worker_configs
-        {
-            let log = log.clone();
-
-            tokio::spawn(async move {
-                loop {
-                    tokio::select! {
-                      packet = packet_rx.recv() => {
-                        match packet {
-                          Some((recv_addr, packet)) => Self::process_downstream_received_packet((recv_addr, packet), &receive_config).await,
-                          None => {
-                            debug!(log, "Worker-{} exiting: work sender channel was closed.", worker_id);
-                            return;
-                          }
-                        }
-                      }
-                      _ = shutdown_rx.changed() => {
-                        debug!(log, "Worker-{} exiting: received shutdown signal.", worker_id);
-                        return;
-                      }
-                    }
+        } = config;
+        loop {
+            tokio::select! {
+              packet = packet_rx.recv() => {
+                match packet {
+                  Some(packet) => Self::process_downstream_received_packet(packet, &receive_config).await,
+                  None => {
+                    debug!(log, "Worker-{} exiting: work sender channel was closed.", worker_id);
+                    return;
+                  }
                 }
-            });
+              }
+              _ = shutdown_rx.changed() => {
+                debug!(log, "Worker-{} exiting: received shutdown signal.", worker_id);
+                return;
+              }
+            }
         }
     }
 
     /// Processes a packet by running it through the filter chain.
     async fn process_downstream_received_packet(
-        packet: (SocketAddr, Vec<u8>),
+        packet: (SocketAddr, Bytes, Instant),
         args: &ProcessDownstreamReceiveConfig,
     ) {
-        let (recv_addr, packet) = packet;
-
-        trace!(
-            args.log,
-            "Packet Received";
-            "from" => recv_addr,
-            "contents" => debug::bytes_to_string(&packet),
-        );
+        let (recv_addr, packet, received_at) = packet;
+
+        if args.log.is_enabled(Level::Trace) {
+            trace!(
+                args.log,
+                "Packet Received";
+                "from" => recv_addr,
+                "length" => packet.len(),
+            );
+        }
+        let span = args
+            .session_metrics
+            .tracer
+            .packet_span("packet.read", recv_addr, packet.len());
+        let sample = args.sampler.start("read", recv_addr, packet.len());
 
-        let endpoints = match args.cluster_manager.read().get_all_endpoints() {
+        let mut endpoints = match args.cluster_manager.read().get_all_endpoints() {
             Some(endpoints) => endpoints,
             None => {
                 args.proxy_metrics.packets_dropped_no_endpoints.inc();
+                span.dropped("no endpoints");
+                sample.dropped(&args.log, "no_endpoints");
                 return;
             }
         };
+        if let Some(restored_routes) = &args.restored_routes {
+            Self::apply_restored_routes(restored_routes, recv_addr, &mut endpoints, args).await;
+        }
+
+        // Packets of multiplexed sessions only go through the filter chain
+        // once their header is removed.
+        let (mux_id, packet) = match multiplex::decode(&packet) {
+            Some((id, contents)) if args.demultiplex => (Some(id), contents),
+            _ => (None, packet),
+        };
 
         let filter_chain = {
             let filter_manager_guard = args.filter_manager.read();
@@ -335,27 +1049,75 @@ impl Server {
         };
         let result = filter_chain.read(ReadContext::new(endpoints, recv_addr, packet));
 
-        if let Some(response) = result {
-            for endpoint in response.endpoints.iter() {
-                Self::session_send_packet(
-                    &response.contents.as_slice(),
-                    recv_addr,
-                    endpoint,
-                    &args,
-                )
-                .await;
+        match result {
+            Some(response) => {
+                span.sent_to(response.endpoints.iter().map(|endpoint| &endpoint.address));
+                sample.sent_to(
+                    &args.log,
+                    response.endpoints.iter().map(|endpoint| &endpoint.address),
+                );
+                for endpoint in response.endpoints.iter() {
+                    Self::session_send_packet(
+                        &response.contents,
+                        recv_addr,
+                        mux_id,
+                        endpoint,
+                        &args,
+                    )
+                    .await;
+                }
+                args.proxy_metrics
+                    .read_processing_duration
+                    .observe(received_at.elapsed().as_secs_f64());
+            }
+            None => {
+                let reason = DropReason::take().unwrap_or(DropReason::UNSPECIFIED);
+                span.dropped("filter");
+                sample.dropped(&args.log, reason.as_str());
             }
         }
     }
 
-    /// Send a packet received from `recv_addr` to an endpoint.
+    /// Restricts `endpoints` to the ones `recv_addr` was sending to before a
+    /// restart, for as long as any of its restored sessions are alive.
+    async fn apply_restored_routes(
+        restored_routes: &RestoredRoutes,
+        recv_addr: SocketAddr,
+        endpoints: &mut UpstreamEndpoints,
+        args: &ProcessDownstreamReceiveConfig,
+    ) {
+        let restored = match restored_routes.get(&recv_addr) {
+            Some(restored) => restored,
+            None => return,
+        };
+        let alive = {
+            let sessions = args.session_manager.get_sessions().await;
+            restored
+                .into_iter()
+                .filter(|endpoint| sessions.contains_key(&(recv_addr, None, *endpoint)))
+                .collect::<Vec<_>>()
+        };
+        if alive.is_empty()
+            || endpoints
+                .retain(|endpoint| alive.contains(&endpoint.address))
+                .is_none()
+        {
+            // Either the restored sessions have expired or their endpoints
+            // are no longer part of the cluster, so route as usual.
+            restored_routes.remove(&recv_addr);
+        }
+    }
+
+    /// Send a packet received from `recv_addr`, as part of multiplexed
+    /// session `mux_id` if set, to an endpoint.
     async fn session_send_packet(
         packet: &[u8],
         recv_addr: SocketAddr,
+        mux_id: Option<u32>,
         endpoint: &Endpoint,
         args: &ProcessDownstreamReceiveConfig,
     ) {
-        let session_key = (recv_addr, endpoint.address);
+        let session_key = (recv_addr, mux_id, endpoint.address);
 
         // Grab a read lock and find the session.
         let guard = args.session_manager.get_sessions().await;
@@ -381,16 +1143,22 @@ impl Server {
                 // simply send the packet.
                 Self::session_send_packet_helper(&args.log, session, packet, args.session_ttl)
                     .await;
+            } else if let Some(limit) = guard.limit_reached(recv_addr, &args.session_limits) {
+                Self::drop_session_limit_reached(limit, recv_addr, args);
             } else {
                 // Otherwise, create the session and insert into the map.
                 match Session::new(
                     &args.log,
                     args.session_metrics.clone(),
                     args.filter_manager.clone(),
-                    session_key.0,
+                    recv_addr,
+                    mux_id,
                     endpoint.clone(),
                     args.send_packets.clone(),
                     args.session_ttl,
+                    &args.upstream,
+                    args.multiplexer.as_ref(),
+                    args.sampler,
                 )
                 .await
                 {
@@ -398,7 +1166,14 @@ impl Server {
                         // Insert the session into the map and release the write lock
                         // immediately since we don't want to block other threads while we send
                         // the packet. Instead, re-acquire a read lock and send the packet.
-                        guard.insert(session.key(), session);
+                        // Sessions on other ports count towards the same limits, and may
+                        // have been created in the meantime.
+                        if let Err(limit) =
+                            guard.insert_within(session.key(), session, &args.session_limits)
+                        {
+                            Self::drop_session_limit_reached(limit, recv_addr, args);
+                            return;
+                        }
 
                         // Release the write lock.
                         drop(guard);
@@ -417,7 +1192,7 @@ impl Server {
                             warn!(
                                 args.log,
                                 "Could not find session";
-                                "key" => format!("({}:{})", session_key.0.to_string(), session_key.1.to_string())
+                                "key" => format!("({}:{})", recv_addr, endpoint.address)
                             )
                         }
                     }
@@ -429,6 +1204,24 @@ impl Server {
         }
     }
 
+    /// Counts a packet from `recv_addr` as dropped because creating a
+    /// session for it would exceed `limit`.
+    fn drop_session_limit_reached(
+        limit: SessionLimit,
+        recv_addr: SocketAddr,
+        args: &ProcessDownstreamReceiveConfig,
+    ) {
+        let dropped = match limit {
+            SessionLimit::Total => &args.proxy_metrics.packets_dropped_session_limit,
+            SessionLimit::Client => &args.proxy_metrics.packets_dropped_client_session_limit,
+        };
+        dropped.inc();
+        if dropped.get() % LOG_SAMPLING_RATE == 0 {
+            warn!(args.log, "Dropping packets: session limit reached";
+                "from" => recv_addr, "count" => dropped.get());
+        }
+    }
+
     // A helper function to push a session's packet on its socket.
     async fn session_send_packet_helper(
         log: &Logger,
@@ -454,17 +1247,70 @@ impl Server {
         mut receive_packets: mpsc::Receiver<Packet>,
     ) {
         let log = self.log.clone();
+        let local_addr = local_addr(&socket);
+        let processing_duration = self.proxy_metrics.write_processing_duration.clone();
+        let segmentation_offload = self.config.proxy.segmentation_offload;
+        let capture = self.metrics.capture.clone();
         tokio::spawn(async move {
-            while let Some(packet) = receive_packets.recv().await {
-                debug!(
-                    log,
-                    "Sending packet back to origin";
-                    "origin" => packet.dest(),
-                    "contents" => debug::bytes_to_string(packet.contents()),
-                );
+            // A packet that was taken off the queue but could not be added
+            // to the previous batch.
+            let mut pending = None;
+            loop {
+                let packet = match pending.take() {
+                    Some(packet) => packet,
+                    None => match receive_packets.recv().await {
+                        Some(packet) => packet,
+                        None => break,
+                    },
+                };
+                if log.is_enabled(Level::Debug) {
+                    debug!(
+                        log,
+                        "Sending packet back to origin";
+                        "origin" => packet.dest(),
+                        "length" => packet.contents().len(),
+                    );
+                }
+
+                if !segmentation_offload {
+                    capture.record(
+                        local_addr,
+                        packet.dest(),
+                        packet.dest(),
+                        None,
+                        packet.contents(),
+                    );
+                    match socket.send_to(packet.contents(), &packet.dest()).await {
+                        Ok(_) => processing_duration
+                            .observe(packet.received_at().elapsed().as_secs_f64()),
+                        Err(err) => {
+                            error!(log, "Error sending packet"; "dest" => %packet.dest(), "error" => %err)
+                        }
+                    }
+                    continue;
+                }
 
-                if let Err(err) = socket.send_to(packet.contents(), &packet.dest()).await {
-                    error!(log, "Error sending packet"; "dest" => %packet.dest(), "error" => %err);
+                // Batch up any packets to the same destination that are
+                // already queued, so they can be sent with a single call.
+                let mut batch = offload::Batch::new(packet);
+                while let Ok(packet) = receive_packets.try_recv() {
+                    if let Err(packet) = batch.push(packet) {
+                        pending = Some(packet);
+                        break;
+                    }
+                }
+                for contents in batch.packets() {
+                    capture.record(local_addr, batch.dest(), batch.dest(), None, contents);
+                }
+                match batch.send(&socket).await {
+                    Ok(()) => {
+                        for received_at in batch.received_at() {
+                            processing_duration.observe(received_at.elapsed().as_secs_f64());
+                        }
+                    }
+                    Err(err) => {
+                        error!(log, "Error sending packets"; "dest" => %batch.dest(), "count" => batch.len(), "error" => %err)
+                    }
                 }
             }
             debug!(log, "Receiver closed");
@@ -473,7 +1319,15 @@ impl Server {
 
     /// log_config outputs a log of what is configured
     fn log_config(&self) {
-        info!(self.log, "Starting"; "port" => self.config.proxy.port);
+        let additional_ports = self
+            .config
+            .proxy
+            .additional_ports
+            .iter()
+            .map(|additional| additional.ports)
+            .collect::<Vec<_>>();
+        info!(self.log, "Starting"; "port" => self.config.proxy.port,
+            "additional_ports" => format!("{:?}", additional_ports));
     }
 
     /// bind binds the local configured port
@@ -481,10 +1335,25 @@ impl Server {
         let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
         UdpSocket::bind(addr).await.map_err(Error::Bind)
     }
+
+    /// bind_tcp binds the local configured port for TCP connections
+    async fn bind_tcp(port: u16) -> Result<TcpListener> {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
+        TcpListener::bind(addr).await.map_err(Error::Bind)
+    }
+}
+
+/// Returns the address `socket` is bound to, which is only used to describe
+/// the packets it sends and receives.
+fn local_addr(socket: &UdpSocket) -> SocketAddr {
+    socket
+        .local_addr()
+        .unwrap_or_else(|_| (Ipv4Addr::UNSPECIFIED, 0).into())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use std::sync::Arc;
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -494,12 +1363,16 @@ mod tests {
     use tokio::sync::mpsc;
     use tokio::time;
     use tokio::time::timeout;
-    use tokio::time::Duration;
+    use tokio::time::{Duration, Instant};
 
     use crate::cluster::cluster_manager::ClusterManager;
     use crate::config;
-    use crate::config::{Builder as ConfigBuilder, EndPoint, Endpoints};
+    use crate::config::{
+        AdditionalPorts, Builder as ConfigBuilder, EndPoint, Endpoints, HotRestart, OverloadPolicy,
+        PortRange, Runtime, SessionLimits, SessionPersistence, Upstream,
+    };
     use crate::filters::{manager::FilterManager, FilterChain};
+    use crate::proxy::debug_sampling::Sampler;
     use crate::proxy::sessions::Packet;
     use crate::proxy::Builder;
     use crate::test_utils::{
@@ -538,6 +1411,419 @@ mod tests {
         assert_eq!(msg, endpoint2.packet_rx.await.unwrap());
     }
 
+    #[tokio::test]
+    async fn run_server_processing_duration() {
+        let mut t = TestHelper::default();
+        let registry = Registry::default();
+        let endpoint = t.run_echo_server().await;
+
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12376);
+        let config = ConfigBuilder::empty()
+            .with_port(local_addr.port())
+            .with_static(vec![], vec![EndPoint::new(endpoint)])
+            .build();
+        t.run_server_with_builder(
+            Builder::from(Arc::new(config))
+                .with_metrics_registry(registry.clone())
+                .disable_admin(),
+        );
+
+        let (mut packet_rx, socket) = t.open_socket_and_recv_multiple_packets().await;
+        socket.send_to(b"hello", &local_addr).await.unwrap();
+        assert_eq!(
+            "hello",
+            timeout(Duration::from_secs(5), packet_rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        );
+
+        let sample_count = |direction: &str| {
+            registry
+                .gather()
+                .iter()
+                .filter(|family| {
+                    family.get_name() == "quilkin_proxy_packet_processing_duration_seconds"
+                })
+                .flat_map(|family| family.get_metric())
+                .filter(|metric| {
+                    metric.get_label().iter().any(|label| {
+                        label.get_name() == "direction" && label.get_value() == direction
+                    })
+                })
+                .map(|metric| metric.get_histogram().get_sample_count())
+                .sum::<u64>()
+        };
+        // Packets are observed once they have been sent, which can be after
+        // the client received the response.
+        timeout(Duration::from_secs(5), async {
+            while sample_count("read") == 0 || sample_count("write") == 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(1, sample_count("read"));
+        assert_eq!(1, sample_count("write"));
+    }
+
+    #[tokio::test]
+    async fn run_server_per_core_runtime() {
+        let mut t = TestHelper::default();
+
+        let endpoint = t.open_socket_and_recv_single_packet().await;
+
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12366);
+        let config = ConfigBuilder::empty()
+            .with_port(local_addr.port())
+            .with_runtime(Runtime {
+                flavor: RuntimeFlavor::PerCore,
+                worker_threads: Some(2),
+            })
+            .with_static(
+                vec![],
+                vec![EndPoint::new(endpoint.socket.local_addr().unwrap())],
+            )
+            .build();
+        t.run_server_with_config(config);
+
+        let msg = "hello";
+        endpoint
+            .socket
+            .send_to(msg.as_bytes(), &local_addr)
+            .await
+            .unwrap();
+        assert_eq!(
+            msg,
+            timeout(Duration::from_secs(5), endpoint.packet_rx)
+                .await
+                .unwrap()
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn run_server_per_core_runtime_additional_ports() {
+        let mut t = TestHelper::default();
+
+        let (mut packet_rx, endpoint) = t.open_socket_and_recv_multiple_packets().await;
+
+        // The workers of both ports run on the same two worker threads.
+        let config = ConfigBuilder::empty()
+            .with_port(12377)
+            .with_additional_ports(vec![PortRange {
+                start: 12378,
+                end: 12378,
+            }
+            .into()])
+            .with_runtime(Runtime {
+                flavor: RuntimeFlavor::PerCore,
+                worker_threads: Some(2),
+            })
+            .with_static(vec![], vec![EndPoint::new(endpoint.local_addr().unwrap())])
+            .build();
+        t.run_server_with_config(config);
+
+        for port in 12377..=12378 {
+            let msg = format!("hello {}", port);
+            let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+            endpoint.send_to(msg.as_bytes(), &local_addr).await.unwrap();
+            assert_eq!(
+                msg,
+                timeout(Duration::from_secs(5), packet_rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn run_server_reload_config() {
+        let mut t = TestHelper::default();
+
+        let endpoint1 = t.open_socket_and_recv_single_packet().await;
+        let (mut endpoint2_rx, endpoint2) = t.open_socket_and_recv_multiple_packets().await;
+
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12370);
+        let config = |endpoint: SocketAddr| {
+            format!(
+                "
+version: v1alpha1
+proxy:
+  port: {}
+static:
+  endpoints:
+    - address: {}
+",
+                local_addr.port(),
+                endpoint
+            )
+        };
+        let path = std::env::temp_dir().join(format!("quilkin-{}.yaml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, config(endpoint1.socket.local_addr().unwrap())).unwrap();
+        t.run_server_with_builder(
+            Builder::from(Arc::new(
+                config::Config::from_reader(std::fs::File::open(&path).unwrap()).unwrap(),
+            ))
+            .disable_admin()
+            .with_config_path(&path),
+        );
+
+        let client = t.create_socket().await;
+        let msg = "hello";
+        client.send_to(msg.as_bytes(), &local_addr).await.unwrap();
+        assert_eq!(msg, endpoint1.packet_rx.await.unwrap());
+
+        // Packets go to the new endpoint once the change was picked up.
+        std::fs::write(&path, config(endpoint2.local_addr().unwrap())).unwrap();
+        let received = timeout(Duration::from_secs(10), async {
+            loop {
+                client.send_to(msg.as_bytes(), &local_addr).await.unwrap();
+                if let Ok(received) = timeout(Duration::from_millis(100), endpoint2_rx.recv()).await
+                {
+                    return received;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(msg, received.unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn run_server_multiplexing() {
+        let mut t = TestHelper::default();
+
+        let sources = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let echo_addr = {
+            let sources = sources.clone();
+            t.run_echo_server_with_tap(move |from, _, _| {
+                sources.lock().unwrap().insert(from);
+            })
+            .await
+        };
+
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12369);
+        let config = ConfigBuilder::empty()
+            .with_port(server_addr.port())
+            .with_demultiplex(true)
+            .with_static(vec![], vec![EndPoint::new(echo_addr)])
+            .build();
+        t.run_server_with_config(config);
+
+        let client_proxy_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12368);
+        let config = ConfigBuilder::empty()
+            .with_port(client_proxy_addr.port())
+            .with_upstream(Upstream {
+                multiplex: true,
+                ..Upstream::default()
+            })
+            .with_static(vec![], vec![EndPoint::new(server_addr)])
+            .build();
+        t.run_server_with_config(config);
+
+        for msg in &["hello", "world"] {
+            let client = t.create_socket().await;
+            let mut buf = vec![0; 1024];
+            // Retry until both proxies are listening.
+            let size = loop {
+                client
+                    .send_to(msg.as_bytes(), &client_proxy_addr)
+                    .await
+                    .unwrap();
+                if let Ok(received) =
+                    timeout(Duration::from_millis(500), client.recv_from(&mut buf)).await
+                {
+                    break received.unwrap().0;
+                }
+            };
+            assert_eq!(msg.as_bytes(), &buf[..size]);
+        }
+
+        // Both clients share the client proxy's socket, but still get a
+        // session of their own on the server proxy.
+        assert_eq!(2, sources.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn run_server_additional_ports() {
+        let mut t = TestHelper::default();
+
+        let (mut packet_rx, endpoint) = t.open_socket_and_recv_multiple_packets().await;
+
+        let config = ConfigBuilder::empty()
+            .with_port(12360)
+            .with_additional_ports(vec![PortRange {
+                start: 12361,
+                end: 12362,
+            }
+            .into()])
+            .with_static(vec![], vec![EndPoint::new(endpoint.local_addr().unwrap())])
+            .build();
+        t.run_server_with_config(config);
+
+        for port in 12360..=12362 {
+            let msg = format!("hello {}", port);
+            let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+            endpoint.send_to(msg.as_bytes(), &local_addr).await.unwrap();
+            assert_eq!(
+                msg,
+                timeout(Duration::from_secs(5), packet_rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn run_server_additional_ports_override() {
+        let mut t = TestHelper::default();
+
+        let (mut source_rx, source_endpoint) = t.open_socket_and_recv_multiple_packets().await;
+        let (mut port_rx, port_endpoint) = t.open_socket_and_recv_multiple_packets().await;
+
+        let config = ConfigBuilder::empty()
+            .with_port(12374)
+            .with_additional_ports(vec![AdditionalPorts {
+                ports: PortRange {
+                    start: 12375,
+                    end: 12375,
+                },
+                filters: Some(vec![config::Filter {
+                    name: "quilkin.extensions.filters.concatenate_bytes.v1alpha1.ConcatenateBytes"
+                        .into(),
+                    config: serde_yaml::from_str("{on_read: APPEND, bytes: YWJj}").unwrap(),
+                }]),
+                endpoints: Some(vec![EndPoint::new(port_endpoint.local_addr().unwrap())]),
+            }])
+            .with_static(
+                vec![],
+                vec![EndPoint::new(source_endpoint.local_addr().unwrap())],
+            )
+            .build();
+        t.run_server_with_config(config);
+
+        let proxy = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        source_endpoint
+            .send_to(b"hello", &proxy(12374))
+            .await
+            .unwrap();
+        assert_eq!(
+            "hello",
+            timeout(Duration::from_secs(5), source_rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        );
+
+        // Packets received on the port go through its own filters to its
+        // own endpoints.
+        source_endpoint
+            .send_to(b"hello", &proxy(12375))
+            .await
+            .unwrap();
+        assert_eq!(
+            "helloabc",
+            timeout(Duration::from_secs(5), port_rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn run_server_segmentation_offload() {
+        let mut t = TestHelper::default();
+
+        let (mut packet_rx, endpoint) = t.open_socket_and_recv_multiple_packets().await;
+
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12363);
+        let config = ConfigBuilder::empty()
+            .with_port(local_addr.port())
+            .with_segmentation_offload(true)
+            .with_static(vec![], vec![EndPoint::new(endpoint.local_addr().unwrap())])
+            .build();
+        t.run_server_with_config(config);
+
+        let msgs = vec!["hello", "world", "!"];
+        for msg in &msgs {
+            endpoint.send_to(msg.as_bytes(), &local_addr).await.unwrap();
+        }
+        let mut received = vec![];
+        for _ in &msgs {
+            received.push(
+                timeout(Duration::from_secs(5), packet_rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+        assert_eq!(msgs, received);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run_server_hot_restart() {
+        let mut t = TestHelper::default();
+
+        let (mut packet_rx, endpoint) = t.open_socket_and_recv_multiple_packets().await;
+
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12364);
+        let socket = std::env::temp_dir().join(format!("quilkin-{}.sock", uuid::Uuid::new_v4()));
+        let config = || {
+            ConfigBuilder::empty()
+                .with_port(local_addr.port())
+                .with_hot_restart(HotRestart {
+                    socket: socket.clone(),
+                    drain_timeout: Duration::from_secs(1),
+                })
+                .with_static(vec![], vec![EndPoint::new(endpoint.local_addr().unwrap())])
+                .build()
+        };
+
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let old = Builder::from(Arc::new(config()))
+            .disable_admin()
+            .validate()
+            .unwrap()
+            .build();
+        let old = tokio::spawn(old.run(shutdown_rx));
+
+        endpoint.send_to(b"old", &local_addr).await.unwrap();
+        assert_eq!(
+            "old",
+            timeout(Duration::from_secs(5), packet_rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        );
+
+        // Starting a second server takes over the port, and the first one
+        // exits once it has drained its session.
+        t.run_server_with_config(config());
+        timeout(Duration::from_secs(5), old)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        endpoint.send_to(b"new", &local_addr).await.unwrap();
+        assert_eq!(
+            "new",
+            timeout(Duration::from_secs(5), packet_rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        );
+
+        std::fs::remove_file(socket).unwrap();
+    }
+
     #[tokio::test]
     async fn run_client() {
         let mut t = TestHelper::default();
@@ -639,7 +1925,8 @@ mod tests {
             // need to switch to 127.0.0.1, as the request comes locally
             receive_addr.set_ip("127.0.0.1".parse().unwrap());
 
-            let session_manager = SessionManager::new(t.log.clone(), shutdown_rx.clone());
+            let session_manager =
+                SessionManager::new(t.log.clone(), Default::default(), shutdown_rx.clone());
             let (send_packets, mut recv_packets) = mpsc::channel::<Packet>(1);
 
             let time_increment = 10;
@@ -658,12 +1945,16 @@ mod tests {
             .unwrap();
             let filter_manager = FilterManager::fixed(chain.clone());
             for worker_id in 0..num_workers {
-                let (packet_tx, packet_rx) = mpsc::channel(num_workers);
-                packet_txs.push(packet_tx);
-
                 let metrics = Arc::new(Metrics::new(&t.log, registry.clone()));
                 let proxy_metrics = ProxyMetrics::new(&metrics.registry).unwrap();
                 let session_metrics = SessionMetrics::new(&metrics.registry).unwrap();
+
+                let (packet_tx, packet_rx) = queue::channel(
+                    num_workers,
+                    OverloadPolicy::Block,
+                    proxy_metrics.packets_dropped_overloaded.clone(),
+                );
+                packet_txs.push(packet_tx);
                 worker_configs.push(DownstreamReceiveWorkerConfig {
                     worker_id,
                     packet_rx,
@@ -676,16 +1967,26 @@ mod tests {
                         filter_manager: filter_manager.clone(),
                         session_manager: session_manager.clone(),
                         session_ttl: Duration::from_secs(10),
+                        session_limits: SessionLimits::default(),
+                        upstream: Upstream::default(),
                         send_packets: send_packets.clone(),
+                        restored_routes: None,
+                        demultiplex: false,
+                        multiplexer: None,
+                        sampler: Sampler::default(),
                     },
                 })
             }
 
-            Server::spawn_downstream_receive_workers(t.log.clone(), worker_configs);
+            Server::spawn_downstream_receive_workers(t.log.clone(), worker_configs, None);
 
             for packet_tx in packet_txs {
                 packet_tx
-                    .send((receive_addr, msg.as_bytes().to_vec()))
+                    .send((
+                        receive_addr,
+                        Bytes::copy_from_slice(msg.as_bytes()),
+                        Instant::now(),
+                    ))
                     .await
                     .unwrap();
             }
@@ -697,7 +1998,7 @@ mod tests {
 
             let map = session_manager.get_sessions().await;
             assert_eq!(expected.session_len, map.len());
-            let build_key = (receive_addr, endpoint.socket.local_addr().unwrap());
+            let build_key = (receive_addr, None, endpoint.socket.local_addr().unwrap());
             assert!(map.contains_key(&build_key));
             let session = map.get(&build_key).unwrap();
             let now_secs = SystemTime::now()
@@ -748,11 +2049,13 @@ mod tests {
     async fn run_recv_from() {
         let t = TestHelper::default();
         let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let (_drain_tx, drain_rx) = watch::channel(());
 
         let msg = "hello";
         let endpoint = t.open_socket_and_recv_single_packet().await;
         let socket = t.create_socket().await;
-        let session_manager = SessionManager::new(t.log.clone(), shutdown_rx.clone());
+        let session_manager =
+            SessionManager::new(t.log.clone(), Default::default(), shutdown_rx.clone());
         let (send_packets, mut recv_packets) = mpsc::channel::<Packet>(1);
 
         let config = Arc::new(config_with_dummy_endpoint().build());
@@ -776,6 +2079,10 @@ mod tests {
             session_ttl: Duration::from_secs(10),
             send_packets,
             shutdown_rx,
+            drain_rx,
+            restored_routes: None,
+            multiplexer: None,
+            worker_runtimes: None,
         });
 
         let addr = socket.local_addr().unwrap();
@@ -804,6 +2111,7 @@ mod tests {
             .send(Packet::new(
                 endpoint.socket.local_addr().unwrap(),
                 msg.as_bytes().to_vec(),
+                Instant::now(),
             ))
             .await
             .is_err()
@@ -815,4 +2123,212 @@ mod tests {
         server.run_receive_packet(endpoint.socket, recv_packet);
         assert_eq!(msg, endpoint.packet_rx.await.unwrap());
     }
+
+    #[tokio::test]
+    async fn session_limits() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+
+        let endpoint1 = Endpoint::from_address("127.0.0.1:10001".parse().unwrap());
+        let endpoint2 = Endpoint::from_address("127.0.0.1:10002".parse().unwrap());
+        let client1: SocketAddr = "127.0.0.1:20001".parse().unwrap();
+        let client2: SocketAddr = "127.0.0.1:20002".parse().unwrap();
+
+        let args = ProcessDownstreamReceiveConfig {
+            log: t.log.clone(),
+            proxy_metrics: ProxyMetrics::new(&registry).unwrap(),
+            session_metrics: SessionMetrics::new(&registry).unwrap(),
+            cluster_manager: ClusterManager::fixed(
+                &registry,
+                Endpoints::new(vec![endpoint1.clone(), endpoint2.clone()]).unwrap(),
+            )
+            .unwrap(),
+            filter_manager: FilterManager::fixed(Arc::new(
+                FilterChain::new(vec![], &registry).unwrap(),
+            )),
+            session_manager: SessionManager::new(t.log.clone(), Default::default(), shutdown_rx),
+            session_ttl: Duration::from_secs(10),
+            session_limits: SessionLimits {
+                max_sessions: Some(2),
+                max_sessions_per_client: Some(1),
+            },
+            upstream: Upstream::default(),
+            send_packets,
+            restored_routes: None,
+            demultiplex: false,
+            multiplexer: None,
+            sampler: Sampler::default(),
+        };
+
+        Server::session_send_packet(b"hello", client1, None, &endpoint1, &args).await;
+        // Over the per client limit.
+        Server::session_send_packet(b"hello", client1, None, &endpoint2, &args).await;
+        Server::session_send_packet(b"hello", client2, None, &endpoint2, &args).await;
+        // Over the global limit.
+        Server::session_send_packet(
+            b"hello",
+            "127.0.0.1:20003".parse().unwrap(),
+            None,
+            &endpoint1,
+            &args,
+        )
+        .await;
+        // Existing sessions are unaffected by the limits.
+        Server::session_send_packet(b"hello", client1, None, &endpoint1, &args).await;
+
+        let sessions = args.session_manager.get_sessions().await;
+        assert_eq!(2, sessions.len());
+        assert!(sessions.contains_key(&(client1, None, endpoint1.address)));
+        assert!(sessions.contains_key(&(client2, None, endpoint2.address)));
+        assert_eq!(
+            1,
+            args.proxy_metrics
+                .packets_dropped_client_session_limit
+                .get()
+        );
+        assert_eq!(1, args.proxy_metrics.packets_dropped_session_limit.get());
+    }
+
+    #[tokio::test]
+    async fn restored_routes() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let (send_packets, _recv_packets) = mpsc::channel::<Packet>(1);
+
+        let endpoint1 = Endpoint::from_address("127.0.0.1:10001".parse().unwrap());
+        let endpoint2 = Endpoint::from_address("127.0.0.1:10002".parse().unwrap());
+        let client1: SocketAddr = "127.0.0.1:20001".parse().unwrap();
+        let client2: SocketAddr = "127.0.0.1:20002".parse().unwrap();
+
+        let restored_routes = RestoredRoutes::default();
+        restored_routes.insert(client1, endpoint2.address);
+        let args = ProcessDownstreamReceiveConfig {
+            log: t.log.clone(),
+            proxy_metrics: ProxyMetrics::new(&registry).unwrap(),
+            session_metrics: SessionMetrics::new(&registry).unwrap(),
+            cluster_manager: ClusterManager::fixed(
+                &registry,
+                Endpoints::new(vec![endpoint1.clone(), endpoint2.clone()]).unwrap(),
+            )
+            .unwrap(),
+            filter_manager: FilterManager::fixed(Arc::new(
+                FilterChain::new(vec![], &registry).unwrap(),
+            )),
+            session_manager: SessionManager::new(t.log.clone(), Default::default(), shutdown_rx),
+            session_ttl: Duration::from_secs(10),
+            session_limits: SessionLimits::default(),
+            upstream: Upstream::default(),
+            send_packets,
+            restored_routes: Some(restored_routes.clone()),
+            demultiplex: false,
+            multiplexer: None,
+            sampler: Sampler::default(),
+        };
+        // The restored session.
+        Server::session_send_packet(b"hello", client1, None, &endpoint2, &args).await;
+
+        // Pinned to the restored session's endpoint.
+        Server::process_downstream_received_packet(
+            (client1, Bytes::from_static(b"hello"), Instant::now()),
+            &args,
+        )
+        .await;
+        // Not restored, so sent to every endpoint.
+        Server::process_downstream_received_packet(
+            (client2, Bytes::from_static(b"hello"), Instant::now()),
+            &args,
+        )
+        .await;
+        {
+            let sessions = args.session_manager.get_sessions().await;
+            assert_eq!(3, sessions.len());
+            assert!(!sessions.contains_key(&(client1, None, endpoint1.address)));
+        }
+
+        // Once the restored session is gone, packets are routed as usual.
+        args.session_manager
+            .get_sessions_mut()
+            .await
+            .remove(&(client1, None, endpoint2.address));
+        Server::process_downstream_received_packet(
+            (client1, Bytes::from_static(b"hello"), Instant::now()),
+            &args,
+        )
+        .await;
+        let sessions = args.session_manager.get_sessions().await;
+        assert!(sessions.contains_key(&(client1, None, endpoint1.address)));
+        assert!(sessions.contains_key(&(client1, None, endpoint2.address)));
+        assert!(restored_routes.get(&client1).is_none());
+        assert_eq!(
+            3,
+            args.proxy_metrics
+                .read_processing_duration
+                .get_sample_count()
+        );
+    }
+
+    #[tokio::test]
+    async fn run_server_session_persistence() {
+        let mut t = TestHelper::default();
+
+        let (mut packet_rx1, endpoint1) = t.open_socket_and_recv_multiple_packets().await;
+        let (mut packet_rx2, endpoint2) = t.open_socket_and_recv_multiple_packets().await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12365);
+        let path = std::env::temp_dir().join(format!("quilkin-{}.json", uuid::Uuid::new_v4()));
+        let expiration = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        persistence::save(
+            &path,
+            vec![SessionEntry {
+                port: local_addr.port(),
+                downstream: client.local_addr().unwrap(),
+                endpoint: endpoint2.local_addr().unwrap(),
+                expiration,
+            }],
+        )
+        .unwrap();
+
+        let config = ConfigBuilder::empty()
+            .with_port(local_addr.port())
+            .with_session_persistence(SessionPersistence {
+                path: path.clone(),
+                interval: Duration::from_millis(100),
+            })
+            .with_static(
+                vec![],
+                vec![
+                    EndPoint::new(endpoint1.local_addr().unwrap()),
+                    EndPoint::new(endpoint2.local_addr().unwrap()),
+                ],
+            )
+            .build();
+        t.run_server_with_config(config);
+
+        client.send_to(b"hello", &local_addr).await.unwrap();
+        assert_eq!(
+            "hello",
+            timeout(Duration::from_secs(5), packet_rx2.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        );
+        assert!(timeout(Duration::from_millis(500), packet_rx1.recv())
+            .await
+            .is_err());
+
+        // The restored session keeps being saved.
+        let sessions = persistence::load(&path).unwrap();
+        assert_eq!(1, sessions.len());
+        assert_eq!(endpoint2.local_addr().unwrap(), sessions[0].endpoint);
+
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -14,15 +14,21 @@
  *  limitations under the License.
  */
 
-use crate::proxy::sessions::error::Error as SessionError;
 use std::fmt::{self, Display, Formatter};
 
+/// An error that stopped a [`Server`][crate::proxy::Server] from running.
 #[derive(Debug)]
 pub enum Error {
+    /// Something the proxy needs could not be set up, e.g an endpoint
+    /// discovery source or exporter.
     Initialize(String),
-    Session(SessionError),
+    /// A listening socket could not be bound.
     Bind(tokio::io::Error),
+    /// A task receiving packets failed.
     RecvLoop(String),
+    /// The task running the server was aborted before it stopped, e.g because
+    /// the runtime shut down.
+    Aborted(String),
 }
 
 impl std::error::Error for Error {}
@@ -31,9 +37,9 @@ impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Error::Initialize(reason) => write!(f, "failed to startup properly: {}", reason),
-            Error::Session(inner) => write!(f, "session error: {}", inner),
             Error::Bind(inner) => write!(f, "failed to bind to port: {}", inner),
             Error::RecvLoop(reason) => write!(f, "receive loop exited with an error: {}", reason),
+            Error::Aborted(reason) => write!(f, "server was aborted: {}", reason),
         }
     }
 }
@@ -0,0 +1,180 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::proxy::server::error::Error;
+use crate::proxy::server::metrics::Metrics as ProxyMetrics;
+use crate::proxy::sessions::metrics::Metrics as SessionMetrics;
+
+/// The cluster manager of a server once it has started.
+pub(super) type StartedClusterManager = Arc<RwLock<Option<SharedClusterManager>>>;
+
+/// A [`Server`][crate::proxy::Server] running in the background, as returned
+/// by [`Server::spawn`][crate::proxy::Server::spawn].
+///
+/// Dropping the handle shuts the server down, like [`Handle::shutdown`].
+pub struct Handle {
+    shutdown_tx: watch::Sender<()>,
+    task: JoinHandle<Result<(), Error>>,
+    proxy_metrics: ProxyMetrics,
+    session_metrics: SessionMetrics,
+    cluster_manager: StartedClusterManager,
+}
+
+/// A snapshot of the traffic of a running server.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// The number of sessions currently active.
+    pub active_sessions: u64,
+    /// The total number of sessions that have been created.
+    pub sessions_total: u64,
+    /// The number of upstream endpoints the server currently knows of, which
+    /// is zero until it has started.
+    pub endpoints: usize,
+    /// The total number of packets sent to upstream endpoints.
+    pub packets_sent: u64,
+    /// The total number of packets received from upstream endpoints.
+    pub packets_received: u64,
+    /// The total number of packets that were dropped rather than sent on, in
+    /// either direction.
+    pub packets_dropped: u64,
+}
+
+impl Handle {
+    pub(super) fn new(
+        shutdown_tx: watch::Sender<()>,
+        task: JoinHandle<Result<(), Error>>,
+        proxy_metrics: ProxyMetrics,
+        session_metrics: SessionMetrics,
+        cluster_manager: StartedClusterManager,
+    ) -> Self {
+        Self {
+            shutdown_tx,
+            task,
+            proxy_metrics,
+            session_metrics,
+            cluster_manager,
+        }
+    }
+
+    /// Tells the server to shut down, which it has done once
+    /// [`Handle::await_termination`] returns.
+    pub fn shutdown(&self) {
+        self.shutdown_tx.send(()).ok();
+    }
+
+    /// Waits for the server to stop, either because it was shut down or
+    /// because it failed. Panics if the server panicked.
+    pub async fn await_termination(self) -> Result<(), Error> {
+        // Keep the sender alive until the server has stopped, as dropping it
+        // would shut the server down.
+        let _shutdown_tx = self.shutdown_tx;
+        match self.task.await {
+            Ok(result) => result,
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(err) => Err(Error::Aborted(err.to_string())),
+        }
+    }
+
+    /// Returns the current traffic of the server.
+    pub fn stats(&self) -> Stats {
+        let session = &self.session_metrics;
+        let proxy = &self.proxy_metrics;
+        let endpoints = self.cluster_manager.read().as_ref().map_or(0, |manager| {
+            manager
+                .read()
+                .get_all_endpoints()
+                .map_or(0, |endpoints| endpoints.size())
+        });
+        Stats {
+            active_sessions: session.active_sessions.get().max(0) as u64,
+            sessions_total: session.sessions_total.get(),
+            endpoints,
+            packets_sent: session.tx_packets_total.get(),
+            packets_received: session.rx_packets_total.get(),
+            packets_dropped: session.packets_dropped_total.get()
+                + proxy.packets_dropped_no_endpoints.get()
+                + proxy.packets_dropped_session_limit.get()
+                + proxy.packets_dropped_client_session_limit.get()
+                + proxy.packets_dropped_overloaded.get(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use crate::config::{Builder as ConfigBuilder, EndPoint};
+    use crate::proxy::Builder;
+    use crate::test_utils::TestHelper;
+
+    #[tokio::test]
+    async fn spawn_and_shutdown() {
+        let mut t = TestHelper::default();
+        let echo = t.run_echo_server().await;
+
+        let local_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 12371));
+        let config = ConfigBuilder::empty()
+            .with_port(local_addr.port())
+            .with_static(vec![], vec![EndPoint::new(echo)])
+            .build();
+        let handle = Builder::from(config)
+            .disable_admin()
+            .validate()
+            .unwrap()
+            .build()
+            .spawn();
+
+        let (mut recv, socket) = t.open_socket_and_recv_multiple_packets().await;
+        // Packets may be sent before the server has bound its socket.
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                socket.send_to(b"hello", &local_addr).await.unwrap();
+                if let Ok(Some(packet)) =
+                    tokio::time::timeout(Duration::from_millis(100), recv.recv()).await
+                {
+                    return packet;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!("hello", received);
+
+        let stats = handle.stats();
+        assert_eq!(1, stats.active_sessions);
+        assert_eq!(1, stats.sessions_total);
+        assert_eq!(1, stats.endpoints);
+        assert!(stats.packets_sent >= 1);
+        assert!(stats.packets_received >= 1);
+        assert_eq!(0, stats.packets_dropped);
+
+        handle.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), handle.await_termination())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Hands the proxy's listening sockets over to a new process.
+//!
+//! A running proxy listens on a Unix domain socket. A newly started proxy
+//! connects to it and receives duplicates of every listening socket, one per
+//! message as `SCM_RIGHTS` ancillary data, followed by a final message
+//! without a socket. Once the new process is serving traffic on the sockets
+//! it writes a single byte back, after which the old process stops accepting
+//! traffic and drains its existing sessions.
+
+use std::io;
+use std::mem;
+use std::net::{TcpListener, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+
+use slog::{warn, Logger};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Payload of a message that carries a socket.
+const MORE: u8 = 1;
+/// Payload of the final message, which carries no socket.
+const DONE: u8 = 0;
+
+/// Sockets received from the process that was running previously.
+pub(super) struct InheritedSockets {
+    udp: Vec<UdpSocket>,
+    tcp: Vec<TcpListener>,
+    stream: UnixStream,
+}
+
+impl InheritedSockets {
+    /// Returns the inherited UDP socket bound to `port`, if any.
+    pub fn take_udp(&mut self, port: u16) -> Option<UdpSocket> {
+        let index = self
+            .udp
+            .iter()
+            .position(|socket| matches!(socket.local_addr(), Ok(addr) if addr.port() == port))?;
+        Some(self.udp.swap_remove(index))
+    }
+
+    /// Returns the inherited TCP listener bound to `port`, if any.
+    pub fn take_tcp(&mut self, port: u16) -> Option<TcpListener> {
+        let index = self
+            .tcp
+            .iter()
+            .position(|socket| matches!(socket.local_addr(), Ok(addr) if addr.port() == port))?;
+        Some(self.tcp.swap_remove(index))
+    }
+
+    /// Tells the previous process that we are now serving traffic, so it can
+    /// start draining. Sockets that were not taken are closed.
+    pub async fn complete(mut self) -> io::Result<()> {
+        self.stream.write_all(&[DONE]).await
+    }
+
+    fn add(&mut self, fd: RawFd) -> io::Result<()> {
+        let mut kind: libc::c_int = 0;
+        let mut len = mem::size_of_val(&kind) as libc::socklen_t;
+        // Safety: the option value points to a live c_int of the given size.
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_TYPE,
+                &mut kind as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result == -1 {
+            let err = io::Error::last_os_error();
+            // Safety: we own the received file descriptor.
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+
+        // Safety: we own the received file descriptor, and it is a socket of
+        // the type it is wrapped as.
+        match kind {
+            libc::SOCK_DGRAM => {
+                let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+                socket.set_nonblocking(true)?;
+                self.udp.push(socket);
+            }
+            libc::SOCK_STREAM => {
+                let socket = unsafe { TcpListener::from_raw_fd(fd) };
+                socket.set_nonblocking(true)?;
+                self.tcp.push(socket);
+            }
+            _ => {
+                unsafe { libc::close(fd) };
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Connects to the process listening on `path` and takes over its listening
+/// sockets. Returns `None` if no process is listening.
+pub(super) async fn take_over(path: &Path) -> io::Result<Option<InheritedSockets>> {
+    let stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(err)
+            if err.kind() == io::ErrorKind::NotFound
+                || err.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            return Ok(None)
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut inherited = InheritedSockets {
+        udp: vec![],
+        tcp: vec![],
+        stream,
+    };
+    loop {
+        let (payload, fd) = recv(&inherited.stream).await?;
+        if let Some(fd) = fd {
+            inherited.add(fd)?;
+        }
+        if payload == DONE {
+            return Ok(Some(inherited));
+        }
+    }
+}
+
+/// Listens on `path` for a new process to take over, replacing whatever
+/// socket was previously there.
+pub(super) fn listen(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    UnixListener::bind(path)
+}
+
+/// Waits for a new process to connect to `listener` and hands it `fds`.
+/// Returns once a process has confirmed it took over.
+pub(super) async fn wait_for_take_over(
+    log: &Logger,
+    listener: UnixListener,
+    fds: Vec<RawFd>,
+) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        match hand_over(stream, &fds).await {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                warn!(log, "Failed to hand over listening sockets"; "error" => %err)
+            }
+        }
+    }
+}
+
+async fn hand_over(mut stream: UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    for fd in fds {
+        send(&stream, MORE, Some(*fd)).await?;
+    }
+    send(&stream, DONE, None).await?;
+
+    let mut ack = [0; 1];
+    stream.read_exact(&mut ack).await?;
+    Ok(())
+}
+
+async fn send(stream: &UnixStream, payload: u8, fd: Option<RawFd>) -> io::Result<()> {
+    loop {
+        stream.writable().await?;
+        match stream.try_io(Interest::WRITABLE, || {
+            sendmsg(stream.as_raw_fd(), payload, fd)
+        }) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+async fn recv(stream: &UnixStream) -> io::Result<(u8, Option<RawFd>)> {
+    loop {
+        stream.readable().await?;
+        match stream.try_io(Interest::READABLE, || recvmsg(stream.as_raw_fd())) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+fn sendmsg(socket: RawFd, mut payload: u8, fd: Option<RawFd>) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: &mut payload as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+    // A u64 array keeps the control buffer aligned for cmsghdr.
+    let mut control = [0u64; 4];
+
+    // Safety: every pointer in the msghdr refers to a buffer that outlives
+    // the call, and the control buffer has room for a single fd.
+    let result = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if let Some(fd) = fd {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+
+        libc::sendmsg(socket, &msg, 0)
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn recvmsg(socket: RawFd) -> io::Result<(u8, Option<RawFd>)> {
+    let mut payload = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: &mut payload as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut control = [0u64; 4];
+
+    // Safety: every pointer in the msghdr refers to a buffer that outlives
+    // the call, with its length set accordingly.
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let size = libc::recvmsg(socket, &mut msg, 0);
+        if size == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut fd = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                fd = Some(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok((payload, fd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+
+    use tokio::net::UdpSocket;
+    use tokio::time::{timeout, Duration};
+    use uuid::Uuid;
+
+    use crate::test_utils::logger;
+
+    use super::{listen, take_over, wait_for_take_over};
+
+    fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!("quilkin-{}.sock", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn take_over_without_running_process() {
+        assert!(take_over(&socket_path()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn take_over_sockets() {
+        let path = socket_path();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_port = listener.local_addr().unwrap().port();
+
+        let fds = vec![socket.as_raw_fd(), listener.as_raw_fd()];
+        let handover = tokio::spawn({
+            let listener = listen(&path).unwrap();
+            async move { wait_for_take_over(&logger(), listener, fds).await }
+        });
+
+        let mut inherited = take_over(&path).await.unwrap().unwrap();
+        assert!(inherited.take_udp(addr.port() + 1).is_none());
+        let inherited_socket =
+            UdpSocket::from_std(inherited.take_udp(addr.port()).unwrap()).unwrap();
+        assert!(inherited.take_tcp(tcp_port).is_some());
+        inherited.complete().await.unwrap();
+
+        timeout(Duration::from_secs(5), handover)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // The old socket is closed, so the inherited one receives everything.
+        drop(socket);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"hello", addr).await.unwrap();
+        let mut buf = [0; 16];
+        let (size, _) = timeout(Duration::from_secs(5), inherited_socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -14,29 +14,61 @@
  * limitations under the License.
  */
 
-use crate::metrics::{opts, CollectorExt};
+use crate::metrics::{histogram_opts, opts, CollectorExt};
 use prometheus::core::{AtomicU64, GenericCounter};
-use prometheus::{IntCounterVec, Registry, Result as MetricsResult};
+use prometheus::{Histogram, HistogramVec, IntCounterVec, Registry, Result as MetricsResult};
 
 #[derive(Clone)]
 pub struct Metrics {
     pub packets_dropped_no_endpoints: GenericCounter<AtomicU64>,
+    pub packets_dropped_session_limit: GenericCounter<AtomicU64>,
+    pub packets_dropped_client_session_limit: GenericCounter<AtomicU64>,
+    pub packets_dropped_overloaded: GenericCounter<AtomicU64>,
+    /// Time from receiving a packet from a client to sending it to endpoints.
+    pub read_processing_duration: Histogram,
+    /// Time from receiving a packet from an endpoint to sending it to its client.
+    pub write_processing_duration: Histogram,
 }
 
 impl Metrics {
     pub fn new(registry: &Registry) -> MetricsResult<Self> {
         let subsystem = "proxy";
+        let packets_dropped_total = IntCounterVec::new(
+            opts(
+                "packets_dropped_total",
+                subsystem,
+                "Total number of packets dropped by the proxy",
+            ),
+            &["reason"],
+        )?
+        .register_if_not_exists(registry)?;
+        let packet_processing_duration = HistogramVec::new(
+            histogram_opts(
+                "packet_processing_duration_seconds",
+                subsystem,
+                "Seconds taken by the proxy to process a packet, from receiving it to sending it on",
+                Some(vec![
+                    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005,
+                    0.01, 0.025, 0.05, 0.1,
+                ]),
+            ),
+            &["direction"],
+        )?
+        .register_if_not_exists(registry)?;
+
         Ok(Self {
-            packets_dropped_no_endpoints: IntCounterVec::new(
-                opts(
-                    "packets_dropped_total",
-                    subsystem,
-                    "Total number of packets dropped by the proxy",
-                ),
-                &["reason"],
-            )?
-            .register_if_not_exists(registry)?
-            .get_metric_with_label_values(&["NoConfiguredEndpoints"])?,
+            packets_dropped_no_endpoints: packets_dropped_total
+                .get_metric_with_label_values(&["NoConfiguredEndpoints"])?,
+            packets_dropped_session_limit: packets_dropped_total
+                .get_metric_with_label_values(&["SessionLimitReached"])?,
+            packets_dropped_client_session_limit: packets_dropped_total
+                .get_metric_with_label_values(&["ClientSessionLimitReached"])?,
+            packets_dropped_overloaded: packets_dropped_total
+                .get_metric_with_label_values(&["Overloaded"])?,
+            read_processing_duration: packet_processing_duration
+                .get_metric_with_label_values(&["read"])?,
+            write_processing_duration: packet_processing_duration
+                .get_metric_with_label_values(&["write"])?,
         })
     }
 }
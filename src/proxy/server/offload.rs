@@ -0,0 +1,406 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! UDP generic segmentation offload (GSO) and generic receive offload (GRO).
+//!
+//! With GRO enabled the kernel may hand us several consecutive packets from
+//! the same sender in a single buffer, all of the same size except possibly
+//! the last. GSO is the reverse, where we hand the kernel such a buffer to
+//! be split into individual packets, which saves a system call per packet.
+//! Both are only available on Linux, everywhere else packets are received
+//! and sent one at a time.
+
+use std::io;
+use std::iter;
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use either::Either;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::proxy::sessions::Packet;
+
+/// The maximum number of segments the kernel accepts in a single GSO send.
+const MAX_SEGMENTS: usize = 64;
+
+/// The maximum payload of a single UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Enables generic receive offload on `socket`.
+pub(super) fn enable_gro(socket: &UdpSocket) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        linux::enable_gro(socket)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = socket;
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "UDP segmentation offload is only supported on Linux",
+        ))
+    }
+}
+
+/// Receives a datagram into `buf`, returning the number of bytes read, the
+/// sender, and the size of the segments the datagram is made up of.
+pub(super) async fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    offload: bool,
+) -> io::Result<(usize, SocketAddr, usize)> {
+    #[cfg(target_os = "linux")]
+    {
+        if offload {
+            return linux::recv_from(socket, buf).await;
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = offload;
+
+    let (size, addr) = socket.recv_from(buf).await?;
+    Ok((size, addr, size))
+}
+
+/// Splits a datagram received with [`recv_from`] into its segments.
+pub(super) fn segments(contents: &[u8], segment_size: usize) -> impl Iterator<Item = &[u8]> {
+    if contents.is_empty() || segment_size == 0 {
+        Either::Left(iter::once(contents))
+    } else {
+        Either::Right(contents.chunks(segment_size))
+    }
+}
+
+/// Packets bound for the same destination that are sent with a single
+/// system call. The packets are passed to the kernel as they are, without
+/// being copied into a single buffer first.
+pub(super) struct Batch {
+    dest: SocketAddr,
+    segment_size: usize,
+    packets: Vec<Bytes>,
+    /// When each of the packets was received by the proxy.
+    received_at: Vec<Instant>,
+    size: usize,
+}
+
+impl Batch {
+    pub fn new(packet: Packet) -> Self {
+        let dest = packet.dest();
+        let received_at = packet.received_at();
+        let contents = packet.into_contents();
+        Batch {
+            dest,
+            segment_size: contents.len(),
+            size: contents.len(),
+            packets: vec![contents],
+            received_at: vec![received_at],
+        }
+    }
+
+    /// Adds `packet` to the batch, or returns it if it cannot be sent along
+    /// with the packets already in the batch.
+    pub fn push(&mut self, packet: Packet) -> Result<(), Packet> {
+        let len = packet.contents().len();
+        let last_segment_full = self.size == self.packets.len() * self.segment_size;
+        if packet.dest() != self.dest
+            || !last_segment_full
+            || len == 0
+            || len > self.segment_size
+            || self.packets.len() == MAX_SEGMENTS
+            || self.size + len > MAX_DATAGRAM_SIZE
+        {
+            return Err(packet);
+        }
+
+        self.received_at.push(packet.received_at());
+        self.packets.push(packet.into_contents());
+        self.size += len;
+        Ok(())
+    }
+
+    pub fn dest(&self) -> SocketAddr {
+        self.dest
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn packets(&self) -> &[Bytes] {
+        &self.packets
+    }
+
+    pub fn received_at(&self) -> &[Instant] {
+        &self.received_at
+    }
+
+    /// Sends every packet in the batch to its destination. If the kernel
+    /// rejects the segmented send, the packets are sent one by one instead.
+    pub async fn send(&self, socket: &UdpSocket) -> io::Result<()> {
+        if self.packets.len() > 1 {
+            #[cfg(target_os = "linux")]
+            {
+                if linux::send_to(socket, &self.packets, self.segment_size as u16, self.dest)
+                    .await
+                    .is_ok()
+                {
+                    return Ok(());
+                }
+            }
+        }
+
+        for packet in &self.packets {
+            socket.send_to(packet, self.dest).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::mem;
+    use std::net::SocketAddr;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::ptr;
+
+    use bytes::Bytes;
+    use socket2::SockAddr;
+    use tokio::io::Interest;
+    use tokio::net::UdpSocket;
+
+    pub fn enable_gro(socket: &UdpSocket) -> io::Result<()> {
+        let enable: libc::c_int = 1;
+        // Safety: the option value points to a live c_int of the given size.
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &enable as *const _ as *const libc::c_void,
+                mem::size_of_val(&enable) as libc::socklen_t,
+            )
+        };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub async fn recv_from(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, usize)> {
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || recvmsg(socket.as_raw_fd(), buf)) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn send_to(
+        socket: &UdpSocket,
+        packets: &[Bytes],
+        segment_size: u16,
+        dest: SocketAddr,
+    ) -> io::Result<usize> {
+        let dest = SockAddr::from(dest);
+        loop {
+            socket.writable().await?;
+            match socket.try_io(Interest::WRITABLE, || {
+                sendmsg(socket.as_raw_fd(), packets, segment_size, &dest)
+            }) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn recvmsg(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // A u64 array keeps the control buffer aligned for cmsghdr.
+        let mut control = [0u64; 8];
+
+        // Safety: every pointer in the msghdr refers to a buffer that outlives
+        // the call, with its length set accordingly.
+        let ((size, segment_size), addr) = unsafe {
+            SockAddr::init(|storage, len| {
+                let mut msg: libc::msghdr = mem::zeroed();
+                msg.msg_name = storage as *mut libc::c_void;
+                msg.msg_namelen = *len;
+                msg.msg_iov = &mut iov;
+                msg.msg_iovlen = 1;
+                msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_controllen = mem::size_of_val(&control) as _;
+
+                let size = libc::recvmsg(fd, &mut msg, 0);
+                if size == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                *len = msg.msg_namelen;
+
+                let size = size as usize;
+                let mut segment_size = size;
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+                while !cmsg.is_null() {
+                    if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                        segment_size =
+                            ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int)
+                                as usize;
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                }
+                Ok((size, segment_size))
+            })?
+        };
+
+        let addr = addr.as_socket().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "received packet from a non IP address",
+            )
+        })?;
+        Ok((size, addr, segment_size))
+    }
+
+    fn sendmsg(
+        fd: RawFd,
+        packets: &[Bytes],
+        segment_size: u16,
+        dest: &SockAddr,
+    ) -> io::Result<usize> {
+        // The kernel joins the packets back together before splitting them
+        // into segments.
+        let mut iov = packets
+            .iter()
+            .map(|packet| libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut control = [0u64; 4];
+
+        // Safety: every pointer in the msghdr refers to a buffer that outlives
+        // the call, and the control buffer has room for a single u16 cmsg.
+        let size = unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_name = dest.as_ptr() as *mut libc::c_void;
+            msg.msg_namelen = dest.len();
+            msg.msg_iov = iov.as_mut_ptr();
+            msg.msg_iovlen = iov.len() as _;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+
+            libc::sendmsg(fd, &msg, 0)
+        };
+        if size == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(size as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+    use tokio::time::{timeout, Duration, Instant};
+
+    use crate::proxy::sessions::Packet;
+
+    use super::{enable_gro, recv_from, segments, Batch, MAX_SEGMENTS};
+
+    fn packet(dest: &str, contents: &[u8]) -> Packet {
+        Packet::new(dest.parse().unwrap(), contents.to_vec(), Instant::now())
+    }
+
+    #[test]
+    fn batch_push() {
+        let mut batch = Batch::new(packet("127.0.0.1:7000", b"abc"));
+        assert!(batch.push(packet("127.0.0.1:7000", b"def")).is_ok());
+        // Different destination.
+        assert!(batch.push(packet("127.0.0.1:7001", b"ghi")).is_err());
+        // Larger than the segment size.
+        assert!(batch.push(packet("127.0.0.1:7000", b"ghij")).is_err());
+        // A shorter segment can only be the last one.
+        assert!(batch.push(packet("127.0.0.1:7000", b"gh")).is_ok());
+        assert!(batch.push(packet("127.0.0.1:7000", b"ijk")).is_err());
+
+        assert_eq!(3, batch.len());
+        assert_eq!(vec![b"abc".as_ref(), b"def", b"gh"], batch.packets);
+
+        let mut batch = Batch::new(packet("127.0.0.1:7000", b"a"));
+        for _ in 1..MAX_SEGMENTS {
+            assert!(batch.push(packet("127.0.0.1:7000", b"a")).is_ok());
+        }
+        assert!(batch.push(packet("127.0.0.1:7000", b"a")).is_err());
+    }
+
+    #[test]
+    fn empty_segments() {
+        assert_eq!(vec![b"".as_ref()], segments(b"", 0).collect::<Vec<_>>());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn send_and_recv() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        enable_gro(&receiver).unwrap();
+        let dest: SocketAddr = receiver.local_addr().unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut batch = Batch::new(Packet::new(dest, b"hello".to_vec(), Instant::now()));
+        assert!(batch
+            .push(Packet::new(dest, b"world".to_vec(), Instant::now()))
+            .is_ok());
+        assert!(batch
+            .push(Packet::new(dest, b"!".to_vec(), Instant::now()))
+            .is_ok());
+        batch.send(&sender).await.unwrap();
+
+        // The kernel may or may not have coalesced the packets, either way
+        // splitting what we receive gives back the original packets.
+        let mut received = vec![];
+        let mut buf = vec![0; 1 << 16];
+        while received.len() < 3 {
+            let (size, from, segment_size) =
+                timeout(Duration::from_secs(5), recv_from(&receiver, &mut buf, true))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(sender.local_addr().unwrap(), from);
+            received.extend(segments(&buf[..size], segment_size).map(<[u8]>::to_vec));
+        }
+
+        assert_eq!(
+            vec![b"hello".to_vec(), b"world".to_vec(), b"!".to_vec()],
+            received
+        );
+    }
+}
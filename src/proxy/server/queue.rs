@@ -0,0 +1,382 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Bounded queues of received packets waiting to be processed by a worker,
+//! which apply an [`OverloadPolicy`] once they are full.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use prometheus::core::{AtomicU64, GenericCounter};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::config::OverloadPolicy;
+
+/// A packet along with the address it was received from and when.
+type QueuedPacket = (SocketAddr, Bytes, Instant);
+
+/// Returned when sending to a queue whose receiver was dropped.
+#[derive(Debug)]
+pub(super) struct Closed;
+
+/// Creates a queue holding up to `capacity` packets. Packets dropped due to
+/// `policy` are counted in `dropped`.
+pub(super) fn channel(
+    capacity: usize,
+    policy: OverloadPolicy,
+    dropped: GenericCounter<AtomicU64>,
+) -> (PacketSender, PacketReceiver) {
+    let packets = match policy {
+        OverloadPolicy::Fair => Packets::Fair {
+            order: VecDeque::new(),
+            by_source: HashMap::new(),
+        },
+        _ => Packets::Fifo(VecDeque::new()),
+    };
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            packets,
+            len: 0,
+            sender_closed: false,
+            receiver_closed: false,
+        }),
+        capacity,
+        policy,
+        dropped,
+        pushed: Notify::new(),
+        popped: Notify::new(),
+    });
+    (PacketSender(shared.clone()), PacketReceiver(shared))
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverloadPolicy,
+    dropped: GenericCounter<AtomicU64>,
+    /// Notified whenever a packet is queued or the sender is dropped.
+    pushed: Notify,
+    /// Notified whenever a packet is taken off the queue.
+    popped: Notify,
+}
+
+struct State {
+    packets: Packets,
+    len: usize,
+    sender_closed: bool,
+    receiver_closed: bool,
+}
+
+enum Packets {
+    Fifo(VecDeque<QueuedPacket>),
+    /// Packets queued per source, with sources taking turns in `order`.
+    Fair {
+        order: VecDeque<SocketAddr>,
+        by_source: HashMap<SocketAddr, VecDeque<(Bytes, Instant)>>,
+    },
+}
+
+impl Packets {
+    fn push(&mut self, (from, contents, received_at): QueuedPacket) {
+        match self {
+            Packets::Fifo(packets) => packets.push_back((from, contents, received_at)),
+            Packets::Fair { order, by_source } => {
+                let queue = by_source.entry(from).or_default();
+                if queue.is_empty() {
+                    order.push_back(from);
+                }
+                queue.push_back((contents, received_at));
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<QueuedPacket> {
+        match self {
+            Packets::Fifo(packets) => packets.pop_front(),
+            Packets::Fair { order, by_source } => {
+                let from = order.pop_front()?;
+                let queue = by_source.get_mut(&from)?;
+                let (contents, received_at) = queue.pop_front()?;
+                if queue.is_empty() {
+                    by_source.remove(&from);
+                } else {
+                    order.push_back(from);
+                }
+                Some((from, contents, received_at))
+            }
+        }
+    }
+
+    /// Drops a packet of the source with the most packets queued, unless
+    /// that is `from`. Returns whether a packet was dropped.
+    fn drop_from_longest(&mut self, from: SocketAddr) -> bool {
+        let (order, by_source) = match self {
+            Packets::Fair { order, by_source } => (order, by_source),
+            Packets::Fifo(_) => return false,
+        };
+        let own_len = by_source.get(&from).map_or(0, VecDeque::len);
+        let longest = by_source
+            .iter()
+            .filter(|(_, queue)| queue.len() > own_len)
+            .max_by_key(|(_, queue)| queue.len())
+            .map(|(source, _)| *source);
+        let longest = match longest {
+            Some(longest) => longest,
+            None => return false,
+        };
+
+        let queue = by_source
+            .get_mut(&longest)
+            .expect("longest source must be queued");
+        queue.pop_front();
+        // The source keeps its turn if it still has packets queued,
+        // otherwise it is forgotten so `pop` never lands on an empty queue.
+        if queue.is_empty() {
+            by_source.remove(&longest);
+            order.retain(|source| *source != longest);
+        }
+        true
+    }
+}
+
+/// The sending half of a packet queue.
+pub(super) struct PacketSender(Arc<Shared>);
+
+impl PacketSender {
+    /// Queues a packet, applying the overload policy if the queue is full.
+    pub async fn send(&self, packet: QueuedPacket) -> Result<(), Closed> {
+        let shared = &self.0;
+        loop {
+            {
+                let mut state = shared.state.lock();
+                if state.receiver_closed {
+                    return Err(Closed);
+                }
+
+                if state.len < shared.capacity {
+                    state.packets.push(packet);
+                    state.len += 1;
+                    drop(state);
+                    shared.pushed.notify_one();
+                    return Ok(());
+                }
+
+                match shared.policy {
+                    OverloadPolicy::Block => {}
+                    OverloadPolicy::DropNewest => {
+                        shared.dropped.inc();
+                        return Ok(());
+                    }
+                    OverloadPolicy::DropOldest => {
+                        state.packets.pop();
+                        state.packets.push(packet);
+                        shared.dropped.inc();
+                        return Ok(());
+                    }
+                    OverloadPolicy::Fair => {
+                        if state.packets.drop_from_longest(packet.0) {
+                            state.packets.push(packet);
+                        }
+                        shared.dropped.inc();
+                        return Ok(());
+                    }
+                }
+            }
+            // Only reached by `Block`, wait for the worker to make room.
+            shared.popped.notified().await;
+        }
+    }
+}
+
+impl Drop for PacketSender {
+    fn drop(&mut self) {
+        self.0.state.lock().sender_closed = true;
+        self.0.pushed.notify_one();
+    }
+}
+
+/// The receiving half of a packet queue.
+pub(super) struct PacketReceiver(Arc<Shared>);
+
+impl PacketReceiver {
+    /// Takes the next packet off the queue, waiting for one if it is empty.
+    /// Returns `None` once the queue is empty and the sender was dropped.
+    pub async fn recv(&mut self) -> Option<QueuedPacket> {
+        let shared = &self.0;
+        loop {
+            {
+                let mut state = shared.state.lock();
+                if let Some(packet) = state.packets.pop() {
+                    state.len -= 1;
+                    drop(state);
+                    shared.popped.notify_one();
+                    return Some(packet);
+                }
+                if state.sender_closed {
+                    return None;
+                }
+            }
+            shared.pushed.notified().await;
+        }
+    }
+}
+
+impl Drop for PacketReceiver {
+    fn drop(&mut self) {
+        self.0.state.lock().receiver_closed = true;
+        self.0.popped.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use prometheus::IntCounter;
+    use tokio::time::{timeout, Duration, Instant};
+
+    use crate::config::OverloadPolicy;
+
+    use super::{channel, PacketReceiver, QueuedPacket};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn packet(port: u16, contents: &'static [u8]) -> QueuedPacket {
+        (addr(port), Bytes::from_static(contents), Instant::now())
+    }
+
+    async fn drain(mut rx: PacketReceiver) -> Vec<(SocketAddr, Bytes)> {
+        let mut packets = vec![];
+        while let Some((from, contents, _)) = rx.recv().await {
+            packets.push((from, contents));
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn block() {
+        let dropped = IntCounter::new("dropped", "dropped").unwrap();
+        let (tx, mut rx) = channel(1, OverloadPolicy::Block, dropped.clone());
+        tx.send(packet(1, b"a")).await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(100), tx.send(packet(1, b"b")))
+                .await
+                .is_err()
+        );
+
+        let send = tokio::spawn(async move {
+            tx.send(packet(1, b"c")).await.unwrap();
+        });
+        let (from, contents, _) = rx.recv().await.unwrap();
+        assert_eq!((addr(1), Bytes::from_static(b"a")), (from, contents));
+        send.await.unwrap();
+        assert_eq!(vec![(addr(1), Bytes::from_static(b"c"))], drain(rx).await);
+        assert_eq!(0, dropped.get());
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        let dropped = IntCounter::new("dropped", "dropped").unwrap();
+        let (tx, rx) = channel(2, OverloadPolicy::DropNewest, dropped.clone());
+        for contents in &[b"a", b"b", b"c"] {
+            tx.send(packet(1, *contents)).await.unwrap();
+        }
+        drop(tx);
+        assert_eq!(
+            vec![
+                (addr(1), Bytes::from_static(b"a")),
+                (addr(1), Bytes::from_static(b"b"))
+            ],
+            drain(rx).await
+        );
+        assert_eq!(1, dropped.get());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let dropped = IntCounter::new("dropped", "dropped").unwrap();
+        let (tx, rx) = channel(2, OverloadPolicy::DropOldest, dropped.clone());
+        for contents in &[b"a", b"b", b"c"] {
+            tx.send(packet(1, *contents)).await.unwrap();
+        }
+        drop(tx);
+        assert_eq!(
+            vec![
+                (addr(1), Bytes::from_static(b"b")),
+                (addr(1), Bytes::from_static(b"c"))
+            ],
+            drain(rx).await
+        );
+        assert_eq!(1, dropped.get());
+    }
+
+    #[tokio::test]
+    async fn fair() {
+        let dropped = IntCounter::new("dropped", "dropped").unwrap();
+        let (tx, rx) = channel(4, OverloadPolicy::Fair, dropped.clone());
+        // A noisy client fills up the queue.
+        for contents in &[b"a", b"b", b"c", b"d"] {
+            tx.send(packet(1, *contents)).await.unwrap();
+        }
+        // Another client still gets its packets queued, at the expense of
+        // the noisy one.
+        tx.send(packet(2, b"x")).await.unwrap();
+        tx.send(packet(2, b"y")).await.unwrap();
+        // Once both have as many queued, new packets are dropped.
+        tx.send(packet(2, b"z")).await.unwrap();
+        drop(tx);
+
+        assert_eq!(
+            vec![
+                (addr(1), Bytes::from_static(b"c")),
+                (addr(2), Bytes::from_static(b"x")),
+                (addr(1), Bytes::from_static(b"d")),
+                (addr(2), Bytes::from_static(b"y")),
+            ],
+            drain(rx).await
+        );
+        assert_eq!(3, dropped.get());
+
+        let dropped = IntCounter::new("dropped", "dropped").unwrap();
+        let (tx, rx) = channel(2, OverloadPolicy::Fair, dropped.clone());
+        // With one packet per source, a new source takes the place of the
+        // only packet of another.
+        tx.send(packet(1, b"a")).await.unwrap();
+        tx.send(packet(2, b"b")).await.unwrap();
+        tx.send(packet(3, b"c")).await.unwrap();
+        drop(tx);
+
+        let packets = drain(rx).await;
+        assert_eq!(2, packets.len());
+        assert!(packets.contains(&(addr(3), Bytes::from_static(b"c"))));
+        assert_eq!(1, dropped.get());
+    }
+
+    #[tokio::test]
+    async fn receiver_dropped() {
+        let dropped = IntCounter::new("dropped", "dropped").unwrap();
+        let (tx, rx) = channel(1, OverloadPolicy::Block, dropped);
+        drop(rx);
+        assert!(tx.send(packet(1, b"a")).await.is_err());
+    }
+}
@@ -0,0 +1,289 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reloading of the static endpoints and filters whenever the configuration
+//! file they were loaded from changes.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use slog::{debug, info, o, warn, Logger};
+use tokio::sync::{mpsc, watch};
+
+use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::config::{Config, Endpoints, Proxy, Source};
+use crate::filters::manager::{filter_chain_event, SharedFilterManager};
+use crate::filters::{FilterChain, FilterRegistry};
+use crate::proxy::builder::{ValidatedConfig, ValidatedSource};
+use crate::proxy::events::Event;
+use crate::proxy::Metrics;
+
+/// How long the file has to be left alone before changes are applied, so
+/// that an editor writing it in several steps only causes a single reload.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(500);
+
+/// Represents arguments to the [`spawn`] function.
+pub(super) struct ReloadArgs {
+    pub log: Logger,
+    pub path: PathBuf,
+    /// The configuration the proxy is currently running with.
+    pub config: Arc<Config>,
+    pub filter_registry: FilterRegistry,
+    pub metrics: Arc<Metrics>,
+    pub cluster_manager: SharedClusterManager,
+    pub filter_manager: SharedFilterManager,
+    pub shutdown_rx: watch::Receiver<()>,
+}
+
+/// Starts watching the configuration file, applying changes to its static
+/// endpoints and filters until shutdown.
+pub(super) fn spawn(args: ReloadArgs) -> notify::Result<()> {
+    let log = args.log.new(o!("source" => "proxy::ConfigReload"));
+
+    // Watch the directory rather than the file itself, since editors and
+    // Kubernetes ConfigMaps replace the file instead of writing to it.
+    let dir = match args.path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => PathBuf::from("."),
+        Some(dir) => dir.to_owned(),
+        None => args.path.clone(),
+    };
+    let (event_tx, event_rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(event_tx, DEBOUNCE_DELAY)?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    // notify delivers events on a blocking channel, so forward them from a
+    // thread of their own. The thread exits along with the watcher once the
+    // reload task is gone.
+    let (changed_tx, mut changed_rx) = mpsc::channel(1);
+    std::thread::Builder::new()
+        .name("quilkin-config-watcher".into())
+        .spawn(move || {
+            let _watcher = watcher;
+            for event in event_rx {
+                match event {
+                    DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => continue,
+                    _ => {}
+                }
+                // A reload is already pending if the channel is full.
+                if let Err(mpsc::error::TrySendError::Closed(_)) = changed_tx.try_send(()) {
+                    return;
+                }
+            }
+        })?;
+
+    let ReloadArgs {
+        path,
+        mut config,
+        filter_registry,
+        metrics,
+        cluster_manager,
+        filter_manager,
+        mut shutdown_rx,
+        ..
+    } = args;
+    tokio::spawn(async move {
+        info!(log, "Watching configuration file for changes"; "path" => %path.display());
+        loop {
+            tokio::select! {
+                Some(()) = changed_rx.recv() => {
+                    let reload = match load(&path, &config, &filter_registry, &metrics) {
+                        Ok(Some(reload)) => reload,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            warn!(log, "Keeping the current configuration: failed to reload the configuration file"; "path" => %path.display(), "error" => %err);
+                            continue;
+                        }
+                    };
+                    for deprecation in reload.config.deprecations() {
+                        warn!(log, "The configuration uses a deprecated format, please update it"; "deprecation" => deprecation);
+                    }
+                    if reload.proxy_changed {
+                        warn!(log, "Changes to the `proxy` section of the configuration file only take effect after a restart");
+                    }
+
+                    // Hold both locks so that packets never see the new
+                    // endpoints together with the old filters or vice versa.
+                    let mut changes = Vec::new();
+                    {
+                        let mut cluster_manager = cluster_manager.write();
+                        let mut filter_manager = filter_manager.write();
+                        let endpoints_before = cluster_manager.get_all_endpoints().map_or(0, |endpoints| endpoints.size());
+                        changes.push(Event::new("cluster.update", "Replaced the endpoints from the configuration file")
+                            .with("endpoints_before", endpoints_before)
+                            .with("endpoints_after", reload.endpoints.as_ref().len()));
+                        cluster_manager.set_endpoints(reload.endpoints);
+                        if let Some(filter_chain) = reload.filter_chain {
+                            changes.push(filter_chain_event(&filter_manager.get_filter_chain(), &filter_chain));
+                            filter_manager.set_filter_chain(filter_chain);
+                        }
+                    }
+                    info!(log, "Applied changes to the configuration file"; "path" => %path.display());
+                    for event in changes {
+                        metrics.events.record(&log, event);
+                    }
+                    config = Arc::new(reload.config);
+                }
+                _ = shutdown_rx.changed() => {
+                    debug!(log, "Stopped watching configuration file");
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The changes to apply after the configuration file changed.
+struct Reload {
+    config: Config,
+    endpoints: Endpoints,
+    /// Only set if the filters changed, as recreating the filter chain
+    /// resets any state its filters keep.
+    filter_chain: Option<Arc<FilterChain>>,
+    /// Whether the `proxy` section changed, which is not applied.
+    proxy_changed: bool,
+}
+
+/// Loads and validates the configuration at `path`, returning `None` if its
+/// static endpoints and filters are the same as in `current`.
+fn load(
+    path: &Path,
+    current: &Config,
+    filter_registry: &FilterRegistry,
+    metrics: &Metrics,
+) -> Result<Option<Reload>, String> {
+    let config = Config::from_file(path).map_err(|err| err.to_string())?;
+    if config.source == current.source {
+        return Ok(None);
+    }
+    let filters_changed = match (&current.source, &config.source) {
+        (
+            Source::Static {
+                filters: current_filters,
+                ..
+            },
+            Source::Static { filters, .. },
+        ) => current_filters != filters,
+        _ => return Err("switching from `static` to another source requires a restart".into()),
+    };
+    // The id is generated anew on every load unless it is set explicitly,
+    // and the runtime may have been overridden on the command line.
+    let proxy_changed = Proxy {
+        id: current.proxy.id.clone(),
+        runtime: current.proxy.runtime.clone(),
+        ..config.proxy.clone()
+    } != current.proxy;
+
+    let validated = ValidatedConfig::validate(Arc::new(config.clone()), filter_registry, metrics)
+        .map_err(|err| err.to_string())?;
+    match validated.source {
+        ValidatedSource::Static {
+            filter_chain,
+            endpoints,
+        } => Ok(Some(Reload {
+            config,
+            endpoints,
+            filter_chain: if filters_changed {
+                Some(filter_chain)
+            } else {
+                None
+            },
+            proxy_changed,
+        })),
+        _ => unreachable!("the source was checked to be static"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use prometheus::Registry;
+    use uuid::Uuid;
+
+    use crate::config::Config;
+    use crate::filters::{FilterRegistry, FilterSet};
+    use crate::proxy::Metrics;
+    use crate::test_utils::logger;
+
+    use super::load;
+
+    const CONFIG: &str = "
+version: v1alpha1
+static:
+  filters:
+    - name: quilkin.extensions.filters.debug.v1alpha1.Debug
+  endpoints:
+    - address: 127.0.0.1:26000
+";
+
+    #[test]
+    fn load_changes() {
+        let log = logger();
+        let filter_registry = FilterRegistry::new(FilterSet::default(&log));
+        let metrics = Metrics::new(&log, Registry::default());
+        let current = Config::from_reader(CONFIG.as_bytes()).unwrap();
+        let path = std::env::temp_dir().join(format!("quilkin-{}.yaml", Uuid::new_v4()));
+        let write_and_load = |contents: &str| {
+            std::fs::write(&path, contents).unwrap();
+            load(&path, &current, &filter_registry, &metrics)
+        };
+
+        assert!(write_and_load(CONFIG).unwrap().is_none());
+
+        // Only the endpoints changed, so the filters keep their state.
+        let reload = write_and_load(&CONFIG.replace("26000", "26001"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "127.0.0.1:26001".parse::<SocketAddr>().unwrap(),
+            reload.endpoints.as_ref()[0].address
+        );
+        assert!(reload.filter_chain.is_none());
+        assert!(!reload.proxy_changed);
+
+        let reload = write_and_load(&CONFIG.replace("Debug", "Debug\n      config: {}"))
+            .unwrap()
+            .unwrap();
+        assert!(reload.filter_chain.is_some());
+
+        let reload = write_and_load(&format!(
+            "proxy:\n  port: 7001{}",
+            CONFIG.replace("26000", "26001")
+        ))
+        .unwrap()
+        .unwrap();
+        assert!(reload.proxy_changed);
+
+        // Invalid configurations are rejected.
+        assert!(write_and_load("version: v1alpha1\nstatic: {}").is_err());
+        assert!(write_and_load(&CONFIG.replace("Debug", "Unknown")).is_err());
+        assert!(write_and_load(
+            "
+version: v1alpha1
+dynamic:
+  management_servers:
+    - address: http://127.0.0.1:18000
+"
+        )
+        .is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(load(&path, &current, &filter_registry, &metrics).is_err());
+    }
+}
@@ -20,8 +20,10 @@ use crate::filters::{
     manager::{FilterManager, ListenerManagerArgs, SharedFilterManager},
     FilterChain, FilterRegistry,
 };
+use crate::proxy::events::EventHistory;
+use crate::proxy::tracing::Tracer;
 use crate::xds::ads_client::{
-    AdsClient, ClusterUpdate, ExecutionResult, UPDATES_CHANNEL_BUFFER_SIZE,
+    AdsClient, ClientState, ClusterUpdate, ExecutionResult, Node, UPDATES_CHANNEL_BUFFER_SIZE,
 };
 use prometheus::Registry;
 use slog::{debug, o, warn, Logger};
@@ -40,6 +42,7 @@ pub(super) struct DynamicResourceManagers {
     pub(super) cluster_manager: SharedClusterManager,
     pub(super) filter_manager: SharedFilterManager,
     pub(super) execution_result_rx: oneshot::Receiver<ExecutionResult>,
+    pub(super) xds_state: ClientState,
 }
 
 impl StaticResourceManagers {
@@ -60,7 +63,9 @@ impl StaticResourceManagers {
 struct SpawnAdsClient {
     log: Logger,
     metrics_registry: Registry,
-    node_id: String,
+    events: EventHistory,
+    tracer: Tracer,
+    node: Node,
     management_servers: Vec<ManagementServer>,
     cluster_updates_tx: mpsc::Sender<ClusterUpdate>,
     listener_manager_args: ListenerManagerArgs,
@@ -71,8 +76,10 @@ struct SpawnAdsClient {
 impl DynamicResourceManagers {
     pub(super) async fn new(
         base_logger: Logger,
-        xds_node_id: String,
+        xds_node: Node,
         metrics_registry: Registry,
+        events: EventHistory,
+        tracer: Tracer,
         filter_registry: FilterRegistry,
         management_servers: Vec<ManagementServer>,
         mut shutdown_rx: watch::Receiver<()>,
@@ -90,10 +97,12 @@ impl DynamicResourceManagers {
         );
 
         let (execution_result_tx, execution_result_rx) = oneshot::channel::<ExecutionResult>();
-        Self::spawn_ads_client(SpawnAdsClient {
+        let xds_state = Self::spawn_ads_client(SpawnAdsClient {
             log: log.clone(),
             metrics_registry: metrics_registry.clone(),
-            node_id: xds_node_id,
+            events: events.clone(),
+            tracer: tracer.clone(),
+            node: xds_node,
             management_servers,
             cluster_updates_tx,
             listener_manager_args,
@@ -124,6 +133,8 @@ impl DynamicResourceManagers {
         let cluster_manager = ClusterManager::dynamic(
             base_logger.new(o!("source" => "ClusterManager")),
             &metrics_registry,
+            events.clone(),
+            tracer,
             cluster_update,
             cluster_updates_rx,
             shutdown_rx.clone(),
@@ -132,6 +143,7 @@ impl DynamicResourceManagers {
 
         let filter_manager = FilterManager::dynamic(
             base_logger.new(o!("source" => "FilterManager")),
+            events,
             filter_chain_update,
             filter_chain_updates_rx,
             shutdown_rx.clone(),
@@ -141,17 +153,21 @@ impl DynamicResourceManagers {
             cluster_manager,
             filter_manager,
             execution_result_rx,
+            xds_state,
         })
     }
 
     // Spawns a task that runs an ADS client.
     // Cluster and Filter updates from the client
     // as well as execution result after termination are sent on the passed-in channels.
-    fn spawn_ads_client(args: SpawnAdsClient) -> Result<(), InitializeError> {
+    // Returns the state of the client.
+    fn spawn_ads_client(args: SpawnAdsClient) -> Result<ClientState, InitializeError> {
         let SpawnAdsClient {
             log,
             metrics_registry,
-            node_id,
+            events,
+            tracer,
+            node,
             management_servers,
             cluster_updates_tx,
             listener_manager_args,
@@ -159,13 +175,15 @@ impl DynamicResourceManagers {
             shutdown_rx,
         } = args;
 
-        let client = AdsClient::new(log.clone(), &metrics_registry).map_err(|err| {
-            InitializeError::Message(format!("failed to initialize xDS client: {:?}", err))
-        })?;
+        let client =
+            AdsClient::new(log.clone(), &metrics_registry, events, tracer).map_err(|err| {
+                InitializeError::Message(format!("failed to initialize xDS client: {:?}", err))
+            })?;
+        let state = client.state();
         tokio::spawn(async move {
             let result = client
                 .run(
-                    node_id,
+                    node,
                     management_servers,
                     cluster_updates_tx,
                     listener_manager_args,
@@ -178,7 +196,7 @@ impl DynamicResourceManagers {
                 .ok();
         });
 
-        Ok(())
+        Ok(state)
     }
 
     // Waits until it receives a cluster update from the given channel.
@@ -233,7 +251,7 @@ mod tests {
     use crate::config::ManagementServer;
     use crate::filters::{manager::ListenerManagerArgs, FilterRegistry};
     use crate::test_utils::logger;
-    use crate::xds::ads_client::ExecutionError;
+    use crate::xds::ads_client::{node, ExecutionError};
 
     use std::time::Duration;
 
@@ -347,7 +365,7 @@ mod tests {
         DynamicResourceManagers::spawn_ads_client(SpawnAdsClient {
             log: logger(),
             metrics_registry: Registry::default(),
-            node_id: "id".into(),
+            node: node("id".into(), None),
             management_servers: vec![ManagementServer {
                 address: "invalid-address".into(),
             }],
@@ -0,0 +1,505 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Deterministic simulations of how a proxy routes packets.
+//!
+//! A [`Simulation`] passes packets through the same filter chain, session
+//! table and cluster of endpoints as a running proxy, but on Tokio's paused
+//! clock rather than in real time, and with the random choices of filters
+//! such as `LoadBalancer` seeded. Sessions expire, and endpoints come and go,
+//! at exactly the same points of every run with the same seed, so behaviours
+//! that depend on timing can be tested reproducibly.
+//!
+//! ```
+//! # #[tokio::main(flavor = "current_thread", start_paused = true)]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::net::SocketAddr;
+//! use std::time::Duration;
+//!
+//! use quilkin::config::{Builder as ConfigBuilder, EndPoint};
+//! use quilkin::proxy::sim::Simulation;
+//!
+//! let endpoint: SocketAddr = "127.0.0.1:7001".parse()?;
+//! let config = ConfigBuilder::empty()
+//!     .with_static(vec![], vec![EndPoint::new(endpoint)])
+//!     .build();
+//! let server = quilkin::Builder::from(config).disable_admin().validate()?.build();
+//! let sim = Simulation::new(42, server).await?;
+//!
+//! let client = "127.0.0.1:7000".parse()?;
+//! assert_eq!(vec![endpoint], sim.receive(client, "hello").await);
+//!
+//! // Sessions expire once they have been idle for a minute.
+//! sim.advance(Duration::from_secs(120)).await;
+//! assert!(sim.sessions().await.is_empty());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::config::{Endpoint, Endpoints};
+use crate::proxy::builder::ValidatedSource;
+use crate::proxy::debug_sampling::Sampler;
+use crate::proxy::sessions::persistence;
+use crate::proxy::sessions::session_manager::SessionManager;
+use crate::proxy::sessions::Packet;
+use crate::utils::{clock, random};
+
+use super::{Error, ProcessDownstreamReceiveConfig, Result, RunRecvFromArgs, Server};
+
+/// The time since the Unix epoch that simulations start at, so that session
+/// expiration times are the same whichever day they run on.
+const EPOCH: Duration = Duration::from_secs(1_600_000_000);
+
+/// One step of a scripted [`Simulation`].
+#[derive(Clone, Debug)]
+pub enum Step {
+    /// A packet is received from a client.
+    Receive(SocketAddr, Bytes),
+    /// The endpoints of the cluster are replaced, e.g to add new game servers
+    /// or to drain old ones.
+    SetEndpoints(Endpoints),
+    /// Time passes.
+    Advance(Duration),
+    /// The proxy restarts, restoring its sessions like a proxy with
+    /// `proxy.sessionPersistence` set does.
+    Restart,
+}
+
+/// Where a packet received during a [`Simulation`] was sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Routed {
+    /// How long after the start of the simulation the packet was received.
+    pub at: Duration,
+    pub from: SocketAddr,
+    /// The endpoints the packet was sent to, in order of address.
+    pub to: Vec<SocketAddr>,
+}
+
+/// A proxy whose clock, random choices and endpoints are controlled by a test.
+///
+/// It has to be created on a runtime whose clock started out paused, such as
+/// one returned by [`runtime`], and only one simulation can run on each
+/// runtime. Packets are really sent to the endpoints, so their addresses must
+/// be reachable, e.g loopback addresses, although nothing needs to listen on
+/// them.
+pub struct Simulation {
+    server: Server,
+    args: ProcessDownstreamReceiveConfig,
+    /// Stands in for the listening socket of the proxy when restoring
+    /// sessions, as packets are passed to the proxy directly instead.
+    socket: Arc<UdpSocket>,
+    /// Stops the pruning of the current session table.
+    shutdown_tx: watch::Sender<()>,
+    /// Receives the responses of endpoints, which are ignored.
+    _recv_packets: mpsc::Receiver<Packet>,
+    start: Instant,
+}
+
+impl Simulation {
+    /// Returns a simulation of `server`, whose random choices are seeded with
+    /// `seed`. The server must have static endpoints.
+    pub async fn new(seed: u64, server: Server) -> Result<Self> {
+        if !matches!(server.config.source, ValidatedSource::Static { .. }) {
+            return Err(Error::Initialize(
+                "simulations only support static endpoints".into(),
+            ));
+        }
+        clock::simulate(Some(EPOCH));
+        random::seed(Some(seed));
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let (cluster_manager, filter_manager, _) = server
+            .create_resource_managers(None, shutdown_rx.clone())
+            .await?;
+        let (send_packets, recv_packets) = mpsc::channel(1);
+        let proxy = &server.config.proxy;
+        let args = ProcessDownstreamReceiveConfig {
+            log: server.log.clone(),
+            proxy_metrics: server.proxy_metrics.clone(),
+            session_metrics: server.session_metrics.clone(),
+            cluster_manager,
+            filter_manager,
+            session_manager: SessionManager::new(
+                server.log.clone(),
+                Default::default(),
+                shutdown_rx,
+            ),
+            session_ttl: proxy.session_timeout,
+            session_limits: proxy.session_limits.clone(),
+            upstream: proxy.upstream.clone(),
+            send_packets,
+            restored_routes: None,
+            demultiplex: false,
+            multiplexer: None,
+            sampler: Sampler::new(proxy.debug_sampling),
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .map_err(Error::Bind)?;
+        Ok(Self {
+            server,
+            args,
+            socket: Arc::new(socket),
+            shutdown_tx,
+            _recv_packets: recv_packets,
+            start: Instant::now(),
+        })
+    }
+
+    /// Returns how much time has passed since the start of the simulation.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Passes a packet from `from` through the proxy, and returns the
+    /// addresses of the endpoints it was sent to. Empty packets are not
+    /// counted as sent.
+    pub async fn receive(&self, from: SocketAddr, contents: impl Into<Bytes>) -> Vec<SocketAddr> {
+        let before = self.tx_bytes(from).await;
+        without_advancing(Server::process_downstream_received_packet(
+            (from, contents.into(), Instant::now()),
+            &self.args,
+        ))
+        .await;
+        let mut to = self
+            .tx_bytes(from)
+            .await
+            .into_iter()
+            .filter(|(endpoint, bytes)| before.get(endpoint) != Some(bytes))
+            .map(|(endpoint, _)| endpoint)
+            .collect::<Vec<_>>();
+        to.sort();
+        to
+    }
+
+    /// Returns how many bytes were sent through each session of `from`, by
+    /// endpoint address.
+    async fn tx_bytes(&self, from: SocketAddr) -> HashMap<SocketAddr, u64> {
+        self.args
+            .session_manager
+            .get_sessions()
+            .await
+            .iter()
+            .filter(|((client, _, _), _)| *client == from)
+            .map(|((_, _, endpoint), session)| (*endpoint, session.tx_bytes()))
+            .collect()
+    }
+
+    /// Replaces the endpoints of the cluster.
+    pub fn set_endpoints(&self, endpoints: Endpoints) {
+        self.args.cluster_manager.write().set_endpoints(endpoints);
+    }
+
+    /// Lets `duration` pass, running everything due in the meantime, such as
+    /// pruning expired sessions, at the time it is due. Time passes in whole
+    /// milliseconds, like Tokio's timers.
+    pub async fn advance(&self, duration: Duration) {
+        // While the clock is paused, Tokio moves it straight to the next
+        // timer whenever there is nothing else to do.
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Returns the sessions of the proxy, as `(client, endpoint)` pairs in
+    /// order.
+    pub async fn sessions(&self) -> Vec<(SocketAddr, SocketAddr)> {
+        let mut sessions = self
+            .args
+            .session_manager
+            .get_sessions()
+            .await
+            .keys()
+            .map(|(from, _, endpoint)| (*from, *endpoint))
+            .collect::<Vec<_>>();
+        sessions.sort();
+        sessions
+    }
+
+    /// Restarts the proxy, which restores its sessions from a snapshot and
+    /// keeps sending the packets of their clients to the same endpoints while
+    /// they are alive. The endpoints and filters stay as they are.
+    pub async fn restart(&mut self) -> Result<()> {
+        let port = self.server.config.proxy.port;
+        let entries = persistence::snapshot(port, &self.args.session_manager).await;
+        self.shutdown_tx.send(()).ok();
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let (_drain_tx, drain_rx) = watch::channel(());
+        let session_manager = SessionManager::new(
+            self.server.log.clone(),
+            Default::default(),
+            shutdown_rx.clone(),
+        );
+        let args = RunRecvFromArgs {
+            cluster_manager: self.args.cluster_manager.clone(),
+            filter_manager: self.args.filter_manager.clone(),
+            socket: self.socket.clone(),
+            session_manager: session_manager.clone(),
+            session_ttl: self.args.session_ttl,
+            send_packets: self.args.send_packets.clone(),
+            shutdown_rx,
+            drain_rx,
+            restored_routes: None,
+            multiplexer: None,
+        };
+        self.args.restored_routes = Some(self.server.restore_sessions(entries.iter(), &args).await);
+        self.args.session_manager = session_manager;
+        self.shutdown_tx = shutdown_tx;
+        Ok(())
+    }
+
+    /// Runs each step of `script` in turn, and returns where the packets it
+    /// received were sent.
+    pub async fn run(&mut self, script: impl IntoIterator<Item = Step>) -> Result<Vec<Routed>> {
+        let mut routed = vec![];
+        for step in script {
+            match step {
+                Step::Receive(from, contents) => {
+                    let to = self.receive(from, contents).await;
+                    routed.push(Routed {
+                        at: self.elapsed(),
+                        from,
+                        to,
+                    });
+                }
+                Step::SetEndpoints(endpoints) => self.set_endpoints(endpoints),
+                Step::Advance(duration) => self.advance(duration).await,
+                Step::Restart => self.restart().await?,
+            }
+        }
+        Ok(routed)
+    }
+}
+
+/// Runs `future` without the paused clock moving on while it waits for
+/// sockets, e.g for a new session's socket to be ready to send on.
+///
+/// Tokio moves a paused clock to the next timer whenever there are no tasks
+/// to run, even if some are about to be woken by a socket, so `future` is
+/// raced against a task that is always ready to run again.
+async fn without_advancing<F: Future>(future: F) -> F::Output {
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            biased;
+            output = &mut future => return output,
+            _ = tokio::task::yield_now() => {}
+        }
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        self.shutdown_tx.send(()).ok();
+        clock::simulate(None);
+        random::seed(None);
+    }
+}
+
+/// Returns a runtime to run a [`Simulation`] on, whose clock starts out
+/// paused.
+///
+/// Tokio's timers fire on whole milliseconds since its runtime started, which
+/// only line up with the paused clock if it was paused from the start. The
+/// same amount of time then always passes in the same steps.
+pub fn runtime() -> io::Result<Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+}
+
+/// Returns a script of `len` steps generated from `seed`, in which `clients`
+/// send packets while up to a minute passes between them, and the cluster is
+/// changed to random subsets of `endpoints` along the way. Neither `clients`
+/// nor `endpoints` may be empty.
+pub fn script(seed: u64, clients: &[SocketAddr], endpoints: &[Endpoint], len: usize) -> Vec<Step> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len)
+        .map(|_| match rng.gen_range(0..10) {
+            0..=6 => Step::Receive(
+                clients[rng.gen_range(0..clients.len())],
+                Bytes::from_static(b"hello"),
+            ),
+            7 | 8 => Step::Advance(Duration::from_millis(rng.gen_range(0..60_000))),
+            _ => {
+                let mut subset = endpoints
+                    .iter()
+                    .filter(|_| rng.gen_bool(0.5))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if subset.is_empty() {
+                    subset.push(endpoints[rng.gen_range(0..endpoints.len())].clone());
+                }
+                Step::SetEndpoints(
+                    Endpoints::new(subset).expect("BUG: the subset should never be empty"),
+                )
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use crate::config::{Builder as ConfigBuilder, EndPoint, Endpoint, Endpoints, Filter};
+    use crate::proxy::{Builder, Server};
+    use crate::test_utils::logger;
+
+    use super::{runtime, script, Routed, Simulation, Step};
+
+    fn server(policy: &str, endpoints: &[SocketAddr]) -> Server {
+        let config = ConfigBuilder::empty()
+            .with_static(
+                vec![Filter {
+                    name: "quilkin.extensions.filters.load_balancer.v1alpha1.LoadBalancer".into(),
+                    config: Some(serde_yaml::from_str(&format!("policy: {}", policy)).unwrap()),
+                }],
+                endpoints
+                    .iter()
+                    .map(|address| EndPoint::new(*address))
+                    .collect(),
+            )
+            .build();
+        Builder::from(config)
+            .with_log(logger())
+            .disable_admin()
+            .validate()
+            .unwrap()
+            .build()
+    }
+
+    fn addresses(ports: &[u16]) -> Vec<SocketAddr> {
+        ports
+            .iter()
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], *port)))
+            .collect()
+    }
+
+    fn endpoints(ports: &[u16]) -> Endpoints {
+        Endpoints::new(
+            addresses(ports)
+                .into_iter()
+                .map(Endpoint::from_address)
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    /// Runs a simulation of `script` on a runtime of its own.
+    fn simulate(seed: u64, policy: &str, script: Vec<Step>) -> Vec<Routed> {
+        runtime().unwrap().block_on(async {
+            let server = server(policy, &addresses(&[10001, 10002, 10003]));
+            let mut sim = Simulation::new(seed, server).await.unwrap();
+            sim.run(script).await.unwrap()
+        })
+    }
+
+    #[test]
+    fn reproducible() {
+        let clients = addresses(&[20001, 20002, 20003]);
+        let endpoints = endpoints(&[10001, 10002, 10003, 10004]);
+        let script = script(7, &clients, endpoints.as_ref(), 200);
+
+        let routed = simulate(1, "RANDOM", script.clone());
+        assert_eq!(routed, simulate(1, "RANDOM", script.clone()));
+        assert_ne!(routed, simulate(2, "RANDOM", script.clone()));
+        assert!(routed.iter().all(|routed| routed.to.len() == 1));
+
+        let routed = simulate(1, "ROUND_ROBIN", script.clone());
+        assert_eq!(routed, simulate(2, "ROUND_ROBIN", script));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_expire() {
+        let endpoints = addresses(&[10001, 10002]);
+        let client = "127.0.0.1:20001".parse().unwrap();
+        let sim = Simulation::new(0, server("ROUND_ROBIN", &endpoints))
+            .await
+            .unwrap();
+
+        assert_eq!(vec![endpoints[0]], sim.receive(client, "hello").await);
+        sim.advance(Duration::from_secs(30)).await;
+        assert_eq!(vec![endpoints[1]], sim.receive(client, "hello").await);
+        assert_eq!(
+            vec![(client, endpoints[0]), (client, endpoints[1])],
+            sim.sessions().await
+        );
+
+        // Sessions are pruned every minute, once they have expired.
+        sim.advance(Duration::from_secs(31)).await;
+        assert_eq!(vec![(client, endpoints[1])], sim.sessions().await);
+        sim.advance(Duration::from_secs(58)).await;
+        assert_eq!(vec![(client, endpoints[1])], sim.sessions().await);
+        sim.advance(Duration::from_secs(2)).await;
+        assert!(sim.sessions().await.is_empty());
+        assert_eq!(Duration::from_secs(121), sim.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restored_sessions_are_sticky() {
+        let endpoints = addresses(&[10001, 10002, 10003]);
+        let clients = addresses(&[20001, 20002]);
+        let mut sim = Simulation::new(0, server("ROUND_ROBIN", &endpoints))
+            .await
+            .unwrap();
+
+        assert_eq!(vec![endpoints[0]], sim.receive(clients[0], "hello").await);
+        assert_eq!(vec![endpoints[1]], sim.receive(clients[1], "hello").await);
+        sim.restart().await.unwrap();
+        assert_eq!(
+            vec![(clients[0], endpoints[0]), (clients[1], endpoints[1])],
+            sim.sessions().await
+        );
+
+        // Clients keep to their endpoints, while new ones are added.
+        sim.set_endpoints(self::endpoints(&[10001, 10002, 10003, 10004]));
+        for _ in 0..4 {
+            assert_eq!(vec![endpoints[0]], sim.receive(clients[0], "hello").await);
+            assert_eq!(vec![endpoints[1]], sim.receive(clients[1], "hello").await);
+            sim.advance(Duration::from_secs(20)).await;
+        }
+
+        // Until their endpoint is drained.
+        sim.set_endpoints(self::endpoints(&[10002, 10003, 10004]));
+        assert_ne!(vec![endpoints[0]], sim.receive(clients[0], "hello").await);
+        assert_eq!(vec![endpoints[1]], sim.receive(clients[1], "hello").await);
+
+        // Or their session expires.
+        sim.advance(Duration::from_secs(120)).await;
+        let mut to = vec![];
+        for _ in 0..3 {
+            to.extend(sim.receive(clients[1], "hello").await);
+        }
+        to.sort();
+        assert_eq!(addresses(&[10002, 10003, 10004]), to);
+    }
+}
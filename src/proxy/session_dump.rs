@@ -0,0 +1,230 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Listing of the sessions the proxy is tracking, to find out which endpoint
+//! the packets of a client are sent to.
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::proxy::capture::Address;
+use crate::proxy::sessions::session_manager::SessionManager;
+
+/// The number of sessions listed if a request does not set it.
+const DEFAULT_LIMIT: usize = 100;
+/// The most sessions that can be listed by a single request.
+const MAX_LIMIT: usize = 1000;
+
+/// The sessions a request asks for.
+#[derive(Debug, Default, PartialEq)]
+struct Query {
+    downstream: Option<Address>,
+    endpoint: Option<Address>,
+    offset: usize,
+    limit: usize,
+}
+
+/// Returns a HTTP 200 response listing the sessions of `session_managers`,
+/// keyed by the port they receive packets on, as requested by `query`.
+pub(super) async fn response(
+    session_managers: &[(u16, SessionManager)],
+    query: Option<&str>,
+) -> Response<Body> {
+    let query = match parse_query(query) {
+        Ok(query) => query,
+        Err(message) => {
+            let mut response = Response::new(message.into());
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+
+    let mut sessions = Vec::new();
+    for (port, session_manager) in session_managers {
+        for session in session_manager.get_sessions().await.values() {
+            let (downstream, mux_id, endpoint) = session.key();
+            let matches = |filter: &Option<Address>, address| match filter {
+                Some(filter) => filter.matches(address),
+                None => true,
+            };
+            if !matches(&query.downstream, downstream) || !matches(&query.endpoint, endpoint) {
+                continue;
+            }
+            let entry = json!({
+                "port": port,
+                "downstream": downstream,
+                "multiplex_id": mux_id,
+                "endpoint": endpoint,
+                "age_seconds": session.age().as_secs_f64(),
+                "idle_seconds": session.idle().as_secs_f64(),
+                "rx_bytes": session.rx_bytes(),
+                "tx_bytes": session.tx_bytes(),
+            });
+            sessions.push(((*port, downstream, mux_id, endpoint), entry));
+        }
+    }
+    // Order sessions the same way for every request, so that pages don't
+    // overlap while sessions are unchanged.
+    sessions.sort_by_key(|(key, _)| *key);
+
+    let total = sessions.len();
+    let sessions: Vec<Value> = sessions
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit)
+        .map(|(_, entry)| entry)
+        .collect();
+    let body = json!({
+        "total": total,
+        "offset": query.offset,
+        "sessions": sessions,
+    });
+    let mut response = Response::new(body.to_string().into());
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+fn parse_query(query: Option<&str>) -> Result<Query, String> {
+    let mut parsed = Query {
+        limit: DEFAULT_LIMIT,
+        ..Query::default()
+    };
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match &*key {
+            "downstream" => parsed.downstream = Some(value.parse()?),
+            "endpoint" => parsed.endpoint = Some(value.parse()?),
+            "offset" => {
+                parsed.offset = value
+                    .parse()
+                    .map_err(|_| format!("invalid offset `{}`", value))?
+            }
+            "limit" => {
+                parsed.limit = match value.parse() {
+                    Ok(limit) if limit > 0 && limit <= MAX_LIMIT => limit,
+                    _ => {
+                        return Err(format!(
+                            "invalid limit `{}`, expected a number between 1 and {}",
+                            value, MAX_LIMIT
+                        ))
+                    }
+                }
+            }
+            _ => return Err(format!("unknown parameter `{}`", key)),
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use prometheus::Registry;
+    use serde_json::Value;
+    use tokio::sync::{mpsc, watch};
+
+    use super::{parse_query, response, Query};
+    use crate::cluster::Endpoint;
+    use crate::config::Upstream;
+    use crate::filters::{manager::FilterManager, FilterChain};
+    use crate::proxy::capture::Address;
+    use crate::proxy::debug_sampling::Sampler;
+    use crate::proxy::sessions::metrics::Metrics;
+    use crate::proxy::sessions::session_manager::SessionManager;
+    use crate::proxy::sessions::{Packet, Session};
+    use crate::test_utils::TestHelper;
+
+    #[tokio::test]
+    async fn list_sessions() {
+        let mut t = TestHelper::default();
+        let registry = Registry::default();
+        let metrics = Metrics::new(&registry).unwrap();
+        let filter_manager =
+            FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap()));
+        let (send, _recv) = mpsc::channel::<Packet>(1);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let session_manager = SessionManager::new(t.log.clone(), Default::default(), shutdown_rx);
+        let endpoint = t.run_echo_server().await;
+
+        for port in &[7001, 7000, 7002] {
+            let from = SocketAddr::from(([127, 0, 0, 1], *port));
+            let session = Session::new(
+                &t.log,
+                metrics.clone(),
+                filter_manager.clone(),
+                from,
+                None,
+                Endpoint::from_address(endpoint),
+                send.clone(),
+                std::time::Duration::from_secs(60),
+                &Upstream::default(),
+                None,
+                Sampler::default(),
+            )
+            .await
+            .unwrap();
+            session.send(b"hello").await.unwrap();
+            session_manager
+                .get_sessions_mut()
+                .await
+                .insert(session.key(), session);
+        }
+        let session_managers = vec![(7777, session_manager)];
+
+        let list = |query: &'static str| {
+            let session_managers = &session_managers;
+            async move {
+                let response = response(session_managers, Some(query)).await;
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                serde_json::from_slice::<Value>(&body).unwrap()
+            }
+        };
+
+        let page = list("offset=1&limit=1").await;
+        assert_eq!(3, page["total"]);
+        let sessions = page["sessions"].as_array().unwrap();
+        assert_eq!(1, sessions.len());
+        assert_eq!("127.0.0.1:7001", sessions[0]["downstream"]);
+        assert_eq!(endpoint.to_string(), sessions[0]["endpoint"]);
+        assert_eq!(7777, sessions[0]["port"]);
+        assert_eq!(5, sessions[0]["tx_bytes"]);
+
+        let page = list("downstream=127.0.0.1:7002").await;
+        assert_eq!(1, page["total"]);
+        assert_eq!("127.0.0.1:7002", page["sessions"][0]["downstream"]);
+    }
+
+    #[test]
+    fn parse_session_query() {
+        assert_eq!(
+            Query {
+                downstream: Some(Address::Ip([10, 0, 0, 1].into())),
+                endpoint: None,
+                offset: 200,
+                limit: 50,
+            },
+            parse_query(Some("downstream=10.0.0.1&offset=200&limit=50")).unwrap()
+        );
+        assert_eq!(100, parse_query(None).unwrap().limit);
+        assert!(parse_query(Some("limit=0")).is_err());
+        assert!(parse_query(Some("limit=5000")).is_err());
+        assert!(parse_query(Some("downstream=player")).is_err());
+    }
+}
@@ -14,10 +14,14 @@
  * limitations under the License.
  */
 
+#[cfg(feature = "quic")]
+pub(crate) use session::bind_upstream_socket;
 pub use session::{Packet, Session};
 pub use session_manager::SESSION_TIMEOUT_SECONDS;
 
 pub(crate) mod error;
 pub(crate) mod metrics;
+pub(crate) mod multiplex;
+pub(crate) mod persistence;
 mod session;
 pub(crate) mod session_manager;
@@ -14,9 +14,24 @@
  * limitations under the License.
  */
 
-use crate::metrics::{histogram_opts, opts, CollectorExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
 use prometheus::core::{AtomicI64, AtomicU64, GenericCounter, GenericGauge};
-use prometheus::{Histogram, IntCounter, IntGauge, Registry, Result as MetricsResult};
+use prometheus::{
+    Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry, Result as MetricsResult,
+};
+
+use crate::metrics::{histogram_opts, opts, CollectorExt};
+use crate::proxy::capture::Capture;
+use crate::proxy::tracing::Tracer;
+use crate::proxy::webhook::Notifier;
+
+/// The `address` label of the series shared by the endpoints that do not
+/// get a series of their own.
+const OTHER_ENDPOINTS: &str = "other";
 
 #[derive(Clone)]
 pub struct Metrics {
@@ -29,11 +44,39 @@ pub struct Metrics {
     pub rx_errors_total: GenericCounter<AtomicU64>,
     pub tx_errors_total: GenericCounter<AtomicU64>,
     pub packets_dropped_total: GenericCounter<AtomicU64>,
+    pub keepalives_total: GenericCounter<AtomicU64>,
     pub duration_secs: Histogram,
+    pub endpoints: EndpointMetrics,
+    pub clients: ClientMetrics,
+    /// Where the creation and expiry of sessions are posted to, if anywhere.
+    pub(crate) webhook: Option<Notifier>,
+    /// The packet capture of the proxy the sessions belong to.
+    pub(crate) capture: Arc<Capture>,
+    /// The tracer of the proxy the sessions belong to.
+    pub(crate) tracer: Tracer,
+}
+
+/// Tracks the number of downstream clients with active sessions.
+#[derive(Clone)]
+pub struct ClientMetrics {
+    pub active_clients: GenericGauge<AtomicI64>,
+    /// The number of sessions of each client.
+    sessions: Arc<Mutex<HashMap<SocketAddr, usize>>>,
 }
 
 impl Metrics {
+    #[cfg(test)]
     pub fn new(registry: &Registry) -> MetricsResult<Self> {
+        Self::with_endpoint_limit(registry, None)
+    }
+
+    /// Creates the session metrics. At most `endpoint_limit` endpoints, if
+    /// set, get series of their own in the per-endpoint metrics at a time,
+    /// see [`EndpointMetrics`].
+    pub fn with_endpoint_limit(
+        registry: &Registry,
+        endpoint_limit: Option<usize>,
+    ) -> MetricsResult<Self> {
         let subsystem = "session";
         Ok(Self {
             active_sessions: IntGauge::with_opts(opts(
@@ -90,6 +133,12 @@ impl Metrics {
                 "Total number of errors encountered while sending a packet",
             ))?
             .register_if_not_exists(registry)?,
+            keepalives_total: IntCounter::with_opts(opts(
+                "keepalives_total",
+                subsystem,
+                "Total number of keepalive packets sent",
+            ))?
+            .register_if_not_exists(registry)?,
             duration_secs: Histogram::with_opts(histogram_opts(
                 "duration_secs",
                 subsystem,
@@ -99,6 +148,359 @@ impl Metrics {
                 ]),
             ))?
             .register_if_not_exists(registry)?,
+            endpoints: EndpointMetrics::new(registry, endpoint_limit)?,
+            clients: ClientMetrics {
+                active_clients: IntGauge::with_opts(opts(
+                    "active_clients",
+                    subsystem,
+                    "Number of downstream clients with active sessions",
+                ))?
+                .register_if_not_exists(registry)?,
+                sessions: Arc::default(),
+            },
+            webhook: None,
+            capture: Arc::default(),
+            tracer: Tracer::default(),
+        })
+    }
+}
+
+impl ClientMetrics {
+    /// Counts a new session of `client`.
+    pub fn session_started(&self, client: SocketAddr) {
+        let mut sessions = self.sessions.lock();
+        let count = sessions.entry(client).or_default();
+        if *count == 0 {
+            self.active_clients.inc();
+        }
+        *count += 1;
+    }
+
+    /// Counts the end of a session of `client`.
+    pub fn session_ended(&self, client: SocketAddr) {
+        let mut sessions = self.sessions.lock();
+        if let Some(count) = sessions.get_mut(&client) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.remove(&client);
+                self.active_clients.dec();
+            }
+        }
+    }
+}
+
+/// Metrics of sessions and their traffic broken down by their upstream
+/// endpoint, labelled with the endpoint's address.
+///
+/// To keep the number of series bounded, only up to `limit` endpoints get a
+/// series of their own at a time, on a first come, first served basis. The
+/// sessions of any other endpoint are counted together under the `other`
+/// address instead. An endpoint keeps the series it started out with until its
+/// last session ends, at which point the series is removed.
+#[derive(Clone)]
+pub struct EndpointMetrics {
+    active_sessions: IntGaugeVec,
+    rx_bytes_total: IntCounterVec,
+    tx_bytes_total: IntCounterVec,
+    rx_packets_total: IntCounterVec,
+    tx_packets_total: IntCounterVec,
+    limit: Option<usize>,
+    endpoints: Arc<Mutex<Endpoints>>,
+}
+
+#[derive(Default)]
+struct Endpoints {
+    /// The number of active sessions of every endpoint, and whether the
+    /// endpoint has a series of its own.
+    sessions: HashMap<SocketAddr, (usize, bool)>,
+    /// The number of endpoints that have a series of their own.
+    labelled: usize,
+    /// The number of endpoints counted under [`OTHER_ENDPOINTS`].
+    other: usize,
+}
+
+/// The traffic counters of a single endpoint, or of the endpoints counted
+/// under [`OTHER_ENDPOINTS`].
+#[derive(Clone)]
+pub struct EndpointCounters {
+    pub rx_bytes_total: IntCounter,
+    pub tx_bytes_total: IntCounter,
+    pub rx_packets_total: IntCounter,
+    pub tx_packets_total: IntCounter,
+}
+
+impl EndpointMetrics {
+    fn new(registry: &Registry, limit: Option<usize>) -> MetricsResult<Self> {
+        let subsystem = "endpoint";
+        let counter = |name: &str, description: &str| {
+            IntCounterVec::new(opts(name, subsystem, description), &["address"])?
+                .register_if_not_exists(registry)
+        };
+        Ok(Self {
+            active_sessions: IntGaugeVec::new(
+                opts(
+                    "active_sessions",
+                    subsystem,
+                    "Number of sessions currently active per upstream endpoint",
+                ),
+                &["address"],
+            )?
+            .register_if_not_exists(registry)?,
+            rx_bytes_total: counter(
+                "rx_bytes_total",
+                "Total number of bytes received from an endpoint",
+            )?,
+            tx_bytes_total: counter(
+                "tx_bytes_total",
+                "Total number of bytes sent to an endpoint",
+            )?,
+            rx_packets_total: counter(
+                "rx_packets_total",
+                "Total number of packets received from an endpoint",
+            )?,
+            tx_packets_total: counter(
+                "tx_packets_total",
+                "Total number of packets sent to an endpoint",
+            )?,
+            limit,
+            endpoints: Arc::new(Mutex::new(Endpoints::default())),
         })
     }
+
+    /// Records a new session to the endpoint with `address`, and returns the
+    /// counters its traffic is recorded in. The session must be released
+    /// with [`EndpointMetrics::release`] once it ends.
+    pub fn acquire(&self, address: SocketAddr) -> EndpointCounters {
+        let mut endpoints = self.endpoints.lock();
+        let labelled = endpoints.labelled;
+        let limit = self.limit;
+        let own = match endpoints.sessions.get_mut(&address) {
+            Some((sessions, own)) => {
+                *sessions += 1;
+                *own
+            }
+            None => {
+                let own = !matches!(limit, Some(limit) if labelled >= limit);
+                endpoints.sessions.insert(address, (1, own));
+                if own {
+                    endpoints.labelled += 1;
+                } else {
+                    endpoints.other += 1;
+                }
+                own
+            }
+        };
+
+        let label = Self::label(own, address);
+        self.active_sessions.with_label_values(&[&label]).inc();
+        EndpointCounters {
+            rx_bytes_total: self.rx_bytes_total.with_label_values(&[&label]),
+            tx_bytes_total: self.tx_bytes_total.with_label_values(&[&label]),
+            rx_packets_total: self.rx_packets_total.with_label_values(&[&label]),
+            tx_packets_total: self.tx_packets_total.with_label_values(&[&label]),
+        }
+    }
+
+    /// Records that a session to the endpoint with `address` has ended.
+    pub fn release(&self, address: SocketAddr) {
+        let mut endpoints = self.endpoints.lock();
+        let (own, last) = match endpoints.sessions.get_mut(&address) {
+            Some((sessions, own)) => {
+                *sessions -= 1;
+                (*own, *sessions == 0)
+            }
+            None => return,
+        };
+
+        let label = Self::label(own, address);
+        self.active_sessions.with_label_values(&[&label]).dec();
+        if !last {
+            return;
+        }
+
+        endpoints.sessions.remove(&address);
+        if own {
+            endpoints.labelled -= 1;
+        } else {
+            endpoints.other -= 1;
+            if endpoints.other > 0 {
+                return;
+            }
+        }
+        let _ = self.active_sessions.remove_label_values(&[&label]);
+        for counter in &[
+            &self.rx_bytes_total,
+            &self.tx_bytes_total,
+            &self.rx_packets_total,
+            &self.tx_packets_total,
+        ] {
+            let _ = counter.remove_label_values(&[&label]);
+        }
+    }
+
+    fn label(own: bool, address: SocketAddr) -> String {
+        if own {
+            address.to_string()
+        } else {
+            OTHER_ENDPOINTS.into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::Registry;
+
+    use super::Metrics;
+
+    fn active_sessions(registry: &Registry) -> Vec<(String, i64)> {
+        let mut series = registry
+            .gather()
+            .into_iter()
+            .filter(|family| family.get_name() == "quilkin_endpoint_active_sessions")
+            .flat_map(|family| family.get_metric().to_vec())
+            .map(|metric| {
+                (
+                    metric.get_label()[0].get_value().to_string(),
+                    metric.get_gauge().get_value() as i64,
+                )
+            })
+            .collect::<Vec<_>>();
+        series.sort();
+        series
+    }
+
+    #[test]
+    fn endpoint_active_sessions() {
+        let registry = Registry::default();
+        let metrics = Metrics::new(&registry).unwrap();
+        let a = "127.0.0.1:8080".parse().unwrap();
+        let b = "127.0.0.1:8081".parse().unwrap();
+
+        metrics.endpoints.acquire(a);
+        metrics.endpoints.acquire(a);
+        metrics.endpoints.acquire(b);
+        assert_eq!(
+            vec![("127.0.0.1:8080".into(), 2), ("127.0.0.1:8081".into(), 1)],
+            active_sessions(&registry)
+        );
+
+        metrics.endpoints.release(a);
+        metrics.endpoints.release(b);
+        assert_eq!(
+            vec![("127.0.0.1:8080".into(), 1)],
+            active_sessions(&registry)
+        );
+    }
+
+    #[test]
+    fn endpoint_counters() {
+        let registry = Registry::default();
+        let metrics = Metrics::with_endpoint_limit(&registry, Some(1)).unwrap();
+        let a = "127.0.0.1:8080".parse().unwrap();
+        let b = "127.0.0.1:8081".parse().unwrap();
+        let c = "127.0.0.1:8082".parse().unwrap();
+        let series = || {
+            let mut series = registry
+                .gather()
+                .into_iter()
+                .filter(|family| family.get_name() == "quilkin_endpoint_tx_packets_total")
+                .flat_map(|family| family.get_metric().to_vec())
+                .map(|metric| {
+                    (
+                        metric.get_label()[0].get_value().to_string(),
+                        metric.get_counter().get_value() as u64,
+                    )
+                })
+                .collect::<Vec<_>>();
+            series.sort();
+            series
+        };
+
+        let first = metrics.endpoints.acquire(a);
+        let second = metrics.endpoints.acquire(a);
+        first.tx_packets_total.inc();
+        second.tx_packets_total.inc();
+        metrics.endpoints.acquire(b).tx_packets_total.inc();
+        metrics.endpoints.acquire(c).tx_packets_total.inc();
+        assert_eq!(
+            vec![("127.0.0.1:8080".into(), 2), ("other".into(), 2)],
+            series()
+        );
+
+        // The counters are kept while the endpoint has any sessions.
+        metrics.endpoints.release(a);
+        metrics.endpoints.release(b);
+        assert_eq!(
+            vec![("127.0.0.1:8080".into(), 2), ("other".into(), 2)],
+            series()
+        );
+        metrics.endpoints.release(a);
+        metrics.endpoints.release(c);
+        assert!(series().is_empty());
+    }
+
+    #[test]
+    fn endpoint_active_sessions_limit() {
+        let registry = Registry::default();
+        let metrics = Metrics::with_endpoint_limit(&registry, Some(1)).unwrap();
+        let a = "127.0.0.1:8080".parse().unwrap();
+        let b = "127.0.0.1:8081".parse().unwrap();
+        let c = "127.0.0.1:8082".parse().unwrap();
+
+        metrics.endpoints.acquire(a);
+        metrics.endpoints.acquire(b);
+        metrics.endpoints.acquire(c);
+        metrics.endpoints.acquire(c);
+        assert_eq!(
+            vec![("127.0.0.1:8080".into(), 1), ("other".into(), 3)],
+            active_sessions(&registry)
+        );
+
+        // The freed up slot goes to the next new endpoint, while the
+        // endpoints counted as other stay there.
+        metrics.endpoints.release(a);
+        let d = "127.0.0.1:8083".parse().unwrap();
+        metrics.endpoints.acquire(d);
+        assert_eq!(
+            vec![("127.0.0.1:8083".into(), 1), ("other".into(), 3)],
+            active_sessions(&registry)
+        );
+
+        metrics.endpoints.release(b);
+        metrics.endpoints.release(c);
+        metrics.endpoints.release(c);
+        assert_eq!(
+            vec![("127.0.0.1:8083".into(), 1)],
+            active_sessions(&registry)
+        );
+    }
+
+    #[test]
+    fn endpoint_active_sessions_aggregated() {
+        let registry = Registry::default();
+        let metrics = Metrics::with_endpoint_limit(&registry, Some(0)).unwrap();
+
+        metrics.endpoints.acquire("127.0.0.1:8080".parse().unwrap());
+        metrics.endpoints.acquire("127.0.0.1:8081".parse().unwrap());
+        assert_eq!(vec![("other".into(), 2)], active_sessions(&registry));
+    }
+
+    #[test]
+    fn active_clients() {
+        let metrics = Metrics::new(&Registry::default()).unwrap();
+        let client1 = "127.0.0.1:20001".parse().unwrap();
+        let client2 = "127.0.0.1:20002".parse().unwrap();
+
+        metrics.clients.session_started(client1);
+        metrics.clients.session_started(client1);
+        metrics.clients.session_started(client2);
+        assert_eq!(2, metrics.clients.active_clients.get());
+
+        metrics.clients.session_ended(client1);
+        assert_eq!(2, metrics.clients.active_clients.get());
+        metrics.clients.session_ended(client1);
+        metrics.clients.session_ended(client2);
+        assert_eq!(0, metrics.clients.active_clients.get());
+    }
 }
@@ -0,0 +1,295 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Multiplexing of many sessions over a single socket. Every packet is
+//! prefixed with a header identifying the session it belongs to, which is
+//! the magic bytes `QM`, the version and the session id as a big-endian
+//! `u32`.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+use slog::{debug, error, o, Drain, Level, Logger};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+
+use crate::config::Upstream;
+use crate::proxy::sessions::metrics::Metrics;
+use crate::proxy::sessions::session::bind_upstream_socket;
+
+/// Identifies a packet that starts with a multiplexing header.
+const MAGIC: [u8; 2] = *b"QM";
+/// The version of the header format.
+const VERSION: u8 = 1;
+/// The length of the header.
+const HEADER_LEN: usize = 7;
+/// The number of received packets queued for a session before further
+/// packets are dropped.
+const SESSION_QUEUE_SIZE: usize = 1024;
+
+/// A packet received from an endpoint, along with the endpoint's address.
+pub(crate) type Received = (SocketAddr, Bytes);
+
+/// Returns `contents` preceded by a header for session `id`.
+pub(crate) fn encode(id: u32, contents: &[u8]) -> Bytes {
+    let mut packet = BytesMut::with_capacity(HEADER_LEN + contents.len());
+    packet.extend_from_slice(&MAGIC);
+    packet.extend_from_slice(&[VERSION]);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(contents);
+    packet.freeze()
+}
+
+/// Returns the session id and contents of `packet`, or `None` if it does not
+/// start with a header.
+pub(crate) fn decode(packet: &Bytes) -> Option<(u32, Bytes)> {
+    if packet.len() < HEADER_LEN || packet[..2] != MAGIC || packet[2] != VERSION {
+        return None;
+    }
+    let mut id = [0; 4];
+    id.copy_from_slice(&packet[3..HEADER_LEN]);
+    Some((u32::from_be_bytes(id), packet.slice(HEADER_LEN..)))
+}
+
+/// Sends the packets of many sessions through a single upstream socket, and
+/// hands the packets received on it to the session whose id they carry.
+#[derive(Clone)]
+pub struct Multiplexer {
+    socket: Arc<UdpSocket>,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+#[derive(Default)]
+struct Sessions {
+    next_id: u32,
+    senders: HashMap<u32, mpsc::Sender<Received>>,
+}
+
+impl Multiplexer {
+    /// Binds the shared socket as configured in `upstream`, and starts
+    /// receiving packets on it until `shutdown_rx` fires.
+    pub(crate) fn bind(
+        base: &Logger,
+        metrics: Metrics,
+        upstream: &Upstream,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> io::Result<Self> {
+        let multiplexer = Self {
+            socket: Arc::new(bind_upstream_socket(upstream)?),
+            sessions: Arc::default(),
+        };
+        let log = base.new(o!("source" => "proxy::Multiplexer"));
+        multiplexer.run(log, metrics, shutdown_rx);
+        Ok(multiplexer)
+    }
+
+    fn run(&self, log: Logger, metrics: Metrics, mut shutdown_rx: watch::Receiver<()>) {
+        let socket = self.socket.clone();
+        let sessions = self.sessions.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 65535];
+            loop {
+                tokio::select! {
+                    received = socket.recv_from(&mut buf) => {
+                        let (size, recv_addr) = match received {
+                            Ok(received) => received,
+                            Err(err) => {
+                                metrics.rx_errors_total.inc();
+                                error!(log, "Error receiving packet"; "error" => %err);
+                                continue;
+                            }
+                        };
+                        let packet = Bytes::copy_from_slice(&buf[..size]);
+                        let (id, contents) = match decode(&packet) {
+                            Some(decoded) => decoded,
+                            None => {
+                                metrics.packets_dropped_total.inc();
+                                if log.is_enabled(Level::Debug) {
+                                    debug!(log, "Dropping packet without a multiplexing header"; "from" => recv_addr);
+                                }
+                                continue;
+                            }
+                        };
+                        let sender = sessions.lock().senders.get(&id).cloned();
+                        // The session may have expired, or be too far behind
+                        // on processing its packets.
+                        let delivered = match sender {
+                            Some(sender) => sender.try_send((recv_addr, contents)).is_ok(),
+                            None => false,
+                        };
+                        if !delivered {
+                            metrics.packets_dropped_total.inc();
+                            if log.is_enabled(Level::Debug) {
+                                debug!(log, "Dropping packet for unknown session"; "from" => recv_addr, "id" => id);
+                            }
+                        }
+                    }
+                    _ = shutdown_rx.changed() => {
+                        debug!(log, "Closing Multiplexer");
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Allocates an id for a new session, returning it along with the
+    /// channel the session's packets are received on.
+    pub(crate) fn register(&self) -> (u32, mpsc::Receiver<Received>) {
+        let (sender, receiver) = mpsc::channel(SESSION_QUEUE_SIZE);
+        let mut sessions = self.sessions.lock();
+        let mut id = sessions.next_id;
+        while sessions.senders.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        sessions.next_id = id.wrapping_add(1);
+        sessions.senders.insert(id, sender);
+        (id, receiver)
+    }
+
+    /// Releases the id of a session that has ended.
+    pub(crate) fn deregister(&self, id: u32) {
+        self.sessions.lock().senders.remove(&id);
+    }
+
+    /// Returns the address of the shared socket.
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sends `buf` to `dest` on behalf of session `id`. On success, returns
+    /// the number of bytes written, including the header.
+    pub(crate) async fn send_to(&self, id: u32, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(&encode(id, buf), dest).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use prometheus::Registry;
+    use tokio::sync::watch;
+    use tokio::time::{timeout, Duration};
+
+    use crate::config::Upstream;
+    use crate::proxy::sessions::metrics::Metrics;
+    use crate::test_utils::TestHelper;
+
+    use super::{decode, encode, Multiplexer};
+
+    #[test]
+    fn encode_decode() {
+        let packet = encode(0x0102_0304, b"hello");
+        assert_eq!(&[b'Q', b'M', 1, 1, 2, 3, 4][..], &packet[..7]);
+        assert_eq!(
+            Some((0x0102_0304, Bytes::from_static(b"hello"))),
+            decode(&packet)
+        );
+
+        for packet in &[&b"hello"[..], b"QM\x01\x00", b"QM\x02\x00\x00\x00\x01hello"] {
+            assert_eq!(None, decode(&Bytes::copy_from_slice(packet)));
+        }
+    }
+
+    #[tokio::test]
+    async fn register() {
+        let t = TestHelper::default();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let multiplexer = Multiplexer::bind(
+            &t.log,
+            Metrics::new(&Registry::default()).unwrap(),
+            &Upstream::default(),
+            shutdown_rx,
+        )
+        .unwrap();
+
+        let (first, _) = multiplexer.register();
+        let (second, _) = multiplexer.register();
+        assert_ne!(first, second);
+
+        multiplexer.sessions.lock().next_id = first;
+        let (third, _) = multiplexer.register();
+        assert_ne!(first, third);
+        assert_ne!(second, third);
+
+        multiplexer.deregister(first);
+        multiplexer.sessions.lock().next_id = first;
+        assert_eq!(first, multiplexer.register().0);
+    }
+
+    #[tokio::test]
+    async fn send_and_receive() {
+        let t = TestHelper::default();
+        let endpoint = t.create_socket().await;
+        let endpoint_addr =
+            SocketAddr::from(([127, 0, 0, 1], endpoint.local_addr().unwrap().port()));
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let registry = Registry::default();
+        let metrics = Metrics::new(&registry).unwrap();
+        let multiplexer =
+            Multiplexer::bind(&t.log, metrics.clone(), &Upstream::default(), shutdown_rx).unwrap();
+
+        let (first, mut first_rx) = multiplexer.register();
+        let (second, mut second_rx) = multiplexer.register();
+        multiplexer
+            .send_to(first, b"hello", endpoint_addr)
+            .await
+            .unwrap();
+        multiplexer
+            .send_to(second, b"world", endpoint_addr)
+            .await
+            .unwrap();
+
+        // Echo both packets back, along with one for an unknown session.
+        let mut buf = vec![0; 1024];
+        let mut multiplexer_addr = None;
+        for _ in 0..2 {
+            let (size, addr) = endpoint.recv_from(&mut buf).await.unwrap();
+            endpoint.send_to(&buf[..size], addr).await.unwrap();
+            multiplexer_addr = Some(addr);
+        }
+        let multiplexer_addr = multiplexer_addr.unwrap();
+        endpoint
+            .send_to(&encode(second + 1, b"unknown"), multiplexer_addr)
+            .await
+            .unwrap();
+
+        let received = timeout(Duration::from_secs(5), first_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((endpoint_addr, Bytes::from_static(b"hello")), received);
+        let received = timeout(Duration::from_secs(5), second_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((endpoint_addr, Bytes::from_static(b"world")), received);
+
+        timeout(Duration::from_secs(5), async {
+            while metrics.packets_dropped_total.get() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
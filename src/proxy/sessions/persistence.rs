@@ -0,0 +1,131 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Snapshots of the session table, so that established sessions survive a
+//! restart of the proxy.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::sessions::session_manager::SessionManager;
+
+/// A session as stored in a snapshot.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct SessionEntry {
+    /// The proxy port the downstream address sends its packets to.
+    pub port: u16,
+    pub downstream: SocketAddr,
+    pub endpoint: SocketAddr,
+    /// When the session expires, in seconds since the Unix epoch.
+    pub expiration: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Snapshot {
+    sessions: Vec<SessionEntry>,
+}
+
+/// Returns the entries of every session in `session_manager`.
+pub(crate) async fn snapshot(port: u16, session_manager: &SessionManager) -> Vec<SessionEntry> {
+    session_manager
+        .get_sessions()
+        .await
+        .values()
+        .filter_map(|session| {
+            // Multiplexed sessions are left out, as their downstream address
+            // alone does not identify them.
+            let (downstream, mux_id, endpoint) = session.key();
+            if mux_id.is_some() {
+                return None;
+            }
+            Some(SessionEntry {
+                port,
+                downstream,
+                endpoint,
+                expiration: session.expiration(),
+            })
+        })
+        .collect()
+}
+
+/// Reads the snapshot at `path`, returning no sessions if it does not exist.
+pub(crate) fn load(path: &Path) -> io::Result<Vec<SessionEntry>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(serde_json::from_slice::<Snapshot>(&contents)?.sessions),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err),
+    }
+}
+
+/// Writes a snapshot of `sessions` to `path`. The snapshot is written to a
+/// temporary file first, so a crash never leaves a partial snapshot behind.
+pub(crate) fn save(path: &Path, sessions: Vec<SessionEntry>) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&Snapshot { sessions })?)?;
+    fs::rename(tmp_path, path)
+}
+
+/// The upstream endpoints of sessions restored from a snapshot, keyed by
+/// downstream address. While a restored session is alive, packets from its
+/// downstream address are only routed to the endpoints it was using before
+/// the restart.
+#[derive(Clone, Default)]
+pub(crate) struct RestoredRoutes(Arc<RwLock<HashMap<SocketAddr, Vec<SocketAddr>>>>);
+
+impl RestoredRoutes {
+    pub fn insert(&self, downstream: SocketAddr, endpoint: SocketAddr) {
+        self.0.write().entry(downstream).or_default().push(endpoint);
+    }
+
+    pub fn get(&self, downstream: &SocketAddr) -> Option<Vec<SocketAddr>> {
+        self.0.read().get(downstream).cloned()
+    }
+
+    pub fn remove(&self, downstream: &SocketAddr) {
+        self.0.write().remove(downstream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{load, save, SessionEntry};
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("quilkin-{}.json", Uuid::new_v4()));
+        assert!(load(&path).unwrap().is_empty());
+
+        let sessions = vec![SessionEntry {
+            port: 7000,
+            downstream: "127.0.0.1:8000".parse().unwrap(),
+            endpoint: "127.0.0.1:9000".parse().unwrap(),
+            expiration: 1_000,
+        }];
+        save(&path, sessions.clone()).unwrap();
+        assert_eq!(sessions, load(&path).unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -14,22 +14,27 @@
  * limitations under the License.
  */
 
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
 
-use slog::{debug, error, o, trace, warn, Logger};
+use bytes::Bytes;
+use slog::{debug, error, o, trace, warn, Drain, Level, Logger};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::UdpSocket;
 use tokio::select;
 use tokio::sync::{mpsc, watch};
-use tokio::time::{Duration, Instant};
+use tokio::time::{self, Duration, Instant};
 
 use crate::cluster::Endpoint;
-use crate::filters::{manager::SharedFilterManager, Filter, WriteContext};
+use crate::config::{Keepalive, Upstream};
+use crate::filters::{manager::SharedFilterManager, DropReason, Filter, WriteContext};
+use crate::proxy::debug_sampling::Sampler;
 use crate::proxy::sessions::error::Error;
-use crate::proxy::sessions::metrics::Metrics;
-use crate::utils::debug;
+use crate::proxy::sessions::metrics::{EndpointCounters, Metrics};
+use crate::proxy::sessions::multiplex::{self, Multiplexer, Received};
+use crate::utils::clock;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -37,117 +42,307 @@ type Result<T> = std::result::Result<T, Error>;
 pub struct Session {
     log: Logger,
     metrics: Metrics,
+    /// The traffic counters of dest.
+    endpoint_metrics: EndpointCounters,
     filter_manager: SharedFilterManager,
     /// created_at is time at which the session was created
     created_at: Instant,
-    socket: Arc<UdpSocket>,
+    upstream: UpstreamSocket,
+    /// The address packets are sent to dest from.
+    local_addr: SocketAddr,
     /// dest is where to send data to
     dest: Endpoint,
     /// from is the original sender
     from: SocketAddr,
+    /// The id the original sender multiplexes this session under, if it
+    /// multiplexes its sessions.
+    mux_id: Option<u32>,
     /// The time at which the session is considered expired and can be removed.
     expiration: Arc<AtomicU64>,
+    /// When a packet was last sent to dest, in milliseconds since created_at.
+    last_sent: Arc<AtomicU64>,
+    /// The traffic of the session so far.
+    traffic: Arc<Traffic>,
     /// a channel to broadcast on if we are shutting down this Session
     shutdown_tx: watch::Sender<()>,
+    /// Samples the packets received from dest for logging.
+    sampler: Sampler,
+}
+
+/// The traffic of a session, as reported by the admin API.
+#[derive(Default)]
+struct Traffic {
+    /// The number of bytes received from dest.
+    rx_bytes: AtomicU64,
+    /// The number of bytes sent to dest.
+    tx_bytes: AtomicU64,
+    /// When a packet was last sent to or received from dest, in milliseconds
+    /// since created_at.
+    last_active: AtomicU64,
+}
+
+impl Traffic {
+    fn record(&self, created_at: Instant, bytes: &AtomicU64, size: usize) {
+        bytes.fetch_add(size as u64, Ordering::Relaxed);
+        self.last_active
+            .store(created_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
 }
 
 /// ReceivedPacketContext contains state needed to process a received packet.
 struct ReceivedPacketContext<'a> {
-    packet: &'a [u8],
+    packet: Bytes,
     filter_manager: SharedFilterManager,
     endpoint: &'a Endpoint,
     from: SocketAddr,
     to: SocketAddr,
+    to_mux_id: Option<u32>,
+    /// When the packet was received from the endpoint.
+    received_at: Instant,
+    sampler: Sampler,
+}
+
+/// The socket a session sends packets to its endpoint on.
+#[derive(Clone)]
+enum UpstreamSocket {
+    /// A socket bound for the session alone.
+    Dedicated(Arc<UdpSocket>),
+    /// A socket shared with other sessions, which tells the session's
+    /// packets apart by `id`.
+    Multiplexed { multiplexer: Multiplexer, id: u32 },
+}
+
+impl UpstreamSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            UpstreamSocket::Dedicated(socket) => socket.local_addr(),
+            UpstreamSocket::Multiplexed { multiplexer, .. } => multiplexer.local_addr(),
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        match self {
+            UpstreamSocket::Dedicated(socket) => socket.send_to(buf, dest).await,
+            UpstreamSocket::Multiplexed { multiplexer, id } => {
+                multiplexer.send_to(*id, buf, dest).await
+            }
+        }
+    }
+}
+
+/// Where a session receives packets from its endpoint.
+enum UpstreamReceiver {
+    Dedicated(Arc<UdpSocket>, Vec<u8>),
+    Multiplexed(mpsc::Receiver<Received>),
+}
+
+impl UpstreamReceiver {
+    /// Returns the next packet, or `None` once no more packets can be received.
+    async fn recv(&mut self) -> Option<io::Result<Received>> {
+        match self {
+            UpstreamReceiver::Dedicated(socket, buf) => Some(
+                socket
+                    .recv_from(buf)
+                    .await
+                    .map(|(size, addr)| (addr, Bytes::copy_from_slice(&buf[..size]))),
+            ),
+            UpstreamReceiver::Multiplexed(receiver) => receiver.recv().await.map(Ok),
+        }
+    }
 }
 
 /// Packet represents a packet that needs to go somewhere
 pub struct Packet {
     dest: SocketAddr,
-    contents: Vec<u8>,
+    contents: Bytes,
+    /// When the packet was received by the proxy, before being processed.
+    received_at: Instant,
 }
 
 impl Packet {
-    pub fn new(dest: SocketAddr, contents: Vec<u8>) -> Packet {
-        Packet { dest, contents }
+    /// Creates a packet that was received by the proxy at `received_at`.
+    pub fn new(dest: SocketAddr, contents: impl Into<Bytes>, received_at: Instant) -> Packet {
+        Packet {
+            dest,
+            contents: contents.into(),
+            received_at,
+        }
     }
 
     pub fn dest(&self) -> SocketAddr {
         self.dest
     }
 
-    pub fn contents(&self) -> &Vec<u8> {
+    pub fn contents(&self) -> &Bytes {
         &self.contents
     }
+
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    pub fn into_contents(self) -> Bytes {
+        self.contents
+    }
+}
+
+/// Binds a socket for sending packets to upstream endpoints, on the local
+/// address and network interface set in `upstream`.
+pub(crate) fn bind_upstream_socket(upstream: &Upstream) -> io::Result<UdpSocket> {
+    let addr = SocketAddr::new(
+        upstream
+            .local_address
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        0,
+    );
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(target_os = "linux")]
+    if let Some(device) = &upstream.bind_device {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
 }
 
 impl Session {
     /// new creates a new Session, and starts the process of receiving udp sockets
-    /// from its ephemeral port from endpoint(s)
+    /// from its ephemeral port from endpoint(s), or from `multiplexer` if set.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         base: &Logger,
         metrics: Metrics,
         filter_manager: SharedFilterManager,
         from: SocketAddr,
+        mux_id: Option<u32>,
         dest: Endpoint,
         sender: mpsc::Sender<Packet>,
         ttl: Duration,
+        upstream: &Upstream,
+        multiplexer: Option<&Multiplexer>,
+        sampler: Sampler,
     ) -> Result<Self> {
-        let log = base
-            .new(o!("source" => "proxy::Session", "from" => from, "dest_address" => dest.address));
-        let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0);
-        let socket = Arc::new(UdpSocket::bind(addr).await.map_err(Error::BindUdpSocket)?);
+        let session = match mux_id {
+            Some(id) => format!("{}#{}->{}", from, id, dest.address),
+            None => format!("{}->{}", from, dest.address),
+        };
+        let log = base.new(o!(
+            "source" => "proxy::Session",
+            "session" => session,
+            "from" => from,
+            "endpoint" => dest.address,
+        ));
+        let (socket, receiver) = match multiplexer {
+            Some(multiplexer) => {
+                let (id, receiver) = multiplexer.register();
+                (
+                    UpstreamSocket::Multiplexed {
+                        multiplexer: multiplexer.clone(),
+                        id,
+                    },
+                    UpstreamReceiver::Multiplexed(receiver),
+                )
+            }
+            None => {
+                let socket =
+                    Arc::new(bind_upstream_socket(upstream).map_err(Error::BindUdpSocket)?);
+                (
+                    UpstreamSocket::Dedicated(socket.clone()),
+                    UpstreamReceiver::Dedicated(socket, vec![0; 65535]),
+                )
+            }
+        };
+        let local_addr = socket.local_addr().map_err(Error::BindUdpSocket)?;
         let (shutdown_tx, shutdown_rx) = watch::channel::<()>(());
 
         let expiration = Arc::new(AtomicU64::new(0));
         Self::do_update_expiration(&expiration, ttl)?;
 
         let s = Session {
+            endpoint_metrics: metrics.endpoints.acquire(dest.address),
             metrics,
             log,
             filter_manager,
-            socket: socket.clone(),
+            upstream: socket,
+            local_addr,
             from,
+            mux_id,
             dest,
             created_at: Instant::now(),
             expiration,
+            last_sent: Arc::new(AtomicU64::new(0)),
+            traffic: Arc::default(),
             shutdown_tx,
+            sampler,
         };
         debug!(s.log, "Session created");
 
         s.metrics.sessions_total.inc();
         s.metrics.active_sessions.inc();
-        s.run(ttl, socket, sender, shutdown_rx);
+        s.metrics.clients.session_started(s.from);
+        if let Some(webhook) = &s.metrics.webhook {
+            webhook.session_created(s.from, s.mux_id, s.dest.address);
+        }
+        s.run(
+            ttl,
+            receiver,
+            sender,
+            shutdown_rx,
+            upstream.keepalive.clone(),
+        );
         Ok(s)
     }
 
-    /// run starts processing received udp packets on its UdpSocket
+    /// run starts processing received udp packets on its UdpSocket, and sends
+    /// keepalive packets to the endpoint whenever the session is idle for longer
+    /// than the keepalive interval.
     fn run(
         &self,
         ttl: Duration,
-        socket: Arc<UdpSocket>,
+        mut receiver: UpstreamReceiver,
         mut sender: mpsc::Sender<Packet>,
         mut shutdown_rx: watch::Receiver<()>,
+        keepalive: Option<Keepalive>,
     ) {
         let log = self.log.clone();
         let from = self.from;
+        let mux_id = self.mux_id;
+        let socket = self.upstream.clone();
         let expiration = self.expiration.clone();
         let filter_manager = self.filter_manager.clone();
         let endpoint = self.dest.clone();
         let metrics = self.metrics.clone();
+        let endpoint_metrics = self.endpoint_metrics.clone();
+        let created_at = self.created_at;
+        let last_sent = self.last_sent.clone();
+        let traffic = self.traffic.clone();
+        let local_addr = self.local_addr;
+        let sampler = self.sampler;
         tokio::spawn(async move {
-            let mut buf: Vec<u8> = vec![0; 65535];
             loop {
-                debug!(log, "Awaiting incoming packet");
+                let next_keepalive = keepalive.as_ref().map(|keepalive| {
+                    created_at
+                        + Duration::from_millis(last_sent.load(Ordering::Relaxed))
+                        + keepalive.interval
+                });
+                if log.is_enabled(Level::Debug) {
+                    debug!(log, "Awaiting incoming packet");
+                }
                 select! {
-                    received = socket.recv_from(&mut buf) => {
+                    received = receiver.recv() => {
                         match received {
-                            Err(err) => {
+                            Some(Err(err)) => {
                                 metrics.rx_errors_total.inc();
                                 error!(log, "Error receiving packet"; "error" => %err);
                             },
-                            Ok((size, recv_addr)) => {
-                                metrics.rx_bytes_total.inc_by(size as u64);
+                            Some(Ok((recv_addr, packet))) => {
+                                let received_at = Instant::now();
+                                metrics.rx_bytes_total.inc_by(packet.len() as u64);
                                 metrics.rx_packets_total.inc();
+                                endpoint_metrics.rx_bytes_total.inc_by(packet.len() as u64);
+                                endpoint_metrics.rx_packets_total.inc();
+                                traffic.record(created_at, &traffic.rx_bytes, packet.len());
+                                metrics.capture.record(recv_addr, local_addr, from, Some(endpoint.address), &packet);
                                 Session::process_recv_packet(
                                     &log,
                                     &metrics,
@@ -156,14 +351,26 @@ impl Session {
                                     ttl,
                                     ReceivedPacketContext {
                                         filter_manager: filter_manager.clone(),
-                                        packet: &buf[..size],
+                                        packet,
                                         endpoint: &endpoint,
                                         from: recv_addr,
                                         to: from,
+                                        to_mux_id: mux_id,
+                                        received_at,
+                                        sampler,
                                     }).await
                             }
+                            None => {
+                                debug!(log, "Closing Session: multiplexer closed");
+                                return;
+                            }
                         };
                     }
+                    _ = time::sleep_until(next_keepalive.unwrap_or(created_at)), if next_keepalive.is_some() => {
+                        if let Some(keepalive) = &keepalive {
+                            Session::send_keepalive(&log, &metrics, &socket, &endpoint, keepalive, created_at, &last_sent).await;
+                        }
+                    }
                     _ = shutdown_rx.changed() => {
                         debug!(log, "Closing Session");
                         return;
@@ -173,14 +380,80 @@ impl Session {
         });
     }
 
+    /// send_keepalive sends a keepalive packet to the endpoint, unless another
+    /// packet was sent to it within the keepalive interval.
+    async fn send_keepalive(
+        log: &Logger,
+        metrics: &Metrics,
+        socket: &UpstreamSocket,
+        endpoint: &Endpoint,
+        keepalive: &Keepalive,
+        created_at: Instant,
+        last_sent: &AtomicU64,
+    ) {
+        let idle = created_at
+            .elapsed()
+            .checked_sub(Duration::from_millis(last_sent.load(Ordering::Relaxed)))
+            .unwrap_or_default();
+        if idle < keepalive.interval {
+            return;
+        }
+
+        Self::mark_sent(created_at, last_sent);
+        match socket.send_to(&keepalive.payload, endpoint.address).await {
+            Ok(_) => metrics.keepalives_total.inc(),
+            Err(err) => {
+                metrics.tx_errors_total.inc();
+                warn!(log, "Error sending keepalive packet"; "error" => %err);
+            }
+        }
+    }
+
+    /// mark_sent records that a packet was just sent to the endpoint.
+    fn mark_sent(created_at: Instant, last_sent: &AtomicU64) {
+        last_sent.store(created_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
     /// expiration returns the current expiration Instant value
     pub fn expiration(&self) -> u64 {
         self.expiration.load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// set_expiration overrides the expiration value, e.g when restoring a
+    /// session from a snapshot.
+    pub fn set_expiration(&self, expiration: u64) {
+        self.expiration.store(expiration, Ordering::Relaxed);
+    }
+
+    /// Returns how long ago the session was created.
+    pub fn age(&self) -> Duration {
+        self.created_at.elapsed()
+    }
+
+    /// Returns how long ago a packet was last sent to or received from the
+    /// session's dest.
+    pub fn idle(&self) -> Duration {
+        self.created_at
+            .elapsed()
+            .checked_sub(Duration::from_millis(
+                self.traffic.last_active.load(Ordering::Relaxed),
+            ))
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of bytes received from the session's dest.
+    pub fn rx_bytes(&self) -> u64 {
+        self.traffic.rx_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes sent to the session's dest.
+    pub fn tx_bytes(&self) -> u64 {
+        self.traffic.tx_bytes.load(Ordering::Relaxed)
+    }
+
     /// key returns the key to be used for this session in a SessionMap
-    pub fn key(&self) -> (SocketAddr, SocketAddr) {
-        (self.from, self.dest.address)
+    pub fn key(&self) -> (SocketAddr, Option<u32>, SocketAddr) {
+        (self.from, self.mux_id, self.dest.address)
     }
 
     /// process_recv_packet processes a packet that is received by this session.
@@ -198,11 +471,18 @@ impl Session {
             endpoint,
             from,
             to,
+            to_mux_id,
+            received_at,
+            sampler,
         } = packet_ctx;
 
-        trace!(log, "Received packet"; "from" => from,
-            "endpoint_addr" => &endpoint.address,
-            "contents" => debug::bytes_to_string(&packet));
+        if log.is_enabled(Level::Trace) {
+            trace!(log, "Received packet"; "length" => packet.len());
+        }
+        let span = metrics
+            .tracer
+            .packet_span("packet.write", endpoint.address, packet.len());
+        let sample = sampler.start("write", endpoint.address, packet.len());
 
         if let Err(err) = Session::do_update_expiration(expiration, ttl) {
             warn!(log, "Error updating session expiration"; "error" => %err)
@@ -212,15 +492,25 @@ impl Session {
             let filter_manager_guard = filter_manager.read();
             filter_manager_guard.get_filter_chain()
         };
-        if let Some(response) =
-            filter_chain.write(WriteContext::new(endpoint, from, to, packet.to_vec()))
-        {
-            if let Err(err) = sender.send(Packet::new(to, response.contents)).await {
-                metrics.rx_errors_total.inc();
-                error!(log, "Error sending packet to channel"; "error" => %err);
+        match filter_chain.write(WriteContext::new(endpoint, from, to, packet)) {
+            Some(response) => {
+                span.sent_to(&[to]);
+                sample.sent_to(log, &[to]);
+                let contents = match to_mux_id {
+                    Some(id) => multiplex::encode(id, &response.contents),
+                    None => response.contents,
+                };
+                if let Err(err) = sender.send(Packet::new(to, contents, received_at)).await {
+                    metrics.rx_errors_total.inc();
+                    error!(log, "Error sending packet to channel"; "error" => %err);
+                }
+            }
+            None => {
+                let reason = DropReason::take().unwrap_or(DropReason::UNSPECIFIED);
+                metrics.packets_dropped_total.inc();
+                span.dropped("filter");
+                sample.dropped(log, reason.as_str());
             }
-        } else {
-            metrics.packets_dropped_total.inc();
         }
     }
 
@@ -231,7 +521,12 @@ impl Session {
 
     /// do_update_expiration increments the expiration value by the session timeout (internal)
     fn do_update_expiration(expiration: &Arc<AtomicU64>, ttl: Duration) -> Result<()> {
-        let new_expiration_time = SystemTime::now()
+        let new_expiration_time = clock::unix_now()
+            .map_err(|_| {
+                Error::UpdateSessionExpiration(
+                    "duration_since was called with time later than the current time".into(),
+                )
+            })?
             .checked_add(ttl)
             .ok_or_else(|| {
                 Error::UpdateSessionExpiration(format!(
@@ -239,12 +534,6 @@ impl Session {
                     ttl
                 ))
             })?
-            .duration_since(UNIX_EPOCH)
-            .map_err(|_| {
-                Error::UpdateSessionExpiration(
-                    "duration_since was called with time later than the current time".into(),
-                )
-            })?
             .as_secs();
 
         expiration.store(new_expiration_time, Ordering::Relaxed);
@@ -254,15 +543,26 @@ impl Session {
 
     /// Sends a packet to the Session's dest.
     pub async fn send(&self, buf: &[u8]) -> Result<Option<usize>> {
-        trace!(self.log, "Sending packet";
-        "dest_address" => &self.dest.address,
-        "contents" => debug::bytes_to_string(buf));
+        if self.log.is_enabled(Level::Trace) {
+            trace!(self.log, "Sending packet"; "length" => buf.len());
+        }
+        self.metrics.capture.record(
+            self.local_addr,
+            self.dest.address,
+            self.from,
+            Some(self.dest.address),
+            buf,
+        );
 
         self.do_send(buf)
             .await
             .map(|size| {
                 self.metrics.tx_packets_total.inc();
                 self.metrics.tx_bytes_total.inc_by(size as u64);
+                self.endpoint_metrics.tx_packets_total.inc();
+                self.endpoint_metrics.tx_bytes_total.inc_by(size as u64);
+                self.traffic
+                    .record(self.created_at, &self.traffic.tx_bytes, size);
                 Some(size)
             })
             .map_err(|err| {
@@ -274,13 +574,29 @@ impl Session {
     /// Sends `buf` to the session's destination address. On success, returns
     /// the number of bytes written.
     pub async fn do_send(&self, buf: &[u8]) -> std::result::Result<usize, std::io::Error> {
-        self.socket.send_to(buf, &self.dest.address).await
+        Self::mark_sent(self.created_at, &self.last_sent);
+        self.upstream.send_to(buf, self.dest.address).await
     }
 }
 
 impl Drop for Session {
     fn drop(&mut self) {
+        if let UpstreamSocket::Multiplexed { multiplexer, id } = &self.upstream {
+            multiplexer.deregister(*id);
+        }
         self.metrics.active_sessions.dec();
+        self.metrics.endpoints.release(self.dest.address);
+        self.metrics.clients.session_ended(self.from);
+        if let Some(webhook) = &self.metrics.webhook {
+            webhook.session_expired(
+                self.from,
+                self.mux_id,
+                self.dest.address,
+                self.age(),
+                self.rx_bytes(),
+                self.tx_bytes(),
+            );
+        }
         self.metrics
             .duration_secs
             .observe(self.created_at.elapsed().as_secs() as f64);
@@ -289,7 +605,7 @@ impl Drop for Session {
             warn!(self.log, "Error sending session shutdown signal"; "error" => error.to_string());
         }
 
-        debug!(self.log, "Session closed"; "from" => self.from, "dest_address" => &self.dest.address);
+        debug!(self.log, "Session closed");
     }
 }
 
@@ -300,16 +616,19 @@ mod tests {
     use std::sync::Arc;
     use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-    use super::{Metrics, Packet, Session};
+    use super::{bind_upstream_socket, Metrics, Packet, Session};
 
+    use bytes::Bytes;
     use prometheus::Registry;
-    use tokio::time::timeout;
+    use tokio::time::{timeout, Instant};
 
     use crate::filters::FilterChain;
     use crate::test_utils::{new_test_chain, TestHelper};
 
     use crate::cluster::Endpoint;
+    use crate::config::{Keepalive, Upstream};
     use crate::filters::manager::FilterManager;
+    use crate::proxy::debug_sampling::Sampler;
     use crate::proxy::sessions::session::ReceivedPacketContext;
     use tokio::sync::mpsc;
 
@@ -327,9 +646,13 @@ mod tests {
             Metrics::new(&registry).unwrap(),
             FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
             addr,
+            None,
             endpoint,
             send_packet,
             Duration::from_secs(20),
+            &Upstream::default(),
+            None,
+            Sampler::default(),
         )
         .await
         .unwrap();
@@ -377,9 +700,13 @@ mod tests {
             Metrics::new(&Registry::default()).unwrap(),
             FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
             addr,
+            None,
             endpoint.clone(),
             sender,
             Duration::from_millis(1000),
+            &Upstream::default(),
+            None,
+            Sampler::default(),
         )
         .await
         .unwrap();
@@ -387,6 +714,48 @@ mod tests {
         assert_eq!(msg, ep.packet_rx.await.unwrap());
     }
 
+    #[tokio::test]
+    async fn session_keepalive() {
+        let mut t = TestHelper::default();
+        let (mut packet_rx, socket) = t.open_socket_and_recv_multiple_packets().await;
+        let addr = socket.local_addr().unwrap();
+        let (sender, _) = mpsc::channel::<Packet>(1);
+        let registry = Registry::default();
+        let metrics = Metrics::new(&registry).unwrap();
+
+        let session = Session::new(
+            &t.log,
+            metrics.clone(),
+            FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
+            addr,
+            None,
+            Endpoint::from_address(addr),
+            sender,
+            Duration::from_secs(10),
+            &Upstream {
+                keepalive: Some(Keepalive {
+                    interval: Duration::from_millis(100),
+                    payload: b"ping".to_vec(),
+                }),
+                ..Upstream::default()
+            },
+            None,
+            Sampler::default(),
+        )
+        .await
+        .unwrap();
+        session.send(b"hello").await.unwrap();
+
+        for expected in &["hello", "ping", "ping"] {
+            let packet = timeout(Duration::from_secs(5), packet_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(*expected, packet);
+        }
+        assert!(metrics.keepalives_total.get() >= 2);
+    }
+
     #[tokio::test]
     async fn process_recv_packet() {
         let t = TestHelper::default();
@@ -413,11 +782,14 @@ mod tests {
             &expiration,
             Duration::from_secs(10),
             ReceivedPacketContext {
-                packet: msg.as_bytes(),
+                packet: Bytes::from_static(msg.as_bytes()),
                 filter_manager: FilterManager::fixed(chain),
                 endpoint: &endpoint,
                 from: endpoint.address,
                 to: dest,
+                to_mux_id: None,
+                received_at: Instant::now(),
+                sampler: Sampler::default(),
             },
         )
         .await;
@@ -427,7 +799,7 @@ mod tests {
             .await
             .expect("Should receive a packet")
             .unwrap();
-        assert_eq!(msg, from_utf8(p.contents.as_slice()).unwrap());
+        assert_eq!(msg, from_utf8(p.contents.as_ref()).unwrap());
         assert_eq!(dest, p.dest);
 
         let expiration = Arc::new(AtomicU64::new(
@@ -448,10 +820,13 @@ mod tests {
             Duration::from_secs(10),
             ReceivedPacketContext {
                 filter_manager: FilterManager::fixed(chain),
-                packet: msg.as_bytes(),
+                packet: Bytes::from_static(msg.as_bytes()),
                 endpoint: &endpoint,
                 from: endpoint.address,
                 to: dest,
+                to_mux_id: None,
+                received_at: Instant::now(),
+                sampler: Sampler::default(),
             },
         )
         .await;
@@ -463,7 +838,7 @@ mod tests {
             .unwrap();
         assert_eq!(
             format!("{}:our:{}:{}", msg, endpoint.address, dest),
-            from_utf8(p.contents.as_slice()).unwrap()
+            from_utf8(p.contents.as_ref()).unwrap()
         );
         assert_eq!(dest, p.dest);
     }
@@ -482,9 +857,13 @@ mod tests {
             Metrics::new(&Registry::default()).unwrap(),
             FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
             addr,
+            None,
             endpoint,
             send_packet,
             Duration::from_secs(10),
+            &Upstream::default(),
+            None,
+            Sampler::default(),
         )
         .await
         .unwrap();
@@ -506,9 +885,13 @@ mod tests {
             Metrics::new(&registry).unwrap(),
             FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
             addr,
+            None,
             Endpoint::from_address(addr),
             sender,
             Duration::from_secs(10),
+            &Upstream::default(),
+            None,
+            Sampler::default(),
         )
         .await
         .unwrap();
@@ -517,6 +900,8 @@ mod tests {
 
         assert_eq!(session.metrics.tx_bytes_total.get(), 5);
         assert_eq!(session.metrics.tx_packets_total.get(), 1);
+        assert_eq!(session.endpoint_metrics.tx_bytes_total.get(), 5);
+        assert_eq!(session.endpoint_metrics.tx_packets_total.get(), 1);
     }
 
     #[tokio::test]
@@ -531,9 +916,13 @@ mod tests {
             Metrics::new(&registry).unwrap(),
             FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
             addr,
+            None,
             Endpoint::from_address(addr),
             send_packet,
             Duration::from_secs(10),
+            &Upstream::default(),
+            None,
+            Sampler::default(),
         )
         .await
         .unwrap();
@@ -546,4 +935,19 @@ mod tests {
         assert_eq!(metrics.sessions_total.get(), 1);
         assert_eq!(metrics.active_sessions.get(), 0);
     }
+
+    #[tokio::test]
+    async fn bind_upstream_socket_local_address() {
+        let socket = bind_upstream_socket(&Upstream {
+            local_address: Some("127.0.0.1".parse().unwrap()),
+            bind_device: None,
+            keepalive: None,
+            multiplex: false,
+        })
+        .unwrap();
+        assert_eq!(
+            "127.0.0.1".parse::<std::net::IpAddr>().unwrap(),
+            socket.local_addr().unwrap().ip()
+        );
+    }
 }
@@ -14,20 +14,173 @@
  *  limitations under the License.
  */
 
+use std::collections::hash_map::{Entry, Iter, Keys, Values};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
+use parking_lot::Mutex;
 use slog::{debug, warn, Logger};
 use tokio::sync::{watch, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+use crate::config::SessionLimits;
 use crate::proxy::sessions::Session;
+use crate::utils::clock;
 
-// Tracks current sessions keyed by key (source_address,destination_address) pair.
-type SessionsMap = HashMap<(SocketAddr, SocketAddr), Session>;
+// Identifies a session by (source_address,multiplexed_session_id,destination_address).
+type SessionKey = (SocketAddr, Option<u32>, SocketAddr);
 type Sessions = Arc<RwLock<SessionsMap>>;
 
+/// Tracks current sessions by their key, and counts them in the
+/// [`SessionCounts`] of the proxy.
+#[derive(Default)]
+pub struct SessionsMap {
+    sessions: HashMap<SessionKey, Session>,
+    counts: Arc<SessionCounts>,
+}
+
+impl SessionsMap {
+    fn new(counts: Arc<SessionCounts>) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            counts,
+        }
+    }
+
+    pub fn get(&self, key: &SessionKey) -> Option<&Session> {
+        self.sessions.get(key)
+    }
+
+    pub fn contains_key(&self, key: &SessionKey) -> bool {
+        self.sessions.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn iter(&self) -> Iter<'_, SessionKey, Session> {
+        self.sessions.iter()
+    }
+
+    pub fn keys(&self) -> Keys<'_, SessionKey, Session> {
+        self.sessions.keys()
+    }
+
+    pub fn values(&self) -> Values<'_, SessionKey, Session> {
+        self.sessions.values()
+    }
+
+    /// Returns the limit that another session of `client` would exceed, if
+    /// any.
+    pub fn limit_reached(
+        &self,
+        client: SocketAddr,
+        limits: &SessionLimits,
+    ) -> Option<SessionLimit> {
+        self.counts.0.lock().limit_reached(client, limits)
+    }
+
+    pub fn insert(&mut self, key: SessionKey, session: Session) -> Option<Session> {
+        let replaced = self.sessions.insert(key, session);
+        if replaced.is_none() {
+            self.counts.0.lock().add(key.0);
+        }
+        replaced
+    }
+
+    /// Inserts `session` unless it would exceed `limits`, which is checked
+    /// along with counting it, as sessions on other ports may have been
+    /// created since [`SessionsMap::limit_reached`] was last checked.
+    pub fn insert_within(
+        &mut self,
+        key: SessionKey,
+        session: Session,
+        limits: &SessionLimits,
+    ) -> Result<(), SessionLimit> {
+        let mut counts = self.counts.0.lock();
+        if !self.sessions.contains_key(&key) {
+            if let Some(limit) = counts.limit_reached(key.0, limits) {
+                return Err(limit);
+            }
+            counts.add(key.0);
+        }
+        self.sessions.insert(key, session);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn remove(&mut self, key: &SessionKey) -> Option<Session> {
+        let removed = self.sessions.remove(key);
+        if removed.is_some() {
+            self.counts.0.lock().forget(key.0);
+        }
+        removed
+    }
+
+    /// Removes every session that `keep` returns false for.
+    pub fn retain(&mut self, mut keep: impl FnMut(&SessionKey, &Session) -> bool) {
+        let mut counts = self.counts.0.lock();
+        self.sessions.retain(|key, session| {
+            let kept = keep(key, session);
+            if !kept {
+                counts.forget(key.0);
+            }
+            kept
+        });
+    }
+}
+
+/// The number of sessions on every port of the proxy, in total and for each
+/// client, so that limits on them are checked without going over every
+/// session.
+#[derive(Default)]
+pub struct SessionCounts(Mutex<Counts>);
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    clients: HashMap<SocketAddr, usize>,
+}
+
+/// A limit of [`SessionLimits`] that a new session would exceed.
+#[derive(Debug, PartialEq)]
+pub enum SessionLimit {
+    /// `max_sessions`
+    Total,
+    /// `max_sessions_per_client`
+    Client,
+}
+
+impl Counts {
+    fn limit_reached(&self, client: SocketAddr, limits: &SessionLimits) -> Option<SessionLimit> {
+        if matches!(limits.max_sessions, Some(max) if self.total >= max) {
+            return Some(SessionLimit::Total);
+        }
+        let client_sessions = self.clients.get(&client).copied().unwrap_or_default();
+        if matches!(limits.max_sessions_per_client, Some(max) if client_sessions >= max) {
+            return Some(SessionLimit::Client);
+        }
+        None
+    }
+
+    fn add(&mut self, client: SocketAddr) {
+        self.total += 1;
+        *self.clients.entry(client).or_default() += 1;
+    }
+
+    fn forget(&mut self, client: SocketAddr) {
+        if let Entry::Occupied(mut entry) = self.clients.entry(client) {
+            self.total -= 1;
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
 /// SESSION_TIMEOUT_SECONDS is the default session timeout.
 pub const SESSION_TIMEOUT_SECONDS: u64 = 60;
 
@@ -38,9 +191,11 @@ const SESSION_EXPIRY_POLL_INTERVAL: u64 = 60;
 pub struct SessionManager(Sessions);
 
 impl SessionManager {
-    pub fn new(log: Logger, shutdown_rx: watch::Receiver<()>) -> Self {
+    /// Returns a manager of the sessions of one port, which counts them
+    /// towards the limits of the proxy in `counts`.
+    pub fn new(log: Logger, counts: Arc<SessionCounts>, shutdown_rx: watch::Receiver<()>) -> Self {
         let poll_interval = Duration::from_secs(SESSION_EXPIRY_POLL_INTERVAL);
-        let sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+        let sessions: Sessions = Arc::new(RwLock::new(SessionsMap::new(counts)));
 
         Self::run_prune_sessions(log.clone(), sessions.clone(), poll_interval, shutdown_rx);
 
@@ -88,7 +243,7 @@ impl SessionManager {
     /// regularly such as on a time interval. This will only write lock
     /// `sessions` if it first finds expired sessions.
     async fn prune_sessions(log: &Logger, sessions: &mut Sessions) {
-        let now = if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        let now = if let Ok(now) = clock::unix_now() {
             now.as_secs()
         } else {
             warn!(log, "Failed to get current time when pruning sessions");
@@ -113,7 +268,6 @@ impl SessionManager {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
     use std::net::SocketAddr;
     use std::ops::Add;
     use std::sync::Arc;
@@ -123,9 +277,13 @@ mod tests {
     use tokio::sync::{mpsc, watch, RwLock};
 
     use crate::cluster::Endpoint;
+    use crate::config::{SessionLimits, Upstream};
     use crate::filters::{manager::FilterManager, FilterChain};
+    use crate::proxy::debug_sampling::Sampler;
     use crate::proxy::sessions::metrics::Metrics;
-    use crate::proxy::sessions::session_manager::Sessions;
+    use crate::proxy::sessions::session_manager::{
+        SessionCounts, SessionLimit, Sessions, SessionsMap,
+    };
     use crate::proxy::sessions::{Packet, Session};
     use crate::test_utils::TestHelper;
 
@@ -134,7 +292,7 @@ mod tests {
     #[tokio::test]
     async fn run_prune_sessions() {
         let t = TestHelper::default();
-        let sessions = Arc::new(RwLock::new(HashMap::new()));
+        let sessions = Arc::new(RwLock::new(SessionsMap::default()));
         let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
         let to: SocketAddr = "127.0.0.1:7001".parse().unwrap();
         let (send, _recv) = mpsc::channel::<Packet>(1);
@@ -154,7 +312,7 @@ mod tests {
             shutdown_rx,
         );
 
-        let key = (from, to);
+        let key = (from, None, to);
 
         // Insert key.
         {
@@ -167,9 +325,13 @@ mod tests {
                     Metrics::new(&registry).unwrap(),
                     FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
                     from,
+                    None,
                     endpoint.clone(),
                     send,
                     ttl,
+                    &Upstream::default(),
+                    None,
+                    Sampler::default(),
                 )
                 .await
                 .unwrap(),
@@ -209,13 +371,13 @@ mod tests {
     #[tokio::test]
     async fn prune_sessions() {
         let t = TestHelper::default();
-        let mut sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+        let mut sessions: Sessions = Arc::new(RwLock::new(SessionsMap::default()));
         let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
         let to: SocketAddr = "127.0.0.1:7001".parse().unwrap();
         let (send, _recv) = mpsc::channel::<Packet>(1);
         let endpoint = Endpoint::from_address(to);
 
-        let key = (from, to);
+        let key = (from, None, to);
         let ttl = Duration::from_secs(1);
 
         {
@@ -228,9 +390,13 @@ mod tests {
                     Metrics::new(&registry).unwrap(),
                     FilterManager::fixed(Arc::new(FilterChain::new(vec![], &registry).unwrap())),
                     from,
+                    None,
                     endpoint.clone(),
                     send,
                     ttl,
+                    &Upstream::default(),
+                    None,
+                    Sampler::default(),
                 )
                 .await
                 .unwrap(),
@@ -265,4 +431,82 @@ mod tests {
             assert_eq!(0, map.len(), "len should be 0, bit is {}", map.len());
         }
     }
+
+    async fn new_session(
+        t: &TestHelper,
+        registry: &Registry,
+        from: SocketAddr,
+        to: SocketAddr,
+    ) -> Session {
+        let (send, _recv) = mpsc::channel::<Packet>(1);
+        Session::new(
+            &t.log,
+            Metrics::new(registry).unwrap(),
+            FilterManager::fixed(Arc::new(FilterChain::new(vec![], registry).unwrap())),
+            from,
+            None,
+            Endpoint::from_address(to),
+            send,
+            Duration::from_secs(60),
+            &Upstream::default(),
+            None,
+            Sampler::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn session_counts() {
+        let t = TestHelper::default();
+        let registry = Registry::default();
+        let client1: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let client2: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let endpoint1: SocketAddr = "127.0.0.1:7100".parse().unwrap();
+        let endpoint2: SocketAddr = "127.0.0.1:7101".parse().unwrap();
+
+        // The sessions of two ports, which count towards the same limits.
+        let counts = Arc::new(SessionCounts::default());
+        let mut port1 = SessionsMap::new(counts.clone());
+        let mut port2 = SessionsMap::new(counts);
+        for (from, to) in &[(client1, endpoint1), (client2, endpoint1)] {
+            let session = new_session(&t, &registry, *from, *to).await;
+            port1.insert(session.key(), session);
+        }
+        let session = new_session(&t, &registry, client1, endpoint2).await;
+        port2.insert(session.key(), session);
+
+        let per_client = SessionLimits {
+            max_sessions: None,
+            max_sessions_per_client: Some(2),
+        };
+        assert_eq!(
+            Some(SessionLimit::Client),
+            port2.limit_reached(client1, &per_client)
+        );
+        assert_eq!(None, port2.limit_reached(client2, &per_client));
+        let total = SessionLimits {
+            max_sessions: Some(3),
+            max_sessions_per_client: None,
+        };
+        assert_eq!(
+            Some(SessionLimit::Total),
+            port2.limit_reached(client2, &total)
+        );
+        let extra = new_session(&t, &registry, client2, endpoint2).await;
+        assert_eq!(
+            Err(SessionLimit::Total),
+            port2.insert_within(extra.key(), extra, &total)
+        );
+        assert_eq!(1, port2.len());
+
+        port1.remove(&(client1, None, endpoint1));
+        assert_eq!(None, port2.limit_reached(client1, &per_client));
+        let extra = new_session(&t, &registry, client2, endpoint2).await;
+        assert_eq!(Ok(()), port2.insert_within(extra.key(), extra, &total));
+
+        port2.retain(|(from, _, _), _| *from != client2);
+        assert_eq!(1, port2.len());
+        assert_eq!(None, port1.limit_reached(client1, &total));
+    }
 }
@@ -0,0 +1,612 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+
+use slog::{debug, error, o, trace, warn, Drain, Level, Logger};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Semaphore};
+use tokio::time::{self, Duration, Instant};
+
+use crate::cluster::cluster_manager::SharedClusterManager;
+use crate::config::{Framing, Upstream};
+use crate::filters::{
+    manager::SharedFilterManager, Filter, FilterChain, ReadContext, WriteContext,
+};
+
+use metrics::Metrics;
+
+pub(crate) mod metrics;
+
+/// Contains the arguments needed to accept and proxy TCP connections.
+pub(crate) struct TcpProxyArgs {
+    pub log: Logger,
+    pub listener: TcpListener,
+    pub framing: Framing,
+    /// The size of the buffer used to read from a TCP stream in
+    /// [`Framing::Chunk`] mode.
+    pub buffer_size: usize,
+    /// How long a connection is kept open without a frame from either side.
+    pub idle_timeout: Duration,
+    /// The maximum number of connections proxied at once, if any.
+    pub max_connections: Option<usize>,
+    /// The local address and network interface that connections to upstream
+    /// endpoints are made from.
+    pub upstream: Upstream,
+    pub cluster_manager: SharedClusterManager,
+    pub filter_manager: SharedFilterManager,
+    pub metrics: Metrics,
+    pub shutdown_rx: watch::Receiver<()>,
+    /// Stops the listener from accepting new connections, while existing
+    /// connections keep being proxied until shutdown.
+    pub drain_rx: watch::Receiver<()>,
+}
+
+/// Contains the state shared by every proxied TCP connection.
+#[derive(Clone)]
+struct ConnectionContext {
+    log: Logger,
+    framing: Framing,
+    buffer_size: usize,
+    idle_timeout: Duration,
+    upstream: Arc<Upstream>,
+    cluster_manager: SharedClusterManager,
+    filter_manager: SharedFilterManager,
+    metrics: Metrics,
+    shutdown_rx: watch::Receiver<()>,
+}
+
+/// Spawns a background task that accepts connections on the provided listener
+/// and proxies each of them to an upstream endpoint.
+///
+/// The endpoint is chosen by running the first frame received on a connection
+/// through the filter chain, with the connection then pinned to the first
+/// endpoint in the response. Every subsequent frame is passed through the
+/// filter chain's `read` on its way upstream and `write` on its way back.
+///
+/// Once `max_connections` connections are being proxied, further connections
+/// are closed as soon as they are accepted.
+pub(crate) fn spawn_listener(args: TcpProxyArgs) {
+    let TcpProxyArgs {
+        log,
+        listener,
+        framing,
+        buffer_size,
+        idle_timeout,
+        max_connections,
+        upstream,
+        cluster_manager,
+        filter_manager,
+        metrics,
+        mut shutdown_rx,
+        mut drain_rx,
+    } = args;
+    let log = log.new(o!("source" => "proxy::Tcp"));
+    let ctx = ConnectionContext {
+        log: log.clone(),
+        framing,
+        buffer_size,
+        idle_timeout,
+        upstream: Arc::new(upstream),
+        cluster_manager,
+        filter_manager,
+        metrics,
+        shutdown_rx: shutdown_rx.clone(),
+    };
+    let connection_limit = max_connections.map(|max| Arc::new(Semaphore::new(max)));
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    match accepted {
+                        Ok((stream, from)) => {
+                            // The permit is held until the connection is closed.
+                            let permit = match &connection_limit {
+                                Some(limit) => match limit.clone().try_acquire_owned() {
+                                    Ok(permit) => Some(permit),
+                                    Err(_) => {
+                                        ctx.metrics.connections_rejected_total.inc();
+                                        debug!(log, "Closing TCP connection: connection limit reached"; "from" => from);
+                                        continue;
+                                    }
+                                },
+                                None => None,
+                            };
+                            let ctx = ctx.clone();
+                            tokio::spawn(async move {
+                                ctx.metrics.connections_total.inc();
+                                ctx.metrics.active_connections.inc();
+                                if let Err(err) = handle_connection(stream, from, &ctx).await {
+                                    debug!(ctx.log, "TCP connection closed with an error"; "from" => from, "error" => %err);
+                                }
+                                ctx.metrics.active_connections.dec();
+                                drop(permit);
+                            });
+                        }
+                        Err(err) => {
+                            error!(log, "Error accepting TCP connection"; "error" => %err);
+                        }
+                    }
+                }
+                _ = shutdown_rx.changed() => {
+                    debug!(log, "Exiting TCP accept loop: received shutdown signal.");
+                    return;
+                }
+                Ok(()) = drain_rx.changed() => {
+                    debug!(log, "Exiting TCP accept loop: draining connections.");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Proxies a single downstream connection until either side closes it, or
+/// no frame has been received from either side for the idle timeout.
+async fn handle_connection(
+    downstream: TcpStream,
+    from: SocketAddr,
+    ctx: &ConnectionContext,
+) -> io::Result<()> {
+    let activity = Activity::new();
+    tokio::select! {
+        result = proxy_connection(downstream, from, ctx, &activity) => result,
+        _ = activity.idle(ctx.idle_timeout) => {
+            ctx.metrics.idle_timeouts_total.inc();
+            debug!(ctx.log, "Closing idle TCP connection"; "from" => from);
+            Ok(())
+        }
+    }
+}
+
+async fn proxy_connection(
+    downstream: TcpStream,
+    from: SocketAddr,
+    ctx: &ConnectionContext,
+    activity: &Activity,
+) -> io::Result<()> {
+    let log = ctx.log.new(o!("from" => from));
+    let (mut downstream_rx, mut downstream_tx) = downstream.into_split();
+    let mut downstream_buf = vec![0; ctx.buffer_size];
+    let mut upstream_buf = vec![0; ctx.buffer_size];
+
+    // Wait for a frame that the filter chain lets through, it decides which
+    // endpoint the connection is routed to.
+    let (endpoint, contents) = loop {
+        let frame = match read_frame(&mut downstream_rx, ctx.framing, &mut downstream_buf).await? {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        activity.frame_received();
+
+        let endpoints = match ctx.cluster_manager.read().get_all_endpoints() {
+            Some(endpoints) => endpoints,
+            None => {
+                warn!(
+                    log,
+                    "Closing TCP connection: no upstream endpoints available"
+                );
+                return Ok(());
+            }
+        };
+
+        // Only the first endpoint of the response is used, since a byte
+        // stream can't be copied to several endpoints the way packets are,
+        // and the endpoint is kept for the lifetime of the connection.
+        match filter_chain(ctx)
+            .read(ReadContext::new(endpoints, from, frame))
+            .and_then(|response| {
+                let endpoint = response.endpoints.iter().next().cloned()?;
+                Some((endpoint, response.contents))
+            }) {
+            Some(routed) => break routed,
+            None => ctx.metrics.frames_dropped_read.inc(),
+        }
+    };
+
+    let upstream = connect_upstream(&ctx.upstream, endpoint.address)
+        .await
+        .map_err(|err| {
+            ctx.metrics.connect_errors_total.inc();
+            err
+        })?;
+    debug!(log, "TCP connection established"; "endpoint" => endpoint.address);
+    let (mut upstream_rx, mut upstream_tx) = upstream.into_split();
+    send_upstream(&mut upstream_tx, ctx, &contents).await?;
+
+    let to_upstream = async {
+        while let Some(frame) =
+            read_frame(&mut downstream_rx, ctx.framing, &mut downstream_buf).await?
+        {
+            activity.frame_received();
+            let endpoints = match ctx.cluster_manager.read().get_all_endpoints() {
+                Some(endpoints) => endpoints,
+                None => {
+                    ctx.metrics.frames_dropped_read.inc();
+                    continue;
+                }
+            };
+
+            match filter_chain(ctx).read(ReadContext::new(endpoints, from, frame)) {
+                Some(response) => send_upstream(&mut upstream_tx, ctx, &response.contents).await?,
+                None => ctx.metrics.frames_dropped_read.inc(),
+            }
+        }
+        Ok(())
+    };
+
+    let to_downstream = async {
+        while let Some(frame) = read_frame(&mut upstream_rx, ctx.framing, &mut upstream_buf).await?
+        {
+            activity.frame_received();
+            ctx.metrics.rx_bytes_total.inc_by(frame.len() as u64);
+            if log.is_enabled(Level::Trace) {
+                trace!(log, "Received TCP frame"; "endpoint" => endpoint.address,
+                    "length" => frame.len());
+            }
+
+            match filter_chain(ctx).write(WriteContext::new(
+                &endpoint,
+                endpoint.address,
+                from,
+                frame,
+            )) {
+                Some(response) => {
+                    write_frame(&mut downstream_tx, ctx.framing, &response.contents).await?
+                }
+                None => ctx.metrics.frames_dropped_write.inc(),
+            }
+        }
+        Ok(())
+    };
+
+    let mut shutdown_rx = ctx.shutdown_rx.clone();
+    tokio::select! {
+        result = to_upstream => result,
+        result = to_downstream => result,
+        _ = shutdown_rx.changed() => Ok(()),
+    }
+}
+
+/// Tracks when a frame was last received on a connection, from either side.
+struct Activity {
+    start: Instant,
+    /// The time since `start` that the last frame was received at, in
+    /// milliseconds.
+    last_frame: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_frame: AtomicU64::new(0),
+        }
+    }
+
+    fn frame_received(&self) {
+        self.last_frame
+            .store(self.start.elapsed().as_millis() as u64, Relaxed);
+    }
+
+    /// Completes once no frame has been received for `timeout`.
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let deadline =
+                self.start + Duration::from_millis(self.last_frame.load(Relaxed)) + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            time::sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Connects to an upstream endpoint at `address`, from the local address and
+/// network interface set in `upstream`.
+async fn connect_upstream(upstream: &Upstream, address: SocketAddr) -> io::Result<TcpStream> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    #[cfg(target_os = "linux")]
+    if let Some(device) = &upstream.bind_device {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+    // Unlike a UDP socket, the socket is only bound when a local address is
+    // set, as it can only connect to endpoints of the same address family.
+    if let Some(local_address) = upstream.local_address {
+        socket.bind(&SocketAddr::new(local_address, 0).into())?;
+    }
+    socket.set_nonblocking(true)?;
+    if let Err(err) = socket.connect(&address.into()) {
+        #[cfg(unix)]
+        let in_progress = err.raw_os_error() == Some(libc::EINPROGRESS);
+        #[cfg(not(unix))]
+        let in_progress = err.kind() == io::ErrorKind::WouldBlock;
+        if !in_progress {
+            return Err(err);
+        }
+    }
+
+    // The connection is established, or has failed, once the socket is
+    // writable.
+    let stream = TcpStream::from_std(socket.into())?;
+    stream.writable().await?;
+    match stream.take_error()? {
+        Some(err) => Err(err),
+        None => Ok(stream),
+    }
+}
+
+fn filter_chain(ctx: &ConnectionContext) -> Arc<FilterChain> {
+    ctx.filter_manager.read().get_filter_chain()
+}
+
+async fn send_upstream<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    ctx: &ConnectionContext,
+    contents: &[u8],
+) -> io::Result<()> {
+    write_frame(writer, ctx.framing, contents).await?;
+    ctx.metrics.tx_bytes_total.inc_by(contents.len() as u64);
+    Ok(())
+}
+
+/// Reads the next frame from `reader`, returning `None` once the stream is closed.
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    framing: Framing,
+    buf: &mut [u8],
+) -> io::Result<Option<Vec<u8>>> {
+    match framing {
+        Framing::Chunk => {
+            let size = reader.read(buf).await?;
+            Ok(if size == 0 {
+                None
+            } else {
+                Some(buf[..size].to_vec())
+            })
+        }
+        Framing::LengthPrefixed => {
+            let mut length = [0; 2];
+            match reader.read_exact(&mut length).await {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
+            }
+
+            let mut frame = vec![0; u16::from_be_bytes(length) as usize];
+            reader.read_exact(&mut frame).await?;
+            Ok(Some(frame))
+        }
+    }
+}
+
+/// Writes `contents` to `writer` as a single frame.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    framing: Framing,
+    contents: &[u8],
+) -> io::Result<()> {
+    if let Framing::LengthPrefixed = framing {
+        let length = u16::try_from(contents.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes exceeds the maximum frame size",
+                    contents.len()
+                ),
+            )
+        })?;
+        writer.write_all(&length.to_be_bytes()).await?;
+    }
+    writer.write_all(contents).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use prometheus::Registry;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::watch;
+    use tokio::time::{timeout, Duration};
+
+    use crate::cluster::cluster_manager::ClusterManager;
+    use crate::cluster::Endpoint;
+    use crate::config::{Endpoints, Framing, Upstream};
+    use crate::filters::manager::FilterManager;
+    use crate::test_utils::{logger, new_test_chain};
+
+    use super::{connect_upstream, read_frame, spawn_listener, write_frame, Metrics, TcpProxyArgs};
+
+    #[tokio::test]
+    async fn length_prefixed_frames() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        write_frame(&mut client, Framing::LengthPrefixed, b"hello")
+            .await
+            .unwrap();
+        write_frame(&mut client, Framing::LengthPrefixed, b"")
+            .await
+            .unwrap();
+        write_frame(&mut client, Framing::LengthPrefixed, b"world")
+            .await
+            .unwrap();
+        drop(client);
+
+        let mut buf = vec![0; 1024];
+        let mut frames = vec![];
+        while let Some(frame) = read_frame(&mut server, Framing::LengthPrefixed, &mut buf)
+            .await
+            .unwrap()
+        {
+            frames.push(frame);
+        }
+
+        assert_eq!(vec![b"hello".to_vec(), vec![], b"world".to_vec()], frames);
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_frame_too_large() {
+        let (mut client, _server) = tokio::io::duplex(1024);
+        let err = write_frame(&mut client, Framing::LengthPrefixed, &vec![0; 1 << 16])
+            .await
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+    }
+
+    async fn run_echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = stream.split();
+                    tokio::io::copy(&mut rx, &mut tx).await.ok();
+                });
+            }
+        });
+        addr
+    }
+
+    /// Spawns a listener proxying connections to `endpoint`, returning its
+    /// address and metrics, along with the sender that shuts it down once
+    /// dropped.
+    async fn spawn_proxy(
+        registry: &Registry,
+        endpoint: SocketAddr,
+        idle_timeout: Duration,
+        max_connections: Option<usize>,
+    ) -> (SocketAddr, Metrics, watch::Sender<()>) {
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let (_drain_tx, drain_rx) = watch::channel(());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let metrics = Metrics::new(registry).unwrap();
+        spawn_listener(TcpProxyArgs {
+            log: logger(),
+            listener,
+            framing: Framing::LengthPrefixed,
+            buffer_size: 1 << 16,
+            idle_timeout,
+            max_connections,
+            upstream: Upstream::default(),
+            cluster_manager: ClusterManager::fixed(
+                registry,
+                Endpoints::new(vec![Endpoint::from_address(endpoint)]).unwrap(),
+            )
+            .unwrap(),
+            filter_manager: FilterManager::fixed(new_test_chain(registry)),
+            metrics: metrics.clone(),
+            shutdown_rx,
+            drain_rx,
+        });
+        (proxy_addr, metrics, shutdown_tx)
+    }
+
+    #[tokio::test]
+    async fn proxy_connection() {
+        let echo_addr = run_echo_server().await;
+        let registry = Registry::default();
+        let (proxy_addr, _metrics, _shutdown_tx) =
+            spawn_proxy(&registry, echo_addr, Duration::from_secs(60), None).await;
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let client_addr = stream.local_addr().unwrap();
+        stream.write_all(&[0, 5]).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+
+        let mut length = [0; 2];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut length))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut contents = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut contents).await.unwrap();
+
+        assert_eq!(
+            format!(
+                "hello:odr:{}:our:{}:{}",
+                client_addr, echo_addr, client_addr
+            ),
+            String::from_utf8(contents).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_connection_closed() {
+        let echo_addr = run_echo_server().await;
+        let registry = Registry::default();
+        let (proxy_addr, metrics, _shutdown_tx) =
+            spawn_proxy(&registry, echo_addr, Duration::from_millis(100), None).await;
+
+        // The connection is closed without a first frame ever being sent.
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert_eq!(1, metrics.idle_timeouts_total.get());
+    }
+
+    #[tokio::test]
+    async fn connection_limit() {
+        let echo_addr = run_echo_server().await;
+        let registry = Registry::default();
+        let (proxy_addr, metrics, _shutdown_tx) =
+            spawn_proxy(&registry, echo_addr, Duration::from_secs(60), Some(1)).await;
+
+        let _first = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut second = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(5), second.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert_eq!(1, metrics.connections_rejected_total.get());
+    }
+
+    #[tokio::test]
+    async fn connect_upstream_local_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let upstream = Upstream {
+            local_address: Some("127.0.0.1".parse().unwrap()),
+            ..Upstream::default()
+        };
+        let stream = connect_upstream(&upstream, address).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(stream.local_addr().unwrap(), peer);
+        assert_eq!("127.0.0.1".parse::<std::net::IpAddr>().unwrap(), peer.ip());
+
+        // Nothing listens on the port once the listener is dropped.
+        drop(listener);
+        assert!(connect_upstream(&upstream, address).await.is_err());
+    }
+}
@@ -0,0 +1,325 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tracking of the traffic received from each client over a sliding window,
+//! to find the clients that send the most.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use parking_lot::Mutex;
+use serde_json::json;
+use tokio::time::Instant;
+
+/// How much time the traffic of each bucket covers.
+const BUCKET_DURATION: Duration = Duration::from_secs(1);
+/// The longest window that traffic can be reported for.
+const MAX_WINDOW: Duration = Duration::from_secs(60);
+/// The window traffic is reported for if a request does not set one.
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+/// The number of clients reported if a request does not set it.
+const DEFAULT_TOP: usize = 10;
+/// The maximum number of clients whose traffic is tracked per bucket of a
+/// shard, so a flood of packets from spoofed addresses can't exhaust memory.
+const MAX_CLIENTS_PER_BUCKET: usize = 100_000;
+
+/// The traffic received from each client, recorded in shards that are only
+/// merged when the traffic is reported.
+#[derive(Clone, Default)]
+pub(crate) struct TopTalkers {
+    shards: Arc<Mutex<Vec<Shard>>>,
+}
+
+/// The traffic recorded by a single receive loop, kept in buckets covering up
+/// to [`MAX_WINDOW`] of the most recent traffic. Each loop records into its
+/// own shard so that loops don't contend on a lock for every packet.
+#[derive(Clone, Default)]
+pub(crate) struct Shard {
+    buckets: Arc<Mutex<VecDeque<Bucket>>>,
+}
+
+struct Bucket {
+    start: Instant,
+    traffic: HashMap<SocketAddr, Traffic>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Traffic {
+    packets: u64,
+    bytes: u64,
+}
+
+/// What clients are ranked by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Order {
+    Packets,
+    Bytes,
+}
+
+impl Shard {
+    /// Records a packet of `size` bytes received from `from`.
+    pub fn record(&self, from: SocketAddr, size: usize) {
+        self.record_at(Instant::now(), from, size);
+    }
+
+    fn record_at(&self, now: Instant, from: SocketAddr, size: usize) {
+        let mut buckets = self.buckets.lock();
+        let expired = match buckets.back() {
+            Some(bucket) => now.saturating_duration_since(bucket.start) >= BUCKET_DURATION,
+            None => true,
+        };
+        if expired {
+            while matches!(buckets.front(), Some(bucket) if now.saturating_duration_since(bucket.start) >= MAX_WINDOW)
+            {
+                buckets.pop_front();
+            }
+            buckets.push_back(Bucket {
+                start: now,
+                traffic: HashMap::new(),
+            });
+        }
+
+        let bucket = buckets.back_mut().expect("a bucket was just added");
+        if bucket.traffic.len() >= MAX_CLIENTS_PER_BUCKET && !bucket.traffic.contains_key(&from) {
+            return;
+        }
+        let traffic = bucket.traffic.entry(from).or_default();
+        traffic.packets += 1;
+        traffic.bytes += size as u64;
+    }
+}
+
+impl TopTalkers {
+    /// Returns a new shard to record traffic into, which is included in every
+    /// report from then on.
+    pub fn shard(&self) -> Shard {
+        let shard = Shard::default();
+        self.shards.lock().push(shard.clone());
+        shard
+    }
+
+    /// Returns the `top` clients that sent the most within `window` before
+    /// `now`, ranked by `order`.
+    fn top(
+        &self,
+        now: Instant,
+        window: Duration,
+        top: usize,
+        order: Order,
+    ) -> Vec<(SocketAddr, Traffic)> {
+        let mut totals = HashMap::<SocketAddr, Traffic>::new();
+        for shard in self.shards.lock().iter() {
+            for bucket in shard
+                .buckets
+                .lock()
+                .iter()
+                .filter(|bucket| now.saturating_duration_since(bucket.start) < window)
+            {
+                for (address, traffic) in &bucket.traffic {
+                    let total = totals.entry(*address).or_default();
+                    total.packets += traffic.packets;
+                    total.bytes += traffic.bytes;
+                }
+            }
+        }
+
+        let mut totals: Vec<_> = totals.into_iter().collect();
+        totals.sort_by_key(|(address, traffic)| {
+            let rank = match order {
+                Order::Packets => (traffic.packets, traffic.bytes),
+                Order::Bytes => (traffic.bytes, traffic.packets),
+            };
+            (Reverse(rank), *address)
+        });
+        totals.truncate(top);
+        totals
+    }
+
+    /// Returns a HTTP 200 response listing the clients that sent the most as
+    /// requested by the `top`, `window` and `order` parameters of `query`.
+    pub(super) fn response(&self, query: Option<&str>) -> Response<Body> {
+        let (top, window, order) = match parse_query(query) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                let mut response = Response::new(message.into());
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                return response;
+            }
+        };
+
+        let seconds = window.as_secs_f64();
+        let clients: Vec<_> = self
+            .top(Instant::now(), window, top, order)
+            .into_iter()
+            .map(|(address, traffic)| {
+                json!({
+                    "address": address,
+                    "packets": traffic.packets,
+                    "bytes": traffic.bytes,
+                    "packets_per_second": traffic.packets as f64 / seconds,
+                    "bytes_per_second": traffic.bytes as f64 / seconds,
+                })
+            })
+            .collect();
+        let body = json!({
+            "window_seconds": seconds,
+            "clients": clients,
+        });
+        let mut response = Response::new(body.to_string().into());
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        response
+    }
+}
+
+fn parse_query(query: Option<&str>) -> Result<(usize, Duration, Order), String> {
+    let mut top = DEFAULT_TOP;
+    let mut window = DEFAULT_WINDOW;
+    let mut order = Order::Packets;
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match &*key {
+            "top" => {
+                top = value
+                    .parse()
+                    .map_err(|_| format!("invalid number of clients `{}`", value))?
+            }
+            "window" => {
+                window = match humantime::parse_duration(&value) {
+                    Ok(window) if window >= BUCKET_DURATION && window <= MAX_WINDOW => window,
+                    _ => {
+                        return Err(format!(
+                            "invalid window `{}`, expected a duration between 1s and 60s",
+                            value
+                        ))
+                    }
+                }
+            }
+            "order" => {
+                order = match &*value {
+                    "packets" => Order::Packets,
+                    "bytes" => Order::Bytes,
+                    _ => {
+                        return Err(format!(
+                            "invalid order `{}`, expected one of: packets, bytes",
+                            value
+                        ))
+                    }
+                }
+            }
+            _ => return Err(format!("unknown parameter `{}`", key)),
+        }
+    }
+    Ok((top, window, order))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{parse_query, Order, TopTalkers, Traffic};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn rank_clients() {
+        let top_talkers = TopTalkers::default();
+        let (shard, other_shard) = (top_talkers.shard(), top_talkers.shard());
+        let start = Instant::now();
+        for _ in 0..3 {
+            shard.record_at(start, addr(1), 10);
+        }
+        shard.record_at(start, addr(2), 1000);
+        // The traffic of a client is added up across shards.
+        other_shard.record_at(start + Duration::from_millis(1500), addr(2), 1000);
+        shard.record_at(start + Duration::from_millis(1500), addr(3), 1);
+
+        let now = start + Duration::from_secs(2);
+        let window = Duration::from_secs(10);
+        assert_eq!(
+            vec![
+                (
+                    addr(1),
+                    Traffic {
+                        packets: 3,
+                        bytes: 30
+                    }
+                ),
+                (
+                    addr(2),
+                    Traffic {
+                        packets: 2,
+                        bytes: 2000
+                    }
+                ),
+            ],
+            top_talkers.top(now, window, 2, Order::Packets)
+        );
+        assert_eq!(
+            vec![addr(2), addr(1), addr(3)],
+            top_talkers
+                .top(now, window, 10, Order::Bytes)
+                .into_iter()
+                .map(|(address, _)| address)
+                .collect::<Vec<_>>()
+        );
+
+        // Only the most recent bucket is within the window.
+        assert_eq!(
+            vec![addr(2), addr(3)],
+            top_talkers
+                .top(now, Duration::from_secs(1), 10, Order::Packets)
+                .into_iter()
+                .map(|(address, _)| address)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn expire_buckets() {
+        let shard = TopTalkers::default().shard();
+        let start = Instant::now();
+        shard.record_at(start, addr(1), 10);
+        shard.record_at(start + Duration::from_secs(61), addr(2), 10);
+
+        assert_eq!(1, shard.buckets.lock().len());
+    }
+
+    #[test]
+    fn parse_top_talkers_query() {
+        assert_eq!(
+            (5, Duration::from_secs(30), Order::Bytes),
+            parse_query(Some("top=5&window=30s&order=bytes")).unwrap()
+        );
+        assert_eq!(
+            (10, Duration::from_secs(10), Order::Packets),
+            parse_query(None).unwrap()
+        );
+        assert!(parse_query(Some("window=5m")).is_err());
+        assert!(parse_query(Some("order=rate")).is_err());
+        assert!(parse_query(Some("top=-1")).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Export of spans for sampled packets and control plane events, such as
+//! cluster updates and reconnects to a management server, through the
+//! OpenTelemetry protocol (OTLP).
+
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use opentelemetry::global;
+use opentelemetry::sdk::trace::{self, Span as SdkSpan, TracerProvider};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{
+    NoopTracerProvider, Span, TraceError, Tracer as _, TracerProvider as _,
+};
+use opentelemetry::{Key, KeyValue};
+
+use crate::config::Tracing;
+
+/// The name of the instrumentation library that spans are recorded by.
+const TRACER_NAME: &str = "quilkin";
+
+/// Records the spans of a single proxy, so that proxies embedded in the same
+/// process each export their own. The default records none.
+#[derive(Clone)]
+pub(crate) struct Tracer(Arc<Inner>);
+
+struct Inner {
+    tracer: trace::Tracer,
+    /// The fraction of packets that spans are recorded for.
+    packet_sample_rate: f64,
+}
+
+/// Exports the spans of a [`Tracer`] until it is shut down.
+pub(crate) struct Exporter(TracerProvider);
+
+/// Starts exporting spans as configured, returning the tracer recording them
+/// along with their exporter.
+///
+/// Errors exporting spans are reported through OpenTelemetry's error
+/// handler, which is left as is since it is shared by the whole process.
+pub(crate) fn install(config: &Tracing, proxy_id: &str) -> Result<(Tracer, Exporter), TraceError> {
+    let resource = Resource::new(vec![
+        KeyValue::new("service.name", config.service_name.clone()),
+        KeyValue::new("service.instance.id", proxy_id.to_owned()),
+    ]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(config.endpoint.clone())
+        .with_trace_config(trace::config().with_resource(resource))
+        .with_tonic()
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    let provider = tracer
+        .provider()
+        .ok_or_else(|| TraceError::from("the tracer provider was dropped"))?;
+    // The pipeline also installs its provider globally, which is taken back
+    // so that the proxy holds the only reference to it, and the provider of
+    // one proxy never replaces that of another.
+    global::set_tracer_provider(NoopTracerProvider::new());
+
+    let tracer = Tracer(Arc::new(Inner {
+        tracer,
+        packet_sample_rate: config.packet_sample_rate,
+    }));
+    Ok((tracer, Exporter(provider)))
+}
+
+impl Exporter {
+    /// Stops exporting spans, once the ones that have not been yet are.
+    pub(crate) async fn shutdown(self) {
+        // Dropping the provider blocks until the exporter, which runs as a
+        // task, has finished, so it must not block the runtime that task runs
+        // on.
+        tokio::task::spawn_blocking(move || drop(self)).await.ok();
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        // The provider is dropped right away, so the tracer records nothing.
+        Self(Arc::new(Inner {
+            tracer: TracerProvider::default().get_tracer(TRACER_NAME, None),
+            packet_sample_rate: 0.0,
+        }))
+    }
+}
+
+impl Tracer {
+    /// Starts a span for a control plane event.
+    pub(crate) fn span(&self, name: &'static str) -> SdkSpan {
+        self.0.tracer.start(name)
+    }
+
+    /// Starts a span for a packet of `size` bytes received from `from`, if
+    /// the packet is sampled.
+    pub(crate) fn packet_span(
+        &self,
+        name: &'static str,
+        from: SocketAddr,
+        size: usize,
+    ) -> PacketSpan {
+        if !sampled(self.0.packet_sample_rate) {
+            return PacketSpan(None);
+        }
+        let span = self.span(name);
+        span.set_attribute(KeyValue::new("net.peer.ip", from.ip().to_string()));
+        span.set_attribute(KeyValue::new("net.peer.port", i64::from(from.port())));
+        span.set_attribute(KeyValue::new("quilkin.packet.size", size as i64));
+        PacketSpan(Some(span))
+    }
+}
+
+/// The span of a packet passing through the proxy, which is only recorded if
+/// the packet is sampled, and ends once dropped.
+pub(crate) struct PacketSpan(Option<SdkSpan>);
+
+impl PacketSpan {
+    /// Records why the packet was dropped.
+    pub fn dropped(&self, reason: &'static str) {
+        if let Some(span) = &self.0 {
+            span.set_attribute(KeyValue::new("quilkin.packet.dropped", reason));
+        }
+    }
+
+    /// Records the addresses the packet is sent to.
+    pub fn sent_to<'a>(&self, addresses: impl IntoIterator<Item = &'a SocketAddr>) {
+        if let Some(span) = &self.0 {
+            let addresses: Vec<Cow<'static, str>> = addresses
+                .into_iter()
+                .map(|address| address.to_string().into())
+                .collect();
+            span.set_attribute(Key::new("quilkin.packet.destinations").array(addresses));
+        }
+    }
+}
+
+/// Returns whether to sample an event that is sampled at `rate`.
+fn sampled(rate: f64) -> bool {
+    rate > 0.0 && rand::random::<f64>() < rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sampled;
+
+    #[test]
+    fn sample_rate() {
+        assert!((0..1000).all(|_| !sampled(0.0)));
+        assert!((0..1000).all(|_| sampled(1.0)));
+
+        let count = (0..10_000).filter(|_| sampled(0.5)).count();
+        assert!((4000..6000).contains(&count), "sampled {} packets", count);
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Posting of session events to a webhook, in batches of JSON objects.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use prometheus::core::{AtomicU64, GenericCounter};
+use prometheus::{IntCounter, Registry};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use slog::{debug, o, warn, Logger};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+
+use crate::config::Webhook;
+use crate::metrics::{opts, CollectorExt};
+
+/// The most events that can wait to be posted, after which new events are
+/// dropped.
+const QUEUE_SIZE: usize = 10_000;
+/// How long to wait for the webhook to accept a batch.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before posting a batch again the first time it fails,
+/// which doubles with every retry.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone)]
+struct Metrics {
+    events_sent_total: GenericCounter<AtomicU64>,
+    events_dropped_total: GenericCounter<AtomicU64>,
+}
+
+impl Metrics {
+    fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let subsystem = "webhook";
+        Ok(Self {
+            events_sent_total: IntCounter::with_opts(opts(
+                "events_sent_total",
+                subsystem,
+                "Total number of events posted to the webhook",
+            ))?
+            .register_if_not_exists(registry)?,
+            events_dropped_total: IntCounter::with_opts(opts(
+                "events_dropped_total",
+                subsystem,
+                "Total number of events dropped because the queue was full or the webhook could not be reached",
+            ))?
+            .register_if_not_exists(registry)?,
+        })
+    }
+}
+
+/// Queues session events to be posted to the webhook.
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    proxy_id: Arc<str>,
+    events_tx: mpsc::Sender<Value>,
+    metrics: Metrics,
+}
+
+impl Notifier {
+    /// Spawns a task that posts the events of the returned notifier until
+    /// shutdown, when the events that are still queued are posted once more.
+    pub fn spawn(
+        base: &Logger,
+        config: &Webhook,
+        proxy_id: &str,
+        registry: &Registry,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> Result<Self, Error> {
+        let metrics = Metrics::new(registry)?;
+        let (events_tx, events_rx) = mpsc::channel(QUEUE_SIZE);
+        let poster = Poster {
+            log: base.new(o!("source" => "proxy::Webhook")),
+            client: Client::builder().timeout(TIMEOUT).build()?,
+            url: Url::parse(&config.url)?,
+            batch_size: config.batch_size,
+            flush_interval: config.flush_interval,
+            max_retries: config.max_retries,
+            metrics: metrics.clone(),
+        };
+        tokio::spawn(poster.run(events_rx, shutdown_rx));
+
+        Ok(Self {
+            proxy_id: proxy_id.into(),
+            events_tx,
+            metrics,
+        })
+    }
+
+    /// Queues a `session.created` event, for a session that sends the
+    /// packets of `source` to `endpoint`.
+    pub fn session_created(&self, source: SocketAddr, mux_id: Option<u32>, endpoint: SocketAddr) {
+        self.queue(json!({
+            "event": "session.created",
+            "source": source.to_string(),
+            "multiplex_id": mux_id,
+            "endpoint": endpoint.to_string(),
+        }));
+    }
+
+    /// Queues a `session.expired` event, with how long the session lasted
+    /// and the bytes it received from and sent to `endpoint`.
+    pub fn session_expired(
+        &self,
+        source: SocketAddr,
+        mux_id: Option<u32>,
+        endpoint: SocketAddr,
+        age: Duration,
+        rx_bytes: u64,
+        tx_bytes: u64,
+    ) {
+        self.queue(json!({
+            "event": "session.expired",
+            "source": source.to_string(),
+            "multiplex_id": mux_id,
+            "endpoint": endpoint.to_string(),
+            "age_seconds": age.as_secs_f64(),
+            "rx_bytes": rx_bytes,
+            "tx_bytes": tx_bytes,
+        }));
+    }
+
+    fn queue(&self, mut event: Value) {
+        event["time"] = humantime::format_rfc3339_millis(SystemTime::now())
+            .to_string()
+            .into();
+        event["proxy_id"] = self.proxy_id.as_ref().into();
+        if self.events_tx.try_send(event).is_err() {
+            self.metrics.events_dropped_total.inc();
+        }
+    }
+}
+
+/// Posts batches of queued events to the webhook.
+struct Poster {
+    log: Logger,
+    client: Client,
+    url: Url,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_retries: u32,
+    metrics: Metrics,
+}
+
+impl Poster {
+    async fn run(self, mut events_rx: mpsc::Receiver<Value>, mut shutdown_rx: watch::Receiver<()>) {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        let mut interval = time::interval(self.flush_interval);
+        loop {
+            tokio::select! {
+                event = events_rx.recv() => match event {
+                    Some(event) => {
+                        batch.push(event);
+                        if batch.len() < self.batch_size {
+                            continue;
+                        }
+                    }
+                    None => return,
+                },
+                _ = interval.tick() => {
+                    if batch.is_empty() {
+                        continue;
+                    }
+                }
+                _ = shutdown_rx.changed() => {
+                    while let Ok(event) = events_rx.try_recv() {
+                        batch.push(event);
+                    }
+                    if !batch.is_empty() {
+                        self.post(&batch, 0, &mut shutdown_rx).await;
+                    }
+                    return;
+                }
+            }
+
+            self.post(&batch, self.max_retries, &mut shutdown_rx).await;
+            batch.clear();
+        }
+    }
+
+    /// Posts `batch`, retrying up to `max_retries` times unless the proxy
+    /// shuts down in the meantime.
+    async fn post(&self, batch: &[Value], max_retries: u32, shutdown_rx: &mut watch::Receiver<()>) {
+        let mut delay = RETRY_DELAY;
+        for attempt in 0..=max_retries {
+            match self.try_post(batch).await {
+                Ok(()) => {
+                    debug!(self.log, "Posted events"; "url" => %self.url, "events" => batch.len());
+                    self.metrics.events_sent_total.inc_by(batch.len() as u64);
+                    return;
+                }
+                Err(err) => {
+                    warn!(self.log, "Failed to post events"; "url" => %self.url, "events" => batch.len(), "attempt" => attempt + 1, "error" => %err)
+                }
+            }
+            if attempt < max_retries {
+                tokio::select! {
+                    _ = time::sleep(delay) => delay *= 2,
+                    _ = shutdown_rx.changed() => break,
+                }
+            }
+        }
+        self.metrics.events_dropped_total.inc_by(batch.len() as u64);
+    }
+
+    async fn try_post(&self, batch: &[Value]) -> Result<(), Error> {
+        self.client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(batch)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, StatusCode};
+    use prometheus::Registry;
+    use serde_json::Value;
+    use tokio::sync::{mpsc, watch};
+
+    use super::Notifier;
+    use crate::config::Webhook;
+    use crate::test_utils::logger;
+
+    #[tokio::test]
+    async fn post_batches_with_retries() {
+        // The first request fails, so its batch is posted again.
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (posted_tx, mut posted_rx) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            let posted_tx = posted_tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let requests = requests.clone();
+                    let posted_tx = posted_tx.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let mut response = Response::new(Body::empty());
+                        if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                        } else {
+                            posted_tx
+                                .send(serde_json::from_slice::<Value>(&body).unwrap())
+                                .unwrap();
+                        }
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let notifier = Notifier::spawn(
+            &logger(),
+            &Webhook {
+                url: format!("http://{}/events", address),
+                batch_size: 2,
+                flush_interval: Duration::from_secs(60),
+                max_retries: 3,
+            },
+            "proxy-1",
+            &Registry::default(),
+            shutdown_rx,
+        )
+        .unwrap();
+
+        let source = "10.0.0.5:51234".parse().unwrap();
+        let endpoint = "10.0.1.7:7000".parse().unwrap();
+        notifier.session_created(source, None, endpoint);
+        notifier.session_expired(source, None, endpoint, Duration::from_secs(30), 100, 200);
+
+        let batch = tokio::time::timeout(Duration::from_secs(5), posted_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let batch = batch.as_array().unwrap();
+        assert_eq!(2, batch.len());
+        assert_eq!("session.created", batch[0]["event"]);
+        assert_eq!("10.0.1.7:7000", batch[0]["endpoint"]);
+        assert_eq!("proxy-1", batch[0]["proxy_id"]);
+        assert_eq!("session.expired", batch[1]["event"]);
+        assert_eq!(30.0, batch[1]["age_seconds"]);
+        assert_eq!(100, batch[1]["rx_bytes"]);
+        assert_eq!(200, batch[1]["tx_bytes"]);
+    }
+}
@@ -14,16 +14,29 @@
  * limitations under the License.
  */
 
-use std::{fs::File, sync::Arc};
+mod bench;
+mod control;
+mod fetch;
+mod generate;
+mod test_server;
 
-use clap::App;
-use slog::{info, o};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use clap::{App, ArgMatches, SubCommand};
+use slog::{info, o, warn, Logger};
 use tokio::{signal, sync::watch};
 
 use crate::{
-    config::Config,
-    filters::{DynFilterFactory, FilterRegistry, FilterSet},
-    proxy::{logger, Builder},
+    config::{Config, EndPoint, RuntimeFlavor, Source},
+    filters::{plugin, DynFilterFactory, FilterRegistry, FilterSet},
+    proxy::{config_logger, logger, Builder, LogLevels},
 };
 
 #[cfg(doc)]
@@ -31,9 +44,58 @@ use crate::filters::FilterFactory;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const CONFIG_FILE: &str = "quilkin.yaml";
+/// The configuration file name that reads the configuration from stdin.
+const STDIN: &str = "-";
 
 pub type Error = Box<dyn std::error::Error>;
 
+/// What the command line arguments asked for.
+enum Command {
+    /// Run a proxy with the configuration.
+    Run(Box<Config>),
+    /// Check the configuration, merged over the named preset if any, and
+    /// exit.
+    Validate(Location, Option<String>),
+    /// Print an example configuration using the named filters and exit.
+    GenerateConfig(Vec<String>),
+    /// Send a request to the admin server of a running proxy and exit.
+    Control(control::Request),
+    /// Send traffic through a proxy, print how it fared and exit.
+    Bench(bench::Bench),
+    /// Run a server that responds to packets, until interrupted.
+    TestServer(test_server::TestServer),
+}
+
+/// Where the configuration is read from.
+enum Location {
+    File(PathBuf),
+    Stdin,
+    Url(fetch::Request),
+}
+
+impl Location {
+    fn read(&self, preset: Option<&str>) -> Result<Config, Error> {
+        let dir = Path::new(".");
+        Ok(match self {
+            Location::File(path) => {
+                Config::from_reader_in(File::open(path)?, path.parent().unwrap_or(dir), preset)?
+            }
+            Location::Stdin => Config::from_reader_in(io::stdin(), dir, preset)?,
+            Location::Url(request) => Config::from_reader_in(&request.fetch()?[..], dir, preset)?,
+        })
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::File(path) => path.display().fmt(f),
+            Location::Stdin => f.write_str("<stdin>"),
+            Location::Url(request) => f.write_str(&request.url),
+        }
+    }
+}
+
 #[cfg(debug_assertions)]
 fn version() -> String {
     format!("{}+debug", VERSION)
@@ -46,13 +108,95 @@ fn version() -> String {
 
 /// Start and run a proxy. Any passed in [`FilterFactory`]s are included
 /// alongside the default filter factories.
+///
+/// The proxy runs on the calling runtime, so `proxy.runtime.worker_threads`
+/// only sets the number of packet workers. Use [`start`] to also have the
+/// proxy create its runtime according to the configuration.
 pub async fn run(
     filter_factories: impl IntoIterator<Item = DynFilterFactory>,
 ) -> Result<(), Error> {
+    let (config_path, config) = match load()? {
+        (_, config_path, Command::Run(config)) => (config_path, *config),
+        (log, _, Command::Validate(location, preset)) => {
+            return validate(log, location, preset, filter_factories)
+        }
+        (log, _, Command::GenerateConfig(filters)) => {
+            return generate_config(log, filters, filter_factories)
+        }
+        (_, _, Command::Control(request)) => return request.send().await,
+        (_, _, Command::Bench(bench)) => {
+            print!("{}", bench.run().await?);
+            return Ok(());
+        }
+        (log, _, Command::TestServer(server)) => return server.run(log).await,
+    };
+    serve(config_path, config, filter_factories).await
+}
+
+/// Like [`run`], but first creates the Tokio runtime described by the
+/// `proxy.runtime` configuration and blocks on it until the proxy exits.
+pub fn start(filter_factories: impl IntoIterator<Item = DynFilterFactory>) -> Result<(), Error> {
+    let (config_path, config) = match load()? {
+        (_, config_path, Command::Run(config)) => (config_path, *config),
+        // Some filters start background tasks when they are created.
+        (log, _, Command::Validate(location, preset)) => {
+            return tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(async { validate(log, location, preset, filter_factories) })
+        }
+        (log, _, Command::GenerateConfig(filters)) => {
+            return generate_config(log, filters, filter_factories)
+        }
+        (_, _, Command::Control(request)) => {
+            return tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(request.send())
+        }
+        (_, _, Command::Bench(bench)) => {
+            let report = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()?
+                .block_on(bench.run())?;
+            print!("{}", report);
+            return Ok(());
+        }
+        (log, _, Command::TestServer(server)) => {
+            return tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(server.run(log))
+        }
+    };
+
+    let runtime = match config.proxy.runtime.flavor {
+        RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(worker_threads) = config.proxy.runtime.worker_threads {
+                builder.worker_threads(worker_threads);
+            }
+            builder
+        }
+        // Packet workers get their own threads, so the main runtime is only
+        // left with reading from the sockets and background tasks.
+        RuntimeFlavor::PerCore => tokio::runtime::Builder::new_current_thread(),
+    }
+    .enable_all()
+    .build()?;
+
+    runtime.block_on(serve(config_path, config, filter_factories))
+}
+
+/// Parses the command line arguments and loads the configuration file,
+/// unless it is only to be validated.
+/// The returned path is `None` if the configuration is read from stdin.
+fn load() -> Result<(Logger, Option<PathBuf>, Command), Error> {
     let version = version();
     let base_logger = logger();
     let log = base_logger.new(o!("source" => "run"));
 
+    let presets = crate::config::preset_names();
     let matches = App::new(clap::crate_name!())
         .version(version.as_str())
         .about(clap::crate_description!())
@@ -60,37 +204,325 @@ pub async fn run(
             clap::Arg::with_name("filename")
                 .short("f")
                 .long("filename")
+                .alias("config")
                 .value_name("FILE")
-                .help("The yaml configuration file")
-                .takes_value(true),
+                .help("The YAML or JSON configuration file, `-` to read it from stdin, or an HTTP(S) URL to fetch it from")
+                .takes_value(true)
+                .global(true),
         )
+        .arg(
+            clap::Arg::with_name("config-authorization")
+                .long("config-authorization")
+                .value_name("VALUE")
+                .help("The `Authorization` header to send when fetching the configuration from a URL")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("config-sha256")
+                .long("config-sha256")
+                .value_name("CHECKSUM")
+                .help("The hex encoded SHA-256 checksum that a configuration fetched from a URL must match")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("preset")
+                .long("preset")
+                .value_name("NAME")
+                .help("The built-in configuration to merge the configuration file over, or to run with if none is passed explicitly")
+                .possible_values(&presets)
+                .takes_value(true)
+                .global(true),
+        )
+        .args(&run_args())
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs a proxy, which is also what happens without a subcommand")
+                .args(&run_args()),
+        )
+        .subcommand(SubCommand::with_name("validate").about(
+            "Checks that the configuration file is valid, exiting with a non-zero status if not",
+        ))
+        .subcommand(
+            SubCommand::with_name("generate-config")
+                .about("Prints an example configuration file, with the configuration of each filter described")
+                .arg(
+                    clap::Arg::with_name("filters")
+                        .long("filters")
+                        .value_name("NAMES")
+                        .help("The names of the filters to include, separated by commas, in the order packets pass through them")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(control::subcommand())
+        .subcommand(bench::subcommand())
+        .subcommand(test_server::subcommand())
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("generate-config") {
+        let filters = matches
+            .values_of("filters")
+            .map(|filters| filters.map(String::from).collect())
+            .unwrap_or_default();
+        return Ok((base_logger, None, Command::GenerateConfig(filters)));
+    }
+    if let Some(matches) = matches.subcommand_matches("control") {
+        let request = control::Request::from_matches(matches)?;
+        return Ok((base_logger, None, Command::Control(request)));
+    }
+    if let Some(matches) = matches.subcommand_matches("bench") {
+        let bench = bench::Bench::from_matches(matches)?;
+        return Ok((base_logger, None, Command::Bench(bench)));
+    }
+    if let Some(matches) = matches.subcommand_matches("test-server") {
+        let server = test_server::TestServer::from_matches(matches)?;
+        return Ok((base_logger, None, Command::TestServer(server)));
+    }
+
     let config_env = std::env::var("QUILKIN_FILENAME").ok();
-    let config_path = matches
+    let config_arg = matches
         .value_of("filename")
-        .or_else(|| config_env.as_deref())
-        .or(Some(CONFIG_FILE))
-        .map(|path| std::path::Path::new(path).canonicalize())
-        .transpose()
-        // Path wll always be `Some` here.
-        .map(Option::unwrap)?;
+        .or_else(|| config_env.as_deref());
+    let config_path = config_arg.unwrap_or(CONFIG_FILE);
+    let location = if config_path == STDIN {
+        Location::Stdin
+    } else if fetch::Request::is_url(config_path) {
+        Location::Url(fetch::Request {
+            url: config_path.into(),
+            authorization: matches
+                .value_of("config-authorization")
+                .map(String::from)
+                .or_else(|| std::env::var("QUILKIN_CONFIG_AUTHORIZATION").ok()),
+            sha256: matches.value_of("config-sha256").map(String::from),
+        })
+    } else {
+        Location::File(config_path.into())
+    };
+
+    let preset = matches.value_of("preset");
+
+    if matches.subcommand_matches("validate").is_some() {
+        return Ok((
+            base_logger,
+            None,
+            Command::Validate(location, preset.map(String::from)),
+        ));
+    }
 
     info!(log, "Starting Quilkin"; "version" => version);
+    let matches = matches.subcommand_matches("run").unwrap_or(&matches);
+
+    let to = to_endpoints(matches)?;
+
+    let (config_path, config) = if let (Some(endpoints), None) = (&to, config_arg) {
+        info!(
+            log,
+            "Sending traffic to the endpoints passed on the command line"
+        );
+        let config = match preset {
+            Some(preset) => Config::from_preset(preset, endpoints.clone())?,
+            None => Config::load_balanced(endpoints.clone()),
+        };
+        (None, config)
+    } else if let Location::File(config_path) = location {
+        let config_path = config_path.canonicalize()?;
+        let config = if config_path.exists() {
+            Location::File(config_path.clone()).read(preset)?
+        } else {
+            Config::from_reader_in(get_config_file()?, Path::new("."), preset)?
+        };
+        info!(log, "Found configuration file"; "path" => config_path.display());
+        (Some(config_path), config)
+    } else {
+        let config = location.read(preset)?;
+        info!(log, "Read configuration"; "from" => %location);
+        (None, config)
+    };
 
-    let config = File::open(&config_path)
-        .or_else(|_| get_config_file())
-        .map_err(Error::from)
-        .and_then(|file| Config::from_reader(file).map_err(Error::from))
-        .map(Arc::new)?;
+    for deprecation in config.deprecations() {
+        warn!(log, "The configuration uses a deprecated format, please update it"; "deprecation" => deprecation);
+    }
+
+    // Changes to the file would undo the overrides.
+    let config_path =
+        config_path.filter(|_| to.is_none() && preset.is_none() && !matches.is_present("port"));
+    let config = apply_overrides(config, matches, to)?;
+
+    Ok((base_logger, config_path, Command::Run(Box::new(config))))
+}
+
+/// Returns the endpoints passed with `--to`, if any.
+fn to_endpoints(matches: &ArgMatches) -> Result<Option<Vec<EndPoint>>, Error> {
+    Ok(matches
+        .values_of("to")
+        .map(|to| {
+            to.map(|address| address.parse().map(EndPoint::new))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?)
+}
+
+/// Overrides the settings of `config` with those passed as [`run_args`],
+/// replacing its endpoints with `to` while keeping its filters.
+fn apply_overrides(
+    mut config: Config,
+    matches: &ArgMatches,
+    to: Option<Vec<EndPoint>>,
+) -> Result<Config, Error> {
+    if let Some(endpoints) = to {
+        let filters = match config.source {
+            Source::Static { filters, .. }
+            | Source::Kubernetes { filters, .. }
+            | Source::GameLift { filters, .. }
+            | Source::Consul { filters, .. } => filters,
+            Source::Dynamic { .. } => vec![],
+        };
+        config.source = Source::Static { filters, endpoints };
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.proxy.port = port.parse()?;
+    }
+    if let Some(id) = matches.value_of("id") {
+        config.proxy.id = id.into();
+    }
+    if let Some(flavor) = matches.value_of("runtime") {
+        config.proxy.runtime.flavor = serde_yaml::from_str::<RuntimeFlavor>(flavor)?;
+    }
+    if let Some(worker_threads) = matches.value_of("worker-threads") {
+        config.proxy.runtime.worker_threads = Some(worker_threads.parse()?);
+    }
+    Ok(config)
+}
+
+/// The arguments of running a proxy, which are accepted both with and without
+/// the `run` subcommand.
+fn run_args<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    vec![
+        clap::Arg::with_name("port")
+            .long("port")
+            .value_name("PORT")
+            .help("The port to receive traffic on, overrides `proxy.port`")
+            .takes_value(true),
+        clap::Arg::with_name("to")
+            .long("to")
+            .value_name("ADDRESS")
+            .help("An endpoint to send traffic to, can be repeated. Replaces the configured endpoints, and the configuration file itself unless one is passed explicitly")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        clap::Arg::with_name("id")
+            .long("id")
+            .value_name("ID")
+            .help("The identifier of the proxy, overrides `proxy.id`")
+            .takes_value(true),
+        clap::Arg::with_name("runtime")
+            .long("runtime")
+            .value_name("FLAVOR")
+            .help("How work is spread across threads, overrides `proxy.runtime.flavor`")
+            .possible_values(&["MULTI_THREAD", "PER_CORE"])
+            .takes_value(true),
+        clap::Arg::with_name("worker-threads")
+            .long("worker-threads")
+            .value_name("COUNT")
+            .help("The number of threads processing packets, overrides `proxy.runtime.worker_threads`")
+            .takes_value(true),
+    ]
+}
+
+/// Loads the configuration file and creates everything a proxy would create
+/// from it, without running the proxy. Exits the process with a non-zero
+/// status if any of it fails.
+fn validate(
+    base_logger: Logger,
+    location: Location,
+    preset: Option<String>,
+    filter_factories: impl IntoIterator<Item = DynFilterFactory>,
+) -> Result<(), Error> {
+    let log = base_logger.new(o!("source" => "run"));
+    let result = location.read(preset.as_deref()).and_then(|config| {
+        for deprecation in config.deprecations() {
+            eprintln!("{}: deprecated: {}", location, deprecation);
+        }
+        let plugin_factories = plugin::load(&base_logger, &config.plugins)?;
+        Builder::from(Arc::new(config))
+            .with_log(base_logger)
+            .disable_admin()
+            .with_filter_registry(FilterRegistry::new(FilterSet::default_with(
+                &log,
+                filter_factories.into_iter().chain(plugin_factories),
+            )))
+            .validate()
+            .map(drop)
+            .map_err(Error::from)
+    });
+
+    match result {
+        Ok(()) => {
+            println!("{}: configuration is valid", location);
+            Ok(())
+        }
+        Err(err) => {
+            eprintln!("{}: {}", location, err);
+            std::process::exit(1);
+        }
+    }
+}
 
-    info!(log, "Found configuration file"; "path" => config_path.display());
+/// Prints an example configuration that passes packets through `filters`.
+fn generate_config(
+    base_logger: Logger,
+    filters: Vec<String>,
+    filter_factories: impl IntoIterator<Item = DynFilterFactory>,
+) -> Result<(), Error> {
+    let log = base_logger.new(o!("source" => "run"));
+    let factories: HashMap<_, _> = FilterSet::default_with(&log, filter_factories)
+        .into_iter()
+        .map(|factory| (factory.name(), factory))
+        .collect();
+    let filters = filters
+        .iter()
+        .map(|name| {
+            factories.get(name.as_str()).ok_or_else(|| {
+                let mut names: Vec<_> = factories.keys().copied().collect();
+                names.sort_unstable();
+                format!(
+                    "unknown filter `{}`, expected one of: {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    print!("{}", generate::generate(&filters)?);
+    Ok(())
+}
 
-    let server = Builder::from(config)
+async fn serve(
+    config_path: Option<PathBuf>,
+    config: Config,
+    filter_factories: impl IntoIterator<Item = DynFilterFactory>,
+) -> Result<(), Error> {
+    // The proxy logs in the format of its configuration from here on.
+    let log_levels = Arc::<LogLevels>::default();
+    let base_logger = config_logger(&config.logging, log_levels.clone())?;
+    let log = base_logger.new(o!("source" => "run"));
+
+    let plugin_factories = plugin::load(&base_logger, &config.plugins)?;
+    let mut builder = Builder::from(Arc::new(config))
         .with_log(base_logger)
+        .with_log_levels(log_levels);
+    // There is only something to watch for changes if the config came from
+    // a file.
+    if let Some(config_path) = config_path {
+        builder = builder.with_config_path(config_path);
+    }
+    let server = builder
         .with_filter_registry(FilterRegistry::new(FilterSet::default_with(
             &log,
-            filter_factories.into_iter(),
+            filter_factories.into_iter().chain(plugin_factories),
         )))
         .validate()?
         .build();
@@ -121,3 +553,79 @@ fn get_config_file() -> Result<File, std::io::Error> {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::App;
+
+    use crate::config::{Config, EndPoint, Source};
+
+    use super::{apply_overrides, run_args, to_endpoints};
+
+    fn run(config: Config, args: &[&str]) -> Result<Config, super::Error> {
+        let matches = App::new("quilkin")
+            .args(&run_args())
+            .get_matches_from_safe(std::iter::once("quilkin").chain(args.iter().copied()))?;
+        apply_overrides(config, &matches, to_endpoints(&matches)?)
+    }
+
+    fn file_config() -> Config {
+        Config::from_reader(
+            "
+version: v1alpha1
+proxy:
+  id: proxy-1
+  port: 7000
+static:
+  filters:
+    - name: quilkin.extensions.filters.debug.v1alpha1.Debug
+  endpoints:
+    - address: 127.0.0.1:26000
+"
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn overrides() {
+        let config = run(file_config(), &[]).unwrap();
+        assert_eq!("proxy-1", config.proxy.id);
+        assert_eq!(7000, config.proxy.port);
+        assert_eq!(file_config().source, config.source);
+
+        let config = run(
+            file_config(),
+            &[
+                "--port",
+                "7001",
+                "--id",
+                "relay",
+                "--to",
+                "127.0.0.1:7777",
+                "--to",
+                "127.0.0.2:7777",
+            ],
+        )
+        .unwrap();
+        assert_eq!("relay", config.proxy.id);
+        assert_eq!(7001, config.proxy.port);
+        match config.source {
+            Source::Static { filters, endpoints } => {
+                assert_eq!(1, filters.len());
+                assert_eq!(
+                    vec![
+                        EndPoint::new("127.0.0.1:7777".parse().unwrap()),
+                        EndPoint::new("127.0.0.2:7777".parse().unwrap()),
+                    ],
+                    endpoints
+                );
+            }
+            source => panic!("unexpected source: {:?}", source),
+        }
+
+        assert!(run(file_config(), &["--port", "70000"]).is_err());
+        assert!(run(file_config(), &["--to", "127.0.0.1"]).is_err());
+        assert!(run(file_config(), &["--to"]).is_err());
+    }
+}
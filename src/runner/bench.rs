@@ -0,0 +1,645 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The `bench` subcommand, which sends traffic through a proxy to endpoints
+//! that echo it back, and reports how much of it made the round trip and how
+//! quickly.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use super::control::{admin_url, summarize};
+use super::Error;
+
+/// The size of the sequence number and the time a packet was sent at, which
+/// start the body of each packet.
+const HEADER_SIZE: usize = 16;
+/// How long to wait for the responses to the last packets sent.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// The metrics of the packets a proxy drops itself. Packets dropped by
+/// filters on their way back from an endpoint are also counted by
+/// `quilkin_session_packets_dropped_total`, which is left out so that they
+/// aren't counted twice.
+const DROPPED_METRICS: [&str; 2] = [
+    "quilkin_proxy_packets_dropped_total",
+    "quilkin_filter_packets_dropped_total",
+];
+
+/// Where clients put their token in the packets they send, like the
+/// `strategy` of the `CaptureBytes` filter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Position {
+    Prefix,
+    Suffix,
+}
+
+/// The traffic to generate.
+#[derive(Debug)]
+pub(super) struct Bench {
+    target: SocketAddr,
+    clients: usize,
+    rate: u32,
+    size: usize,
+    duration: Duration,
+    tokens: Vec<Vec<u8>>,
+    position: Position,
+    /// The URL of the proxy's admin server, to count the packets it drops.
+    admin: Option<String>,
+}
+
+/// The results of a [`Bench`].
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct Report {
+    clients: usize,
+    duration: Duration,
+    size: usize,
+    sent: u64,
+    /// The number of packets that came back, counting each one once.
+    received: u64,
+    /// The number of packets that came back more than once.
+    duplicated: u64,
+    /// The number of packets that came back after a packet sent later.
+    reordered: u64,
+    /// The round trip times of the packets received, sorted.
+    latencies: Vec<Duration>,
+    /// The packets the proxy dropped during the run, by metric.
+    dropped_by_proxy: Option<BTreeMap<String, u64>>,
+}
+
+/// The packets that came back to one client.
+#[derive(Debug, Default)]
+struct Received {
+    /// A bit for each sequence number, set once it came back.
+    seen: Vec<u64>,
+    unique: u64,
+    duplicated: u64,
+    reordered: u64,
+    /// The highest sequence number that came back so far.
+    highest: Option<u64>,
+    /// The round trip time of each packet, the first time it came back.
+    latencies: Vec<Duration>,
+}
+
+/// Returns the `bench` subcommand.
+pub(super) fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    let value = |name: &'a str, value_name: &'a str, help: &'a str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name(value_name)
+            .help(help)
+            .takes_value(true)
+    };
+
+    SubCommand::with_name("bench")
+        .about("Sends traffic through a proxy to endpoints that echo it back, and reports the throughput, loss and latency")
+        .arg(value("target", "ADDRESS", "The address of the proxy").required(true))
+        .arg(value("clients", "COUNT", "The number of clients sending packets at the same time, each from a socket of its own").default_value("1"))
+        .arg(value("rate", "PACKETS", "The number of packets each client sends per second").default_value("100"))
+        .arg(value("size", "BYTES", "The size of each packet, including its token").default_value("64"))
+        .arg(value("duration", "DURATION", "How long to send packets for").default_value("10s"))
+        .arg(
+            value("token", "TOKEN", "A base64 encoded token to add to packets, can be repeated to give clients different tokens in turn")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            value("token-position", "POSITION", "Where to add the token in packets, as captured by the `CaptureBytes` filter")
+                .possible_values(&["PREFIX", "SUFFIX"])
+                .default_value("SUFFIX"),
+        )
+        .arg(value("admin-address", "URL", "The address of the proxy's admin server, to tell the packets the proxy dropped apart from those lost elsewhere"))
+}
+
+/// Parses the value of the argument `name`, which has a default value.
+fn parse<T>(matches: &ArgMatches, name: &str) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = matches.value_of(name).unwrap_or_default();
+    value
+        .parse()
+        .map_err(|err| format!("invalid value `{}` for `--{}`: {}", value, name, err).into())
+}
+
+impl Bench {
+    /// Returns the traffic the arguments of the `bench` subcommand ask for.
+    pub(super) fn from_matches(matches: &ArgMatches) -> Result<Self, Error> {
+        let tokens = matches
+            .values_of("token")
+            .map(|tokens| {
+                tokens
+                    .map(|token| {
+                        base64::decode(token)
+                            .map_err(|err| format!("invalid token `{}`: {}", token, err))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let bench = Self {
+            target: parse(matches, "target")?,
+            clients: parse(matches, "clients")?,
+            rate: parse(matches, "rate")?,
+            size: parse(matches, "size")?,
+            duration: humantime::parse_duration(matches.value_of("duration").unwrap_or_default())
+                .map_err(|err| format!("invalid value for `--duration`: {}", err))?,
+            tokens,
+            position: match matches.value_of("token-position") {
+                Some("PREFIX") => Position::Prefix,
+                _ => Position::Suffix,
+            },
+            admin: matches.value_of("admin-address").map(admin_url),
+        };
+
+        if bench.clients == 0 || bench.rate == 0 {
+            return Err("`--clients` and `--rate` must be greater than zero".into());
+        }
+        let token_size = bench.tokens.iter().map(Vec::len).max().unwrap_or(0);
+        if bench.size < HEADER_SIZE + token_size {
+            return Err(format!(
+                "`--size` must be at least {} bytes to fit the token and the {} bytes that identify each packet",
+                HEADER_SIZE + token_size,
+                HEADER_SIZE
+            )
+            .into());
+        }
+        Ok(bench)
+    }
+
+    /// Sends the traffic from each client at the same time and waits for the
+    /// responses.
+    pub(super) async fn run(self) -> Result<Report, Error> {
+        let bench = Arc::new(self);
+        let dropped_before = match &bench.admin {
+            Some(admin) => Some(dropped(admin).await?),
+            None => None,
+        };
+        let start = Instant::now();
+        let clients: Vec<_> = (0..bench.clients)
+            .map(|client| {
+                let token = match bench.tokens.len() {
+                    0 => None,
+                    tokens => Some(client % tokens),
+                };
+                tokio::spawn(bench.clone().client(token, start))
+            })
+            .collect();
+
+        let mut report = Report {
+            clients: bench.clients,
+            duration: bench.duration,
+            size: bench.size,
+            ..Report::default()
+        };
+        for client in clients {
+            let (sent, received) = client.await??;
+            report.sent += sent;
+            report.received += received.unique;
+            report.duplicated += received.duplicated;
+            report.reordered += received.reordered;
+            report.latencies.extend(received.latencies);
+        }
+        report.latencies.sort();
+
+        if let (Some(admin), Some(before)) = (&bench.admin, dropped_before) {
+            let after = dropped(admin).await?;
+            report.dropped_by_proxy = Some(
+                after
+                    .into_iter()
+                    .map(|(name, total)| {
+                        let before = before.get(&name).copied().unwrap_or_default();
+                        (name, (total - before).max(0.0) as u64)
+                    })
+                    .collect(),
+            );
+        }
+        Ok(report)
+    }
+
+    /// Sends packets at the rate until the end of the run, and returns how
+    /// many it sent along with those that came back.
+    async fn client(
+        self: Arc<Self>,
+        token: Option<usize>,
+        start: Instant,
+    ) -> io::Result<(u64, Received)> {
+        let bind: SocketAddr = if self.target.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(self.target).await?;
+
+        let token = token.map(|token| &self.tokens[token][..]);
+        let mut packet = vec![0; self.size];
+        let header = match (token, self.position) {
+            (Some(token), Position::Prefix) => {
+                packet[..token.len()].copy_from_slice(token);
+                token.len()
+            }
+            (Some(token), Position::Suffix) => {
+                let at = packet.len() - token.len();
+                packet[at..].copy_from_slice(token);
+                0
+            }
+            (None, _) => 0,
+        };
+
+        let mut sent = 0u64;
+        let mut received = Received::default();
+        let mut buffer = vec![0; u16::MAX as usize];
+        let mut interval = tokio::time::interval(
+            (Duration::from_secs(1) / self.rate).max(Duration::from_nanos(1)),
+        );
+        let end = start + self.duration;
+        let until_end = tokio::time::sleep_until(end);
+        tokio::pin!(until_end);
+        loop {
+            tokio::select! {
+                _ = &mut until_end => break,
+                now = interval.tick() => {
+                    packet[header..header + 8].copy_from_slice(&sent.to_be_bytes());
+                    let elapsed = now.saturating_duration_since(start).as_nanos() as u64;
+                    packet[header + 8..header + HEADER_SIZE].copy_from_slice(&elapsed.to_be_bytes());
+                    socket.send(&packet).await?;
+                    sent += 1;
+                }
+                size = socket.recv(&mut buffer) => {
+                    if let Some((sequence, latency)) = decode(&buffer[..size?], token, self.position, start) {
+                        received.record(sequence, sent, latency);
+                    }
+                }
+            }
+        }
+
+        // Packets still in flight are only lost if they don't come back soon.
+        let drained = end + DRAIN_TIMEOUT;
+        while received.unique < sent {
+            match tokio::time::timeout_at(drained, socket.recv(&mut buffer)).await {
+                Ok(size) => {
+                    if let Some((sequence, latency)) =
+                        decode(&buffer[..size?], token, self.position, start)
+                    {
+                        received.record(sequence, sent, latency);
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        Ok((sent, received))
+    }
+}
+
+/// Returns the number of packets the proxy with the admin server at `admin`
+/// dropped so far, by metric.
+async fn dropped(admin: &str) -> Result<BTreeMap<String, f64>, Error> {
+    let metrics = reqwest::get(&format!("{}/metrics", admin))
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let totals = summarize(&metrics, Some("quilkin_"));
+    // Metrics with labels only show up once something was counted.
+    Ok(DROPPED_METRICS
+        .iter()
+        .map(|name| {
+            let total = totals.get(*name).copied().unwrap_or_default();
+            ((*name).to_owned(), total)
+        })
+        .collect())
+}
+
+/// Returns the sequence number and round trip time of a packet received
+/// back, or `None` if it isn't one that was sent. Packets may come back with
+/// or without their token, depending on whether the proxy removed it.
+fn decode(
+    packet: &[u8],
+    token: Option<&[u8]>,
+    position: Position,
+    start: Instant,
+) -> Option<(u64, Duration)> {
+    let packet = match token {
+        Some(token) if position == Position::Prefix && packet.starts_with(token) => {
+            &packet[token.len()..]
+        }
+        _ => packet,
+    };
+    let sequence = u64::from_be_bytes(packet.get(..8)?.try_into().ok()?);
+    let sent_at = u64::from_be_bytes(packet.get(8..HEADER_SIZE)?.try_into().ok()?);
+    let latency = start.elapsed().checked_sub(Duration::from_nanos(sent_at))?;
+    Some((sequence, latency))
+}
+
+impl Received {
+    /// Records that packet `sequence` came back after `latency`, unless it
+    /// isn't one of the `sent` packets.
+    fn record(&mut self, sequence: u64, sent: u64, latency: Duration) {
+        if sequence >= sent {
+            return;
+        }
+        let (word, bit) = ((sequence / 64) as usize, 1 << (sequence % 64));
+        if self.seen.len() <= word {
+            self.seen.resize(word + 1, 0);
+        }
+        if self.seen[word] & bit != 0 {
+            self.duplicated += 1;
+            return;
+        }
+        self.seen[word] |= bit;
+        self.unique += 1;
+        self.latencies.push(latency);
+        match self.highest {
+            Some(highest) if sequence < highest => self.reordered += 1,
+            _ => self.highest = Some(sequence),
+        }
+    }
+}
+
+impl Report {
+    /// Returns the latency that `percentile` percent of packets made the
+    /// round trip within.
+    fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies.get(rank.saturating_sub(1)).copied()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let seconds = self.duration.as_secs_f64();
+        let lost = self.sent.saturating_sub(self.received);
+        writeln!(
+            f,
+            "Sent {} packets of {} bytes from {} clients in {}: {:.1} packets/s, {:.0} bytes/s",
+            self.sent,
+            self.size,
+            self.clients,
+            humantime::format_duration(self.duration),
+            self.sent as f64 / seconds,
+            (self.sent * self.size as u64) as f64 / seconds
+        )?;
+        writeln!(
+            f,
+            "Received {} packets back: {:.1} packets/s, {} lost ({:.2}%)",
+            self.received,
+            self.received as f64 / seconds,
+            lost,
+            if self.sent == 0 {
+                0.0
+            } else {
+                lost as f64 * 100.0 / self.sent as f64
+            }
+        )?;
+        match (
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.latencies.last(),
+        ) {
+            (Some(p50), Some(p90), Some(p99), Some(max)) => writeln!(
+                f,
+                "Round trip latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+                p50, p90, p99, max
+            ),
+            _ => writeln!(f, "Round trip latency: no packets came back"),
+        }?;
+        writeln!(
+            f,
+            "{} packets came back more than once, {} out of order",
+            self.duplicated, self.reordered
+        )?;
+        if let Some(dropped_by_proxy) = &self.dropped_by_proxy {
+            let dropped = dropped_by_proxy.values().sum::<u64>();
+            writeln!(
+                f,
+                "The proxy dropped {} packets ({}), {} were lost elsewhere",
+                dropped,
+                dropped_by_proxy
+                    .iter()
+                    .map(|(name, dropped)| format!("{} {}", name, dropped))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                lost.saturating_sub(dropped)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response};
+
+    use super::{subcommand, Bench, Position, Received, Report};
+    use crate::test_utils::TestHelper;
+
+    /// Serves metrics whose drop counters go up by two on each request.
+    async fn serve_metrics() -> SocketAddr {
+        let requests = Arc::new(AtomicU64::new(0));
+        let make_service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let dropped = requests.fetch_add(1, Ordering::Relaxed) * 2;
+                    async move {
+                        Ok::<_, Infallible>(Response::new(Body::from(format!(
+                            "quilkin_proxy_packets_dropped_total{{reason=\"Overloaded\"}} {}\n\
+                             quilkin_session_packets_dropped_total 100\n",
+                            dropped
+                        ))))
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn bench(args: &[&str]) -> Result<Bench, String> {
+        let matches = subcommand().get_matches_from(args);
+        Bench::from_matches(&matches).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn from_matches() {
+        let b = bench(&["bench", "--target", "127.0.0.1:7000"]).unwrap();
+        assert_eq!(1, b.clients);
+        assert_eq!(100, b.rate);
+        assert_eq!(64, b.size);
+        assert_eq!(Duration::from_secs(10), b.duration);
+        assert!(b.tokens.is_empty());
+
+        let b = bench(&[
+            "bench",
+            "--target",
+            "127.0.0.1:7000",
+            "--token",
+            "YWJj",
+            "--token",
+            "eHl6",
+            "--token-position",
+            "PREFIX",
+        ])
+        .unwrap();
+        assert_eq!(vec![b"abc".to_vec(), b"xyz".to_vec()], b.tokens);
+        assert_eq!(Position::Prefix, b.position);
+
+        assert_eq!(
+            "`--size` must be at least 19 bytes to fit the token and the 16 bytes that identify each packet",
+            bench(&[
+                "bench",
+                "--target",
+                "127.0.0.1:7000",
+                "--size",
+                "18",
+                "--token",
+                "YWJj"
+            ])
+            .unwrap_err()
+        );
+        assert!(bench(&["bench", "--target", "127.0.0.1:7000", "--rate", "0"]).is_err());
+        assert_eq!(
+            "invalid value `x` for `--clients`: invalid digit found in string",
+            bench(&["bench", "--target", "127.0.0.1:7000", "--clients", "x"]).unwrap_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn run() {
+        let mut t = TestHelper::default();
+        let echo = t.run_echo_server().await;
+        let report = bench(&[
+            "bench",
+            "--target",
+            &echo.to_string(),
+            "--clients",
+            "2",
+            "--rate",
+            "50",
+            "--duration",
+            "200ms",
+            "--token",
+            "YWJj",
+            "--token-position",
+            "PREFIX",
+        ])
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+
+        assert!(report.sent >= 10, "{:?}", report);
+        assert_eq!(report.sent, report.received);
+        assert_eq!(report.received as usize, report.latencies.len());
+        assert!(report.percentile(50.0) <= report.percentile(99.0));
+        assert!(report.to_string().contains("0 lost (0.00%)"));
+        assert!(report
+            .to_string()
+            .contains("0 packets came back more than once, 0 out of order"));
+        assert!(report.dropped_by_proxy.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_by_proxy() {
+        let mut t = TestHelper::default();
+        let echo = t.run_echo_server().await;
+        let admin = serve_metrics().await;
+        let report = bench(&[
+            "bench",
+            "--target",
+            &echo.to_string(),
+            "--duration",
+            "50ms",
+            "--admin-address",
+            &admin.to_string(),
+        ])
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+
+        let dropped_by_proxy = report.dropped_by_proxy.as_ref().unwrap();
+        assert_eq!(
+            vec![
+                ("quilkin_filter_packets_dropped_total", 0),
+                ("quilkin_proxy_packets_dropped_total", 2),
+            ],
+            dropped_by_proxy
+                .iter()
+                .map(|(name, dropped)| (name.as_str(), *dropped))
+                .collect::<Vec<_>>()
+        );
+        assert!(report.to_string().contains(
+            "The proxy dropped 2 packets (quilkin_filter_packets_dropped_total 0, \
+             quilkin_proxy_packets_dropped_total 2), 0 were lost elsewhere"
+        ));
+    }
+
+    #[test]
+    fn received() {
+        let mut received = Received::default();
+        for sequence in [0, 2, 1, 2, 100, 70, 3].iter() {
+            received.record(*sequence, 80, Duration::from_millis(*sequence));
+        }
+        // 100 was never sent.
+        assert_eq!(5, received.unique);
+        assert_eq!(1, received.duplicated);
+        assert_eq!(2, received.reordered);
+        assert_eq!(
+            vec![0, 2, 1, 70, 3],
+            received
+                .latencies
+                .iter()
+                .map(|latency| latency.as_millis())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn percentiles() {
+        let report = Report {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..Report::default()
+        };
+        assert_eq!(Some(Duration::from_millis(50)), report.percentile(50.0));
+        assert_eq!(Some(Duration::from_millis(99)), report.percentile(99.0));
+        assert_eq!(None, Report::default().percentile(50.0));
+        assert!(Report::default()
+            .to_string()
+            .contains("no packets came back"));
+    }
+}
@@ -0,0 +1,565 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The `control` subcommand, which queries and controls a running proxy
+//! through its admin server.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use reqwest::header::AUTHORIZATION;
+use reqwest::Method;
+
+use super::Error;
+
+/// How long to wait for the admin server to respond, other than to a
+/// capture.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A request to the admin server, as asked for on the command line.
+pub(super) struct Request {
+    method: Method,
+    url: String,
+    authorization: Option<String>,
+    timeout: Option<Duration>,
+    output: Output,
+}
+
+/// What is done with the body of the response.
+enum Output {
+    /// It is written to stdout as is.
+    Print,
+    /// It is written to a file.
+    File(String),
+    /// It is metrics, whose totals are written to stdout if their name
+    /// starts with the prefix.
+    Summarize(Option<String>),
+}
+
+/// Returns the `control` subcommand.
+pub(super) fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    let address = |name: &'a str, help: &'a str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("ADDRESS")
+            .help(help)
+            .takes_value(true)
+    };
+    let value = |name: &'a str, value_name: &'a str, help: &'a str| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name(value_name)
+            .help(help)
+            .takes_value(true)
+    };
+
+    SubCommand::with_name("control")
+        .about("Queries and controls a running proxy through its admin server")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("admin-address")
+                .long("admin-address")
+                .value_name("URL")
+                .help("The address of the admin server")
+                .default_value("http://localhost:9091")
+                .global(true),
+        )
+        .arg(
+            value(
+                "authorization",
+                "VALUE",
+                "The `Authorization` header to send, if the admin server requires one",
+            )
+            .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("config").about("Prints the configuration the proxy runs with"),
+        )
+        .subcommand(
+            SubCommand::with_name("sessions")
+                .about("Lists the sessions of the proxy")
+                .arg(address("downstream", "Only lists the sessions of a client"))
+                .arg(address(
+                    "endpoint",
+                    "Only lists the sessions sending packets to an endpoint",
+                ))
+                .arg(value("limit", "COUNT", "The number of sessions to list"))
+                .arg(value("offset", "COUNT", "The number of sessions to skip")),
+        )
+        .subcommand(
+            SubCommand::with_name("clients")
+                .about("Lists the clients that sent the most packets recently")
+                .arg(value("top", "COUNT", "The number of clients to list"))
+                .arg(value(
+                    "window",
+                    "DURATION",
+                    "How far back to look at the traffic of clients",
+                ))
+                .arg(
+                    value("order", "ORDER", "What to rank clients by")
+                        .possible_values(&["packets", "bytes"]),
+                ),
+        )
+        .subcommand(SubCommand::with_name("events").about("Lists the recent changes to the proxy"))
+        .subcommand(
+            SubCommand::with_name("metrics")
+                .about("Prints the total of each metric of the proxy, across its labels")
+                .arg(value(
+                    "prefix",
+                    "PREFIX",
+                    "Only prints the metrics whose name starts with it",
+                ))
+                .arg(
+                    value("path", "PATH", "The path that metrics are served on")
+                        .default_value("/metrics"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("log-level")
+                .about("Prints the levels logs are written at, or changes them")
+                .arg(
+                    Arg::with_name("level")
+                        .value_name("LEVEL")
+                        .help("The level to write logs at")
+                        .possible_values(&[
+                            "critical", "error", "warning", "info", "debug", "trace",
+                        ])
+                        .conflicts_with("reset"),
+                )
+                .arg(value(
+                    "target",
+                    "TARGET",
+                    "The source or module of the logs to change the level of, rather than all logs",
+                ))
+                .arg(
+                    Arg::with_name("reset")
+                        .long("reset")
+                        .help("Makes the logs of the target use the level of all logs again")
+                        .requires("target"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("capture")
+                .about("Captures the packets passing through the proxy in the pcap format")
+                .arg(value(
+                    "packets",
+                    "COUNT",
+                    "The number of packets to capture",
+                ))
+                .arg(value(
+                    "duration",
+                    "DURATION",
+                    "How long to capture packets for",
+                ))
+                .arg(address("source", "Only captures the packets of a client"))
+                .arg(address(
+                    "endpoint",
+                    "Only captures the packets of an endpoint",
+                ))
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("The file to write the capture to, rather than stdout")
+                        .takes_value(true),
+                ),
+        )
+}
+
+impl Request {
+    /// Returns the request the arguments of the `control` subcommand ask for.
+    pub(super) fn from_matches(matches: &ArgMatches) -> Result<Self, Error> {
+        let (name, subcommand) = match matches.subcommand() {
+            (name, Some(subcommand)) => (name, subcommand),
+            (_, None) => return Err("a subcommand of `control` is required".into()),
+        };
+        // Global arguments may be passed before or after the subcommand, and
+        // only the matches they were passed to know that they weren't
+        // defaulted.
+        let global = |name: &str| {
+            if subcommand.occurrences_of(name) > 0 {
+                subcommand
+            } else {
+                matches
+            }
+            .value_of(name)
+            .map(String::from)
+        };
+        let base = admin_url(&global("admin-address").unwrap_or_default());
+        let authorization = global("authorization");
+        let matches = subcommand;
+
+        // The arguments of each subcommand are named after the query
+        // parameters they are sent as.
+        let query = |names: &[&str]| {
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            for name in names {
+                if let Some(value) = matches.value_of(name) {
+                    query.append_pair(name, value);
+                }
+            }
+            query.finish()
+        };
+        let (method, path, query, output) = match name {
+            "config" => (Method::GET, "/config_dump", String::new(), Output::Print),
+            "sessions" => (
+                Method::GET,
+                "/sessions",
+                query(&["downstream", "endpoint", "limit", "offset"]),
+                Output::Print,
+            ),
+            "clients" => (
+                Method::GET,
+                "/clients",
+                query(&["top", "window", "order"]),
+                Output::Print,
+            ),
+            "events" => (Method::GET, "/events", String::new(), Output::Print),
+            "metrics" => (
+                Method::GET,
+                matches.value_of("path").unwrap_or("/metrics"),
+                String::new(),
+                Output::Summarize(matches.value_of("prefix").map(String::from)),
+            ),
+            "log-level" => {
+                let method = if matches.is_present("reset") {
+                    Method::DELETE
+                } else if matches.is_present("level") {
+                    Method::POST
+                } else {
+                    Method::GET
+                };
+                (
+                    method,
+                    "/logging",
+                    query(&["target", "level"]),
+                    Output::Print,
+                )
+            }
+            "capture" => (
+                Method::POST,
+                "/capture",
+                query(&["packets", "duration", "source", "endpoint"]),
+                matches
+                    .value_of("output")
+                    .map_or(Output::Print, |path| Output::File(path.into())),
+            ),
+            name => return Err(format!("unknown subcommand `{}`", name).into()),
+        };
+
+        let mut url = format!("{}{}", base, path);
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+        Ok(Self {
+            method,
+            url,
+            authorization,
+            // Captures are streamed for as long as they last.
+            timeout: if name == "capture" {
+                None
+            } else {
+                Some(TIMEOUT)
+            },
+            output,
+        })
+    }
+
+    /// Sends the request and writes the response as asked for. Fails if the
+    /// admin server responds with an error.
+    pub(super) async fn send(self) -> Result<(), Error> {
+        let mut client = reqwest::Client::builder();
+        if let Some(timeout) = self.timeout {
+            client = client.timeout(timeout);
+        }
+        let mut request = client.build()?.request(self.method, &self.url);
+        if let Some(authorization) = self.authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let mut response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "the admin server responded to {} with {}: {}",
+                self.url,
+                status,
+                body.trim()
+            )
+            .into());
+        }
+
+        match self.output {
+            Output::Print => {
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                while let Some(chunk) = response.chunk().await? {
+                    stdout.write_all(&chunk)?;
+                    stdout.flush()?;
+                }
+            }
+            Output::File(path) => {
+                let mut file = File::create(&path)?;
+                while let Some(chunk) = response.chunk().await? {
+                    file.write_all(&chunk)?;
+                }
+            }
+            Output::Summarize(prefix) => {
+                let totals = summarize(&response.text().await?, prefix.as_deref());
+                let width = totals.keys().map(|name| name.len()).max().unwrap_or(0);
+                for (name, total) in totals {
+                    println!("{:width$}  {}", name, total, width = width);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the URL of the admin server at `address`, which may leave out the
+/// scheme.
+pub(super) fn admin_url(address: &str) -> String {
+    let address = address.trim_end_matches('/');
+    if address.contains("://") {
+        address.to_owned()
+    } else {
+        format!("http://{}", address)
+    }
+}
+
+/// Returns the total of each metric in the Prometheus text format `metrics`
+/// whose name starts with `prefix`, across its labels. The buckets of
+/// histograms are left out, as their totals are meaningless.
+pub(super) fn summarize(metrics: &str, prefix: Option<&str>) -> BTreeMap<String, f64> {
+    let mut totals = BTreeMap::new();
+    for line in metrics.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let name = line
+            .split(|c: char| c == '{' || c.is_whitespace())
+            .next()
+            .unwrap_or_default();
+        if name.ends_with("_bucket") || !name.starts_with(prefix.unwrap_or_default()) {
+            continue;
+        }
+        // The value follows the labels, which may contain spaces.
+        let value = line
+            .rsplit('}')
+            .next()
+            .unwrap_or(line)
+            .split_whitespace()
+            .find(|value| *value != name)
+            .and_then(|value| value.parse::<f64>().ok());
+        if let Some(value) = value {
+            *totals.entry(name.to_owned()).or_insert(0.0) += value;
+        }
+    }
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, StatusCode};
+    use reqwest::Method;
+
+    use super::{subcommand, summarize, Output, Request};
+
+    /// Responds to each request with its method and URI, or with an error
+    /// for `/logging` requests without a level.
+    async fn serve() -> SocketAddr {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: hyper::Request<Body>| async move {
+                let uri = request.uri().to_string();
+                Ok::<_, Infallible>(if uri.starts_with("/logging") && !uri.contains("level=") {
+                    Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from("missing level\n"))
+                        .unwrap()
+                } else {
+                    Response::new(Body::from(format!("{} {}", request.method(), uri)))
+                })
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn request(args: &[&str]) -> Request {
+        let matches = subcommand().get_matches_from(args);
+        Request::from_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn from_matches() {
+        let r = request(&["control", "config"]);
+        assert_eq!(Method::GET, r.method);
+        assert_eq!("http://localhost:9091/config_dump", r.url);
+        assert!(r.authorization.is_none());
+
+        let r = request(&[
+            "control",
+            "--authorization",
+            "Basic YWRtaW46aHVudGVyMg==",
+            "config",
+        ]);
+        assert_eq!(
+            Some("Basic YWRtaW46aHVudGVyMg=="),
+            r.authorization.as_deref()
+        );
+
+        let r = request(&[
+            "control",
+            "sessions",
+            "--downstream",
+            "10.0.0.5",
+            "--limit",
+            "10",
+            "--admin-address",
+            "127.0.0.1:9000/",
+        ]);
+        assert_eq!(
+            "http://127.0.0.1:9000/sessions?downstream=10.0.0.5&limit=10",
+            r.url
+        );
+
+        let r = request(&[
+            "control",
+            "log-level",
+            "debug",
+            "--target",
+            "extensions::TokenRouter",
+        ]);
+        assert_eq!(Method::POST, r.method);
+        assert_eq!(
+            "http://localhost:9091/logging?target=extensions%3A%3ATokenRouter&level=debug",
+            r.url
+        );
+        let r = request(&["control", "log-level", "--reset", "--target", "quilkin"]);
+        assert_eq!(Method::DELETE, r.method);
+        assert_eq!("http://localhost:9091/logging?target=quilkin", r.url);
+        assert_eq!(Method::GET, request(&["control", "log-level"]).method);
+
+        let r = request(&[
+            "control",
+            "capture",
+            "--packets",
+            "100",
+            "-o",
+            "capture.pcap",
+        ]);
+        assert_eq!(Method::POST, r.method);
+        assert_eq!("http://localhost:9091/capture?packets=100", r.url);
+        assert!(matches!(r.output, Output::File(path) if path == "capture.pcap"));
+
+        let r = request(&[
+            "control",
+            "metrics",
+            "--admin-address",
+            "https://proxy:9091",
+            "--path",
+            "/stats/prometheus",
+            "--authorization",
+            "Basic cHJvbWV0aGV1czpodW50ZXIz",
+        ]);
+        assert_eq!("https://proxy:9091/stats/prometheus", r.url);
+        assert_eq!(
+            Some("Basic cHJvbWV0aGV1czpodW50ZXIz"),
+            r.authorization.as_deref()
+        );
+    }
+
+    #[tokio::test]
+    async fn send() {
+        let addr = serve().await;
+        let output = std::env::temp_dir().join(format!("quilkin-{}.pcap", uuid::Uuid::new_v4()));
+        let r = request(&[
+            "control",
+            "--admin-address",
+            &addr.to_string(),
+            "capture",
+            "--duration",
+            "5s",
+            "-o",
+            output.to_str().unwrap(),
+        ]);
+        assert!(r.timeout.is_none());
+        r.send().await.unwrap();
+        assert_eq!(
+            "POST /capture?duration=5s",
+            std::fs::read_to_string(&output).unwrap()
+        );
+        std::fs::remove_file(&output).unwrap();
+
+        let err = request(&[
+            "control",
+            "--admin-address",
+            &addr.to_string(),
+            "log-level",
+            "--target",
+            "quilkin",
+        ])
+        .send()
+        .await
+        .unwrap_err();
+        assert_eq!(
+            format!(
+                "the admin server responded to http://{}/logging?target=quilkin with 400 Bad Request: missing level",
+                addr
+            ),
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn summarize_metrics() {
+        let metrics = r#"
+# HELP quilkin_session_active Number of sessions currently active
+# TYPE quilkin_session_active gauge
+quilkin_session_active{port="7000"} 3
+quilkin_session_active{port="7001"} 2
+# TYPE quilkin_packets_total counter
+quilkin_packets_total{event="read",reason="a b"} 10
+quilkin_packets_total{event="write",reason="}"} 5.5
+quilkin_filter_read_duration_seconds_bucket{le="0.005"} 4
+quilkin_filter_read_duration_seconds_count 4
+process_open_fds 12
+"#;
+        let totals: Vec<_> = summarize(metrics, Some("quilkin_")).into_iter().collect();
+        assert_eq!(
+            vec![
+                ("quilkin_filter_read_duration_seconds_count".to_owned(), 4.0),
+                ("quilkin_packets_total".to_owned(), 15.5),
+                ("quilkin_session_active".to_owned(), 5.0),
+            ],
+            totals
+        );
+        assert_eq!(
+            Some(&12.0),
+            summarize(metrics, None).get("process_open_fds")
+        );
+    }
+}
@@ -0,0 +1,168 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Fetching of the configuration from a config service over HTTP(S).
+
+use std::time::Duration;
+
+use reqwest::header::AUTHORIZATION;
+use ring::digest::{digest, SHA256};
+
+use super::Error;
+
+/// How long to wait for the config service to respond in full.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+type SendError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A request for a configuration.
+pub(super) struct Request {
+    pub url: String,
+    /// The value of the `Authorization` header to send, if any.
+    pub authorization: Option<String>,
+    /// The hex encoded SHA-256 checksum that the configuration must match,
+    /// if any.
+    pub sha256: Option<String>,
+}
+
+impl Request {
+    /// Returns whether `path` is a URL to fetch the configuration from.
+    pub fn is_url(path: &str) -> bool {
+        path.starts_with("http://") || path.starts_with("https://")
+    }
+
+    /// Fetches the configuration, verifying its checksum if one was given.
+    pub fn fetch(&self) -> Result<Vec<u8>, Error> {
+        let url = self.url.clone();
+        let authorization = self.authorization.clone();
+        // Use a runtime on a thread of its own, as this may be called from
+        // within a runtime that must not be blocked on.
+        let body = std::thread::spawn(move || -> Result<Vec<u8>, SendError> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(async move {
+                let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+                let mut request = client.get(&url);
+                if let Some(authorization) = authorization {
+                    request = request.header(AUTHORIZATION, authorization);
+                }
+                let response = request.send().await?.error_for_status()?;
+                Ok(response.bytes().await?.to_vec())
+            })
+        })
+        .join()
+        .map_err(|_| "fetching the configuration panicked")?
+        .map_err(|err| err as Error)?;
+
+        if let Some(expected) = &self.sha256 {
+            let actual = hex(digest(&SHA256, &body).as_ref());
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(format!(
+                    "checksum mismatch, expected SHA-256 {} but got {}",
+                    expected, actual
+                )
+                .into());
+            }
+        }
+        Ok(body)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, StatusCode};
+
+    use super::Request;
+
+    const CONFIG: &str = "version: v1alpha1";
+    const CONFIG_SHA256: &str = "a70cefa7c1d0994eefd697feefea07b2441d21de53704d9cbd81947b70fc09c3";
+
+    /// Serves `CONFIG` to requests authorized as `Bearer token`.
+    async fn serve() -> SocketAddr {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: hyper::Request<Body>| async move {
+                let authorized = request
+                    .headers()
+                    .get(hyper::header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    == Some("Bearer token");
+                Ok::<_, Infallible>(if authorized {
+                    Response::new(Body::from(CONFIG))
+                } else {
+                    Response::builder()
+                        .status(StatusCode::UNAUTHORIZED)
+                        .body(Body::empty())
+                        .unwrap()
+                })
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn fetch() {
+        let addr = serve().await;
+        // Fetching blocks, so it must not hold up the runtime serving it.
+        let fetch = |authorization: Option<&str>, sha256: Option<&str>| {
+            let request = Request {
+                url: format!("http://{}/quilkin.yaml", addr),
+                authorization: authorization.map(String::from),
+                sha256: sha256.map(String::from),
+            };
+            async move {
+                tokio::task::spawn_blocking(move || request.fetch().map_err(|err| err.to_string()))
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(
+            CONFIG.as_bytes(),
+            &fetch(Some("Bearer token"), None).await.unwrap()[..]
+        );
+        assert_eq!(
+            CONFIG.as_bytes(),
+            &fetch(Some("Bearer token"), Some(&CONFIG_SHA256.to_uppercase()))
+                .await
+                .unwrap()[..]
+        );
+        assert!(fetch(Some("Bearer token"), Some(&"0".repeat(64)))
+            .await
+            .unwrap_err()
+            .contains("checksum mismatch"));
+        assert!(fetch(None, None).await.is_err());
+    }
+
+    #[test]
+    fn is_url() {
+        assert!(Request::is_url("https://example.com/quilkin.yaml"));
+        assert!(Request::is_url("http://127.0.0.1:8080/quilkin.yaml"));
+        assert!(!Request::is_url("quilkin.yaml"));
+        assert!(!Request::is_url("/etc/quilkin/https://quilkin.yaml"));
+    }
+}
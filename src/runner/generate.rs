@@ -0,0 +1,217 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Generation of example configurations from the schemas of filters.
+
+use serde_yaml::Value;
+
+use super::Error;
+use crate::filters::DynFilterFactory;
+
+/// The indentation of the properties of a filter's configuration.
+const CONFIG_INDENT: &str = "        ";
+
+/// Returns a commented example configuration that runs packets through the
+/// filters created by `factories`, in order.
+pub(super) fn generate(factories: &[&DynFilterFactory]) -> Result<String, Error> {
+    let mut config = String::from(
+        "\
+# An example configuration generated by `quilkin generate-config`.
+version: v1alpha1
+proxy:
+  # The port to receive traffic on.
+  port: 7000
+static:
+",
+    );
+
+    if factories.is_empty() {
+        config.push_str("  filters: []\n");
+    } else {
+        config.push_str("  filters:\n");
+    }
+    for factory in factories {
+        config.push_str(&format!("    - name: {}\n", factory.name()));
+        match factory.config_schema() {
+            Some(schema) => {
+                let schema = serde_yaml::from_str(schema).map_err(|err| {
+                    format!("invalid config schema of `{}`: {}", factory.name(), err)
+                })?;
+                config.push_str("      config:\n");
+                push_properties(&mut config, &schema);
+            }
+            None => config.push_str(
+                "      # This filter does not describe its configuration, see its documentation.\n",
+            ),
+        }
+    }
+
+    config.push_str("  endpoints:\n");
+    config.push_str("    # The address of the game server to send traffic to.\n");
+    config.push_str("    - address: 127.0.0.1:26000\n");
+    Ok(config)
+}
+
+/// Writes an example value for every property in `schema`, preceded by its
+/// description as a comment.
+fn push_properties(config: &mut String, schema: &Value) {
+    let required: Vec<_> = schema
+        .get("required")
+        .and_then(Value::as_sequence)
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let properties = match schema.get("properties").and_then(Value::as_mapping) {
+        Some(properties) if !properties.is_empty() => properties,
+        _ => {
+            config.push_str(&format!("{}{{}}\n", CONFIG_INDENT));
+            return;
+        }
+    };
+
+    for (name, property) in properties {
+        let name = match name.as_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let description = property
+            .get("description")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        for line in description.trim_end().lines() {
+            config.push_str(CONFIG_INDENT);
+            config.push_str(format!("# {}", line).trim_end());
+            config.push('\n');
+        }
+        if let Some(values) = property.get("enum").and_then(Value::as_sequence) {
+            let values: Vec<_> = values.iter().map(to_yaml).collect();
+            config.push_str(&format!(
+                "{}# One of: {}\n",
+                CONFIG_INDENT,
+                values.join(", ")
+            ));
+        }
+        if required.contains(&name) {
+            config.push_str(&format!("{}# Required.\n", CONFIG_INDENT));
+        }
+        config.push_str(&format!(
+            "{}{}: {}\n",
+            CONFIG_INDENT,
+            name,
+            to_yaml(&example(property))
+        ));
+    }
+}
+
+/// Returns the value to write for `property`, preferring its example over
+/// its default and falling back to an empty value of its type.
+fn example(property: &Value) -> Value {
+    if let Some(value) = property.get("example").or_else(|| property.get("default")) {
+        return value.clone();
+    }
+    if let Some(value) = property
+        .get("enum")
+        .and_then(Value::as_sequence)
+        .and_then(|values| values.first())
+    {
+        return value.clone();
+    }
+    match property.get("type").and_then(Value::as_str) {
+        Some("integer") | Some("number") => Value::from(0),
+        Some("boolean") => Value::from(false),
+        Some("string") => Value::from(""),
+        Some("array") => Value::Sequence(vec![]),
+        Some("object") => Value::Mapping(Default::default()),
+        _ => Value::Null,
+    }
+}
+
+/// Returns `value` written as inline YAML.
+fn to_yaml(value: &Value) -> String {
+    match value {
+        Value::Sequence(values) if values.is_empty() => "[]".into(),
+        Value::Mapping(values) if values.is_empty() => "{}".into(),
+        value => serde_yaml::to_string(value)
+            .map(|yaml| yaml.trim_start_matches("---").trim().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::generate;
+    use crate::config::Config;
+    use crate::filters::{CreateFilterArgs, DynFilterFactory, Error, Filter, FilterFactory};
+    use crate::proxy::{logger, Builder};
+
+    struct Undocumented;
+
+    impl FilterFactory for Undocumented {
+        fn name(&self) -> &'static str {
+            "undocumented"
+        }
+
+        fn create_filter(&self, _: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+            unimplemented!()
+        }
+    }
+
+    // Some filters start background tasks when they are created.
+    #[tokio::test]
+    async fn generate_valid_config() {
+        let factories: Vec<DynFilterFactory> = crate::filters::FilterSet::default(&logger())
+            .into_iter()
+            .collect();
+        let config = generate(&factories.iter().collect::<Vec<_>>()).unwrap();
+
+        let config = Config::from_reader(config.as_bytes()).unwrap();
+        assert_eq!(
+            factories.len(),
+            config.source.get_static_filters().unwrap().len()
+        );
+        Builder::from(Arc::new(config)).validate().unwrap();
+
+        let config = generate(&[]).unwrap();
+        Builder::from(Arc::new(Config::from_reader(config.as_bytes()).unwrap()))
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn generate_commented_config() {
+        let compress = crate::filters::extensions::CompressFactory::new(&logger());
+        let compress: DynFilterFactory = Box::new(compress);
+        let undocumented: DynFilterFactory = Box::new(Undocumented);
+        let config = generate(&[&compress, &undocumented]).unwrap();
+
+        assert!(config.contains(
+            "
+    - name: quilkin.extensions.filters.compress.v1alpha1.Compress
+      config:
+        # Whether to compress, decompress or do nothing when reading packets from the local listening port
+        # One of: DO_NOTHING, COMPRESS, DECOMPRESS
+        on_read: DO_NOTHING
+"
+        ));
+        assert!(config.contains(
+            "
+    - name: undocumented
+      # This filter does not describe its configuration, see its documentation.
+"
+        ));
+    }
+}
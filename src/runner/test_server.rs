@@ -0,0 +1,200 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The `test-server` subcommand, which stands in for a game server behind a
+//! proxy by responding to each packet it receives.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use slog::{debug, info, o, warn, Logger};
+use tokio::net::UdpSocket;
+
+use super::Error;
+
+/// How the server responds to packets.
+#[derive(Debug)]
+pub(super) struct TestServer {
+    port: u16,
+    /// What to respond with, rather than the packet itself.
+    response: Option<Vec<u8>>,
+    /// How long to wait before responding.
+    delay: Option<Duration>,
+}
+
+/// Returns the `test-server` subcommand.
+pub(super) fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("test-server")
+        .about("Runs a UDP server that echoes packets back, to stand in for a game server behind a proxy")
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .value_name("PORT")
+                .help("The port to receive packets on")
+                .default_value("8000"),
+        )
+        .arg(
+            Arg::with_name("response")
+                .long("response")
+                .value_name("TEXT")
+                .help("What to respond to each packet with, rather than the packet itself")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("delay")
+                .long("delay")
+                .value_name("DURATION")
+                .help("How long to wait before responding to each packet, e.g `50ms`")
+                .takes_value(true),
+        )
+}
+
+impl TestServer {
+    /// Returns the server the arguments of the `test-server` subcommand ask
+    /// for.
+    pub(super) fn from_matches(matches: &ArgMatches) -> Result<Self, Error> {
+        let port = matches.value_of("port").unwrap_or_default();
+        Ok(Self {
+            port: port
+                .parse()
+                .map_err(|err| format!("invalid value `{}` for `--port`: {}", port, err))?,
+            response: matches
+                .value_of("response")
+                .map(|response| response.as_bytes().to_vec()),
+            delay: matches
+                .value_of("delay")
+                .map(humantime::parse_duration)
+                .transpose()
+                .map_err(|err| format!("invalid value for `--delay`: {}", err))?,
+        })
+    }
+
+    /// Runs the server until the process is interrupted.
+    pub(super) async fn run(self, log: Logger) -> Result<(), Error> {
+        let log = log.new(o!("source" => "test_server"));
+        let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.port))).await?;
+        info!(log, "Starting test server"; "port" => self.port);
+        tokio::select! {
+            result = self.serve(log.clone(), Arc::new(socket)) => Ok(result?),
+            result = tokio::signal::ctrl_c() => {
+                info!(log, "Shutting down test server");
+                Ok(result?)
+            }
+        }
+    }
+
+    /// Responds to the packets received on `socket`, forever unless it fails.
+    async fn serve(self, log: Logger, socket: Arc<UdpSocket>) -> io::Result<()> {
+        let mut buffer = vec![0; u16::MAX as usize];
+        loop {
+            let (size, from) = socket.recv_from(&mut buffer).await?;
+            debug!(log, "Received packet"; "from" => from, "size" => size);
+            let response = match &self.response {
+                Some(response) => response.clone(),
+                None => buffer[..size].to_vec(),
+            };
+
+            // Delayed responses are sent in the background, so that each
+            // packet is delayed by the same amount rather than queuing up
+            // behind the packets before it.
+            let socket = socket.clone();
+            let log = log.clone();
+            let respond = async move {
+                if let Err(err) = socket.send_to(&response, from).await {
+                    warn!(log, "Failed to respond to packet"; "to" => from, "error" => %err);
+                }
+            };
+            match self.delay {
+                Some(delay) => {
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        respond.await
+                    });
+                }
+                None => respond.await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use tokio::time::{timeout, Instant};
+
+    use super::{subcommand, TestServer};
+    use crate::test_utils::logger;
+
+    /// Starts a server with `args` on an ephemeral port, and returns a socket
+    /// connected to it.
+    async fn start(args: &[&str]) -> UdpSocket {
+        let matches = subcommand().get_matches_from(args);
+        let server = TestServer::from_matches(&matches).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(server.serve(logger(), Arc::new(socket)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(address).await.unwrap();
+        client
+    }
+
+    async fn recv(client: &UdpSocket) -> String {
+        let mut buffer = [0; 64];
+        let size = timeout(Duration::from_secs(5), client.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        String::from_utf8_lossy(&buffer[..size]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn echo() {
+        let client = start(&["test-server"]).await;
+        client.send(b"hello").await.unwrap();
+        assert_eq!("hello", recv(&client).await);
+        client.send(b"world").await.unwrap();
+        assert_eq!("world", recv(&client).await);
+    }
+
+    #[tokio::test]
+    async fn response_and_delay() {
+        let client = start(&["test-server", "--response", "pong", "--delay", "200ms"]).await;
+        let sent = Instant::now();
+        client.send(b"ping").await.unwrap();
+        client.send(b"ping").await.unwrap();
+        assert_eq!("pong", recv(&client).await);
+        assert_eq!("pong", recv(&client).await);
+        let elapsed = sent.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let matches = subcommand().get_matches_from(["test-server", "--delay", "soon"].iter());
+        assert!(TestServer::from_matches(&matches)
+            .unwrap_err()
+            .to_string()
+            .starts_with("invalid value for `--delay`"));
+    }
+}
@@ -14,11 +14,51 @@
  * limitations under the License.
  */
 
-/// Common utilities for testing
+//! Helpers for testing filters and proxies, such as echo servers standing in
+//! for game servers, and a harness running proxies in the background.
+//!
+//! They are enabled by the `test-utils` feature, e.g for the tests of a crate
+//! with custom filters:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! quilkin = { version = "0.2", features = ["test-utils"] }
+//! ```
+//!
+//! A test can then send packets through a proxy to an echo server, and check
+//! what comes back:
+//!
+//! ```
+//! use quilkin::config::{Builder, EndPoint};
+//! use quilkin::test_utils::TestHelper;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let mut t = TestHelper::default();
+//! let echo = t.run_echo_server().await;
+//! t.run_server_with_config(
+//!     Builder::empty()
+//!         .with_port(12373)
+//!         .with_static(vec![], vec![EndPoint::new(echo)])
+//!         .build(),
+//! );
+//!
+//! let (mut packets, socket) = t.open_socket_and_recv_multiple_packets().await;
+//! socket.send_to(b"hello", "127.0.0.1:12373").await.unwrap();
+//! assert_eq!("hello", packets.recv().await.unwrap());
+//! # }
+//! ```
+//!
+//! Everything the helper starts is stopped when it is dropped.
+
+pub mod corpus;
+pub mod prop;
+
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::str::from_utf8;
 use std::sync::Arc;
 
+use bytes::Bytes;
 use slog::{o, warn, Drain, Logger};
 use slog_term::{FullFormat, PlainSyncDecorator};
 use tokio::net::UdpSocket;
@@ -29,6 +69,7 @@ use crate::config::{Builder as ConfigBuilder, Config, EndPoint, Endpoints};
 use crate::filters::{prelude::*, DynFilterFactory, FilterChain, FilterRegistry, FilterSet};
 use crate::proxy::{Builder, PendingValidation};
 
+/// Creates [`TestFilter`]s, under the name `TestFilter`.
 pub struct TestFilterFactory {}
 impl FilterFactory for TestFilterFactory {
     fn name(&self) -> &'static str {
@@ -40,7 +81,11 @@ impl FilterFactory for TestFilterFactory {
     }
 }
 
-// TestFilter is useful for testing that commands are executing filters appropriately.
+/// A filter that appends to the contents and metadata of each packet, which
+/// is useful for testing that filters are run, and in what order.
+///
+/// Packets read get `:odr:<from>` appended, and packets written
+/// `:our:<from>:<to>`.
 pub struct TestFilter {}
 
 impl Filter for TestFilter {
@@ -51,8 +96,12 @@ impl Filter for TestFilter {
             .and_modify(|e| e.downcast_mut::<String>().unwrap().push_str(":receive"))
             .or_insert_with(|| Box::new("receive".to_string()));
 
-        ctx.contents
-            .append(&mut format!(":odr:{}", ctx.from).into_bytes());
+        ctx.contents = [
+            ctx.contents.as_ref(),
+            format!(":odr:{}", ctx.from).as_bytes(),
+        ]
+        .concat()
+        .into();
         Some(ctx.into())
     }
 
@@ -63,21 +112,28 @@ impl Filter for TestFilter {
             .and_modify(|e| e.downcast_mut::<String>().unwrap().push_str(":receive"))
             .or_insert_with(|| Box::new("receive".to_string()));
 
-        ctx.contents
-            .append(&mut format!(":our:{}:{}", ctx.from, ctx.to).into_bytes());
+        ctx.contents = [
+            ctx.contents.as_ref(),
+            format!(":our:{}:{}", ctx.from, ctx.to).as_bytes(),
+        ]
+        .concat()
+        .into();
         Some(ctx.into())
     }
 }
 
-// logger returns a standard out, non structured terminal logger, suitable for using in tests,
-// since it's more human readable.
+/// Returns a logger writing human readable, rather than structured, logs to
+/// stdout, which the test harness only shows for failing tests.
 pub fn logger() -> Logger {
     let plain = PlainSyncDecorator::new(std::io::stdout());
     let drain = FullFormat::new(plain).build().fuse();
     Logger::root(drain, o!())
 }
 
+/// Starts echo servers, sockets and proxies in the background of a test, and
+/// stops them all when dropped.
 pub struct TestHelper {
+    /// The logger of the helper, as returned by [`logger`].
     pub log: Logger,
     /// Channel to subscribe to, and trigger the shutdown of created resources.
     shutdown_ch: Option<(watch::Sender<()>, watch::Receiver<()>)>,
@@ -228,6 +284,7 @@ impl TestHelper {
         self.run_server_with_builder(Builder::from(Arc::new(config)).disable_admin());
     }
 
+    /// Run a proxy server built by `builder`, which must be valid.
     pub fn run_server_with_builder(&mut self, builder: Builder<PendingValidation>) {
         let (shutdown_tx, shutdown_rx) = watch::channel::<()>(());
         self.server_shutdown_tx.push(Some(shutdown_tx));
@@ -257,7 +314,27 @@ impl TestHelper {
     }
 }
 
-/// assert that read makes no changes
+/// Returns the context of a packet with `contents` received from `from`, to
+/// be sent to `endpoints`, to test [`Filter::read`] with. Panics if there are
+/// no endpoints.
+pub fn read_context(
+    from: SocketAddr,
+    endpoints: Vec<Endpoint>,
+    contents: impl Into<Bytes>,
+) -> ReadContext {
+    ReadContext::new(Endpoints::new(endpoints).unwrap().into(), from, contents)
+}
+
+/// Returns the reason a filter dropped a packet for, given the `response` it
+/// returned for the packet, or `None` if it did not drop it or gave no reason.
+pub fn drop_reason<T>(response: Option<T>) -> Option<DropReason> {
+    match response {
+        Some(_) => None,
+        None => DropReason::take(),
+    }
+}
+
+/// Asserts that [`Filter::read`] passes a packet on unchanged.
 pub fn assert_filter_read_no_change<F>(filter: &F)
 where
     F: Filter,
@@ -266,11 +343,7 @@ where
     let from = "127.0.0.1:90".parse().unwrap();
     let contents = "hello".to_string().into_bytes();
 
-    match filter.read(ReadContext::new(
-        Endpoints::new(endpoints.clone()).unwrap().into(),
-        from,
-        contents.clone(),
-    )) {
+    match filter.read(read_context(from, endpoints.clone(), contents.clone())) {
         None => unreachable!("should return a result"),
         Some(response) => {
             assert_eq!(
@@ -282,7 +355,7 @@ where
     }
 }
 
-/// assert that write makes no changes
+/// Asserts that [`Filter::write`] passes a packet on unchanged.
 pub fn assert_write_no_change<F>(filter: &F)
 where
     F: Filter,
@@ -301,17 +374,21 @@ where
     }
 }
 
+/// Returns a configuration builder with a single endpoint, which nothing
+/// listens on.
 pub fn config_with_dummy_endpoint() -> ConfigBuilder {
     ConfigBuilder::empty().with_static(
         vec![],
         vec![EndPoint::new("127.0.0.1:8080".parse().unwrap())],
     )
 }
+
 /// Creates a dummy endpoint with `id` as a suffix.
 pub fn ep(id: u8) -> EndPoint {
     EndPoint::new(format!("127.0.0.{:?}:8080", id).parse().unwrap())
 }
 
+/// Returns a filter chain of a single [`TestFilter`].
 pub fn new_test_chain(registry: &prometheus::Registry) -> Arc<FilterChain> {
     Arc::new(
         FilterChain::new(
@@ -322,6 +399,8 @@ pub fn new_test_chain(registry: &prometheus::Registry) -> Arc<FilterChain> {
     )
 }
 
+/// Returns a registry of the built-in filters along with
+/// [`TestFilterFactory`].
 pub fn new_registry(log: &slog::Logger) -> FilterRegistry {
     FilterRegistry::new(FilterSet::default_with(
         log,
@@ -0,0 +1,538 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Corpora of golden packets: packets recorded along with what a filter chain
+//! did with them, which are replayed through the chain of a later version to
+//! check that it still does the same.
+//!
+//! Packets are recorded wherever they go through a [`FilterChain`], such as
+//! a service running Quilkin's filters itself, and the corpus is written to a
+//! YAML file:
+//!
+//! ```
+//! use quilkin::config::{EndPoint, Filter};
+//! use quilkin::filters::{prelude::*, FilterRegistry, FilterSet};
+//! use quilkin::test_utils::corpus::Corpus;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let log = quilkin::test_utils::logger();
+//! let registry = FilterRegistry::new(FilterSet::default(&log));
+//! let mut corpus = Corpus::new(
+//!     vec![Filter {
+//!         name: "quilkin.extensions.filters.compress.v1alpha1.Compress".into(),
+//!         config: serde_yaml::from_str("{on_read: COMPRESS, on_write: DECOMPRESS}")?,
+//!     }],
+//!     vec![EndPoint::new("127.0.0.1:7001".parse()?)],
+//! );
+//! let chain = corpus.chain(&registry)?;
+//! let response = corpus.record_read(&chain, "127.0.0.1:7000".parse()?, b"hello".to_vec());
+//! assert!(response.is_some());
+//!
+//! let mut file = Vec::new();
+//! corpus.to_writer(&mut file)?;
+//! # let _ = file;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A test then replays the file, failing with every packet whose outcome has
+//! changed:
+//!
+//! ```no_run
+//! # use quilkin::filters::{FilterRegistry, FilterSet};
+//! # use quilkin::test_utils::corpus::Corpus;
+//! # let registry = FilterRegistry::new(FilterSet::default(&quilkin::test_utils::logger()));
+//! let corpus = Corpus::from_file("tests/corpus/compress_token_router.yaml".as_ref()).unwrap();
+//! corpus.replay(&corpus.chain(&registry).unwrap()).unwrap();
+//! ```
+//!
+//! Only metadata values that are bytes or strings, such as the tokens captured
+//! by `CaptureBytes`, are recorded, others are left out.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use base64_serde::base64_serde_type;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Endpoint;
+use crate::config::{EndPoint, Endpoints, Filter as FilterConfig};
+use crate::filters::{
+    DropReason, Filter, FilterChain, FilterChainError, FilterRegistry, ReadContext, ReadResponse,
+    WriteContext, WriteResponse,
+};
+
+base64_serde_type!(Base64Standard, base64::STANDARD);
+
+/// A filter chain along with the packets recorded going through it.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Corpus {
+    /// The filters of the chain, in the same format as a configuration file.
+    pub filters: Vec<FilterConfig>,
+    /// The endpoints packets that are read can be sent to.
+    pub endpoints: Vec<EndPoint>,
+    /// The packets, in the order they were recorded.
+    #[serde(default)]
+    pub packets: Vec<Packet>,
+}
+
+/// Which way a packet went through a filter chain.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// From a client, to the endpoints.
+    Read,
+    /// From an endpoint, to a client.
+    Write,
+}
+
+/// A packet recorded going through a filter chain, and what the chain did
+/// with it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Packet {
+    pub direction: Direction,
+    /// Where the packet came from, a client or an endpoint.
+    pub from: SocketAddr,
+    /// The client a written packet is sent to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<SocketAddr>,
+    #[serde(with = "Base64Standard")]
+    pub contents: Vec<u8>,
+    /// The metadata the packet started with.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, Value>,
+    pub outcome: Outcome,
+}
+
+/// What a filter chain did with a packet.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The packet was dropped, for the reason given.
+    Dropped(String),
+    /// The packet was passed on.
+    Forwarded {
+        #[serde(with = "Base64Standard")]
+        contents: Vec<u8>,
+        /// The addresses of the endpoints a packet that is read is sent to.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        endpoints: Vec<SocketAddr>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        metadata: BTreeMap<String, Value>,
+    },
+}
+
+/// A metadata value that can be recorded.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Value {
+    Bytes(#[serde(with = "Base64Standard")] Vec<u8>),
+    String(String),
+}
+
+impl Value {
+    fn from_any(value: &(dyn Any + Send)) -> Option<Self> {
+        if let Some(bytes) = value.downcast_ref::<Vec<u8>>() {
+            Some(Value::Bytes(bytes.clone()))
+        } else {
+            value
+                .downcast_ref::<String>()
+                .map(|string| Value::String(string.clone()))
+        }
+    }
+
+    pub(super) fn into_any(self) -> Box<dyn Any + Send> {
+        match self {
+            Value::Bytes(bytes) => Box::new(bytes),
+            Value::String(string) => Box::new(string),
+        }
+    }
+}
+
+impl Corpus {
+    /// Returns a corpus without any packets, for `filters` sending packets to
+    /// `endpoints`.
+    pub fn new(filters: Vec<FilterConfig>, endpoints: Vec<EndPoint>) -> Self {
+        Self {
+            filters,
+            endpoints,
+            packets: Vec::new(),
+        }
+    }
+
+    /// Returns the corpus written as YAML to `input`.
+    pub fn from_reader<R: io::Read>(input: R) -> Result<Self, serde_yaml::Error> {
+        let corpus: Self = serde_yaml::from_reader(input)?;
+        corpus.cluster().map_err(serde::de::Error::custom)?;
+        if let Some(index) = corpus
+            .packets
+            .iter()
+            .position(|packet| packet.direction == Direction::Write && packet.to.is_none())
+        {
+            return Err(serde::de::Error::custom(format!(
+                "packet {} is written but has no `to` address",
+                index
+            )));
+        }
+        Ok(corpus)
+    }
+
+    /// Returns the corpus in the file at `path`.
+    pub fn from_file(path: &Path) -> Result<Self, serde_yaml::Error> {
+        Self::from_reader(File::open(path).map_err(serde::de::Error::custom)?)
+    }
+
+    /// Writes the corpus to `output` as YAML.
+    pub fn to_writer<W: io::Write>(&self, output: W) -> Result<(), serde_yaml::Error> {
+        serde_yaml::to_writer(output, self)
+    }
+
+    /// Returns a chain of the corpus' filters, created from `registry`.
+    pub fn chain(&self, registry: &FilterRegistry) -> Result<FilterChain, FilterChainError> {
+        FilterChain::try_create(
+            self.filters.clone(),
+            registry,
+            &prometheus::Registry::default(),
+        )
+    }
+
+    /// Passes a packet with `contents` received from `from` through `chain`,
+    /// to the corpus' endpoints, and records it along with the outcome.
+    /// Panics if the corpus has invalid endpoints.
+    pub fn record_read(
+        &mut self,
+        chain: &FilterChain,
+        from: SocketAddr,
+        contents: Vec<u8>,
+    ) -> Option<ReadResponse> {
+        let packet = Packet {
+            direction: Direction::Read,
+            from,
+            to: None,
+            contents,
+            metadata: BTreeMap::new(),
+            // Replaced below.
+            outcome: Outcome::Dropped(String::new()),
+        };
+        let endpoints = self.cluster().unwrap();
+        let response = read(chain, endpoints, &packet);
+        self.push(packet, read_outcome(&response));
+        response
+    }
+
+    /// Passes a packet with `contents` sent by the endpoint at `from` to the
+    /// client at `to` through `chain`, and records it along with the
+    /// outcome. Panics if the corpus has invalid endpoints.
+    pub fn record_write(
+        &mut self,
+        chain: &FilterChain,
+        from: SocketAddr,
+        to: SocketAddr,
+        contents: Vec<u8>,
+    ) -> Option<WriteResponse> {
+        let packet = Packet {
+            direction: Direction::Write,
+            from,
+            to: Some(to),
+            contents,
+            metadata: BTreeMap::new(),
+            outcome: Outcome::Dropped(String::new()),
+        };
+        let endpoints = self.cluster().unwrap();
+        let response = write(chain, &endpoints, &packet);
+        self.push(packet, write_outcome(&response));
+        response
+    }
+
+    /// Passes each packet through `chain`, returning an error describing the
+    /// packets whose outcome differs from the one recorded.
+    pub fn replay(&self, chain: &FilterChain) -> Result<(), String> {
+        let endpoints = self.cluster()?;
+        let mismatches = self
+            .packets
+            .iter()
+            .enumerate()
+            .filter_map(|(index, packet)| {
+                let outcome = match packet.direction {
+                    Direction::Read => read_outcome(&read(chain, endpoints.clone(), packet)),
+                    Direction::Write => write_outcome(&write(chain, &endpoints, packet)),
+                };
+                (outcome != packet.outcome).then(|| {
+                    format!(
+                        "packet {} from {}: expected {:?}, got {:?}",
+                        index, packet.from, packet.outcome, outcome
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} of {} packets changed outcome:\n{}",
+                mismatches.len(),
+                self.packets.len(),
+                mismatches.join("\n")
+            ))
+        }
+    }
+
+    fn push(&mut self, mut packet: Packet, outcome: Outcome) {
+        packet.outcome = outcome;
+        self.packets.push(packet);
+    }
+
+    fn cluster(&self) -> Result<Vec<Endpoint>, String> {
+        self.endpoints
+            .iter()
+            .map(Endpoint::from_config)
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|endpoints| {
+                Endpoints::new(endpoints.clone())
+                    .map(|_| endpoints)
+                    .map_err(|_| "a corpus needs at least one endpoint".into())
+            })
+    }
+}
+
+fn read(chain: &FilterChain, endpoints: Vec<Endpoint>, packet: &Packet) -> Option<ReadResponse> {
+    let mut ctx = ReadContext::new(
+        Endpoints::new(endpoints).unwrap().into(),
+        packet.from,
+        packet.contents.clone(),
+    );
+    ctx.metadata = packet
+        .metadata
+        .iter()
+        .map(|(key, value)| (Arc::new(key.clone()), value.clone().into_any()))
+        .collect();
+    chain.read(ctx)
+}
+
+fn write(chain: &FilterChain, endpoints: &[Endpoint], packet: &Packet) -> Option<WriteResponse> {
+    let endpoint = endpoints
+        .iter()
+        .find(|endpoint| endpoint.address == packet.from)
+        .cloned()
+        .unwrap_or_else(|| Endpoint::from_address(packet.from));
+    let mut ctx = WriteContext::new(
+        &endpoint,
+        packet.from,
+        packet.to.expect("written packets have a `to` address"),
+        packet.contents.clone(),
+    );
+    ctx.metadata = packet
+        .metadata
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone().into_any()))
+        .collect();
+    chain.write(ctx)
+}
+
+fn read_outcome(response: &Option<ReadResponse>) -> Outcome {
+    match response {
+        Some(response) => Outcome::Forwarded {
+            contents: response.contents.to_vec(),
+            endpoints: response
+                .endpoints
+                .iter()
+                .map(|endpoint| endpoint.address)
+                .collect(),
+            metadata: metadata(
+                response
+                    .metadata
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value)),
+            ),
+        },
+        None => Outcome::Dropped(dropped()),
+    }
+}
+
+fn write_outcome(response: &Option<WriteResponse>) -> Outcome {
+    match response {
+        Some(response) => Outcome::Forwarded {
+            contents: response.contents.to_vec(),
+            endpoints: Vec::new(),
+            metadata: metadata(
+                response
+                    .metadata
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value)),
+            ),
+        },
+        None => Outcome::Dropped(dropped()),
+    }
+}
+
+/// Returns the reason the last packet was dropped for.
+fn dropped() -> String {
+    DropReason::take()
+        .unwrap_or(DropReason::UNSPECIFIED)
+        .to_string()
+}
+
+/// Returns the values of `metadata` that can be recorded.
+fn metadata<'a>(
+    metadata: impl Iterator<Item = (&'a str, &'a Box<dyn Any + Send>)>,
+) -> BTreeMap<String, Value> {
+    metadata
+        .filter_map(|(key, value)| Some((key.to_string(), Value::from_any(value.as_ref())?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Corpus, Outcome};
+    use crate::config::{EndPoint, Filter};
+    use crate::filters::{FilterRegistry, FilterSet};
+    use crate::test_utils::logger;
+
+    fn registry() -> FilterRegistry {
+        FilterRegistry::new(FilterSet::default(&logger()))
+    }
+
+    /// A corpus of packets routed by their last 3 bytes, to two endpoints
+    /// with the tokens `abc` and `xyz`.
+    fn token_router() -> Corpus {
+        let filter = |name: &str, config: &str| Filter {
+            name: format!("quilkin.extensions.filters.{}", name),
+            config: serde_yaml::from_str(config).unwrap(),
+        };
+        let endpoint = |address: &str, token: &str| {
+            EndPoint::with_metadata(
+                address.parse().unwrap(),
+                serde_yaml::from_str(&format!("{{quilkin.dev: {{tokens: [{}]}}}}", token)).unwrap(),
+            )
+        };
+        Corpus::new(
+            vec![
+                filter(
+                    "capture_bytes.v1alpha1.CaptureBytes",
+                    "{strategy: SUFFIX, size: 3, remove: true}",
+                ),
+                filter("token_router.v1alpha1.TokenRouter", "{}"),
+            ],
+            vec![
+                endpoint("127.0.0.1:7001", "YWJj"),
+                endpoint("127.0.0.1:7002", "eHl6"),
+            ],
+        )
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let registry = registry();
+        let mut corpus = token_router();
+        let chain = corpus.chain(&registry).unwrap();
+        let client = "127.0.0.1:7000".parse().unwrap();
+        corpus
+            .record_read(&chain, client, b"helloxyz".to_vec())
+            .unwrap();
+        assert!(corpus
+            .record_read(&chain, client, b"hellofoo".to_vec())
+            .is_none());
+        corpus
+            .record_write(
+                &chain,
+                "127.0.0.1:7001".parse().unwrap(),
+                client,
+                b"world".to_vec(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            Outcome::Forwarded {
+                contents: b"hello".to_vec(),
+                endpoints: vec!["127.0.0.1:7002".parse().unwrap()],
+                metadata: vec![(
+                    "quilkin.dev/captured_bytes".into(),
+                    super::Value::Bytes(b"xyz".to_vec())
+                )]
+                .into_iter()
+                .collect(),
+            },
+            corpus.packets[0].outcome
+        );
+        assert_eq!(
+            Outcome::Dropped("no_endpoint_match".into()),
+            corpus.packets[1].outcome
+        );
+
+        let mut file = Vec::new();
+        corpus.to_writer(&mut file).unwrap();
+        let replayed = Corpus::from_reader(file.as_slice()).unwrap();
+        assert_eq!(corpus, replayed);
+        replayed
+            .replay(&replayed.chain(&registry).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn changed_outcome() {
+        let registry = registry();
+        let mut corpus = token_router();
+        let chain = corpus.chain(&registry).unwrap();
+        corpus
+            .record_read(
+                &chain,
+                "127.0.0.1:7000".parse().unwrap(),
+                b"helloabc".to_vec(),
+            )
+            .unwrap();
+
+        // Capturing the token from the start of packets instead sends them
+        // nowhere.
+        corpus.filters[0].config =
+            serde_yaml::from_str("{strategy: PREFIX, size: 3, remove: true}").unwrap();
+        let err = corpus
+            .replay(&corpus.chain(&registry).unwrap())
+            .unwrap_err();
+        assert!(err.starts_with("1 of 1 packets changed outcome:\npacket 0 from 127.0.0.1:7000"));
+    }
+
+    #[test]
+    fn invalid_corpus() {
+        let err = Corpus::from_reader(
+            "
+filters: []
+endpoints: [{address: 127.0.0.1:7001}]
+packets:
+  - direction: write
+    from: 127.0.0.1:7001
+    contents: aGVsbG8=
+    outcome: {dropped: nope}
+"
+            .as_bytes(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            "packet 0 is written but has no `to` address",
+            err.to_string()
+        );
+
+        let err = Corpus::from_reader("filters: []\nendpoints: []".as_bytes()).unwrap_err();
+        assert_eq!("a corpus needs at least one endpoint", err.to_string());
+    }
+}
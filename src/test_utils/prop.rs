@@ -0,0 +1,225 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Generators of packets for property tests with [`proptest`], and checks of
+//! invariants that filters commonly have, which fail a test with the packet
+//! that broke them.
+//!
+//! A filter that reverses packets both ways must give back what it was
+//! given:
+//!
+//! ```
+//! use proptest::prelude::*;
+//! use quilkin::filters::{Filter, ReadContext, ReadResponse, WriteContext, WriteResponse};
+//! use quilkin::test_utils::prop::{check_read_write, packet};
+//!
+//! struct Reverse;
+//!
+//! impl Filter for Reverse {
+//!     fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+//!         ctx.contents = ctx.contents.iter().rev().copied().collect::<Vec<_>>().into();
+//!         Some(ctx.into())
+//!     }
+//!
+//!     fn write(&self, mut ctx: WriteContext) -> Option<WriteResponse> {
+//!         ctx.contents = ctx.contents.iter().rev().copied().collect::<Vec<_>>().into();
+//!         Some(ctx.into())
+//!     }
+//! }
+//!
+//! proptest! {
+//!     fn round_trip(packet in packet()) {
+//!         check_read_write(&Reverse, &packet)?;
+//!     }
+//! }
+//! # fn main() { round_trip(); }
+//! ```
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use proptest::collection::{btree_map, hash_set, vec};
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+
+use super::corpus::Value;
+use crate::cluster::Endpoint;
+use crate::config::Endpoints;
+use crate::filters::{Filter, ReadContext, WriteContext};
+
+/// The largest packet generated, which is about as much as fits in a single
+/// Ethernet frame.
+pub const MAX_CONTENTS: usize = 1500;
+
+/// A packet that is read from a client or written to it, along with the
+/// endpoints it can be sent to.
+#[derive(Clone, Debug)]
+pub struct Packet {
+    /// The client the packet comes from, or is written to.
+    pub client: SocketAddr,
+    /// The endpoints a packet that is read can be sent to, of which the first
+    /// one writes it.
+    pub endpoints: Vec<Endpoint>,
+    pub contents: Vec<u8>,
+    /// The metadata the packet starts with.
+    pub metadata: BTreeMap<String, Value>,
+}
+
+impl Packet {
+    /// Returns the context of reading the packet from its client.
+    pub fn read_context(&self) -> ReadContext {
+        let mut ctx = ReadContext::new(
+            Endpoints::new(self.endpoints.clone()).unwrap().into(),
+            self.client,
+            self.contents.clone(),
+        );
+        ctx.metadata = self
+            .metadata
+            .iter()
+            .map(|(key, value)| (Arc::new(key.clone()), value.clone().into_any()))
+            .collect();
+        ctx
+    }
+
+    /// Returns the context of writing the packet from its first endpoint to
+    /// its client.
+    pub fn write_context(&self) -> WriteContext<'_> {
+        let endpoint = &self.endpoints[0];
+        let mut ctx = WriteContext::new(
+            endpoint,
+            endpoint.address,
+            self.client,
+            self.contents.clone(),
+        );
+        ctx.metadata = self
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone().into_any()))
+            .collect();
+        ctx
+    }
+}
+
+/// Generates the contents of packets, up to [`MAX_CONTENTS`] bytes.
+pub fn contents() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..=MAX_CONTENTS)
+}
+
+/// Generates routing tokens, of 1 to 16 bytes.
+pub fn token() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 1..=16)
+}
+
+/// Generates metadata of up to 4 values, as filters such as `CaptureBytes`
+/// leave for the filters after them.
+pub fn metadata() -> impl Strategy<Value = BTreeMap<String, Value>> {
+    let value = prop_oneof![
+        token().prop_map(Value::Bytes),
+        "[ -~]{0,16}".prop_map(Value::String),
+    ];
+    btree_map("[a-z]{1,8}(\\.[a-z]{1,8})?(/[a-z_]{1,16})?", value, 0..=4)
+}
+
+/// Generates between 1 and 8 endpoints with different addresses, each with
+/// up to 3 tokens.
+pub fn endpoints() -> impl Strategy<Value = Vec<Endpoint>> {
+    btree_map(any::<SocketAddr>(), hash_set(token(), 0..=3), 1..=8).prop_map(|endpoints| {
+        endpoints
+            .into_iter()
+            .map(|(address, tokens)| Endpoint::new(address, tokens, None))
+            .collect()
+    })
+}
+
+/// Generates packets, from any client and without metadata.
+pub fn packet() -> impl Strategy<Value = Packet> {
+    (any::<SocketAddr>(), endpoints(), contents()).prop_map(|(client, endpoints, contents)| {
+        Packet {
+            client,
+            endpoints,
+            contents,
+            metadata: BTreeMap::new(),
+        }
+    })
+}
+
+/// Generates packets like [`packet`], but starting with metadata.
+pub fn packet_with_metadata() -> impl Strategy<Value = Packet> {
+    (packet(), metadata()).prop_map(|(packet, metadata)| Packet { metadata, ..packet })
+}
+
+/// Checks that writing what `filter` reads from `packet` gives back its
+/// contents, e.g for a filter compressing packets it reads and decompressing
+/// packets it writes.
+pub fn check_read_write<F: Filter + ?Sized>(
+    filter: &F,
+    packet: &Packet,
+) -> Result<(), TestCaseError> {
+    let read = filter
+        .read(packet.read_context())
+        .ok_or_else(|| TestCaseError::fail("read dropped"))?;
+    let written = filter
+        .write(WriteContext::new(
+            &packet.endpoints[0],
+            packet.endpoints[0].address,
+            packet.client,
+            read.contents,
+        ))
+        .ok_or_else(|| TestCaseError::fail("write dropped"))?;
+    prop_assert_eq!(&packet.contents, &written.contents.to_vec());
+    Ok(())
+}
+
+/// Checks that `decode` reading what `encode` reads from `packet` gives back
+/// its contents, e.g for the proxies on either side of a connection, one
+/// adding a token to packets and the other capturing it.
+pub fn check_read_read<E: Filter + ?Sized, D: Filter + ?Sized>(
+    encode: &E,
+    decode: &D,
+    packet: &Packet,
+) -> Result<(), TestCaseError> {
+    let encoded = encode
+        .read(packet.read_context())
+        .ok_or_else(|| TestCaseError::fail("encoding dropped"))?;
+    let decoded = decode
+        .read(ReadContext::with_response(packet.client, encoded))
+        .ok_or_else(|| TestCaseError::fail("decoding dropped"))?;
+    prop_assert_eq!(&packet.contents, &decoded.contents.to_vec());
+    Ok(())
+}
+
+/// Checks that `filter` passes `packet` on unchanged in both directions,
+/// along with its endpoints.
+pub fn check_no_change<F: Filter + ?Sized>(
+    filter: &F,
+    packet: &Packet,
+) -> Result<(), TestCaseError> {
+    let read = filter
+        .read(packet.read_context())
+        .ok_or_else(|| TestCaseError::fail("read dropped"))?;
+    prop_assert_eq!(&packet.contents, &read.contents.to_vec());
+    prop_assert_eq!(
+        &packet.endpoints,
+        &read.endpoints.iter().cloned().collect::<Vec<_>>()
+    );
+
+    let written = filter
+        .write(packet.write_context())
+        .ok_or_else(|| TestCaseError::fail("write dropped"))?;
+    prop_assert_eq!(&packet.contents, &written.contents.to_vec());
+    Ok(())
+}
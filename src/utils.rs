@@ -14,4 +14,5 @@
  *  limitations under the License.
  */
 
-pub(crate) mod debug;
+pub(crate) mod clock;
+pub(crate) mod random;
@@ -0,0 +1,62 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The time of day that sessions expire by, which a simulation replaces with
+//! its virtual clock.
+
+use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
+
+#[cfg(feature = "sim")]
+use std::cell::Cell;
+
+#[cfg(feature = "sim")]
+use tokio::time::Instant;
+
+#[cfg(feature = "sim")]
+thread_local! {
+    /// The Tokio instant at which a simulation on this thread started, along
+    /// with the time since the Unix epoch that it stands for.
+    static SIMULATED: Cell<Option<(Instant, Duration)>> = Cell::default();
+}
+
+/// Returns the time since the Unix epoch.
+pub(crate) fn unix_now() -> Result<Duration, SystemTimeError> {
+    match simulated_now() {
+        Some(now) => Ok(now),
+        None => SystemTime::now().duration_since(UNIX_EPOCH),
+    }
+}
+
+#[cfg(feature = "sim")]
+fn simulated_now() -> Option<Duration> {
+    SIMULATED.with(|simulated| {
+        simulated
+            .get()
+            .map(|(start, unix)| unix + Instant::now().saturating_duration_since(start))
+    })
+}
+
+#[cfg(not(feature = "sim"))]
+fn simulated_now() -> Option<Duration> {
+    None
+}
+
+/// Makes [`unix_now`] on this thread follow Tokio's clock from now on,
+/// starting at `unix`, or the system clock again for `None`.
+#[cfg(feature = "sim")]
+pub(crate) fn simulate(unix: Option<Duration>) {
+    SIMULATED.with(|simulated| simulated.set(unix.map(|unix| (Instant::now(), unix))));
+}
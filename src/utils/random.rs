@@ -0,0 +1,59 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Random choices made while routing packets, which a simulation seeds so
+//! that they are the same on every run.
+
+use rand::{thread_rng, Rng};
+
+#[cfg(feature = "sim")]
+use std::cell::RefCell;
+
+#[cfg(feature = "sim")]
+use rand::{rngs::StdRng, SeedableRng};
+
+#[cfg(feature = "sim")]
+thread_local! {
+    /// The generator of the simulation running on this thread, if any.
+    static SEEDED: RefCell<Option<StdRng>> = RefCell::default();
+}
+
+/// Returns a random index into a list of `len` items.
+pub(crate) fn index(len: usize) -> usize {
+    seeded_index(len).unwrap_or_else(|| thread_rng().gen_range(0..len))
+}
+
+#[cfg(feature = "sim")]
+fn seeded_index(len: usize) -> Option<usize> {
+    SEEDED.with(|seeded| {
+        seeded
+            .borrow_mut()
+            .as_mut()
+            .map(|rng| rng.gen_range(0..len))
+    })
+}
+
+#[cfg(not(feature = "sim"))]
+fn seeded_index(_: usize) -> Option<usize> {
+    None
+}
+
+/// Makes [`index`] on this thread return the same sequence for the same
+/// `seed`, or random ones again for `None`.
+#[cfg(feature = "sim")]
+pub(crate) fn seed(seed: Option<u64>) {
+    SEEDED.with(|seeded| *seeded.borrow_mut() = seed.map(StdRng::seed_from_u64));
+}
@@ -14,10 +14,14 @@
  * limitations under the License.
  */
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 use crate::xds::google::rpc::Status as GrpcStatus;
 use backoff::{backoff::Backoff, exponential::ExponentialBackoff, Clock, SystemClock};
+use opentelemetry::trace::{Span, StatusCode};
+use opentelemetry::KeyValue;
+use parking_lot::RwLock;
 use prometheus::{Registry, Result as MetricsResult};
 use slog::{debug, error, info, o, warn, Logger};
 use tokio::{
@@ -33,8 +37,12 @@ use tonic::{
 use crate::cluster::Cluster;
 use crate::config::ManagementServer;
 use crate::filters::manager::ListenerManagerArgs;
+use crate::proxy::cloud_metadata::Instance;
+use crate::proxy::events::{Event, EventHistory};
+use crate::proxy::tracing::Tracer;
 use crate::xds::cluster::ClusterManager;
-use crate::xds::envoy::config::core::v3::Node;
+use crate::xds::envoy::config::core::v3::Locality;
+pub(crate) use crate::xds::envoy::config::core::v3::Node;
 use crate::xds::envoy::service::discovery::v3::{
     aggregated_discovery_service_client::AggregatedDiscoveryServiceClient, DiscoveryRequest,
 };
@@ -48,6 +56,21 @@ use tokio::sync::mpsc::error::SendError;
 pub(crate) struct AdsClient {
     log: Logger,
     metrics: Metrics,
+    versions: ResourceVersions,
+    events: EventHistory,
+    tracer: Tracer,
+}
+
+/// The version of each type of resource that was last accepted from a
+/// management server, by type URL.
+pub(crate) type ResourceVersions = Arc<RwLock<BTreeMap<String, String>>>;
+
+/// The state of an [`AdsClient`] that can be observed while it runs.
+#[derive(Clone)]
+pub(crate) struct ClientState {
+    /// Set to 1 while the client is connected to a management server.
+    pub connected: GenericGauge<AtomicU64>,
+    pub versions: ResourceVersions,
 }
 
 /// Contains the components that handle XDS responses for supported resources.
@@ -67,8 +90,11 @@ impl ResourceHandlers {
 struct RpcSessionArgs<'a> {
     log: Logger,
     metrics: Metrics,
+    versions: ResourceVersions,
+    events: EventHistory,
+    tracer: Tracer,
     server_addr: String,
-    node_id: String,
+    node: Node,
     resource_handlers: ResourceHandlers,
     backoff: ExponentialBackoff<SystemClock>,
     discovery_req_rx: &'a mut mpsc::Receiver<DiscoveryRequest>,
@@ -117,16 +143,36 @@ pub type ExecutionResult = Result<(), ExecutionError>;
 pub const UPDATES_CHANNEL_BUFFER_SIZE: usize = 1;
 
 impl AdsClient {
-    pub fn new(base_logger: Logger, metrics_registry: &Registry) -> MetricsResult<Self> {
+    pub fn new(
+        base_logger: Logger,
+        metrics_registry: &Registry,
+        events: EventHistory,
+        tracer: Tracer,
+    ) -> MetricsResult<Self> {
         let log = base_logger.new(o!("source" => "xds::AdsClient"));
         let metrics = Metrics::new(metrics_registry)?;
-        Ok(Self { log, metrics })
+        Ok(Self {
+            log,
+            metrics,
+            versions: Default::default(),
+            events,
+            tracer,
+        })
     }
+
+    /// Returns the state of the client, which is updated while it runs.
+    pub fn state(&self) -> ClientState {
+        ClientState {
+            connected: self.metrics.connected_state.clone(),
+            versions: self.versions.clone(),
+        }
+    }
+
     /// Continuously tracks CDS and EDS resources on an ADS server,
     /// sending summarized cluster updates on the provided channel.
     pub async fn run(
         self,
-        node_id: String,
+        node: Node,
         management_servers: Vec<ManagementServer>,
         cluster_updates_tx: mpsc::Sender<ClusterUpdate>,
         listener_manager_args: ListenerManagerArgs,
@@ -135,6 +181,9 @@ impl AdsClient {
         let mut backoff = ExponentialBackoff::<SystemClock>::default();
         let log = self.log;
         let metrics = self.metrics;
+        let versions = self.versions;
+        let events = self.events;
+        let tracer = self.tracer;
 
         let (discovery_req_tx, mut discovery_req_rx) =
             mpsc::channel::<DiscoveryRequest>(UPDATES_CHANNEL_BUFFER_SIZE);
@@ -170,8 +219,11 @@ impl AdsClient {
             let args = RpcSessionArgs {
                 log: log.clone(),
                 metrics: metrics.clone(),
+                versions: versions.clone(),
+                events: events.clone(),
+                tracer: tracer.clone(),
                 server_addr: server_addr.clone(),
-                node_id: node_id.clone(),
+                node: node.clone(),
                 resource_handlers,
                 backoff,
                 discovery_req_rx: &mut discovery_req_rx,
@@ -203,6 +255,13 @@ impl AdsClient {
                         Err(RpcSessionError::Receive(handlers, bk_off, status)) => {
                             resource_handlers = handlers;
                             backoff = bk_off;
+                            let span = tracer.span("xds.disconnect");
+                            span.set_attribute(KeyValue::new("quilkin.xds.address", server_addr.clone()));
+                            span.set_status(StatusCode::Error, status.message().to_owned());
+                            span.end();
+                            events.record(&log, Event::new("xds.disconnect", "Disconnected from the XDS server")
+                                .with("address", server_addr.clone())
+                                .with("error", status.message()));
                             error!(log, "Failed to receive from XDS server"; "address" => server_addr, "status" => #?status);
                             Self::backoff(
                                 &log,
@@ -230,23 +289,35 @@ impl AdsClient {
         let RpcSessionArgs {
             log,
             metrics,
+            versions,
+            events,
+            tracer,
             server_addr,
-            node_id,
+            node,
             resource_handlers,
             backoff,
             discovery_req_rx,
             shutdown_rx,
         } = args;
-        let client = match AggregatedDiscoveryServiceClient::connect(server_addr).await {
+        let span = tracer.span("xds.connect");
+        span.set_attribute(KeyValue::new("quilkin.xds.address", server_addr.clone()));
+        let client = match AggregatedDiscoveryServiceClient::connect(server_addr.clone()).await {
             Ok(client) => client,
             Err(err) => {
+                span.set_status(StatusCode::Error, err.to_string());
                 return Err(RpcSessionError::InitialConnect(
                     resource_handlers,
                     backoff,
                     err,
-                ))
+                ));
             }
         };
+        span.end();
+        events.record(
+            &log,
+            Event::new("xds.connect", "Connected to the XDS server")
+                .with("address", server_addr.clone()),
+        );
 
         let (mut rpc_tx, rpc_rx) = mpsc::channel::<DiscoveryRequest>(UPDATES_CHANNEL_BUFFER_SIZE);
 
@@ -262,7 +333,7 @@ impl AdsClient {
         );
 
         // Fetch the initial set of resources.
-        Self::send_initial_cds_and_lds_request(&log, &metrics, node_id, &mut rpc_tx).await?;
+        Self::send_initial_cds_and_lds_request(&log, &metrics, &tracer, node, &mut rpc_tx).await?;
 
         // Run the send loop on the current task.
         loop {
@@ -276,7 +347,12 @@ impl AdsClient {
 
                 req = discovery_req_rx.recv() => {
                     if let Some(req) = req {
-                    Self::send_discovery_request(&log, &metrics, req, &mut rpc_tx)
+                    // A request without an error acknowledges the version
+                    // it carries.
+                    if req.error_detail.is_none() && !req.version_info.is_empty() {
+                        versions.write().insert(req.type_url.clone(), req.version_info.clone());
+                    }
+                    Self::send_discovery_request(&log, &metrics, &tracer, req, &mut rpc_tx)
                         .await
                         .map_err(|err| RpcSessionError::NonRecoverable(
                             "failed to send discovery request on channel",
@@ -304,27 +380,18 @@ impl AdsClient {
     async fn send_initial_cds_and_lds_request(
         log: &Logger,
         metrics: &Metrics,
-        node_id: String,
+        tracer: &Tracer,
+        node: Node,
         rpc_tx: &mut mpsc::Sender<DiscoveryRequest>,
     ) -> Result<(), RpcSessionError> {
         for resource_type in &[CLUSTER_TYPE, LISTENER_TYPE] {
             let send_result = Self::send_discovery_request(
                 log,
                 metrics,
+                tracer,
                 DiscoveryRequest {
                     version_info: "".into(),
-                    node: Some(Node {
-                        id: node_id.clone(),
-                        cluster: "".into(),
-                        metadata: None,
-                        dynamic_parameters: Default::default(),
-                        locality: None,
-                        user_agent_name: "quilkin".into(),
-                        extensions: vec![],
-                        client_features: vec![],
-                        listening_addresses: vec![],
-                        user_agent_version_type: None,
-                    }),
+                    node: Some(node.clone()),
                     resource_names: vec![], // Wildcard mode.
                     type_url: (*resource_type).into(),
                     response_nonce: "".into(),
@@ -429,10 +496,22 @@ impl AdsClient {
     async fn send_discovery_request(
         log: &Logger,
         metrics: &Metrics,
+        tracer: &Tracer,
         req: DiscoveryRequest,
         req_tx: &mut mpsc::Sender<DiscoveryRequest>,
     ) -> Result<(), SendError<DiscoveryRequest>> {
-        if req.error_detail.is_some() {
+        let span = tracer.span("xds.request");
+        span.set_attribute(KeyValue::new("quilkin.xds.type_url", req.type_url.clone()));
+        span.set_attribute(KeyValue::new(
+            "quilkin.xds.version",
+            req.version_info.clone(),
+        ));
+        span.set_attribute(KeyValue::new(
+            "quilkin.xds.nonce",
+            req.response_nonce.clone(),
+        ));
+        if let Some(error) = &req.error_detail {
+            span.set_status(StatusCode::Error, error.message.clone());
             metrics.update_failure_total.inc();
         } else {
             metrics.update_success_total.inc();
@@ -457,6 +536,37 @@ impl AdsClient {
     }
 }
 
+/// Returns the node the proxy identifies itself as to management servers,
+/// which is located in the zone of the cloud instance it runs on, if known.
+pub(crate) fn node(id: String, instance: Option<&Instance>) -> Node {
+    let string = |value: &str| prost_types::Value {
+        kind: Some(prost_types::value::Kind::StringValue(value.into())),
+    };
+    Node {
+        id,
+        cluster: "".into(),
+        metadata: instance.map(|instance| prost_types::Struct {
+            fields: vec![
+                ("cloud_provider".into(), string(instance.provider)),
+                ("instance_id".into(), string(&instance.id)),
+            ]
+            .into_iter()
+            .collect(),
+        }),
+        dynamic_parameters: Default::default(),
+        locality: instance.map(|instance| Locality {
+            region: instance.region.clone(),
+            zone: instance.zone.clone(),
+            sub_zone: "".into(),
+        }),
+        user_agent_name: "quilkin".into(),
+        extensions: vec![],
+        client_features: vec![],
+        listening_addresses: vec![],
+        user_agent_version_type: None,
+    }
+}
+
 // Send a Discovery request with the provided arguments on the channel.
 pub(super) async fn send_discovery_req(
     log: Logger,
@@ -496,10 +606,13 @@ pub(super) async fn send_discovery_req(
 
 #[cfg(test)]
 mod tests {
-    use super::AdsClient;
+    use super::{node, AdsClient};
     use crate::config::ManagementServer;
     use crate::filters::FilterRegistry;
+    use crate::proxy::cloud_metadata::Instance;
+    use crate::proxy::events::EventHistory;
     use crate::proxy::logger;
+    use crate::proxy::tracing::Tracer;
     use crate::xds::ads_client::ListenerManagerArgs;
     use crate::xds::envoy::service::discovery::v3::DiscoveryRequest;
     use crate::xds::google::rpc::Status as GrpcStatus;
@@ -508,6 +621,7 @@ mod tests {
     use std::time::Duration;
 
     use prometheus::Registry;
+    use prost_types::value::Kind;
     use tokio::sync::{mpsc, watch};
 
     #[tokio::test]
@@ -518,8 +632,15 @@ mod tests {
         let (_shutdown_tx, shutdown_rx) = watch::channel::<()>(());
         let (cluster_updates_tx, _) = mpsc::channel(10);
         let (filter_chain_updates_tx, _) = mpsc::channel(10);
-        let run = AdsClient::new(logger(), &Registry::default()).unwrap().run(
-            "test-id".into(),
+        let run = AdsClient::new(
+            logger(),
+            &Registry::default(),
+            EventHistory::default(),
+            Tracer::default(),
+        )
+        .unwrap()
+        .run(
+            node("test-id".into(), None),
             vec![ManagementServer {
                 address: "localhost:18000".into(),
             }],
@@ -576,4 +697,32 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn node_with_instance() {
+        let node_without_instance = node("proxy-1".into(), None);
+        assert_eq!("proxy-1", node_without_instance.id);
+        assert_eq!(None, node_without_instance.locality);
+        assert_eq!(None, node_without_instance.metadata);
+
+        let instance = Instance {
+            provider: "gce",
+            region: "europe-west1".into(),
+            zone: "europe-west1-b".into(),
+            id: "4520031799277581759".into(),
+        };
+        let node = node("proxy-1".into(), Some(&instance));
+        let locality = node.locality.unwrap();
+        assert_eq!("europe-west1", locality.region);
+        assert_eq!("europe-west1-b", locality.zone);
+        let metadata = node.metadata.unwrap();
+        assert_eq!(
+            Some(&Kind::StringValue("4520031799277581759".into())),
+            metadata.fields["instance_id"].kind.as_ref()
+        );
+        assert_eq!(
+            Some(&Kind::StringValue("gce".into())),
+            metadata.fields["cloud_provider"].kind.as_ref()
+        );
+    }
 }
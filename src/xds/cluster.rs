@@ -18,7 +18,7 @@ use crate::cluster::{
     Cluster as ProxyCluster, ClusterLocalities, Endpoint, Locality, LocalityEndpoints,
 };
 use crate::xds::envoy::config::cluster::v3::{cluster, Cluster};
-use crate::xds::envoy::config::core::v3::{address, socket_address};
+use crate::xds::envoy::config::core::v3::{address, socket_address, HealthStatus};
 use crate::xds::envoy::config::endpoint::v3::{lb_endpoint, ClusterLoadAssignment};
 use crate::xds::envoy::service::discovery::v3::{DiscoveryRequest, DiscoveryResponse};
 use crate::xds::metadata;
@@ -253,16 +253,16 @@ impl ClusterManager {
 
             // Extract components of the endpoint that we care about.
             let mut processed_endpoints = vec![];
-            for (host_identifier, metadata) in
-                lb_locality
-                    .lb_endpoints
-                    .into_iter()
-                    .filter_map(|lb_endpoint| {
-                        let metadata = lb_endpoint.metadata;
-                        lb_endpoint
-                            .host_identifier
-                            .map(|host_identifier| (host_identifier, metadata))
-                    })
+            for (host_identifier, metadata, health_status) in lb_locality
+                .lb_endpoints
+                .into_iter()
+                .filter_map(|lb_endpoint| {
+                    let metadata = lb_endpoint.metadata;
+                    let health_status = lb_endpoint.health_status;
+                    lb_endpoint
+                        .host_identifier
+                        .map(|host_identifier| (host_identifier, metadata, health_status))
+                })
             {
                 let endpoint = match host_identifier {
                     lb_endpoint::HostIdentifier::Endpoint(endpoint) => Ok(endpoint),
@@ -316,19 +316,30 @@ impl ClusterManager {
                     (None, Default::default())
                 };
 
-                processed_endpoints.push((address, tokens, metadata));
+                // Endpoints of an unknown health, or degraded, can still
+                // serve traffic.
+                let healthy = !matches!(
+                    HealthStatus::from_i32(health_status),
+                    Some(HealthStatus::Unhealthy)
+                        | Some(HealthStatus::Draining)
+                        | Some(HealthStatus::Timeout)
+                );
+
+                processed_endpoints.push((address, tokens, metadata, healthy));
             }
 
             let mut endpoints = vec![];
-            for ((addr, port), tokens, metadata) in processed_endpoints.into_iter() {
-                endpoints.push(Endpoint::new(
+            for ((addr, port), tokens, metadata, healthy) in processed_endpoints.into_iter() {
+                let mut endpoint = Endpoint::new(
                     // We only support IP addresses so anything else is an error.
                     addr.parse::<std::net::IpAddr>()
                         .map_err(|err| Error::new(format!("invalid ip address: {}", err)))
                         .map(|ip_addr| SocketAddr::new(ip_addr, port))?,
                     tokens,
                     metadata,
-                ));
+                );
+                endpoint.healthy = healthy;
+                endpoints.push(endpoint);
             }
 
             existing_endpoints.insert(locality, LocalityEndpoints { endpoints });
@@ -407,7 +418,7 @@ mod tests {
     use crate::test_utils::logger;
     use crate::xds::envoy::config::cluster::v3::{cluster::ClusterDiscoveryType, Cluster};
     use crate::xds::envoy::config::core::v3::{
-        address, socket_address::PortSpecifier, Address, Metadata, SocketAddress,
+        address, socket_address::PortSpecifier, Address, HealthStatus, Metadata, SocketAddress,
     };
     use crate::xds::envoy::config::endpoint::v3::{
         lb_endpoint::HostIdentifier, ClusterLoadAssignment, Endpoint, LbEndpoint,
@@ -854,6 +865,44 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn endpoint_health() {
+        let (cluster_updates_tx, mut cluster_updates_rx) = mpsc::channel::<ClusterState>(100);
+        let (discovery_req_tx, _) = mpsc::channel::<DiscoveryRequest>(100);
+        let mut cm = ClusterManager::new(logger(), cluster_updates_tx, discovery_req_tx);
+
+        for (health_status, healthy) in &[
+            (HealthStatus::Unknown, true),
+            (HealthStatus::Healthy, true),
+            (HealthStatus::Degraded, true),
+            (HealthStatus::Unhealthy, false),
+            (HealthStatus::Draining, false),
+            (HealthStatus::Timeout, false),
+        ] {
+            cm.on_cluster_response(cluster_discovery_response_with_update(
+                &format!("{:?}", health_status),
+                "2",
+                vec!["a".into()],
+                |mut cluster| {
+                    if let Some(assignment) = cluster.load_assignment.as_mut() {
+                        assignment.endpoints[0].lb_endpoints[0].health_status =
+                            *health_status as i32;
+                    }
+                    cluster
+                },
+            ))
+            .await;
+
+            let cluster_state = cluster_updates_rx.recv().await.unwrap();
+            let (_, locality) = cluster_state["a"].localities.iter().next().unwrap();
+            assert_eq!(
+                *healthy, locality.endpoints[0].healthy,
+                "{:?}",
+                health_status
+            );
+        }
+    }
+
     // Test Helpers
     fn create_endpoint_resource(cluster_name: &str) -> ClusterLoadAssignment {
         ClusterLoadAssignment {
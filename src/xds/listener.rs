@@ -145,6 +145,7 @@ impl ListenerManager {
         lds_filter_chain: FilterChain,
     ) -> Result<ProxyFilterChain, Error> {
         let mut filters = vec![];
+        let mut configs = vec![];
         for filter in lds_filter_chain.filters {
             let config = filter
                 .config_type
@@ -156,6 +157,14 @@ impl ListenerManager {
                     ))),
                 })
                 .transpose()?;
+            // The configuration is only known as Protobuf, so it is reported
+            // as its type and encoded value.
+            configs.push(config.as_ref().map(|config| {
+                serde_json::json!({
+                    "@type": config.type_url,
+                    "value": base64::encode(&config.value),
+                })
+            }));
             let create_filter_args =
                 CreateFilterArgs::dynamic(self.metrics_registry.clone(), config);
 
@@ -168,7 +177,7 @@ impl ListenerManager {
             filters.push((name, filter));
         }
 
-        Ok(ProxyFilterChain::new(filters, &self.metrics_registry)?)
+        Ok(ProxyFilterChain::new(filters, &self.metrics_registry)?.with_configs(configs))
     }
 
     // Send a DiscoveryRequest ACK/NACK back to the server for the given version and nonce.
@@ -239,10 +248,10 @@ mod tests {
         fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
             ctx.contents = format!(
                 "{}{}",
-                String::from_utf8(ctx.contents).unwrap(),
+                std::str::from_utf8(&ctx.contents).unwrap(),
                 self.value.as_ref().unwrap()
             )
-            .into_bytes();
+            .into();
             Some(ctx.into())
         }
     }
@@ -375,13 +384,13 @@ mod tests {
                     .unwrap(),
                 ),
                 "127.0.0.1:8081".parse().unwrap(),
-                "hello-".into(),
+                "hello-",
             ))
             .unwrap();
 
         assert_eq!(
             "hello-world!",
-            String::from_utf8(response.contents).unwrap()
+            std::str::from_utf8(&response.contents).unwrap()
         );
     }
 
@@ -487,13 +496,13 @@ mod tests {
                         .unwrap(),
                     ),
                     "127.0.0.1:8081".parse().unwrap(),
-                    "hello-".into(),
+                    "hello-",
                 ))
                 .unwrap();
 
             assert_eq!(
                 expected_payload,
-                String::from_utf8(response.contents).unwrap()
+                std::str::from_utf8(&response.contents).unwrap()
             );
         }
     }
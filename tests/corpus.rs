@@ -0,0 +1,39 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *       http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ */
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use quilkin::filters::{FilterRegistry, FilterSet};
+    use quilkin::test_utils::{corpus::Corpus, logger};
+
+    /// Replays the corpora in `tests/corpus`, which were recorded with an
+    /// earlier version of the filters they use.
+    #[test]
+    fn replay() {
+        let registry = FilterRegistry::new(FilterSet::default(&logger()));
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let corpus = Corpus::from_file(&path).unwrap();
+            if let Err(err) = corpus.replay(&corpus.chain(&registry).unwrap()) {
+                panic!("{}: {}", path.display(), err);
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#[cfg(test)]
+mod tests {
+    use quilkin::config::{Endpoint, Endpoints, Filter};
+    use quilkin::filters::{
+        extensions::{CaptureBytesFactory, TokenRouterFactory},
+        Filter as _, FilterChain, FilterFactory, FilterRegistry, FilterSet, ReadContext,
+    };
+    use quilkin::test_utils::{drop_reason, logger};
+
+    /// A chain routes packets without running a proxy.
+    #[test]
+    fn standalone_filter_chain() {
+        let log = logger();
+        let chain = FilterChain::try_create(
+            vec![
+                Filter {
+                    name: CaptureBytesFactory::new(&log).name().into(),
+                    config: serde_yaml::from_str("{size: 3, remove: true}").unwrap(),
+                },
+                Filter {
+                    name: TokenRouterFactory::new(&log).name().into(),
+                    config: None,
+                },
+            ],
+            &FilterRegistry::new(FilterSet::default(&log)),
+            &prometheus::Registry::default(),
+        )
+        .unwrap();
+
+        let abc = Endpoint::new(
+            "127.0.0.1:80".parse().unwrap(),
+            vec![b"abc".to_vec()].into_iter().collect(),
+            None,
+        );
+        let xyz = Endpoint::new(
+            "127.0.0.1:90".parse().unwrap(),
+            vec![b"xyz".to_vec()].into_iter().collect(),
+            None,
+        );
+        let read = |contents: &[u8]| {
+            chain.read(ReadContext::new(
+                Endpoints::new(vec![abc.clone(), xyz.clone()])
+                    .unwrap()
+                    .into(),
+                "127.0.0.1:7000".parse().unwrap(),
+                contents.to_vec(),
+            ))
+        };
+
+        let response = read(b"helloxyz").unwrap();
+        assert_eq!(b"hello", &*response.contents);
+        assert_eq!(vec![&xyz], response.endpoints.iter().collect::<Vec<_>>());
+
+        assert_eq!(
+            "no_endpoint_match",
+            drop_reason(read(b"hello123")).unwrap().as_str()
+        );
+    }
+}
@@ -33,6 +33,7 @@ mod tests {
             .with_static(vec![], vec![EndPoint::new("127.0.0.1:0".parse().unwrap())])
             .with_admin(Admin {
                 address: "[::]:9093".parse().unwrap(),
+                ..Admin::default()
             })
             .build();
         t.run_server_with_builder(ProxyBuilder::from(Arc::new(server_config)));
@@ -41,6 +41,7 @@ mod tests {
             .with_static(vec![], vec![EndPoint::new(echo)])
             .with_admin(Admin {
                 address: "[::]:9092".parse().unwrap(),
+                ..Admin::default()
             })
             .build();
         t.run_server_with_builder(Builder::from(Arc::new(server_config)));
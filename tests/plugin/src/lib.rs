@@ -0,0 +1,72 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A plugin exporting the `quilkin.test.v1alpha1.Append` filter, which
+//! appends the `suffix` in its configuration to packets it reads. It is
+//! built and loaded by its tests, as Quilkin would load any other plugin.
+
+use quilkin::filters::{
+    plugin::Registrar, ConfigType, CreateFilterArgs, Error, Filter, FilterFactory, ReadContext,
+    ReadResponse,
+};
+
+const NAME: &str = "quilkin.test.v1alpha1.Append";
+
+struct Append {
+    suffix: Vec<u8>,
+}
+
+impl Filter for Append {
+    fn read(&self, mut ctx: ReadContext) -> Option<ReadResponse> {
+        ctx.contents = [&*ctx.contents, &self.suffix].concat().into();
+        Some(ctx.into())
+    }
+}
+
+struct AppendFactory;
+
+impl FilterFactory for AppendFactory {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn create_filter(&self, args: CreateFilterArgs) -> Result<Box<dyn Filter>, Error> {
+        let suffix = match args.config {
+            Some(ConfigType::Static(config)) => config
+                .get("suffix")
+                .and_then(|suffix| suffix.as_str())
+                .ok_or_else(|| Error::FieldInvalid {
+                    field: "suffix".into(),
+                    reason: "a string is required".into(),
+                })?,
+            Some(ConfigType::Dynamic(_)) => {
+                return Err(Error::DeserializeFailed(
+                    "only static configuration is supported".into(),
+                ))
+            }
+            None => return Err(Error::MissingConfig(NAME)),
+        };
+        Ok(Box::new(Append {
+            suffix: suffix.as_bytes().to_vec(),
+        }))
+    }
+}
+
+fn register(registrar: &mut Registrar) {
+    registrar.register(Box::new(AppendFactory));
+}
+
+quilkin::export_plugin!(register);
@@ -0,0 +1,91 @@
+/*
+ * Copyright 2021 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::PathBuf;
+
+use prometheus::Registry;
+use quilkin::config::{Endpoint, Endpoints, Filter, Plugin};
+use quilkin::filters::{plugin, Filter as _, FilterChain, FilterRegistry, FilterSet, ReadContext};
+use slog::{o, Discard, Logger};
+
+/// Returns the path of the plugin that Cargo built for this test, next to
+/// the test itself.
+fn plugin_path() -> PathBuf {
+    std::env::current_exe().unwrap().with_file_name(format!(
+        "{}quilkin_test_plugin{}",
+        std::env::consts::DLL_PREFIX,
+        std::env::consts::DLL_SUFFIX
+    ))
+}
+
+#[test]
+#[cfg_attr(not(unix), ignore)]
+fn load_plugin() {
+    let log = Logger::root(Discard, o!());
+    let factories = plugin::load(
+        &log,
+        &[Plugin {
+            path: plugin_path(),
+        }],
+    )
+    .unwrap();
+    assert_eq!(
+        vec!["quilkin.test.v1alpha1.Append"],
+        factories
+            .iter()
+            .map(|factory| factory.name())
+            .collect::<Vec<_>>()
+    );
+
+    // The filters of the plugin are created from the configuration like any
+    // other.
+    let registry = FilterRegistry::new(FilterSet::default_with(&log, factories));
+    let chain = FilterChain::try_create(
+        vec![Filter {
+            name: "quilkin.test.v1alpha1.Append".into(),
+            config: serde_yaml::from_str("suffix: abc").unwrap(),
+        }],
+        &registry,
+        &Registry::default(),
+    )
+    .unwrap();
+    let endpoint = Endpoint::new("127.0.0.1:80".parse().unwrap(), Default::default(), None);
+    let response = chain
+        .read(ReadContext::new(
+            Endpoints::new(vec![endpoint]).unwrap().into(),
+            "127.0.0.1:7000".parse().unwrap(),
+            b"hello".to_vec(),
+        ))
+        .unwrap();
+    assert_eq!(b"helloabc", &*response.contents);
+
+    // Errors of the plugin's factory are reported by the proxy.
+    let err = FilterChain::try_create(
+        vec![Filter {
+            name: "quilkin.test.v1alpha1.Append".into(),
+            config: None,
+        }],
+        &registry,
+        &Registry::default(),
+    )
+    .err()
+    .unwrap();
+    assert!(
+        err.to_string().contains("requires configuration"),
+        "{}",
+        err
+    );
+}